@@ -1,11 +1,48 @@
-use caesar_core::relay;
-use caesar_core::sender;
-use caesar_core::{receiver, sender::util::generate_random_name};
+use caesar_core::bench::{self, BenchOptions};
+use caesar_core::error::CaesarError;
+use caesar_core::event::{EventReport, TransferEvent};
+use caesar_core::idle::IdleTimeout;
+use caesar_core::progress::{FileProgress, ProgressReport};
+use caesar_core::relay::{
+    self,
+    auth::AuthConfig,
+    blocklist::Blocklist,
+    limiter::RateLimits,
+    Priority,
+    reload::ConfigSource,
+    room::Room,
+    server::{RelayConfig, TlsConfig},
+};
+use base64::{engine::general_purpose, Engine as _};
+use caesar_core::sender::{
+    self, filter::PathFilter, usage::PathUsage, ArchiveFormat, SenderOptions, SymlinkPolicy,
+};
+use caesar_core::receiver::{
+    select::{Confirmation, FileSelection, OfferedFile},
+    ConflictPolicy, ReceiverOptions,
+};
+use caesar_core::simulate::{self, Scenario};
+use caesar_core::{
+    format::{parse_size, NumberFormat},
+    receiver, status,
+    sender::util::{generate_mailbox, generate_name, DEFAULT_NAME_WORDS},
+};
 use clap::{Parser, Subcommand};
-use std::{env, sync::Arc};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::{
+    collections::HashMap,
+    env,
+    io::{self, Write},
+    path::PathBuf,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+use tokio_util::sync::CancellationToken;
 use tracing::debug;
 
-use crate::config::GLOBAL_CONFIG;
+use crate::cli::tui::{Side, Tui};
+use crate::config::{self, CaesarConfig, Mailbox, Profile, GLOBAL_CONFIG};
 
 /// Struct representing the command line arguments parsed by clap.
 ///
@@ -25,28 +62,184 @@ pub struct Args {
     /// any specific subcommand.
     #[command(subcommand)]
     pub command: Option<Commands>,
+
+    /// Relay profile from the config file to send and receive with, flags take precedence over it
+    #[arg(long, global = true, value_name = "NAME")]
+    pub profile: Option<String>,
 }
 
-#[derive(Subcommand, Debug)]
+#[derive(Subcommand, Debug, Clone)]
 pub enum Commands {
     /// Send files to the receiver or relay server
     Send {
-        /// Address of the relay server. Accepted formats are: 127.0.0.1:8080, [::1]:8080, example.com
+        /// Address of the relay server, may be given several times to fall back to the next one if it is down. Accepted formats are: 127.0.0.1:8080, [::1]:8080, example.com, wss://example.com
         #[arg(short, long)]
-        relay: Option<String>,
-        /// Path to file(s)
+        relay: Vec<String>,
+        /// Path to file(s), or - to send stdin as a stream. Directories need --tar or --archive
         #[arg(value_name = "FILES")]
         files: Vec<String>,
+        /// Compress the transfer with zstd if the receiver supports it
+        #[arg(short, long)]
+        compress: bool,
+        /// Allow sending FIFOs, sockets and devices
+        #[arg(long)]
+        allow_special: bool,
+        /// Seconds without data before a stalled transfer is closed, 0 to wait forever
+        #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+        idle_timeout: u64,
+        /// Times to reconnect after a suspend or a dropped connection, 0 to give up at once
+        #[arg(long, value_name = "COUNT", default_value_t = 5)]
+        reconnect: u32,
+        /// Access token for a relay that requires authentication
+        #[arg(long, value_name = "TOKEN")]
+        token: Option<String>,
+        /// Send to a mailbox from the config file instead of under a random name
+        #[arg(long, value_name = "LABEL")]
+        mailbox: Option<String>,
+        /// How urgent the transfer is, a relay under load serves urgent ones first
+        #[arg(long, value_enum, default_value = "normal")]
+        priority: Priority,
+        /// Number of words in the generated transfer name
+        #[arg(long, value_name = "COUNT", default_value_t = DEFAULT_NAME_WORDS)]
+        words: usize,
+        /// Number of receivers that get the files with the same transfer code, up to 64
+        #[arg(long, value_name = "COUNT", default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=64))]
+        receivers: u8,
+        /// Send a short text like a password or a URL instead of files, or - to read it from stdin
+        #[arg(long, value_name = "TEXT", conflicts_with = "files")]
+        text: Option<String>,
+        /// Do not announce the transfer on the local network
+        #[arg(long)]
+        no_announce: bool,
+        /// Keep the transfer on the local network, without the relay. The receiver finds it with --discover
+        #[arg(long, conflicts_with = "no_announce")]
+        local: bool,
+        /// Do not offer receivers behind NAT a direct connection through hole punching
+        #[arg(long, conflicts_with = "local")]
+        no_punch: bool,
+        /// Do not accept receivers on the local network over QUIC, only over WebSocket
+        #[arg(long)]
+        no_quic: bool,
+        /// Port of the local server, the next free one is taken if it is busy [default: 9000]
+        #[arg(long, value_name = "PORT")]
+        local_port: Option<u16>,
+        /// Send directories as tar streams that the receiver unpacks as they arrive
+        #[arg(long)]
+        tar: bool,
+        /// Pack all paths into a single archive while they are sent, so the receiver gets one file
+        #[arg(long, value_enum, value_name = "FORMAT", conflicts_with = "tar")]
+        archive: Option<ArchiveFormat>,
+        /// Send the files and directories that symbolic links point to instead of the links
+        #[arg(long, conflicts_with_all = ["skip_symlinks", "preserve_symlinks"])]
+        follow_symlinks: bool,
+        /// Leave symbolic links out, both the given ones and those inside directories
+        #[arg(long, conflicts_with = "preserve_symlinks")]
+        skip_symlinks: bool,
+        /// Send symbolic links as links, which the receiver creates again on Unix
+        #[arg(long)]
+        preserve_symlinks: bool,
+        /// Send only the files that match this glob pattern, may be given several times
+        #[arg(long, value_name = "PATTERN")]
+        include: Vec<String>,
+        /// Leave out the files and directories that match this glob pattern, like 'target/**', may be given several times
+        #[arg(long, value_name = "PATTERN")]
+        exclude: Vec<String>,
+        /// Leave one small file encrypted on the relay and print a link to download it once in a browser
+        #[arg(long, conflicts_with_all = ["text", "mailbox", "receivers", "local", "tar", "archive"])]
+        link: bool,
+        /// Play a simulated transfer without any network and print its events as JSON lines, e.g. to build a user interface against
+        #[arg(long, value_enum, value_name = "SCENARIO")]
+        simulate: Option<Scenario>,
+        /// Seed of the simulated transfer, the same seed plays the same transfer
+        #[arg(long, default_value_t = 0, requires = "simulate")]
+        seed: u64,
+        /// Print the events of the transfer as JSON lines instead of text for humans, e.g. for scripts
+        #[arg(long, conflicts_with = "link")]
+        json: bool,
+        /// Show the transfer full screen with a progress bar for every file, q cancels it
+        #[arg(long, conflicts_with_all = ["json", "link"])]
+        tui: bool,
     },
     /// Receives Files from the sender with the matching password
     Receive {
-        /// Address of the relay server. Accepted formats are: 127.0.0.1:8080, [::1]:8080, example.com
+        /// Address of the relay server, may be given several times to fall back to the next one if it is down. Accepted formats are: 127.0.0.1:8080, [::1]:8080, example.com, wss://example.com
         #[arg(short, long)]
-        relay: Option<String>,
+        relay: Vec<String>,
 
-        /// Name of Transfer to download files
-        #[arg(value_name = "Transfer_Name")]
-        name: String,
+        /// Transfer code printed by the sender, like 482913-brave-tiger-42, or only its PIN with --discover
+        #[arg(value_name = "Transfer_Name", required_unless_present_any = ["mailbox", "simulate"])]
+        name: Option<String>,
+
+        /// Directory to save the received files in. It is created if it does not exist [default: .]
+        #[arg(short, long)]
+        output: Option<String>,
+
+        /// What to do when a received file already exists in the output directory
+        #[arg(long, value_enum, default_value = "abort")]
+        on_conflict: ConflictPolicy,
+
+        /// Receive only the file with this name, may be given several times
+        #[arg(long, value_name = "NAME")]
+        select: Vec<String>,
+
+        /// Receive the files without asking first, e.g. in scripts
+        #[arg(short, long)]
+        yes: bool,
+
+        /// Seconds without data before a stalled transfer is closed, 0 to wait forever
+        #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+        idle_timeout: u64,
+        /// Times to reconnect after a suspend or a dropped connection, 0 to give up at once
+        #[arg(long, value_name = "COUNT", default_value_t = 5)]
+        reconnect: u32,
+        /// Access token for a relay that requires authentication
+        #[arg(long, value_name = "TOKEN")]
+        token: Option<String>,
+        /// Receive from a mailbox from the config file instead of by transfer name
+        #[arg(long, value_name = "LABEL", conflicts_with = "name")]
+        mailbox: Option<String>,
+        /// Save a text the sender sent as snippet.txt in the output directory instead of printing it
+        #[arg(long)]
+        save_text: bool,
+        /// Give the received files the modification times they had on the sender's side
+        #[arg(long)]
+        preserve_times: bool,
+        /// Fetch only the changed blocks of files that are overwritten with --on-conflict overwrite
+        #[arg(long)]
+        delta: bool,
+        /// Create the symbolic links the sender preserved, if they stay inside the output directory
+        #[arg(long)]
+        create_symlinks: bool,
+        /// Refuse transfers with a file larger than this, like 500M or 2GiB
+        #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+        max_file_size: Option<u64>,
+        /// Refuse transfers whose files are larger than this together, like 500M or 2GiB
+        #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+        max_total_size: Option<u64>,
+        /// Write the received files to stdout instead of the output directory, e.g. into a pipe
+        #[arg(long)]
+        stdout: bool,
+        /// Find the sender on the local network instead of asking the relay
+        #[arg(long, visible_alias = "local")]
+        discover: bool,
+        /// Do not try a direct connection through hole punching before falling back to the relay
+        #[arg(long)]
+        no_punch: bool,
+        /// Do not try QUIC before the WebSocket connection to a sender on the local network
+        #[arg(long)]
+        no_quic: bool,
+        /// Play a simulated transfer without any network and print its events as JSON lines, e.g. to build a user interface against
+        #[arg(long, value_enum, value_name = "SCENARIO")]
+        simulate: Option<Scenario>,
+        /// Seed of the simulated transfer, the same seed plays the same transfer
+        #[arg(long, default_value_t = 0, requires = "simulate")]
+        seed: u64,
+        /// Print the events of the transfer as JSON lines instead of text for humans, e.g. for scripts
+        #[arg(long, conflicts_with = "stdout")]
+        json: bool,
+        /// Show the transfer full screen with a progress bar for every file, q cancels it
+        #[arg(long, conflicts_with_all = ["json", "stdout"])]
+        tui: bool,
     },
     /// Start a relay server
     Serve {
@@ -56,6 +249,102 @@ pub enum Commands {
         /// The Listen address to run the relay server on
         #[arg(short, long)]
         listen_address: Option<String>,
+        /// PEM certificate chain to serve HTTPS and WSS with, instead of a reverse proxy
+        #[arg(long, value_name = "PATH", requires = "tls_key")]
+        tls_cert: Option<PathBuf>,
+        /// PEM private key of the certificate
+        #[arg(long, value_name = "PATH", requires = "tls_cert")]
+        tls_key: Option<PathBuf>,
+        /// Require OIDC access tokens issued by this issuer
+        #[arg(long, value_name = "URL")]
+        oidc_issuer: Option<String>,
+        /// The audience the access tokens must be issued for
+        #[arg(long, value_name = "AUDIENCE")]
+        oidc_audience: Option<String>,
+        /// The URL the issuer publishes its signing keys at
+        #[arg(long, value_name = "URL")]
+        oidc_jwks_url: Option<String>,
+        /// Transfers a user may have registered at a time, 0 for no limit
+        #[arg(long, value_name = "COUNT")]
+        user_quota: Option<usize>,
+        /// Seconds a room may wait for its members before it is closed, 0 to keep it [default: 3600]
+        #[arg(long, value_name = "SECONDS")]
+        room_ttl: Option<u64>,
+        /// ID of this relay among several behind a load balancer, issued as routing token
+        #[arg(long, value_name = "ID")]
+        instance_id: Option<String>,
+        /// SQLite database to keep the announced transfers in, so they survive a restart
+        #[arg(long, value_name = "PATH")]
+        database: Option<PathBuf>,
+        /// Rooms a client address may create per minute, 0 for no limit [default: 30]
+        #[arg(long, value_name = "COUNT")]
+        rate_limit_rooms: Option<u32>,
+        /// Uploads a client address may make per minute, 0 for no limit [default: 60]
+        #[arg(long, value_name = "COUNT")]
+        rate_limit_uploads: Option<u32>,
+        /// Transfer lookups a client address may make per minute, 0 for no limit [default: 60]
+        #[arg(long, value_name = "COUNT")]
+        rate_limit_downloads: Option<u32>,
+        /// Frames in flight from which on rooms of higher priority are served first, 0 to serve all alike
+        #[arg(long, value_name = "FRAMES", default_value_t = 256)]
+        qos_capacity: usize,
+        /// Client address or network like 203.0.113.0/24 to refuse, in addition to the blocklist of the config file
+        #[arg(long, value_name = "NETWORK")]
+        block: Vec<String>,
+        /// Token that authorizes POST /admin/reload, which reloads the config file like SIGHUP
+        #[arg(long, value_name = "TOKEN")]
+        admin_token: Option<String>,
+        /// Largest file in bytes senders may leave for a one-time download link, 0 to offer no links
+        #[arg(long, value_name = "BYTES", default_value_t = 0)]
+        link_size: u64,
+        /// Most clients a room may hold, the sender included [default: 65]
+        #[arg(long, value_name = "CLIENTS")]
+        max_room_size: Option<usize>,
+        /// Relay to look up transfers at that this relay does not know, like the public relay behind an internal one
+        #[arg(long, value_name = "URL")]
+        upstream: Option<String>,
+    },
+    /// Show whether a transfer is pending, completed or expired
+    Status {
+        /// Address of the relay server. Accepted formats are: 127.0.0.1:8080, [::1]:8080, example.com, wss://example.com
+        #[arg(short, long)]
+        relay: Option<String>,
+        /// Transfer code printed by the sender, like 482913-brave-tiger-42
+        #[arg(value_name = "Transfer_Name")]
+        name: String,
+        /// Access token for a relay that requires authentication, its history tells completed and expired transfers apart
+        #[arg(long, value_name = "TOKEN")]
+        token: Option<String>,
+    },
+    /// Provision mailboxes for unattended transfers between two machines
+    Mailbox {
+        #[command(subcommand)]
+        command: MailboxCommands,
+    },
+    /// Measure a transfer through a relay on this machine, e.g. to compare the speed of two builds
+    Bench {
+        /// Size of the random file to transfer, like 500M or 2GiB
+        #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "256MiB")]
+        size: u64,
+        /// Compress the file while it is sent
+        #[arg(long)]
+        compress: bool,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum MailboxCommands {
+    /// Create a new mailbox and print the code to add it on the other machine
+    Create {
+        /// Label to refer to the mailbox by
+        label: String,
+    },
+    /// Add a mailbox that was created on another machine
+    Add {
+        /// Label to refer to the mailbox by
+        label: String,
+        /// The code printed when the mailbox was created
+        code: String,
     },
 }
 
@@ -99,40 +388,282 @@ impl Args {
         // Retrieve the global configuration
         let cfg = &GLOBAL_CONFIG;
         debug!("args: {:#?}", self);
+        // Look up the relay profile the clients use
+        let profile = match self.profile.as_deref().map(find_profile) {
+            Some(Ok(profile)) => profile,
+            Some(Err(message)) => {
+                println!("Error: {message}");
+                return Ok(());
+            }
+            None => Profile::default(),
+        };
 
         // Match on the `command` field of `Args` to execute the corresponding command
         match &self.command {
             // Command to send files to the receiver or relay server
-            Some(Commands::Send { relay, files }) => {
-                // Create a string representation of the relay address
-                let relay_string: String = relay.as_deref().unwrap_or(&cfg.app_origin).to_string();
+            Some(Commands::Send {
+                relay,
+                files,
+                compress,
+                allow_special,
+                idle_timeout,
+                reconnect,
+                token,
+                mailbox,
+                priority,
+                words,
+                receivers,
+                text,
+                no_announce,
+                local,
+                no_punch,
+                no_quic,
+                local_port,
+                tar,
+                archive,
+                follow_symlinks,
+                skip_symlinks,
+                preserve_symlinks,
+                include,
+                exclude,
+                link,
+                simulate,
+                seed,
+                json,
+                tui,
+            }) => {
+                // Play a simulated transfer instead of a real one
+                if let Some(scenario) = simulate {
+                    match tui {
+                        true => show_simulation(Side::Sender, *scenario, *seed).await?,
+                        false => print_simulation(*scenario, *seed).await,
+                    }
+                    return Ok(());
+                }
+                // Leave the file on the relay instead of waiting for a receiver
+                if *link {
+                    let [file] = files.as_slice() else {
+                        println!("Error: --link takes exactly one file.");
+                        return Ok(());
+                    };
+                    match caesar_core::link::create_link(
+                        &relay_settings(relay, &profile).0,
+                        file,
+                        token_setting(token, &profile).as_deref(),
+                    )
+                    .await
+                    {
+                        Ok(url) => {
+                            println!(
+                                "Open this link in a browser to download {file:?}. It works once:"
+                            );
+                            println!("{url}");
+                        }
+                        Err(error) => println!("Error: {error}"),
+                    }
+                    return Ok(());
+                }
+                // Pick the relay and the ones to fall back to if it is down
+                let (relay_string, fallback_relays) = relay_settings(relay, &profile);
                 // Create Arc wrappers for the relay address and file paths
                 let relay_arc = Arc::new(relay_string);
                 let files_arc = Arc::new(files.to_vec());
-                // Generate a random name
-                let rand_name = generate_random_name();
-                // Start the sender with the generated name, relay address, and file paths
-                sender::start_sender(rand_name, relay_arc, files_arc).await;
+                // Send to the mailbox, or generate a random name
+                let (rand_name, key) = match mailbox.as_deref().map(find_mailbox) {
+                    Some(Ok((name, key))) => (name, Some(key)),
+                    Some(Err(message)) => {
+                        println!("Error: {message}");
+                        return Ok(());
+                    }
+                    None => (generate_name(*words), None),
+                };
+                // Read the text from stdin if asked to, without the final line break
+                let text = match text.as_deref() {
+                    Some("-") => match io::read_to_string(io::stdin()) {
+                        Ok(text) => Some(text.trim_end_matches(['\r', '\n']).to_string()),
+                        Err(error) => {
+                            println!("Error: Failed to read the text from stdin: {error}");
+                            return Ok(());
+                        }
+                    },
+                    text => text.map(String::from),
+                };
+                let filter = match PathFilter::new(include, exclude) {
+                    Ok(filter) => filter,
+                    Err(message) => {
+                        println!("Error: {message}");
+                        return Ok(());
+                    }
+                };
+                // Show the transfer full screen if asked to
+                let cancel = cancel_on_ctrl_c();
+                let screen = match tui {
+                    true => Some(Tui::start(Side::Sender, cancel.clone())?),
+                    false => None,
+                };
+                // Collect the options of the transfer
+                let options = SenderOptions {
+                    compress: *compress,
+                    allow_special: *allow_special,
+                    cancel,
+                    idle: IdleTimeout::new(Duration::from_secs(*idle_timeout)),
+                    reconnect: *reconnect,
+                    token: token_setting(token, &profile),
+                    route: None,
+                    fallback_relays,
+                    key,
+                    pin: None,
+                    priority: *priority,
+                    receivers: usize::from(*receivers),
+                    text,
+                    announce: !*no_announce,
+                    local_only: *local,
+                    punch: !*no_punch,
+                    quic: !*no_quic,
+                    local_port: *local_port,
+                    tar: *tar,
+                    archive: *archive,
+                    symlinks: symlink_policy(*follow_symlinks, *skip_symlinks, *preserve_symlinks),
+                    filter,
+                    usage: PathUsage::default(),
+                    progress: match &screen {
+                        Some(_) => Some(Tui::progress()),
+                        None => (!*json).then(|| progress_bars(*receivers > 1)),
+                    },
+                    events: match &screen {
+                        Some(screen) => Some(screen.events()),
+                        None => json.then(json_events),
+                    },
+                };
+                // Start the sender with the generated name, relay address, file paths and options
+                let result = sender::start_sender(rand_name, relay_arc, files_arc, options).await;
+                if let Some(screen) = screen {
+                    screen.finish(result.as_ref().err())?;
+                }
+                if let Err(error) = result {
+                    report(&error);
+                    if *json {
+                        print_event(&TransferEvent::Error {
+                            message: error.to_string(),
+                        });
+                    }
+                }
             }
             // Command to receive files from the sender with the matching password
             Some(Commands::Receive {
                 relay,
                 name,
+                output,
+                on_conflict,
+                select,
+                yes,
+                idle_timeout,
+                reconnect,
+                token,
+                mailbox,
+                save_text,
+                preserve_times,
+                delta,
+                create_symlinks,
+                max_file_size,
+                max_total_size,
+                stdout,
+                discover,
+                no_punch,
+                no_quic,
+                simulate,
+                seed,
+                json,
+                tui,
             }) => {
-                // Print the received transfer name
-                println!("Receive for {name:?}");
-                // Start the receiver with the current directory, relay address, and transfer name
-                let _ = receiver::start_receiver(
-                    ".".to_string(),
-                    relay.as_deref().unwrap_or(&cfg.app_origin),
-                    name,
+                // Play a simulated transfer instead of a real one
+                if let Some(scenario) = simulate {
+                    match tui {
+                        true => show_simulation(Side::Receiver, *scenario, *seed).await?,
+                        false => print_simulation(*scenario, *seed).await,
+                    }
+                    return Ok(());
+                }
+                // Receive from the mailbox, or by the given name
+                let (name, key) = match mailbox.as_deref().map(find_mailbox) {
+                    Some(Ok((name, key))) => (name, Some(key)),
+                    Some(Err(message)) => {
+                        println!("Error: {message}");
+                        return Ok(());
+                    }
+                    None => (name.clone().unwrap_or_default(), None),
+                };
+                // Print the received transfer name, off stdout if the files or the events go there
+                if *stdout || *json {
+                    eprintln!("Receive for {name:?}");
+                } else {
+                    println!("Receive for {name:?}");
+                }
+                // Start the receiver with the output directory, relay address, and transfer name
+                let cancel = cancel_on_ctrl_c();
+                let screen = match tui {
+                    true => Some(Tui::start(Side::Receiver, cancel.clone())?),
+                    false => None,
+                };
+                let (relay, fallback_relays) = relay_settings(relay, &profile);
+                let result = receiver::start_receiver(
+                    client_setting(output, &profile.output, "CAESAR_OUTPUT", "")
+                        .unwrap_or_else(|| ".".to_string()),
+                    &relay,
+                    &name,
+                    ReceiverOptions {
+                        on_conflict: *on_conflict,
+                        cancel: cancel.clone(),
+                        idle: IdleTimeout::new(Duration::from_secs(*idle_timeout)),
+                        reconnect: *reconnect,
+                        token: token_setting(token, &profile),
+                        route: None,
+                        fallback_relays,
+                        key,
+                        save_text: *save_text,
+                        preserve_times: *preserve_times,
+                        delta: *delta,
+                        links: *create_symlinks,
+                        max_file_size: *max_file_size,
+                        max_total_size: *max_total_size,
+                        stdout: *stdout,
+                        discover: *discover,
+                        punch: !*no_punch,
+                        quic: !*no_quic,
+                        select: (!select.is_empty())
+                            .then(|| FileSelection::names(select.clone())),
+                        confirm: match &screen {
+                            Some(screen) => (!*yes).then(|| screen.confirmation()),
+                            None => (!*yes).then(|| ask_to_accept(*stdout || *json, cancel)),
+                        },
+                        progress: match &screen {
+                            Some(_) => Some(Tui::progress()),
+                            None => (!*json).then(|| progress_bars(false)),
+                        },
+                        events: match &screen {
+                            Some(screen) => Some(screen.events()),
+                            None => json.then(json_events),
+                        },
+                    },
                 )
                 .await;
+                if let Some(screen) = screen {
+                    screen.finish(result.as_ref().err())?;
+                }
+                if let Err(error) = result {
+                    report(&error);
+                    if *json {
+                        print_event(&TransferEvent::Error {
+                            message: error.to_string(),
+                        });
+                    }
+                }
             }
             // Command to start a relay server
-            Some(Commands::Serve {
+            Some(command @ Commands::Serve {
                 port,
                 listen_address,
+                ..
             }) => {
                 // Create a string representation of the listen address
                 let address: String = listen_address
@@ -142,8 +673,94 @@ impl Args {
                 // Create an integer representation of the port
                 let port_value = port.unwrap_or(cfg.app_port.parse::<i32>().unwrap_or(0));
                 let port: i32 = port_value;
-                // Start the relay server with the port and listen address
-                relay::server::start_ws(&port, &address).await;
+                let mut config = match relay_config(command, cfg) {
+                    Ok(config) => config,
+                    Err(message) => {
+                        println!("Error: {message}");
+                        return Ok(());
+                    }
+                };
+                // Read the config file again on SIGHUP or POST /admin/reload, the flags keep their values
+                let command = command.clone();
+                config.reload = Some(ConfigSource::new(move || {
+                    let cfg: CaesarConfig = confy::load("caesar", "caesar").map_err(|e| e.to_string())?;
+                    relay_config(&command, &cfg)
+                }));
+                // Start the relay server with the port, listen address and optional features
+                relay::server::start_ws(&port, &address, config).await;
+            }
+            // Command to look up the state of a transfer on the relay
+            Some(Commands::Status { relay, name, token }) => {
+                let token = token_setting(token, &profile);
+                match status::transfer_state(
+                    &relay_setting(relay, &profile),
+                    name,
+                    token.as_deref(),
+                )
+                .await
+                {
+                    Ok(state) => println!("Transfer {name:?} is {state}."),
+                    Err(error) => println!("Error: {error}"),
+                }
+            }
+            // Command to provision mailboxes
+            Some(Commands::Mailbox { command }) => match command {
+                MailboxCommands::Create { label } => {
+                    let (name, key) = generate_mailbox();
+                    let mailbox = Mailbox {
+                        name,
+                        key: general_purpose::STANDARD.encode(key),
+                    };
+                    let code = format!("{}:{}", mailbox.name, mailbox.key);
+                    let path = config::store_mailbox(label, mailbox)?;
+                    println!("Created the mailbox '{label}' in {}.", path.display());
+                    println!("Add it on the other machine with the following command. Keep the code secret, it contains the key:");
+                    println!("  caesar mailbox add {label} {code}");
+                }
+                MailboxCommands::Add { label, code } => {
+                    // The code is the name and the key of the mailbox
+                    let mailbox = code.split_once(':').and_then(|(name, key)| {
+                        let valid = !name.is_empty() && general_purpose::STANDARD.decode(key).is_ok();
+                        valid.then(|| Mailbox {
+                            name: name.to_string(),
+                            key: key.to_string(),
+                        })
+                    });
+                    let Some(mailbox) = mailbox else {
+                        println!("Error: The mailbox code '{code}' is not valid.");
+                        return Ok(());
+                    };
+                    let path = config::store_mailbox(label, mailbox)?;
+                    println!("Added the mailbox '{label}' to {}.", path.display());
+                }
+            },
+            // Command to measure a transfer on the loopback interface
+            Some(Commands::Bench { size, compress }) => {
+                let options = BenchOptions {
+                    size: *size,
+                    compress: *compress,
+                };
+                let report = match bench::run(options).await {
+                    Ok(report) => report,
+                    Err(error) => {
+                        println!("Error: {error}");
+                        return Ok(());
+                    }
+                };
+                let format = NumberFormat::current();
+                println!(
+                    "Transferred {} in {:.2?} at {}.",
+                    format.size(report.size),
+                    report.elapsed,
+                    format.speed(report.size, report.elapsed)
+                );
+                if let (Some(cpu), Some(load)) = (report.cpu, report.cpu_load()) {
+                    println!(
+                        "Used {:.2?} of CPU time, {:.0}% of one core.",
+                        cpu,
+                        load * 100.0
+                    );
+                }
             }
             // No command provided
             None => {}
@@ -151,3 +768,497 @@ impl Args {
         Ok(())
     }
 }
+
+/// Prints the error that ended a transfer.
+///
+/// Errors of the transfer itself are already printed while it runs, so only the ones
+/// that happen before a connection is established are printed here.
+///
+/// # Arguments
+///
+/// * `error` - The error that ended the transfer.
+fn report(error: &CaesarError) {
+    debug!("Transfer failed: {error:?}");
+
+    if let CaesarError::RelayUnreachable { .. }
+    | CaesarError::TransferNotFound(_)
+    | CaesarError::Refused(_) = error
+    {
+        eprintln!("Error: {error}");
+    }
+}
+
+/// Plays a simulated transfer and prints each of its events as a line of JSON.
+///
+/// # Arguments
+///
+/// * `scenario` - How the transfer goes.
+/// * `seed` - The seed of the simulation.
+async fn print_simulation(scenario: Scenario, seed: u64) {
+    let mut events = simulate::simulate(scenario, seed);
+    while let Some(event) = events.recv().await {
+        // Stop quietly once the frontend closed the pipe
+        if !print_event(&event) {
+            break;
+        }
+    }
+}
+
+/// Plays a simulated transfer full screen.
+///
+/// # Arguments
+///
+/// * `side` - The side of the transfer that is shown.
+/// * `scenario` - How the transfer goes.
+/// * `seed` - The seed of the simulation.
+///
+/// # Returns
+///
+/// The error if the terminal could not be used.
+async fn show_simulation(side: Side, scenario: Scenario, seed: u64) -> io::Result<()> {
+    let cancel = CancellationToken::new();
+    let screen = Tui::start(side, cancel.clone())?;
+    let mut events = simulate::simulate(scenario, seed);
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Some(event) => screen.report(event),
+                None => break,
+            },
+            _ = cancel.cancelled() => break,
+        }
+    }
+    screen.finish(None)
+}
+
+/// Creates the event report of a transfer that prints every event as a line of JSON.
+///
+/// # Returns
+///
+/// The event report for the transfer options.
+fn json_events() -> EventReport {
+    EventReport::new(|event| {
+        print_event(event);
+    })
+}
+
+/// Prints an event of a transfer as a line of JSON.
+///
+/// # Arguments
+///
+/// * `event` - The event.
+///
+/// # Returns
+///
+/// Whether the line was written, which fails once the reader closed the pipe.
+fn print_event(event: &TransferEvent) -> bool {
+    writeln!(io::stdout(), "{}", serde_json::to_string(event).unwrap()).is_ok()
+}
+
+/// Collects the configuration of the relay from the flags of the serve command and the
+/// config file.
+///
+/// # Arguments
+///
+/// * `command` - The serve command. Its flags take precedence over the config file.
+/// * `cfg` - The config file.
+///
+/// # Returns
+///
+/// The configuration of the relay, or the message to print if it is invalid.
+fn relay_config(command: &Commands, cfg: &CaesarConfig) -> Result<RelayConfig, String> {
+    let Commands::Serve {
+        tls_cert,
+        tls_key,
+        oidc_issuer,
+        oidc_audience,
+        oidc_jwks_url,
+        user_quota,
+        room_ttl,
+        instance_id,
+        database,
+        rate_limit_rooms,
+        rate_limit_uploads,
+        rate_limit_downloads,
+        qos_capacity,
+        block,
+        admin_token,
+        link_size,
+        max_room_size,
+        upstream,
+        ..
+    } = command
+    else {
+        return Err("Only the serve command configures a relay.".into());
+    };
+
+    // Collect the certificate if the relay terminates TLS itself
+    let tls = tls_cert.clone().zip(tls_key.clone()).map(|(cert, key)| TlsConfig { cert, key });
+    // Collect the identity provider if the relay requires tokens
+    let setting = |flag: &Option<String>, config: &String| {
+        flag.clone().or_else(|| Some(config.clone()).filter(|c| !c.is_empty()))
+    };
+    let auth = match (
+        setting(oidc_issuer, &cfg.oidc_issuer),
+        setting(oidc_audience, &cfg.oidc_audience),
+        setting(oidc_jwks_url, &cfg.oidc_jwks_url),
+    ) {
+        (Some(issuer), Some(audience), Some(jwks_url)) => Some(AuthConfig {
+            issuer,
+            audience,
+            jwks_url,
+            quota: user_quota.unwrap_or(cfg.user_quota),
+        }),
+        (None, None, None) => None,
+        _ => return Err("The OIDC issuer, audience and JWKS URL must be set together.".into()),
+    };
+    // Block the networks of the config file and the command line
+    let blocklist = Blocklist::parse(&[cfg.blocklist.as_slice(), block.as_slice()].concat())?;
+    // Operators may only lower the size of the rooms
+    let max_room_size = max_room_size.unwrap_or(cfg.max_room_size);
+    if !(Room::DEFAULT_ROOM_SIZE..=Room::MAX_ROOM_SIZE).contains(&max_room_size) {
+        return Err(format!(
+            "The maximum room size must be between {} and {}.",
+            Room::DEFAULT_ROOM_SIZE,
+            Room::MAX_ROOM_SIZE
+        ));
+    }
+
+    Ok(RelayConfig {
+        tls,
+        auth,
+        room_ttl: Duration::from_secs(room_ttl.unwrap_or(cfg.room_ttl)),
+        instance: instance_id.clone(),
+        database: database
+            .clone()
+            .or_else(|| Some(PathBuf::from(&cfg.database)).filter(|_| !cfg.database.is_empty())),
+        rate_limits: RateLimits {
+            create_room: rate_limit_rooms.unwrap_or(cfg.rate_limit_rooms),
+            upload: rate_limit_uploads.unwrap_or(cfg.rate_limit_uploads),
+            download: rate_limit_downloads.unwrap_or(cfg.rate_limit_downloads),
+        },
+        qos_capacity: *qos_capacity,
+        blocklist,
+        admin_token: setting(admin_token, &cfg.admin_token),
+        reload: None,
+        link_size: *link_size,
+        max_room_size: Some(max_room_size),
+        upstream: setting(upstream, &cfg.upstream),
+    })
+}
+
+/// Picks a setting of the clients.
+///
+/// The flag takes precedence over the profile, the profile over the environment
+/// variable, and the variable over the rest of the config file. Empty values count as
+/// not set.
+///
+/// # Arguments
+///
+/// * `flag` - The value given on the command line.
+/// * `profile` - The value of the relay profile.
+/// * `var` - The name of the environment variable.
+/// * `config` - The value of the config file.
+///
+/// # Returns
+///
+/// The first value that is set, if any.
+fn client_setting(flag: &Option<String>, profile: &str, var: &str, config: &str) -> Option<String> {
+    [
+        flag.clone(),
+        Some(profile.to_string()),
+        env::var(var).ok(),
+        Some(config.to_string()),
+    ]
+    .into_iter()
+    .flatten()
+    .find(|value| !value.is_empty())
+}
+
+/// Returns the address of the relay.
+///
+/// # Arguments
+///
+/// * `relay` - The address given on the command line.
+/// * `profile` - The relay profile.
+///
+/// # Returns
+///
+/// The given address, or the one of the profile, `CAESAR_RELAY` or the config file.
+fn relay_setting(relay: &Option<String>, profile: &Profile) -> String {
+    client_setting(
+        relay,
+        &profile.url,
+        "CAESAR_RELAY",
+        &GLOBAL_CONFIG.app_origin,
+    )
+    .unwrap_or_default()
+}
+
+/// Returns the relays to try in order.
+///
+/// # Arguments
+///
+/// * `relays` - The addresses given on the command line.
+/// * `profile` - The relay profile.
+///
+/// # Returns
+///
+/// The relay tried first and the ones to fall back to. Without addresses on the
+/// command line, these are the relay of `relay_setting` and the fallback relays of the
+/// config file.
+fn relay_settings(relays: &[String], profile: &Profile) -> (String, Vec<String>) {
+    match relays.split_first() {
+        Some((relay, fallbacks)) => (relay.clone(), fallbacks.to_vec()),
+        None => (
+            relay_setting(&None, profile),
+            GLOBAL_CONFIG.fallback_relays.clone(),
+        ),
+    }
+}
+
+/// Returns the access token for the relay.
+///
+/// # Arguments
+///
+/// * `token` - The token given on the command line.
+/// * `profile` - The relay profile.
+///
+/// # Returns
+///
+/// The given token, or the one of the profile, `CAESAR_TOKEN` or the config file.
+fn token_setting(token: &Option<String>, profile: &Profile) -> Option<String> {
+    client_setting(
+        token,
+        &profile.token,
+        "CAESAR_TOKEN",
+        &GLOBAL_CONFIG.app_token,
+    )
+}
+
+/// Turns the symlink flags of `send` into the policy of the sender.
+///
+/// # Arguments
+///
+/// * `follow` - Whether `--follow-symlinks` was given.
+/// * `skip` - Whether `--skip-symlinks` was given.
+/// * `preserve` - Whether `--preserve-symlinks` was given.
+///
+/// # Returns
+///
+/// The policy of the given flag, or `None` for the default if none was given.
+fn symlink_policy(follow: bool, skip: bool, preserve: bool) -> Option<SymlinkPolicy> {
+    match (follow, skip, preserve) {
+        (true, _, _) => Some(SymlinkPolicy::Follow),
+        (_, true, _) => Some(SymlinkPolicy::Skip),
+        (_, _, true) => Some(SymlinkPolicy::Preserve),
+        _ => None,
+    }
+}
+
+/// Looks up a relay profile in the config file.
+///
+/// # Arguments
+///
+/// * `name` - The name of the profile.
+///
+/// # Returns
+///
+/// The profile, or the message to print if there is none by that name.
+fn find_profile(name: &str) -> Result<Profile, String> {
+    GLOBAL_CONFIG
+        .profiles
+        .get(name)
+        .cloned()
+        .ok_or_else(|| format!("There is no profile '{name}' in the config file."))
+}
+
+/// Looks up a mailbox in the config file.
+///
+/// # Arguments
+///
+/// * `label` - The label of the mailbox.
+///
+/// # Returns
+///
+/// The name and the HMAC key of the mailbox, or the message to print if it cannot be
+/// used.
+fn find_mailbox(label: &str) -> Result<(String, Vec<u8>), String> {
+    let Some(mailbox) = GLOBAL_CONFIG.mailboxes.get(label) else {
+        return Err(format!("There is no mailbox '{label}' in the config file."));
+    };
+    let key = general_purpose::STANDARD
+        .decode(&mailbox.key)
+        .map_err(|_| format!("The key of the mailbox '{label}' is not valid base64."))?;
+    Ok((mailbox.name.clone(), key))
+}
+
+/// Asks on the terminal whether to receive the files the sender offers.
+///
+/// The files are listed with their sizes before the question, which is declined
+/// unless it is answered with yes. Without a terminal to answer on, e.g. in a
+/// script, nothing is received. Pressing Ctrl-C declines as well.
+///
+/// # Arguments
+///
+/// * `stdout` - Whether the received files go to stdout, the question then goes to stderr.
+/// * `cancel` - The cancellation token of the transfer.
+///
+/// # Returns
+///
+/// The confirmation for the receiver options.
+fn ask_to_accept(stdout: bool, cancel: CancellationToken) -> Confirmation {
+    Confirmation::new(move |files: &[OfferedFile]| {
+        let format = NumberFormat::current();
+        let mut question = String::new();
+        for file in files {
+            let size = match file.stream {
+                true => "unknown size".to_string(),
+                false => format.size(file.size),
+            };
+            question.push_str(&format!("  {} ({})\n", file.name, size));
+        }
+        let total = files.iter().map(|file| file.size).sum();
+        question.push_str(&format!(
+            "Accept {} file(s), {}? [y/N] ",
+            files.len(),
+            format.size(total)
+        ));
+
+        // Reading the answer blocks, so other tasks move to the remaining workers
+        tokio::task::block_in_place(|| {
+            let written = if stdout {
+                write!(io::stderr(), "{question}").and_then(|_| io::stderr().flush())
+            } else {
+                write!(io::stdout(), "{question}").and_then(|_| io::stdout().flush())
+            };
+            if written.is_err() {
+                return false;
+            }
+
+            // Stdin cannot be interrupted, so it is read on a thread of its own while
+            // this one watches for Ctrl-C
+            let (answers, answer) = mpsc::channel();
+            thread::spawn(move || {
+                let mut line = String::new();
+                let _ = answers.send(io::stdin().read_line(&mut line).map(|_| line));
+            });
+            loop {
+                match answer.recv_timeout(Duration::from_millis(100)) {
+                    Ok(Ok(line)) => {
+                        return matches!(line.trim().to_lowercase().as_str(), "y" | "yes")
+                    }
+                    Err(mpsc::RecvTimeoutError::Timeout) if !cancel.is_cancelled() => {}
+                    _ => return false,
+                }
+            }
+        })
+    })
+}
+
+/// Creates a token that is cancelled when the user presses Ctrl-C.
+///
+/// The transfer then leaves the room and cleans up instead of being killed mid-write.
+///
+/// # Returns
+///
+/// The cancellation token for the transfer options.
+fn cancel_on_ctrl_c() -> CancellationToken {
+    let cancel = CancellationToken::new();
+    let token = cancel.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            token.cancel();
+        }
+    });
+    cancel
+}
+
+/// Draws a progress bar for every file of a transfer.
+///
+/// A bar tells the time left for its file and, while other files follow it, for all
+/// of them. A finished file is left as a line with its size, and a stream, whose size is
+/// unknown until it ends, gets a spinner instead of a bar. The bars are drawn to stderr, so nothing is
+/// drawn when it is not a terminal.
+///
+/// # Arguments
+///
+/// * `several` - Whether the files are sent to several receivers, whose bars are told
+///   apart by their number.
+///
+/// # Returns
+///
+/// The progress report for the transfer options.
+fn progress_bars(several: bool) -> ProgressReport {
+    let bars = MultiProgress::new();
+    let active = Mutex::new(HashMap::new());
+    ProgressReport::new(move |progress: &FileProgress| {
+        let mut active = active.lock().unwrap();
+        let bar = active
+            .entry((progress.peer, progress.index))
+            .or_insert_with(|| {
+                let bar = if progress.size > 0 {
+                    ProgressBar::new(progress.size).with_style(
+                        ProgressStyle::with_template(
+                            "{msg} [{bar:30}] {binary_bytes}/{binary_total_bytes} ({binary_bytes_per_sec}, {eta} left{prefix})",
+                        )
+                        .unwrap()
+                        .progress_chars("=> "),
+                    )
+                } else {
+                    ProgressBar::new_spinner().with_style(
+                        ProgressStyle::with_template(match progress.transferred {
+                            Some(_) => "{spinner} {msg} {binary_bytes} ({binary_bytes_per_sec})",
+                            None => "{spinner} {msg} {elapsed}",
+                        })
+                        .unwrap(),
+                    )
+                };
+                let name = match several {
+                    true => format!("{} to receiver {}", progress.name, progress.peer + 1),
+                    false => progress.name.clone(),
+                };
+
+                // A resumed file starts where the last transfer stopped, which must not
+                // count towards the speed
+                let bar = bar
+                    .with_message(name)
+                    .with_position(progress.transferred.unwrap_or(0));
+                bar.reset_eta();
+                let bar = bars.add(bar);
+                if progress.size == 0 {
+                    bar.enable_steady_tick(Duration::from_millis(100));
+                }
+                bar
+            });
+
+        if let Some(transferred) = progress.transferred {
+            bar.set_position(transferred);
+        }
+        // The bar of the current file also tells how long the files after it take
+        match progress.remaining {
+            Some(remaining) => bar.set_prefix(format!(
+                ", {} for all files",
+                NumberFormat::current().duration(remaining)
+            )),
+            None => bar.set_prefix(""),
+        }
+        // A finished bar becomes a line above the others, so the messages after the
+        // transfer start on a line of their own
+        if progress.done {
+            bar.finish_and_clear();
+            bars.remove(bar);
+            let format = NumberFormat::current();
+            let _ = bars.println(match progress.transferred {
+                Some(transferred) => format!(
+                    "{}: {} in {}",
+                    bar.message(),
+                    format.size(transferred),
+                    format.duration(bar.elapsed())
+                ),
+                None => format!("{}: done in {}", bar.message(), format.duration(bar.elapsed())),
+            });
+            active.remove(&(progress.peer, progress.index));
+        }
+    })
+}
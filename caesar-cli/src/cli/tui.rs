@@ -0,0 +1,647 @@
+//! A full screen view of a transfer in the terminal.
+//!
+//! `--tui` shows the transfer code, the verification codes, a progress bar for every
+//! file and every receiver, and what happened so far, in place of the printed lines.
+//! The view is drawn from the events the clients report, the same ones `--json`
+//! prints, so a simulated transfer is shown just like a real one. It runs on a thread
+//! of its own, which also reads the keys: `q` cancels the transfer and closes the view
+//! once it has ended, and the files the receiver is offered are accepted with `y`.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, IsTerminal, Stdout},
+    sync::mpsc::{self, Receiver, Sender, TryRecvError},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use caesar_core::{
+    error::CaesarError,
+    event::{EventReport, ListedFile, TransferEvent},
+    format::NumberFormat,
+    progress::ProgressReport,
+    receiver::select::{Confirmation, OfferedFile},
+};
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use ratatui::{
+    backend::CrosstermBackend,
+    layout::{Constraint, Direction, Layout},
+    style::{Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, Paragraph, Wrap},
+    Frame, Terminal,
+};
+use tokio_util::sync::CancellationToken;
+
+/// How long the view waits for a key before it is drawn again.
+const TICK: Duration = Duration::from_millis(100);
+
+/// The most events the view keeps.
+const MAX_LOG_LINES: usize = 100;
+
+/// The side of the transfer the view shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    /// The files are sent.
+    Sender,
+    /// The files are received.
+    Receiver,
+}
+
+/// Something the view is told by the transfer.
+enum Update {
+    /// The transfer reported an event.
+    Event(TransferEvent),
+    /// The receiver asks whether to receive the offered files.
+    Prompt(Prompt),
+    /// The transfer ended, with the error that ended it if any.
+    Finished(Option<String>),
+}
+
+/// A question whether to receive the offered files.
+struct Prompt {
+    /// The files that would be received.
+    files: Vec<OfferedFile>,
+    /// Where the answer goes.
+    reply: Sender<bool>,
+}
+
+/// The running view of a transfer.
+pub struct Tui {
+    /// Where the updates of the transfer go.
+    updates: Sender<Update>,
+    /// The thread that draws the view and reads the keys.
+    screen: JoinHandle<io::Result<()>>,
+}
+
+impl Tui {
+    /// Switches the terminal to the view of a transfer.
+    ///
+    /// # Arguments
+    ///
+    /// * `side` - The side of the transfer.
+    /// * `cancel` - The cancellation token of the transfer, which `q` triggers.
+    ///
+    /// # Returns
+    ///
+    /// The view, or the error if stdout is no terminal or cannot be switched.
+    pub fn start(side: Side, cancel: CancellationToken) -> io::Result<Tui> {
+        if !io::stdout().is_terminal() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "The terminal interface needs a terminal.",
+            ));
+        }
+
+        let screen = Screen::enter()?;
+        let (updates, received) = mpsc::channel();
+        let view = View::new(side, cancel);
+        let screen = thread::spawn(move || run(screen, view, received));
+        Ok(Tui { updates, screen })
+    }
+
+    /// Returns the event report that shows the events of the transfer.
+    pub fn events(&self) -> EventReport {
+        let updates = self.updates.clone();
+        EventReport::new(move |event| {
+            let _ = updates.send(Update::Event(event.clone()));
+        })
+    }
+
+    /// Returns the progress report of the transfer options.
+    ///
+    /// The progress is shown from the events, so the report only keeps the clients
+    /// from printing it.
+    pub fn progress() -> ProgressReport {
+        ProgressReport::new(|_| {})
+    }
+
+    /// Returns the confirmation that asks in the view whether to receive the files.
+    ///
+    /// Cancelling the transfer declines them.
+    pub fn confirmation(&self) -> Confirmation {
+        let updates = self.updates.clone();
+        Confirmation::new(move |files: &[OfferedFile]| {
+            let (reply, answer) = mpsc::channel();
+            let prompt = Prompt {
+                files: files.to_vec(),
+                reply,
+            };
+            if updates.send(Update::Prompt(prompt)).is_err() {
+                return false;
+            }
+
+            // Waiting for the answer blocks, so other tasks move to the remaining workers
+            tokio::task::block_in_place(|| answer.recv().unwrap_or(false))
+        })
+    }
+
+    /// Shows an event, e.g. of a simulated transfer.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event.
+    pub fn report(&self, event: TransferEvent) {
+        let _ = self.updates.send(Update::Event(event));
+    }
+
+    /// Shows how the transfer ended and waits until the view is closed.
+    ///
+    /// # Arguments
+    ///
+    /// * `error` - The error that ended the transfer, if any.
+    ///
+    /// # Returns
+    ///
+    /// The error if the terminal could not be drawn on or restored.
+    pub fn finish(self, error: Option<&CaesarError>) -> io::Result<()> {
+        let _ = self
+            .updates
+            .send(Update::Finished(error.map(ToString::to_string)));
+        tokio::task::block_in_place(|| self.screen.join())
+            .unwrap_or_else(|_| Err(io::Error::other("The terminal interface crashed.")))
+    }
+}
+
+/// The terminal while it shows the view. It is restored when this is dropped, also
+/// when the thread of the view panics.
+struct Screen(Terminal<CrosstermBackend<Stdout>>);
+
+impl Screen {
+    /// Switches the terminal to raw mode and the alternate screen.
+    fn enter() -> io::Result<Screen> {
+        enable_raw_mode()?;
+        let mut stdout = io::stdout();
+        if let Err(error) = execute!(stdout, EnterAlternateScreen) {
+            let _ = disable_raw_mode();
+            return Err(error);
+        }
+        Terminal::new(CrosstermBackend::new(stdout)).map(Screen)
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = execute!(self.0.backend_mut(), LeaveAlternateScreen);
+        let _ = self.0.show_cursor();
+    }
+}
+
+/// Draws the view until it is closed.
+///
+/// # Arguments
+///
+/// * `screen` - The terminal.
+/// * `view` - The state of the view.
+/// * `updates` - The updates of the transfer.
+///
+/// # Returns
+///
+/// The error if the terminal could not be drawn on.
+fn run(mut screen: Screen, mut view: View, updates: Receiver<Update>) -> io::Result<()> {
+    loop {
+        let mut repaint = false;
+        loop {
+            match updates.try_recv() {
+                Ok(update) => repaint |= view.apply(update),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    view.finished = true;
+                    break;
+                }
+            }
+        }
+
+        // The clients print their messages to stderr besides the events, which would
+        // stay on the screen until it is drawn from scratch
+        if repaint {
+            screen.0.clear()?;
+        }
+        screen.0.draw(|frame| view.render(frame))?;
+
+        if event::poll(TICK)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && view.on_key(key) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// What the view knows about the transfer.
+struct View {
+    /// The side of the transfer.
+    side: Side,
+    /// The cancellation token of the transfer.
+    cancel: CancellationToken,
+    /// The transfer code the receivers enter, only known to the sender.
+    code: Option<String>,
+    /// The ID of the room at the relay, only known to the sender.
+    room: Option<String>,
+    /// The verification codes, for each receiver if there are several.
+    verifications: BTreeMap<Option<usize>, String>,
+    /// The files of the transfer.
+    files: Vec<ListedFile>,
+    /// The bytes that arrived and the size of every file, by its index and receiver.
+    progress: BTreeMap<(usize, Option<usize>), (u64, u64)>,
+    /// What happened so far, oldest first.
+    log: Vec<String>,
+    /// The question the receiver waits for an answer to.
+    prompt: Option<Prompt>,
+    /// How the transfer ended, once it has.
+    outcome: Option<Result<String, String>>,
+    /// Whether the transfer has ended.
+    finished: bool,
+    /// How the numbers are written.
+    format: NumberFormat,
+}
+
+impl View {
+    /// Creates the view of a transfer that has not started yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `side` - The side of the transfer.
+    /// * `cancel` - The cancellation token of the transfer.
+    fn new(side: Side, cancel: CancellationToken) -> View {
+        View {
+            side,
+            cancel,
+            code: None,
+            room: None,
+            verifications: BTreeMap::new(),
+            files: Vec::new(),
+            progress: BTreeMap::new(),
+            log: Vec::new(),
+            prompt: None,
+            outcome: None,
+            finished: false,
+            format: NumberFormat::current(),
+        }
+    }
+
+    /// Takes in an update of the transfer.
+    ///
+    /// # Arguments
+    ///
+    /// * `update` - The update.
+    ///
+    /// # Returns
+    ///
+    /// Whether the screen has to be drawn from scratch, as the clients may have
+    /// printed a message along with the update.
+    fn apply(&mut self, update: Update) -> bool {
+        match update {
+            Update::Event(TransferEvent::Progress {
+                file,
+                receiver,
+                transferred,
+                size,
+            }) => {
+                self.progress.insert((file, receiver), (transferred, size));
+                return false;
+            }
+            Update::Event(event) => self.on_event(event),
+            Update::Prompt(prompt) => {
+                // A cancelled transfer declines the files right away
+                if self.cancel.is_cancelled() {
+                    let _ = prompt.reply.send(false);
+                } else {
+                    self.prompt = Some(prompt);
+                }
+            }
+            Update::Finished(error) => {
+                self.finished = true;
+                if let Some(error) = &error {
+                    self.record(format!("Error: {error}"));
+                }
+                // The events may already have told how the transfer ended
+                if self.outcome.is_none() {
+                    let error = error.unwrap_or_else(|| "The transfer has ended.".into());
+                    self.outcome = Some(Err(error));
+                }
+            }
+        }
+        true
+    }
+
+    /// Takes in an event of the transfer other than progress.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event.
+    fn on_event(&mut self, event: TransferEvent) {
+        match event {
+            TransferEvent::RoomCreated { room_id, code } => {
+                self.record(format!("Created the room {room_id}."));
+                self.room = Some(room_id);
+                self.code = Some(code);
+            }
+            TransferEvent::Handshake { name } => {
+                self.record(format!("Connected for {name}."));
+            }
+            TransferEvent::Verification { code, receiver } => {
+                match receiver {
+                    Some(receiver) => {
+                        self.record(format!("Receiver {} connected.", receiver + 1))
+                    }
+                    None => self.record("Connected.".into()),
+                }
+                self.verifications.insert(receiver, code);
+            }
+            TransferEvent::List { files } => {
+                let size = files.iter().map(|file| file.size).sum();
+                self.record(format!(
+                    "Listed {} file(s), {}.",
+                    files.len(),
+                    self.format.size(size)
+                ));
+                self.files = files;
+            }
+            TransferEvent::Progress { .. } => {}
+            TransferEvent::Retry { file, reason } => {
+                let name = self.file_name(file);
+                self.record(format!("Sending {name} again: {reason}"));
+                self.progress.retain(|&(index, _), _| index != file);
+            }
+            TransferEvent::Reconnect { attempt } => {
+                self.record(format!("Reconnecting, attempt {attempt}."));
+            }
+            TransferEvent::Text { text } => {
+                self.record(format!("Text: {text}"));
+            }
+            TransferEvent::Error { message } => {
+                self.record(format!("Error: {message}"));
+                self.outcome = Some(Err(message));
+            }
+            TransferEvent::Complete { files, bytes } => {
+                let outcome = format!(
+                    "Transfer has completed: {files} file(s), {}.",
+                    self.format.size(bytes)
+                );
+                self.record(outcome.clone());
+                self.outcome = Some(Ok(outcome));
+            }
+        }
+    }
+
+    /// Adds a line to the events that happened.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - The line.
+    fn record(&mut self, line: String) {
+        self.log.push(line);
+        if self.log.len() > MAX_LOG_LINES {
+            self.log.remove(0);
+        }
+    }
+
+    /// Handles a key.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key that was pressed.
+    ///
+    /// # Returns
+    ///
+    /// Whether the view is closed.
+    fn on_key(&mut self, key: KeyEvent) -> bool {
+        let quit = matches!(key.code, KeyCode::Char('q') | KeyCode::Esc)
+            || (key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL));
+
+        if let Some(prompt) = self.prompt.take() {
+            let accepted = match key.code {
+                KeyCode::Char('y' | 'Y') => true,
+                KeyCode::Char('n' | 'N') => false,
+                _ if quit => false,
+                _ => {
+                    self.prompt = Some(prompt);
+                    return false;
+                }
+            };
+            let _ = prompt.reply.send(accepted);
+            self.record(match accepted {
+                true => "Accepted the files.".into(),
+                false => "Declined the files.".into(),
+            });
+            return false;
+        }
+
+        if !quit {
+            return false;
+        }
+        // A transfer that does not end after it was cancelled can still be left
+        if self.finished || self.cancel.is_cancelled() {
+            return true;
+        }
+        self.cancel.cancel();
+        self.record("Cancelling the transfer.".into());
+        false
+    }
+
+    /// Returns the name of a file of the transfer.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the file.
+    fn file_name(&self, index: usize) -> String {
+        match self.files.get(index) {
+            Some(file) => file.name.clone(),
+            None => format!("file {}", index + 1),
+        }
+    }
+
+    /// Returns what the transfer is doing right now.
+    fn status(&self) -> Line<'static> {
+        let bold = Style::default().add_modifier(Modifier::BOLD);
+        let status = match (&self.outcome, &self.prompt) {
+            (Some(Ok(outcome)), _) => outcome.clone(),
+            (Some(Err(error)), _) => format!("Error: {error}"),
+            (None, Some(prompt)) => {
+                let size = prompt.files.iter().map(|file| file.size).sum();
+                format!(
+                    "Accept {} file(s), {}?",
+                    prompt.files.len(),
+                    self.format.size(size)
+                )
+            }
+            (None, None) if self.cancel.is_cancelled() => "Cancelling...".into(),
+            (None, None) if !self.progress.is_empty() => "Transferring...".into(),
+            (None, None) if !self.verifications.is_empty() => "Connected.".into(),
+            (None, None) if self.code.is_some() => "Waiting for the receiver...".into(),
+            (None, None) => "Connecting...".into(),
+        };
+        Line::from(Span::styled(status, bold))
+    }
+
+    /// Returns the lines above the files: the codes and what the transfer is doing.
+    fn header(&self) -> Vec<Line<'static>> {
+        let mut lines = vec![self.status()];
+        if let Some(code) = &self.code {
+            lines.push(Line::from(vec![
+                Span::raw("Transfer code: "),
+                Span::styled(code.clone(), Style::default().add_modifier(Modifier::BOLD)),
+            ]));
+        }
+        if let Some(room) = &self.room {
+            lines.push(Line::from(format!("Room: {room}")));
+        }
+        for (receiver, code) in &self.verifications {
+            lines.push(Line::from(match receiver {
+                Some(receiver) => format!("Verification code of receiver {}: {code}", receiver + 1),
+                None => format!("Verification code: {code}"),
+            }));
+        }
+        lines
+    }
+
+    /// Returns a line with a progress bar for every file and receiver.
+    ///
+    /// # Arguments
+    ///
+    /// * `width` - The width the lines are drawn in.
+    ///
+    /// # Returns
+    ///
+    /// The lines, and the index of the first one of a file that has not arrived yet.
+    fn file_lines(&self, width: usize) -> (Vec<Line<'static>>, usize) {
+        // The files of the list, or the ones progress was reported for
+        let mut rows: Vec<(String, Option<(u64, u64)>)> = Vec::new();
+        let count = self
+            .progress
+            .keys()
+            .map(|&(index, _)| index + 1)
+            .max()
+            .unwrap_or(0)
+            .max(self.files.len());
+        for index in 0..count {
+            let name = self.file_name(index);
+            let mut reported = self
+                .progress
+                .range((index, None)..=(index, Some(usize::MAX)))
+                .peekable();
+            if reported.peek().is_none() {
+                rows.push((name, None));
+            }
+            for (&(_, receiver), &progress) in reported {
+                let name = match receiver {
+                    Some(receiver) => format!("{name} to receiver {}", receiver + 1),
+                    None => name.clone(),
+                };
+                rows.push((name, Some(progress)));
+            }
+        }
+
+        let name_width = rows
+            .iter()
+            .map(|(name, _)| name.chars().count())
+            .max()
+            .unwrap_or(0)
+            .min(width / 3);
+        let bar_width = width.saturating_sub(name_width + 30).clamp(10, 40);
+        let first = rows
+            .iter()
+            .position(|(_, progress)| !progress.is_some_and(|(done, size)| size > 0 && done >= size))
+            .unwrap_or(0);
+        let lines = rows
+            .into_iter()
+            .map(|(name, progress)| {
+                let name: String = name.chars().take(name_width).collect();
+                let state = match progress {
+                    None => "waiting".to_string(),
+                    Some((transferred, 0)) => self.format.size(transferred),
+                    Some((transferred, size)) => format!(
+                        "[{}] {}/{}",
+                        bar(transferred, size, bar_width),
+                        self.format.size(transferred),
+                        self.format.size(size)
+                    ),
+                };
+                Line::from(format!("{name:<name_width$}  {state}"))
+            })
+            .collect();
+        (lines, first)
+    }
+
+    /// Returns the keys that can be pressed right now.
+    fn keys(&self) -> Line<'static> {
+        let keys = match (&self.prompt, self.finished) {
+            (Some(_), _) => "y: accept  n: decline",
+            (None, true) => "q: close",
+            (None, false) if self.cancel.is_cancelled() => "q: leave without waiting",
+            (None, false) => "q: cancel the transfer",
+        };
+        Line::from(Span::styled(keys, Style::default().add_modifier(Modifier::DIM)))
+    }
+
+    /// Draws the view.
+    ///
+    /// # Arguments
+    ///
+    /// * `frame` - The frame to draw on.
+    fn render(&self, frame: &mut Frame) {
+        let header = self.header();
+        let areas = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Length(header.len() as u16 + 2),
+                Constraint::Min(3),
+                Constraint::Length(8),
+                Constraint::Length(1),
+            ])
+            .split(frame.size());
+
+        let title = match self.side {
+            Side::Sender => " Sending ",
+            Side::Receiver => " Receiving ",
+        };
+        frame.render_widget(
+            Paragraph::new(header).block(Block::default().borders(Borders::ALL).title(title)),
+            areas[0],
+        );
+
+        // Files that have arrived scroll out of the view while others follow them
+        let height = usize::from(areas[1].height.saturating_sub(2));
+        let (files, first) = self.file_lines(usize::from(areas[1].width.saturating_sub(2)));
+        let scroll = first.min(files.len().saturating_sub(height));
+        frame.render_widget(
+            Paragraph::new(files)
+                .scroll((scroll as u16, 0))
+                .block(Block::default().borders(Borders::ALL).title(" Files ")),
+            areas[1],
+        );
+
+        let height = usize::from(areas[2].height.saturating_sub(2));
+        let log: Vec<Line> = self.log[self.log.len().saturating_sub(height)..]
+            .iter()
+            .map(|line| Line::from(line.clone()))
+            .collect();
+        frame.render_widget(
+            Paragraph::new(log)
+                .wrap(Wrap { trim: false })
+                .block(Block::default().borders(Borders::ALL).title(" Events ")),
+            areas[2],
+        );
+
+        frame.render_widget(Paragraph::new(self.keys()), areas[3]);
+    }
+}
+
+/// Draws a progress bar as text.
+///
+/// # Arguments
+///
+/// * `transferred` - The bytes that arrived.
+/// * `size` - The size of the file, more than zero.
+/// * `width` - The width of the bar.
+fn bar(transferred: u64, size: u64, width: usize) -> String {
+    let filled = (u128::from(transferred.min(size)) * width as u128 / u128::from(size)) as usize;
+    format!("{}{}", "=".repeat(filled), " ".repeat(width - filled))
+}
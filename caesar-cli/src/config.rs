@@ -1,5 +1,11 @@
+use caesar_core::relay::room::Room;
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
 
 /// Represents the configuration settings for the Caesar application.
 ///
@@ -37,8 +43,133 @@ pub struct CaesarConfig {
     /// This is typically a string representation of a logging level, such as "info",
     /// "debug", or "error".
     pub rust_log: String,
+
+    /// The access token sent to a relay that requires authentication.
+    ///
+    /// An empty string means no token is sent.
+    #[serde(default)]
+    pub app_token: String,
+
+    /// The issuer of the OIDC access tokens the relay accepts.
+    ///
+    /// An empty string means the relay is open to everyone.
+    #[serde(default)]
+    pub oidc_issuer: String,
+
+    /// The audience the OIDC access tokens must be issued for.
+    #[serde(default)]
+    pub oidc_audience: String,
+
+    /// The URL the OIDC issuer publishes its signing keys at.
+    #[serde(default)]
+    pub oidc_jwks_url: String,
+
+    /// How many transfers a user may have registered at the relay at a time.
+    ///
+    /// Zero means no limit.
+    #[serde(default)]
+    pub user_quota: usize,
+
+    /// The SQLite database the relay keeps the announced transfers in.
+    ///
+    /// An empty string means they only live in memory.
+    #[serde(default)]
+    pub database: String,
+
+    /// How many seconds a room may wait at the relay for its members before it is closed.
+    ///
+    /// Zero keeps rooms until their members leave.
+    #[serde(default = "default_room_ttl")]
+    pub room_ttl: u64,
+
+    /// How many rooms a client address may create at the relay per minute.
+    ///
+    /// Zero means no limit, like for the other rate limits.
+    #[serde(default = "default_rate_limit_rooms")]
+    pub rate_limit_rooms: u32,
+
+    /// How many uploads a client address may make to the relay per minute.
+    #[serde(default = "default_rate_limit_transfers")]
+    pub rate_limit_uploads: u32,
+
+    /// How many transfer lookups a client address may make at the relay per minute.
+    #[serde(default = "default_rate_limit_transfers")]
+    pub rate_limit_downloads: u32,
+
+    /// The most clients a room at the relay may hold, the sender included.
+    ///
+    /// It may be lowered from the default of 65, a sender with 64 receivers.
+    #[serde(default = "default_max_room_size")]
+    pub max_room_size: usize,
+
+    /// The client addresses and networks like "203.0.113.0/24" the relay refuses to serve.
+    #[serde(default)]
+    pub blocklist: Vec<String>,
+
+    /// The token that authorizes reloading the relay's configuration over HTTP.
+    ///
+    /// An empty string means it is only reloaded on SIGHUP.
+    #[serde(default)]
+    pub admin_token: String,
+
+    /// The relay that transfers unknown to the relay are looked up at, like the public
+    /// relay behind an internal one.
+    ///
+    /// An empty string means unknown transfers are not found.
+    #[serde(default)]
+    pub upstream: String,
+
+    /// The relays the clients fall back to in this order when `app_origin` or the relay
+    /// of the profile is down.
+    #[serde(default)]
+    pub fallback_relays: Vec<String>,
+
+    /// The relays the clients can pick with `--profile`, where the key is the name of
+    /// the profile.
+    ///
+    /// It and the mailboxes have to stay the last fields, as TOML writes tables after
+    /// plain values.
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+
+    /// The mailboxes provisioned on this machine, where the key is the label the user
+    /// refers to them by.
+    #[serde(default)]
+    pub mailboxes: BTreeMap<String, Mailbox>,
 }
 
+/// The settings of a relay the clients use together, like the relay at work.
+///
+/// An empty setting is taken from the environment or the rest of the config file
+/// instead.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Profile {
+    /// The address of the relay.
+    #[serde(default)]
+    pub url: String,
+
+    /// The access token sent to the relay.
+    #[serde(default)]
+    pub token: String,
+
+    /// The directory received files are saved in.
+    #[serde(default)]
+    pub output: String,
+}
+
+/// A transfer mailbox shared by two machines for unattended transfers.
+///
+/// Both machines hold the same name and key, so cron jobs can send and receive without
+/// exchanging a transfer name first.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Mailbox {
+    /// The long random name transfers to the mailbox are announced under.
+    pub name: String,
+
+    /// The base64 encoded key both machines run the SPAKE2 handshake with in place of
+    /// a PIN.
+    pub key: String,
+}
 
 /// The default configuration values for the Caesar application.
 ///
@@ -57,6 +188,18 @@ impl Default for CaesarConfig {
     /// - `app_origin`: "wss://caesar-transfer-iu.shuttleapp.rs"
     /// - `app_relay`: "0.0.0.0:8000"
     /// - `rust_log`: "info"
+    /// - `app_token`, `oidc_issuer`, `oidc_audience`, `oidc_jwks_url`: "" (no authentication)
+    /// - `user_quota`: 0 (no limit)
+    /// - `database`: "" (transfers only live in memory)
+    /// - `room_ttl`: 3600
+    /// - `rate_limit_rooms`: 30, `rate_limit_uploads` and `rate_limit_downloads`: 60
+    /// - `max_room_size`: 65
+    /// - `blocklist`: none
+    /// - `admin_token`: "" (no reloads over HTTP)
+    /// - `upstream`: "" (no upstream relay)
+    /// - `fallback_relays`: none
+    /// - `profiles`: none
+    /// - `mailboxes`: none
     fn default() -> Self {
         CaesarConfig {
             app_environment: "production".to_string(),  // The environment in which the application is running.
@@ -65,10 +208,48 @@ impl Default for CaesarConfig {
             app_origin: "wss://caesar-transfer-iu.shuttleapp.rs".to_string(),  // The origin of the application.
             app_relay: "0.0.0.0:8000".to_string(),     // The relay endpoint of the application.
             rust_log: "info".to_string(),              // The logging level for the application.
+            app_token: String::new(),                  // No access token for the relay.
+            oidc_issuer: String::new(),                // The relay does not require tokens.
+            oidc_audience: String::new(),
+            oidc_jwks_url: String::new(),
+            user_quota: 0,                             // No limit on transfers per user.
+            database: String::new(),                   // Transfers only live in memory.
+            room_ttl: default_room_ttl(),              // Rooms wait an hour for their members.
+            rate_limit_rooms: default_rate_limit_rooms(),
+            rate_limit_uploads: default_rate_limit_transfers(),
+            rate_limit_downloads: default_rate_limit_transfers(),
+            max_room_size: default_max_room_size(),
+            blocklist: Vec::new(),                     // No client is blocked.
+            admin_token: String::new(),                // No reloads over HTTP.
+            upstream: String::new(),                   // No upstream relay.
+            fallback_relays: Vec::new(),               // No relay to fall back to.
+            profiles: BTreeMap::new(),                 // No relay profiles.
+            mailboxes: BTreeMap::new(),                // No mailboxes provisioned.
         }
     }
 }
 
+/// The default of `room_ttl`, for config files written before it existed.
+fn default_room_ttl() -> u64 {
+    3600
+}
+
+/// The default of `rate_limit_rooms`, for config files written before it existed.
+fn default_rate_limit_rooms() -> u32 {
+    30
+}
+
+/// The default of `rate_limit_uploads` and `rate_limit_downloads`, for config files
+/// written before they existed.
+fn default_rate_limit_transfers() -> u32 {
+    60
+}
+
+/// The default of `max_room_size`, for config files written before it existed.
+fn default_max_room_size() -> usize {
+    Room::MAX_ROOM_SIZE
+}
+
 lazy_static! {
     pub static ref GLOBAL_CONFIG: CaesarConfig = {
         let cfg: CaesarConfig =
@@ -76,3 +257,62 @@ lazy_static! {
         cfg
     };
 }
+
+/// Adds a mailbox to the config file, replacing one with the same label.
+///
+/// # Arguments
+///
+/// * `label` - The label the user refers to the mailbox by.
+/// * `mailbox` - The name and key of the mailbox.
+///
+/// # Returns
+///
+/// The path of the config file, or the error if it cannot be written.
+pub fn store_mailbox(label: &str, mailbox: Mailbox) -> Result<PathBuf, confy::ConfyError> {
+    // Load the file again, the global config may be outdated
+    let mut cfg: CaesarConfig = confy::load("caesar", "caesar")?;
+    cfg.mailboxes.insert(label.to_string(), mailbox);
+
+    // The file holds the key of the mailbox, so only the user may read it, before and
+    // after it is written
+    let path = confy::get_configuration_file_path("caesar", "caesar")?;
+    restrict_permissions(&path).map_err(confy::ConfyError::WriteConfigurationFileError)?;
+    confy::store_path(&path, cfg)?;
+    restrict_permissions(&path).map_err(confy::ConfyError::WriteConfigurationFileError)?;
+    Ok(path)
+}
+
+/// Creates a file if it does not exist and lets only its owner read and write it.
+///
+/// # Arguments
+///
+/// * `path` - The path of the file.
+///
+/// # Returns
+///
+/// The error if the file cannot be created or its permissions cannot be changed.
+#[cfg(unix)]
+fn restrict_permissions(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+}
+
+/// Creates a file if it does not exist. Other platforms keep the permissions the
+/// file has.
+#[cfg(not(unix))]
+fn restrict_permissions(path: &Path) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(())
+}
@@ -17,8 +17,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
     // Initialize the logging subscriber.
     // It configures the logging level based on the `RUST_LOG` environment variable.
+    // Logs go to stderr, so they never mix with files received to stdout.
     tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
         .init();
 
     // Parse the command line arguments.
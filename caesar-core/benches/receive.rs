@@ -0,0 +1,254 @@
+//! Allocations of the receiver per gigabyte received.
+//!
+//! Sends a file through a local relay and counts the heap allocations made on the
+//! receiver's thread, once without and once with compression. Run with:
+//!
+//! ```sh
+//! cargo bench -p caesar-core --bench receive
+//! ```
+//!
+//! Set `CAESAR_BENCH_SIZE_MB` to change the size of the file, 256 MiB by default.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    fs,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use caesar_core::{
+    receiver::{http_client::download_info, start_ws_com, ReceiverOptions},
+    relay::server::{start_ws, RelayConfig},
+    sender::{client, SenderOptions},
+};
+use tokio::{runtime::Runtime, time::sleep};
+use tokio_tungstenite::connect_async;
+
+/// The port of the local relay.
+const PORT: i32 = 18491;
+
+/// The key both sides authenticate the handshake with.
+const KEY: &[u8] = b"bench-key";
+
+/// One gigabyte, the unit the allocations are reported in.
+const GB: f64 = (1u64 << 30) as f64;
+
+/// Counts the allocations of the threads that opted in and passes them on to the system
+/// allocator.
+struct CountingAllocator;
+
+thread_local! {
+    /// Whether the allocations of the current thread are counted.
+    static COUNTED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// The number of counted allocations, reallocations included.
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// The number of counted bytes allocated.
+static ALLOCATED: AtomicU64 = AtomicU64::new(0);
+
+/// Records an allocation if the current thread is counted.
+fn record(size: usize) {
+    if COUNTED.with(Cell::get) {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED.fetch_add(size as u64, Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        record(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        record(layout.size());
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        record(new_size);
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Starts a relay on its own runtime and waits until it accepts connections.
+fn start_relay(runtime: &Runtime) -> String {
+    std::thread::spawn(|| {
+        Runtime::new().unwrap().block_on(start_ws(
+            &PORT,
+            &String::from("127.0.0.1"),
+            RelayConfig::default(),
+        ))
+    });
+
+    let relay = format!("ws://127.0.0.1:{}", PORT);
+    runtime.block_on(async {
+        for _ in 0..50 {
+            if connect_async(format!("{}/ws", relay)).await.is_ok() {
+                return;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        panic!("The relay did not start.");
+    });
+    relay
+}
+
+/// Content that compresses a little, like most real files.
+fn content(size: usize) -> Vec<u8> {
+    let mut state = 0x2545f491u32;
+    (0..size)
+        .map(|i| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            if i % 4 == 0 {
+                state as u8
+            } else {
+                (i % 64) as u8
+            }
+        })
+        .collect()
+}
+
+/// Sends a file to a receiver on the current thread and counts its allocations.
+///
+/// # Returns
+///
+/// The time the transfer took, the allocations and the bytes allocated.
+fn receive(
+    runtime: &Runtime,
+    relay: &str,
+    file: &str,
+    output: &str,
+    name: &str,
+    compress: bool,
+) -> (Duration, u64, u64) {
+    // The sender runs on its own runtime, so its allocations are not counted
+    let paths = vec![file.to_string()];
+    let relay_url = relay.to_string();
+    let transfer_name = name.to_string();
+    let sender = std::thread::spawn(move || {
+        Runtime::new().unwrap().block_on(async move {
+            let (socket, _) = connect_async(format!("{}/ws", relay_url)).await.unwrap();
+            let options = SenderOptions {
+                compress,
+                key: Some(KEY.to_vec()),
+                ..Default::default()
+            };
+            client::start(
+                socket,
+                paths,
+                None,
+                relay_url,
+                transfer_name,
+                false,
+                options,
+            )
+            .await
+        })
+    });
+
+    let room_id = runtime.block_on(async {
+        let http_url = relay.replace("ws://", "http://");
+        for _ in 0..50 {
+            if let Ok(info) = download_info(&http_url, name, None, None).await {
+                if !info.relay_room_id.is_empty() {
+                    return info.relay_room_id;
+                }
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        panic!("The sender did not announce the room.");
+    });
+
+    ALLOCATIONS.store(0, Ordering::Relaxed);
+    ALLOCATED.store(0, Ordering::Relaxed);
+    COUNTED.with(|counted| counted.set(true));
+    let start = Instant::now();
+    let options = ReceiverOptions {
+        key: Some(KEY.to_vec()),
+        ..Default::default()
+    };
+    runtime
+        .block_on(start_ws_com(output.to_string(), relay, &room_id, options))
+        .unwrap();
+    let elapsed = start.elapsed();
+    COUNTED.with(|counted| counted.set(false));
+
+    sender.join().unwrap().unwrap();
+    (
+        elapsed,
+        ALLOCATIONS.load(Ordering::Relaxed),
+        ALLOCATED.load(Ordering::Relaxed),
+    )
+}
+
+fn main() {
+    let size_mb: usize = std::env::var("CAESAR_BENCH_SIZE_MB")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(256);
+
+    // The receiver and everything it spawns run on this thread only
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let relay = start_relay(&runtime);
+
+    let dir = std::env::temp_dir().join("caesar-bench-receive");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let file: PathBuf = dir.join("data.bin");
+    fs::write(&file, content(size_mb << 20)).unwrap();
+
+    let mut rows = vec![];
+    for (compression, compress) in [("none", false), ("zstd", true)] {
+        let output = dir.join(compression);
+        fs::create_dir_all(&output).unwrap();
+        let (elapsed, allocations, allocated) = receive(
+            &runtime,
+            &relay,
+            file.to_str().unwrap(),
+            output.to_str().unwrap(),
+            &format!("bench-receive-{compression}"),
+            compress,
+        );
+        assert_eq!(
+            fs::read(output.join("data.bin")).unwrap().len(),
+            size_mb << 20
+        );
+        rows.push((compression, elapsed, allocations, allocated));
+    }
+
+    let gigabytes = (size_mb << 20) as f64 / GB;
+    println!();
+    println!(
+        "{:<12} {:>10} {:>12} {:>16} {:>16}",
+        "compression", "size", "time", "allocations/GB", "allocated/GB"
+    );
+    for (compression, elapsed, allocations, allocated) in rows {
+        println!(
+            "{:<12} {:>6} MiB {:>10.2?} {:>16.0} {:>12.1} MiB",
+            compression,
+            size_mb,
+            elapsed,
+            allocations as f64 / gigabytes,
+            allocated as f64 / gigabytes / (1 << 20) as f64,
+        );
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+}
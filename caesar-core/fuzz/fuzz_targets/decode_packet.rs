@@ -0,0 +1,21 @@
+//! Binary messages a peer sends through the relay, before and after the handshake.
+
+#![no_main]
+
+use aes_gcm::{Aes128Gcm, Key, KeyInit};
+use caesar_core::shared::{decode_packet, packets::Framing, SessionKey};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // Plain packets of the handshake
+    let _ = decode_packet(&None, 0, data.to_vec());
+
+    // Encrypted packets with both framings, from the sender and from a receiver
+    let cipher = Aes128Gcm::new(Key::<Aes128Gcm>::from_slice(&[0u8; 16]));
+    for framing in [Framing::V1, Framing::V2] {
+        let key = Some(SessionKey::new(cipher.clone(), framing));
+        for peer in [0, 1] {
+            let _ = decode_packet(&key, peer, data.to_vec());
+        }
+    }
+});
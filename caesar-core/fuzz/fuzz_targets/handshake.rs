@@ -0,0 +1,20 @@
+//! Key shares and confirmations a peer sends in the handshake.
+
+#![no_main]
+
+use caesar_core::pake::{Role, Spake2};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let sender = Spake2::new(Role::Sender, b"123456");
+    let receiver = Spake2::new(Role::Receiver, b"123456");
+
+    // Any share of the peer
+    let _ = sender.finish(data);
+    let _ = receiver.finish(data);
+
+    // Any confirmation after a valid share
+    if let Ok(keys) = receiver.finish(sender.message()) {
+        let _ = keys.verify(Role::Sender, &[], data);
+    }
+});
@@ -0,0 +1,15 @@
+//! Text messages between the clients and the relay, and between sender and receiver.
+
+#![no_main]
+
+use caesar_core::relay::{transfer::TransferRequest, RequestPacket, ResponsePacket};
+use caesar_core::shared::{JsonPacket, JsonPacketResponse};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = serde_json::from_slice::<RequestPacket>(data);
+    let _ = serde_json::from_slice::<ResponsePacket>(data);
+    let _ = serde_json::from_slice::<JsonPacket>(data);
+    let _ = serde_json::from_slice::<JsonPacketResponse>(data);
+    let _ = serde_json::from_slice::<TransferRequest>(data);
+});
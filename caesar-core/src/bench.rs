@@ -0,0 +1,333 @@
+//! A benchmark of the transfer path on the loopback interface.
+//!
+//! The benchmark serves a relay, starts a sender and a receiver in the same process and
+//! transfers a file of random bytes through the relay. Nothing but the chunking,
+//! encryption and writing of the files is measured, so a regression there shows up
+//! without a network in the way.
+
+use std::{
+    fs,
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use rand::{thread_rng, RngCore};
+use tokio::{net::TcpListener, sync::oneshot};
+
+use crate::error::{CaesarError, Result};
+use crate::event::EventReport;
+use crate::progress::ProgressReport;
+use crate::receiver::{execute, plan::Endpoint, resolve, ReceiverOptions};
+use crate::relay::server::{relay_state, router, RelayConfig};
+use crate::sender::{start_sender, util::generate_mailbox, SenderOptions};
+
+/// The size of the blocks the payload is written in.
+const BLOCK_SIZE: usize = 1024 * 1024;
+
+/// How often the receiver looks up the transfer before the sender has published it.
+const LOOKUP_ATTEMPTS: usize = 50;
+
+/// What a benchmark transfers.
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// The size of the file of random bytes to transfer.
+    pub size: u64,
+    /// Compresses the file while it is sent, like `caesar send --compress`. Random bytes
+    /// do not shrink, so this measures the cost of the compression alone.
+    pub compress: bool,
+}
+
+impl Default for BenchOptions {
+    fn default() -> BenchOptions {
+        BenchOptions {
+            size: 256 * 1024 * 1024,
+            compress: false,
+        }
+    }
+}
+
+/// The results of a benchmark.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BenchReport {
+    /// The number of bytes transferred.
+    pub size: u64,
+    /// How long the receiver took, from the connection to the relay to the last chunk.
+    pub elapsed: Duration,
+    /// The CPU time the process spent meanwhile, in all of its threads. If `None`, the
+    /// platform does not tell it.
+    pub cpu: Option<Duration>,
+}
+
+impl BenchReport {
+    /// Returns the average throughput in bytes per second.
+    pub fn throughput(&self) -> u64 {
+        // A tiny payload may arrive within the resolution of the clock
+        let seconds = self.elapsed.as_secs_f64().max(0.001);
+        (self.size as f64 / seconds) as u64
+    }
+
+    /// Returns the CPU time in relation to the elapsed time, where `1.0` is one core
+    /// busy for the whole transfer.
+    pub fn cpu_load(&self) -> Option<f64> {
+        let seconds = self.elapsed.as_secs_f64().max(0.001);
+        self.cpu.map(|cpu| cpu.as_secs_f64() / seconds)
+    }
+}
+
+/// Transfers a file of random bytes from a sender to a receiver through a relay, all
+/// in this process.
+///
+/// # Arguments
+///
+/// * `options` - What to transfer.
+///
+/// # Returns
+///
+/// The results of the benchmark, or the error the transfer ended with.
+pub async fn run(options: BenchOptions) -> Result<BenchReport> {
+    let dir = std::env::temp_dir().join(format!("caesar-bench-{}", std::process::id()));
+    let result = run_in(&dir, &options).await;
+    // The payload may be large, so it is removed even if the transfer failed
+    let _ = fs::remove_dir_all(&dir);
+    result
+}
+
+/// Runs a benchmark with its files in a directory.
+///
+/// # Arguments
+///
+/// * `dir` - The directory for the payload and the received file.
+/// * `options` - What to transfer.
+///
+/// # Returns
+///
+/// The results of the benchmark, or the error the transfer ended with.
+async fn run_in(dir: &Path, options: &BenchOptions) -> Result<BenchReport> {
+    let out = dir.join("out");
+    fs::create_dir_all(&out)
+        .map_err(|error| CaesarError::io("Failed to create the benchmark directory", error))?;
+    let path = write_payload(&dir.join("payload.bin"), options.size)
+        .map_err(|error| CaesarError::io("Failed to write the benchmark payload", error))?;
+
+    let (relay, shutdown) = serve_relay()?;
+    let result = transfer(&relay, path, &out, options).await;
+    // The relay stops with its runtime once its thread is told to
+    let _ = shutdown.send(());
+    result
+}
+
+/// Serves a relay on the loopback interface.
+///
+/// The relay runs on a thread of its own, as the sender publishes its transfers with a
+/// blocking request that would otherwise hold up the relay it waits for.
+///
+/// # Returns
+///
+/// The URL of the relay and the sender that stops it, or the error if it cannot be
+/// served.
+fn serve_relay() -> Result<(String, oneshot::Sender<()>)> {
+    let (addresses, address) = std::sync::mpsc::channel();
+    let (shutdown, stop) = oneshot::channel::<()>();
+    thread::spawn(move || {
+        let served = async {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let config = RelayConfig::default();
+            let state = relay_state(&config).await.map_err(io::Error::other)?;
+            let app = router(state, config);
+            addresses.send(Ok(listener.local_addr()?)).ok();
+            let serve = axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            );
+            tokio::select! {
+                _ = serve => {}
+                _ = stop => {}
+            }
+            Ok::<_, io::Error>(())
+        };
+        let result = tokio::runtime::Runtime::new().and_then(|runtime| runtime.block_on(served));
+        // Only an error before the relay was served is still waited for
+        if let Err(error) = result {
+            addresses.send(Err(error)).ok();
+        }
+    });
+
+    let address = address
+        .recv()
+        .unwrap_or_else(|_| Err(io::Error::other("the relay stopped")))
+        .map_err(|error| CaesarError::io("Failed to serve the benchmark relay", error))?;
+    Ok((format!("ws://{}", address), shutdown))
+}
+
+/// Transfers the payload through the relay and measures the receiver.
+///
+/// # Arguments
+///
+/// * `relay` - The URL of the relay.
+/// * `path` - The payload.
+/// * `out` - The directory to receive the payload into.
+/// * `options` - What to transfer.
+///
+/// # Returns
+///
+/// The results of the benchmark, or the error the transfer ended with.
+async fn transfer(
+    relay: &str,
+    path: PathBuf,
+    out: &Path,
+    options: &BenchOptions,
+) -> Result<BenchReport> {
+    // A mailbox spares reading the PIN the sender prints
+    let (name, key) = generate_mailbox();
+    let sender = tokio::spawn(start_sender(
+        name.clone(),
+        Arc::new(relay.to_string()),
+        Arc::new(vec![path.to_string_lossy().to_string()]),
+        SenderOptions {
+            key: Some(key.clone()),
+            compress: options.compress,
+            announce: false,
+            punch: false,
+            quic: false,
+            progress: Some(ProgressReport::new(|_| {})),
+            events: Some(EventReport::new(|_| {})),
+            ..SenderOptions::default()
+        },
+    ));
+
+    let receiver = ReceiverOptions {
+        key: Some(key),
+        punch: false,
+        quic: false,
+        progress: Some(ProgressReport::new(|_| {})),
+        events: Some(EventReport::new(|_| {})),
+        ..ReceiverOptions::default()
+    };
+    // The sender may not have published the transfer yet
+    let mut plan = resolve(relay, &name, &receiver).await;
+    for _ in 0..LOOKUP_ATTEMPTS {
+        if plan.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        plan = resolve(relay, &name, &receiver).await;
+    }
+    let mut plan = match plan {
+        Ok(plan) => plan,
+        Err(error) => {
+            sender.abort();
+            return Err(error);
+        }
+    };
+    // The sender's local server would skip the relay, and with it half of the path
+    plan.endpoints
+        .retain(|endpoint| matches!(endpoint, Endpoint::Relay { .. }));
+
+    let start = Instant::now();
+    let cpu = cpu_time();
+
+    let received = execute(out.to_string_lossy().to_string(), &plan, receiver).await;
+    let elapsed = start.elapsed();
+    let cpu = cpu.zip(cpu_time()).map(|(start, end)| end - start);
+    let summary = match received {
+        Ok(summary) => summary,
+        Err(error) => {
+            sender.abort();
+            return Err(error);
+        }
+    };
+    sender
+        .await
+        .map_err(|error| CaesarError::Connection(error.to_string()))??;
+
+    Ok(BenchReport {
+        size: summary.bytes,
+        elapsed,
+        cpu,
+    })
+}
+
+/// Writes a file of random bytes.
+///
+/// # Arguments
+///
+/// * `path` - The file to write.
+/// * `size` - The number of bytes to write.
+///
+/// # Returns
+///
+/// The path of the file, or the error of the write.
+fn write_payload(path: &Path, size: u64) -> io::Result<PathBuf> {
+    let mut file = io::BufWriter::new(fs::File::create(path)?);
+    let mut block = vec![0; BLOCK_SIZE];
+    let mut remaining = size;
+    while remaining > 0 {
+        let length = remaining.min(BLOCK_SIZE as u64) as usize;
+        thread_rng().fill_bytes(&mut block[..length]);
+        file.write_all(&block[..length])?;
+        remaining -= length as u64;
+    }
+    file.flush()?;
+    Ok(path.to_path_buf())
+}
+
+/// Returns the CPU time the process has spent so far, in user and kernel mode.
+#[cfg(unix)]
+fn cpu_time() -> Option<Duration> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    // SAFETY: getrusage only writes to the struct it is given
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    // SAFETY: the call succeeded, so the struct is filled in
+    let usage = unsafe { usage.assume_init() };
+    let time =
+        |value: libc::timeval| Duration::new(value.tv_sec as u64, value.tv_usec as u32 * 1000);
+    Some(time(usage.ru_utime) + time(usage.ru_stime))
+}
+
+/// Returns the CPU time the process has spent so far, which this platform does not tell.
+#[cfg(not(unix))]
+fn cpu_time() -> Option<Duration> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_throughput() {
+        let report = BenchReport {
+            size: 4 * 1024 * 1024,
+            elapsed: Duration::from_secs(2),
+            cpu: Some(Duration::from_secs(1)),
+        };
+        assert_eq!(report.throughput(), 2 * 1024 * 1024);
+        assert_eq!(report.cpu_load(), Some(0.5));
+    }
+
+    #[test]
+    fn test_write_payload() {
+        let dir = std::env::temp_dir().join(format!("caesar-payload-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = write_payload(&dir.join("payload.bin"), BLOCK_SIZE as u64 + 5).unwrap();
+        assert_eq!(fs::metadata(path).unwrap().len(), BLOCK_SIZE as u64 + 5);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let report = run(BenchOptions {
+            size: 3 * 1024 * 1024,
+            compress: false,
+        })
+        .await
+        .unwrap();
+        assert_eq!(report.size, 3 * 1024 * 1024);
+    }
+}
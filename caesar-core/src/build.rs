@@ -1,22 +1,194 @@
 // This build script is invoked by cargo when it is building our crate. It is responsible for
 // generating code based on other protocol buffer definitions.
 //
-// Specifically, it generates Rust code from the `packets.proto` file in the root of our
-// crate. This generated code is then compiled into our final binary.
+// Specifically, it generates Rust code from the `proto/packets.proto` file of our crate. This
+// generated code is then compiled into our final binary.
 //
 // The `prost_build` crate is responsible for doing the actual work of generating code from
-// protocol buffer definitions. We're passing it the path to our `.proto` file and the root
-// directory of our crate.
+// protocol buffer definitions. We're passing it the path to our `.proto` file and the
+// directory it is included from.
+//
+// The schema is also the contract for clients that are not built from this crate. Every
+// released version of it is kept under `proto/history/`, and the build compares the current
+// schema against each of them, so a change that old clients could not decode fails here
+// instead of in the field.
 extern crate prost_build;
 
+use std::{collections::HashMap, env, fs, path::PathBuf};
+
+use prost::Message;
+use prost_types::{DescriptorProto, EnumDescriptorProto, FileDescriptorSet};
+
+/// The released versions of the schema under `proto/history/`.
+const HISTORY: &[&str] = &["v1"];
+
 fn main() {
-    // Invoke the `compile_protos` function from the `prost_build` crate. This function takes
-    // two arguments: a list of `.proto` files to compile and the root directory of our crate.
-    // It returns a `Result` indicating whether the compilation was successful or not.
-    //
-    // The `.unwrap()` method is then called on the `Result` to panic if the compilation
-    // failed. This is okay in a build script because it will stop the build process and
-    // prevent our code from being built.
-    prost_build::compile_protos(&["packets.proto"], &["."]).unwrap();
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+
+    // Compile the current schema into `packets.rs`. The descriptors are kept next to it for
+    // the comparison below. The `.unwrap()` panics if the compilation failed, which is okay
+    // in a build script because it stops the build process. The data of a chunk and of a
+    // delta is kept as `Bytes`, so decoding it points into the received message instead
+    // of copying it.
+    let current = out_dir.join("packets.bin");
+    prost_build::Config::new()
+        .bytes([".Packets.ChunkPacket.chunk", ".Packets.DeltaPacket.Op.data"])
+        .file_descriptor_set_path(&current)
+        .compile_protos(&["proto/packets.proto"], &["proto"])
+        .unwrap();
+
+    // The released versions are compiled into `history/<version>/packets.rs` as well, so the
+    // compatibility tests can encode packets the way old clients do.
+    for version in HISTORY {
+        let dir = out_dir.join("history").join(version);
+        fs::create_dir_all(&dir).unwrap();
+
+        let released = dir.join("packets.bin");
+        let include = format!("proto/history/{version}");
+        prost_build::Config::new()
+            .out_dir(&dir)
+            .file_descriptor_set_path(&released)
+            .compile_protos(&[format!("{include}/packets.proto")], &[include])
+            .unwrap();
+
+        let problems = breaking_changes(&load(&released), &load(&current));
+        if !problems.is_empty() {
+            panic!(
+                "proto/packets.proto is not compatible with version {version}:\n  - {}",
+                problems.join("\n  - ")
+            );
+        }
+    }
+}
+
+/// Reads a descriptor set written by `prost_build`.
+///
+/// # Arguments
+///
+/// * `path` - The path of the descriptor set.
+fn load(path: &PathBuf) -> FileDescriptorSet {
+    FileDescriptorSet::decode(fs::read(path).unwrap().as_slice()).unwrap()
 }
 
+/// Collects the messages and enums of a descriptor set by their full name, including the
+/// nested ones.
+///
+/// # Arguments
+///
+/// * `set` - The descriptor set to index.
+///
+/// # Returns
+///
+/// The messages and the enums, each by their full name like `Packets.ListPacket.Entry`.
+fn index(
+    set: &FileDescriptorSet,
+) -> (
+    HashMap<String, &DescriptorProto>,
+    HashMap<String, &EnumDescriptorProto>,
+) {
+    fn walk<'a>(
+        prefix: &str,
+        messages: &'a [DescriptorProto],
+        enums: &'a [EnumDescriptorProto],
+        index: &mut (
+            HashMap<String, &'a DescriptorProto>,
+            HashMap<String, &'a EnumDescriptorProto>,
+        ),
+    ) {
+        for message in messages {
+            let name = format!("{prefix}.{}", message.name());
+            walk(&name, &message.nested_type, &message.enum_type, index);
+            index.0.insert(name, message);
+        }
+        for item in enums {
+            index.1.insert(format!("{prefix}.{}", item.name()), item);
+        }
+    }
+
+    let mut index = (HashMap::new(), HashMap::new());
+    for file in &set.file {
+        walk(file.package(), &file.message_type, &file.enum_type, &mut index);
+    }
+    index
+}
+
+/// Lists the changes of a schema that break the wire format of an older one.
+///
+/// Like the `WIRE` rules of `buf breaking`, this only looks at what is on the wire: names
+/// may change, but messages, enum values and field numbers that were used may not go away,
+/// and fields may not change their type, their label or whether they are part of a oneof.
+/// A removed field or enum value is fine if its number is reserved.
+///
+/// # Arguments
+///
+/// * `old` - The descriptors of the released schema.
+/// * `new` - The descriptors of the current schema.
+///
+/// # Returns
+///
+/// A description of every breaking change, empty if the schemas are compatible.
+fn breaking_changes(old: &FileDescriptorSet, new: &FileDescriptorSet) -> Vec<String> {
+    let (old_messages, old_enums) = index(old);
+    let (new_messages, new_enums) = index(new);
+    let mut problems = Vec::new();
+
+    for (name, old_message) in &old_messages {
+        let Some(new_message) = new_messages.get(name) else {
+            problems.push(format!("message {name} was removed"));
+            continue;
+        };
+
+        for old_field in &old_message.field {
+            let number = old_field.number();
+            let field = format!("{name}.{} ({number})", old_field.name());
+            let Some(new_field) = new_message.field.iter().find(|f| f.number() == number) else {
+                let reserved = new_message
+                    .reserved_range
+                    .iter()
+                    .any(|range| (range.start()..range.end()).contains(&number));
+                if !reserved {
+                    problems.push(format!("field {field} was removed without reserving it"));
+                }
+                continue;
+            };
+
+            if new_field.r#type() != old_field.r#type()
+                || new_field.type_name() != old_field.type_name()
+            {
+                problems.push(format!("field {field} changed its type"));
+            }
+            if new_field.label() != old_field.label() {
+                problems.push(format!("field {field} changed its label"));
+            }
+            if new_field.oneof_index.is_some() != old_field.oneof_index.is_some() {
+                problems.push(format!("field {field} moved into or out of a oneof"));
+            }
+        }
+    }
+
+    for (name, old_enum) in &old_enums {
+        let Some(new_enum) = new_enums.get(name) else {
+            problems.push(format!("enum {name} was removed"));
+            continue;
+        };
+
+        for value in &old_enum.value {
+            let number = value.number();
+            let kept = new_enum.value.iter().any(|v| v.number() == number)
+                || new_enum
+                    .reserved_range
+                    .iter()
+                    .any(|range| (range.start()..=range.end()).contains(&number));
+            if !kept {
+                problems.push(format!(
+                    "enum value {name}.{} ({number}) was removed without reserving it",
+                    value.name()
+                ));
+            }
+        }
+    }
+
+    // Sort the problems, so the message does not change between builds
+    problems.sort();
+    problems
+}
@@ -0,0 +1,348 @@
+//! Fault injection for the transport between a client and the relay.
+//!
+//! This module is only compiled for tests and with the `chaos` feature. It wraps a
+//! WebSocket connection and delays, reorders, drops and disconnects messages according
+//! to a seeded `ChaosProfile`, so integration tests can reproduce bad networks
+//! deterministically.
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{task::JoinHandle, time::sleep};
+use tokio_tungstenite::tungstenite::{protocol::Message as WebSocketMessage, Error};
+
+use crate::shared::Transport;
+
+/// Describes which faults are injected into a connection.
+///
+/// Drops and reordering only affect binary messages, so the JSON packets used to
+/// create and join rooms always get through. Latency applies to every message.
+#[derive(Debug, Clone, Default)]
+pub struct ChaosProfile {
+    /// The seed of the random generator. The same seed yields the same faults.
+    pub seed: u64,
+
+    /// The smallest delay added to a message.
+    pub min_latency: Duration,
+
+    /// The largest delay added to a message.
+    pub max_latency: Duration,
+
+    /// The probability that a binary message is dropped.
+    pub drop_rate: f64,
+
+    /// The probability that a binary message is held back and delivered after the next one.
+    pub reorder_rate: f64,
+
+    /// The number of outgoing messages after which the connection is closed.
+    pub disconnect_after: Option<usize>,
+}
+
+/// What happens to a single message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Deliver the message after the given delay.
+    Deliver(Duration),
+
+    /// Deliver the message after the next one, with the given delay.
+    Reorder(Duration),
+
+    /// Drop the message.
+    Drop,
+}
+
+/// Decides the fault of each message of one direction of a connection.
+#[derive(Debug)]
+pub struct Chaos {
+    profile: ChaosProfile,
+    rng: StdRng,
+}
+
+impl Chaos {
+    /// Creates a new decision maker for the given profile.
+    ///
+    /// # Arguments
+    ///
+    /// * `profile` - The faults to inject.
+    /// * `stream` - Distinguishes the directions of a connection so they do not share faults.
+    pub fn new(profile: ChaosProfile, stream: u64) -> Chaos {
+        let rng = StdRng::seed_from_u64(profile.seed.wrapping_add(stream));
+
+        Chaos { profile, rng }
+    }
+
+    /// Decides what happens to the next message.
+    ///
+    /// The random generator is advanced by the same amount for every message, so the
+    /// faults only depend on the seed and the position of the message.
+    pub fn next_fault(&mut self, message: &WebSocketMessage) -> Fault {
+        let latency = if self.profile.max_latency > self.profile.min_latency {
+            self.rng
+                .gen_range(self.profile.min_latency..=self.profile.max_latency)
+        } else {
+            self.profile.min_latency
+        };
+        let dropped = self.rng.gen_bool(self.profile.drop_rate.clamp(0.0, 1.0));
+        let reordered = self.rng.gen_bool(self.profile.reorder_rate.clamp(0.0, 1.0));
+
+        match message {
+            WebSocketMessage::Binary(_) if dropped => Fault::Drop,
+            WebSocketMessage::Binary(_) if reordered => Fault::Reorder(latency),
+            _ => Fault::Deliver(latency),
+        }
+    }
+}
+
+/// A transport that injects faults into the messages of a wrapped connection.
+///
+/// Both directions are driven by background tasks, which are stopped when the
+/// transport is dropped.
+pub struct ChaosTransport {
+    incoming: flume::r#async::RecvStream<'static, Result<WebSocketMessage, Error>>,
+    outgoing: flume::Sender<WebSocketMessage>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl ChaosTransport {
+    /// Wraps a connection and starts injecting faults.
+    ///
+    /// # Arguments
+    ///
+    /// * `socket` - The connection to wrap.
+    /// * `profile` - The faults to inject.
+    pub fn new(socket: impl Transport + 'static, profile: ChaosProfile) -> ChaosTransport {
+        let (mut sink, mut stream) = socket.split();
+        let (incoming_sender, incoming) = flume::unbounded();
+        let (outgoing, outgoing_receiver) = flume::unbounded::<WebSocketMessage>();
+
+        // Forward incoming messages to the client
+        let mut chaos = Chaos::new(profile.clone(), 0);
+        let incoming_task = tokio::spawn(async move {
+            let mut held = None;
+
+            while let Some(message) = stream.next().await {
+                let message = match message {
+                    Ok(message) => message,
+                    Err(error) => {
+                        let _ = incoming_sender.send(Err(error));
+                        break;
+                    }
+                };
+
+                let deliver = |message| incoming_sender.send(Ok(message)).is_ok();
+                if !apply(chaos.next_fault(&message), message, &mut held, deliver).await {
+                    break;
+                }
+            }
+
+            if let Some(message) = held {
+                let _ = incoming_sender.send(Ok(message));
+            }
+        });
+
+        // Forward outgoing messages to the wrapped connection
+        let incoming_abort = incoming_task.abort_handle();
+        let mut chaos = Chaos::new(profile.clone(), 1);
+        let outgoing_task = tokio::spawn(async move {
+            let mut held = None;
+            let mut sent = 0;
+
+            while let Ok(message) = outgoing_receiver.recv_async().await {
+                sent += 1;
+
+                let mut buffer = vec![];
+                let fault = chaos.next_fault(&message);
+                apply(fault, message, &mut held, |message| {
+                    buffer.push(message);
+                    true
+                })
+                .await;
+
+                for message in buffer {
+                    if sink.send(message).await.is_err() {
+                        return;
+                    }
+                }
+
+                // Cut the connection once the limit is reached
+                if profile.disconnect_after.is_some_and(|limit| sent >= limit) {
+                    incoming_abort.abort();
+                    let _ = sink.close().await;
+                    return;
+                }
+            }
+
+            if let Some(message) = held {
+                let _ = sink.send(message).await;
+            }
+            let _ = sink.close().await;
+        });
+
+        ChaosTransport {
+            incoming: incoming.into_stream(),
+            outgoing,
+            tasks: vec![incoming_task, outgoing_task],
+        }
+    }
+}
+
+/// Applies a fault to a message.
+///
+/// # Arguments
+///
+/// * `fault` - The fault to apply.
+/// * `message` - The message to deliver.
+/// * `held` - The message held back by an earlier reorder, if any.
+/// * `deliver` - Delivers a message and returns whether the other side is still there.
+///
+/// # Returns
+///
+/// Whether the other side is still there.
+async fn apply(
+    fault: Fault,
+    message: WebSocketMessage,
+    held: &mut Option<WebSocketMessage>,
+    mut deliver: impl FnMut(WebSocketMessage) -> bool,
+) -> bool {
+    match fault {
+        Fault::Drop => true,
+        Fault::Reorder(latency) if held.is_none() => {
+            sleep(latency).await;
+            *held = Some(message);
+            true
+        }
+        Fault::Deliver(latency) | Fault::Reorder(latency) => {
+            sleep(latency).await;
+            if !deliver(message) {
+                return false;
+            }
+
+            // Release the message held back by an earlier reorder
+            match held.take() {
+                Some(message) => deliver(message),
+                None => true,
+            }
+        }
+    }
+}
+
+impl Drop for ChaosTransport {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl Stream for ChaosTransport {
+    type Item = Result<WebSocketMessage, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.incoming.poll_next_unpin(cx)
+    }
+}
+
+impl Sink<WebSocketMessage> for ChaosTransport {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: WebSocketMessage) -> Result<(), Error> {
+        self.outgoing
+            .send(message)
+            .map_err(|_| Error::ConnectionClosed)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+    use tokio_tungstenite::{tungstenite::protocol::Role, WebSocketStream};
+
+    fn binary(byte: u8) -> WebSocketMessage {
+        WebSocketMessage::Binary(vec![byte])
+    }
+
+    #[test]
+    fn test_chaos_is_deterministic() {
+        let profile = ChaosProfile {
+            seed: 42,
+            max_latency: Duration::from_millis(10),
+            drop_rate: 0.2,
+            reorder_rate: 0.2,
+            ..Default::default()
+        };
+
+        let mut first = Chaos::new(profile.clone(), 0);
+        let mut second = Chaos::new(profile, 0);
+        for byte in 0..100 {
+            assert_eq!(
+                first.next_fault(&binary(byte)),
+                second.next_fault(&binary(byte))
+            );
+        }
+    }
+
+    #[test]
+    fn test_chaos_spares_text_messages() {
+        let mut chaos = Chaos::new(
+            ChaosProfile {
+                drop_rate: 1.0,
+                ..Default::default()
+            },
+            0,
+        );
+
+        assert_eq!(chaos.next_fault(&binary(0)), Fault::Drop);
+        assert_eq!(
+            chaos.next_fault(&WebSocketMessage::Text("{}".into())),
+            Fault::Deliver(Duration::ZERO)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chaos_transport() {
+        let (client, server) = duplex(4096);
+        let client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+        let mut server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+
+        let mut transport = ChaosTransport::new(
+            client,
+            ChaosProfile {
+                reorder_rate: 1.0,
+                disconnect_after: Some(4),
+                ..Default::default()
+            },
+        );
+        for byte in 0..6 {
+            transport.send(binary(byte)).await.unwrap();
+        }
+
+        // Every second message overtakes the one before it, and the connection is
+        // closed after four messages
+        let mut received = vec![];
+        while let Some(Ok(message)) = server.next().await {
+            if let WebSocketMessage::Binary(data) = message {
+                received.extend(data);
+            }
+        }
+        assert_eq!(received, vec![1, 0, 3, 2]);
+        assert!(transport.next().await.is_none());
+    }
+}
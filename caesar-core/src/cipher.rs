@@ -0,0 +1,228 @@
+//! The ciphers the packets of a session are encrypted with.
+//!
+//! The cipher is negotiated in the handshake: the sender offers AES-256-GCM and
+//! ChaCha20-Poly1305, and the receiver picks ChaCha20-Poly1305 on a CPU without AES
+//! instructions, like many ARM boards, where it is several times faster than AES in
+//! software. Peers of earlier versions, which used AES-128-GCM with one key for both
+//! directions, are refused in the handshake.
+
+use aes_gcm::{
+    aead::{self, generic_array::GenericArray, AeadInPlace},
+    Aes128Gcm, Aes256Gcm, KeyInit,
+};
+use chacha20poly1305::ChaCha20Poly1305;
+
+use crate::shared::packets::Cipher;
+
+/// The cipher of a session, keyed for one direction.
+#[derive(Clone)]
+pub enum SessionCipher {
+    /// AES-128-GCM, which is only used when it was offered.
+    Aes128Gcm(Box<Aes128Gcm>),
+    /// AES-256-GCM.
+    Aes256Gcm(Box<Aes256Gcm>),
+    /// ChaCha20-Poly1305, for CPUs without AES instructions.
+    ChaCha20Poly1305(ChaCha20Poly1305),
+}
+
+impl SessionCipher {
+    /// Creates a cipher with a key.
+    ///
+    /// # Arguments
+    ///
+    /// * `cipher` - The cipher negotiated in the handshake.
+    /// * `key` - The key, of which AES-128-GCM uses the first 16 bytes.
+    ///
+    /// # Returns
+    ///
+    /// A new `SessionCipher` instance.
+    pub fn new(cipher: Cipher, key: &[u8; 32]) -> SessionCipher {
+        match cipher {
+            Cipher::Aes128Gcm => SessionCipher::Aes128Gcm(Box::new(Aes128Gcm::new(
+                GenericArray::from_slice(&key[..16]),
+            ))),
+            Cipher::Aes256Gcm => SessionCipher::Aes256Gcm(Box::new(Aes256Gcm::new(key.into()))),
+            Cipher::Chacha20Poly1305 => {
+                SessionCipher::ChaCha20Poly1305(ChaCha20Poly1305::new(key.into()))
+            }
+        }
+    }
+
+    /// Encrypts a packet in place.
+    ///
+    /// # Arguments
+    ///
+    /// * `nonce` - The 12 byte nonce of the packet.
+    /// * `associated_data` - The associated data the packet is encrypted with.
+    /// * `buffer` - The packet, which is replaced by the ciphertext.
+    ///
+    /// # Returns
+    ///
+    /// The 16 byte tag of the packet.
+    pub fn encrypt_in_place_detached(
+        &self,
+        nonce: &[u8],
+        associated_data: &[u8],
+        buffer: &mut [u8],
+    ) -> aead::Result<[u8; 16]> {
+        let nonce = GenericArray::from_slice(nonce);
+        let tag = match self {
+            SessionCipher::Aes128Gcm(cipher) => {
+                cipher.encrypt_in_place_detached(nonce, associated_data, buffer)
+            }
+            SessionCipher::Aes256Gcm(cipher) => {
+                cipher.encrypt_in_place_detached(nonce, associated_data, buffer)
+            }
+            SessionCipher::ChaCha20Poly1305(cipher) => {
+                cipher.encrypt_in_place_detached(nonce, associated_data, buffer)
+            }
+        }?;
+        Ok(tag.into())
+    }
+
+    /// Decrypts a packet in place.
+    ///
+    /// # Arguments
+    ///
+    /// * `nonce` - The 12 byte nonce of the packet.
+    /// * `associated_data` - The associated data the packet was encrypted with.
+    /// * `buffer` - The ciphertext, which is replaced by the packet.
+    /// * `tag` - The 16 byte tag of the packet.
+    ///
+    /// # Returns
+    ///
+    /// An error if the packet was not encrypted with this key or was changed.
+    pub fn decrypt_in_place_detached(
+        &self,
+        nonce: &[u8],
+        associated_data: &[u8],
+        buffer: &mut [u8],
+        tag: &[u8],
+    ) -> aead::Result<()> {
+        let nonce = GenericArray::from_slice(nonce);
+        let tag = GenericArray::from_slice(tag);
+        match self {
+            SessionCipher::Aes128Gcm(cipher) => {
+                cipher.decrypt_in_place_detached(nonce, associated_data, buffer, tag)
+            }
+            SessionCipher::Aes256Gcm(cipher) => {
+                cipher.decrypt_in_place_detached(nonce, associated_data, buffer, tag)
+            }
+            SessionCipher::ChaCha20Poly1305(cipher) => {
+                cipher.decrypt_in_place_detached(nonce, associated_data, buffer, tag)
+            }
+        }
+    }
+}
+
+/// Returns whether the CPU can run AES-GCM in hardware.
+pub fn has_aes_instructions() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    return std::arch::is_x86_feature_detected!("aes")
+        && std::arch::is_x86_feature_detected!("pclmulqdq");
+
+    #[cfg(target_arch = "aarch64")]
+    return std::arch::is_aarch64_feature_detected!("aes")
+        && std::arch::is_aarch64_feature_detected!("pmull");
+
+    // Other architectures have no hardware AES the cipher could use
+    #[allow(unreachable_code)]
+    false
+}
+
+/// Returns the ciphers the sender offers.
+///
+/// # Arguments
+///
+/// * `hardware_aes` - Whether the CPU of the sender has AES instructions.
+///
+/// # Returns
+///
+/// The ciphers, the one the sender encrypts fastest with first.
+pub fn offered_ciphers(hardware_aes: bool) -> Vec<Cipher> {
+    match hardware_aes {
+        true => vec![Cipher::Aes256Gcm, Cipher::Chacha20Poly1305],
+        false => vec![Cipher::Chacha20Poly1305, Cipher::Aes256Gcm],
+    }
+}
+
+/// Chooses the cipher of a session from the ones the sender offered.
+///
+/// # Arguments
+///
+/// * `offered` - The ciphers the sender offered, the one it prefers first.
+/// * `hardware_aes` - Whether the CPU of the receiver has AES instructions.
+///
+/// # Returns
+///
+/// The cipher, AES-128-GCM if the sender offered none.
+pub fn choose_cipher(offered: impl IntoIterator<Item = Cipher>, hardware_aes: bool) -> Cipher {
+    let offered: Vec<Cipher> = offered.into_iter().collect();
+    // AES in software would slow down the whole transfer
+    if !hardware_aes && offered.contains(&Cipher::Chacha20Poly1305) {
+        return Cipher::Chacha20Poly1305;
+    }
+    offered.first().copied().unwrap_or(Cipher::Aes128Gcm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_choose_cipher() {
+        // The sender's preference wins, unless the receiver lacks AES instructions
+        assert_eq!(
+            choose_cipher(offered_ciphers(true), true),
+            Cipher::Aes256Gcm
+        );
+        assert_eq!(
+            choose_cipher(offered_ciphers(true), false),
+            Cipher::Chacha20Poly1305
+        );
+        assert_eq!(
+            choose_cipher(offered_ciphers(false), true),
+            Cipher::Chacha20Poly1305
+        );
+
+        // A sender of an earlier version offers nothing
+        assert_eq!(choose_cipher([], false), Cipher::Aes128Gcm);
+        assert_eq!(choose_cipher([Cipher::Aes256Gcm], false), Cipher::Aes256Gcm);
+    }
+
+    #[test]
+    fn test_session_cipher() {
+        let key = [7u8; 32];
+        let nonce = [1u8; 12];
+        for cipher in [
+            Cipher::Aes128Gcm,
+            Cipher::Aes256Gcm,
+            Cipher::Chacha20Poly1305,
+        ] {
+            let session = SessionCipher::new(cipher, &key);
+            let encrypt = || {
+                let mut buffer = *b"packet";
+                let tag = session
+                    .encrypt_in_place_detached(&nonce, b"route", &mut buffer)
+                    .unwrap();
+                (buffer, tag)
+            };
+            let (mut buffer, tag) = encrypt();
+            assert_ne!(&buffer, b"packet");
+            session
+                .decrypt_in_place_detached(&nonce, b"route", &mut buffer, &tag)
+                .unwrap();
+            assert_eq!(&buffer, b"packet");
+
+            // Another cipher with the same key cannot read the packet
+            let other = match cipher {
+                Cipher::Chacha20Poly1305 => Cipher::Aes256Gcm,
+                _ => Cipher::Chacha20Poly1305,
+            };
+            let (mut buffer, tag) = encrypt();
+            assert!(SessionCipher::new(other, &key)
+                .decrypt_in_place_detached(&nonce, b"route", &mut buffer, &tag)
+                .is_err());
+        }
+    }
+}
@@ -0,0 +1,477 @@
+//! Delta transfer of files the receiver already has in another version.
+//!
+//! Like rsync, the receiver splits its copy of a file into blocks and sends the sender
+//! a signature with two checksums of every block. The sender slides a window of the
+//! block size over its version of the file, and wherever the window matches a block,
+//! the receiver is told to copy that block instead of being sent its bytes. So only
+//! the parts that changed go over the network.
+//!
+//! The weak checksum is rolled along a byte at a time, which checks every position of
+//! the file cheaply, and a BLAKE3 hash confirms the few windows it matches. A wrong
+//! match would still be caught by the hash of the whole file, which then requests the
+//! file again without a delta.
+
+use std::{
+    collections::HashMap,
+    io::{self, Read, Seek, SeekFrom},
+};
+
+use bytes::Bytes;
+
+use crate::shared::packets::{delta_packet, signature_packet, DeltaPacket, SignaturePacket};
+
+/// The smallest block a file is split into.
+const MIN_BLOCK_SIZE: u64 = 1024;
+
+/// The largest block a file is split into, and the largest a sender accepts.
+pub const MAX_BLOCK_SIZE: u32 = 16 * 1024 * 1024;
+
+/// The number of blocks from which on larger files get larger blocks, which keeps the
+/// signature small.
+const MAX_BLOCKS: u64 = 65536;
+
+/// The number of bytes of the BLAKE3 hash that are kept for every block.
+const STRONG_LENGTH: usize = 16;
+
+/// How much of the file the sender reads at once.
+const READ_SIZE: u64 = 1024 * 1024;
+
+/// Chooses the size of the blocks a file is split into.
+///
+/// Like rsync, the size grows with the square root of the file size, so neither the
+/// signature nor the bytes sent for a changed block get large.
+///
+/// # Arguments
+///
+/// * `size` - The size of the file.
+///
+/// # Returns
+///
+/// The block size, a multiple of 1 KiB.
+pub fn block_size(size: u64) -> u32 {
+    let block_size = ((size as f64).sqrt() as u64)
+        .max(size.div_ceil(MAX_BLOCKS))
+        .max(MIN_BLOCK_SIZE)
+        .next_multiple_of(MIN_BLOCK_SIZE);
+    block_size.min(u64::from(MAX_BLOCK_SIZE)) as u32
+}
+
+/// The weak checksum of rsync, which is moved along by a byte in constant time.
+struct Rolling {
+    /// The sum of the bytes in the window.
+    a: u32,
+    /// The sum of the bytes weighted by their distance from the end of the window.
+    b: u32,
+    /// The length of the window.
+    length: u32,
+}
+
+impl Rolling {
+    /// Computes the checksum of a window.
+    ///
+    /// # Arguments
+    ///
+    /// * `window` - The bytes in the window.
+    ///
+    /// # Returns
+    ///
+    /// A new `Rolling` instance.
+    fn new(window: &[u8]) -> Rolling {
+        let length = window.len() as u32;
+        let (a, b) =
+            window
+                .iter()
+                .zip((1..=length).rev())
+                .fold((0u32, 0u32), |(a, b), (&byte, weight)| {
+                    (
+                        a.wrapping_add(u32::from(byte)),
+                        b.wrapping_add(weight.wrapping_mul(u32::from(byte))),
+                    )
+                });
+        Rolling { a, b, length }
+    }
+
+    /// Moves the window on by a byte.
+    ///
+    /// # Arguments
+    ///
+    /// * `out` - The byte that leaves the window at its start.
+    /// * `next` - The byte that enters the window at its end.
+    fn roll(&mut self, out: u8, next: u8) {
+        self.a = self
+            .a
+            .wrapping_sub(u32::from(out))
+            .wrapping_add(u32::from(next));
+        self.b = self
+            .b
+            .wrapping_sub(self.length.wrapping_mul(u32::from(out)))
+            .wrapping_add(self.a);
+    }
+
+    /// Returns the checksum of the window.
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+/// Computes the strong checksum of a block.
+fn strong(block: &[u8]) -> Vec<u8> {
+    blake3::hash(block).as_bytes()[..STRONG_LENGTH].to_vec()
+}
+
+/// The receiver's copy of a file, which the sender's version is rebuilt from.
+#[derive(Debug)]
+pub struct Basis<F> {
+    /// The copy of the file.
+    file: F,
+    /// The size of its blocks.
+    block_size: u32,
+    /// The number of blocks in the signature.
+    blocks: u32,
+}
+
+impl<F: Read + Seek> Basis<F> {
+    /// Splits a file into blocks and creates the signature the sender needs.
+    ///
+    /// The bytes after the last whole block are not part of any block.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The receiver's copy of the file, read from its start.
+    /// * `size` - The size of the copy.
+    /// * `index` - The index of the file in the sender's list.
+    ///
+    /// # Returns
+    ///
+    /// The basis and its signature, or an error if the copy could not be read.
+    pub fn new(mut file: F, size: u64, index: u32) -> io::Result<(Basis<F>, SignaturePacket)> {
+        let block_size = block_size(size);
+        let mut block = vec![0u8; block_size as usize];
+        let mut blocks = vec![];
+        for _ in 0..size / u64::from(block_size) {
+            file.read_exact(&mut block)?;
+            blocks.push(signature_packet::Block {
+                weak: Rolling::new(&block).digest(),
+                strong: strong(&block),
+            });
+        }
+
+        let basis = Basis {
+            file,
+            block_size,
+            blocks: blocks.len() as u32,
+        };
+        let signature = SignaturePacket {
+            index,
+            block_size,
+            blocks,
+        };
+        Ok((basis, signature))
+    }
+
+    /// Rebuilds a part of the sender's version of the file.
+    ///
+    /// # Arguments
+    ///
+    /// * `delta` - The part, as the sender described it.
+    /// * `limit` - The most bytes the part may have, which is what is left of the file.
+    ///
+    /// # Returns
+    ///
+    /// The bytes of the part, or an error if the delta does not fit the signature or the
+    /// copy could not be read.
+    pub fn patch(&mut self, delta: DeltaPacket, limit: u64) -> io::Result<Vec<u8>> {
+        let mut part = vec![];
+        for op in delta.ops {
+            let length = match &op.value {
+                Some(delta_packet::op::Value::Block(block)) if *block < self.blocks => {
+                    self.block_size as usize
+                }
+                Some(delta_packet::op::Value::Data(data)) => data.len(),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "The delta names a block the signature does not have",
+                    ))
+                }
+            };
+            if (part.len() + length) as u64 > limit {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "The delta describes more than the file",
+                ));
+            }
+
+            match op.value {
+                Some(delta_packet::op::Value::Block(block)) => {
+                    let start = part.len();
+                    part.resize(start + length, 0);
+                    self.file.seek(SeekFrom::Start(
+                        u64::from(block) * u64::from(self.block_size),
+                    ))?;
+                    self.file.read_exact(&mut part[start..])?;
+                }
+                Some(delta_packet::op::Value::Data(data)) => part.extend_from_slice(&data),
+                None => {}
+            }
+        }
+        Ok(part)
+    }
+}
+
+/// Describes the sender's version of a file with the blocks of the receiver's copy.
+pub struct Encoder<R> {
+    /// The sender's version of the file, read from its start.
+    reader: R,
+    /// The size of the blocks.
+    block_size: usize,
+    /// The position and strong checksum of every block, by its weak checksum.
+    blocks: HashMap<u32, Vec<(u32, Vec<u8>)>>,
+    /// The bytes that were read but not described yet.
+    buffer: Vec<u8>,
+    /// The start of the bytes in the buffer that match no block.
+    literal: usize,
+    /// The start of the window in the buffer.
+    position: usize,
+    /// The weak checksum of the window, if it has been computed.
+    rolling: Option<Rolling>,
+    /// Whether the whole file has been read.
+    exhausted: bool,
+}
+
+impl<R: Read> Encoder<R> {
+    /// Creates an encoder for the signature the receiver sent.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The sender's version of the file.
+    /// * `signature` - The signature of the receiver's copy.
+    ///
+    /// # Returns
+    ///
+    /// A new `Encoder` instance.
+    pub fn new(reader: R, signature: SignaturePacket) -> Encoder<R> {
+        let mut blocks: HashMap<u32, Vec<(u32, Vec<u8>)>> = HashMap::new();
+        for (position, block) in (0..).zip(signature.blocks) {
+            blocks
+                .entry(block.weak)
+                .or_default()
+                .push((position, block.strong));
+        }
+
+        Encoder {
+            reader,
+            block_size: signature.block_size.max(1) as usize,
+            blocks,
+            buffer: vec![],
+            literal: 0,
+            position: 0,
+            rolling: None,
+            exhausted: false,
+        }
+    }
+
+    /// Describes the next part of the file.
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - The number of bytes of the file a part describes. A part that ends
+    ///   with a block may describe up to a block more.
+    ///
+    /// # Returns
+    ///
+    /// The next part, `None` once the whole file is described, or an error if the file
+    /// could not be read.
+    pub fn next_part(&mut self, limit: usize) -> io::Result<Option<DeltaPacket>> {
+        let mut ops = vec![];
+        let mut described = 0;
+
+        while described < limit {
+            self.fill()?;
+
+            // The bytes after the last whole window are sent as they are
+            let end = self.position + self.block_size;
+            if end > self.buffer.len() {
+                self.flush(&mut ops, self.buffer.len());
+                self.position = self.buffer.len();
+                break;
+            }
+
+            let weak = self
+                .rolling
+                .get_or_insert_with(|| Rolling::new(&self.buffer[self.position..end]))
+                .digest();
+            if let Some(block) = self.find(weak, &self.buffer[self.position..end]) {
+                described += self.flush(&mut ops, self.position);
+                ops.push(delta_packet::Op {
+                    value: Some(delta_packet::op::Value::Block(block)),
+                });
+                described += self.block_size;
+
+                self.position = end;
+                self.literal = end;
+                self.rolling = None;
+                continue;
+            }
+
+            // Move the window on by a byte
+            match (self.rolling.as_mut(), self.buffer.get(end)) {
+                (Some(rolling), Some(&next)) => rolling.roll(self.buffer[self.position], next),
+                _ => self.rolling = None,
+            }
+            self.position += 1;
+
+            // Bytes that match no block are sent once they fill the part
+            if described + (self.position - self.literal) >= limit {
+                described += self.flush(&mut ops, self.position);
+            }
+        }
+
+        Ok((!ops.is_empty()).then_some(DeltaPacket { ops }))
+    }
+
+    /// Looks up the block a window matches.
+    ///
+    /// # Arguments
+    ///
+    /// * `weak` - The weak checksum of the window.
+    /// * `window` - The bytes in the window.
+    ///
+    /// # Returns
+    ///
+    /// The position of the block in the signature, or `None` if no block matches.
+    fn find(&self, weak: u32, window: &[u8]) -> Option<u32> {
+        let candidates = self.blocks.get(&weak)?;
+        let hash = strong(window);
+        candidates
+            .iter()
+            .find(|(_, strong)| *strong == hash)
+            .map(|(position, _)| *position)
+    }
+
+    /// Adds the bytes that match no block up to a position to the part.
+    ///
+    /// # Arguments
+    ///
+    /// * `ops` - The operations of the part.
+    /// * `end` - The position in the buffer up to which the bytes are added.
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes that were added.
+    fn flush(&mut self, ops: &mut Vec<delta_packet::Op>, end: usize) -> usize {
+        let data = &self.buffer[self.literal..end];
+        if !data.is_empty() {
+            ops.push(delta_packet::Op {
+                value: Some(delta_packet::op::Value::Data(Bytes::copy_from_slice(data))),
+            });
+        }
+        self.literal = end;
+        data.len()
+    }
+
+    /// Reads on until the buffer holds the window and the byte after it, or the file
+    /// ends.
+    fn fill(&mut self) -> io::Result<()> {
+        while !self.exhausted && self.buffer.len() <= self.position + self.block_size {
+            // Drop the bytes that are described already
+            self.buffer.drain(..self.literal);
+            self.position -= self.literal;
+            self.literal = 0;
+
+            let read = (&mut self.reader)
+                .take(READ_SIZE)
+                .read_to_end(&mut self.buffer)?;
+            self.exhausted = read == 0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, RngCore, SeedableRng};
+    use std::io::Cursor;
+
+    /// Returns random bytes that are the same on every run.
+    fn random_bytes(length: usize, seed: u64) -> Vec<u8> {
+        let mut bytes = vec![0u8; length];
+        StdRng::seed_from_u64(seed).fill_bytes(&mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn test_block_size() {
+        assert_eq!(block_size(0), 1024);
+        assert_eq!(block_size(10_000), 1024);
+        assert_eq!(block_size(100_000_000), 10240);
+        assert_eq!(block_size(1 << 40), MAX_BLOCK_SIZE);
+    }
+
+    #[test]
+    fn test_rolling() {
+        let bytes = random_bytes(300, 1);
+        let mut rolling = Rolling::new(&bytes[..100]);
+        for start in 1..200 {
+            rolling.roll(bytes[start - 1], bytes[start + 99]);
+            assert_eq!(
+                rolling.digest(),
+                Rolling::new(&bytes[start..start + 100]).digest()
+            );
+        }
+    }
+
+    #[test]
+    fn test_delta_round_trip() {
+        let old = random_bytes(64 * 1024, 2);
+
+        // Insert bytes, change a byte and append some, which shifts every block after
+        let mut new = old[..10_000].to_vec();
+        new.extend(random_bytes(500, 3));
+        new.extend_from_slice(&old[10_000..40_000]);
+        new.push(old[40_000] ^ 0xff);
+        new.extend_from_slice(&old[40_001..]);
+        new.extend(random_bytes(700, 4));
+
+        let (mut basis, signature) =
+            Basis::new(Cursor::new(old.clone()), old.len() as u64, 7).unwrap();
+        assert_eq!(signature.index, 7);
+        assert_eq!(signature.blocks.len(), 64);
+
+        let mut encoder = Encoder::new(Cursor::new(new.clone()), signature);
+        let mut rebuilt = vec![];
+        let mut sent = 0;
+        while let Some(delta) = encoder.next_part(8 * 1024).unwrap() {
+            sent += delta
+                .ops
+                .iter()
+                .map(|op| match &op.value {
+                    Some(delta_packet::op::Value::Data(data)) => data.len(),
+                    _ => 0,
+                })
+                .sum::<usize>();
+            let limit = (new.len() - rebuilt.len()) as u64;
+            rebuilt.extend(basis.patch(delta, limit).unwrap());
+        }
+
+        assert_eq!(rebuilt, new);
+        // Only the changed blocks and the bytes around them were sent
+        assert!(sent < 5 * 1024, "{sent} bytes were sent");
+    }
+
+    #[test]
+    fn test_patch_rejects_invalid_deltas() {
+        let old = random_bytes(4096, 5);
+        let (mut basis, _) = Basis::new(Cursor::new(old), 4096, 0).unwrap();
+        let block = |block| DeltaPacket {
+            ops: vec![delta_packet::Op {
+                value: Some(delta_packet::op::Value::Block(block)),
+            }],
+        };
+
+        assert_eq!(basis.patch(block(3), 4096).unwrap().len(), 1024);
+        assert!(basis.patch(block(4), 4096).is_err());
+        assert!(basis.patch(block(0), 1000).is_err());
+    }
+}
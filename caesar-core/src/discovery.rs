@@ -0,0 +1,228 @@
+//! Finding senders on the local network without the relay.
+//!
+//! While the sender's local WebSocket server runs, the sender announces the transfer
+//! over mDNS/DNS-SD as a `_caesar._tcp` service. The instance is named after the
+//! transfer, and a TXT record carries the room ID. A receiver in discovery mode browses
+//! for the service, so it only needs the PIN to reach a sender on the same network.
+//!
+//! The PIN is never announced, so anyone on the network can see that a transfer is
+//! waiting, but only the receiver with the PIN can complete the handshake.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use tracing::debug;
+
+use crate::error::{CaesarError, Result};
+
+/// The DNS-SD service type of the sender's local server.
+pub const SERVICE_TYPE: &str = "_caesar._tcp.local.";
+
+/// The TXT property that holds the room ID.
+const ROOM_PROPERTY: &str = "room";
+
+/// How long a receiver listens for announcements by default.
+pub const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A transfer announced on the local network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    /// The name of the transfer, without the PIN.
+    pub name: String,
+    /// The address of the sender's local server.
+    pub address: IpAddr,
+    /// The port of the sender's local server.
+    pub port: u16,
+    /// The room the sender waits in on its local server.
+    pub room_id: String,
+}
+
+impl Peer {
+    /// Reads a peer from a resolved service.
+    ///
+    /// # Arguments
+    ///
+    /// * `info` - The resolved service.
+    ///
+    /// # Returns
+    ///
+    /// The peer, or `None` if the service has no IPv4 address or no room.
+    fn from_info(info: &ServiceInfo) -> Option<Peer> {
+        let name = info
+            .get_fullname()
+            .strip_suffix(SERVICE_TYPE)?
+            .trim_end_matches('.')
+            .to_string();
+        // Prefer IPv4, as a link-local IPv6 address is not reached without its interface
+        let address = info.get_addresses_v4().into_iter().next()?;
+
+        Some(Peer {
+            name,
+            address: IpAddr::V4(*address),
+            port: info.get_port(),
+            room_id: info.get_property_val_str(ROOM_PROPERTY)?.to_string(),
+        })
+    }
+
+    /// Returns the WebSocket URL of the sender's local server.
+    pub fn url(&self) -> String {
+        format!("ws://{}", SocketAddr::new(self.address, self.port))
+    }
+}
+
+/// A transfer that is announced until this is dropped.
+pub struct Announcement {
+    /// The daemon that answers the queries of receivers.
+    daemon: ServiceDaemon,
+}
+
+impl Drop for Announcement {
+    fn drop(&mut self) {
+        // The daemon says goodbye, so receivers forget the transfer at once
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// Announces a transfer on the local network.
+///
+/// # Arguments
+///
+/// * `name` - The name of the transfer, without the PIN.
+/// * `room_id` - The room the sender waits in on its local server.
+/// * `port` - The port of the local server.
+///
+/// # Returns
+///
+/// The announcement, which ends when it is dropped.
+pub fn announce(name: &str, room_id: &str, port: u16) -> Result<Announcement> {
+    let daemon = ServiceDaemon::new().map_err(discovery_error)?;
+    let info = ServiceInfo::new(
+        SERVICE_TYPE,
+        name,
+        &format!("{}.local.", name),
+        (),
+        port,
+        &[(ROOM_PROPERTY, room_id)][..],
+    )
+    .map_err(discovery_error)?
+    // Answer with the addresses of every interface
+    .enable_addr_auto();
+
+    daemon.register(info).map_err(discovery_error)?;
+    debug!("Announced '{}' on the local network", name);
+
+    Ok(Announcement { daemon })
+}
+
+/// Looks for transfers announced on the local network.
+///
+/// # Arguments
+///
+/// * `timeout` - How long to listen for announcements.
+///
+/// # Returns
+///
+/// Every transfer that was announced within the timeout, in the order they were found.
+pub async fn discover(timeout: Duration) -> Result<Vec<Peer>> {
+    let daemon = ServiceDaemon::new().map_err(discovery_error)?;
+    let events = daemon.browse(SERVICE_TYPE).map_err(discovery_error)?;
+    let mut peers: Vec<Peer> = vec![];
+
+    let deadline = tokio::time::sleep(timeout);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            event = events.recv_async() => match event {
+                Ok(ServiceEvent::ServiceResolved(info)) => {
+                    match Peer::from_info(&info) {
+                        Some(peer) if !peers.contains(&peer) => peers.push(peer),
+                        _ => {}
+                    }
+                }
+                Ok(_) => {}
+                Err(_) => break,
+            },
+        }
+    }
+
+    let _ = daemon.shutdown();
+    Ok(peers)
+}
+
+/// Picks the transfer to receive from the ones found on the local network.
+///
+/// # Arguments
+///
+/// * `peers` - The transfers found.
+/// * `name` - The name of the transfer, or an empty string to take the only one found.
+///
+/// # Returns
+///
+/// The transfer, or an error if none or several match.
+pub fn select_peer(peers: Vec<Peer>, name: &str) -> Result<Peer> {
+    let mut matching: Vec<Peer> = peers
+        .into_iter()
+        .filter(|peer| name.is_empty() || peer.name.eq_ignore_ascii_case(name))
+        .collect();
+
+    match matching.len() {
+        0 if name.is_empty() => Err(CaesarError::TransferNotFound(
+            "any transfer on the local network".to_string(),
+        )),
+        0 => Err(CaesarError::TransferNotFound(name.to_string())),
+        1 => Ok(matching.remove(0)),
+        _ => {
+            let names: Vec<&str> = matching.iter().map(|peer| peer.name.as_str()).collect();
+            Err(CaesarError::Discovery(format!(
+                "Several transfers are announced: {}. Enter the whole code of yours.",
+                names.join(", ")
+            )))
+        }
+    }
+}
+
+/// Wraps an error of the mDNS daemon.
+fn discovery_error(error: mdns_sd::Error) -> CaesarError {
+    CaesarError::Discovery(error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_peer() {
+        let peer = |name: &str| Peer {
+            name: name.to_string(),
+            address: IpAddr::from([192, 168, 1, 20]),
+            port: 9000,
+            room_id: "room".to_string(),
+        };
+
+        assert_eq!(
+            select_peer(vec![peer("brave-tiger-42")], "").unwrap(),
+            peer("brave-tiger-42")
+        );
+        assert_eq!(
+            select_peer(
+                vec![peer("brave-tiger-42"), peer("calm-otter-7")],
+                "Calm-Otter-7"
+            )
+            .unwrap()
+            .url(),
+            "ws://192.168.1.20:9000"
+        );
+        assert!(matches!(
+            select_peer(vec![peer("brave-tiger-42"), peer("calm-otter-7")], ""),
+            Err(CaesarError::Discovery(_))
+        ));
+        assert!(matches!(
+            select_peer(vec![], "brave-tiger-42"),
+            Err(CaesarError::TransferNotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,219 @@
+use std::{fmt, io, mem};
+
+use crate::sender::util::PathProblem;
+
+/// A specialized `Result` type for caesar operations.
+pub type Result<T> = std::result::Result<T, CaesarError>;
+
+/// The errors a transfer can end with.
+///
+/// Every public entry point of the sender and the receiver returns this error, so
+/// embedders can tell apart problems they can act on, like an unreachable relay or a
+/// mistyped transfer name, from failures of the transfer itself.
+#[derive(Debug, thiserror::Error)]
+pub enum CaesarError {
+    /// The relay could not be reached.
+    #[error("The relay '{relay}' is unreachable: {reason}")]
+    RelayUnreachable {
+        /// The URL of the relay.
+        relay: String,
+        /// Why the connection failed.
+        reason: String,
+    },
+
+    /// The connection broke down after it was established.
+    #[error("Connection error: {0}")]
+    Connection(String),
+
+    /// The relay rejected the token, the quota of the user is used up, or the client
+    /// exceeded a rate limit.
+    #[error("The relay refused the request: {0}")]
+    Refused(String),
+
+    /// The relay does not know a transfer with the given name.
+    #[error("The transfer '{0}' does not exist.")]
+    TransferNotFound(String),
+
+    /// The invite code handed to the receiver is malformed.
+    #[error("The invite code '{0}' is not valid.")]
+    InvalidInviteCode(String),
+
+    /// The peers could not agree on a shared key.
+    #[error("Handshake failed: {0}")]
+    Handshake(String),
+
+    /// The peers do not share the same PIN, so the transfer was ended to prevent further
+    /// guesses.
+    #[error("The transfer code is wrong: {0}")]
+    WrongCode(String),
+
+    /// A packet could not be encrypted or decrypted.
+    #[error("Crypto error: {0}")]
+    Crypto(String),
+
+    /// A received file already exists and the conflict policy forbids replacing it.
+    #[error("The file '{0}' already exists.")]
+    FileExists(String),
+
+    /// Reading or writing a local file failed.
+    #[error("{context}: {source}")]
+    Io {
+        /// What was being done when the error occurred.
+        context: String,
+        /// The underlying error.
+        #[source]
+        source: io::Error,
+    },
+
+    /// The paths given to the sender cannot be sent.
+    #[error("{}", InvalidPaths(.0))]
+    InvalidPaths(Vec<PathProblem>),
+
+    /// The text given to the sender is longer than a snippet may be.
+    #[error("The text has {0} bytes, but a snippet may have at most {max}. Send it as a file instead.", max = crate::sender::MAX_TEXT_SIZE)]
+    TextTooLong(usize),
+
+    /// Looking for the sender on the local network failed.
+    #[error("Local discovery failed: {0}")]
+    Discovery(String),
+
+    /// The other peer sent something unexpected or reported an error.
+    #[error("{0}")]
+    Protocol(String),
+
+    /// The transfer was cancelled through its cancellation token.
+    #[error("The transfer was cancelled.")]
+    Cancelled,
+
+    /// The other peer did not respond for longer than the idle timeout.
+    #[error("The other side did not respond for {0} seconds, the transfer was closed.")]
+    IdleTimeout(u64),
+
+    /// The system was suspended and the session was closed to be re-established.
+    #[error("The system was suspended for {0} seconds.")]
+    Suspended(u64),
+}
+
+impl CaesarError {
+    /// Creates an IO error with a description of what was being done.
+    ///
+    /// # Arguments
+    ///
+    /// * `context` - What was being done when the error occurred.
+    /// * `source` - The underlying error.
+    pub fn io(context: impl Into<String>, source: io::Error) -> CaesarError {
+        CaesarError::Io {
+            context: context.into(),
+            source,
+        }
+    }
+
+    /// Creates the error for a relay that refused a request.
+    ///
+    /// # Arguments
+    ///
+    /// * `status` - The HTTP status the relay answered with.
+    ///
+    /// # Returns
+    ///
+    /// The error, or `None` if the status does not refuse the request.
+    pub(crate) fn refused(status: reqwest::StatusCode) -> Option<CaesarError> {
+        match status {
+            reqwest::StatusCode::UNAUTHORIZED
+            | reqwest::StatusCode::FORBIDDEN
+            | reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                Some(CaesarError::Refused(status.to_string()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns whether a new session might get past this error.
+    ///
+    /// Errors caused by the user or by local files happen again in every session, all
+    /// others may be caused by the connection breaking down.
+    pub fn is_recoverable(&self) -> bool {
+        !matches!(
+            self,
+            CaesarError::Cancelled
+                | CaesarError::IdleTimeout(_)
+                | CaesarError::InvalidInviteCode(_)
+                | CaesarError::WrongCode(_)
+                | CaesarError::Refused(_)
+                | CaesarError::InvalidPaths(_)
+                | CaesarError::TextTooLong(_)
+                | CaesarError::Discovery(_)
+                | CaesarError::FileExists(_)
+                | CaesarError::Io { .. }
+        )
+    }
+}
+
+/// Errors are equal if they are of the same kind and have the same message.
+impl PartialEq for CaesarError {
+    fn eq(&self, other: &Self) -> bool {
+        mem::discriminant(self) == mem::discriminant(other) && self.to_string() == other.to_string()
+    }
+}
+
+impl From<String> for CaesarError {
+    fn from(message: String) -> Self {
+        CaesarError::Protocol(message)
+    }
+}
+
+impl From<&str> for CaesarError {
+    fn from(message: &str) -> Self {
+        CaesarError::Protocol(message.to_string())
+    }
+}
+
+/// Lists every problem of the paths on its own line.
+struct InvalidPaths<'a>(&'a [PathProblem]);
+
+impl fmt::Display for InvalidPaths<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The following files cannot be sent:")?;
+        for problem in self.0 {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_messages() {
+        let error = CaesarError::InvalidPaths(vec![PathProblem {
+            path: "a.txt".into(),
+            reason: "does not exist".into(),
+            fix: "Check the spelling.".into(),
+        }]);
+        assert_eq!(
+            error.to_string(),
+            "The following files cannot be sent:\n  - 'a.txt' does not exist. Check the spelling."
+        );
+
+        let error = CaesarError::io("Failed to open 'a.txt'", io::ErrorKind::NotFound.into());
+        assert_eq!(error.to_string(), "Failed to open 'a.txt': entity not found");
+
+        assert_eq!(CaesarError::from("Oops."), CaesarError::Protocol("Oops.".into()));
+        assert_ne!(
+            CaesarError::Handshake("Oops.".into()),
+            CaesarError::Protocol("Oops.".into())
+        );
+    }
+
+    #[test]
+    fn test_is_recoverable() {
+        assert!(CaesarError::Suspended(60).is_recoverable());
+        assert!(CaesarError::Connection("Closed.".into()).is_recoverable());
+        assert!(!CaesarError::Cancelled.is_recoverable());
+        assert!(!CaesarError::Refused("401 Unauthorized".into()).is_recoverable());
+        assert!(!CaesarError::FileExists("a.txt".into()).is_recoverable());
+        assert!(!CaesarError::WrongCode("Oops.".into()).is_recoverable());
+    }
+}
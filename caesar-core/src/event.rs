@@ -0,0 +1,158 @@
+//! Events of a transfer for programs that drive the clients.
+//!
+//! The clients print what happens for a human to read. A program that reads the
+//! transfer instead, like a script behind `--json` or a frontend, passes an event
+//! report in the options and is told each step as a `TransferEvent`, which serializes
+//! to a line of JSON. Simulated transfers play the same events.
+
+use std::{fmt, sync::Arc};
+
+use serde::Serialize;
+
+/// A file the sender lists.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ListedFile {
+    /// The name of the file.
+    pub name: String,
+    /// The size of the file in bytes, zero for a stream.
+    pub size: u64,
+}
+
+/// Something that happened in a transfer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum TransferEvent {
+    /// The sender created the room the receivers join. Only the sender has this event.
+    #[serde(rename_all = "camelCase")]
+    RoomCreated {
+        /// The ID of the room.
+        room_id: String,
+        /// The transfer code the receivers enter.
+        code: String,
+    },
+    /// The peers completed the handshake.
+    Handshake {
+        /// The name of the transfer.
+        name: String,
+    },
+    /// The code both sides show after the handshake. If it differs, someone is in the
+    /// middle of the connection.
+    Verification {
+        /// The code, six digits in two groups.
+        code: String,
+        /// The receiver the code is shared with, only told by a sender with several of
+        /// them.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        receiver: Option<usize>,
+    },
+    /// The sender listed the files of the transfer.
+    List {
+        /// The files in the order they are sent.
+        files: Vec<ListedFile>,
+    },
+    /// More of a file has arrived.
+    Progress {
+        /// The index of the file in the list.
+        file: usize,
+        /// The receiver the file arrived at, only told by a sender with several of them.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        receiver: Option<usize>,
+        /// The bytes of the file that have arrived so far.
+        transferred: u64,
+        /// The size of the file in bytes.
+        size: u64,
+    },
+    /// A file is sent again from its start.
+    Retry {
+        /// The index of the file in the list.
+        file: usize,
+        /// Why the file is sent again.
+        reason: String,
+    },
+    /// The connection broke down and is re-established.
+    Reconnect {
+        /// The number of the attempt, starting at one.
+        attempt: u32,
+    },
+    /// The sender sent a text in place of files.
+    Text {
+        /// The text.
+        text: String,
+    },
+    /// The transfer ended with an error. This is the last event.
+    Error {
+        /// The message of the error.
+        message: String,
+    },
+    /// Every file has arrived. This is the last event.
+    Complete {
+        /// The number of files.
+        files: usize,
+        /// The size of all files in bytes.
+        bytes: u64,
+    },
+}
+
+/// A function that is told the events of a transfer.
+type Report = dyn Fn(&TransferEvent) + Send + Sync;
+
+/// Receives the events of a transfer.
+#[derive(Clone)]
+pub struct EventReport(Arc<Report>);
+
+impl EventReport {
+    /// Creates a report from a function, e.g. one that prints the events as JSON.
+    ///
+    /// # Arguments
+    ///
+    /// * `report` - Called with every event of the transfer.
+    pub fn new<F>(report: F) -> EventReport
+    where
+        F: Fn(&TransferEvent) + Send + Sync + 'static,
+    {
+        EventReport(Arc::new(report))
+    }
+
+    /// Reports an event.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event.
+    pub fn report(&self, event: TransferEvent) {
+        (self.0)(&event)
+    }
+}
+
+impl fmt::Debug for EventReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EventReport")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_json() {
+        let event = TransferEvent::Progress {
+            file: 1,
+            receiver: None,
+            transferred: 512,
+            size: 1024,
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"type":"progress","file":1,"transferred":512,"size":1024}"#
+        );
+
+        let event = TransferEvent::RoomCreated {
+            room_id: "room".into(),
+            code: "482913-brave-tiger-42".into(),
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"type":"roomCreated","roomId":"room","code":"482913-brave-tiger-42"}"#
+        );
+    }
+}
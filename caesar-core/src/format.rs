@@ -0,0 +1,216 @@
+//! Formatting of sizes, speeds and durations for people.
+//!
+//! Sizes use binary units like `2.4 GiB`, and the decimal separator follows the locale
+//! of the user, so a German user reads `2,4 GiB`. The locale is taken from `LC_ALL`,
+//! `LC_NUMERIC` or `LANG`, like the C library does.
+
+use std::{env, sync::OnceLock, time::Duration};
+
+/// The units of sizes, each 1024 times the one before.
+const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+
+/// The languages that write a comma before the decimals.
+const COMMA_LANGUAGES: [&str; 26] = [
+    "bg", "cs", "da", "de", "el", "es", "et", "fi", "fr", "hr", "hu", "id", "it", "lt", "lv", "nb",
+    "nl", "nn", "pl", "pt", "ro", "ru", "sk", "sl", "sv", "uk",
+];
+
+/// How numbers are written for the user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NumberFormat {
+    /// The character between the integer part and the decimals.
+    pub decimal_separator: char,
+}
+
+impl Default for NumberFormat {
+    fn default() -> Self {
+        NumberFormat {
+            decimal_separator: '.',
+        }
+    }
+}
+
+impl NumberFormat {
+    /// Returns the format of the user's locale, read from the environment once.
+    pub fn current() -> NumberFormat {
+        static CURRENT: OnceLock<NumberFormat> = OnceLock::new();
+        *CURRENT.get_or_init(|| {
+            ["LC_ALL", "LC_NUMERIC", "LANG"]
+                .iter()
+                .filter_map(|name| env::var(name).ok())
+                .find(|locale| !locale.is_empty())
+                .map_or_else(NumberFormat::default, |locale| {
+                    NumberFormat::for_locale(&locale)
+                })
+        })
+    }
+
+    /// Returns the format of a locale.
+    ///
+    /// # Arguments
+    ///
+    /// * `locale` - The locale like `de_DE.UTF-8`, `fr` or `C`.
+    pub fn for_locale(locale: &str) -> NumberFormat {
+        let language = locale
+            .split(['_', '-', '.', '@'])
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+
+        NumberFormat {
+            decimal_separator: if COMMA_LANGUAGES.contains(&language.as_str()) {
+                ','
+            } else {
+                '.'
+            },
+        }
+    }
+
+    /// Formats a size, like `512 B` or `2.4 GiB`.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The size in bytes.
+    pub fn size(&self, bytes: u64) -> String {
+        let mut value = bytes as f64;
+        let mut unit = 0;
+        while value >= 1024.0 && unit < UNITS.len() - 1 {
+            value /= 1024.0;
+            unit += 1;
+        }
+
+        // Bytes have no fractions
+        if unit == 0 {
+            return format!("{} B", bytes);
+        }
+        format!("{} {}", self.decimal(value), UNITS[unit])
+    }
+
+    /// Formats a speed, like `12.3 MiB/s`.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The number of bytes transferred.
+    /// * `elapsed` - The time it took to transfer them.
+    pub fn speed(&self, bytes: u64, elapsed: Duration) -> String {
+        // Right after the start, the speed of the first chunk would be far off
+        let seconds = elapsed.as_secs_f64().max(0.001);
+        format!("{}/s", self.size((bytes as f64 / seconds) as u64))
+    }
+
+    /// Formats a duration with its two largest units, like `45s`, `3m 05s` or `1h 02m`.
+    ///
+    /// # Arguments
+    ///
+    /// * `duration` - The duration to format, rounded down to seconds.
+    pub fn duration(&self, duration: Duration) -> String {
+        let seconds = duration.as_secs();
+        match seconds {
+            0..=59 => format!("{}s", seconds),
+            60..=3599 => format!("{}m {:02}s", seconds / 60, seconds % 60),
+            _ => format!("{}h {:02}m", seconds / 3600, seconds % 3600 / 60),
+        }
+    }
+
+    /// Formats a number with one decimal and the decimal separator of the format.
+    fn decimal(&self, value: f64) -> String {
+        format!("{:.1}", value).replace('.', &self.decimal_separator.to_string())
+    }
+}
+
+/// Parses a size a person wrote, like `500`, `64K`, `1.5 GiB` or `2G`.
+///
+/// The units are binary like the ones sizes are shown with, so `K`, `KB` and `KiB` all
+/// stand for 1024 bytes. A number without a unit is a number of bytes.
+///
+/// # Arguments
+///
+/// * `text` - The size to parse.
+///
+/// # Returns
+///
+/// The number of bytes, or the message to show if the text is no size.
+pub fn parse_size(text: &str) -> Result<u64, String> {
+    let invalid = || format!("'{}' is not a size like 500M or 2GiB.", text);
+
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+
+    let unit = unit.trim().to_ascii_uppercase();
+    let prefix = unit
+        .strip_suffix("IB")
+        .or_else(|| unit.strip_suffix('B'))
+        .unwrap_or(&unit);
+    let exponent = match prefix {
+        "" => Some(0),
+        _ if prefix.len() == 1 => (1..UNITS.len()).find(|&unit| UNITS[unit].starts_with(prefix)),
+        _ => None,
+    }
+    .ok_or_else(invalid)?;
+
+    let bytes = number * 1024f64.powi(exponent as i32);
+    if bytes >= u64::MAX as f64 {
+        return Err(invalid());
+    }
+    Ok(bytes as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_size() {
+        let format = NumberFormat::default();
+        assert_eq!(format.size(0), "0 B");
+        assert_eq!(format.size(1023), "1023 B");
+        assert_eq!(format.size(1536), "1.5 KiB");
+        assert_eq!(format.size(2_576_980_378), "2.4 GiB");
+        assert_eq!(format.size(u64::MAX), "16384.0 PiB");
+        assert_eq!(
+            NumberFormat::for_locale("de_DE.UTF-8").size(1536),
+            "1,5 KiB"
+        );
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("500"), Ok(500));
+        assert_eq!(parse_size("64K"), Ok(64 * 1024));
+        assert_eq!(parse_size("1.5 GiB"), Ok(1536 * 1024 * 1024));
+        assert_eq!(parse_size("2gb"), Ok(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_size("10 B"), Ok(10));
+        assert!(parse_size("lots").is_err());
+        assert!(parse_size("5X").is_err());
+        assert!(parse_size("1e9").is_err());
+        assert!(parse_size("99999999 PiB").is_err());
+    }
+
+    #[test]
+    fn test_speed_and_duration() {
+        let format = NumberFormat::default();
+        assert_eq!(
+            format.speed(25 * 1024 * 1024, Duration::from_secs(2)),
+            "12.5 MiB/s"
+        );
+        assert_eq!(format.speed(1024, Duration::ZERO), "1000.0 KiB/s");
+        assert_eq!(format.duration(Duration::from_secs(45)), "45s");
+        assert_eq!(format.duration(Duration::from_secs(185)), "3m 05s");
+        assert_eq!(format.duration(Duration::from_secs(3720)), "1h 02m");
+    }
+
+    #[test]
+    fn test_for_locale() {
+        assert_eq!(NumberFormat::for_locale("fr").decimal_separator, ',');
+        assert_eq!(NumberFormat::for_locale("pt-BR").decimal_separator, ',');
+        assert_eq!(
+            NumberFormat::for_locale("en_US.UTF-8").decimal_separator,
+            '.'
+        );
+        assert_eq!(NumberFormat::for_locale("C").decimal_separator, '.');
+    }
+}
@@ -0,0 +1,123 @@
+//! Heartbeats between sender and receiver.
+//!
+//! The relay answers the pings of the idle watchdog itself, so they only show that the
+//! relay is still there. A peer whose laptop went to sleep may leave its connection to
+//! the relay open for minutes, while the other side waits at the same progress. Peers
+//! that agreed on heartbeats in the handshake therefore send each other an encrypted
+//! heartbeat packet every few seconds. Like every other packet it counts as activity
+//! for the watchdog, so a peer that stays silent for the idle timeout is reported as
+//! unresponsive, while a peer that only waits, e.g. for more input on a stream, is not.
+
+use std::{
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::time::sleep;
+
+use crate::shared::{
+    packets::{packet::Value, HeartbeatPacket},
+    PacketSender, Sender, SessionKey,
+};
+
+/// How often a heartbeat is sent, well below the default idle timeout.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Sends heartbeats to a peer until the session ends.
+///
+/// # Arguments
+///
+/// * `sender` - The channel of the session.
+/// * `key` - The key of the peer.
+/// * `destination` - The position of the peer in the room, which changes when a
+///   receiver in front of it leaves.
+pub async fn beat(sender: Sender, key: SessionKey, destination: Arc<AtomicU8>) {
+    beat_every(HEARTBEAT_INTERVAL, sender, key, destination).await
+}
+
+/// Sends heartbeats to a peer in the given interval until the session ends.
+///
+/// # Arguments
+///
+/// * `interval` - The time between two heartbeats.
+/// * `sender` - The channel of the session.
+/// * `key` - The key of the peer.
+/// * `destination` - The position of the peer in the room.
+async fn beat_every(
+    interval: Duration,
+    sender: Sender,
+    key: SessionKey,
+    destination: Arc<AtomicU8>,
+) {
+    let key = Some(key);
+
+    loop {
+        sleep(interval).await;
+
+        // The channel closes with the session
+        if sender.is_disconnected() {
+            return;
+        }
+
+        // A full queue means data is flowing, which shows the peer is there anyway
+        if sender.is_full() {
+            continue;
+        }
+
+        sender.send_encrypted_packet(
+            &key,
+            destination.load(Ordering::Relaxed),
+            Value::Heartbeat(HeartbeatPacket {}),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::decode_packet;
+    use aes_gcm::{Aes128Gcm, Key, KeyInit};
+    use tokio_tungstenite::tungstenite::protocol::Message as WebSocketMessage;
+
+    #[tokio::test]
+    async fn test_beat() {
+        let key: SessionKey = Aes128Gcm::new(Key::<Aes128Gcm>::from_slice(&[0u8; 16])).into();
+        let (sender, receiver) = flume::bounded(1000);
+        let destination = Arc::new(AtomicU8::new(1));
+        let interval = Duration::from_millis(10);
+        let task = tokio::spawn(beat_every(
+            interval,
+            sender,
+            key.clone(),
+            destination.clone(),
+        ));
+
+        // The heartbeats follow the peer to its new position
+        let WebSocketMessage::Binary(data) = receiver.recv_async().await.unwrap() else {
+            panic!("the heartbeat is not binary");
+        };
+        assert_eq!(data[0], 1);
+        destination.store(2, Ordering::Relaxed);
+        while let Ok(WebSocketMessage::Binary(mut data)) = receiver.recv_async().await {
+            if data[0] == 2 {
+                // The receiver decodes what the sender sent it
+                data[0] = 0;
+                assert_eq!(
+                    decode_packet(&Some(key), 0, None, data).unwrap(),
+                    Value::Heartbeat(HeartbeatPacket {})
+                );
+                break;
+            }
+        }
+
+        // The heartbeats stop with the session
+        drop(receiver);
+        tokio::time::timeout(interval * 10, task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,202 @@
+//! Detection of stalled transfers.
+//!
+//! A transfer whose peer stops sending data, for example because the network dropped or
+//! the laptop on the other end went to sleep, would otherwise wait forever. The watchdog
+//! in this module warns after a quiet period, probes the connection with a ping and
+//! gives up after a hard timeout, so the client can close the session and leave the
+//! partial files on disk for a later resume.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use tokio::time::sleep;
+use tokio_tungstenite::tungstenite::protocol::Message as WebSocketMessage;
+use tracing::warn;
+
+use crate::shared::Sender;
+
+/// How long a transfer may stall before the user is warned and the session is closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdleTimeout {
+    /// The quiet period after which a warning is printed and a ping is sent.
+    pub warning: Duration,
+
+    /// The quiet period after which the session is closed. A zero timeout disables
+    /// the watchdog.
+    pub timeout: Duration,
+}
+
+impl IdleTimeout {
+    /// Creates a timeout that warns after a quarter of the hard timeout.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - The quiet period after which the session is closed.
+    ///
+    /// # Returns
+    ///
+    /// A new `IdleTimeout` instance.
+    pub fn new(timeout: Duration) -> IdleTimeout {
+        IdleTimeout {
+            warning: timeout / 4,
+            timeout,
+        }
+    }
+
+    /// Returns whether the watchdog is turned off.
+    pub fn is_disabled(&self) -> bool {
+        self.timeout.is_zero()
+    }
+}
+
+impl Default for IdleTimeout {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(60))
+    }
+}
+
+/// Tracks when the last packet of a transfer arrived.
+///
+/// The tracker is only armed by the first packet, so waiting for the other peer to
+/// join the room never counts as a stall.
+#[derive(Debug)]
+pub struct Activity {
+    /// The reference point of the stored timestamps.
+    epoch: Instant,
+
+    /// Milliseconds since the epoch plus one at the last packet, or zero before the
+    /// first one.
+    last: AtomicU64,
+}
+
+impl Activity {
+    /// Creates a new tracker that has not seen any packet yet.
+    ///
+    /// # Returns
+    ///
+    /// A new `Activity` instance.
+    pub fn new() -> Activity {
+        Activity {
+            epoch: Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    /// Records that a packet arrived.
+    pub fn touch(&self) {
+        let elapsed = self.epoch.elapsed().as_millis() as u64;
+        self.last.store(elapsed + 1, Ordering::Relaxed);
+    }
+
+    /// Returns how long ago the last packet arrived.
+    ///
+    /// # Returns
+    ///
+    /// The quiet period, or `None` if no packet has arrived yet.
+    pub fn idle(&self) -> Option<Duration> {
+        match self.last.load(Ordering::Relaxed) {
+            0 => None,
+            last => {
+                let last = Duration::from_millis(last - 1);
+                Some(self.epoch.elapsed().saturating_sub(last))
+            }
+        }
+    }
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Waits until the transfer has been quiet for longer than the timeout.
+///
+/// Once the warning period has passed, a warning is printed and a ping is sent to keep
+/// the connection alive and to find out whether it is still there. Both happen again
+/// if packets arrive in between and the transfer stalls once more.
+///
+/// # Arguments
+///
+/// * `activity` - The tracker updated by the client for every packet.
+/// * `idle` - The warning and hard timeout.
+/// * `sender` - The channel the ping is sent through.
+pub async fn watch(activity: &Activity, idle: IdleTimeout, sender: &Sender) {
+    // Never return if the watchdog is turned off
+    if idle.is_disabled() {
+        return std::future::pending().await;
+    }
+
+    let tick = (idle.warning.min(idle.timeout) / 2)
+        .clamp(Duration::from_millis(10), Duration::from_secs(1));
+    let mut warned = false;
+
+    loop {
+        sleep(tick).await;
+
+        let Some(quiet) = activity.idle() else {
+            continue;
+        };
+
+        if quiet >= idle.timeout {
+            return;
+        }
+
+        if quiet < idle.warning {
+            warned = false;
+        } else if !warned {
+            warned = true;
+            warn!(
+                "The other side did not respond for {} seconds",
+                quiet.as_secs()
+            );
+            eprintln!();
+            eprintln!(
+                "Warning: The other side did not respond for {} seconds, checking the connection...",
+                quiet.as_secs()
+            );
+            let _ = sender.send(WebSocketMessage::Ping(vec![]));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_activity() {
+        let activity = Activity::new();
+        assert_eq!(activity.idle(), None);
+
+        activity.touch();
+        assert!(activity.idle().unwrap() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_watch() {
+        let (sender, receiver) = flume::unbounded();
+        let activity = Activity::new();
+        let idle = IdleTimeout {
+            warning: Duration::from_millis(40),
+            timeout: Duration::from_millis(120),
+        };
+
+        // Nothing happens before the first packet
+        let unarmed =
+            tokio::time::timeout(Duration::from_millis(200), watch(&activity, idle, &sender)).await;
+        assert!(unarmed.is_err());
+        assert!(receiver.is_empty());
+
+        // A stalled transfer is probed once and then given up
+        activity.touch();
+        watch(&activity, idle, &sender).await;
+        assert_eq!(
+            receiver.drain().collect::<Vec<_>>(),
+            vec![WebSocketMessage::Ping(vec![])]
+        );
+        assert!(activity.idle().unwrap() >= idle.timeout);
+    }
+}
@@ -1,4 +1,28 @@
+#[cfg(feature = "relay")]
+pub mod bench;
+#[cfg(any(test, feature = "chaos"))]
+pub mod chaos;
+pub mod cipher;
+pub mod delta;
+#[cfg(feature = "mdns")]
+pub mod discovery;
+pub mod error;
+pub mod event;
+pub mod format;
+pub mod heartbeat;
+pub mod idle;
+pub mod link;
+pub mod net;
+pub mod pake;
+pub mod progress;
+pub mod punch;
+#[cfg(feature = "quic")]
+pub mod quic;
 pub mod receiver;
 pub mod relay;
 pub mod sender;
 pub mod shared;
+pub mod simulate;
+pub mod status;
+pub mod summary;
+pub mod suspend;
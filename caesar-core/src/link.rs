@@ -0,0 +1,197 @@
+//! One-time download links for recipients who cannot run a client.
+//!
+//! The sender encrypts a small file and leaves it on the relay. The key is only part of
+//! the fragment of the link, which browsers never send, so the relay keeps nothing it
+//! could decrypt the file with. The page behind the link fetches the file once and
+//! decrypts it in the browser. The relay drops the file after that download, or once
+//! the room TTL has passed.
+
+use std::{fs, path::Path};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes128Gcm, Key,
+};
+use base64::{engine::general_purpose, Engine as _};
+use uuid::Uuid;
+
+use crate::error::{CaesarError, Result};
+use crate::format::NumberFormat;
+use crate::sender::util::{check_paths, replace_protocol, PathProblem, STDIN_PATH};
+
+/// The largest file a download link is created for, as browsers decrypt it in memory.
+pub const MAX_LINK_SIZE: u64 = 64 * 1024 * 1024;
+
+/// The length of the nonce in front of the encrypted file.
+const NONCE_SIZE: usize = 12;
+
+/// Leaves a file on the relay and creates a link to download it once.
+///
+/// # Arguments
+///
+/// * `relay` - The URL of the relay.
+/// * `path` - The path of the file.
+/// * `token` - The access token, if the relay requires authentication.
+///
+/// # Returns
+///
+/// The link, with the key in its fragment, or the error if the file cannot be read or
+/// the relay does not take it.
+pub async fn create_link(relay: &str, path: &str, token: Option<&str>) -> Result<String> {
+    let problem = |reason: &str, fix: &str| {
+        CaesarError::InvalidPaths(vec![PathProblem {
+            path: path.to_string(),
+            reason: reason.to_string(),
+            fix: fix.to_string(),
+        }])
+    };
+
+    // Only a regular file can be read up front and decrypted as a whole
+    if path == STDIN_PATH {
+        return Err(problem(
+            "cannot be left on the relay",
+            "Save the input to a file and pass that instead.",
+        ));
+    }
+    let problems = check_paths(&[path.to_string()], false, false);
+    if !problems.is_empty() {
+        return Err(CaesarError::InvalidPaths(problems));
+    }
+    let size = fs::metadata(path)
+        .map_err(|error| CaesarError::io(format!("Failed to read '{}'", path), error))?
+        .len();
+    if size > MAX_LINK_SIZE {
+        return Err(problem(
+            &format!(
+                "is larger than the {} a download link may have",
+                NumberFormat::current().size(MAX_LINK_SIZE)
+            ),
+            "Send it to a receiver that runs caesar instead.",
+        ));
+    }
+
+    let content = tokio::fs::read(path)
+        .await
+        .map_err(|error| CaesarError::io(format!("Failed to read '{}'", path), error))?;
+    let name = Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let key = Aes128Gcm::generate_key(OsRng);
+    let sealed = seal(&name, &content, &key)?;
+
+    // The relay only learns the ID, never the key
+    let http_url = replace_protocol(relay);
+    let id = Uuid::new_v4().simple().to_string();
+    let mut request = reqwest::Client::new()
+        .put(format!("{}/link/{}", http_url, id))
+        .body(sealed);
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    let response = request
+        .send()
+        .await
+        .map_err(|e| CaesarError::RelayUnreachable {
+            relay: http_url.clone(),
+            reason: e.to_string(),
+        })?;
+
+    if let Some(error) = CaesarError::refused(response.status()) {
+        return Err(error);
+    }
+    match response.status() {
+        status if status.is_success() => Ok(format!(
+            "{}/link/{}#{}",
+            http_url,
+            id,
+            general_purpose::URL_SAFE_NO_PAD.encode(key)
+        )),
+        // Relays before download links and relays that turned them off look alike
+        reqwest::StatusCode::NOT_FOUND => {
+            Err(CaesarError::Refused("it offers no download links".into()))
+        }
+        reqwest::StatusCode::PAYLOAD_TOO_LARGE => Err(CaesarError::Refused(
+            "the file is larger than it allows for download links".into(),
+        )),
+        status => Err(CaesarError::Protocol(format!(
+            "Failed to create the download link: {}",
+            status
+        ))),
+    }
+}
+
+/// Encrypts a file for a download link.
+///
+/// The sealed file is the nonce followed by the encrypted name length as two big endian
+/// bytes, the name and the content, which the page behind the link decrypts in one go.
+///
+/// # Arguments
+///
+/// * `name` - The name the file is saved as.
+/// * `content` - The content of the file.
+/// * `key` - The key of the link.
+///
+/// # Returns
+///
+/// The sealed file.
+fn seal(name: &str, content: &[u8], key: &Key<Aes128Gcm>) -> Result<Vec<u8>> {
+    let name = name.as_bytes();
+    let length = u16::try_from(name.len())
+        .map_err(|_| CaesarError::Protocol("The file name is too long.".into()))?;
+
+    let mut plain = Vec::with_capacity(2 + name.len() + content.len());
+    plain.extend_from_slice(&length.to_be_bytes());
+    plain.extend_from_slice(name);
+    plain.extend_from_slice(content);
+
+    let nonce = Aes128Gcm::generate_nonce(&mut OsRng);
+    let encrypted = Aes128Gcm::new(key)
+        .encrypt(&nonce, plain.as_slice())
+        .map_err(|e| CaesarError::Crypto(e.to_string()))?;
+
+    let mut sealed = Vec::with_capacity(NONCE_SIZE + encrypted.len());
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&encrypted);
+    Ok(sealed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes_gcm::Nonce;
+
+    #[test]
+    fn test_seal() {
+        let key = Aes128Gcm::generate_key(OsRng);
+        let sealed = seal("notes.txt", b"hello", &key).unwrap();
+
+        let (nonce, encrypted) = sealed.split_at(NONCE_SIZE);
+        let plain = Aes128Gcm::new(&key)
+            .decrypt(Nonce::from_slice(nonce), encrypted)
+            .unwrap();
+        assert_eq!(&plain[..2], &[0, 9]);
+        assert_eq!(&plain[2..11], b"notes.txt");
+        assert_eq!(&plain[11..], b"hello");
+
+        // Another key cannot open it
+        let other = Aes128Gcm::generate_key(OsRng);
+        assert!(Aes128Gcm::new(&other)
+            .decrypt(Nonce::from_slice(nonce), encrypted)
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_create_link_checks_the_file() {
+        let error = create_link("ws://127.0.0.1:9", "-", None)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, CaesarError::InvalidPaths(_)));
+
+        let error = create_link("ws://127.0.0.1:9", "missing.txt", None)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("does not exist"));
+    }
+}
@@ -0,0 +1,113 @@
+//! Addresses and listeners for IPv4 and IPv6 alike.
+//!
+//! A listener on the unspecified IPv6 address `::` is made dual-stack, so it accepts
+//! IPv4 connections as well, whatever the system's default for IPv6 sockets is. IPv6
+//! literals need brackets wherever a port follows them, as in `[fe80::1]:9000`.
+
+use std::{
+    io,
+    net::{SocketAddr, TcpListener},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// The number of connections that may wait to be accepted, like the standard library's.
+const BACKLOG: i32 = 128;
+
+/// Joins a host and a port into an address.
+///
+/// # Arguments
+///
+/// * `host` - A host name or an IP address, which may already be in brackets.
+/// * `port` - The port.
+///
+/// # Returns
+///
+/// The address, with an IPv6 literal in brackets. Only IPv6 literals contain colons.
+pub fn join_host_port(host: &str, port: impl std::fmt::Display) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    }
+}
+
+/// Binds a TCP listener to an address given as text, like `0.0.0.0:8000`,
+/// `[::]:8000` or `localhost:8000`.
+///
+/// # Arguments
+///
+/// * `address` - The address to listen on. A host name is resolved first.
+///
+/// # Returns
+///
+/// The non-blocking listener, or the error of the bind.
+pub fn bind(address: &str) -> io::Result<TcpListener> {
+    let listener = match address.parse::<SocketAddr>() {
+        Ok(address) => return bind_dual_stack(address),
+        Err(_) => TcpListener::bind(address)?,
+    };
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Binds a TCP listener, dual-stack on the unspecified IPv6 address.
+///
+/// # Arguments
+///
+/// * `address` - The address to listen on.
+///
+/// # Returns
+///
+/// The non-blocking listener, or the error of the bind.
+pub fn bind_dual_stack(address: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    if address.is_ipv6() && address.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    // Like tokio and the standard library, so a restart does not wait for old connections
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(BACKLOG)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr, TcpStream};
+
+    #[test]
+    fn test_join_host_port() {
+        assert_eq!(join_host_port("192.168.1.20", 9000), "192.168.1.20:9000");
+        assert_eq!(join_host_port("fe80::1", 9000), "[fe80::1]:9000");
+        assert_eq!(join_host_port("[fe80::1]", 9000), "[fe80::1]:9000");
+        assert_eq!(join_host_port("localhost", "8000"), "localhost:8000");
+    }
+
+    #[test]
+    fn test_bind_dual_stack() {
+        // Hosts without IPv6 cannot bind the unspecified IPv6 address at all
+        let Ok(listener) = bind_dual_stack(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))) else {
+            return;
+        };
+        let port = listener.local_addr().unwrap().port();
+
+        TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+        TcpStream::connect((Ipv6Addr::LOCALHOST, port)).unwrap();
+    }
+
+    #[test]
+    fn test_bind() {
+        let listener = bind("127.0.0.1:0").unwrap();
+        assert!(listener.local_addr().unwrap().is_ipv4());
+        assert!(bind("localhost:0").is_ok());
+        assert!(bind("not an address").is_err());
+    }
+}
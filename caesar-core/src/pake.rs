@@ -0,0 +1,420 @@
+//! Password authenticated key exchange for the handshake.
+//!
+//! The sender and the receiver only share a short PIN, which is part of the transfer
+//! code the receiver types. SPAKE2 (RFC 9382) over P-256 turns it into a strong session
+//! key: each side blinds its Diffie-Hellman share with the PIN, so only a peer that
+//! knows the PIN ends up with the same key. Anyone else, the relay included, gets one
+//! guess per handshake and cannot test further PINs offline. The sender ends the
+//! transfer after a wrong guess, so the PIN only has to be hard to guess once.
+
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use p256::{
+    elliptic_curve::{ops::Reduce, sec1::ToEncodedPoint},
+    FieldBytes, NonZeroScalar, ProjectivePoint, PublicKey, Scalar, U256,
+};
+use rand::{rngs::OsRng, Rng};
+use sha2::{Digest, Sha256};
+
+use crate::error::CaesarError;
+
+/// The number of digits of a PIN.
+pub const PIN_DIGITS: usize = 6;
+
+/// The point the sender blinds its share with, from RFC 9382.
+const M: &str = "02886e2f97ace46e55ba9dd7242579f2993b64e16ef3dcab95afd497333d8fa12f";
+
+/// The point the receiver blinds its share with, from RFC 9382.
+const N: &str = "03d8bbd6c639c62937b04d997f38c3770719c629d7014d49a24b4f98baa1292b49";
+
+/// The side of the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// The side that creates the room and sends the first share.
+    Sender,
+    /// The side that joins the room and answers with its share.
+    Receiver,
+}
+
+impl Role {
+    /// Returns the identity of the side that goes into the transcript.
+    fn identity(self) -> &'static [u8] {
+        match self {
+            Role::Sender => b"caesar-sender",
+            Role::Receiver => b"caesar-receiver",
+        }
+    }
+
+    /// Returns the point the side blinds its share with.
+    fn blinding(self) -> ProjectivePoint {
+        let hex = match self {
+            Role::Sender => M,
+            Role::Receiver => N,
+        };
+        PublicKey::from_sec1_bytes(&hex::decode(hex).unwrap())
+            .unwrap()
+            .to_projective()
+    }
+
+    /// Returns the other side.
+    pub fn peer(self) -> Role {
+        match self {
+            Role::Sender => Role::Receiver,
+            Role::Receiver => Role::Sender,
+        }
+    }
+}
+
+/// The secret a transfer is authenticated with.
+#[derive(Debug, Clone)]
+pub enum Secret {
+    /// A short PIN that is shown as part of the transfer code.
+    Pin(String),
+    /// A key both sides already have, e.g. the one of a mailbox.
+    Key(Vec<u8>),
+}
+
+impl Secret {
+    /// Creates a random PIN.
+    ///
+    /// # Returns
+    ///
+    /// A `Secret::Pin` with `PIN_DIGITS` digits.
+    pub fn random_pin() -> Secret {
+        let pin: u32 = OsRng.gen_range(0..10u32.pow(PIN_DIGITS as u32));
+        Secret::Pin(format!("{pin:0width$}", width = PIN_DIGITS))
+    }
+
+    /// Returns the bytes the key exchange is run with.
+    pub fn password(&self) -> &[u8] {
+        match self {
+            Secret::Pin(pin) => pin.as_bytes(),
+            Secret::Key(key) => key,
+        }
+    }
+}
+
+/// Splits the PIN off a transfer code.
+///
+/// # Arguments
+///
+/// * `code` - The transfer code, like `482913-brave-tiger-42`.
+///
+/// # Returns
+///
+/// The PIN, if the code starts with one, and the name of the transfer.
+pub fn split_code(code: &str) -> (Option<&str>, &str) {
+    match code.split_once('-') {
+        Some((pin, name)) if pin.len() == PIN_DIGITS && pin.bytes().all(|c| c.is_ascii_digit()) => {
+            (Some(pin), name)
+        }
+        _ => (None, code),
+    }
+}
+
+/// Joins the PIN and the name of a transfer into the code the receiver types.
+///
+/// # Arguments
+///
+/// * `pin` - The PIN of the transfer.
+/// * `name` - The name of the transfer.
+///
+/// # Returns
+///
+/// The transfer code, like `482913-brave-tiger-42`.
+pub fn join_code(pin: &str, name: &str) -> String {
+    format!("{pin}-{name}")
+}
+
+/// One side of a SPAKE2 key exchange.
+pub struct Spake2 {
+    /// The side of the handshake.
+    role: Role,
+    /// The ephemeral secret of this side.
+    secret: NonZeroScalar,
+    /// The password as a scalar.
+    password: Scalar,
+    /// The blinded share that is sent to the peer.
+    message: Vec<u8>,
+}
+
+/// The keys both sides derive from a key exchange.
+pub struct Keys {
+    /// The keys the sender and the receiver encrypt their packets with, in that order.
+    traffic: [[u8; 32]; 2],
+    /// The keys each side proves with that it derived the same keys.
+    confirmation: [Vec<u8>; 2],
+    /// The transcript of the key exchange.
+    transcript: Vec<u8>,
+}
+
+impl Spake2 {
+    /// Starts a key exchange.
+    ///
+    /// # Arguments
+    ///
+    /// * `role` - The side of the handshake.
+    /// * `password` - The secret both sides share.
+    ///
+    /// # Returns
+    ///
+    /// A new `Spake2` instance, whose `message` has to be sent to the peer.
+    pub fn new(role: Role, password: &[u8]) -> Spake2 {
+        let hashed = Sha256::new()
+            .chain_update(b"caesar-spake2-password:")
+            .chain_update(password)
+            .finalize();
+        let password = <Scalar as Reduce<U256>>::reduce_bytes(&hashed);
+
+        let secret = NonZeroScalar::random(&mut OsRng);
+        let share = ProjectivePoint::GENERATOR * *secret + role.blinding() * password;
+        let message = encode(&share);
+
+        Spake2 {
+            role,
+            secret,
+            password,
+            message,
+        }
+    }
+
+    /// Returns the blinded share that is sent to the peer.
+    pub fn message(&self) -> &[u8] {
+        &self.message
+    }
+
+    /// Derives the keys from the share of the peer.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer` - The blinded share the peer sent.
+    ///
+    /// # Returns
+    ///
+    /// The keys, or an error if the share is not a valid point.
+    pub fn finish(&self, peer: &[u8]) -> Result<Keys, CaesarError> {
+        let invalid = || CaesarError::Handshake("Invalid key share from the peer.".into());
+
+        let share = PublicKey::from_sec1_bytes(peer)
+            .map_err(|_| invalid())?
+            .to_projective();
+        let shared = (share - self.role.peer().blinding() * self.password) * *self.secret;
+        if shared == ProjectivePoint::IDENTITY {
+            return Err(invalid());
+        }
+
+        let (sender, receiver) = match self.role {
+            Role::Sender => (self.message.as_slice(), peer),
+            Role::Receiver => (peer, self.message.as_slice()),
+        };
+
+        // The transcript binds the keys to both shares and the password
+        let mut transcript = Vec::new();
+        for part in [
+            Role::Sender.identity(),
+            Role::Receiver.identity(),
+            sender,
+            receiver,
+            &encode(&shared),
+            FieldBytes::from(self.password).as_slice(),
+        ] {
+            transcript.extend_from_slice(&(part.len() as u64).to_le_bytes());
+            transcript.extend_from_slice(part);
+        }
+
+        let hashed = Sha256::digest(&transcript);
+        let (encryption, authentication) = hashed.split_at(16);
+
+        let mut confirmation = [0u8; 32];
+        Hkdf::<Sha256>::new(None, authentication)
+            .expand(b"ConfirmationKeys", &mut confirmation)
+            .expect("32 bytes are a valid HKDF output length");
+
+        // A key of its own for each direction keeps a packet from being reflected
+        let mut traffic = [[0u8; 32]; 2];
+        let expansion = Hkdf::<Sha256>::new(None, encryption);
+        let infos: [&[u8]; 2] = [b"SenderKey", b"ReceiverKey"];
+        for (key, info) in traffic.iter_mut().zip(infos) {
+            expansion
+                .expand(info, key)
+                .expect("32 bytes are a valid HKDF output length");
+        }
+
+        Ok(Keys {
+            traffic,
+            confirmation: [confirmation[..16].to_vec(), confirmation[16..].to_vec()],
+            transcript,
+        })
+    }
+}
+
+impl Keys {
+    /// Returns the key a side encrypts its packets with, expanded from the shared
+    /// secret with HKDF-SHA256. A cipher with shorter keys uses the start of it.
+    ///
+    /// # Arguments
+    ///
+    /// * `role` - The side that encrypts the packets.
+    pub fn traffic(&self, role: Role) -> [u8; 32] {
+        match role {
+            Role::Sender => self.traffic[0],
+            Role::Receiver => self.traffic[1],
+        }
+    }
+
+    /// Returns the MAC of a side of the handshake over the transcript and what was
+    /// negotiated along with it.
+    ///
+    /// The offer and the answer travel in the clear next to the shares. Without them in
+    /// the MAC, a relay could strip the protections from the offer, and both sides would
+    /// agree on the weakest without noticing.
+    ///
+    /// # Arguments
+    ///
+    /// * `role` - The side the MAC is created for.
+    /// * `negotiation` - The offer and the choices of the handshake, as both sides see them.
+    fn mac(&self, role: Role, negotiation: &[u8]) -> Hmac<Sha256> {
+        let key = match role {
+            Role::Sender => &self.confirmation[0],
+            Role::Receiver => &self.confirmation[1],
+        };
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+        mac.update(&self.transcript);
+        mac.update(&(negotiation.len() as u64).to_le_bytes());
+        mac.update(negotiation);
+        mac
+    }
+
+    /// Creates the proof that a side derived these keys and saw the same negotiation.
+    ///
+    /// # Arguments
+    ///
+    /// * `role` - The side that sends the proof.
+    /// * `negotiation` - The offer and the choices of the handshake.
+    ///
+    /// # Returns
+    ///
+    /// The confirmation that is sent to the peer.
+    pub fn confirmation(&self, role: Role, negotiation: &[u8]) -> Vec<u8> {
+        self.mac(role, negotiation).finalize().into_bytes().to_vec()
+    }
+
+    /// Checks the proof of the peer in constant time.
+    ///
+    /// # Arguments
+    ///
+    /// * `role` - The side that sent the proof.
+    /// * `negotiation` - The offer and the choices of the handshake, as this side sees them.
+    /// * `confirmation` - The confirmation the peer sent.
+    ///
+    /// # Returns
+    ///
+    /// Whether the peer derived the same keys, i.e. knows the same password, and the
+    /// negotiation reached it unchanged.
+    pub fn verify(&self, role: Role, negotiation: &[u8], confirmation: &[u8]) -> bool {
+        self.mac(role, negotiation).verify_slice(confirmation).is_ok()
+    }
+
+    /// Returns a short code both sides show to compare them by voice or at a glance.
+    ///
+    /// The code depends on the whole transcript, so a relay that ran a key exchange
+    /// with each side on its own would leave them with different codes.
+    ///
+    /// # Returns
+    ///
+    /// Six digits in two groups, like `274 018`.
+    pub fn short_code(&self) -> String {
+        let mut bytes = [0u8; 4];
+        Hkdf::<Sha256>::new(None, &Sha256::digest(&self.transcript))
+            .expand(b"ShortAuthenticationString", &mut bytes)
+            .expect("4 bytes are a valid HKDF output length");
+        let code = u32::from_be_bytes(bytes) % 1_000_000;
+        format!("{:03} {:03}", code / 1000, code % 1000)
+    }
+
+    /// Returns the ID of the transfer, which sets its packets apart from those of any
+    /// other transfer in their associated data.
+    pub fn transfer_id(&self) -> [u8; 16] {
+        let mut id = [0u8; 16];
+        Hkdf::<Sha256>::new(None, &Sha256::digest(&self.transcript))
+            .expand(b"TransferId", &mut id)
+            .expect("16 bytes are a valid HKDF output length");
+        id
+    }
+}
+
+/// Encodes a point in uncompressed SEC1 form.
+fn encode(point: &ProjectivePoint) -> Vec<u8> {
+    point
+        .to_affine()
+        .to_encoded_point(false)
+        .as_bytes()
+        .to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_exchange() {
+        let sender = Spake2::new(Role::Sender, b"482913");
+        let receiver = Spake2::new(Role::Receiver, b"482913");
+
+        let sender_keys = sender.finish(receiver.message()).unwrap();
+        let receiver_keys = receiver.finish(sender.message()).unwrap();
+        assert_eq!(sender_keys.short_code(), receiver_keys.short_code());
+        assert_eq!(sender_keys.short_code().len(), 7);
+        assert_eq!(sender_keys.transfer_id(), receiver_keys.transfer_id());
+        // Both sides expand the same key for each direction, and the directions differ
+        for role in [Role::Sender, Role::Receiver] {
+            assert_eq!(sender_keys.traffic(role), receiver_keys.traffic(role));
+        }
+        assert_ne!(
+            sender_keys.traffic(Role::Sender),
+            sender_keys.traffic(Role::Receiver)
+        );
+        let confirmation = receiver_keys.confirmation(Role::Receiver, b"offer");
+        assert!(sender_keys.verify(Role::Receiver, b"offer", &confirmation));
+        // A confirmation cannot be replayed by the other side
+        assert!(!sender_keys.verify(Role::Sender, b"offer", &confirmation));
+        // Nor does it hold for a negotiation that was changed on the way
+        assert!(!sender_keys.verify(Role::Receiver, b"offe", &confirmation));
+
+        // A wrong PIN leads to other keys, which the confirmation reveals
+        let guess = Spake2::new(Role::Receiver, b"482914");
+        let sender_keys = sender.finish(guess.message()).unwrap();
+        let guess_keys = guess.finish(sender.message()).unwrap();
+        assert_ne!(
+            sender_keys.traffic(Role::Sender),
+            guess_keys.traffic(Role::Sender)
+        );
+        assert_ne!(sender_keys.short_code(), guess_keys.short_code());
+        assert!(!sender_keys.verify(
+            Role::Receiver,
+            b"offer",
+            &guess_keys.confirmation(Role::Receiver, b"offer")
+        ));
+
+        assert!(sender.finish(&[4; 65]).is_err());
+    }
+
+    #[test]
+    fn test_transfer_code() {
+        let Secret::Pin(pin) = Secret::random_pin() else {
+            panic!("random_pin did not create a PIN");
+        };
+        assert_eq!(pin.len(), PIN_DIGITS);
+
+        let code = join_code(&pin, "brave-tiger-cookie");
+        assert_eq!(
+            split_code(&code),
+            (Some(pin.as_str()), "brave-tiger-cookie")
+        );
+        assert_eq!(
+            split_code("brave-tiger-cookie"),
+            (None, "brave-tiger-cookie")
+        );
+        assert_eq!(split_code("4829-brave-tiger"), (None, "4829-brave-tiger"));
+    }
+}
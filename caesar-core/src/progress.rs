@@ -0,0 +1,241 @@
+//! Reporting the progress of a transfer.
+//!
+//! Both clients print a line per file that is rewritten as the file arrives. A program
+//! that draws the progress itself, like the CLI with its progress bars, passes a report
+//! in the options instead, which is called with the state of the file every time it
+//! changes. Both estimate the time left from a moving average of the throughput.
+
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::format::NumberFormat;
+
+/// How far back the moving average of the throughput roughly reaches.
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(5);
+
+/// The shortest time between two samples of the throughput.
+const THROUGHPUT_SAMPLE: Duration = Duration::from_millis(200);
+
+/// The progress of a single file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileProgress {
+    /// The index of the receiver the file is sent to, always zero on the receiving side.
+    pub peer: usize,
+    /// The index of the file in the sender's list.
+    pub index: u32,
+    /// The name of the file.
+    pub name: String,
+    /// The size of the file in bytes, zero for a stream.
+    pub size: u64,
+    /// The number of bytes of the file that arrived so far.
+    ///
+    /// If `None`, the amount is unknown, which is the case for a stream on the sending
+    /// side, as the receiver only tells when it ended.
+    pub transferred: Option<u64>,
+    /// Whether the whole file arrived.
+    pub done: bool,
+    /// The estimated time until every file of the transfer arrived.
+    ///
+    /// If `None`, there is no estimate yet, a stream leaves the end open, or no other
+    /// file follows this one.
+    pub remaining: Option<Duration>,
+}
+
+/// A function that is told the progress of a file.
+type Report = dyn Fn(&FileProgress) + Send + Sync;
+
+/// Receives the progress of the files in place of the printed lines.
+#[derive(Clone)]
+pub struct ProgressReport(Arc<Report>);
+
+impl ProgressReport {
+    /// Creates a report from a function, e.g. one that draws progress bars.
+    ///
+    /// # Arguments
+    ///
+    /// * `report` - Called with the progress of a file every time it changes.
+    pub fn new<F>(report: F) -> ProgressReport
+    where
+        F: Fn(&FileProgress) + Send + Sync + 'static,
+    {
+        ProgressReport(Arc::new(report))
+    }
+
+    /// Reports the progress of a file.
+    ///
+    /// # Arguments
+    ///
+    /// * `progress` - The progress of the file.
+    pub fn report(&self, progress: &FileProgress) {
+        (self.0)(progress)
+    }
+}
+
+impl fmt::Debug for ProgressReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressReport")
+    }
+}
+
+/// A moving average of the throughput, which the time left is estimated from.
+///
+/// Each sample is weighted by the time since the one before, so a burst of progress,
+/// like a run of small files, moves the average no more than its share of the time.
+#[derive(Debug, Clone, Default)]
+pub struct Throughput {
+    /// When the last sample was taken and how many bytes had arrived by then.
+    last: Option<(Instant, u64)>,
+    /// The average in bytes per second, known from the second sample on.
+    rate: Option<f64>,
+}
+
+impl Throughput {
+    /// Takes a sample of the transfer.
+    ///
+    /// # Arguments
+    ///
+    /// * `transferred` - The number of bytes that arrived so far. If it went down,
+    ///   e.g. because a file is sent again, the average continues from there.
+    /// * `now` - When the bytes arrived.
+    pub fn record(&mut self, transferred: u64, now: Instant) {
+        if let Some((time, bytes)) = self.last {
+            let elapsed = now.saturating_duration_since(time);
+            // Progress that comes in right after the last sample counts towards the next
+            if elapsed < THROUGHPUT_SAMPLE {
+                return;
+            }
+
+            if let Some(delta) = transferred.checked_sub(bytes) {
+                let elapsed = elapsed.as_secs_f64();
+                let sample = delta as f64 / elapsed;
+                let weight = 1.0 - (-elapsed / THROUGHPUT_WINDOW.as_secs_f64()).exp();
+                self.rate = Some(match self.rate {
+                    Some(rate) => rate + weight * (sample - rate),
+                    None => sample,
+                });
+            }
+        }
+        self.last = Some((now, transferred));
+    }
+
+    /// Returns the average throughput in bytes per second, if it is known yet.
+    pub fn rate(&self) -> Option<u64> {
+        self.rate.map(|rate| rate as u64)
+    }
+
+    /// Estimates how long the rest of the transfer takes.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The number of bytes still to come.
+    ///
+    /// # Returns
+    ///
+    /// The time left, or `None` if the throughput is not known or has stalled.
+    pub fn remaining(&self, bytes: u64) -> Option<Duration> {
+        let rate = self.rate.filter(|rate| *rate > 0.0)?;
+        Duration::try_from_secs_f64(bytes as f64 / rate).ok()
+    }
+
+    /// Estimates how long the files still to come take, next to the current one.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The bytes of the current file still to come, `None` for a stream.
+    /// * `total` - The bytes of all files still to come, the current one included, or
+    ///   `None` while a stream leaves the end open.
+    ///
+    /// # Returns
+    ///
+    /// The time left for all files, or `None` if it is not known or no other file
+    /// follows the current one, so it would only repeat the time left for that file.
+    pub fn remaining_total(&self, file: Option<u64>, total: Option<u64>) -> Option<Duration> {
+        let total = total.filter(|total| *total > 0 && file != Some(*total))?;
+        self.remaining(total)
+    }
+
+    /// Describes the throughput and the time left for the progress line of a file.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The bytes of the current file still to come, `None` for a stream.
+    /// * `total` - The bytes of all files still to come, the current one included, or
+    ///   `None` while a stream leaves the end open.
+    /// * `format` - How the numbers are written.
+    ///
+    /// # Returns
+    ///
+    /// A phrase like `12.3 MiB/s, 45s left, 3m 05s for all files`, or `None` until the
+    /// throughput is known.
+    pub fn describe(
+        &self,
+        file: Option<u64>,
+        total: Option<u64>,
+        format: &NumberFormat,
+    ) -> Option<String> {
+        let mut description = format!("{}/s", format.size(self.rate()?));
+        if let Some(left) = file.and_then(|bytes| self.remaining(bytes)) {
+            description.push_str(&format!(", {} left", format.duration(left)));
+        }
+        if let Some(left) = self.remaining_total(file, total) {
+            description.push_str(&format!(", {} for all files", format.duration(left)));
+        }
+        Some(description)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throughput() {
+        let start = Instant::now();
+        let mut throughput = Throughput::default();
+        throughput.record(0, start);
+        assert_eq!(throughput.rate(), None);
+        assert_eq!(throughput.remaining(1024), None);
+
+        // Samples close together are merged into one
+        throughput.record(512, start + Duration::from_millis(100));
+        assert_eq!(throughput.rate(), None);
+        throughput.record(2048, start + Duration::from_secs(1));
+        assert_eq!(throughput.rate(), Some(2048));
+        assert_eq!(throughput.remaining(4096), Some(Duration::from_secs(2)));
+
+        // A slower second moves the average only part of the way
+        throughput.record(3072, start + Duration::from_secs(2));
+        let rate = throughput.rate().unwrap();
+        assert!(rate > 1024 && rate < 2048);
+
+        // A file sent again does not count as negative throughput
+        throughput.record(0, start + Duration::from_secs(3));
+        assert_eq!(throughput.rate(), Some(rate));
+    }
+
+    #[test]
+    fn test_describe() {
+        let start = Instant::now();
+        let mut throughput = Throughput::default();
+        let format = NumberFormat::default();
+        assert_eq!(throughput.describe(Some(1024), Some(1024), &format), None);
+
+        throughput.record(0, start);
+        throughput.record(1024 * 1024, start + Duration::from_secs(1));
+        assert_eq!(
+            throughput.describe(Some(45 * 1024 * 1024), Some(45 * 1024 * 1024), &format),
+            Some("1.0 MiB/s, 45s left".to_string())
+        );
+        assert_eq!(
+            throughput.describe(Some(45 * 1024 * 1024), Some(185 * 1024 * 1024), &format),
+            Some("1.0 MiB/s, 45s left, 3m 05s for all files".to_string())
+        );
+        assert_eq!(
+            throughput.describe(None, None, &format),
+            Some("1.0 MiB/s".to_string())
+        );
+    }
+}
@@ -0,0 +1,242 @@
+//! Direct connections between peers behind NAT through TCP hole punching.
+//!
+//! Each peer opens a socket on a random port and reaches the relay's rendezvous from it,
+//! so its NAT maps the port to the public address the relay sees. Once the relay swapped
+//! the candidates of both peers, they connect to each other from the same port at the
+//! same time. The outgoing attempts open the NATs for the incoming ones, which works
+//! for most home routers, though not behind symmetric NATs.
+//!
+//! The sender bridges the direct connections to its local server, so the receiver runs
+//! the same protocol as on the local network, and the relay only carries the signaling.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use futures_util::{SinkExt, StreamExt};
+use local_ip_address::{local_ip, local_ipv6};
+use tokio::{
+    net::{lookup_host, TcpSocket, TcpStream},
+    sync::mpsc,
+    task,
+    time::{sleep, sleep_until, timeout, Instant},
+};
+use tokio_tungstenite::{
+    client_async_tls,
+    tungstenite::{client::IntoClientRequest, http::HeaderValue, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+use tracing::debug;
+
+use crate::{
+    error::{CaesarError, Result},
+    relay::{punch::Candidates, routing::ROUTE_HEADER},
+    sender::util::websocket_url,
+};
+
+/// How long the peers try to reach each other, and how long a receiver waits for the
+/// sender at the rendezvous.
+pub const PUNCH_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The pause between two attempts to connect to a candidate.
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Opens direct connections to the other peer of a room.
+///
+/// # Arguments
+///
+/// * `relay` - The relay whose rendezvous the peers meet at.
+/// * `room_id` - The room of the transfer.
+/// * `route` - The routing token of the relay instance that holds the room.
+/// * `token` - The access token, if the relay requires authentication.
+/// * `wait` - How long to wait for the other peer at the rendezvous, or `None` to wait
+///   until it shows up.
+/// * `listen` - Whether to accept connections as well, which the sender does.
+///
+/// # Returns
+///
+/// The receiver of the direct connections that were opened. It is closed once
+/// `PUNCH_TIMEOUT` has passed since the peers met.
+pub async fn punch(
+    relay: &str,
+    room_id: &str,
+    route: Option<&str>,
+    token: Option<&str>,
+    wait: Option<Duration>,
+    listen: bool,
+) -> Result<mpsc::Receiver<TcpStream>> {
+    let unreachable = |reason: String| CaesarError::RelayUnreachable {
+        relay: relay.to_string(),
+        reason,
+    };
+
+    // The rendezvous lives next to the WebSocket endpoint of the relay
+    let url = format!(
+        "{}/punch/{}",
+        websocket_url(relay).trim_end_matches("/ws"),
+        room_id
+    );
+    let mut request = url
+        .into_client_request()
+        .map_err(|e| unreachable(format!("Failed to create request: {}", e)))?;
+    if let Some(route) = route {
+        if let Ok(value) = HeaderValue::from_str(route) {
+            request.headers_mut().insert(ROUTE_HEADER, value);
+        }
+    }
+    if let Some(token) = token {
+        if let Ok(value) = HeaderValue::from_str(&format!("Bearer {token}")) {
+            request.headers_mut().insert("Authorization", value);
+        }
+    }
+
+    // Reach the relay from the port the peer punches from
+    let host = request.uri().host().unwrap_or_default().to_string();
+    let port = request
+        .uri()
+        .port_u16()
+        .unwrap_or(match request.uri().scheme_str() {
+            Some("wss") => 443,
+            _ => 80,
+        });
+    let relay_address = lookup_host((host.as_str(), port))
+        .await
+        .map_err(|e| unreachable(e.to_string()))?
+        .next()
+        .ok_or_else(|| unreachable(format!("Failed to resolve '{host}'.")))?;
+    let unspecified = match relay_address {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let socket =
+        reusable_socket(SocketAddr::new(unspecified, 0)).map_err(|e| unreachable(e.to_string()))?;
+    let local_address = socket
+        .local_addr()
+        .map_err(|e| unreachable(e.to_string()))?;
+    let stream = match timeout(PUNCH_TIMEOUT, socket.connect(relay_address)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => return Err(unreachable(e.to_string())),
+        Err(_) => return Err(unreachable("Timed out while connecting.".into())),
+    };
+    let (mut rendezvous, _) = client_async_tls(request, stream)
+        .await
+        .map_err(|e| unreachable(e.to_string()))?;
+
+    // Tell the relay where the peer is reached on its own network
+    let local_ip = match relay_address {
+        SocketAddr::V4(_) => local_ip(),
+        SocketAddr::V6(_) => local_ipv6(),
+    };
+    let candidates = Candidates {
+        public: None,
+        local: local_ip
+            .ok()
+            .map(|ip| SocketAddr::new(ip, local_address.port())),
+    };
+    rendezvous
+        .send(Message::Text(serde_json::to_string(&candidates).unwrap()))
+        .await
+        .map_err(|e| CaesarError::Connection(e.to_string()))?;
+
+    let peer = match wait {
+        Some(wait) => timeout(wait, read_candidates(&mut rendezvous))
+            .await
+            .ok()
+            .flatten(),
+        None => read_candidates(&mut rendezvous).await,
+    }
+    .ok_or_else(|| CaesarError::Connection("The other peer did not show up.".into()))?;
+    let _ = rendezvous.close(None).await;
+    debug!("Punching from {local_address} to {peer:?}");
+
+    let (tx, rx) = mpsc::channel(4);
+    let deadline = Instant::now() + PUNCH_TIMEOUT;
+
+    // Accept the connections of the other peer on the same port
+    if listen {
+        let listener = reusable_socket(local_address).and_then(|socket| socket.listen(4));
+        if let Ok(listener) = listener {
+            let tx = tx.clone();
+            task::spawn(async move {
+                loop {
+                    tokio::select! {
+                        accepted = listener.accept() => match accepted {
+                            Ok((stream, _)) => {
+                                let _ = tx.send(stream).await;
+                            }
+                            Err(_) => break,
+                        },
+                        _ = sleep_until(deadline) => break,
+                    }
+                }
+            });
+        }
+    }
+
+    // Connect to every candidate of the other peer until one answers
+    let mut targets = vec![];
+    for candidate in [peer.public, peer.local].into_iter().flatten() {
+        if !targets.contains(&candidate) {
+            targets.push(candidate);
+        }
+    }
+    for target in targets {
+        let tx = tx.clone();
+        task::spawn(async move {
+            while Instant::now() < deadline {
+                let Ok(socket) = reusable_socket(local_address) else {
+                    break;
+                };
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if let Ok(Ok(stream)) = timeout(remaining, socket.connect(target)).await {
+                    let _ = tx.send(stream).await;
+                    break;
+                }
+                sleep(RETRY_DELAY).await;
+            }
+        });
+    }
+
+    Ok(rx)
+}
+
+/// Reads the candidates of the other peer from the rendezvous.
+///
+/// # Arguments
+///
+/// * `rendezvous` - The connection to the rendezvous.
+///
+/// # Returns
+///
+/// The candidates, or `None` if the relay closed the connection without them.
+async fn read_candidates(
+    rendezvous: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+) -> Option<Candidates> {
+    while let Some(message) = rendezvous.next().await {
+        match message {
+            Ok(Message::Text(text)) => return serde_json::from_str(&text).ok(),
+            Ok(_) => continue,
+            Err(_) => return None,
+        }
+    }
+    None
+}
+
+/// Creates a socket that shares its port with the other sockets of the punch.
+///
+/// # Arguments
+///
+/// * `address` - The address to bind the socket to.
+fn reusable_socket(address: SocketAddr) -> io::Result<TcpSocket> {
+    let socket = match address {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    #[cfg(unix)]
+    socket.set_reuseport(true)?;
+    socket.bind(address)?;
+    Ok(socket)
+}
@@ -0,0 +1,429 @@
+//! Direct transfers over QUIC.
+//!
+//! Next to its local WebSocket server, the sender listens for QUIC on the UDP port of the
+//! same number. Every bidirectional stream a receiver opens is bridged to the local
+//! server, so the receiver runs the same WebSocket session as over TCP. QUIC brings its
+//! own congestion control, which copes better with lossy Wi-Fi, and keeps the connection
+//! when a session breaks down: the receiver continues on a new stream of the same
+//! connection, or reconnects with 0-RTT if the connection is gone as well.
+//!
+//! The sender presents a self-signed certificate that receivers do not check. The PIN
+//! authenticated handshake of the session already proves the sender, and its keys
+//! encrypt the files end to end, as they do on the unencrypted WebSocket connection.
+
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use quinn::{
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+    ClientConfig, Connection, Endpoint, RecvStream, SendStream, ServerConfig, TransportConfig,
+};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{self, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime},
+    DigitallySignedStruct, SignatureScheme,
+};
+use tokio::{
+    io::{copy_bidirectional, AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    task,
+    time::timeout,
+};
+use tokio_tungstenite::{client_async, WebSocketStream};
+use tracing::debug;
+
+use crate::error::{CaesarError, Result};
+
+/// The protocol both peers agree on during the QUIC handshake.
+const ALPN: &[u8] = b"caesar/1";
+
+/// The name the sender's certificate is issued for.
+const SERVER_NAME: &str = "caesar";
+
+/// How long a receiver waits for the QUIC handshake before it takes the WebSocket
+/// connection instead, e.g. because the sender was built without QUIC.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often an idle connection is kept alive, so NATs and firewalls keep it open.
+const KEEP_ALIVE: Duration = Duration::from_secs(5);
+
+/// A bidirectional QUIC stream, which carries one WebSocket session.
+#[derive(Debug)]
+pub struct QuicStream {
+    /// The sending half of the stream.
+    send: SendStream,
+    /// The receiving half of the stream.
+    recv: RecvStream,
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.send), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_shutdown(cx)
+    }
+}
+
+/// Accepts QUIC connections and bridges their streams to the sender's local server.
+///
+/// # Arguments
+///
+/// * `port` - The UDP port to listen on, which is also the TCP port of the local server.
+pub async fn serve(port: u16) {
+    let endpoint = match server_config().and_then(|config| {
+        Endpoint::server(config, SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))
+            .map_err(|e| CaesarError::Connection(e.to_string()))
+    }) {
+        Ok(endpoint) => endpoint,
+        Err(error) => {
+            debug!("Not accepting QUIC connections: {error}");
+            return;
+        }
+    };
+    debug!("QUIC listening on: {:?}", endpoint.local_addr());
+
+    while let Some(incoming) = endpoint.accept().await {
+        task::spawn(async move {
+            let connection = match incoming.accept() {
+                // Take the early data of a receiver that reconnects
+                Ok(connecting) => match connecting.into_0rtt() {
+                    Ok((connection, _)) => connection,
+                    Err(connecting) => match connecting.await {
+                        Ok(connection) => connection,
+                        Err(_) => return,
+                    },
+                },
+                Err(_) => return,
+            };
+            debug!(
+                "Accepted a QUIC connection from {}",
+                connection.remote_address()
+            );
+
+            // Every stream is a session of its own
+            while let Ok((send, recv)) = connection.accept_bi().await {
+                task::spawn(async move {
+                    let mut stream = QuicStream { send, recv };
+                    if let Ok(mut local) = TcpStream::connect(("127.0.0.1", port)).await {
+                        let _ = copy_bidirectional(&mut stream, &mut local).await;
+                    }
+                });
+            }
+        });
+    }
+}
+
+/// Opens WebSocket sessions to a sender over QUIC.
+///
+/// The dialer keeps its connection between sessions, and the session ticket of the
+/// sender after the connection is gone, so a reconnect sends its first data right away.
+#[derive(Debug)]
+pub struct Dialer {
+    /// The endpoint the connections are made from.
+    endpoint: Endpoint,
+    /// The connection of the last session, if it is still open.
+    connection: Option<Connection>,
+}
+
+impl Dialer {
+    /// Creates a dialer on a random UDP port.
+    ///
+    /// # Returns
+    ///
+    /// The dialer, or the error if no UDP socket can be opened.
+    pub fn new() -> Result<Dialer> {
+        // A dual-stack socket reaches IPv4 and IPv6 senders
+        let mut endpoint = Endpoint::client(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)))
+            .or_else(|_| Endpoint::client(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))))
+            .map_err(|e| CaesarError::Connection(e.to_string()))?;
+        endpoint.set_default_client_config(client_config()?);
+
+        Ok(Dialer {
+            endpoint,
+            connection: None,
+        })
+    }
+
+    /// Opens a WebSocket session to the sender's local server.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The address of the sender.
+    ///
+    /// # Returns
+    ///
+    /// The WebSocket connection, or `CaesarError::Connection` if the sender cannot be
+    /// reached over QUIC.
+    pub async fn connect(&mut self, address: SocketAddr) -> Result<WebSocketStream<QuicStream>> {
+        let url = format!("ws://{}/ws", address);
+        let reused = self.connection.is_some();
+        let connection = self.connection(address).await?;
+
+        match open_session(&connection, &url).await {
+            Ok(socket) => Ok(socket),
+            // The sender may have rejected the early data of a resumed connection, which
+            // is still usable for another stream
+            Err(_) if connection.close_reason().is_none() => open_session(&connection, &url).await,
+            Err(error) if reused => {
+                debug!("The QUIC connection is gone: {error}");
+                self.connection = None;
+                let connection = self.connection(address).await?;
+                open_session(&connection, &url).await
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Returns the open connection to the sender, or connects to it.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The address of the sender.
+    async fn connection(&mut self, address: SocketAddr) -> Result<Connection> {
+        if let Some(connection) = &self.connection {
+            if connection.close_reason().is_none() && connection.remote_address() == address {
+                return Ok(connection.clone());
+            }
+        }
+
+        let connecting = self
+            .endpoint
+            .connect(address, SERVER_NAME)
+            .map_err(|e| CaesarError::Connection(e.to_string()))?;
+        // Resume with 0-RTT if the sender gave us a session ticket before
+        let connection = match connecting.into_0rtt() {
+            Ok((connection, _)) => connection,
+            Err(connecting) => match timeout(CONNECT_TIMEOUT, connecting).await {
+                Ok(Ok(connection)) => connection,
+                Ok(Err(e)) => return Err(CaesarError::Connection(e.to_string())),
+                Err(_) => {
+                    return Err(CaesarError::Connection(
+                        "Timed out during the QUIC handshake.".into(),
+                    ))
+                }
+            },
+        };
+        self.connection = Some(connection.clone());
+        Ok(connection)
+    }
+}
+
+/// Opens a stream on a connection and runs the WebSocket handshake on it.
+///
+/// # Arguments
+///
+/// * `connection` - The connection to the sender.
+/// * `url` - The WebSocket URL of the sender's local server.
+async fn open_session(connection: &Connection, url: &str) -> Result<WebSocketStream<QuicStream>> {
+    let handshake = async {
+        let (send, recv) = connection
+            .open_bi()
+            .await
+            .map_err(|e| CaesarError::Connection(e.to_string()))?;
+        let (socket, _) = client_async(url, QuicStream { send, recv })
+            .await
+            .map_err(|e| CaesarError::Connection(e.to_string()))?;
+        Ok(socket)
+    };
+    timeout(CONNECT_TIMEOUT, handshake)
+        .await
+        .unwrap_or_else(|_| {
+            Err(CaesarError::Connection(
+                "Timed out while opening a QUIC stream.".into(),
+            ))
+        })
+}
+
+/// Creates the configuration of the sender's endpoint, with a new self-signed
+/// certificate.
+fn server_config() -> Result<ServerConfig> {
+    let invalid = |e: &dyn std::fmt::Display| CaesarError::Connection(e.to_string());
+
+    let certified =
+        rcgen::generate_simple_self_signed(vec![SERVER_NAME.into()]).map_err(|e| invalid(&e))?;
+    let certificate = certified.cert.der().clone();
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+
+    let mut crypto = rustls::ServerConfig::builder_with_provider(provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(|e| invalid(&e))?
+        .with_no_client_auth()
+        .with_single_cert(vec![certificate], key)
+        .map_err(|e| invalid(&e))?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    // Accept the early data of receivers that reconnect
+    crypto.max_early_data_size = u32::MAX;
+
+    let crypto = QuicServerConfig::try_from(crypto).map_err(|e| invalid(&e))?;
+    Ok(ServerConfig::with_crypto(Arc::new(crypto)))
+}
+
+/// Creates the configuration of the receiver's endpoint.
+fn client_config() -> Result<ClientConfig> {
+    let invalid = |e: &dyn std::fmt::Display| CaesarError::Connection(e.to_string());
+
+    let mut crypto = rustls::ClientConfig::builder_with_provider(provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(|e| invalid(&e))?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyCertificate(provider())))
+        .with_no_client_auth();
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    crypto.enable_early_data = true;
+
+    let crypto = QuicClientConfig::try_from(crypto).map_err(|e| invalid(&e))?;
+    let mut transport = TransportConfig::default();
+    transport.keep_alive_interval(Some(KEEP_ALIVE));
+    let mut config = ClientConfig::new(Arc::new(crypto));
+    config.transport_config(Arc::new(transport));
+    Ok(config)
+}
+
+/// Returns the cryptography both endpoints use.
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+/// Accepts the self-signed certificate of any sender.
+///
+/// The signatures of the handshake are still checked, so the connection is encrypted
+/// with the key of the certificate. Who holds that key is proven by the session's PIN
+/// authenticated handshake instead.
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::{accept_async, tungstenite::Message};
+
+    #[tokio::test]
+    async fn test_quic_bridge() {
+        // A WebSocket server stands in for the sender's local server
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        task::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                task::spawn(async move {
+                    let mut socket = accept_async(stream).await.unwrap();
+                    while let Some(Ok(message)) = socket.next().await {
+                        if socket.send(message).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        task::spawn(serve(port));
+
+        let address = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        let mut dialer = Dialer::new().unwrap();
+        let mut connected = None;
+        for _ in 0..20 {
+            if let Ok(socket) = dialer.connect(address).await {
+                connected = Some(socket);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let mut socket = connected.expect("The QUIC endpoint did not start.");
+        socket.send(Message::Text("hello".into())).await.unwrap();
+        assert_eq!(
+            socket.next().await.unwrap().unwrap(),
+            Message::Text("hello".into())
+        );
+
+        // A second session runs on a new stream of the same connection
+        let mut second = dialer.connect(address).await.unwrap();
+        second.send(Message::Text("again".into())).await.unwrap();
+        assert_eq!(
+            second.next().await.unwrap().unwrap(),
+            Message::Text("again".into())
+        );
+
+        // After the connection is gone, the dialer resumes it with the session ticket
+        let closed = dialer.connection.take().unwrap();
+        closed.close(0u32.into(), b"");
+        let mut resumed = dialer.connect(address).await.unwrap();
+        resumed.send(Message::Text("resumed".into())).await.unwrap();
+        assert_eq!(
+            resumed.next().await.unwrap().unwrap(),
+            Message::Text("resumed".into())
+        );
+    }
+}
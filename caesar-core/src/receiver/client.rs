@@ -1,26 +1,56 @@
-use std::{fs, io::stdout, path::Path};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    io::{self, stderr, stdout, IoSlice, Seek, SeekFrom, Write},
+    path::{Component, Path},
+    sync::{atomic::AtomicU8, Arc},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
+use crate::cipher::{choose_cipher, has_aes_instructions};
+use crate::delta::Basis;
+use crate::error::{CaesarError, Result};
+use crate::event::{ListedFile, TransferEvent};
+use crate::format::NumberFormat;
+use crate::heartbeat;
+use crate::idle::{self, Activity};
+use crate::pake::{Role, Spake2};
+use crate::progress::{FileProgress, Throughput};
+use crate::receiver::select::OfferedFile;
+use crate::receiver::writer::{ChunkWriter, QueuedSink};
+use crate::receiver::{ConflictPolicy, ReceiverOptions};
+use crate::summary::TransferSummary;
+use crate::suspend::{self, SUSPEND_THRESHOLD};
 use crate::shared::{
     packets::{
-        packet::Value, ChunkPacket, HandshakePacket, HandshakeResponsePacket, ListPacket, Packet,
-        ProgressPacket,
+        list_packet, packet::Value, resume_packet, ChunkPacket, Compression, CreditPacket,
+        DeltaPacket, DonePacket, ErrorPacket, Framing, HandshakePacket, HandshakeResponsePacket,
+        HashAlgorithm, KeySchedule, ListPacket, NonceScheme, ProgressPacket, ResumePacket,
+        RetryPacket, SignaturePacket, TextPacket,
     },
-    JsonPacket, JsonPacketResponse, JsonPacketSender, PacketSender, Sender, Socket, Status,
+    decode_packet, negotiation, FileHasher, Interruption, JsonPacket, JsonPacketResponse,
+    JsonPacketSender, PacketSender, Sender, SessionKey, Status, Transport, INTERLEAVED,
+    MAX_STREAMS, OUTGOING_QUEUE, STREAM_END,
 };
 
-use aes_gcm::{aead::Aead, Aes128Gcm, Key};
-use base64::{engine::general_purpose, Engine as _};
-use futures_util::{future, pin_mut, stream::TryStreamExt, StreamExt};
-use hmac::{Hmac, Mac};
-use p256::{ecdh::EphemeralSecret, pkcs8::der::Writer, PublicKey};
+use bytes::Bytes;
+use futures_util::{future, pin_mut, stream::TryStreamExt, SinkExt, StreamExt};
 use prost::Message;
-use rand::rngs::OsRng;
-use sha2::Sha256;
 use tokio_tungstenite::tungstenite::{protocol::Message as WebSocketMessage, Error};
-use tracing::error;
+use tracing::{debug, error};
 
 const DESTINATION: u8 = 0;
-const NONCE_SIZE: usize = 12;
+/// The number of chunks the sender may have in flight before it has to wait.
+const CREDIT_WINDOW: u32 = 64;
+/// The number of written chunks after which new credits are granted.
+const CREDIT_BATCH: u32 = 16;
+/// The number of times a file is received before a broken copy fails the transfer.
+const MAX_ATTEMPTS: u32 = 3;
+/// The name a received text snippet is saved under.
+const SNIPPET_NAME: &str = "snippet.txt";
+/// The suffix of a file while it is received. It gets its name once it is complete.
+const PART_SUFFIX: &str = ".caesar.part";
 
 #[cfg(target_os = "android")]
 const FILE_PATH_PREFIX: &str = "/storage/emulated/0/Download";
@@ -30,23 +60,226 @@ const FILE_PATH_PREFIX: &str = "/storage/emulated/0/Download";
 ///
 /// # Fields
 ///
+/// - `index`: The index of the file in the sender's list.
 /// - `name`: The name of the file.
+/// - `path`: The path the file is saved at once it is complete.
 /// - `size`: The total size of the file in bytes.
 /// - `progress`: The number of bytes that have been transferred so far.
-/// - `handle`: The file handle for reading and writing the file.
+/// - `offset`: The number of bytes that were already on disk before the transfer.
+/// - `hash`: The hash announced by the sender.
+/// - `hasher`: The hasher fed with every byte written to the file.
+/// - `damaged`: Whether writing a chunk of the current attempt failed.
+/// - `attempts`: The number of times the file has been requested.
+/// - `stream`: Whether the size of the file is unknown until it ends.
+/// - `handle`: Where the received bytes are written to.
 #[derive(Debug)]
 struct File {
+    /// The index of the file in the sender's list.
+    index: u32,
+
     /// The name of the file.
     name: String,
 
+    /// The path the file is saved at once it is complete.
+    ///
+    /// Until then a file is written next to it, with `PART_SUFFIX` appended to the path.
+    path: String,
+
     /// The total size of the file in bytes.
     size: u64,
 
     /// The number of bytes that have been transferred so far.
     progress: u64,
 
-    /// The file handle for reading and writing the file.
-    handle: fs::File,
+    /// The number of bytes that were already on disk before the transfer.
+    ///
+    /// A non-zero offset means the file is resumed from a previous transfer.
+    offset: u64,
+
+    /// The number of bytes of the file on disk, including those of a previous transfer.
+    length: u64,
+
+    /// The hash announced by the sender.
+    ///
+    /// An empty hash means the sender did not provide one and verification is skipped.
+    hash: Vec<u8>,
+
+    /// The hasher fed with every byte written to the file.
+    hasher: FileHasher,
+
+    /// Whether writing a chunk of the current attempt failed.
+    ///
+    /// The remaining chunks of a damaged file are discarded and the file is requested
+    /// again once the sender finished it.
+    damaged: bool,
+
+    /// The number of times the file has been requested.
+    attempts: u32,
+
+    /// Whether the size of the file is unknown until it ends.
+    ///
+    /// The sender marks the end of a stream with an empty chunk. It is not verified,
+    /// and it cannot be requested again or resumed.
+    stream: bool,
+
+    /// When the file was last modified on the sender's side.
+    ///
+    /// If `None`, the sender did not tell it.
+    modified: Option<SystemTime>,
+
+    /// The older copy of the file the sender describes the changes to.
+    ///
+    /// If `None`, the sender sends the file as it is.
+    basis: Option<Basis<fs::File>>,
+
+    /// Where the received bytes are written to, by a thread of the file.
+    handle: QueuedSink<Sink>,
+}
+
+/// Where the bytes of a received file are written to.
+#[derive(Debug)]
+enum Sink {
+    /// The part file in the target directory, which is renamed once it is complete.
+    File(fs::File),
+    /// The stdout of the receiver, e.g. a pipe into another program.
+    Stdout(io::Stdout),
+    /// A directory the received tar archive is unpacked into as it arrives.
+    Archive(Unpacker),
+}
+
+impl Sink {
+    /// Completes the file once its last byte was written.
+    ///
+    /// # Returns
+    ///
+    /// The error of the last write, or why the archive could not be unpacked.
+    fn finish(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Archive(unpacker) => unpacker.finish(),
+            sink => sink.flush(),
+        }
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Sink::File(file) => file.write(buf),
+            Sink::Stdout(stdout) => stdout.write(buf),
+            Sink::Archive(unpacker) => unpacker.write(buf),
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        match self {
+            Sink::File(file) => file.write_vectored(bufs),
+            Sink::Stdout(stdout) => stdout.write_vectored(bufs),
+            Sink::Archive(unpacker) => unpacker.write_vectored(bufs),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::File(file) => file.flush(),
+            Sink::Stdout(stdout) => stdout.flush(),
+            Sink::Archive(unpacker) => unpacker.flush(),
+        }
+    }
+}
+
+/// Unpacks a tar archive into a directory while it arrives.
+///
+/// The received bytes are written into a pipe, and a thread unpacks the archive from the
+/// other end, so the archive itself is never stored.
+#[derive(Debug)]
+struct Unpacker {
+    /// The end of the pipe the archive is written to. Closing it ends the archive.
+    pipe: Option<io::PipeWriter>,
+    /// The thread that unpacks the archive.
+    thread: Option<JoinHandle<io::Result<()>>>,
+    /// Whether the directory was created for the archive. Only then it is removed if the
+    /// archive does not arrive completely.
+    created: bool,
+}
+
+impl Unpacker {
+    /// Starts unpacking an archive into a directory.
+    ///
+    /// # Arguments
+    ///
+    /// * `directory` - The directory to unpack into. It is created if it does not exist.
+    ///
+    /// # Returns
+    ///
+    /// A new `Unpacker` instance, or the error if the directory cannot be created.
+    fn new(directory: &Path) -> io::Result<Unpacker> {
+        let created = !directory.exists();
+        fs::create_dir_all(directory)?;
+        let (mut reader, pipe) = io::pipe()?;
+        let directory = directory.to_path_buf();
+
+        let thread = thread::spawn(move || {
+            // Entries that would end up outside the directory are skipped
+            let unpacked = tar::Archive::new(&mut reader).unpack(&directory);
+            // Read the rest, so the transfer never blocks on a full pipe
+            io::copy(&mut reader, &mut io::sink())?;
+            unpacked
+        });
+
+        Ok(Unpacker {
+            pipe: Some(pipe),
+            thread: Some(thread),
+            created,
+        })
+    }
+
+    /// Ends the archive and waits until it is unpacked.
+    ///
+    /// # Returns
+    ///
+    /// The error if the archive could not be unpacked.
+    fn finish(&mut self) -> io::Result<()> {
+        drop(self.pipe.take());
+        match self.thread.take() {
+            Some(thread) => thread
+                .join()
+                .unwrap_or_else(|_| Err(io::Error::other("Unpacking the archive panicked."))),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the end of the pipe, or an error once the archive has ended.
+    fn pipe(&mut self) -> io::Result<&mut io::PipeWriter> {
+        self.pipe
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "The archive has ended."))
+    }
+}
+
+impl Write for Unpacker {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pipe()?.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.pipe()?.write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.pipe()?.flush()
+    }
+}
+
+impl From<fs::File> for Sink {
+    fn from(file: fs::File) -> Self {
+        Sink::File(file)
+    }
+}
+
+impl From<fs::File> for QueuedSink<Sink> {
+    fn from(file: fs::File) -> Self {
+        QueuedSink::new(Sink::File(file))
+    }
 }
 
 
@@ -54,27 +287,31 @@ struct File {
 ///
 /// # Fields
 ///
-/// - `hmac`: The HMAC key used for authentication.
+/// - `password`: The secret the handshake is authenticated with.
 /// - `sender`: The sender used for sending packets.
-/// - `key`: The ephemeral secret key used for key agreement.
 /// - `shared_key`: The shared key used for encryption.
 /// - `files`: The list of files being transferred.
 /// - `sequence`: The sequence number of the last received packet.
 /// - `index`: The index of the current file being transferred.
-/// - `progress`: The number of bytes transferred so far.
-/// - `length`: The total length of the file being transferred.
+/// - `compression`: The compression negotiated with the sender.
+/// - `options`: The options of the transfer.
+/// - `pending_credits`: The number of chunks written since credits were last granted.
+/// - `retries`: The files requested again, in the order the sender resends them.
+/// - `recovered`: The paths of the files saved by earlier sessions of the same transfer.
+/// - `writer`: The chunks received but not yet written.
+/// - `shared`: Whether other receivers are in the room as well.
+/// - `text`: The text snippet the sender sent in place of files.
+/// - `received`: The number of bytes received since the list arrived.
+/// - `started`: When the list arrived.
 struct Context {
-    /// The HMAC key used for authentication.
-    hmac: Vec<u8>,
+    /// The secret the handshake is authenticated with.
+    password: Vec<u8>,
 
     /// The sender used for sending packets.
     sender: Sender,
 
-    /// The ephemeral secret key used for key agreement.
-    key: EphemeralSecret,
-
     /// The shared key used for encryption.
-    shared_key: Option<Aes128Gcm>,
+    shared_key: Option<SessionKey>,
 
     /// The list of files being transferred.
     files: Vec<File>,
@@ -83,44 +320,121 @@ struct Context {
     sequence: u32,
 
     /// The index of the current file being transferred.
+    ///
+    /// Files the sender sends at the same time are told apart by their chunks instead.
     index: usize,
 
-    /// The number of bytes transferred so far.
-    progress: u64,
+    /// The compression negotiated with the sender.
+    compression: Compression,
 
-    /// The total length of the file being transferred.
-    length: u64,
+    /// Whether the sender sends several files at the same time.
+    ///
+    /// Every chunk names its file then, and the sequence numbers count the chunks of the
+    /// whole transfer.
+    interleaved: bool,
+
+    /// Whether the sender sends only the changed blocks of the files it gets a signature
+    /// for.
+    delta: bool,
+
+    /// The options of the transfer.
+    options: ReceiverOptions,
+
+    /// The number of chunks written since credits were last granted.
+    pending_credits: u32,
+
+    /// The files requested again, in the order the sender resends them.
+    ///
+    /// Each entry is a position in `files`. The sender resends these files after all
+    /// others, so they are received once the last regular file is done.
+    retries: VecDeque<usize>,
+
+    /// The paths of the files saved by earlier sessions of the same transfer.
+    recovered: HashMap<u32, String>,
+
+    /// The chunks received but not yet written.
+    writer: ChunkWriter,
+
+    /// Whether other receivers are in the room as well.
+    ///
+    /// Their positions in the room change as receivers come and go, so a packet for
+    /// one of them may reach this receiver now and then. It cannot be decrypted with
+    /// our key and is skipped.
+    shared: bool,
+
+    /// The text snippet the sender sent in place of files.
+    text: Option<String>,
+
+    /// The number of bytes received since the list arrived.
+    received: u64,
+
+    /// When the list arrived, which the speed of the transfer is measured from.
+    started: Instant,
+
+    /// The moving average of the speed, which the time left is estimated from.
+    throughput: Throughput,
+}
+
+/// What a receiver session leaves behind for the next session of the same transfer.
+///
+/// When a session breaks down, for example because the system was suspended, a new
+/// session continues the files at the paths they were saved at, no matter which
+/// conflict policy chose those paths.
+#[derive(Debug, Default)]
+pub struct Recovery {
+    /// The paths of the files that can be continued, by their index in the sender's list.
+    paths: HashMap<u32, String>,
+}
+
+impl Recovery {
+    /// Returns whether an earlier session saved files that a new session can continue.
+    pub fn is_resumable(&self) -> bool {
+        !self.paths.is_empty()
+    }
+
+    /// Removes the part files of the earlier sessions, once the transfer is cancelled
+    /// between two of them. Complete files have no part file any more and are kept.
+    pub fn remove_partial_files(&self) {
+        for path in self.paths.values() {
+            match fs::remove_file(part_path(path)) {
+                Err(error) if error.kind() != io::ErrorKind::NotFound => {
+                    error!("Error: Failed to remove partial file '{}': {}", path, error);
+                }
+                _ => {}
+            }
+        }
+    }
 }
 
 
 /// Handle the join room packet.
 ///
+/// The relay sends the position in the room to the client that joined, and a join
+/// packet without it to everyone already in the room. Only a room the sender shares
+/// with several receivers takes further members after us.
+///
 /// # Arguments
 ///
-/// * `size` - The size of the room.
+/// * `context` - The receiver context.
+/// * `size` - The position in the room, or `None` if another receiver joined.
 ///
 /// # Returns
 ///
 /// A `Status` representing the result of the operation.
-///
-/// # Errors
-///
-/// Returns an error if the join room packet is invalid.
-fn on_join_room(size: Option<usize>) -> Status {
-    // Check if the size of the room is provided
-    if size.is_none() {
-        // Return an error if the join room packet is invalid
-        return Status::Err("Invalid join room packet.".into());
-    }
+fn on_join_room(context: &mut Context, size: Option<usize>) -> Status {
+    let Some(position) = size else {
+        context.shared = true;
+        return Status::Continue();
+    };
+    context.shared = position > 1;
 
     // Print a message indicating that the client has successfully connected to the room
-    println!("Connected to room.");
+    notify!(context.options, "Connected to room.");
 
     // Return a continue status to indicate that the operation was successful
     Status::Continue()
 }
 
-
 /// Handle the error packet.
 ///
 /// # Arguments
@@ -136,7 +450,7 @@ fn on_join_room(size: Option<usize>) -> Status {
 /// Returns an error with the provided error message.
 fn on_error(message: String) -> Status {
     // Return an error with the provided error message
-    Status::Err(message)
+    Status::Err(message.into())
 }
 
 
@@ -145,7 +459,7 @@ fn on_error(message: String) -> Status {
 /// # Arguments
 ///
 /// * `context` - The receiver context.
-/// * `_` - The index of the sender. Currently unused.
+/// * `index` - The position of the member that left the room.
 ///
 /// # Returns
 ///
@@ -153,13 +467,33 @@ fn on_error(message: String) -> Status {
 ///
 /// # Errors
 ///
-/// Returns an error if there are still files being transferred.
-fn on_leave_room(context: &mut Context, _: usize) -> Status {
+/// Returns an error if there are still files being transferred, or if the sender left
+/// during the handshake.
+fn on_leave_room(context: &mut Context, index: usize) -> Status {
+    // The sender created the room, so anyone behind it is another receiver
+    if index != 0 {
+        return Status::Continue();
+    }
+
+    // The sender ends the transfer when our confirmation of the handshake does not
+    // match, so leaving before the list arrived means the PIN was wrong
+    if context.shared_key.is_some() && context.files.is_empty() && context.text.is_none() {
+        notify!(context.options);
+        notify!(context.options, "The sender ended the transfer during the handshake. Check the code and ask for a new one.");
+
+        return Status::Err(CaesarError::WrongCode(
+            "The sender did not accept the handshake.".into(),
+        ));
+    }
+
     // Check if there are any files being transferred with less than 100% progress
     if context.files.iter().any(|file| file.progress < 100) {
         // Print a message indicating that the transfer was interrupted because the host left the room
-        println!();
-        println!("Transfer was interrupted because the host left the room.");
+        notify!(context.options);
+        notify!(
+            context.options,
+            "Transfer was interrupted because the host left the room."
+        );
 
         // Return an error with the provided message
         Status::Err("Transfer was interrupted because the host left the room.".into())
@@ -170,471 +504,2904 @@ fn on_leave_room(context: &mut Context, _: usize) -> Status {
 }
 
 
-/// Handle the list packet.
+/// Finds a file path that does not exist yet by appending a counter to the name.
 ///
 /// # Arguments
 ///
-/// * `filepath` - The path to the directory where the files will be saved.
-/// * `context` - The receiver context.
-/// * `list` - The list packet containing the files to be transferred.
+/// * `directory` - The directory the file is saved in.
+/// * `name` - The sanitized name of the file.
 ///
 /// # Returns
 ///
-/// A `Status` representing the result of the operation.
+/// A path of the form `directory/name (n).ext` that does not exist yet.
+fn unique_file_path(directory: &str, name: &str) -> String {
+    let path = Path::new(name);
+    let stem = path
+        .file_stem()
+        .map_or(name.to_string(), |stem| stem.to_string_lossy().to_string());
+    let extension = path
+        .extension()
+        .map_or(String::new(), |extension| {
+            format!(".{}", extension.to_string_lossy())
+        });
+
+    (1..)
+        .map(|counter| format!("{}/{} ({}){}", directory, stem, counter, extension))
+        .find(|candidate| !Path::new(candidate).exists())
+        .unwrap()
+}
+
+/// Checks the chosen files against the size limits of the receiver.
 ///
-/// # Errors
+/// Streams have no size yet, so they are only checked while they are received.
 ///
-/// Returns an error if the list packet is invalid or if a file with the same name already exists.
-fn on_list(filepath: String, context: &mut Context, list: ListPacket) -> Status {
-    // Check if the shared key is established
-    if context.shared_key.is_none() {
-        return Status::Err("Invalid list packet: no shared key established".into());
+/// # Arguments
+///
+/// * `options` - The options with the limits.
+/// * `entries` - The files that were chosen to be received.
+///
+/// # Returns
+///
+/// Why the files are refused, or `None` if they are within the limits.
+fn size_limit_error(options: &ReceiverOptions, entries: &[&list_packet::Entry]) -> Option<String> {
+    let format = NumberFormat::current();
+
+    if let Some(limit) = options.max_file_size {
+        if let Some(entry) = entries.iter().find(|entry| entry.size > limit) {
+            return Some(format!(
+                "The file '{}' has {}, but the receiver accepts at most {} per file.",
+                entry.name,
+                format.size(entry.size),
+                format.size(limit)
+            ));
+        }
     }
 
-    // Iterate over the entries in the list packet
-    for entry in list.entries {
-        // Sanitize the filename to prevent directory traversal attacks
-        let path = sanitize_filename::sanitize(entry.name.clone());
-        // Construct the file path
-        let file_path = format!("{}/{}", filepath, path);
+    let total = entries
+        .iter()
+        .fold(0u64, |total, entry| total.saturating_add(entry.size));
+    match options.max_total_size {
+        Some(limit) if total > limit => Some(format!(
+            "The files have {} together, but the receiver accepts at most {} per transfer.",
+            format.size(total),
+            format.size(limit)
+        )),
+        _ => None,
+    }
+}
 
-        // Check if the file already exists
-        if Path::new(&file_path).exists() {
-            return Status::Err(format!("The file '{}' already exists.", path));
-        }
+/// Checks that the chosen files fit on the filesystem of the target directory.
+///
+/// The bytes that part files of an interrupted transfer already hold are not needed
+/// again. Streams have no size yet and are not counted.
+///
+/// # Arguments
+///
+/// * `directory` - The directory the files are saved in, which may not exist yet.
+/// * `entries` - The files that were chosen to be received.
+///
+/// # Returns
+///
+/// Why the files do not fit, or `None` if they fit or the free space is unknown.
+fn space_error(directory: &str, entries: &[&list_packet::Entry]) -> Option<String> {
+    let needed = entries.iter().fold(0u64, |needed, entry| {
+        let path = format!("{}/{}", directory, sanitize_filename::sanitize(&entry.name));
+        let saved = fs::symlink_metadata(part_path(&path)).map_or(0, |metadata| metadata.len());
+        needed.saturating_add(entry.size.saturating_sub(saved))
+    });
 
-        // Create a new file
-        let handle = match fs::File::create(&file_path) {
-            Ok(handle) => handle,
-            Err(error) => {
-                return Status::Err(format!(
-                    "Error: Failed to create file '{}': {}",
-                    file_path, error
-                ));
-            }
-        };
+    // The directory is created later, so the nearest one that exists is asked
+    let existing = Path::new(directory)
+        .ancestors()
+        .find(|path| path.exists())
+        .unwrap_or(Path::new("."));
+    let free = match fs4::available_space(existing) {
+        Ok(free) => free,
+        Err(error) => {
+            debug!(
+                "The free space of '{}' is unknown: {}",
+                existing.display(),
+                error
+            );
+            return None;
+        }
+    };
 
-        // Create a new file object and add it to the context
-        let file = File {
-            name: entry.name,
-            size: entry.size,
-            handle,
-            progress: 0,
-        };
+    let format = NumberFormat::current();
+    (needed > free).then(|| {
+        format!(
+            "Not enough space in '{}': the files need {}, but only {} are free.",
+            directory,
+            format.size(needed),
+            format.size(free)
+        )
+    })
+}
 
-        context.files.push(file);
+/// Checks if a file the receiver already has matches an offered entry.
+///
+/// Streams, links and entries without a hash are never taken as identical, as there
+/// is nothing to compare them with.
+///
+/// # Arguments
+///
+/// * `path` - The path the entry would be written to.
+/// * `entry` - The entry the sender offers.
+/// * `algorithm` - The algorithm the sender hashed its files with.
+///
+/// # Returns
+///
+/// `true` if the file has the same size and hash as the entry.
+fn has_same_content(path: &str, entry: &list_packet::Entry, algorithm: HashAlgorithm) -> bool {
+    if entry.hash.is_empty() || entry.stream || !entry.link.is_empty() {
+        return false;
     }
 
-    // Reset the context for the next file transfer
-    context.index = 0;
-    context.progress = 0;
-    context.sequence = 0;
-    context.length = 0;
+    // A link may lead to a file the sender should learn nothing about
+    if is_link(path) {
+        return false;
+    }
+    let Ok(mut file) = fs::File::open(path) else {
+        return false;
+    };
+    // Comparing the size first spares hashing files that differ anyway
+    if !file
+        .metadata()
+        .is_ok_and(|metadata| metadata.is_file() && metadata.len() == entry.size)
+    {
+        return false;
+    }
 
-    Status::Continue()
+    let mut hasher = FileHasher::new(algorithm);
+    io::copy(&mut file, &mut hasher).is_ok() && hasher.finalize_reset() == entry.hash
 }
 
-/// Handle a chunk packet.
-///
-/// This function is responsible for processing chunk packets received from the sender.
-/// It checks if the shared key has been established, verifies the sequence number,
-/// writes the chunk to the corresponding file, updates the file's progress, sends progress
-/// updates if necessary, and handles the end of a file transfer.
+/// Opens the copy of a file the receiver has, so only its changed blocks are sent.
 ///
 /// # Arguments
 ///
-/// * `context` - The receiver context.
-/// * `chunk` - The chunk packet received from the sender.
+/// * `path` - The path of the copy.
+/// * `index` - The index of the file in the sender's list.
 ///
 /// # Returns
 ///
-/// A status indicating if the operation was successful.
-fn on_chunk(context: &mut Context, chunk: ChunkPacket) -> Status {
-    // Check if the shared key is established
-    if context.shared_key.is_none() {
-        return Status::Err("Invalid chunk packet: no shared key established".into());
+/// The copy and the signature for the sender, or `None` if there is no copy with a
+/// whole block to build on.
+fn open_basis(path: &str, index: u32) -> Option<(Basis<fs::File>, SignaturePacket)> {
+    let file = fs::File::open(path).ok()?;
+    let metadata = file.metadata().ok().filter(|metadata| metadata.is_file())?;
+    match Basis::new(file, metadata.len(), index) {
+        Ok((basis, signature)) if !signature.blocks.is_empty() => Some((basis, signature)),
+        Ok(_) => None,
+        Err(error) => {
+            debug!("Failed to read '{}' for a delta: {}", path, error);
+            None
+        }
     }
+}
 
-    // Verify the sequence number
-    if chunk.sequence != context.sequence {
-        return Status::Err(format!(
-            "Expected sequence {}, but got {}.",
-            context.sequence, chunk.sequence
-        ));
+/// Checks that a link the sender sent stays inside the directory it is created in.
+///
+/// The target has to be relative and may not climb up with `..`. Parts of it that
+/// already exist, e.g. a link the user keeps in the directory, must not lead outside
+/// either.
+///
+/// # Arguments
+///
+/// * `target` - The path the link points to.
+/// * `directory` - The directory the link is created in.
+///
+/// # Returns
+///
+/// `true` if the link is safe to create.
+fn is_contained_link(target: &str, directory: &str) -> bool {
+    let target = Path::new(target);
+    if target.as_os_str().is_empty()
+        || !target
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        return false;
     }
 
-    // Get the file corresponding to the current index
-    let Some(file) = context.files.get_mut(context.index) else {
-        return Status::Err("Invalid file index.".into());
+    let Ok(root) = fs::canonicalize(directory) else {
+        return false;
     };
+    let mut path = root.clone();
+    for component in target.components() {
+        path.push(component);
+        if fs::symlink_metadata(&path).is_err() {
+            break;
+        }
+        if !fs::canonicalize(&path).is_ok_and(|resolved| resolved.starts_with(&root)) {
+            return false;
+        }
+    }
+    true
+}
 
-    // Update the file's length
-    context.length += chunk.chunk.len() as u64;
-
-    // Increment the sequence number
-    context.sequence += 1;
+/// Returns the name of an entry that would be written over or through another one.
+///
+/// Two entries with the same name, or one named like the part file of another, let a
+/// link of the sender redirect where a later file is written.
+///
+/// # Arguments
+///
+/// * `entries` - The entries of the list packet.
+fn colliding_entry(entries: &[list_packet::Entry]) -> Option<String> {
+    let mut names = HashSet::new();
+    for entry in entries {
+        let name = sanitize_filename::sanitize(&entry.name);
+        if !names.insert(name.clone()) {
+            return Some(name);
+        }
+    }
+    names.iter().find(|name| names.contains(&part_path(name))).cloned()
+}
 
-    // Write the chunk to the file
-    file.handle.write(&chunk.chunk).unwrap();
+/// Opens a part file for writing, without following a link in its place.
+///
+/// # Arguments
+///
+/// * `part` - The path of the part file.
+/// * `append` - Continue the file instead of starting it over.
+fn open_part(part: &str, append: bool) -> io::Result<fs::File> {
+    let mut options = fs::OpenOptions::new();
+    if append {
+        options.append(true);
+    } else {
+        options.write(true).create(true).truncate(true);
+    }
 
-    // Update the file's progress
-    file.progress = (context.length * 100) / file.size;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.custom_flags(libc::O_NOFOLLOW);
+    }
 
-    // Send progress updates if necessary
-    if file.progress == 100 || file.progress - context.progress >= 1 || chunk.sequence == 0 {
-        context.progress = file.progress;
+    #[cfg(not(unix))]
+    {
+        if is_link(part) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a link is in the place of the part file",
+            ));
+        }
+    }
 
-        let progress = ProgressPacket {
-            index: context.index.try_into().unwrap(),
-            progress: context.progress.try_into().unwrap(),
-        };
+    options.open(part)
+}
 
-        context.sender.send_encrypted_packet(
-            &context.shared_key,
-            DESTINATION,
-            Value::Progress(progress),
-        );
+/// Returns `true` if the path is a link, whether or not its target exists.
+///
+/// # Arguments
+///
+/// * `path` - The path to check.
+fn is_link(path: &str) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_symlink())
+}
 
-        print!("\rTransferring '{}': {}%", file.name, file.progress);
-        std::io::Write::flush(&mut stdout()).unwrap();
+/// Creates a link the sender sent in place of a file.
+///
+/// The target is taken as it is, so a relative link points to the same place as on the
+/// sender's side. It has to pass `is_contained_link` first.
+///
+/// # Arguments
+///
+/// * `target` - The path the link points to.
+/// * `path` - The path of the link.
+///
+/// # Returns
+///
+/// An error of the kind `Unsupported` on systems other than Unix.
+fn create_link(target: &str, path: &str) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(target, path)
     }
 
-    // Handle the end of a file transfer
-    if file.size == context.length {
-        context.index += 1;
-        context.length = 0;
-        context.progress = 0;
-        context.sequence = 0;
-
-        println!();
+    #[cfg(not(unix))]
+    {
+        let _ = (target, path);
+        Err(io::ErrorKind::Unsupported.into())
     }
+}
 
-    Status::Continue()
+/// Returns the path a file is written to until it is complete.
+///
+/// # Arguments
+///
+/// * `path` - The path the file is saved at once it is complete.
+fn part_path(path: &str) -> String {
+    format!("{}{}", path, PART_SUFFIX)
 }
 
-/// Handle the handshake packet.
+/// Handle the list packet.
 ///
-/// This function is responsible for handling the handshake packet received from the sender.
-/// It performs the necessary verification and establishes the shared key between the sender and receiver.
+/// The target directory is created if it does not exist yet. Files that already exist
+/// are handled according to the conflict policy of the receiver. Every file is written
+/// to a part file next to its path, and a part file an earlier transfer left behind is
+/// resumed. A file under the final name is never taken for a partial download, so
+/// with the default `Abort` policy any existing file ends the transfer with
+/// `FileExists` and is left as it is. Files saved by an earlier session of the same
+/// transfer are continued at their paths instead.
+///
+/// The number of bytes already present for each file is reported back to the sender in
+/// a resume packet. Skipped files are reported with their full size. The sender is then
+/// granted an initial window of credits, one for each chunk it may send.
 ///
 /// # Arguments
 ///
+/// * `filepath` - The path to the directory where the files will be saved.
 /// * `context` - The receiver context.
-/// * `handshake` - The handshake packet received from the sender.
+/// * `list` - The list packet containing the files to be transferred.
 ///
 /// # Returns
 ///
 /// A `Status` representing the result of the operation.
-fn on_handshake(context: &mut Context, handshake: HandshakePacket) -> Status {
-    // Check if the shared key is already established
-    if context.shared_key.is_some() {
-        return Status::Err("Already performed handshake.".into());
+///
+/// # Errors
+///
+/// Returns an error if the list packet is invalid or if a file with the same name already
+/// exists and the conflict policy is `Abort`.
+fn on_list(filepath: String, context: &mut Context, list: ListPacket) -> Status {
+    // Check if the shared key is established
+    if context.shared_key.is_none() {
+        return Status::Err("Invalid list packet: no shared key established".into());
     }
 
-    // Create a HMAC instance using the shared key
-    let mut mac = Hmac::<Sha256>::new_from_slice(&context.hmac).unwrap();
+    // Refuse lists in which a file would be written over or through another entry
+    if let Some(name) = colliding_entry(&list.entries) {
+        return Status::Err(
+            format!("Invalid list packet: '{name}' collides with another file").into(),
+        );
+    }
 
-    // Update the HMAC with the sender's public key
-    mac.update(&handshake.public_key);
+    let offered: Vec<OfferedFile> = list
+        .entries
+        .iter()
+        .map(|entry| OfferedFile {
+            index: entry.index,
+            name: entry.name.clone(),
+            size: entry.size,
+            stream: entry.stream,
+        })
+        .collect();
+    if let Some(events) = &context.options.events {
+        events.report(TransferEvent::List {
+            files: offered
+                .iter()
+                .map(|file| ListedFile {
+                    name: file.name.clone(),
+                    size: file.size,
+                })
+                .collect(),
+        });
+    }
 
-    // Verify the signature using the HMAC
-    let verification = mac.verify_slice(&handshake.signature);
-    if verification.is_err() {
-        return Status::Err("Invalid signature from the sender.".into());
+    // Choose the files to receive, the sender skips the others
+    let selected: Option<HashSet<u32>> = context
+        .options
+        .select
+        .as_ref()
+        .map(|selection| selection.select(&offered).into_iter().collect());
+    if let Some(selected) = &selected {
+        let count = list
+            .entries
+            .iter()
+            .filter(|entry| selected.contains(&entry.index))
+            .count();
+        if count == 0 {
+            return Status::Err("None of the offered files was selected.".into());
+        }
+        notify!(
+            context.options,
+            "Receiving {} of {} files.",
+            count,
+            list.entries.len()
+        );
     }
 
-    // Generate the receiver's public key
-    let public_key = context.key.public_key().to_sec1_bytes().into_vec();
+    // Refuse files larger than the receiver accepts before anything is written
+    let chosen: Vec<&list_packet::Entry> = list
+        .entries
+        .iter()
+        .filter(|entry| {
+            selected
+                .as_ref()
+                .is_none_or(|selected| selected.contains(&entry.index))
+        })
+        .collect();
+    if let Some(message) = size_limit_error(&context.options, &chosen) {
+        // Let the sender know why the transfer was refused
+        context.sender.send_encrypted_packet(
+            &context.shared_key,
+            DESTINATION,
+            Value::Error(ErrorPacket {
+                message: message.clone(),
+            }),
+        );
 
-    // Create a new HMAC instance using the shared key
-    let mut mac = Hmac::<Sha256>::new_from_slice(&context.hmac).unwrap();
+        return Status::Err(message.into());
+    }
 
-    // Update the HMAC with the receiver's public key
-    mac.update(&public_key);
+    // Files the receiver already has with the same content are not sent again
+    let hash_algorithm = list.hash_algorithm();
+    let identical: HashSet<u32> = chosen
+        .iter()
+        .filter(|entry| {
+            !context.options.stdout
+                && !context.recovered.contains_key(&entry.index)
+                && has_same_content(
+                    &format!("{}/{}", filepath, sanitize_filename::sanitize(&entry.name)),
+                    entry,
+                    hash_algorithm,
+                )
+        })
+        .map(|entry| entry.index)
+        .collect();
+
+    // Fail now if the files do not fit, rather than with a write error halfway through
+    if !context.options.stdout {
+        let missing: Vec<&list_packet::Entry> = chosen
+            .iter()
+            .copied()
+            .filter(|entry| !identical.contains(&entry.index))
+            .collect();
+        if let Some(message) = space_error(&filepath, &missing) {
+            // Let the sender know why the transfer was refused
+            context.sender.send_encrypted_packet(
+                &context.shared_key,
+                DESTINATION,
+                Value::Error(ErrorPacket {
+                    message: message.clone(),
+                }),
+            );
+
+            return Status::Err(message.into());
+        }
+    }
+
+    // Ask before anything is written, a session that continues saved files asked already
+    if let Some(confirmation) = &context.options.confirm {
+        let chosen: Vec<OfferedFile> = offered
+            .into_iter()
+            .filter(|file| {
+                selected
+                    .as_ref()
+                    .is_none_or(|selected| selected.contains(&file.index))
+            })
+            .collect();
+        if context.recovered.is_empty() && !confirmation.confirm(&chosen) {
+            // Let the sender know why the transfer ended
+            context.sender.send_encrypted_packet(
+                &context.shared_key,
+                DESTINATION,
+                Value::Error(ErrorPacket {
+                    message: "The receiver declined the transfer.".to_string(),
+                }),
+            );
+
+            return Status::Err("The transfer was declined.".into());
+        }
+    }
+
+    // Create the target directory if it is missing, files written to stdout need none
+    if !context.options.stdout {
+        if let Err(error) = fs::create_dir_all(&filepath) {
+            return Status::Err(CaesarError::io(
+                format!("Failed to create directory '{}'", filepath),
+                error,
+            ));
+        }
+    }
+
+    let mut resume_entries = vec![];
+
+    // Iterate over the entries in the list packet
+    for entry in list.entries {
+        // Sanitize the filename to prevent directory traversal attacks
+        let path = sanitize_filename::sanitize(entry.name.clone());
+        // Construct the file path
+        let mut file_path = format!("{}/{}", filepath, path);
+        let mut offset = 0;
+        // The offset that tells the sender not to send the file
+        let end = if entry.stream { STREAM_END } else { entry.size };
+
+        if selected
+            .as_ref()
+            .is_some_and(|selected| !selected.contains(&entry.index))
+        {
+            resume_entries.push(resume_packet::Entry {
+                index: entry.index,
+                offset: end,
+            });
+            continue;
+        }
+
+        if context.options.stdout {
+            // Stdout takes every file from the start, one after the other
+        } else if let Some(recovered) = context.recovered.get(&entry.index) {
+            // Continue the file an earlier session of this transfer saved
+            file_path = recovered.clone();
+            if fs::metadata(&file_path).is_ok_and(|metadata| metadata.len() == entry.size) {
+                notify!(context.options, "'{}' was already received.", entry.name);
+
+                resume_entries.push(resume_packet::Entry {
+                    index: entry.index,
+                    offset: end,
+                });
+                continue;
+            }
+        } else if identical.contains(&entry.index) {
+            notify!(
+                context.options,
+                "'{}' is already here with the same content.",
+                entry.name
+            );
+
+            resume_entries.push(resume_packet::Entry {
+                index: entry.index,
+                offset: end,
+            });
+            continue;
+        } else if fs::symlink_metadata(&file_path).is_ok() {
+            // Resolve conflicts with existing files. Partial downloads only ever carry
+            // the suffix of a part file, so a file under the final name is never one.
+            match context.options.on_conflict {
+                ConflictPolicy::Abort => {
+                    return Status::Err(CaesarError::FileExists(path));
+                }
+                // Overwriting replaces a link itself, never what it points to
+                ConflictPolicy::Overwrite => {
+                    if is_link(&file_path) {
+                        let _ = fs::remove_file(&file_path);
+                    }
+                }
+                ConflictPolicy::Rename => file_path = unique_file_path(&filepath, &path),
+                ConflictPolicy::Skip => {
+                    notify!(
+                        context.options,
+                        "Skipping '{}' because it already exists.",
+                        entry.name
+                    );
+
+                    resume_entries.push(resume_packet::Entry {
+                        index: entry.index,
+                        offset: end,
+                    });
+                    continue;
+                }
+            }
+        }
+
+        // A link has no content, so it is created at once and nothing is sent for it
+        if !entry.link.is_empty() {
+            if context.options.stdout {
+                notify!(
+                    context.options,
+                    "Skipping '{}' because links cannot be written to stdout.",
+                    entry.name
+                );
+            } else if !context.options.links {
+                notify!(
+                    context.options,
+                    "Skipping '{}' because creating links is turned off.",
+                    entry.name
+                );
+            } else if !is_contained_link(&entry.link, &filepath) {
+                notify!(
+                    context.options,
+                    "Skipping '{}' because its link points outside the target directory.",
+                    entry.name
+                );
+            } else {
+                // Overwriting replaces the file, not the one an existing link points to
+                if context.options.on_conflict == ConflictPolicy::Overwrite {
+                    let _ = fs::remove_file(&file_path);
+                }
+                match create_link(&entry.link, &file_path) {
+                    Ok(()) => {}
+                    Err(error) if error.kind() == io::ErrorKind::Unsupported => {
+                        notify!(
+                            context.options,
+                            "Skipping '{}' because links are only created on Unix.",
+                            entry.name
+                        );
+                    }
+                    Err(error) => {
+                        return Status::Err(CaesarError::io(
+                            format!("Failed to create link '{}'", file_path),
+                            error,
+                        ));
+                    }
+                }
+            }
+
+            resume_entries.push(resume_packet::Entry {
+                index: entry.index,
+                offset: end,
+            });
+            continue;
+        }
+
+        // Continue the part file an interrupted transfer left behind. Streams are never
+        // resumed, and archives are unpacked without one.
+        let part = part_path(&file_path);
+        let writes_part = !context.options.stdout && !entry.archive;
+        if writes_part && !entry.stream {
+            match fs::symlink_metadata(&part) {
+                Ok(metadata) if metadata.is_file() && metadata.len() < entry.size => {
+                    offset = metadata.len()
+                }
+                _ => {}
+            }
+        }
+
+        // Create a new part file or open the partial one for appending, without following
+        // a link in its place. An archive is unpacked into a directory, unless it goes to
+        // stdout as it is.
+        let handle = if context.options.stdout {
+            Ok(Sink::Stdout(io::stdout()))
+        } else if entry.archive {
+            Unpacker::new(Path::new(&file_path)).map(Sink::Archive)
+        } else {
+            open_part(&part, offset > 0).map(Sink::from)
+        };
+        let handle = match handle {
+            Ok(handle) => handle,
+            Err(error) => {
+                return Status::Err(CaesarError::io(
+                    format!("Failed to create file '{}'", file_path),
+                    error,
+                ));
+            }
+        };
+
+        // Ask only for the blocks that changed of an older copy that is overwritten
+        let mut basis = None;
+        if context.delta
+            && context.options.delta
+            && context.options.on_conflict == ConflictPolicy::Overwrite
+            && writes_part
+            && !entry.stream
+            && offset == 0
+            && entry.size > 0
+        {
+            if let Some((opened, signature)) = open_basis(&file_path, entry.index) {
+                notify!(
+                    context.options,
+                    "Fetching only the changed parts of '{}'.",
+                    entry.name
+                );
+
+                context.sender.send_encrypted_packet(
+                    &context.shared_key,
+                    DESTINATION,
+                    Value::Signature(signature),
+                );
+                basis = Some(opened);
+            }
+        }
+
+        // Feed the bytes that are already on disk into the hasher
+        let mut hasher = FileHasher::new(hash_algorithm);
+        if offset > 0 {
+            notify!(
+                context.options,
+                "Resuming '{}' at {} bytes.",
+                entry.name,
+                offset
+            );
+
+            let copied =
+                fs::File::open(&part).and_then(|mut existing| io::copy(&mut existing, &mut hasher));
+            if let Err(error) = copied {
+                return Status::Err(CaesarError::io(
+                    format!("Failed to read file '{}'", file_path),
+                    error,
+                ));
+            }
+        }
+
+        resume_entries.push(resume_packet::Entry {
+            index: entry.index,
+            offset,
+        });
+
+        // Create a new file object and add it to the context
+        let file = File {
+            index: entry.index,
+            name: entry.name,
+            path: file_path,
+            size: entry.size,
+            handle: QueuedSink::new(handle),
+            progress: 0,
+            offset,
+            length: offset,
+            hash: entry.hash,
+            hasher,
+            damaged: false,
+            attempts: 1,
+            stream: entry.stream,
+            modified: (entry.modified > 0)
+                .then(|| UNIX_EPOCH + Duration::from_nanos(entry.modified)),
+            basis,
+        };
+
+        context.files.push(file);
+    }
+
+    // Reset the context for the next file transfer
+    context.received = 0;
+    context.started = Instant::now();
+    context.throughput = Throughput::default();
+    context.index = 0;
+    context.sequence = 0;
+    context.retries.clear();
+
+    // Tell the sender where to continue each file
+    context.sender.send_encrypted_packet(
+        &context.shared_key,
+        DESTINATION,
+        Value::Resume(ResumePacket {
+            entries: resume_entries,
+        }),
+    );
+
+    // Nothing is left to receive if every file was skipped
+    if context.files.is_empty() {
+        return Status::Exit();
+    }
+
+    // Allow the sender to start sending chunks
+    context.pending_credits = 0;
+    context.sender.send_encrypted_packet(
+        &context.shared_key,
+        DESTINATION,
+        Value::Credit(CreditPacket {
+            chunks: CREDIT_WINDOW,
+        }),
+    );
+
+    Status::Continue()
+}
+
+/// Handle a chunk packet.
+///
+/// This function is responsible for processing chunk packets received from the sender.
+/// It checks if the shared key has been established, verifies the sequence number,
+/// writes the chunk to the corresponding file, grants the sender new credits after every
+/// batch of written chunks, updates the file's progress, sends progress updates if
+/// necessary, and handles the end of a file transfer.
+///
+/// # Arguments
+///
+/// * `context` - The receiver context.
+/// * `chunk` - The chunk packet received from the sender.
+///
+/// # Returns
+///
+/// A status indicating if the operation was successful.
+fn on_chunk(context: &mut Context, chunk: ChunkPacket) -> Status {
+    // Check if the shared key is established
+    if context.shared_key.is_none() {
+        return Status::Err("Invalid chunk packet: no shared key established".into());
+    }
+
+    // Verify the sequence number
+    if chunk.sequence != context.sequence {
+        return Status::Err(
+            format!(
+                "Expected sequence {}, but got {}.",
+                context.sequence, chunk.sequence
+            )
+            .into(),
+        );
+    }
+
+    // Get the file the chunk belongs to, which is named by the chunk itself when the
+    // sender sends several files at the same time
+    let position = match context.interleaved {
+        true => context
+            .files
+            .binary_search_by_key(&chunk.index, |file| file.index)
+            .ok(),
+        false => Some(context.index),
+    };
+    let Some(file) = position.and_then(|position| context.files.get_mut(position)) else {
+        return Status::Err("Invalid file index.".into());
+    };
+    let position = position.unwrap_or_default();
+
+    // Decompress the chunk if compression was negotiated
+    let data = match context.compression {
+        Compression::None => chunk.chunk,
+        Compression::Zstd => match context.writer.decompress(&chunk.chunk) {
+            Ok(data) => data,
+            Err(error) => {
+                return Status::Err(format!("Failed to decompress chunk: {}", error).into())
+            }
+        },
+    };
+
+    // Rebuild a file the sender sends as a delta from the blocks of the older copy
+    let data = match &mut file.basis {
+        Some(basis) => {
+            let patched = DeltaPacket::decode(data)
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+                .and_then(|delta| basis.patch(delta, file.size.saturating_sub(file.length)));
+            match patched {
+                Ok(patched) => Bytes::from(patched),
+                Err(error) => {
+                    return Status::Err(
+                        format!("Failed to apply the delta of '{}': {}", file.name, error).into(),
+                    )
+                }
+            }
+        }
+        None => data,
+    };
+
+    // The sender ends a stream with an empty chunk
+    let end = data.is_empty();
+
+    // Update the file's length
+    file.length += data.len() as u64;
+    context.received += data.len() as u64;
+    context.throughput.record(context.received, Instant::now());
+
+    // A stream had no size to check in advance, so it is stopped once it is too large
+    let options = &context.options;
+    let too_long = options
+        .max_file_size
+        .is_some_and(|limit| file.length > limit);
+    let too_many = options
+        .max_total_size
+        .is_some_and(|limit| context.received > limit);
+    if file.stream && (too_long || too_many) {
+        let message = format!(
+            "The stream '{}' is larger than the receiver accepts.",
+            file.name
+        );
+
+        // Let the sender know why the transfer failed
+        context.sender.send_encrypted_packet(
+            &context.shared_key,
+            DESTINATION,
+            Value::Error(ErrorPacket {
+                message: message.clone(),
+            }),
+        );
+
+        return Status::Err(message.into());
+    }
+
+    // Increment the sequence number
+    context.sequence += 1;
+
+    // Queue the chunk for writing, discarding the rest of a damaged file
+    if !file.damaged {
+        context.writer.push(data);
+    }
+
+    // Write the batch before the sender gets room for more chunks, and a finished file
+    // before its hash is checked
+    context.pending_credits += 1;
+    let finished = if file.stream {
+        end
+    } else {
+        file.size == file.length
+    };
+    if context.pending_credits == CREDIT_BATCH || finished || context.interleaved {
+        let written = context
+            .writer
+            .write_to(&mut file.handle, &mut file.hasher)
+            .and_then(|_| {
+                if finished {
+                    file.handle.wait().and_then(Sink::finish)
+                } else {
+                    Ok(())
+                }
+            });
+        if let Err(error) = written {
+            notify!(context.options);
+            notify!(
+                context.options,
+                "Error: Failed to write file '{}': {}",
+                file.name,
+                error
+            );
+            file.damaged = true;
+        }
+    }
+
+    // Grant the sender room for more chunks once a batch has been written
+    if context.pending_credits == CREDIT_BATCH {
+        context.pending_credits = 0;
+        context.sender.send_encrypted_packet(
+            &context.shared_key,
+            DESTINATION,
+            Value::Credit(CreditPacket {
+                chunks: CREDIT_BATCH,
+            }),
+        );
+    }
+
+    // Verify the integrity of a finished file before it is reported as complete, so
+    // the sender never counts a broken file as done
+    let mut intact = true;
+    if finished {
+        let hash = file.hasher.finalize_reset();
+        intact = !file.damaged && (file.hash.is_empty() || hash == file.hash);
+
+        // Streams are sent only once and stdout cannot be rewound, so such files are
+        // never requested again
+        let retryable = !file.stream && !context.options.stdout;
+        if !intact && (file.attempts >= MAX_ATTEMPTS || !retryable) {
+            let message = if file.damaged {
+                format!("Failed to write file '{}'.", file.name)
+            } else {
+                format!("Integrity check failed for file '{}'.", file.name)
+            };
+
+            // Let the sender know why the transfer failed
+            context.sender.send_encrypted_packet(
+                &context.shared_key,
+                DESTINATION,
+                Value::Error(ErrorPacket {
+                    message: message.clone(),
+                }),
+            );
+
+            notify!(context.options);
+            return Status::Err(message.into());
+        }
+    }
+
+    // Give a complete file its name, so no file carries it before all of it arrived
+    if finished && intact {
+        if let Some(Sink::File(handle)) = file.handle.get() {
+            // Keep the time the sender's file was last modified, if asked to
+            let modified = file.modified.filter(|_| context.options.preserve_times);
+            let renamed = modified
+                .map_or(Ok(()), |modified| handle.set_modified(modified))
+                .and_then(|_| handle.sync_all())
+                .and_then(|_| fs::rename(part_path(&file.path), &file.path));
+            if let Err(error) = renamed {
+                notify!(context.options);
+                return Status::Err(CaesarError::io(
+                    format!("Failed to save file '{}'", file.path),
+                    error,
+                ));
+            }
+        }
+    }
+
+    // Update the file's progress, a stream is only done once it ended. An empty file is
+    // done at once, and a sender that sends more than it listed cannot go past 100%.
+    let progress = match file.stream {
+        true if finished => 100,
+        true => 0,
+        false => file
+            .length
+            .saturating_mul(100)
+            .checked_div(file.size)
+            .map_or(100, |progress| progress.min(100)),
+    };
+    let advanced = progress != file.progress;
+    file.progress = progress;
+
+    // Send progress updates if necessary
+    if intact && (file.progress == 100 || advanced || chunk.sequence == 0) {
+        let progress = ProgressPacket {
+            index: file.index,
+            progress: u32::try_from(file.progress.min(100)).unwrap_or(100),
+        };
+
+        context.sender.send_encrypted_packet(
+            &context.shared_key,
+            DESTINATION,
+            Value::Progress(progress),
+        );
+
+        if let Some(events) = &context.options.events {
+            events.report(TransferEvent::Progress {
+                file: file.index as usize,
+                receiver: None,
+                transferred: file.length,
+                size: file.size,
+            });
+        }
+
+        let total = remaining_bytes(context);
+        let file = &context.files[position];
+        // A stream has no end to estimate
+        let left = (!file.stream).then(|| file.size.saturating_sub(file.length));
+
+        // Show how much arrived, unless the progress is drawn or reported elsewhere
+        if let Some(report) = &context.options.progress {
+            report.report(&FileProgress {
+                peer: 0,
+                index: file.index,
+                name: file.name.clone(),
+                size: file.size,
+                transferred: Some(file.length),
+                done: file.progress == 100,
+                remaining: context.throughput.remaining_total(left, total),
+            });
+        } else if context.options.events.is_none() {
+            let format = NumberFormat::current();
+            let speed = context
+                .throughput
+                .describe(left, total, &format)
+                .unwrap_or_else(|| format.speed(context.received, context.started.elapsed()));
+
+            // A stream has no size to compare with
+            let status = if file.stream {
+                format!(
+                    "\rTransferring '{}': {} ({})",
+                    file.name,
+                    format.size(file.length),
+                    speed
+                )
+            } else {
+                format!(
+                    "\rTransferring '{}': {}% of {} ({})",
+                    file.name,
+                    file.progress,
+                    format.size(file.size),
+                    speed
+                )
+            };
+            if context.options.stdout {
+                eprint!("{}", status);
+                stderr().flush().unwrap();
+            } else {
+                print!("{}", status);
+                stdout().flush().unwrap();
+            }
+        }
+    }
+
+    // Handle the end of a file transfer
+    if finished {
+        if context.options.progress.is_none() && context.options.events.is_none() {
+            notify!(context.options);
+        }
+
+        if !intact {
+            if let Err(error) = request_retry(context, position) {
+                return Status::Err(error);
+            }
+        }
+
+        // Files sent at the same time keep counting their chunks together
+        if !context.interleaved {
+            context.index += 1;
+
+            // Receive the files requested again once all others are done
+            if context.index >= context.files.len() {
+                if let Some(position) = context.retries.pop_front() {
+                    context.index = position;
+                }
+            }
+
+            context.sequence = 0;
+        }
+    }
+
+    Status::Continue()
+}
+
+/// Counts the bytes of all files that are still to come.
+///
+/// # Arguments
+///
+/// * `context` - The receiver context.
+///
+/// # Returns
+///
+/// The number of bytes, or `None` while a stream leaves the end open.
+fn remaining_bytes(context: &Context) -> Option<u64> {
+    let pending = context.files.iter().filter(|file| file.progress < 100);
+    let mut bytes = 0;
+    for file in pending {
+        if file.stream {
+            return None;
+        }
+        bytes += file.size.saturating_sub(file.length);
+    }
+    Some(bytes)
+}
+
+/// Requests a file again after it arrived broken.
+///
+/// The file is truncated and the sender is asked to send it again from the start.
+/// The sender resends it after the files it has not sent yet.
+///
+/// # Arguments
+///
+/// * `context` - The receiver context.
+/// * `position` - The position of the file in the list.
+///
+/// # Returns
+///
+/// A `Result` with an error if the file could not be reset.
+fn request_retry(context: &mut Context, position: usize) -> Result<()> {
+    let file = &mut context.files[position];
+
+    // Start over with an empty file. Files on stdout are never requested again.
+    if let Ok(Sink::File(handle)) = file.handle.wait() {
+        handle
+            .set_len(0)
+            .and_then(|_| handle.seek(SeekFrom::Start(0)))
+            .map_err(|error| {
+                CaesarError::io(format!("Failed to reset file '{}'", file.path), error)
+            })?;
+    }
+    file.offset = 0;
+    file.length = 0;
+    file.progress = 0;
+    file.damaged = false;
+    file.attempts += 1;
+    // The sender resends the file as it is
+    file.basis = None;
+
+    notify!(
+        context.options,
+        "'{}' arrived broken, requesting it again (attempt {} of {}).",
+        file.name, file.attempts, MAX_ATTEMPTS
+    );
+    if let Some(events) = &context.options.events {
+        events.report(TransferEvent::Retry {
+            file: file.index as usize,
+            reason: "The file arrived broken.".into(),
+        });
+    }
+
+    context.sender.send_encrypted_packet(
+        &context.shared_key,
+        DESTINATION,
+        Value::Retry(RetryPacket { index: file.index }),
+    );
+    context.retries.push_back(position);
+
+    Ok(())
+}
+
+/// Handle the handshake packet.
+///
+/// This function is responsible for handling the handshake packet received from the sender.
+/// It performs the necessary verification and establishes the shared key between the sender and receiver.
+///
+/// # Arguments
+///
+/// * `context` - The receiver context.
+/// * `handshake` - The handshake packet received from the sender.
+///
+/// # Returns
+///
+/// A `Status` representing the result of the operation.
+fn on_handshake(context: &mut Context, handshake: HandshakePacket) -> Status {
+    // Check if the shared key is already established
+    if context.shared_key.is_some() {
+        return Status::Err(CaesarError::Handshake("Already performed handshake.".into()));
+    }
+
+    // Answer the sender's share with our own, blinded with the same secret
+    let pake = Spake2::new(Role::Receiver, &context.password);
+    let keys = match pake.finish(&handshake.public_key) {
+        Ok(keys) => keys,
+        Err(error) => return Status::Err(error),
+    };
+    let public_key = pake.message().to_vec();
+
+    // Use compression if the sender offered it
+    let compression = if handshake
+        .compressions()
+        .any(|compression| compression == Compression::Zstd)
+    {
+        Compression::Zstd
+    } else {
+        Compression::None
+    };
+
+    // Encrypt each direction with a key of its own, protect the routing byte, the ID of
+    // the transfer and the position of the chunks, and count the nonces so repeated
+    // packets are rejected. Every sender that covers the offer with the confirmation
+    // supports all three, so an offer without them is refused rather than falling back.
+    if !handshake
+        .key_schedules()
+        .any(|schedule| schedule == KeySchedule::Hkdf)
+    {
+        return Status::Err(CaesarError::Handshake(
+            "The sender did not offer a key for each direction.".into(),
+        ));
+    }
+    if !handshake.framings().any(|framing| framing == Framing::V3) {
+        return Status::Err(CaesarError::Handshake(
+            "The sender did not offer framing v3.".into(),
+        ));
+    }
+    if !handshake
+        .nonce_schemes()
+        .any(|scheme| scheme == NonceScheme::Counter)
+    {
+        return Status::Err(CaesarError::Handshake(
+            "The sender did not offer counter nonces.".into(),
+        ));
+    }
+    let cipher = choose_cipher(handshake.ciphers(), has_aes_instructions());
+
+    // Take several files at once if the sender offers it, but one after the other when
+    // they are written to stdout
+    let streams = match context.options.stdout {
+        true => 1,
+        false => handshake.streams.clamp(1, MAX_STREAMS),
+    };
+
+    // Create the handshake response packet, accepting heartbeats if they are offered
+    let mut handshake_response = HandshakeResponsePacket {
+        public_key,
+        signature: vec![],
+        compression: compression.into(),
+        framing: Framing::V3.into(),
+        heartbeat: handshake.heartbeat,
+        key_schedule: KeySchedule::Hkdf.into(),
+        cipher: cipher.into(),
+        nonce_scheme: NonceScheme::Counter.into(),
+        streams,
+    };
+
+    // Prove to the sender that we derived the same keys and saw the same offer. With a
+    // wrong secret the keys differ, and with an offer the relay changed the proof does,
+    // so the sender ends the transfer either way.
+    handshake_response.signature = keys.confirmation(
+        Role::Receiver,
+        &negotiation(&handshake, &handshake_response),
+    );
+
+    // Send the handshake response packet to the sender
+    context
+        .sender
+        .send_packet(DESTINATION, Value::HandshakeResponse(handshake_response));
+
+    // Establish the shared key and the compression
+    let shared_key = SessionKey::derive(&keys, Role::Receiver, cipher);
+    if handshake.heartbeat {
+        tokio::spawn(heartbeat::beat(
+            context.sender.clone(),
+            shared_key.clone(),
+            Arc::new(AtomicU8::new(DESTINATION)),
+        ));
+    }
+    context.shared_key = Some(shared_key);
+    context.compression = compression;
+    context.delta = handshake.delta;
+    context.interleaved = streams > 1;
+
+    // The user compares the code with the sender's before accepting the files
+    let code = keys.short_code();
+    notify!(
+        context.options,
+        "Verification code: {}. Compare it with the code the sender shows.",
+        code
+    );
+    if let Some(events) = &context.options.events {
+        events.report(TransferEvent::Verification {
+            code,
+            receiver: None,
+        });
+    }
+
+    Status::Continue()
+}
+
+/// Handle the text packet.
+///
+/// The text is printed, or saved as `snippet.txt` in the target directory if the
+/// options ask for it, under a new name if that file exists. The sender is told with
+/// a done packet and leaves the room once it got it.
+///
+/// # Arguments
+///
+/// * `filepath` - The directory the text is saved in.
+/// * `context` - The receiver context.
+/// * `text` - The text packet sent in place of the list.
+///
+/// # Returns
+///
+/// A `Status` representing the result of the operation.
+fn on_text(filepath: String, context: &mut Context, text: TextPacket) -> Status {
+    // Check if the shared key is established
+    if context.shared_key.is_none() {
+        return Status::Err("Invalid text packet: no shared key established".into());
+    }
+
+    if context.options.save_text {
+        if let Err(error) = fs::create_dir_all(&filepath) {
+            return Status::Err(CaesarError::io(
+                format!("Failed to create directory '{}'", filepath),
+                error,
+            ));
+        }
+
+        // Never replace a file, the text is easily sent again under another name
+        let mut path = format!("{}/{}", filepath, SNIPPET_NAME);
+        if Path::new(&path).exists() {
+            path = unique_file_path(&filepath, SNIPPET_NAME);
+        }
+        if let Err(error) = fs::write(&path, &text.text) {
+            return Status::Err(CaesarError::io(
+                format!("Failed to write '{}'", path),
+                error,
+            ));
+        }
+        notify!(context.options, "Saved the text to '{}'.", path);
+    } else if let Some(events) = &context.options.events {
+        events.report(TransferEvent::Text {
+            text: text.text.clone(),
+        });
+    } else if context.options.stdout {
+        // The text is the output, so it is written without blank lines around it
+        println!("{}", text.text);
+    } else {
+        println!();
+        println!("{}", text.text);
+        println!();
+    }
+
+    context.text = Some(text.text);
+    context.sender.send_encrypted_packet(
+        &context.shared_key,
+        DESTINATION,
+        Value::Done(DonePacket {}),
+    );
+
+    Status::Continue()
+}
+
+/// Handle the done packet.
+///
+/// The sender of a shared room stays for the other receivers, so it tells each
+/// receiver that has every file with this packet instead of leaving the room.
+///
+/// # Arguments
+///
+/// * `context` - The receiver context.
+///
+/// # Returns
+///
+/// `Status::Exit` if every file is complete, an error otherwise.
+fn on_done(context: &Context) -> Status {
+    if context.files.iter().all(|file| file.progress == 100) {
+        Status::Exit()
+    } else {
+        Status::Err("The sender ended the transfer before every file was received.".into())
+    }
+}
+
+/// Handle a message that cannot be decrypted.
+///
+/// # Arguments
+///
+/// * `context` - The receiver context.
+/// * `plain` - The message, if the sender may have sent it in plain text.
+/// * `error` - The error decrypting the message.
+///
+/// # Returns
+///
+/// A `Status` representing the result of the operation.
+fn on_unreadable(context: &Context, plain: Option<Vec<u8>>, error: CaesarError) -> Status {
+    // A sender with several receivers turns the one with a wrong code away, as it has
+    // no key in common with it
+    if let Some(Value::Error(_)) = plain.and_then(|data| decode_packet(&None, DESTINATION, None, data).ok())
+    {
+        notify!(context.options);
+        notify!(
+            context.options,
+            "The sender did not accept the handshake. Check the code and ask for a new one."
+        );
+
+        return Status::Err(CaesarError::WrongCode(
+            "The sender did not accept the handshake.".into(),
+        ));
+    }
+
+    // The packet was meant for another receiver of the room
+    if context.shared {
+        debug!("Skipping a packet for another receiver: {error}");
+        return Status::Continue();
+    }
+
+    Status::Err(error)
+}
+
+/// Handle a message received from the WebSocket connection.
+///
+/// This function takes a `filepath` string, a mutable reference to a `Context` struct,
+/// and a `WebSocketMessage` enum. It returns a `Status` enum.
+///
+/// # Arguments
+///
+/// * `filepath` - A string representing the file path.
+/// * `context` - A mutable reference to a `Context` struct.
+/// * `message` - A `WebSocketMessage` enum.
+///
+/// # Returns
+///
+/// A `Status` enum.
+fn on_message(filepath: String, context: &mut Context, message: WebSocketMessage) -> Status {
+    // Handle text messages
+    match message {
+        WebSocketMessage::Text(text) => {
+            // Parse the JSON packet
+            let packet = match serde_json::from_str(&text) {
+                Ok(packet) => packet,
+                Err(_) => {
+                    return Status::Continue();
+                }
+            };
+            // Handle different types of JSON packets
+            return match packet {
+                JsonPacketResponse::Join { size, .. } => on_join_room(context, size),
+                JsonPacketResponse::Leave { index } => on_leave_room(context, index),
+                JsonPacketResponse::Error { message } => on_error(message),
+                _ => Status::Err(format!("Unexpected json packet: {:?}", packet).into()),
+            };
+        }
+        // Handle binary messages
+        WebSocketMessage::Binary(data) => {
+            // Until the list arrives, the sender may turn us away in plain text
+            let plain = (context.shared_key.is_some() && context.files.is_empty())
+                .then(|| data.clone());
+
+            // Decrypt and decode the packet, a chunk at the position of the next one
+            let position = context
+                .files
+                .get(context.index)
+                .map(|file| match context.interleaved {
+                    true => (INTERLEAVED, context.sequence),
+                    false => (file.index, context.sequence),
+                });
+            let value = match decode_packet(&context.shared_key, DESTINATION, position, data) {
+                Ok(value) => value,
+                Err(error) => return on_unreadable(context, plain, error),
+            };
+            // Handle different types of packets
+            return match value {
+                Value::List(list) => on_list(filepath, context, list),
+                Value::Text(text) => on_text(filepath, context, text),
+                Value::Chunk(chunk) => on_chunk(context, chunk),
+                Value::Handshake(handshake) => on_handshake(context, handshake),
+                Value::Error(error) => on_error(error.message),
+                Value::Done(_) => on_done(context),
+                // Heartbeats only count as activity
+                Value::Heartbeat(_) => Status::Continue(),
+                _ => Status::Err(format!("Unexpected packet: {:?}", value).into()),
+            };
+        }
+        // Keep-alive probes need no handling
+        WebSocketMessage::Ping(_) | WebSocketMessage::Pong(_) => return Status::Continue(),
+        _ => (),
+    }
+
+    // Return an error status for invalid message types
+    Status::Err("Invalid message type".into())
+}
+
+/// Starts the receiver's client.
+///
+/// This function takes in a file path, a socket, and a fragment string, which is
+/// the ID of the room. The handshake is authenticated with the key of the options.
+///
+/// The function initializes a `Context` struct with the extracted information
+/// and sets up the necessary communication channels. It then sends a join
+/// request to the server and starts handling incoming messages.
+///
+/// # Arguments
+///
+/// * `filepath` - The path to the file to be received.
+/// * `socket` - The WebSocket connection to the server.
+/// * `fragment` - The invite code containing the room ID.
+/// * `options` - The options of the transfer.
+///
+/// # Returns
+///
+/// The summary of the transfer once every file has been received, or the error that
+/// ended it.
+pub async fn start(
+    filepath: String,
+    socket: impl Transport,
+    fragment: &str,
+    options: ReceiverOptions,
+) -> Result<TransferSummary> {
+    start_session(filepath, socket, fragment, options, &mut Recovery::default()).await
+}
+
+/// Starts a session of the receiver's client that continues an earlier session.
+///
+/// This works like `start`, but continues the files an earlier session of the same
+/// transfer saved. Once the session has ended, `recovery` describes the files of this
+/// session for the next one.
+///
+/// # Arguments
+///
+/// * `filepath` - The path to the file to be received.
+/// * `socket` - The WebSocket connection to the server.
+/// * `fragment` - The invite code containing the room ID.
+/// * `options` - The options of the transfer.
+/// * `recovery` - The files of the earlier sessions.
+///
+/// # Returns
+///
+/// The summary of the transfer once every file has been received, or the error that
+/// ended the session.
+pub async fn start_session(
+    filepath: String,
+    socket: impl Transport,
+    fragment: &str,
+    options: ReceiverOptions,
+    recovery: &mut Recovery,
+) -> Result<TransferSummary> {
+    let id = fragment;
+    if id.is_empty() {
+        notify!(
+            options,
+            "Error: The invite code '{}' is not valid.",
+            fragment
+        );
+        return Err(CaesarError::InvalidInviteCode(fragment.to_string()));
+    }
+
+    // The secret is never part of the invite code
+    let Some(password) = options.key.clone() else {
+        error!("Error: No PIN or key to authenticate the handshake with.");
+        return Err(CaesarError::InvalidInviteCode(fragment.to_string()));
+    };
+
+    let (sender, receiver) = flume::bounded(OUTGOING_QUEUE);
+
+    let (mut outgoing, incoming) = socket.split();
+    let cancel = options.cancel.clone();
+    let idle = options.idle;
+    let reconnect = options.reconnect > 0;
+    let activity = Activity::new();
+    let keep_alive = sender.clone();
+
+    let mut context = Context {
+        password,
+        sender,
+
+        shared_key: None,
+        files: vec![],
+
+        index: 0,
+        sequence: 0,
+        compression: Compression::None,
+        interleaved: false,
+        delta: false,
+        options,
+        pending_credits: 0,
+        retries: VecDeque::new(),
+        recovered: recovery.paths.clone(),
+        writer: ChunkWriter::default(),
+        shared: false,
+        text: None,
+        received: 0,
+        started: Instant::now(),
+        throughput: Throughput::default(),
+    };
+
+    notify!(context.options, "Attempting to join room '{}'...", id);
+
+    context
+        .sender
+        .send_json_packet(JsonPacket::Join { id: id.to_string() });
+
+    // The result once the transfer has ended on its own
+    let mut outcome = None;
+
+    let interruption = {
+        let outgoing_handler = receiver.stream().map(Ok).forward(&mut outgoing);
+        let incoming_handler = incoming.try_for_each(|message| {
+            if let WebSocketMessage::Binary(_) = message {
+                activity.touch();
+            }
+
+            match on_message(filepath.clone(), &mut context, message) {
+                Status::Exit() => {
+                    context.sender.send_json_packet(JsonPacket::Leave);
+                    notify!(context.options, "Transfer has completed.");
+
+                    outcome = Some(Ok(summarize(&context)));
+                    return future::err(Error::ConnectionClosed);
+                }
+                Status::Err(error) => {
+                    notify!(context.options, "Error: {}", error);
+
+                    outcome = Some(Err(error));
+                    return future::err(Error::ConnectionClosed);
+                }
+                _ => {}
+            };
+
+            future::ok(())
+        });
+
+        pin_mut!(incoming_handler, outgoing_handler);
+
+        // Wait for the handlers to complete, the transfer to be cancelled, to stall or
+        // for the system to wake up from a suspend
+        tokio::select! {
+            _ = incoming_handler => None,
+            _ = outgoing_handler => None,
+            _ = cancel.cancelled() => Some(Interruption::Cancelled),
+            _ = idle::watch(&activity, idle, &keep_alive) => Some(Interruption::IdleTimeout),
+            gap = suspend::watch(SUSPEND_THRESHOLD), if reconnect => {
+                Some(Interruption::Suspended(gap))
+            }
+        }
+    };
+
+    // A session that ended on its own may have queued the leave or the reason for an
+    // error after the last message went out, which the sender still has to get
+    if outcome.is_some() {
+        while let Ok(message) = receiver.try_recv() {
+            if outgoing.send(message).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    // Keep the chunks of an unfinished batch, so the next session continues after them
+    write_pending(&mut context);
+
+    // Remember the files a new session can continue. A file of full length that did
+    // not pass the integrity check is not continued, nor are streams and stdout.
+    recovery.paths.extend(
+        context
+            .files
+            .iter()
+            .filter(|file| match file.handle.get() {
+                Some(Sink::File(handle)) if !file.stream => {
+                    file.progress == 100
+                        || handle
+                            .metadata()
+                            .is_ok_and(|metadata| metadata.len() < file.size)
+                }
+                _ => false,
+            })
+            .map(|file| (file.index, file.path.clone())),
+    );
+
+    // The sender leaves as soon as every file is complete. Over its local server, which
+    // also carries direct connections, the connection may close before the leave
+    // arrives, which still ends a complete transfer.
+    if outcome.is_none()
+        && interruption.is_none()
+        && !context.files.is_empty()
+        && context.files.iter().all(|file| file.progress == 100)
+    {
+        notify!(context.options, "Transfer has completed.");
+
+        outcome = Some(Ok(summarize(&context)));
+    }
+
+    let Some(interruption) = interruption else {
+        return outcome.unwrap_or_else(|| {
+            Err(CaesarError::Connection(
+                "The connection to the relay was closed.".into(),
+            ))
+        });
+    };
+
+    // Leave the room so the sender stops sending chunks
+    let leave = serde_json::to_string(&JsonPacket::Leave).unwrap();
+    let _ = outgoing.send(WebSocketMessage::Text(leave)).await;
+    let _ = outgoing.close().await;
+
+    match interruption {
+        Interruption::Cancelled => {
+            remove_partial_files(&mut context);
+            notify!(context.options, "Transfer was cancelled.");
+
+            Err(CaesarError::Cancelled)
+        }
+        Interruption::IdleTimeout => {
+            // The partial files stay on disk and are resumed by the next transfer
+            notify!(context.options);
+            notify!(
+                context.options,
+                "Error: The sender did not respond for {} seconds, closing the transfer. Receive the files again to resume.",
+                idle.timeout.as_secs()
+            );
+
+            Err(CaesarError::IdleTimeout(idle.timeout.as_secs()))
+        }
+        Interruption::Suspended(gap) => {
+            // The partial files stay on disk and are continued by the next session
+            notify!(context.options);
+            notify!(
+                context.options,
+                "The system was suspended for {} seconds.",
+                gap.as_secs()
+            );
+
+            Err(CaesarError::Suspended(gap.as_secs()))
+        }
+    }
+}
+
+/// Sums up how much was received and how fast, once the transfer has completed.
+///
+/// # Arguments
+///
+/// * `context` - The receiver context.
+///
+/// # Returns
+///
+/// The summary of the transfer. The caller knows the path the files took.
+fn summarize(context: &Context) -> TransferSummary {
+    if let Some(events) = &context.options.events {
+        events.report(TransferEvent::Complete {
+            files: context.files.len(),
+            bytes: context.files.iter().map(|file| file.size).sum(),
+        });
+    }
+
+    TransferSummary {
+        files: context.files.len(),
+        bytes: context.received,
+        elapsed: context.started.elapsed(),
+        path: None,
+    }
+}
+
+/// Writes the chunks of the current file that were received since the last batch, and
+/// waits until the batches of every file are written.
+///
+/// # Arguments
+///
+/// * `context` - The receiver context.
+fn write_pending(context: &mut Context) {
+    if let Some(file) = context.files.get_mut(context.index) {
+        if let Err(error) = context.writer.write_to(&mut file.handle, &mut file.hasher) {
+            error!("Error: Failed to write file '{}': {}", file.path, error);
+            file.damaged = true;
+        }
+    }
+
+    // Wait until every file is on disk
+    for file in &mut context.files {
+        if let Err(error) = file.handle.wait() {
+            error!("Error: Failed to write file '{}': {}", file.path, error);
+            file.damaged = true;
+        }
+    }
+}
+
+/// Removes the files that have not been received completely.
+///
+/// A file is complete once all of its bytes arrived and passed the integrity check,
+/// every other file is removed.
+///
+/// # Arguments
+///
+/// * `context` - The receiver context.
+fn remove_partial_files(context: &mut Context) {
+    let (complete, partial): (Vec<File>, Vec<File>) = std::mem::take(&mut context.files)
+        .into_iter()
+        .partition(|file| file.progress == 100);
+    context.files = complete;
+
+    for file in partial {
+        // Close the handle before removing the file, nothing is removed from stdout
+        let removed = match file.handle.into_inner() {
+            Some(Sink::File(handle)) => {
+                drop(handle);
+                fs::remove_file(part_path(&file.path))
+            }
+            // A directory that existed before keeps what was unpacked into it
+            Some(Sink::Archive(mut unpacker)) => {
+                let _ = unpacker.finish();
+                if !unpacker.created {
+                    continue;
+                }
+                fs::remove_dir_all(&file.path)
+            }
+            Some(Sink::Stdout(_)) => continue,
+            None => fs::remove_file(part_path(&file.path)),
+        };
+
+        if let Err(error) = removed {
+            error!("Error: Failed to remove partial file '{}': {}", file.path, error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::receiver::select::{Confirmation, FileSelection};
+    use std::sync::Mutex;
+    use aes_gcm::{Aes128Gcm, Key, KeyInit};
+    use tokio_tungstenite::tungstenite::Message as WebSocketMessage;
+
+    /// Builds the state of a receiver that has not joined a room yet.
+    fn test_context(sender: Sender) -> Context {
+        Context {
+            password: vec![],
+            sender,
+            shared_key: None,
+            files: vec![],
+            sequence: 0,
+            index: 0,
+            compression: Compression::None,
+            interleaved: false,
+            delta: false,
+            options: ReceiverOptions::default(),
+            pending_credits: 0,
+            retries: VecDeque::new(),
+            recovered: HashMap::new(),
+            writer: ChunkWriter::default(),
+            shared: false,
+            text: None,
+            received: 0,
+            started: Instant::now(),
+            throughput: Throughput::default(),
+        }
+    }
+
+    /// Builds the key the tests encrypt their packets with.
+    fn test_key() -> SessionKey {
+        Aes128Gcm::new(Key::<Aes128Gcm>::from_slice(&[0u8; 16])).into()
+    }
+
+    /// Builds an entry of a list packet for a plain file.
+    fn test_entry(index: u32, name: &str, size: u64) -> list_packet::Entry {
+        list_packet::Entry {
+            index,
+            size,
+            name: name.to_string(),
+            hash: vec![],
+            stream: false,
+            archive: false,
+            modified: 0,
+            link: String::new(),
+        }
+    }
+
+    /// Builds an entry of a list packet for a link to another file.
+    fn test_link(index: u32, name: &str, link: &str) -> list_packet::Entry {
+        list_packet::Entry {
+            link: link.to_string(),
+            ..test_entry(index, name, 0)
+        }
+    }
+
+    #[test]
+    fn test_on_join_room() {
+        let (sender, _) = flume::bounded(1000);
+        let mut context = test_context(sender);
+        assert_eq!(on_join_room(&mut context, Some(1)), Status::Continue());
+        assert!(!context.shared);
+
+        // Another receiver joins a shared room
+        assert_eq!(on_join_room(&mut context, None), Status::Continue());
+        assert!(context.shared);
+        assert_eq!(on_leave_room(&mut context, 2), Status::Continue());
+    }
+    #[test]
+    fn test_on_error_with_message() {
+        assert_eq!(
+            on_error("Error message".to_string()),
+            Status::Err("Error message".into())
+        );
+    }
+    #[test]
+    fn test_on_leave_room() {
+        let (sender, _) = flume::bounded(1000);
+        let mut context = Context {
+            files: vec![
+                File {
+                    index: 0,
+                    name: "file1.txt".to_string(),
+                    path: "file1.txt".to_string(),
+                    size: 100,
+                    progress: 100,
+                    offset: 0,
+                    length: 0,
+                    hash: vec![],
+                    hasher: FileHasher::new(HashAlgorithm::Sha256),
+                    damaged: false,
+                    attempts: 1,
+                    handle: fs::File::create("file1.txt").unwrap().into(),
+                    stream: false,
+                    modified: None,
+                    basis: None,
+                },
+                File {
+                    index: 1,
+                    name: "file2.txt".to_string(),
+                    path: "file2.txt".to_string(),
+                    size: 100,
+                    progress: 50,
+                    offset: 0,
+                    length: 0,
+                    hash: vec![],
+                    hasher: FileHasher::new(HashAlgorithm::Sha256),
+                    damaged: false,
+                    attempts: 1,
+                    handle: fs::File::create("file2.txt").unwrap().into(),
+                    stream: false,
+                    modified: None,
+                    basis: None,
+                },
+            ],
+            ..test_context(sender)
+        };
+
+        assert_eq!(
+            on_leave_room(&mut context, 0),
+            Status::Err("Transfer was interrupted because the host left the room.".into())
+        );
+        context.files[1].progress = 100;
+        assert_eq!(on_leave_room(&mut context, 0), Status::Exit());
+    }
+    #[test]
+    fn test_on_message_turned_away() {
+        let (sender, _) = flume::bounded(1000);
+        let key = Aes128Gcm::new(Key::<Aes128Gcm>::from_slice(&[0u8; 16]));
+        let mut context = Context {
+            shared_key: Some(SessionKey::new(key, Framing::V2)),
+            shared: true,
+            ..test_context(sender)
+        };
+
+        // The sender of a shared room has no key in common with a wrong guess
+        let (peer, messages) = flume::bounded(1);
+        peer.send_packet(
+            1,
+            Value::Error(ErrorPacket {
+                message: "The receiver does not know the PIN.".to_string(),
+            }),
+        );
+        let mut data = messages.recv().unwrap().into_data();
+        data[0] = 0;
+        assert_eq!(
+            on_message("".to_string(), &mut context, WebSocketMessage::Binary(data)),
+            Status::Err(CaesarError::WrongCode(
+                "The sender did not accept the handshake.".into()
+            ))
+        );
+
+        // Packets for other receivers are skipped
+        assert_eq!(
+            on_message(
+                "".to_string(),
+                &mut context,
+                WebSocketMessage::Binary(vec![0; 64])
+            ),
+            Status::Continue()
+        );
+    }
+    #[test]
+    fn test_on_message_text_join() {
+        let (sender, _) = flume::bounded(1000);
+        let mut context = test_context(sender);
+
+        let text_message = WebSocketMessage::Text(r#"{"type":"join","size":10}"#.to_string());
+        assert_eq!(
+            on_message("".to_string(), &mut context, text_message),
+            Status::Continue()
+        );
+    }
+
+    #[test]
+    fn test_remove_partial_files() {
+        fs::create_dir_all("partial_test").unwrap();
+        let file = |index: u32, name: &str, progress: u64| File {
+            index,
+            name: name.to_string(),
+            path: format!("partial_test/{}", name),
+            size: 100,
+            progress,
+            offset: 0,
+            length: 0,
+            hash: vec![],
+            hasher: FileHasher::new(HashAlgorithm::Sha256),
+            damaged: false,
+            attempts: 1,
+            handle: fs::File::create(part_path(&format!("partial_test/{}", name)))
+                .unwrap()
+                .into(),
+            stream: false,
+            modified: None,
+            basis: None,
+        };
+
+        let (sender, _) = flume::bounded(1000);
+        let mut context = Context {
+            files: vec![
+                file(0, "done.txt", 100),
+                file(1, "partial.txt", 40),
+                file(2, "next.txt", 0),
+            ],
+            index: 1,
+            ..test_context(sender)
+        };
+        remove_partial_files(&mut context);
+
+        assert!(Path::new("partial_test/done.txt.caesar.part").exists());
+        assert!(!Path::new("partial_test/partial.txt.caesar.part").exists());
+        assert!(!Path::new("partial_test/next.txt.caesar.part").exists());
+        assert_eq!(context.files.len(), 1);
+
+        fs::remove_dir_all("partial_test").unwrap();
+    }
+
+    #[test]
+    fn test_unique_file_path() {
+        fs::create_dir_all("unique_test").unwrap();
+        fs::write("unique_test/report.txt", b"").unwrap();
+        fs::write("unique_test/report (1).txt", b"").unwrap();
+
+        assert_eq!(
+            unique_file_path("unique_test", "report.txt"),
+            "unique_test/report (2).txt"
+        );
+        assert_eq!(
+            unique_file_path("unique_test", "archive"),
+            "unique_test/archive (1)"
+        );
+
+        fs::remove_dir_all("unique_test").unwrap();
+    }
+
+    #[test]
+    fn test_on_chunk() {
+        let (sender, _) = flume::bounded(1000);
+        let mut context = Context {
+            files: vec![File {
+                index: 0,
+                name: "file1.txt".to_string(),
+                path: "file1.txt".to_string(),
+                size: 100,
+                progress: 0,
+                offset: 0,
+                length: 0,
+                hash: vec![],
+                hasher: FileHasher::new(HashAlgorithm::Sha256),
+                damaged: false,
+                attempts: 1,
+                handle: fs::File::create("file1.txt").unwrap().into(),
+                stream: false,
+                modified: None,
+                basis: None,
+            }],
+            ..test_context(sender)
+        };
+        let chunk_packet = ChunkPacket {
+            sequence: 0,
+            chunk: Bytes::from_static(b"Hello, world!"),
+            index: 0,
+        };
+        assert_eq!(
+            on_chunk(&mut context, chunk_packet),
+            Status::Err("Invalid chunk packet: no shared key established".into())
+        );
+    }
+
+    #[test]
+    fn test_on_chunk_requests_retry() {
+        let (sender, _receiver) = flume::bounded(1000);
+        let mut context = Context {
+            shared_key: Some(test_key()),
+            files: vec![File {
+                index: 0,
+                name: "retry.txt".to_string(),
+                path: "retry.txt".to_string(),
+                size: 5,
+                progress: 0,
+                offset: 0,
+                length: 0,
+                hash: vec![0u8; 32],
+                hasher: FileHasher::new(HashAlgorithm::Sha256),
+                damaged: false,
+                attempts: 1,
+                handle: fs::File::create("retry.txt").unwrap().into(),
+                stream: false,
+                modified: None,
+                basis: None,
+            }],
+            ..test_context(sender)
+        };
+        let chunk = || ChunkPacket {
+            sequence: 0,
+            chunk: Bytes::from_static(b"hello"),
+            index: 0,
+        };
+
+        // A broken file is truncated and expected again
+        assert_eq!(on_chunk(&mut context, chunk()), Status::Continue());
+        assert_eq!(fs::metadata("retry.txt").unwrap().len(), 0);
+        assert_eq!(context.files[0].attempts, 2);
+        assert_eq!(context.files[0].progress, 0);
+        assert_eq!(context.index, 0);
+        assert_eq!(context.files[0].length, 0);
+
+        // The transfer fails once every attempt is used up
+        assert_eq!(on_chunk(&mut context, chunk()), Status::Continue());
+        assert_eq!(
+            on_chunk(&mut context, chunk()),
+            Status::Err("Integrity check failed for file 'retry.txt'.".into())
+        );
+
+        fs::remove_file("retry.txt").unwrap();
+    }
+
+    #[test]
+    fn test_on_chunk_stream() {
+        let (sender, _receiver) = flume::bounded(1000);
+        let mut context = Context {
+            shared_key: Some(test_key()),
+            files: vec![File {
+                index: 0,
+                name: "stdin".to_string(),
+                path: "stream.txt".to_string(),
+                size: 0,
+                progress: 0,
+                offset: 0,
+                length: 0,
+                hash: vec![],
+                hasher: FileHasher::new(HashAlgorithm::Sha256),
+                damaged: false,
+                attempts: 1,
+                handle: fs::File::create(part_path("stream.txt")).unwrap().into(),
+                stream: true,
+                modified: None,
+                basis: None,
+            }],
+            ..test_context(sender)
+        };
+        let chunk = |sequence: u32, chunk: &'static [u8]| ChunkPacket {
+            sequence,
+            chunk: Bytes::from_static(chunk),
+            index: 0,
+        };
+
+        // A stream goes on until the empty chunk, whatever its size
+        assert_eq!(
+            on_chunk(&mut context, chunk(0, b"hello")),
+            Status::Continue()
+        );
+        assert_eq!(
+            on_chunk(&mut context, chunk(1, b" world")),
+            Status::Continue()
+        );
+        assert_eq!(context.files[0].progress, 0);
+        assert!(!Path::new("stream.txt").exists());
+        assert_eq!(on_chunk(&mut context, chunk(2, b"")), Status::Continue());
+        assert_eq!(context.files[0].progress, 100);
+        assert_eq!(context.index, 1);
+
+        // The complete stream gets its name
+        assert_eq!(fs::read("stream.txt").unwrap(), b"hello world");
+        assert!(!Path::new("stream.txt.caesar.part").exists());
+
+        fs::remove_file("stream.txt").unwrap();
+    }
+
+    #[test]
+    fn test_on_chunk_empty_file() {
+        let (sender, _receiver) = flume::bounded(1000);
+        let file = |index: u32, name: &str| File {
+            index,
+            name: name.to_string(),
+            path: name.to_string(),
+            size: 0,
+            progress: 0,
+            offset: 0,
+            length: 0,
+            hash: vec![],
+            hasher: FileHasher::new(HashAlgorithm::Sha256),
+            damaged: false,
+            attempts: 1,
+            handle: fs::File::create(part_path(name)).unwrap().into(),
+            stream: false,
+            modified: None,
+            basis: None,
+        };
+        let mut context = Context {
+            shared_key: Some(test_key()),
+            files: vec![file(0, "empty.txt"), file(1, "overlong.txt")],
+            ..test_context(sender)
+        };
+        let chunk = |chunk: &'static [u8]| ChunkPacket {
+            sequence: 0,
+            chunk: Bytes::from_static(chunk),
+            index: 0,
+        };
+
+        // An empty file is complete with its first chunk
+        assert_eq!(on_chunk(&mut context, chunk(b"")), Status::Continue());
+        assert_eq!(context.files[0].progress, 100);
+        assert_eq!(context.index, 1);
+        assert_eq!(fs::read("empty.txt").unwrap(), b"");
+
+        // A sender that sends data for an empty file does not crash the receiver
+        assert_eq!(on_chunk(&mut context, chunk(b"junk")), Status::Continue());
+        assert_eq!(context.files[1].progress, 100);
+
+        fs::remove_file("empty.txt").unwrap();
+        fs::remove_file("overlong.txt.caesar.part").unwrap();
+    }
+
+    #[test]
+    fn test_on_chunk_interleaved() {
+        let (sender, _receiver) = flume::bounded(1000);
+        let file = |index: u32, name: &str| File {
+            index,
+            name: name.to_string(),
+            path: name.to_string(),
+            size: 10,
+            progress: 0,
+            offset: 0,
+            length: 0,
+            hash: vec![],
+            hasher: FileHasher::new(HashAlgorithm::Sha256),
+            damaged: false,
+            attempts: 1,
+            handle: fs::File::create(part_path(name)).unwrap().into(),
+            stream: false,
+            modified: None,
+            basis: None,
+        };
+        let mut context = Context {
+            shared_key: Some(test_key()),
+            files: vec![file(0, "interleaved1.txt"), file(2, "interleaved2.txt")],
+            interleaved: true,
+            ..test_context(sender)
+        };
+        let chunk = |sequence: u32, index: u32, chunk: &'static [u8]| ChunkPacket {
+            sequence,
+            chunk: Bytes::from_static(chunk),
+            index,
+        };
+
+        // The chunks of both files arrive in turns and are told apart by their index
+        let chunks = [
+            chunk(0, 2, b"hello"),
+            chunk(1, 0, b"01234"),
+            chunk(2, 0, b"56789"),
+            chunk(3, 2, b"world"),
+        ];
+        for chunk in chunks {
+            assert_eq!(on_chunk(&mut context, chunk), Status::Continue());
+        }
+        assert_eq!(context.files[0].progress, 100);
+        assert_eq!(context.files[1].progress, 100);
+        assert_eq!(fs::read("interleaved1.txt").unwrap(), b"0123456789");
+        assert_eq!(fs::read("interleaved2.txt").unwrap(), b"helloworld");
+
+        // A chunk of a file that is not in the list is rejected
+        assert_eq!(
+            on_chunk(&mut context, chunk(4, 1, b"x")),
+            Status::Err("Invalid file index.".into())
+        );
+
+        fs::remove_file("interleaved1.txt").unwrap();
+        fs::remove_file("interleaved2.txt").unwrap();
+    }
+
+    #[test]
+    fn test_on_chunk_preserves_times() {
+        let (sender, _receiver) = flume::bounded(1000);
+        let modified = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let mut context = Context {
+            shared_key: Some(test_key()),
+            files: vec![File {
+                index: 0,
+                name: "times.txt".to_string(),
+                path: "times.txt".to_string(),
+                size: 5,
+                progress: 0,
+                offset: 0,
+                length: 0,
+                hash: vec![],
+                hasher: FileHasher::new(HashAlgorithm::Sha256),
+                damaged: false,
+                attempts: 1,
+                handle: fs::File::create(part_path("times.txt")).unwrap().into(),
+                stream: false,
+                modified: Some(modified),
+                basis: None,
+            }],
+            options: ReceiverOptions {
+                preserve_times: true,
+                ..Default::default()
+            },
+            ..test_context(sender)
+        };
+        let chunk = ChunkPacket {
+            sequence: 0,
+            chunk: Bytes::from_static(b"hello"),
+            index: 0,
+        };
 
-    // Generate the signature using the HMAC
-    let signature = mac.finalize().into_bytes().to_vec();
+        // The complete file gets the time it had on the sender's side
+        assert_eq!(on_chunk(&mut context, chunk), Status::Continue());
+        assert_eq!(fs::read("times.txt").unwrap(), b"hello");
+        assert_eq!(
+            fs::metadata("times.txt").unwrap().modified().unwrap(),
+            modified
+        );
 
-    // Convert the sender's public key into a `PublicKey` object
-    let shared_public_key = PublicKey::from_sec1_bytes(&handshake.public_key).unwrap();
+        fs::remove_file("times.txt").unwrap();
+    }
 
-    // Perform Diffie-Hellman key exchange
-    let shared_secret = context.key.diffie_hellman(&shared_public_key);
-    let shared_secret = shared_secret.raw_secret_bytes();
-    let shared_secret = &shared_secret[0..16];
+    #[test]
+    fn test_unpacker() {
+        // An archive of a directory with a nested file
+        let mut builder = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "./nested/hello.txt", &b"hello"[..])
+            .unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        let directory = Path::new("unpack_test");
+        let mut unpacker = Unpacker::new(directory).unwrap();
+        assert!(unpacker.created);
+        // The archive arrives in chunks of any size
+        for chunk in archive.chunks(700) {
+            unpacker.write_all(chunk).unwrap();
+        }
+        unpacker.finish().unwrap();
+        assert_eq!(
+            fs::read(directory.join("nested/hello.txt")).unwrap(),
+            b"hello"
+        );
+        assert!(unpacker.write_all(b"late").is_err());
+        fs::remove_dir_all(directory).unwrap();
+
+        // A broken archive is reported once it ends
+        let mut unpacker = Unpacker::new(directory).unwrap();
+        unpacker.write_all(&[0xff; 512]).unwrap();
+        assert!(unpacker.finish().is_err());
+        fs::remove_dir_all(directory).unwrap();
+    }
 
-    // Create a new 128-bit AES-GCM key from the shared secret
-    let shared_key: &Key<Aes128Gcm> = shared_secret.into();
-    let shared_key = <Aes128Gcm as aes_gcm::KeyInit>::new(shared_key);
+    #[test]
+    fn test_on_list_continues_recovered_files() {
+        let directory = "recovered";
+        fs::create_dir_all(directory).unwrap();
+        fs::write(format!("{directory}/notes.txt"), b"other").unwrap();
+        fs::write(format!("{directory}/notes (1).txt.caesar.part"), b"hel").unwrap();
+        fs::write(format!("{directory}/done.txt"), b"done!").unwrap();
+
+        let (sender, _receiver) = flume::bounded(1000);
+        let mut context = Context {
+            shared_key: Some(test_key()),
+            options: ReceiverOptions {
+                on_conflict: ConflictPolicy::Rename,
+                ..Default::default()
+            },
+            recovered: HashMap::from([
+                (0, format!("{directory}/notes (1).txt")),
+                (1, format!("{directory}/done.txt")),
+            ]),
+            ..test_context(sender)
+        };
+        let list = ListPacket {
+            entries: vec![test_entry(0, "notes.txt", 5), test_entry(1, "done.txt", 5)],
+            hash_algorithm: HashAlgorithm::Sha256.into(),
+        };
 
-    // Create the handshake response packet
-    let handshake_response = HandshakeResponsePacket {
-        public_key,
-        signature,
-    };
+        // The renamed file is continued and the complete one is not received again
+        assert_eq!(
+            on_list(directory.to_string(), &mut context, list),
+            Status::Continue()
+        );
+        assert_eq!(context.files.len(), 1);
+        assert_eq!(context.files[0].path, format!("{directory}/notes (1).txt"));
+        assert_eq!(context.files[0].offset, 3);
+        assert_eq!(
+            fs::read(format!("{directory}/notes.txt")).unwrap(),
+            b"other"
+        );
 
-    // Send the handshake response packet to the sender
-    context
-        .sender
-        .send_packet(DESTINATION, Value::HandshakeResponse(handshake_response));
+        fs::remove_dir_all(directory).unwrap();
+    }
 
-    // Establish the shared key
-    context.shared_key = Some(shared_key);
+    #[test]
+    fn test_on_list_resumes_part_files() {
+        let directory = "part_test";
+        fs::create_dir_all(directory).unwrap();
+        fs::write(format!("{directory}/notes.txt.caesar.part"), b"hell").unwrap();
+        fs::write(format!("{directory}/report.txt.caesar.part"), b"he").unwrap();
 
-    Status::Continue()
-}
+        let (sender, _receiver) = flume::bounded(1000);
+        let mut context = Context {
+            shared_key: Some(test_key()),
+            ..test_context(sender)
+        };
+        let list = ListPacket {
+            entries: vec![
+                test_entry(0, "notes.txt", 5),
+                test_entry(1, "report.txt", 5),
+            ],
+            hash_algorithm: HashAlgorithm::Sha256.into(),
+        };
 
-/// Handle a message received from the WebSocket connection.
-///
-/// This function takes a `filepath` string, a mutable reference to a `Context` struct,
-/// and a `WebSocketMessage` enum. It returns a `Status` enum.
-///
-/// # Arguments
-///
-/// * `filepath` - A string representing the file path.
-/// * `context` - A mutable reference to a `Context` struct.
-/// * `message` - A `WebSocketMessage` enum.
-///
-/// # Returns
-///
-/// A `Status` enum.
-fn on_message(filepath: String, context: &mut Context, message: WebSocketMessage) -> Status {
-    // Handle text messages
-    match message.clone() {
-        WebSocketMessage::Text(text) => {
-            // Parse the JSON packet
-            let packet = match serde_json::from_str(&text) {
-                Ok(packet) => packet,
-                Err(_) => {
-                    return Status::Continue();
-                }
-            };
-            // Handle different types of JSON packets
-            return match packet {
-                JsonPacketResponse::Join { size } => on_join_room(size),
-                JsonPacketResponse::Leave { index } => on_leave_room(context, index),
-                JsonPacketResponse::Error { message } => on_error(message),
-                _ => Status::Err(format!("Unexpected json packet: {:?}", packet)),
-            };
-        }
-        // Handle binary messages
-        WebSocketMessage::Binary(data) => {
-            // Extract the data from the binary message
-            let data = &data[1..];
+        // Both files continue in their part files, nothing carries the final names yet
+        assert_eq!(
+            on_list(directory.to_string(), &mut context, list),
+            Status::Continue()
+        );
+        assert_eq!(context.files[0].offset, 4);
+        assert_eq!(context.files[1].offset, 2);
+        assert!(!Path::new(&format!("{directory}/notes.txt")).exists());
+        assert!(!Path::new(&format!("{directory}/report.txt")).exists());
+
+        // A smaller file under the final name is someone else's file, not a partial
+        // download, and is neither renamed nor continued
+        fs::write(format!("{directory}/older.txt"), b"he").unwrap();
+        context.files.clear();
+        let list = ListPacket {
+            entries: vec![test_entry(0, "older.txt", 5)],
+            hash_algorithm: HashAlgorithm::Sha256.into(),
+        };
+        assert!(matches!(
+            on_list(directory.to_string(), &mut context, list),
+            Status::Err(CaesarError::FileExists(_))
+        ));
+        assert_eq!(fs::read(format!("{directory}/older.txt")).unwrap(), b"he");
+        assert!(!Path::new(&format!("{directory}/older.txt.caesar.part")).exists());
 
-            let data = if let Some(shared_key) = &context.shared_key {
-                let nonce = &data[..NONCE_SIZE];
-                let ciphertext = &data[NONCE_SIZE..];
+        fs::remove_dir_all(directory).unwrap();
+    }
 
-                shared_key.decrypt(nonce.into(), ciphertext).unwrap()
-            } else {
-                data.to_vec()
-            };
+    #[test]
+    fn test_on_list_aborts_on_existing_files() {
+        let directory = "abort_test";
+        fs::create_dir_all(directory).unwrap();
 
-            // Decode the packet
-            let packet = Packet::decode(data.as_ref()).unwrap();
-            let value = packet.value.unwrap();
-            // Handle different types of packets
-            return match value {
-                Value::List(list) => on_list(filepath, context, list),
-                Value::Chunk(chunk) => on_chunk(context, chunk),
-                Value::Handshake(handshake) => on_handshake(context, handshake),
-                _ => Status::Err(format!("Unexpected packet: {:?}", value)),
+        let (sender, _receiver) = flume::bounded(1000);
+        let mut context = Context {
+            shared_key: Some(test_key()),
+            ..test_context(sender)
+        };
+        assert_eq!(context.options.on_conflict, ConflictPolicy::Abort);
+
+        // Smaller, equally large and larger files than the announced one are all kept
+        let existing: [(&str, &[u8]); 3] = [
+            ("smaller.txt", b"he"),
+            ("same.txt", b"other"),
+            ("larger.txt", b"hello world"),
+        ];
+        for (name, content) in existing {
+            let path = format!("{directory}/{name}");
+            fs::write(&path, content).unwrap();
+
+            let list = ListPacket {
+                entries: vec![crate::shared::packets::list_packet::Entry {
+                    index: 0,
+                    size: 5,
+                    name: name.to_string(),
+                    hash: vec![],
+                    stream: false,
+                    archive: false,
+                    modified: 0,
+                    link: String::new(),
+                }],
+                hash_algorithm: HashAlgorithm::Sha256.into(),
             };
+            assert!(matches!(
+                on_list(directory.to_string(), &mut context, list),
+                Status::Err(CaesarError::FileExists(_))
+            ));
+            assert!(context.files.is_empty());
+            assert_eq!(fs::read(&path).unwrap(), content);
+            assert!(!Path::new(&format!("{path}.caesar.part")).exists());
         }
-        _ => (),
-    }
-
-    // Return an error status for invalid message types
-    Status::Err("Invalid message type".into())
-}
-
- 
-/// Starts the receiver's client.
-///
-/// This function takes in a file path, a socket, and a fragment string. It
-/// then extracts the room ID and HMAC from the fragment string. The function
-/// also generates an ephemeral secret key.
-///
-/// The function initializes a `Context` struct with the extracted information
-/// and sets up the necessary communication channels. It then sends a join
-/// request to the server and starts handling incoming messages.
-///
-/// # Arguments
-///
-/// * `filepath` - The path to the file to be received.
-/// * `socket` - The WebSocket connection to the server.
-/// * `fragment` - The invite code containing the room ID and HMAC.
-pub async fn start(filepath: String, socket: Socket, fragment: &str) {
-    let Some(index) = fragment.rfind('-') else {
-        println!("Error: The invite code '{}' is not valid.", fragment);
-        return;
-    };
 
-    let id = &fragment[..index];
-    let hmac = &fragment[index + 1..];
-    let Ok(hmac) = general_purpose::STANDARD.decode(hmac) else {
-        error!("Error: Invalid base64 inside the invite code.");
-        return;
-    };
+        fs::remove_dir_all(directory).unwrap();
+    }
 
-    let key = EphemeralSecret::random(&mut OsRng);
+    #[test]
+    fn test_on_list_selects_files() {
+        let directory = "selected";
+        let (sender, _receiver) = flume::bounded(1000);
+        let mut context = Context {
+            shared_key: Some(test_key()),
+            options: ReceiverOptions {
+                select: Some(FileSelection::names(vec!["photos.zip".into()])),
+                ..Default::default()
+            },
+            ..test_context(sender)
+        };
+        let list = ListPacket {
+            entries: vec![
+                test_entry(0, "report.pdf", 5),
+                test_entry(1, "photos.zip", 5),
+            ],
+            hash_algorithm: HashAlgorithm::Sha256.into(),
+        };
 
-    let (sender, receiver) = flume::bounded(1000);
+        // Only the selected file is created and expected
+        assert_eq!(
+            on_list(directory.to_string(), &mut context, list.clone()),
+            Status::Continue()
+        );
+        assert_eq!(context.files.len(), 1);
+        assert_eq!(context.files[0].index, 1);
+        assert!(!Path::new(directory).join("report.pdf").exists());
+        fs::remove_dir_all(directory).unwrap();
+
+        // A selection that matches nothing ends the transfer
+        context.files.clear();
+        context.options.select = Some(FileSelection::names(vec!["missing.txt".into()]));
+        assert!(matches!(
+            on_list(directory.to_string(), &mut context, list),
+            Status::Err(_)
+        ));
+        let _ = fs::remove_dir_all(directory);
+    }
 
-    let (outgoing, incoming) = socket.split();
+    #[cfg(unix)]
+    #[test]
+    fn test_on_list_creates_links() {
+        let directory = "linked";
+        let (sender, _receiver) = flume::bounded(1000);
+        let mut context = Context {
+            shared_key: Some(test_key()),
+            options: ReceiverOptions {
+                links: true,
+                ..Default::default()
+            },
+            ..test_context(sender)
+        };
+        let list = ListPacket {
+            entries: vec![
+                test_entry(0, "notes.txt", 5),
+                test_link(1, "latest.txt", "notes.txt"),
+            ],
+            hash_algorithm: HashAlgorithm::Sha256.into(),
+        };
 
-    let mut context = Context {
-        hmac,
-        sender,
-        key,
+        // The link is created at once, only the file is expected
+        assert_eq!(
+            on_list(directory.to_string(), &mut context, list),
+            Status::Continue()
+        );
+        assert_eq!(context.files.len(), 1);
+        assert_eq!(context.files[0].index, 0);
+        assert_eq!(
+            fs::read_link(Path::new(directory).join("latest.txt")).unwrap(),
+            Path::new("notes.txt")
+        );
 
-        shared_key: None,
-        files: vec![],
+        fs::remove_dir_all(directory).unwrap();
+    }
 
-        index: 0,
-        sequence: 0,
-        progress: 0,
-        length: 0,
-    };
+    #[cfg(unix)]
+    #[test]
+    fn test_on_list_refuses_unsafe_links() {
+        let directory = "unsafe_links";
+        let outside = "unsafe_links_target.txt";
+        fs::create_dir_all(directory).unwrap();
+        fs::write(outside, b"keep").unwrap();
 
-    println!("Attempting to join room '{}'...", id);
+        let (sender, _receiver) = flume::bounded(1000);
+        let mut context = Context {
+            shared_key: Some(test_key()),
+            ..test_context(sender)
+        };
+        let list = |entries| ListPacket {
+            entries,
+            hash_algorithm: HashAlgorithm::Sha256.into(),
+        };
+        let target = fs::canonicalize(outside).unwrap();
 
-    context
-        .sender
-        .send_json_packet(JsonPacket::Join { id: id.to_string() });
+        // Links are only created when the receiver asks for them
+        let entries = vec![test_link(0, "plain.txt", "notes.txt")];
+        assert_eq!(
+            on_list(directory.to_string(), &mut context, list(entries)),
+            Status::Exit()
+        );
+        assert!(fs::symlink_metadata(format!("{directory}/plain.txt")).is_err());
+
+        // Links that lead outside the target directory are skipped
+        context.options.links = true;
+        let entries = vec![
+            test_link(0, "absolute.txt", target.to_str().unwrap()),
+            test_link(1, "parent.txt", "../unsafe_links_target.txt"),
+        ];
+        assert_eq!(
+            on_list(directory.to_string(), &mut context, list(entries)),
+            Status::Exit()
+        );
+        assert!(fs::symlink_metadata(format!("{directory}/absolute.txt")).is_err());
+        assert!(fs::symlink_metadata(format!("{directory}/parent.txt")).is_err());
 
-    let outgoing_handler = receiver.stream().map(Ok).forward(outgoing);
-    let incoming_handler = incoming.try_for_each(|message| {
-        match on_message(filepath.clone(), &mut context, message) {
-            Status::Exit() => {
-                context.sender.send_json_packet(JsonPacket::Leave);
-                println!("Transfer has completed.");
+        // So are links through a link the user keeps in the directory
+        std::os::unix::fs::symlink(fs::canonicalize(".").unwrap(), format!("{directory}/up"))
+            .unwrap();
+        let entries = vec![test_link(0, "through.txt", "up/unsafe_links_target.txt")];
+        assert_eq!(
+            on_list(directory.to_string(), &mut context, list(entries)),
+            Status::Exit()
+        );
+        assert!(fs::symlink_metadata(format!("{directory}/through.txt")).is_err());
+
+        // A link in the place of the part file of a later entry is refused with the list
+        let entries = vec![
+            test_link(0, "x.caesar.part", "notes.txt"),
+            test_entry(1, "x", 5),
+        ];
+        assert!(matches!(
+            on_list(directory.to_string(), &mut context, list(entries)),
+            Status::Err(_)
+        ));
+        let entries = vec![
+            test_link(0, "twice", "notes.txt"),
+            test_entry(1, "twice", 5),
+        ];
+        assert!(matches!(
+            on_list(directory.to_string(), &mut context, list(entries)),
+            Status::Err(_)
+        ));
+        assert!(context.files.is_empty());
+
+        // A part file that is a link is never written through
+        std::os::unix::fs::symlink(&target, format!("{directory}/y.caesar.part")).unwrap();
+        let entries = vec![test_entry(0, "y", 5)];
+        assert!(matches!(
+            on_list(directory.to_string(), &mut context, list(entries)),
+            Status::Err(_)
+        ));
+        assert_eq!(fs::read(outside).unwrap(), b"keep");
 
-                return future::err(Error::ConnectionClosed);
-            }
-            Status::Err(error) => {
-                println!("Error: {}", error);
+        fs::remove_dir_all(directory).unwrap();
+        fs::remove_file(outside).unwrap();
+    }
 
-                return future::err(Error::ConnectionClosed);
-            }
-            _ => {}
+    #[test]
+    fn test_on_list_asks_before_writing() {
+        let directory = "confirmed";
+        let (sender, receiver) = flume::bounded(1000);
+        let asked = Arc::new(Mutex::new(vec![]));
+        let record = asked.clone();
+        let mut context = Context {
+            shared_key: Some(test_key()),
+            options: ReceiverOptions {
+                select: Some(FileSelection::names(vec!["photos.zip".into()])),
+                confirm: Some(Confirmation::new(move |files| {
+                    record
+                        .lock()
+                        .unwrap()
+                        .extend(files.iter().map(|file| file.name.clone()));
+                    false
+                })),
+                ..Default::default()
+            },
+            ..test_context(sender)
+        };
+        let list = ListPacket {
+            entries: vec![
+                test_entry(0, "report.pdf", 5),
+                test_entry(1, "photos.zip", 5),
+            ],
+            hash_algorithm: HashAlgorithm::Sha256.into(),
         };
 
-        future::ok(())
-    });
+        // A declined transfer writes nothing and tells the sender
+        assert_eq!(
+            on_list(directory.to_string(), &mut context, list.clone()),
+            Status::Err("The transfer was declined.".into())
+        );
+        assert_eq!(*asked.lock().unwrap(), vec!["photos.zip".to_string()]);
+        assert!(!Path::new(directory).exists());
+        assert!(receiver.try_recv().is_ok());
 
-    pin_mut!(incoming_handler, outgoing_handler);
+        // An accepted transfer goes on as usual
+        context.options.confirm = Some(Confirmation::new(|_| true));
+        assert_eq!(
+            on_list(directory.to_string(), &mut context, list),
+            Status::Continue()
+        );
+        assert_eq!(context.files.len(), 1);
+        fs::remove_dir_all(directory).unwrap();
+    }
 
-    future::select(incoming_handler, outgoing_handler).await;
-}
+    #[test]
+    fn test_on_list_refuses_large_files() {
+        let directory = "limited";
+        let (sender, receiver) = flume::bounded(1000);
+        let mut context = Context {
+            shared_key: Some(test_key()),
+            options: ReceiverOptions {
+                max_file_size: Some(1024),
+                ..Default::default()
+            },
+            ..test_context(sender)
+        };
+        let list = ListPacket {
+            entries: vec![
+                test_entry(0, "notes.txt", 600),
+                test_entry(1, "video.mp4", 4096),
+            ],
+            hash_algorithm: HashAlgorithm::Sha256.into(),
+        };
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tokio_tungstenite::tungstenite::Message as WebSocketMessage;
+        // A file above the limit refuses the transfer before anything is written
+        assert_eq!(
+            on_list(directory.to_string(), &mut context, list.clone()),
+            Status::Err(
+                "The file 'video.mp4' has 4.0 KiB, but the receiver accepts at most 1.0 KiB per file."
+                    .into()
+            )
+        );
+        assert!(!Path::new(directory).exists());
+        assert!(receiver.try_recv().is_ok());
 
-    #[test]
-    fn test_on_join_room_valid_size() {
-        assert_eq!(on_join_room(Some(10)), Status::Continue());
-    }
-    #[test]
-    fn test_on_join_room_invalid_size() {
+        // Files that are not selected do not count
+        context.options.select = Some(FileSelection::names(vec!["notes.txt".into()]));
+        context.options.max_total_size = Some(500);
         assert_eq!(
-            on_join_room(None),
-            Status::Err("Invalid join room packet.".into())
+            on_list(directory.to_string(), &mut context, list.clone()),
+            Status::Err(
+                "The files have 600 B together, but the receiver accepts at most 500 B per transfer."
+                    .into()
+            )
         );
-    }
-    #[test]
-    fn test_on_error_with_message() {
+        context.options.max_total_size = Some(600);
         assert_eq!(
-            on_error("Error message".to_string()),
-            Status::Err("Error message".to_string())
+            on_list(directory.to_string(), &mut context, list),
+            Status::Continue()
         );
+        assert_eq!(context.files.len(), 1);
+        fs::remove_dir_all(directory).unwrap();
     }
+
     #[test]
-    fn test_on_leave_room() {
-        let (sender, _) = flume::bounded(1000);
+    fn test_on_list_skips_identical_files() {
+        let directory = "identical";
+        fs::create_dir_all(directory).unwrap();
+        fs::write(format!("{}/same.txt", directory), b"unchanged").unwrap();
+        fs::write(format!("{}/other.txt", directory), b"outdated!").unwrap();
+
+        let (sender, receiver) = flume::bounded(1000);
         let mut context = Context {
-            hmac: vec![],
-            sender,
-            key: EphemeralSecret::random(&mut OsRng),
-            shared_key: None,
-            files: vec![
-                File {
-                    name: "file1.txt".to_string(),
-                    size: 100,
-                    progress: 100,
-                    handle: fs::File::create("file1.txt").unwrap(),
+            shared_key: Some(test_key()),
+            options: ReceiverOptions {
+                on_conflict: ConflictPolicy::Overwrite,
+                ..Default::default()
+            },
+            ..test_context(sender)
+        };
+        let mut hasher = FileHasher::new(HashAlgorithm::Sha256);
+        hasher.write_all(b"unchanged").unwrap();
+        let hash = hasher.finalize_reset();
+        let list = ListPacket {
+            entries: vec![
+                list_packet::Entry {
+                    hash: hash.clone(),
+                    ..test_entry(0, "same.txt", 9)
                 },
-                File {
-                    name: "file2.txt".to_string(),
-                    size: 100,
-                    progress: 50,
-                    handle: fs::File::create("file2.txt").unwrap(),
+                list_packet::Entry {
+                    hash: hash.clone(),
+                    ..test_entry(1, "other.txt", 9)
                 },
             ],
-            sequence: 0,
-            index: 0,
-            progress: 0,
-            length: 0,
+            hash_algorithm: HashAlgorithm::Sha256.into(),
         };
 
+        // Only the file whose content differs is received again
         assert_eq!(
-            on_leave_room(&mut context, 0),
-            Status::Err("Transfer was interrupted because the host left the room.".into())
+            on_list(directory.to_string(), &mut context, list),
+            Status::Continue()
         );
-        context.files[1].progress = 100;
-        assert_eq!(on_leave_room(&mut context, 0), Status::Exit());
+        assert_eq!(context.files.len(), 1);
+        assert_eq!(context.files[0].name, "other.txt");
+        assert!(!Path::new(&part_path(&format!("{}/same.txt", directory))).exists());
+        assert!(receiver.try_recv().is_ok());
+        fs::remove_dir_all(directory).unwrap();
     }
+
     #[test]
-    fn test_on_message_text_join() {
-        let (sender, _) = flume::bounded(1000);
+    fn test_on_list_sends_signatures() {
+        let directory = "delta";
+        fs::create_dir_all(directory).unwrap();
+        fs::write(format!("{}/large.bin", directory), vec![1u8; 4096]).unwrap();
+        fs::write(format!("{}/small.bin", directory), vec![1u8; 100]).unwrap();
+
+        let (sender, receiver) = flume::bounded(1000);
         let mut context = Context {
-            hmac: vec![],
-            sender,
-            key: EphemeralSecret::random(&mut OsRng),
-            shared_key: None,
-            files: vec![],
-            sequence: 0,
-            index: 0,
-            progress: 0,
-            length: 0,
+            shared_key: Some(test_key()),
+            delta: true,
+            options: ReceiverOptions {
+                on_conflict: ConflictPolicy::Overwrite,
+                delta: true,
+                ..Default::default()
+            },
+            ..test_context(sender)
+        };
+        let list = ListPacket {
+            entries: vec![
+                test_entry(0, "large.bin", 5000),
+                test_entry(1, "small.bin", 5000),
+                test_entry(2, "new.bin", 5000),
+            ],
+            hash_algorithm: HashAlgorithm::Sha256.into(),
         };
 
-        let text_message = WebSocketMessage::Text(r#"{"type":"join","size":10}"#.to_string());
+        // Only a copy with a whole block is worth a signature
         assert_eq!(
-            on_message("".to_string(), &mut context, text_message),
+            on_list(directory.to_string(), &mut context, list),
             Status::Continue()
         );
+        assert!(context.files[0].basis.is_some());
+        assert!(context.files[1].basis.is_none());
+        assert!(context.files[2].basis.is_none());
+        assert_eq!(receiver.len(), 3);
+
+        // A file that arrived broken is sent again as it is
+        assert!(request_retry(&mut context, 0).is_ok());
+        assert!(context.files[0].basis.is_none());
+        fs::remove_dir_all(directory).unwrap();
     }
 
     #[test]
-    fn test_on_chunk() {
-        let (sender, _) = flume::bounded(1000);
+    fn test_space_error() {
+        // No filesystem has room for 8 EiB, even if the directory does not exist yet
+        let small = test_entry(0, "notes.txt", 5);
+        let huge = test_entry(0, "disk.img", u64::MAX / 2);
+        assert_eq!(space_error("space_test/new", &[&small]), None);
+        let message = space_error("space_test/new", &[&small, &huge]).unwrap();
+        assert!(message.starts_with("Not enough space in 'space_test/new': the files need"));
+        assert!(!Path::new("space_test").exists());
+    }
+
+    #[test]
+    fn test_on_text() {
+        let (sender, receiver) = flume::bounded(1000);
         let mut context = Context {
-            hmac: vec![],
-            sender,
-            key: EphemeralSecret::random(&mut OsRng),
-            shared_key: None,
-            files: vec![File {
-                name: "file1.txt".to_string(),
-                size: 100,
-                progress: 0,
-                handle: fs::File::create("file1.txt").unwrap(),
-            }],
-            sequence: 0,
-            index: 0,
-            progress: 0,
-            length: 0,
+            shared_key: Some(test_key()),
+            options: ReceiverOptions {
+                save_text: true,
+                ..ReceiverOptions::default()
+            },
+            ..test_context(sender)
         };
-        let chunk_packet = ChunkPacket {
-            sequence: 0,
-            chunk: b"Hello, world!".to_vec(),
+        let text = || TextPacket {
+            text: "https://example.com/".to_string(),
         };
+
+        // The text is saved without replacing an earlier one, and confirmed to the sender
         assert_eq!(
-            on_chunk(&mut context, chunk_packet),
-            Status::Err("Invalid chunk packet: no shared key established".into())
+            on_text("text_test".into(), &mut context, text()),
+            Status::Continue()
         );
+        assert_eq!(
+            on_text("text_test".into(), &mut context, text()),
+            Status::Continue()
+        );
+        assert_eq!(
+            fs::read_to_string("text_test/snippet (1).txt").unwrap(),
+            "https://example.com/"
+        );
+        assert_eq!(receiver.len(), 2);
+
+        // The sender leaving afterwards does not look like a rejected handshake
+        assert_eq!(on_leave_room(&mut context, 0), Status::Exit());
+
+        fs::remove_dir_all("text_test").unwrap();
     }
 }
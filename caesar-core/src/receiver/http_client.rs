@@ -1,9 +1,9 @@
-use anyhow::{anyhow, Result};
-use hex;
-use reqwest::{self, Client};
-use sha2::{Digest, Sha256};
+use reqwest::{self, Client, StatusCode};
 
-use crate::relay::transfer::TransferResponse;
+use crate::error::{CaesarError, Result};
+use crate::relay::routing::ROUTE_HEADER;
+use crate::relay::transfer::{HistoryEntry, TransferResponse};
+use crate::sender::util::hash_random_name;
 
 /// Fetches download information from the relay server for the given file name.
 ///
@@ -11,28 +11,55 @@ use crate::relay::transfer::TransferResponse;
 ///
 /// * `relay` - The URL of the relay server.
 /// * `name` - The name of the file.
+/// * `token` - The access token, if the relay requires authentication.
+/// * `route` - The routing token of the relay instance that holds the transfer.
 ///
 /// # Returns
 ///
 /// A future that resolves to a `Result` containing the download information
-/// if the request is successful, or an error if the request fails.
-pub async fn download_info(relay: &str, name: &str) -> Result<TransferResponse> {
+/// if the request is successful, or an error if the request fails. An unknown
+/// name results in `CaesarError::TransferNotFound`.
+pub async fn download_info(
+    relay: &str,
+    name: &str,
+    token: Option<&str>,
+    route: Option<&str>,
+) -> Result<TransferResponse> {
     // Convert the relay server URL and file name to strings
     let url = String::from(relay);
-    let hashed_name = Sha256::digest(name.as_bytes());
-    let hashed_string = hex::encode(hashed_name);
+    // The name is hashed like the sender did, regardless of case
+    let hashed_string = hash_random_name(name.to_string());
 
     // Send a GET request to the relay server with the file name hash as a query parameter
-    let resp = reqwest::get(format!("{}/download/{}", url, hashed_string))
+    let mut request = Client::new().get(format!("{}/download/{}", url, hashed_string));
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    if let Some(route) = route {
+        request = request.header(ROUTE_HEADER, route);
+    }
+    let resp = request
+        .send()
         .await
         // If the request fails, return an error with the reason
-        .map_err(|e| anyhow!("Failed to send GET request: {}", e))?;
+        .map_err(|e| CaesarError::RelayUnreachable {
+            relay: url.clone(),
+            reason: e.to_string(),
+        })?;
+
+    // The relay answers with not found if no transfer has this name
+    if resp.status() == StatusCode::NOT_FOUND {
+        return Err(CaesarError::TransferNotFound(name.to_string()));
+    }
+    if let Some(error) = CaesarError::refused(resp.status()) {
+        return Err(error);
+    }
 
     // Parse the response body as JSON into a `TransferResponse` struct
     resp.json::<TransferResponse>()
         .await
         // If the JSON parsing fails, return an error with the reason
-        .map_err(|e| anyhow!("Failed to parse JSON response: {}", e))
+        .map_err(|e| CaesarError::Protocol(format!("Failed to parse JSON response: {}", e)))
 }
 
 
@@ -42,28 +69,85 @@ pub async fn download_info(relay: &str, name: &str) -> Result<TransferResponse>
 ///
 /// * `relay` - The URL of the relay server.
 /// * `name` - The name of the file.
+/// * `token` - The access token, if the relay requires authentication.
+/// * `route` - The routing token of the relay instance that holds the transfer.
 ///
 /// # Returns
 ///
 /// A future that resolves to a `Result` containing `Ok(())` if the request is successful,
 /// or an error if the request fails.
-pub async fn download_success(relay: &str, name: &str) -> Result<()> {
+pub async fn download_success(
+    relay: &str,
+    name: &str,
+    token: Option<&str>,
+    route: Option<&str>,
+) -> Result<()> {
     // Convert the relay server URL and file name to strings
     let url = String::from(relay);
-    let hashed_name = Sha256::digest(name.as_bytes());
-    let hashed_string = hex::encode(hashed_name);
+    // The name is hashed like the sender did, regardless of case
+    let hashed_string = hash_random_name(name.to_string());
 
     // Create a new HTTP client
     let client = Client::new();
 
     // Send a POST request to the relay server with the file name hash as a query parameter
-    let _ = client
-        .post(format!("{}/download_success/{}", url, hashed_string))
+    let mut request = client.post(format!("{}/download_success/{}", url, hashed_string));
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    if let Some(route) = route {
+        request = request.header(ROUTE_HEADER, route);
+    }
+    let _ = request
         .send()
         .await
         // If the request fails, return an error with the reason
-        .map_err(|e| anyhow!("Failed to send POST request: {}", e))?;
+        .map_err(|e| CaesarError::RelayUnreachable {
+            relay: url.clone(),
+            reason: e.to_string(),
+        })?;
 
     // Return Ok(()) if the request was successful
     Ok(())
 }
+
+/// Fetches the recent transfers of the user the token was issued to.
+///
+/// Only relays that require authentication keep a history.
+///
+/// # Arguments
+///
+/// * `relay` - The URL of the relay server.
+/// * `token` - The access token of the user.
+///
+/// # Returns
+///
+/// A future that resolves to the transfers of the user, newest first.
+pub async fn transfer_history(relay: &str, token: &str) -> Result<Vec<HistoryEntry>> {
+    let url = String::from(relay);
+
+    let resp = Client::new()
+        .get(format!("{}/transfers", url))
+        .bearer_auth(token)
+        .send()
+        .await
+        // If the request fails, return an error with the reason
+        .map_err(|e| CaesarError::RelayUnreachable {
+            relay: url.clone(),
+            reason: e.to_string(),
+        })?;
+
+    if let Some(error) = CaesarError::refused(resp.status()) {
+        return Err(error);
+    }
+    // A relay without authentication does not know the route
+    if resp.status() == StatusCode::NOT_FOUND {
+        return Err(CaesarError::Protocol(
+            "The relay does not keep a transfer history.".into(),
+        ));
+    }
+
+    resp.json::<Vec<HistoryEntry>>()
+        .await
+        .map_err(|e| CaesarError::Protocol(format!("Failed to parse JSON response: {}", e)))
+}
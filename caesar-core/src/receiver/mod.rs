@@ -1,8 +1,54 @@
+/// Prints a message for the user, on stderr if the files are written to stdout or the
+/// events of the transfer are reported there.
+///
+/// # Arguments
+///
+/// * `options` - The `ReceiverOptions` of the transfer.
+/// * The rest are the arguments of `println!`.
+macro_rules! notify {
+    ($options:expr) => {
+        if $options.stdout || $options.events.is_some() {
+            eprintln!()
+        } else {
+            println!()
+        }
+    };
+    ($options:expr, $($arg:tt)*) => {
+        if $options.stdout || $options.events.is_some() {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
 pub mod client;
 pub mod http_client;
+pub mod plan;
+pub mod select;
+mod writer;
 
-use crate::{receiver::client as receiver, sender::util::replace_protocol};
-use anyhow::{anyhow, Result};
+use crate::{
+    error::{CaesarError, Result},
+    event::{EventReport, TransferEvent},
+    format::NumberFormat,
+    idle::IdleTimeout,
+    net,
+    pake::split_code,
+    progress::ProgressReport,
+    receiver::{
+        client::{self as receiver, Recovery},
+        plan::{Endpoint, TransferCode, TransferPlan},
+        select::{Confirmation, FileSelection},
+    },
+    relay::routing::{split_route, ROUTE_HEADER},
+    sender::util::{replace_protocol, websocket_url},
+    summary::TransferSummary,
+    suspend,
+};
+use clap::ValueEnum;
+use std::iter;
+use tokio_util::sync::CancellationToken;
 
 use tokio_tungstenite::{
     connect_async,
@@ -10,106 +56,673 @@ use tokio_tungstenite::{
 };
 use tracing::{debug, error};
 
+/// What to do when a received file already exists in the target directory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ConflictPolicy {
+    /// Abort the whole transfer and leave the existing file as it is. Partial downloads
+    /// are resumed from their part files, never from a file under the final name.
+    #[default]
+    Abort,
+    /// Replace the existing file.
+    Overwrite,
+    /// Save the file under a new name like `name (1).ext`.
+    Rename,
+    /// Keep the existing file and do not receive this one.
+    Skip,
+}
+
+/// Options controlling how the receiver saves files.
+#[derive(Debug, Clone, Default)]
+pub struct ReceiverOptions {
+    /// What to do when a received file already exists.
+    pub on_conflict: ConflictPolicy,
+    /// Cancels the transfer when triggered. The receiver leaves the room and removes
+    /// the files it has not finished.
+    pub cancel: CancellationToken,
+    /// How long the transfer may stall before the session is closed. The partial files
+    /// are kept, so the transfer can be resumed.
+    pub idle: IdleTimeout,
+    /// How often the session is re-established after it broke down, for example because
+    /// the system was suspended. Zero turns the recovery and the suspend detection off.
+    pub reconnect: u32,
+    /// The access token sent to a relay that requires authentication. It is never
+    /// sent to the sender's local server.
+    pub token: Option<String>,
+    /// The routing token presented to the relay, so a load balancer sends every request
+    /// of the transfer to the same relay instance.
+    ///
+    /// If `None`, it is taken from the transfer name.
+    pub route: Option<String>,
+    /// Further relays that are asked in order when the relay does not answer or does not
+    /// know the transfer, e.g. because the sender fell back to one of them.
+    pub fallback_relays: Vec<String>,
+    /// The key shared with the sender beforehand, e.g. for a mailbox. It authenticates
+    /// the handshake in place of the PIN.
+    ///
+    /// If `None`, `start_receiver` takes the PIN from the transfer code.
+    pub key: Option<Vec<u8>>,
+    /// Save a text snippet the sender sent in place of files as `snippet.txt` in the
+    /// target directory, instead of only printing it.
+    pub save_text: bool,
+    /// Give every received file the modification time it had on the sender's side,
+    /// e.g. for backups that compare times. Streams have none and keep the time they
+    /// were received at.
+    pub preserve_times: bool,
+    /// Create the links the sender sent in place of files, on Unix. Only links that stay
+    /// inside the target directory are created, the others are skipped.
+    ///
+    /// Off by default, as a link could redirect where later files are written.
+    pub links: bool,
+    /// Fetch only the blocks that changed of files that are overwritten, if the sender
+    /// supports it. Takes effect with `ConflictPolicy::Overwrite`, as the older copy is
+    /// what the file is rebuilt from.
+    pub delta: bool,
+    /// The largest file that is accepted, in bytes. A transfer with a larger file is
+    /// refused before anything is written, and a stream is stopped once it grows larger.
+    ///
+    /// If `None`, files of any size are accepted.
+    pub max_file_size: Option<u64>,
+    /// The most bytes all chosen files of a transfer may have together. A larger transfer
+    /// is refused before anything is written, and a stream is stopped once the bytes
+    /// received exceed it.
+    ///
+    /// If `None`, transfers of any size are accepted.
+    pub max_total_size: Option<u64>,
+    /// Write the received files to stdout instead of the target directory, e.g. to pipe
+    /// a stream the sender read from stdin into another program. Messages for the user
+    /// go to stderr then.
+    ///
+    /// Nothing is resumed or requested again, as stdout cannot be rewound.
+    pub stdout: bool,
+    /// Find the sender on the local network over mDNS instead of asking the relay. The
+    /// transfer code may then be the PIN alone. Needs the `mdns` feature.
+    pub discover: bool,
+    /// Try a direct connection to a sender behind NAT through hole punching before the
+    /// transfer falls back to the relay.
+    pub punch: bool,
+    /// Reach the sender's local server over QUIC first, and over WebSocket only if that
+    /// fails. Needs the `quic` feature.
+    pub quic: bool,
+    /// Receive only the files this selection chooses from the sender's list. The
+    /// sender skips the others.
+    ///
+    /// If `None`, every file is received.
+    pub select: Option<FileSelection>,
+    /// Ask whether to receive the chosen files once the sender listed them, before
+    /// anything is written. The transfer is declined if they are not accepted.
+    ///
+    /// If `None`, the files are received without asking.
+    pub confirm: Option<Confirmation>,
+    /// Told the progress of every file, e.g. to draw progress bars.
+    ///
+    /// If `None`, the progress is printed as a line per file.
+    pub progress: Option<ProgressReport>,
+    /// Told what happens in the transfer, e.g. to print it as JSON. The messages for the
+    /// user then go to stderr.
+    ///
+    /// If `None`, only the messages are printed.
+    pub events: Option<EventReport>,
+}
+
 /// Start the receiver process.
 ///
-/// This function initiates the receiver process by performing the following steps:
-/// 1. Replaces the protocol of the given `relay` URL.
-/// 2. Downloads the room information from the server.
-/// 3. Connects to the local or relay server based on the platform.
-/// 4. Downloads the file from the server.
+/// This resolves the transfer code to a plan with `resolve` and runs it with `execute`.
 ///
 /// # Arguments
 ///
-/// * `filepath` - The path to the file to be received.
+/// * `filepath` - The directory the received files are saved in. It is created if missing.
 /// * `relay` - The URL of the relay server.
-/// * `name` - The name of the receiver.
+/// * `name` - The transfer code, like `482913-brave-tiger-42`, optionally followed by
+///   `@` and the routing token. A transfer to a mailbox is received by the name alone.
+/// * `options` - The options of the transfer.
+///
+/// # Returns
+///
+/// The summary of the transfer once every file has been received, or the error that
+/// ended it.
+pub async fn start_receiver(
+    filepath: String,
+    relay: &str,
+    name: &str,
+    options: ReceiverOptions,
+) -> Result<TransferSummary> {
+    let plan = resolve(relay, name, &options).await?;
+    execute(filepath, &plan, options).await
+}
+
+/// Finds out where the sender of a transfer waits.
+///
+/// Nothing is received yet, so a UI can show the plan before it calls `execute`.
+///
+/// # Arguments
+///
+/// * `relay` - The URL of the relay server.
+/// * `code` - The transfer code, like `482913-brave-tiger-42`, optionally followed by
+///   `@` and the routing token. With `options.discover`, it may be the PIN alone.
+/// * `options` - The options of the transfer.
 ///
 /// # Returns
 ///
-/// Returns a `Result` indicating the success or failure of the receiver process.
-pub async fn start_receiver(filepath: String, relay: &str, name: &str) -> Result<()> {
-    let http_url = replace_protocol(relay);
-    let res = http_client::download_info(http_url.as_str(), name)
+/// The plan of the transfer, or the error if the code is invalid or the transfer cannot
+/// be found.
+pub async fn resolve(relay: &str, code: &str, options: &ReceiverOptions) -> Result<TransferPlan> {
+    // Find the sender on the local network instead of asking the relay
+    if options.discover {
+        return resolve_discovered(code.trim(), options).await;
+    }
+
+    let code = match TransferCode::parse(code, options) {
+        Ok(code) => code,
+        Err(error) => {
+            let (code, _) = split_route(code.trim());
+            let (_, name) = split_code(code);
+            notify!(options, "Error: The transfer code '{code}' has no PIN. Enter the whole code the sender printed, like 482913-{name}.");
+            return Err(error);
+        }
+    };
+
+    // The sender published the transfer on the first relay it reached, so a relay that
+    // is down or does not know the transfer is followed by the next one
+    let relays = iter::once(relay).chain(options.fallback_relays.iter().map(String::as_str));
+    let mut failure = None;
+    for relay in relays {
+        match http_client::download_info(
+            replace_protocol(relay).as_str(),
+            &code.name,
+            options.token.as_deref(),
+            code.route.as_deref(),
+        )
         .await
-        .unwrap();
-    debug!("Got room_id from Server: {:?}", res);
-    let res_ip = String::from("ws://") + res.ip.as_str() + ":9000";
-
-    #[cfg(not(target_os = "android"))]
-    if let Err(local_err) = start_ws_com(
-        filepath.clone(),
-        res_ip.as_str(),
-        res.local_room_id.as_str(),
+        {
+            Ok(res) => {
+                debug!("Got room_id from Server: {:?}", res);
+                return Ok(TransferPlan::for_relay(relay, code, &res, options));
+            }
+            Err(
+                error @ (CaesarError::RelayUnreachable { .. } | CaesarError::TransferNotFound(_)),
+            ) => {
+                debug!("The relay {relay} cannot be asked for the transfer: {error}");
+                // A relay that does not know the transfer tells more than one that is down
+                if !matches!(failure, Some(CaesarError::TransferNotFound(_))) {
+                    failure = Some(error);
+                }
+            }
+            Err(error) => return Err(error),
+        }
+    }
+    Err(failure.expect("the first relay is always asked"))
+}
+
+/// Receives a transfer along its plan.
+///
+/// The endpoints of the plan are tried in order. The next one is only tried if the
+/// connection to the last one failed; errors of the transfer itself are not retried.
+/// Once every file has been received, the relay is told the transfer is complete and
+/// the summary is printed.
+///
+/// # Arguments
+///
+/// * `filepath` - The directory the received files are saved in. It is created if missing.
+/// * `plan` - The plan `resolve` returned.
+/// * `options` - The options of the transfer.
+///
+/// # Returns
+///
+/// The summary of the transfer once every file has been received, or the error that
+/// ended it.
+pub async fn execute(
+    filepath: String,
+    plan: &TransferPlan,
+    mut options: ReceiverOptions,
+) -> Result<TransferSummary> {
+    options.key = Some(plan.key.clone());
+    options.route = plan.route.clone();
+
+    let mut result = Err(CaesarError::Connection(
+        "The transfer has no endpoint.".into(),
+    ));
+    for endpoint in &plan.endpoints {
+        result = match endpoint {
+            Endpoint::Local {
+                host,
+                port,
+                room_id,
+            } => {
+                start_local(
+                    filepath.clone(),
+                    host,
+                    *port,
+                    room_id,
+                    // The token and the route are meant for the relay only
+                    ReceiverOptions {
+                        token: None,
+                        route: None,
+                        ..options.clone()
+                    },
+                )
+                .await
+            }
+            Endpoint::Direct { relay, room_id } => {
+                start_direct(filepath.clone(), relay, room_id, &options).await
+            }
+            Endpoint::Relay { relay, room_id } => {
+                start_ws_com(filepath.clone(), relay, room_id, options.clone()).await
+            }
+        }
+        .map(|summary| summary.over(endpoint.path()));
+        match &result {
+            Err(error) if endpoint.falls_back(error) => {
+                debug!("Failed to connect to the {endpoint}: {error}");
+            }
+            _ => break,
+        }
+    }
+
+    let summary = match result {
+        Ok(summary) => summary,
+        Err(error) => {
+            debug!("Failed to receive: {error}");
+            return Err(error);
+        }
+    };
+    // A sender found on the local network is not known to the relay
+    if let Some(relay) = &plan.relay {
+        http_client::download_success(
+            replace_protocol(relay).as_str(),
+            &plan.name,
+            options.token.as_deref(),
+            plan.route.as_deref(),
+        )
+        .await?;
+    }
+
+    // A text has no files to sum up
+    if summary.files > 0 {
+        notify!(
+            options,
+            "Received {}.",
+            summary.describe(&NumberFormat::current())
+        );
+    }
+    debug!("Success");
+    Ok(summary)
+}
+
+/// Finds the sender on the local network.
+///
+/// The relay is not asked for the transfer, so it is neither looked up nor reported as
+/// complete there.
+///
+/// # Arguments
+///
+/// * `code` - The transfer code, or the PIN alone if one transfer is announced.
+/// * `options` - The options of the transfer.
+///
+/// # Returns
+///
+/// The plan that reaches the sender's local server, or the error if no sender is found.
+#[cfg(feature = "mdns")]
+async fn resolve_discovered(code: &str, options: &ReceiverOptions) -> Result<TransferPlan> {
+    use crate::discovery::{discover, select_peer, DISCOVERY_TIMEOUT};
+    use crate::pake::PIN_DIGITS;
+
+    // The code may be the PIN alone
+    let (pin, name) = match split_code(code) {
+        (None, pin) if pin.len() == PIN_DIGITS && pin.bytes().all(|c| c.is_ascii_digit()) => {
+            (Some(pin), "")
+        }
+        split => split,
+    };
+    let Some(key) = options
+        .key
+        .clone()
+        .or(pin.map(|pin| pin.as_bytes().to_vec()))
+    else {
+        notify!(options, "Error: The transfer code '{code}' has no PIN. Enter the PIN the sender printed, like 482913.");
+        return Err(CaesarError::InvalidInviteCode(code.to_string()));
+    };
+
+    notify!(options, "Looking for the sender on the local network...");
+    let peer = select_peer(discover(DISCOVERY_TIMEOUT).await?, name)?;
+    notify!(options, "Found '{}' at {}.", peer.name, peer.address);
+
+    Ok(TransferPlan {
+        name: peer.name,
+        relay: None,
+        route: None,
+        key,
+        endpoints: vec![Endpoint::Local {
+            host: peer.address.to_string(),
+            port: peer.port,
+            room_id: peer.room_id,
+        }],
+    })
+}
+
+/// Stands in for the discovery in builds without the `mdns` feature.
+#[cfg(not(feature = "mdns"))]
+async fn resolve_discovered(_code: &str, _options: &ReceiverOptions) -> Result<TransferPlan> {
+    Err(CaesarError::Discovery(
+        "This build cannot look for senders on the local network.".into(),
+    ))
+}
+
+/// Receives from the sender's local server, over QUIC if both peers support it.
+///
+/// # Arguments
+///
+/// * `filepath` - The directory the received files are saved in.
+/// * `host` - The address of the sender on its network.
+/// * `port` - The port of the sender's local server.
+/// * `room_id` - The room the sender waits in on its local server.
+/// * `options` - The options of the transfer.
+///
+/// # Returns
+///
+/// The summary of the transfer once every file has been received, or the error that
+/// ended it.
+async fn start_local(
+    filepath: String,
+    host: &str,
+    port: u16,
+    room_id: &str,
+    options: ReceiverOptions,
+) -> Result<TransferSummary> {
+    #[cfg(feature = "quic")]
+    if options.quic {
+        match start_quic(filepath.clone(), host, port, room_id, options.clone()).await {
+            Err(error @ (CaesarError::RelayUnreachable { .. } | CaesarError::Connection(_))) => {
+                debug!("Failed to connect over QUIC: {error}");
+            }
+            result => return result,
+        }
+    }
+
+    start_ws_com(
+        filepath,
+        &format!("ws://{}", net::join_host_port(host, port)),
+        room_id,
+        options,
     )
     .await
-    {
-        debug!("Failed to connect local: {local_err}");
-        if let Err(relay_err) = start_ws_com(filepath, relay, res.relay_room_id.as_str()).await {
-            debug!("Failed to connect remote: {relay_err}");
+}
+
+/// Receives from the sender's local server over QUIC.
+///
+/// A session that breaks down continues on a new stream of the same connection, or on
+/// a new connection that is resumed with 0-RTT, up to `options.reconnect` times.
+///
+/// # Arguments
+///
+/// * `filepath` - The directory the received files are saved in.
+/// * `host` - The address of the sender on its network.
+/// * `port` - The UDP port the sender accepts QUIC connections on.
+/// * `room_id` - The room the sender waits in on its local server.
+/// * `options` - The options of the transfer.
+///
+/// # Returns
+///
+/// The summary of the transfer once every file has been received, or the error that
+/// ended it.
+#[cfg(feature = "quic")]
+async fn start_quic(
+    filepath: String,
+    host: &str,
+    port: u16,
+    room_id: &str,
+    options: ReceiverOptions,
+) -> Result<TransferSummary> {
+    use crate::quic::Dialer;
+
+    let address = tokio::net::lookup_host(net::join_host_port(host, port))
+        .await
+        .ok()
+        .and_then(|mut addresses| addresses.next())
+        .ok_or_else(|| CaesarError::Connection(format!("Failed to resolve '{host}'.")))?;
+    let mut dialer = Dialer::new()?;
+    let mut recovery = Recovery::default();
+    let mut attempt = 0;
+
+    loop {
+        let result = match dialer.connect(address).await {
+            Ok(socket) => {
+                debug!("Connected to {address} over QUIC");
+                receiver::start_session(
+                    filepath.clone(),
+                    socket,
+                    room_id,
+                    options.clone(),
+                    &mut recovery,
+                )
+                .await
+            }
+            Err(error) => Err(error),
+        };
+
+        match result {
+            Err(error)
+                if error.is_recoverable()
+                    && recovery.is_resumable()
+                    && attempt < options.reconnect =>
+            {
+                attempt += 1;
+                debug!("Session broke down: {error}");
+                notify!(
+                    options,
+                    "Reconnecting ({}/{})...",
+                    attempt,
+                    options.reconnect
+                );
+                if let Some(events) = &options.events {
+                    events.report(TransferEvent::Reconnect { attempt });
+                }
+                if !suspend::reconnect_delay(attempt, &options.cancel).await {
+                    recovery.remove_partial_files();
+                    notify!(options, "Transfer was cancelled.");
+                    return Err(CaesarError::Cancelled);
+                }
+            }
+            result => return result,
         }
     }
+}
 
-    #[cfg(target_os = "android")]
-    if let Err(relay_err) = start_ws_com(filepath, relay, res.relay_room_id.as_str()).await {
-        debug!("Failed to connect remote: {relay_err}");
-    }
-    http_client::download_success(http_url.as_str(), name)
+/// Receives over a direct connection to the sender, punched through the NATs of both.
+///
+/// The connection reaches the sender's local server, so the session runs like one on
+/// the local network. A connection that breaks down is not punched again, the transfer
+/// then continues through the relay.
+///
+/// # Arguments
+///
+/// * `filepath` - The directory the received files are saved in.
+/// * `relay` - The relay whose rendezvous the peers meet at.
+/// * `room_id` - The room of the transfer.
+/// * `options` - The options of the transfer.
+///
+/// # Returns
+///
+/// The summary of the transfer once every file has been received, or the error that
+/// ended it.
+async fn start_direct(
+    filepath: String,
+    relay: &str,
+    room_id: &str,
+    options: &ReceiverOptions,
+) -> Result<TransferSummary> {
+    use crate::punch::{punch, PUNCH_TIMEOUT};
+    use tokio_tungstenite::client_async;
+
+    let mut streams = punch(
+        relay,
+        room_id,
+        options.route.as_deref(),
+        options.token.as_deref(),
+        Some(PUNCH_TIMEOUT),
+        false,
+    )
+    .await?;
+    let stream = streams.recv().await.ok_or_else(|| {
+        CaesarError::Connection("No direct connection to the sender could be opened.".into())
+    })?;
+    let address = stream
+        .peer_addr()
+        .map_err(|e| CaesarError::Connection(e.to_string()))?;
+    let (socket, _) = client_async(format!("ws://{}/ws", address), stream)
         .await
-        .map_err(|e| anyhow!("Failed to download success: {}", e))?;
+        .map_err(|e| CaesarError::Connection(e.to_string()))?;
+    notify!(options, "Connected directly to the sender at {}.", address);
 
-    debug!("Success");
-    Ok(())
+    receiver::start_session(
+        filepath,
+        socket,
+        room_id,
+        // The token and the route are meant for the relay only
+        ReceiverOptions {
+            token: None,
+            route: None,
+            ..options.clone()
+        },
+        &mut Recovery::default(),
+    )
+    .await
 }
 
 /// Asynchronously starts a WebSocket communication with a relay server.
 ///
+/// Once files have been saved, a session that breaks down is re-established up to
+/// `options.reconnect` times and continues the files where they stopped.
+///
 /// # Arguments
 ///
 /// * `filepath` - The path of the file to transfer.
 /// * `relay` - The URL of the relay server.
 /// * `name` - The name of the receiver.
+/// * `options` - The options of the transfer.
 ///
 /// # Returns
 ///
-/// Returns a `Result` indicating the success or failure of the WebSocket communication.
-pub async fn start_ws_com(filepath: String, relay: &str, name: &str) -> Result<()> {
-    // Construct the WebSocket URL by appending "/ws" to the relay URL.
-    let url = String::from(relay) + "/ws";
+/// The summary of the transfer once every file has been received, or the error that
+/// ended it.
+pub async fn start_ws_com(
+    filepath: String,
+    relay: &str,
+    name: &str,
+    options: ReceiverOptions,
+) -> Result<TransferSummary> {
+    let mut recovery = Recovery::default();
+    let mut attempt = 0;
+
+    loop {
+        let result = start_session(
+            filepath.clone(),
+            relay,
+            name,
+            options.clone(),
+            &mut recovery,
+        )
+        .await;
+
+        match result {
+            Err(error)
+                if error.is_recoverable()
+                    && recovery.is_resumable()
+                    && attempt < options.reconnect =>
+            {
+                attempt += 1;
+                debug!("Session broke down: {error}");
+                notify!(
+                    options,
+                    "Reconnecting ({}/{})...",
+                    attempt,
+                    options.reconnect
+                );
+                if let Some(events) = &options.events {
+                    events.report(TransferEvent::Reconnect { attempt });
+                }
+                if !suspend::reconnect_delay(attempt, &options.cancel).await {
+                    recovery.remove_partial_files();
+                    notify!(options, "Transfer was cancelled.");
+                    return Err(CaesarError::Cancelled);
+                }
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Connects to the relay server and runs a single session of the receiver.
+///
+/// # Arguments
+///
+/// * `filepath` - The path of the file to transfer.
+/// * `relay` - The URL of the relay server.
+/// * `name` - The name of the receiver.
+/// * `options` - The options of the transfer.
+/// * `recovery` - The files of the earlier sessions.
+///
+/// # Returns
+///
+/// The summary of the transfer once every file has been received, or the error that
+/// ended the session.
+async fn start_session(
+    filepath: String,
+    relay: &str,
+    name: &str,
+    options: ReceiverOptions,
+    recovery: &mut Recovery,
+) -> Result<TransferSummary> {
+    let unreachable = |reason: String| CaesarError::RelayUnreachable {
+        relay: relay.to_string(),
+        reason,
+    };
+
+    // Construct the WebSocket URL, with TLS if the relay is fronted by HTTPS.
+    let url = websocket_url(relay);
 
     // Create a WebSocket request using the constructed URL.
     let mut request = url
         .into_client_request()
-        .map_err(|e| anyhow!("Failed to create request: {}", e))?;
+        .map_err(|e| unreachable(format!("Failed to create request: {}", e)))?;
 
     // Set the "Origin" header of the request to the relay URL.
-    request
-        .headers_mut()
-        .insert("Origin", HeaderValue::from_str(relay).unwrap());
+    let origin = HeaderValue::from_str(relay)
+        .map_err(|e| unreachable(format!("Invalid relay address: {}", e)))?;
+    request.headers_mut().insert("Origin", origin);
+
+    // Stay on the relay instance that holds the room.
+    if let Some(route) = &options.route {
+        let route = HeaderValue::from_str(route)
+            .map_err(|e| CaesarError::InvalidInviteCode(format!("{route}: {e}")))?;
+        request.headers_mut().insert(ROUTE_HEADER, route);
+    }
+
+    // Authenticate with the relay if it requires a token.
+    if let Some(token) = &options.token {
+        let authorization = HeaderValue::from_str(&format!("Bearer {token}"))
+            .map_err(|e| CaesarError::Refused(format!("Invalid access token: {}", e)))?;
+        request.headers_mut().insert("Authorization", authorization);
+    }
 
     // Print a message indicating the attempt to connect.
-    println!("Attempting to connect...");
+    notify!(options, "Attempting to connect...");
 
     // Attempt to establish a WebSocket connection with the relay server.
     // If the connection fails or times out, return an error.
-    let _ = match tokio::time::timeout(std::time::Duration::from_secs(5), connect_async(request))
-        .await
-    {
+    match tokio::time::timeout(std::time::Duration::from_secs(5), connect_async(request)).await {
         Ok(Ok((socket, _))) => {
             // Start the receiver process with the established WebSocket connection.
-            receiver::start(filepath, socket, name).await;
-            Ok(())
+            receiver::start_session(filepath, socket, name, options, recovery).await
         }
         Ok(Err(e)) => {
             // Log the failure to connect.
             error!("Error: Failed to connect: {e:?}");
-            Err(Box::new(e))
+            Err(unreachable(e.to_string()))
         }
-        Err(e) => {
+        Err(_) => {
             // Log the timeout.
             error!("Error: Timeout reached for local connection attempt");
-            Err(Box::new(e))
-        }?,
-    };
-    Ok(())
+            Err(unreachable("Timed out while connecting.".into()))
+        }
+    }
 }
-
@@ -0,0 +1,319 @@
+//! The ways a receiver may reach the sender of a transfer.
+//!
+//! Receiving happens in two steps. `resolve` turns the transfer code into a
+//! `TransferPlan`: it asks the relay, or the local network, where the sender waits, and
+//! lists the endpoints in the order they are tried. `execute` then runs the transfer
+//! along the plan. A UI can show the plan in between, e.g. whether the sender is on the
+//! same network, before it starts the transfer.
+
+use std::fmt;
+
+use crate::{
+    error::{CaesarError, Result},
+    net,
+    pake::split_code,
+    receiver::ReceiverOptions,
+    relay::{routing::split_route, transfer::TransferResponse},
+    summary::TransferPath,
+};
+
+/// A way to reach the sender.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    /// The sender's local server, for a receiver on the same network.
+    Local {
+        /// The address of the sender on its network.
+        host: String,
+        /// The port of the sender's local server.
+        port: u16,
+        /// The room the sender waits in on its local server.
+        room_id: String,
+    },
+    /// A direct connection to the sender's local server, punched through the NATs of
+    /// both peers at the relay's rendezvous.
+    Direct {
+        /// The relay whose rendezvous the peers meet at.
+        relay: String,
+        /// The room of the transfer.
+        room_id: String,
+    },
+    /// The relay, which forwards the whole transfer.
+    Relay {
+        /// The address of the relay.
+        relay: String,
+        /// The room the sender waits in on the relay.
+        room_id: String,
+    },
+}
+
+impl Endpoint {
+    /// Returns `true` if the next endpoint of the plan is tried after this error.
+    ///
+    /// Only errors of the connection fall back. Errors of the transfer itself, like a
+    /// wrong PIN or a full disk, would happen on every endpoint alike.
+    ///
+    /// # Arguments
+    ///
+    /// * `error` - The error the transfer over this endpoint ended with.
+    pub fn falls_back(&self, error: &CaesarError) -> bool {
+        match self {
+            // The local room ID is not valid on a server the receiver reached by mistake
+            Endpoint::Local { .. } => matches!(
+                error,
+                CaesarError::RelayUnreachable { .. }
+                    | CaesarError::InvalidInviteCode(_)
+                    | CaesarError::Connection(_)
+            ),
+            Endpoint::Direct { .. } => matches!(
+                error,
+                CaesarError::RelayUnreachable { .. } | CaesarError::Connection(_)
+            ),
+            Endpoint::Relay { .. } => false,
+        }
+    }
+
+    /// Returns the path the files take over this endpoint.
+    pub fn path(&self) -> TransferPath {
+        match self {
+            Endpoint::Local { .. } => TransferPath::Local,
+            Endpoint::Direct { .. } => TransferPath::Direct,
+            Endpoint::Relay { .. } => TransferPath::Relay,
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Local { host, port, .. } => {
+                write!(f, "local server at {}", net::join_host_port(host, port))
+            }
+            Endpoint::Direct { .. } => write!(f, "direct connection"),
+            Endpoint::Relay { relay, .. } => write!(f, "relay at {relay}"),
+        }
+    }
+}
+
+/// Where and how a transfer is received.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferPlan {
+    /// The name of the transfer, without the PIN and the routing token.
+    pub name: String,
+    /// The relay the transfer was looked up at, which is told once it is complete. If
+    /// `None`, the sender was found on the local network.
+    pub relay: Option<String>,
+    /// The routing token of the relay instance that holds the transfer.
+    pub route: Option<String>,
+    /// The key that authenticates the handshake, usually the PIN of the transfer code.
+    pub key: Vec<u8>,
+    /// The ways to reach the sender, in the order they are tried.
+    pub endpoints: Vec<Endpoint>,
+}
+
+impl TransferPlan {
+    /// Creates the plan for a transfer the relay knows.
+    ///
+    /// # Arguments
+    ///
+    /// * `relay` - The relay the transfer was looked up at.
+    /// * `code` - The parts of the transfer code.
+    /// * `response` - Where the relay says the sender waits.
+    /// * `options` - The options of the transfer.
+    ///
+    /// # Returns
+    ///
+    /// The plan, which tries the sender's local server, a direct connection and the
+    /// relay in this order. Android only takes the relay.
+    pub fn for_relay(
+        relay: &str,
+        code: TransferCode,
+        response: &TransferResponse,
+        options: &ReceiverOptions,
+    ) -> TransferPlan {
+        let mut endpoints = vec![];
+        if cfg!(not(target_os = "android")) {
+            endpoints.push(Endpoint::Local {
+                host: response.ip.clone(),
+                port: response.port,
+                room_id: response.local_room_id.clone(),
+            });
+            if options.punch {
+                endpoints.push(Endpoint::Direct {
+                    relay: relay.to_string(),
+                    room_id: response.local_room_id.clone(),
+                });
+            }
+        }
+        endpoints.push(Endpoint::Relay {
+            relay: relay.to_string(),
+            room_id: response.relay_room_id.clone(),
+        });
+
+        TransferPlan {
+            name: code.name,
+            relay: Some(relay.to_string()),
+            route: code.route,
+            key: code.key,
+            endpoints,
+        }
+    }
+}
+
+/// The parts of a transfer code like `482913-brave-tiger-42@relay-1`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferCode {
+    /// The name of the transfer the relay knows it by.
+    pub name: String,
+    /// The key that authenticates the handshake.
+    pub key: Vec<u8>,
+    /// The routing token of the relay instance that holds the transfer.
+    pub route: Option<String>,
+}
+
+impl TransferCode {
+    /// Splits a transfer code into its parts.
+    ///
+    /// # Arguments
+    ///
+    /// * `code` - The transfer code. A transfer to a mailbox is received by the name
+    ///   alone, as the key is part of the options.
+    /// * `options` - The options of the transfer. Their key and route take precedence.
+    ///
+    /// # Returns
+    ///
+    /// The parts, or `CaesarError::InvalidInviteCode` if there is neither a PIN in the
+    /// code nor a key in the options.
+    pub fn parse(code: &str, options: &ReceiverOptions) -> Result<TransferCode> {
+        let (code, route) = split_route(code.trim());
+        // The PIN authenticates the handshake and never reaches the relay
+        let (pin, name) = split_code(code);
+        let key = options
+            .key
+            .clone()
+            .or(pin.map(|pin| pin.as_bytes().to_vec()))
+            .ok_or_else(|| CaesarError::InvalidInviteCode(code.to_string()))?;
+
+        Ok(TransferCode {
+            name: name.to_string(),
+            key,
+            route: options.route.clone().or(route.map(String::from)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response() -> TransferResponse {
+        TransferResponse {
+            port: 9001,
+            ..TransferResponse::new(
+                "brave-tiger-42".into(),
+                "192.168.1.20".into(),
+                "local-room".into(),
+                "relay-room".into(),
+            )
+        }
+    }
+
+    #[test]
+    fn test_parse_code() {
+        let options = ReceiverOptions::default();
+        let code = TransferCode::parse(" 482913-brave-tiger-42@relay-1 ", &options).unwrap();
+        assert_eq!(code.name, "brave-tiger-42");
+        assert_eq!(code.key, b"482913");
+        assert_eq!(code.route.as_deref(), Some("relay-1"));
+
+        // Without a PIN the handshake cannot be authenticated
+        assert!(matches!(
+            TransferCode::parse("brave-tiger-42", &options),
+            Err(CaesarError::InvalidInviteCode(_))
+        ));
+
+        // A mailbox brings its own key
+        let options = ReceiverOptions {
+            key: Some(b"secret".to_vec()),
+            ..ReceiverOptions::default()
+        };
+        let code = TransferCode::parse("mailbox-name", &options).unwrap();
+        assert_eq!(code.key, b"secret");
+        assert_eq!(code.route, None);
+    }
+
+    #[test]
+    fn test_plan_for_relay() {
+        let options = ReceiverOptions {
+            punch: true,
+            ..ReceiverOptions::default()
+        };
+        let code = TransferCode::parse("482913-brave-tiger-42", &options).unwrap();
+        let plan = TransferPlan::for_relay("ws://relay", code.clone(), &response(), &options);
+
+        assert_eq!(plan.name, "brave-tiger-42");
+        assert_eq!(plan.relay.as_deref(), Some("ws://relay"));
+        assert_eq!(
+            plan.endpoints,
+            vec![
+                Endpoint::Local {
+                    host: "192.168.1.20".into(),
+                    port: 9001,
+                    room_id: "local-room".into(),
+                },
+                Endpoint::Direct {
+                    relay: "ws://relay".into(),
+                    room_id: "local-room".into(),
+                },
+                Endpoint::Relay {
+                    relay: "ws://relay".into(),
+                    room_id: "relay-room".into(),
+                },
+            ]
+        );
+
+        // Without hole punching the direct connection is left out
+        let plan =
+            TransferPlan::for_relay("ws://relay", code, &response(), &ReceiverOptions::default());
+        assert_eq!(plan.endpoints.len(), 2);
+        assert!(!plan
+            .endpoints
+            .iter()
+            .any(|endpoint| matches!(endpoint, Endpoint::Direct { .. })));
+    }
+
+    #[test]
+    fn test_falls_back() {
+        let local = Endpoint::Local {
+            host: "192.168.1.20".into(),
+            port: crate::sender::LOCAL_PORT,
+            room_id: "local-room".into(),
+        };
+        let relay = Endpoint::Relay {
+            relay: "ws://relay".into(),
+            room_id: "relay-room".into(),
+        };
+        let closed = CaesarError::Connection("Closed.".into());
+
+        assert!(local.falls_back(&closed));
+        assert!(local.falls_back(&CaesarError::InvalidInviteCode("room".into())));
+        assert!(!local.falls_back(&CaesarError::Cancelled));
+        assert!(!relay.falls_back(&closed));
+    }
+
+    #[test]
+    fn test_display_brackets_ipv6() {
+        let local = |host: &str| Endpoint::Local {
+            host: host.into(),
+            port: 9001,
+            room_id: "local-room".into(),
+        };
+        assert_eq!(
+            local("192.168.1.20").to_string(),
+            "local server at 192.168.1.20:9001"
+        );
+        assert_eq!(
+            local("fd00::20").to_string(),
+            "local server at [fd00::20]:9001"
+        );
+    }
+}
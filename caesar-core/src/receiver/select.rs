@@ -0,0 +1,144 @@
+//! Choosing which of the offered files a receiver takes.
+//!
+//! The sender lists every file of the transfer before it sends any. A receiver that
+//! only needs some of them answers with the end of the others as their offset, like
+//! for files it already has, so the sender never reads or sends them. Senders that
+//! predate the selection skip such files as well. A receiver may also be asked to
+//! accept the chosen files before anything is written, and declines the transfer if
+//! it does not.
+
+use std::{fmt, sync::Arc};
+
+/// A file the sender offers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OfferedFile {
+    /// The index of the file in the sender's list.
+    pub index: u32,
+    /// The name of the file.
+    pub name: String,
+    /// The size of the file in bytes, zero for a stream.
+    pub size: u64,
+    /// Whether the file is a stream whose size is unknown until it ends.
+    pub stream: bool,
+}
+
+/// A function that returns the indices of the files to receive.
+type Select = dyn Fn(&[OfferedFile]) -> Vec<u32> + Send + Sync;
+
+/// Chooses which of the offered files are received.
+#[derive(Clone)]
+pub struct FileSelection(Arc<Select>);
+
+impl FileSelection {
+    /// Creates a selection from a function, e.g. one that asks the user.
+    ///
+    /// # Arguments
+    ///
+    /// * `select` - Returns the indices of the files to receive.
+    pub fn new<F>(select: F) -> FileSelection
+    where
+        F: Fn(&[OfferedFile]) -> Vec<u32> + Send + Sync + 'static,
+    {
+        FileSelection(Arc::new(select))
+    }
+
+    /// Creates a selection of the files with the given names.
+    ///
+    /// # Arguments
+    ///
+    /// * `names` - The names of the files to receive, as the sender lists them.
+    pub fn names(names: Vec<String>) -> FileSelection {
+        FileSelection::new(move |files| {
+            files
+                .iter()
+                .filter(|file| names.contains(&file.name))
+                .map(|file| file.index)
+                .collect()
+        })
+    }
+
+    /// Chooses the files to receive.
+    ///
+    /// # Arguments
+    ///
+    /// * `files` - The files the sender offers.
+    ///
+    /// # Returns
+    ///
+    /// The indices of the files to receive.
+    pub fn select(&self, files: &[OfferedFile]) -> Vec<u32> {
+        (self.0)(files)
+    }
+}
+
+impl fmt::Debug for FileSelection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("FileSelection")
+    }
+}
+
+/// A function that returns whether to receive the files.
+type Confirm = dyn Fn(&[OfferedFile]) -> bool + Send + Sync;
+
+/// Asks whether to receive the offered files at all.
+#[derive(Clone)]
+pub struct Confirmation(Arc<Confirm>);
+
+impl Confirmation {
+    /// Creates a confirmation from a function, e.g. one that prompts the user.
+    ///
+    /// # Arguments
+    ///
+    /// * `confirm` - Returns whether to receive the files.
+    pub fn new<F>(confirm: F) -> Confirmation
+    where
+        F: Fn(&[OfferedFile]) -> bool + Send + Sync + 'static,
+    {
+        Confirmation(Arc::new(confirm))
+    }
+
+    /// Asks whether to receive the files.
+    ///
+    /// # Arguments
+    ///
+    /// * `files` - The files that would be received.
+    ///
+    /// # Returns
+    ///
+    /// Whether the files are accepted.
+    pub fn confirm(&self, files: &[OfferedFile]) -> bool {
+        (self.0)(files)
+    }
+}
+
+impl fmt::Debug for Confirmation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Confirmation")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_names() {
+        let offered = |index: u32, name: &str| OfferedFile {
+            index,
+            name: name.to_string(),
+            size: 1024,
+            stream: false,
+        };
+        let files = vec![
+            offered(0, "report.pdf"),
+            offered(1, "photos.zip"),
+            offered(2, "notes.txt"),
+        ];
+
+        let selection = FileSelection::names(vec!["notes.txt".into(), "report.pdf".into()]);
+        assert_eq!(selection.select(&files), vec![0, 2]);
+        assert!(FileSelection::names(vec!["missing.txt".into()])
+            .select(&files)
+            .is_empty());
+    }
+}
@@ -0,0 +1,344 @@
+//! Writing the received chunks to disk.
+//!
+//! The chunks of a batch are kept as they came off the socket and written to the file
+//! together with a single vectored write once the batch is complete. Compressed chunks
+//! are decompressed into a buffer that is reused for every batch, so a transfer
+//! allocates about the same no matter how large its files are.
+//!
+//! The batches are written by a thread of each file, so a slow disk holds up only that
+//! thread while the receiver keeps reading from the connection.
+
+use std::io::{self, IoSlice, Write};
+use std::thread::{self, JoinHandle};
+
+use bytes::{Bytes, BytesMut};
+use zstd::{bulk::Decompressor, zstd_safe::get_frame_content_size};
+
+use crate::shared::FileHasher;
+
+/// The number of chunks handed to the operating system in one vectored write.
+const MAX_SLICES: usize = 16;
+
+/// The largest decompressed chunk that is accepted, far above the chunks senders create.
+const MAX_CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// The number of batches that may wait for the disk before the receiver waits as well.
+const QUEUE_LENGTH: usize = 4;
+
+/// Collects the chunks of a batch and writes them to the file.
+#[derive(Default)]
+pub struct ChunkWriter {
+    /// The chunks received since the last write.
+    chunks: Vec<Bytes>,
+    /// The buffer compressed chunks are decompressed into.
+    ///
+    /// The decompressed chunks are split off it. Once they are written and dropped,
+    /// the buffer takes their memory back for the next batch.
+    buffer: BytesMut,
+    /// The decompression context, created with the first compressed chunk.
+    decompressor: Option<Decompressor<'static>>,
+}
+
+impl ChunkWriter {
+    /// Decompresses a zstd compressed chunk.
+    ///
+    /// # Arguments
+    ///
+    /// * `chunk` - The compressed chunk.
+    ///
+    /// # Returns
+    ///
+    /// The decompressed chunk, or an error if the chunk is not valid zstd data.
+    pub fn decompress(&mut self, chunk: &[u8]) -> io::Result<Bytes> {
+        // Frames without their size, which the sender never creates, take the slow path,
+        // which is capped all the same, so a small chunk cannot expand without bound
+        let Ok(Some(size)) = get_frame_content_size(chunk) else {
+            return zstd::bulk::decompress(chunk, MAX_CHUNK_SIZE as usize).map(Bytes::from);
+        };
+        if size > MAX_CHUNK_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Chunk is too large",
+            ));
+        }
+
+        let decompressor = match &mut self.decompressor {
+            Some(decompressor) => decompressor,
+            None => self.decompressor.insert(Decompressor::new()?),
+        };
+
+        self.buffer.resize(size as usize, 0);
+        match decompressor.decompress_to_buffer(chunk, &mut self.buffer[..]) {
+            Ok(written) => {
+                self.buffer.truncate(written);
+                Ok(self.buffer.split().freeze())
+            }
+            Err(error) => {
+                self.buffer.clear();
+                Err(error)
+            }
+        }
+    }
+
+    /// Adds a chunk to the current batch.
+    ///
+    /// # Arguments
+    ///
+    /// * `chunk` - The content of the chunk.
+    pub fn push(&mut self, chunk: Bytes) {
+        self.chunks.push(chunk);
+    }
+
+    /// Queues the current batch for writing to a file and feeds it into the file's hasher.
+    ///
+    /// The batch is discarded if an earlier write failed, as the file is damaged then.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The file to write to.
+    /// * `hasher` - The hasher of the file.
+    ///
+    /// # Returns
+    ///
+    /// An error if writing an earlier batch failed.
+    pub fn write_to<W>(
+        &mut self,
+        file: &mut QueuedSink<W>,
+        hasher: &mut FileHasher,
+    ) -> io::Result<()>
+    where
+        W: Write + Send + 'static,
+    {
+        if self.chunks.is_empty() {
+            return Ok(());
+        }
+
+        for chunk in &self.chunks {
+            hasher.update(chunk);
+        }
+        file.queue(std::mem::take(&mut self.chunks))
+    }
+}
+
+/// A sink the batches of a file are written to by a thread of its own.
+///
+/// The thread is started with the first batch and ends once [`QueuedSink::wait`] was
+/// called, which hands the sink back, e.g. to rename the file or to start it over.
+#[derive(Debug)]
+pub struct QueuedSink<W> {
+    /// The sink, while no thread writes to it.
+    sink: Option<W>,
+    /// The queue of the batches and the thread writing them, which returns the sink.
+    thread: Option<Thread<W>>,
+}
+
+/// The queue of the batches and the thread writing them to a sink.
+type Thread<W> = (flume::Sender<Vec<Bytes>>, JoinHandle<(W, io::Result<()>)>);
+
+impl<W: Write + Send + 'static> QueuedSink<W> {
+    /// Creates a queue in front of a sink.
+    ///
+    /// # Arguments
+    ///
+    /// * `sink` - Where the batches are written to.
+    ///
+    /// # Returns
+    ///
+    /// A new `QueuedSink` instance.
+    pub fn new(sink: W) -> QueuedSink<W> {
+        QueuedSink {
+            sink: Some(sink),
+            thread: None,
+        }
+    }
+
+    /// Queues a batch for writing, starting the thread if none runs.
+    ///
+    /// Once the queue is full, this waits until the thread took the oldest batch.
+    ///
+    /// # Arguments
+    ///
+    /// * `batch` - The chunks to write.
+    ///
+    /// # Returns
+    ///
+    /// An error if writing an earlier batch failed.
+    fn queue(&mut self, batch: Vec<Bytes>) -> io::Result<()> {
+        if let Some(mut sink) = self.sink.take() {
+            let (batches, queue) = flume::bounded::<Vec<Bytes>>(QUEUE_LENGTH);
+            let thread = thread::spawn(move || {
+                // The thread stops at the first error, which the next batch picks up
+                let written = queue
+                    .iter()
+                    .try_for_each(|batch| write_batch(&mut sink, &batch));
+                (sink, written)
+            });
+            self.thread = Some((batches, thread));
+        }
+
+        let sent = match &self.thread {
+            Some((batches, _)) => batches.send(batch).is_ok(),
+            None => false,
+        };
+        match sent {
+            true => Ok(()),
+            false => self.wait().map(|_| ()),
+        }
+    }
+
+    /// Waits until every queued batch is written and takes the sink back.
+    ///
+    /// # Returns
+    ///
+    /// The sink, or the error of the first batch that could not be written. The sink is
+    /// taken back in either case.
+    pub fn wait(&mut self) -> io::Result<&mut W> {
+        let mut written = Ok(());
+        if let Some((batches, thread)) = self.thread.take() {
+            drop(batches);
+            let (sink, result) = thread
+                .join()
+                .map_err(|_| io::Error::other("Writing the file panicked."))?;
+            self.sink = Some(sink);
+            written = result;
+        }
+
+        written?;
+        self.sink
+            .as_mut()
+            .ok_or_else(|| io::Error::other("The file was lost while it was written."))
+    }
+
+    /// Returns the sink, unless a thread is writing to it.
+    pub fn get(&self) -> Option<&W> {
+        self.sink.as_ref()
+    }
+
+    /// Waits until every queued batch is written and returns the sink.
+    ///
+    /// # Returns
+    ///
+    /// The sink, or `None` if the thread writing to it panicked.
+    pub fn into_inner(mut self) -> Option<W> {
+        let _ = self.wait();
+        self.sink
+    }
+}
+
+/// Writes a batch of chunks with as few vectored writes as possible.
+///
+/// # Arguments
+///
+/// * `file` - The file to write to.
+/// * `batch` - The chunks to write.
+fn write_batch(file: &mut impl Write, batch: &[Bytes]) -> io::Result<()> {
+    batch.chunks(MAX_SLICES).try_for_each(|chunks| {
+        let mut slices = [IoSlice::new(&[]); MAX_SLICES];
+        for (slice, chunk) in slices.iter_mut().zip(chunks) {
+            *slice = IoSlice::new(chunk);
+        }
+        write_all_vectored(file, &mut slices[..chunks.len()])
+    })
+}
+
+/// Writes all slices, like the unstable `Write::write_all_vectored`.
+///
+/// # Arguments
+///
+/// * `file` - The file to write to.
+/// * `slices` - The data to write.
+fn write_all_vectored(file: &mut impl Write, mut slices: &mut [IoSlice<'_>]) -> io::Result<()> {
+    // Skip empty chunks, so a write of zero bytes means the file cannot take more
+    IoSlice::advance_slices(&mut slices, 0);
+    while !slices.is_empty() {
+        match file.write_vectored(slices) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(written) => IoSlice::advance_slices(&mut slices, written),
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::packets::HashAlgorithm;
+
+    #[test]
+    fn test_write_to() {
+        let mut writer = ChunkWriter::default();
+        let mut hasher = FileHasher::new(HashAlgorithm::Sha256);
+        let mut file = QueuedSink::new(vec![]);
+
+        // More chunks than fit into one vectored write, and an empty one
+        let chunks: Vec<Vec<u8>> = (0..40u8).map(|i| vec![i; i as usize]).collect();
+        for chunk in &chunks {
+            writer.push(Bytes::from(chunk.clone()));
+        }
+        writer.write_to(&mut file, &mut hasher).unwrap();
+
+        let expected = chunks.concat();
+        assert_eq!(*file.wait().unwrap(), expected);
+        let mut check = FileHasher::new(HashAlgorithm::Sha256);
+        check.update(&expected);
+        assert_eq!(hasher.finalize_reset(), check.finalize_reset());
+
+        // The batch is gone once written
+        writer.write_to(&mut file, &mut hasher).unwrap();
+        assert_eq!(file.wait().unwrap().len(), expected.len());
+    }
+
+    #[test]
+    fn test_write_to_reports_errors() {
+        let mut writer = ChunkWriter::default();
+        let mut hasher = FileHasher::new(HashAlgorithm::Sha256);
+        let mut file = QueuedSink::new(io::Cursor::new([0u8; 4]));
+
+        // The failed write turns up once the queue is waited for
+        writer.push(Bytes::from_static(b"too long"));
+        writer.write_to(&mut file, &mut hasher).unwrap();
+        let error = file.wait().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WriteZero);
+
+        // The sink is taken back all the same
+        assert_eq!(file.wait().unwrap().get_ref(), b"too ");
+        assert_eq!(file.into_inner().unwrap().position(), 4);
+    }
+
+    #[test]
+    fn test_decompress_reuses_buffer() {
+        let mut writer = ChunkWriter::default();
+        let data = b"caesar caesar caesar caesar".repeat(100);
+        let compressed = zstd::bulk::compress(&data, 3).unwrap();
+
+        let chunk = writer.decompress(&compressed).unwrap();
+        assert_eq!(chunk, data);
+        let address = chunk.as_ptr();
+
+        // Once the chunk is dropped, the next one takes its memory
+        drop(chunk);
+        let chunk = writer.decompress(&compressed).unwrap();
+        assert_eq!(chunk, data);
+        assert_eq!(chunk.as_ptr(), address);
+
+        // Frames without their size are still read
+        let streamed = zstd::stream::encode_all(data.as_slice(), 3).unwrap();
+        assert_eq!(writer.decompress(&streamed).unwrap(), data);
+        assert!(writer.decompress(b"not zstd").is_err());
+    }
+
+    #[test]
+    fn test_decompress_limits_size() {
+        let mut writer = ChunkWriter::default();
+        let data = vec![0u8; MAX_CHUNK_SIZE as usize + 1];
+
+        // A few bytes that expand beyond the limit are refused, with or without their size
+        let sized = zstd::bulk::compress(&data, 3).unwrap();
+        assert!(writer.decompress(&sized).is_err());
+        let streamed = zstd::stream::encode_all(data.as_slice(), 3).unwrap();
+        assert!(get_frame_content_size(&streamed).unwrap().is_none());
+        assert!(writer.decompress(&streamed).is_err());
+    }
+}
@@ -1,8 +1,23 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::Arc,
+    time::Duration,
+};
+use reqwest::Client;
 use tokio::sync::RwLock;
+use tracing::error;
 
+use crate::relay::blocklist::Blocklist;
+use crate::relay::events::{RoomEvent, RoomWatchers};
+use crate::relay::federation::{self, ForwardedRoom};
+use crate::relay::limiter::{JoinAttempts, RateLimiter};
+use crate::relay::link::{self, SpooledFile};
+use crate::relay::punch::Waiting;
+use crate::relay::qos::Qos;
 use crate::relay::room::Room;
-use crate::relay::transfer::TransferResponse;
+use crate::relay::store::TransferStore;
+use crate::relay::transfer::{HistoryEntry, TransferResponse};
 
 /// State of the application.
 ///
@@ -15,6 +30,43 @@ pub struct AppState {
     pub rooms: HashMap<String, Room>,
     /// Vector of transfers.
     pub transfers: Vec<TransferResponse>,
+    /// Failed join attempts, where the key is the IP address of the client.
+    pub failed_joins_by_ip: HashMap<IpAddr, JoinAttempts>,
+    /// The requests of every client address, counted against the rate limits.
+    pub rate_limiter: RateLimiter,
+    /// Decides which frames are forwarded first when the relay is under load.
+    pub qos: Arc<Qos>,
+    /// The recent transfers of the authenticated users, newest first, where the key
+    /// is the subject of the user.
+    pub history: HashMap<String, VecDeque<HistoryEntry>>,
+    /// The database the transfers are written through to. If `None`, they only live
+    /// in memory and are lost when the relay restarts.
+    pub store: Option<TransferStore>,
+    /// The watchers of the rooms, who are told when clients join or leave and when
+    /// the transfer is complete.
+    pub watchers: RoomWatchers,
+    /// The peers that wait at the rendezvous of hole punching, where the key is the
+    /// ID of their room.
+    pub punches: HashMap<String, Waiting>,
+    /// How long a room may wait for its members before it is closed. A zero TTL keeps
+    /// rooms until their members leave.
+    pub room_ttl: Duration,
+    /// The client addresses the relay refuses to serve.
+    pub blocklist: Blocklist,
+    /// The encrypted files of download links, where the key is the ID of the link.
+    pub links: HashMap<String, SpooledFile>,
+    /// The largest file in bytes a download link may have. Zero offers no links.
+    pub link_size: u64,
+    /// The most clients a room may hold, the sender included.
+    pub max_room_size: usize,
+    /// The relay that transfers unknown to this relay are looked up at. If `None`,
+    /// they are not found.
+    pub upstream: Option<String>,
+    /// The rooms of the transfers that were found at the upstream relay, where the key
+    /// is the ID of the room. Clients that join them are connected through.
+    pub forwarded: HashMap<String, ForwardedRoom>,
+    /// The client the requests to the upstream relay are sent with.
+    pub federation: Client,
 }
 
 impl AppState {
@@ -32,16 +84,258 @@ impl AppState {
         let app_state = AppState {
             rooms: HashMap::new(),
             transfers: Vec::new(),
+            failed_joins_by_ip: HashMap::new(),
+            rate_limiter: RateLimiter::default(),
+            qos: Arc::new(Qos::default()),
+            history: HashMap::new(),
+            store: None,
+            watchers: RoomWatchers::default(),
+            punches: HashMap::new(),
+            room_ttl: Duration::ZERO,
+            blocklist: Blocklist::default(),
+            links: HashMap::new(),
+            link_size: 0,
+            max_room_size: Room::MAX_ROOM_SIZE,
+            upstream: None,
+            forwarded: HashMap::new(),
+            federation: federation::client(),
         };
 
         // Wrap the `app_state` in a `RwLock` to make it thread-safe.
         Arc::new(RwLock::new(app_state))
     }
+
+    /// Records a failed join attempt of a client address.
+    ///
+    /// Only the address is tracked, so clients that guess the ID of a room cannot slow
+    /// down the legitimate members of that room. Old attempts are removed by
+    /// `remove_expired_joins`.
+    ///
+    /// # Arguments
+    ///
+    /// * `ip` - The IP address of the client.
+    ///
+    /// # Returns
+    ///
+    /// The delay that should be applied before answering the client.
+    pub fn record_failed_join(&mut self, ip: IpAddr) -> Duration {
+        self.failed_joins_by_ip
+            .entry(ip)
+            .or_default()
+            .record_failure()
+    }
+
+    /// Forgets the failed join attempts that are older than `JoinAttempts::RESET_AFTER`.
+    ///
+    /// # Returns
+    ///
+    /// The number of client addresses that were forgotten.
+    pub fn remove_expired_joins(&mut self) -> usize {
+        let count = self.failed_joins_by_ip.len();
+        self.failed_joins_by_ip
+            .retain(|_, attempts| !attempts.is_expired());
+        count - self.failed_joins_by_ip.len()
+    }
+
+    /// The number of transfers kept in the history of every user.
+    pub const HISTORY_LIMIT: usize = 50;
+
+    /// Adds a newly registered transfer to the history of its owner.
+    ///
+    /// The oldest entries are dropped once the history holds `HISTORY_LIMIT` entries.
+    ///
+    /// # Arguments
+    ///
+    /// * `owner` - The subject of the user who registered the transfer.
+    /// * `name` - The hashed name of the transfer.
+    pub fn record_transfer(&mut self, owner: &str, name: &str) {
+        let history = self.history.entry(owner.to_string()).or_default();
+        history.push_front(HistoryEntry::new(name.to_string()));
+        history.truncate(Self::HISTORY_LIMIT);
+    }
+
+    /// Marks the latest transfer with the given name as completed in the history of
+    /// its owner.
+    ///
+    /// # Arguments
+    ///
+    /// * `owner` - The subject of the user who registered the transfer.
+    /// * `name` - The hashed name of the transfer.
+    pub fn complete_transfer(&mut self, owner: &str, name: &str) {
+        if let Some(entry) = self
+            .history
+            .get_mut(owner)
+            .and_then(|history| history.iter_mut().find(|entry| entry.name == name))
+        {
+            entry.complete();
+        }
+    }
+
+    /// Writes a new or updated transfer through to the database.
+    ///
+    /// The handlers call it while they hold the lock on the state, so the writes reach
+    /// the database in the order of the changes. A failed write is only logged. The
+    /// transfer is still served from memory, it is just lost if the relay restarts
+    /// before the next successful write.
+    ///
+    /// # Arguments
+    ///
+    /// * `transfer` - The transfer to save.
+    pub async fn persist_transfer(&self, transfer: &TransferResponse) {
+        if let Some(store) = &self.store {
+            if let Err(e) = store.save(transfer).await {
+                error!("Failed to save the transfer '{}': {e}", transfer.name);
+            }
+        }
+    }
+
+    /// Removes a finished transfer from the database.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the transfer.
+    pub async fn forget_transfer(&self, name: &str) {
+        if let Some(store) = &self.store {
+            if let Err(e) = store.remove(name).await {
+                error!("Failed to remove the transfer '{name}': {e}");
+            }
+        }
+    }
+
+    /// Removes the rooms that waited longer than the TTL for their members.
+    ///
+    /// # Arguments
+    ///
+    /// * `ttl` - How long a room may wait for its members.
+    ///
+    /// # Returns
+    ///
+    /// The removed rooms with their IDs, so their remaining members can be notified.
+    /// Their watchers are told right away.
+    pub fn remove_expired_rooms(&mut self, ttl: Duration) -> Vec<(String, Room)> {
+        let expired: Vec<String> = self
+            .rooms
+            .iter()
+            .filter(|(_, room)| room.is_expired(ttl))
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        for id in &expired {
+            self.watchers.finish(id, RoomEvent::Expired);
+        }
+
+        expired
+            .into_iter()
+            .filter_map(|id| self.rooms.remove_entry(&id))
+            .collect()
+    }
+
+    /// Removes the files of download links that waited longer than the TTL.
+    ///
+    /// # Arguments
+    ///
+    /// * `ttl` - How long a file may wait for its download.
+    ///
+    /// # Returns
+    ///
+    /// The number of removed files.
+    pub fn remove_expired_links(&mut self, ttl: Duration) -> usize {
+        let count = self.links.len();
+        self.links.retain(|_, file| !file.is_expired(ttl));
+        count - self.links.len()
+    }
+
+    /// Returns `true` if a file of the given size would exceed `link::MAX_LINKS` or
+    /// `link::MAX_SPOOLED_BYTES`.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - The size of the file in bytes, zero to check if any file fits.
+    pub fn is_spool_full(&self, size: usize) -> bool {
+        let spooled: usize = self.links.values().map(|file| file.data.len()).sum();
+        self.links.len() >= link::MAX_LINKS
+            || spooled.saturating_add(size) > link::MAX_SPOOLED_BYTES
+    }
+
+    /// Removes the forwarded rooms that were looked up longer than the TTL ago.
+    ///
+    /// # Arguments
+    ///
+    /// * `ttl` - How long a room may wait for its members.
+    ///
+    /// # Returns
+    ///
+    /// The number of removed rooms.
+    pub fn remove_expired_forwards(&mut self, ttl: Duration) -> usize {
+        let count = self.forwarded.len();
+        self.forwarded.retain(|_, room| !room.is_expired(ttl));
+        count - self.forwarded.len()
+    }
+
+    /// The most rooms of the upstream relay that are remembered at the same time.
+    pub const MAX_FORWARDED: usize = 1024;
+
+    /// Returns `true` if no more rooms of the upstream relay can be remembered.
+    ///
+    /// Lookups are anonymous, so the rooms are capped in number as well as by the TTL,
+    /// which may be zero.
+    pub fn is_forwarding_full(&self) -> bool {
+        self.forwarded.len() >= Self::MAX_FORWARDED
+    }
+
+    /// Remembers the room of a transfer that was found at the upstream relay.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the room.
+    /// * `room` - The room at the upstream relay.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the room was not remembered, because `MAX_FORWARDED` rooms are.
+    pub fn forward(&mut self, id: String, room: ForwardedRoom) -> bool {
+        if !self.forwarded.contains_key(&id) && self.is_forwarding_full() {
+            return false;
+        }
+        self.forwarded.insert(id, room);
+        true
+    }
+
+    /// Removes the forwarded room of a transfer, e.g. once it is complete.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The hashed name of the transfer.
+    ///
+    /// # Returns
+    ///
+    /// The removed room, or `None` if the transfer was not found at the upstream relay.
+    pub fn take_forwarded(&mut self, name: &str) -> Option<ForwardedRoom> {
+        let id = self
+            .forwarded
+            .iter()
+            .find(|(_, room)| room.name == name)
+            .map(|(id, _)| id.clone())?;
+        self.forwarded.remove(&id)
+    }
+
+    /// Returns `true` if the client address has to wait before its next join attempt.
+    ///
+    /// # Arguments
+    ///
+    /// * `ip` - The IP address of the client.
+    pub fn is_join_blocked(&self, ip: &IpAddr) -> bool {
+        self.failed_joins_by_ip
+            .get(ip)
+            .is_some_and(JoinAttempts::is_blocked)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::relay::transfer::TransferStatus;
+    use bytes::Bytes;
     use std::sync::Arc;
 
     #[test]
@@ -50,4 +344,141 @@ mod tests {
 
         assert!(Arc::ptr_eq(&app_state, &app_state.clone()));
     }
+
+    #[tokio::test]
+    async fn test_record_failed_join() {
+        let app_state = AppState::new();
+        let mut state = app_state.write().await;
+        let ip = IpAddr::from([127, 0, 0, 1]);
+
+        assert!(!state.is_join_blocked(&ip));
+        state.record_failed_join(ip);
+        let delay = state.record_failed_join(ip);
+
+        assert_eq!(delay, Duration::from_millis(500));
+        assert!(state.is_join_blocked(&ip));
+        assert_eq!(state.remove_expired_joins(), 0);
+
+        // Attempts are only forgotten by the periodic cleanup
+        let other = IpAddr::from([127, 0, 0, 2]);
+        state.record_failed_join(other);
+        state.failed_joins_by_ip.get_mut(&other).unwrap().last_failure -=
+            JoinAttempts::RESET_AFTER;
+        assert_eq!(state.remove_expired_joins(), 1);
+        assert!(state.failed_joins_by_ip.contains_key(&ip));
+    }
+
+    #[tokio::test]
+    async fn test_history() {
+        let app_state = AppState::new();
+        let mut state = app_state.write().await;
+
+        state.record_transfer("alice", "first");
+        state.record_transfer("alice", "second");
+        state.complete_transfer("alice", "first");
+        // Other users cannot complete transfers in a foreign history
+        state.complete_transfer("bob", "second");
+
+        let history = &state.history["alice"];
+        assert_eq!(history[0].name, "second");
+        assert_eq!(history[0].status, TransferStatus::Pending);
+        assert_eq!(history[1].status, TransferStatus::Completed);
+        assert!(history[1].completed.is_some());
+
+        // Only the most recent transfers are kept
+        for index in 0..AppState::HISTORY_LIMIT {
+            state.record_transfer("alice", &index.to_string());
+        }
+        assert_eq!(state.history["alice"].len(), AppState::HISTORY_LIMIT);
+        assert!(!state.history.contains_key("bob"));
+    }
+
+    #[tokio::test]
+    async fn test_remove_expired_rooms() {
+        let app_state = AppState::new();
+        let mut state = app_state.write().await;
+        let ttl = Duration::from_secs(60);
+
+        let mut stale = Room::new(Room::DEFAULT_ROOM_SIZE);
+        stale.created -= ttl;
+        state.rooms.insert("stale".into(), stale);
+        state
+            .rooms
+            .insert("fresh".into(), Room::new(Room::DEFAULT_ROOM_SIZE));
+        let mut watcher = state.watchers.subscribe("stale");
+
+        let removed = state.remove_expired_rooms(ttl);
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].0, "stale");
+        assert!(state.rooms.contains_key("fresh"));
+        assert_eq!(watcher.recv().await.unwrap(), RoomEvent::Expired);
+    }
+
+    #[tokio::test]
+    async fn test_remove_expired_links() {
+        let app_state = AppState::new();
+        let mut state = app_state.write().await;
+        let ttl = Duration::from_secs(60);
+
+        let mut stale = SpooledFile::new(Bytes::from_static(b"stale"));
+        stale.created -= ttl;
+        state.links.insert("stale".into(), stale);
+        state.links.insert(
+            "fresh".into(),
+            SpooledFile::new(Bytes::from_static(b"fresh")),
+        );
+
+        assert_eq!(state.remove_expired_links(ttl), 1);
+        assert!(state.links.contains_key("fresh"));
+    }
+
+    #[tokio::test]
+    async fn test_spool_is_capped() {
+        let app_state = AppState::new();
+        let mut state = app_state.write().await;
+
+        assert!(!state.is_spool_full(link::MAX_SPOOLED_BYTES));
+        assert!(state.is_spool_full(link::MAX_SPOOLED_BYTES + 1));
+
+        for index in 0..link::MAX_LINKS {
+            let file = SpooledFile::new(Bytes::from_static(b"sealed"));
+            state.links.insert(index.to_string(), file);
+        }
+        assert!(state.is_spool_full(0));
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_rooms() {
+        let app_state = AppState::new();
+        let mut state = app_state.write().await;
+        let ttl = Duration::from_secs(60);
+
+        let mut stale = ForwardedRoom::new("stale".into(), "ws://upstream".into(), None);
+        stale.created -= ttl;
+        state.forwarded.insert("room-1".into(), stale);
+        let fresh = ForwardedRoom::new("fresh".into(), "ws://upstream".into(), None);
+        state.forwarded.insert("room-2".into(), fresh.clone());
+
+        assert_eq!(state.remove_expired_forwards(ttl), 1);
+        assert_eq!(state.take_forwarded("fresh"), Some(fresh));
+        assert_eq!(state.take_forwarded("fresh"), None);
+        assert!(state.forwarded.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_rooms_are_capped() {
+        let app_state = AppState::new();
+        let mut state = app_state.write().await;
+        let room = |name: &str| ForwardedRoom::new(name.into(), "ws://upstream".into(), None);
+
+        for index in 0..AppState::MAX_FORWARDED {
+            assert!(state.forward(index.to_string(), room("name")));
+        }
+        assert!(state.is_forwarding_full());
+        assert!(!state.forward("new".into(), room("new")));
+        // A room that is already remembered may still be looked up again
+        assert!(state.forward("0".into(), room("again")));
+        assert_eq!(state.forwarded.len(), AppState::MAX_FORWARDED);
+        assert_eq!(state.forwarded["0"].name, "again");
+    }
 }
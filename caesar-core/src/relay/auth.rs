@@ -0,0 +1,491 @@
+//! Optional authentication of the relay's clients with OpenID Connect access tokens.
+//!
+//! A relay that is shared by a team can require every request to carry a bearer token
+//! issued by the team's identity provider. The token is checked against the keys the
+//! provider publishes at its JWKS URL, and the subject of the token becomes the identity
+//! of the request. Transfers are recorded with this identity, so the audit log shows
+//! who registered them, and every user may only register a limited number at a time.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_client_ip::SecureClientIp;
+use headers::{authorization::Bearer, Authorization, HeaderMapExt};
+use jsonwebtoken::{
+    jwk::{JwkSet, KeyAlgorithm},
+    Algorithm, DecodingKey, Validation,
+};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::{Mutex, RwLock};
+use tracing::{debug, info, warn};
+
+use crate::relay::AUDIT_TARGET;
+
+/// The shortest time between two downloads of the keys.
+///
+/// An unknown key ID makes the relay download the keys again, since the provider may
+/// have rotated them. This keeps tokens with made up key IDs from flooding the provider.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a connection to the identity provider may take to open.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a download of the keys may take, the connection included.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The identity provider the relay accepts tokens from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthConfig {
+    /// The issuer the tokens must be issued by, e.g. `https://login.example.com/`.
+    pub issuer: String,
+    /// The audience the tokens must be issued for.
+    pub audience: String,
+    /// The URL the provider publishes its signing keys at.
+    pub jwks_url: String,
+    /// How many transfers a user may have registered at a time. Zero means no limit.
+    pub quota: usize,
+}
+
+/// The authenticated user of a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    /// The subject of the token.
+    pub subject: String,
+    /// How many transfers the user may have registered at a time. Zero means no limit.
+    pub quota: usize,
+}
+
+/// Why a token was rejected.
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    /// The request did not carry a bearer token.
+    #[error("Missing bearer token.")]
+    MissingToken,
+
+    /// The token is malformed, expired or not signed by the identity provider.
+    #[error("Invalid token: {0}")]
+    InvalidToken(String),
+
+    /// The signing keys of the identity provider could not be downloaded.
+    #[error("Failed to fetch the signing keys: {0}")]
+    Keys(String),
+}
+
+/// The claims of a token the relay reads.
+///
+/// The issuer, audience and expiry are checked while the token is decoded.
+#[derive(Debug, Deserialize)]
+struct Claims {
+    /// The user the token was issued to.
+    sub: String,
+}
+
+/// The signing keys of the identity provider and when they were downloaded.
+#[derive(Debug)]
+struct Keys {
+    /// The downloaded keys.
+    set: JwkSet,
+    /// When the keys were downloaded, or `None` before the first download.
+    fetched: Option<Instant>,
+}
+
+impl Default for Keys {
+    fn default() -> Self {
+        Keys {
+            set: JwkSet { keys: Vec::new() },
+            fetched: None,
+        }
+    }
+}
+
+impl Keys {
+    /// Returns whether the keys were downloaded too recently to download them again.
+    fn is_fresh(&self) -> bool {
+        self.fetched
+            .is_some_and(|fetched| fetched.elapsed() < REFRESH_INTERVAL)
+    }
+}
+
+/// Validates the tokens of incoming requests.
+#[derive(Debug)]
+pub struct Authenticator {
+    /// The identity provider tokens are accepted from. It may change when the relay
+    /// reloads its configuration.
+    config: RwLock<AuthConfig>,
+    /// The cached signing keys of the identity provider.
+    keys: RwLock<Keys>,
+    /// Held while the keys are downloaded, so only one request downloads them.
+    refresh: Mutex<()>,
+    /// The client the keys are downloaded with.
+    http: reqwest::Client,
+}
+
+impl Authenticator {
+    /// Creates a new authenticator. The keys are downloaded with the first token.
+    ///
+    /// All downloads share one client, whose timeouts keep a slow identity provider
+    /// from holding up the requests with a new key ID.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The identity provider tokens are accepted from.
+    ///
+    /// # Returns
+    ///
+    /// A new `Authenticator` instance.
+    pub fn new(config: AuthConfig) -> Authenticator {
+        Authenticator {
+            config: RwLock::new(config),
+            keys: RwLock::new(Keys::default()),
+            refresh: Mutex::new(()),
+            http: reqwest::Client::builder()
+                .connect_timeout(CONNECT_TIMEOUT)
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .expect("Failed to create the client for the signing keys."),
+        }
+    }
+
+    /// Checks a token and returns the identity it was issued to.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The bearer token of the request.
+    ///
+    /// # Returns
+    ///
+    /// The identity of the user, or why the token was rejected.
+    pub async fn authenticate(&self, token: &str) -> Result<Identity, AuthError> {
+        let header = jsonwebtoken::decode_header(token)
+            .map_err(|e| AuthError::InvalidToken(e.to_string()))?;
+
+        // Only asymmetric signatures can be checked with published keys
+        if matches!(
+            header.alg,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        ) {
+            return Err(AuthError::InvalidToken(format!(
+                "Unsupported algorithm {:?}",
+                header.alg
+            )));
+        }
+
+        let kid = header
+            .kid
+            .ok_or_else(|| AuthError::InvalidToken("Missing key ID".into()))?;
+        let key = self.key(&kid, header.alg).await?;
+
+        validate(&*self.config.read().await, &key, header.alg, token)
+    }
+
+    /// Accepts tokens by the given identity provider from now on.
+    ///
+    /// The cached keys are dropped if the provider publishes them elsewhere, so the next
+    /// token downloads the keys of the new provider.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The identity provider tokens are accepted from.
+    pub async fn reconfigure(&self, config: AuthConfig) {
+        let moved = {
+            let mut current = self.config.write().await;
+            let moved = current.jwks_url != config.jwks_url;
+            *current = config;
+            moved
+        };
+        if moved {
+            *self.keys.write().await = Keys::default();
+        }
+    }
+
+    /// Looks up the key with the given ID, downloading the keys again if it is unknown.
+    ///
+    /// # Arguments
+    ///
+    /// * `kid` - The ID of the key the token was signed with.
+    /// * `alg` - The algorithm the token was signed with.
+    ///
+    /// # Returns
+    ///
+    /// The key to check the signature with.
+    async fn key(&self, kid: &str, alg: Algorithm) -> Result<DecodingKey, AuthError> {
+        {
+            let keys = self.keys.read().await;
+            if let Some(key) = find_key(&keys.set, kid, alg) {
+                return key;
+            }
+            if keys.is_fresh() {
+                return Err(AuthError::InvalidToken(format!("Unknown key ID '{kid}'")));
+            }
+        }
+
+        // Another request may have downloaded the keys while this one waited
+        let _refresh = self.refresh.lock().await;
+        {
+            let keys = self.keys.read().await;
+            if keys.is_fresh() {
+                return find_key(&keys.set, kid, alg).unwrap_or_else(|| {
+                    Err(AuthError::InvalidToken(format!("Unknown key ID '{kid}'")))
+                });
+            }
+        }
+
+        // The keys are downloaded without locking them, so requests with a known key ID
+        // go on meanwhile
+        let jwks_url = self.config.read().await.jwks_url.clone();
+        debug!("Fetching signing keys from {jwks_url}");
+        let set = self.fetch(&jwks_url).await;
+
+        let mut keys = self.keys.write().await;
+        // The keys of a provider the relay no longer accepts tokens from are dropped
+        if self.config.read().await.jwks_url != jwks_url {
+            return Err(AuthError::Keys("The identity provider was changed.".into()));
+        }
+        keys.fetched = Some(Instant::now());
+        keys.set = set?;
+
+        find_key(&keys.set, kid, alg)
+            .unwrap_or_else(|| Err(AuthError::InvalidToken(format!("Unknown key ID '{kid}'"))))
+    }
+
+    /// Downloads the signing keys of the identity provider.
+    ///
+    /// # Arguments
+    ///
+    /// * `jwks_url` - The URL the provider publishes its signing keys at.
+    ///
+    /// # Returns
+    ///
+    /// The keys, or why they could not be downloaded.
+    async fn fetch(&self, jwks_url: &str) -> Result<JwkSet, AuthError> {
+        self.http
+            .get(jwks_url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| AuthError::Keys(e.to_string()))?
+            .json()
+            .await
+            .map_err(|e| AuthError::Keys(e.to_string()))
+    }
+}
+
+/// Finds the key with the given ID in a key set.
+///
+/// # Arguments
+///
+/// * `set` - The keys of the identity provider.
+/// * `kid` - The ID of the key the token was signed with.
+/// * `alg` - The algorithm the token was signed with.
+///
+/// # Returns
+///
+/// `None` if the set has no key with this ID, otherwise the key or why it cannot be
+/// used for this token.
+fn find_key(set: &JwkSet, kid: &str, alg: Algorithm) -> Option<Result<DecodingKey, AuthError>> {
+    let jwk = set.find(kid)?;
+
+    // A key that is bound to an algorithm must not be used with another one
+    if let Some(key_alg) = jwk.common.key_algorithm {
+        if !key_algorithm_matches(key_alg, alg) {
+            return Some(Err(AuthError::InvalidToken(format!(
+                "Key '{kid}' is not meant for {alg:?}"
+            ))));
+        }
+    }
+
+    Some(DecodingKey::from_jwk(jwk).map_err(|e| AuthError::Keys(e.to_string())))
+}
+
+/// Returns whether a key bound to an algorithm may check a token signed with another.
+///
+/// # Arguments
+///
+/// * `key_alg` - The algorithm the key is bound to.
+/// * `alg` - The algorithm of the token.
+fn key_algorithm_matches(key_alg: KeyAlgorithm, alg: Algorithm) -> bool {
+    format!("{key_alg:?}") == format!("{alg:?}")
+}
+
+/// Checks the signature and the claims of a token.
+///
+/// # Arguments
+///
+/// * `config` - The identity provider tokens are accepted from.
+/// * `key` - The key to check the signature with.
+/// * `alg` - The algorithm the token was signed with.
+/// * `token` - The token to check.
+///
+/// # Returns
+///
+/// The identity of the user, or why the token was rejected.
+fn validate(
+    config: &AuthConfig,
+    key: &DecodingKey,
+    alg: Algorithm,
+    token: &str,
+) -> Result<Identity, AuthError> {
+    let mut validation = Validation::new(alg);
+    validation.set_issuer(&[&config.issuer]);
+    validation.set_audience(&[&config.audience]);
+    validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+    let data = jsonwebtoken::decode::<Claims>(token, key, &validation)
+        .map_err(|e| AuthError::InvalidToken(e.to_string()))?;
+
+    Ok(Identity {
+        subject: data.claims.sub,
+        quota: config.quota,
+    })
+}
+
+/// Middleware that rejects requests without a valid token.
+///
+/// The identity of an accepted request is added to its extensions, where the handlers
+/// can pick it up with `Option<Extension<Identity>>`.
+///
+/// # Arguments
+///
+/// * `auth` - The authenticator of the relay.
+/// * `ip` - The IP address of the client.
+/// * `request` - The incoming request.
+/// * `next` - The rest of the middleware stack.
+///
+/// # Returns
+///
+/// The response of the handler, or `401 Unauthorized` if the token was rejected.
+pub async fn require_token(
+    State(auth): State<Arc<Authenticator>>,
+    SecureClientIp(ip): SecureClientIp,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let result = match request.headers().typed_get::<Authorization<Bearer>>() {
+        Some(Authorization(bearer)) => auth.authenticate(bearer.token()).await,
+        None => Err(AuthError::MissingToken),
+    };
+
+    match result {
+        Ok(identity) => {
+            info!(
+                target: AUDIT_TARGET,
+                ip = %ip,
+                user = %identity.subject,
+                path = %request.uri().path(),
+                "Authenticated request"
+            );
+            request.extensions_mut().insert(identity);
+            next.run(request).await
+        }
+        Err(error) => {
+            warn!(
+                target: AUDIT_TARGET,
+                ip = %ip,
+                path = %request.uri().path(),
+                "Rejected request: {error}"
+            );
+            (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Bearer")],
+                Json(json!({
+                    "message": error.to_string()
+                })),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde::Serialize;
+
+    #[derive(Serialize)]
+    struct TestClaims<'a> {
+        sub: &'a str,
+        iss: &'a str,
+        aud: &'a str,
+        exp: u64,
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = AuthConfig {
+            issuer: "https://login.example.com/".into(),
+            audience: "caesar".into(),
+            jwks_url: "https://login.example.com/jwks".into(),
+            quota: 3,
+        };
+        let secret = b"test-secret";
+        let exp = jsonwebtoken::get_current_timestamp() + 60;
+        let sign = |claims: &TestClaims| {
+            jsonwebtoken::encode(
+                &Header::default(),
+                claims,
+                &EncodingKey::from_secret(secret),
+            )
+            .unwrap()
+        };
+        let check = |token: &str| {
+            validate(
+                &config,
+                &DecodingKey::from_secret(secret),
+                Algorithm::HS256,
+                token,
+            )
+        };
+
+        let claims = TestClaims {
+            sub: "alice",
+            iss: "https://login.example.com/",
+            aud: "caesar",
+            exp,
+        };
+        assert_eq!(
+            check(&sign(&claims)).unwrap(),
+            Identity {
+                subject: "alice".into(),
+                quota: 3
+            }
+        );
+
+        // Tokens of another issuer, for another audience or expired ones are rejected
+        let foreign = TestClaims {
+            iss: "https://evil.example.com/",
+            ..claims
+        };
+        assert!(check(&sign(&foreign)).is_err());
+        let other_audience = TestClaims {
+            aud: "other",
+            ..claims
+        };
+        assert!(check(&sign(&other_audience)).is_err());
+        let expired = TestClaims {
+            exp: exp - 3600,
+            ..claims
+        };
+        assert!(check(&sign(&expired)).is_err());
+    }
+
+    #[test]
+    fn test_key_algorithm_matches() {
+        assert!(key_algorithm_matches(KeyAlgorithm::RS256, Algorithm::RS256));
+        assert!(key_algorithm_matches(KeyAlgorithm::ES256, Algorithm::ES256));
+        assert!(!key_algorithm_matches(
+            KeyAlgorithm::RS256,
+            Algorithm::PS256
+        ));
+    }
+}
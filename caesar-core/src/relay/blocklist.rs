@@ -0,0 +1,213 @@
+//! Client addresses the relay refuses to serve.
+//!
+//! Operators of a public relay can block single addresses like `198.51.100.7` or whole
+//! networks like `203.0.113.0/24` that abuse it. Blocked clients get `403 Forbidden` for
+//! every request, including the WebSocket upgrade.
+
+use std::{net::IpAddr, str::FromStr, sync::Arc};
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_client_ip::SecureClientIp;
+use serde_json::json;
+use tokio::sync::RwLock;
+use tracing::warn;
+
+use crate::relay::{appstate::AppState, AUDIT_TARGET};
+
+/// A network of client addresses, or a single address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    /// The first address of the network.
+    pub address: IpAddr,
+    /// The number of leading bits that all addresses of the network share.
+    pub prefix: u8,
+}
+
+impl Network {
+    /// Returns `true` if the address belongs to the network.
+    ///
+    /// IPv4 addresses mapped into IPv6, like `::ffff:198.51.100.7`, belong to the IPv4
+    /// networks they map.
+    ///
+    /// # Arguments
+    ///
+    /// * `ip` - The address of the client.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(*ip, IpAddr::V4),
+            IpAddr::V4(_) => *ip,
+        };
+        match (self.address, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => prefix_matches(
+                u32::from(network).into(),
+                u32::from(ip).into(),
+                32,
+                self.prefix,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(network.into(), ip.into(), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    /// Parses a network like `203.0.113.0/24` or `2001:db8::/32`, or a single address.
+    fn from_str(text: &str) -> Result<Network, String> {
+        let invalid = || format!("'{text}' is neither an IP address nor a network.");
+        let (address, prefix) = match text.trim().split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (text.trim(), None),
+        };
+        let address: IpAddr = address.parse().map_err(|_| invalid())?;
+        let bits = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= bits)
+                .ok_or_else(invalid)?,
+            None => bits,
+        };
+
+        Ok(Network { address, prefix })
+    }
+}
+
+/// Returns `true` if the leading bits of two addresses are the same.
+///
+/// # Arguments
+///
+/// * `network` - The address of the network.
+/// * `ip` - The address to check.
+/// * `bits` - The length of the addresses in bits.
+/// * `prefix` - The number of leading bits to compare.
+fn prefix_matches(network: u128, ip: u128, bits: u8, prefix: u8) -> bool {
+    if prefix == 0 {
+        return true;
+    }
+    let shift = u32::from(bits - prefix);
+    network >> shift == ip >> shift
+}
+
+/// The networks whose clients the relay refuses to serve.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Blocklist {
+    /// The blocked networks.
+    pub networks: Vec<Network>,
+}
+
+impl Blocklist {
+    /// Parses a blocklist from its entries.
+    ///
+    /// # Arguments
+    ///
+    /// * `entries` - Addresses and networks like `203.0.113.0/24`.
+    ///
+    /// # Returns
+    ///
+    /// The blocklist, or the message of the first entry that cannot be parsed.
+    pub fn parse<S: AsRef<str>>(entries: &[S]) -> Result<Blocklist, String> {
+        let networks = entries
+            .iter()
+            .map(|entry| entry.as_ref().parse())
+            .collect::<Result<Vec<Network>, String>>()?;
+
+        Ok(Blocklist { networks })
+    }
+
+    /// Returns `true` if the address is blocked.
+    ///
+    /// # Arguments
+    ///
+    /// * `ip` - The address of the client.
+    pub fn is_blocked(&self, ip: &IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(ip))
+    }
+}
+
+/// Middleware that rejects the requests of blocked clients.
+///
+/// # Arguments
+///
+/// * `state` - The shared state of the server.
+/// * `ip` - The IP address of the client.
+/// * `request` - The incoming request.
+/// * `next` - The rest of the middleware stack.
+///
+/// # Returns
+///
+/// The response of the handler, or `403 Forbidden` if the client is blocked.
+pub async fn reject_blocked(
+    State(state): State<Arc<RwLock<AppState>>>,
+    SecureClientIp(ip): SecureClientIp,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !state.read().await.blocklist.is_blocked(&ip) {
+        return next.run(request).await;
+    }
+
+    warn!(
+        target: AUDIT_TARGET,
+        ip = %ip,
+        path = %request.uri().path(),
+        "Rejected request of a blocked client"
+    );
+    (
+        StatusCode::FORBIDDEN,
+        Json(json!({
+            "message": "This client is blocked."
+        })),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv6Addr;
+
+    #[test]
+    fn test_blocklist() {
+        let blocklist =
+            Blocklist::parse(&["203.0.113.0/24", "198.51.100.7", "2001:db8::/32"]).unwrap();
+        let blocked = |ip: &str| blocklist.is_blocked(&ip.parse().unwrap());
+
+        assert!(blocked("203.0.113.200"));
+        assert!(blocked("198.51.100.7"));
+        assert!(blocked("::ffff:198.51.100.7"));
+        assert!(blocked("2001:db8:1::5"));
+        assert!(!blocked("203.0.114.1"));
+        assert!(!blocked("198.51.100.8"));
+        assert!(!blocked("2001:db9::1"));
+        assert!(Blocklist::parse(&["0.0.0.0/0"])
+            .unwrap()
+            .is_blocked(&"192.0.2.1".parse().unwrap()));
+        assert!(!Blocklist::default().is_blocked(&Ipv6Addr::LOCALHOST.into()));
+    }
+
+    #[test]
+    fn test_parse_network() {
+        assert_eq!(
+            "10.0.0.0/8".parse::<Network>().unwrap(),
+            Network {
+                address: "10.0.0.0".parse().unwrap(),
+                prefix: 8
+            }
+        );
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+        assert!("example.com".parse::<Network>().is_err());
+        assert!(Blocklist::parse(&["10.0.0.1", "nonsense"]).is_err());
+    }
+}
@@ -1,29 +1,36 @@
 use axum::extract::ws::Message;
 use futures_util::{future::join_all, stream::SplitSink, SinkExt};
-use std::{sync::Arc, vec};
-use tokio::{sync::Mutex, sync::RwLock};
-use tracing::{debug, error};
+use std::{net::IpAddr, sync::Arc, vec};
+use tokio::{sync::Mutex, sync::RwLock, time::sleep};
+use tracing::{debug, error, info, warn};
 
 use crate::relay::appstate::AppState;
+use crate::relay::events::RoomEvent;
+use crate::relay::limiter::RateLimited;
 use crate::relay::room::Room;
-use crate::relay::RequestPacket;
+use crate::relay::{Priority, RequestPacket};
 use crate::relay::ResponsePacket;
+use crate::relay::AUDIT_TARGET;
 use uuid::Uuid;
 
 /// Type alias for a synchronized WebSocket sender.
 /// 
 /// This is used to send messages to a WebSocket connection.
-type Sender = Arc<Mutex<SplitSink<axum::extract::ws::WebSocket, Message>>>;
+pub(crate) type Sender = Arc<Mutex<SplitSink<axum::extract::ws::WebSocket, Message>>>;
 
 /// Struct representing a WebSocket client.
 /// 
-/// This struct contains a WebSocket sender and an optional room ID.
+/// This struct contains a WebSocket sender, the client's IP address and an optional room ID.
 /// The sender is used to send messages to the WebSocket connection,
 /// while the room ID is used to identify the client's room.
 #[derive(Debug)]
 pub struct Client {
     /// The WebSocket sender for sending messages.
     sender: Sender,
+    /// The IP address of the client.
+    ///
+    /// This is used to rate limit room creation and failed join attempts.
+    ip: IpAddr,
     /// The optional room ID of the client.
     /// 
     /// This is used to identify the client's room.
@@ -36,17 +43,24 @@ impl Client {
     /// # Arguments
     ///
     /// * `sender` - A synchronized WebSocket sender.
+    /// * `ip` - The IP address of the client.
     ///
     /// # Returns
     ///
     /// A new WebSocket client instance.
-    pub fn new(sender: Sender) -> Client {
+    pub fn new(sender: Sender, ip: IpAddr) -> Client {
         Client {
             sender, // The WebSocket sender for sending messages.
+            ip, // The IP address of the client.
             room_id: None, // The optional room ID of the client. This is used to identify the client's room.
         }
     }
 
+    /// Returns `true` if the client created or joined a room.
+    pub fn is_in_room(&self) -> bool {
+        self.room_id.is_some()
+    }
+
     /// Sends a message to the WebSocket connection.
     ///
     /// # Arguments
@@ -106,11 +120,19 @@ impl Client {
     ///
     /// * `server` - A lock guard of the `AppState`.
     /// * `id` - An optional string representing the room identifier.
+    /// * `priority` - The priority the client asked for.
+    /// * `size` - The number of clients the room holds, or `None` for the default.
     ///
     /// # Errors
     ///
-    /// If the room already exists or if the room creation fails.
-    async fn handle_create_room(&mut self, server: &RwLock<AppState>, id: Option<String>) {
+    /// If the room already exists, its size is out of range or if the room creation fails.
+    async fn handle_create_room(
+        &mut self,
+        server: &RwLock<AppState>,
+        id: Option<String>,
+        priority: Priority,
+        size: Option<usize>,
+    ) {
         // Acquire a write lock on the server state.
         let mut server = server.write().await;
 
@@ -123,8 +145,40 @@ impl Client {
             return;
         }
 
+        // Keep a single client from filling the relay with rooms.
+        if !server.rate_limiter.allow(RateLimited::CreateRoom, self.ip) {
+            drop(server); // Release the lock before returning.
+
+            warn!(
+                target: AUDIT_TARGET,
+                ip = %self.ip,
+                "Rejected room creation, the rate limit is exceeded"
+            );
+            return self
+                .send_error_packet(
+                    self.sender.clone(),
+                    "Too many rooms were created, try again later.".to_string(),
+                )
+                .await;
+        }
+
         // Set the room size and generate a room identifier if none is provided.
-        let size = Room::DEFAULT_ROOM_SIZE;
+        let size = size.unwrap_or(Room::DEFAULT_ROOM_SIZE);
+        let max_room_size = server.max_room_size;
+        if !(Room::DEFAULT_ROOM_SIZE..=max_room_size).contains(&size) {
+            drop(server); // Release the lock before returning.
+
+            return self
+                .send_error_packet(
+                    self.sender.clone(),
+                    format!(
+                        "A room holds between {} and {} clients.",
+                        Room::DEFAULT_ROOM_SIZE,
+                        max_room_size
+                    ),
+                )
+                .await;
+        }
         let room_id = match id {
             Some(id) => id,
             None => Uuid::new_v4().to_string(),
@@ -144,10 +198,14 @@ impl Client {
 
         // Create a new room and add the client to it.
         let mut room = Room::new(size);
+        room.priority = priority;
         room.senders.push(self.sender.clone());
 
         // Insert the room into the server state.
         server.rooms.insert(room_id.clone(), room);
+        server
+            .watchers
+            .publish(&room_id, RoomEvent::Join { members: 1 });
 
         self.room_id = Some(room_id.clone()); // Store the room identifier.
 
@@ -181,28 +239,73 @@ impl Client {
             return;
         }
 
-        let Some(room) = server.rooms.get_mut(&room_id) else {
+        // Reject clients that are still backing off from earlier failed attempts.
+        if server.is_join_blocked(&self.ip) {
             drop(server);
 
+            warn!(
+                target: AUDIT_TARGET,
+                ip = %self.ip,
+                room_id = %room_id,
+                "Rejected join attempt during backoff"
+            );
+
             // Send an error packet to the client.
             return self
-                .send_error_packet(self.sender.clone(), "The room does not exist.".to_string())
+                .send_error_packet(
+                    self.sender.clone(),
+                    "Too many failed join attempts, try again later.".to_string(),
+                )
                 .await;
+        }
+
+        // Check if the room exists and is not full.
+        let failure = match server.rooms.get(&room_id) {
+            None => Some("The room does not exist."),
+            Some(room) if room.senders.len() >= room.size => Some("The room is full."),
+            Some(_) => None,
         };
 
-        // Check if the room is full.
-        if room.senders.len() >= room.size {
+        if let Some(message) = failure {
+            // Record the failed attempt and back off before answering.
+            let delay = server.record_failed_join(self.ip);
             drop(server);
 
+            warn!(
+                target: AUDIT_TARGET,
+                ip = %self.ip,
+                room_id = %room_id,
+                delay = ?delay,
+                "Failed join attempt: {message}"
+            );
+            sleep(delay).await;
+
             // Send an error packet to the client.
             return self
-                .send_error_packet(self.sender.clone(), "The room is full.".to_string())
+                .send_error_packet(self.sender.clone(), message.to_string())
                 .await;
         }
 
+        // Forget earlier failed attempts of this client.
+        server.failed_joins_by_ip.remove(&self.ip);
+        info!(
+            target: AUDIT_TARGET,
+            ip = %self.ip,
+            room_id = %room_id,
+            "Client joined room"
+        );
+
+        let Some(room) = server.rooms.get_mut(&room_id) else {
+            return;
+        };
+
         // Add the client to the room.
         room.senders.push(self.sender.clone());
-        self.room_id = Some(room_id);
+        let members = room.senders.len();
+        self.room_id = Some(room_id.clone());
+
+        // The client joins behind all others.
+        let index = members - 1;
 
         let mut futures = vec![];
         for sender in &room.senders {
@@ -211,15 +314,19 @@ impl Client {
                 futures.push(self.send_packet(
                     sender.clone(),
                     ResponsePacket::Join {
-                        size: Some(room.senders.len() - 1),
+                        size: Some(index),
+                        index,
                     },
                 ));
             } else {
-                // Send a join packet to the client without its position in the room.
-                futures.push(self.send_packet(sender.clone(), ResponsePacket::Join { size: None }));
+                // Tell the other clients where they reach the new one.
+                futures.push(
+                    self.send_packet(sender.clone(), ResponsePacket::Join { size: None, index }),
+                );
             }
         }
 
+        server.watchers.publish(&room_id, RoomEvent::Join { members });
         drop(server);
         join_all(futures).await;
     }
@@ -272,9 +379,11 @@ impl Client {
         }
 
         // If the room is empty, remove it from the server state.
-        if room.senders.is_empty() {
+        let members = room.senders.len();
+        if members == 0 {
             server.rooms.remove(&room_id);
         }
+        server.watchers.publish(&room_id, RoomEvent::Leave { members });
 
         drop(server);
 
@@ -301,7 +410,9 @@ impl Client {
                 };
                 // Match on the RequestPacket type and perform the corresponding action.
                 match packet {
-                    RequestPacket::Create { id } => self.handle_create_room(server, id).await,
+                    RequestPacket::Create { id, priority, size } => {
+                        self.handle_create_room(server, id, priority, size).await
+                    }
                     RequestPacket::Join { id } => self.handle_join_room(server, id).await,
                     RequestPacket::Leave => self.handle_leave_room(server).await,
                 }
@@ -340,6 +451,10 @@ impl Client {
                     return; // Return if the data is empty.
                 }
 
+                // Forward the frames of urgent rooms first when the relay is busy.
+                let priority = room.priority;
+                let qos = server.qos.clone();
+
                 // Convert the index to a u8 and assign it as the source.
                 let source = u8::try_from(index).unwrap();
 
@@ -352,21 +467,33 @@ impl Client {
                     let sender = room.senders[destination].clone();
 
                     drop(server);
+                    let _slot = qos.admit(priority).await;
                     return self.send(sender, Message::Binary(data)).await;
                 }
 
                 // If the destination is u8::MAX, send the data to all the room senders except the current one.
                 if destination == usize::from(u8::MAX) {
-                    let mut futures = vec![];
-                    for sender in &room.senders {
-                        if Arc::ptr_eq(sender, &self.sender) {
-                            continue; // Skip the current client.
-                        }
+                    // Skip the current client.
+                    let others: Vec<Sender> = room
+                        .senders
+                        .iter()
+                        .filter(|sender| !Arc::ptr_eq(sender, &self.sender))
+                        .cloned()
+                        .collect();
 
-                        futures.push(self.send(sender.clone(), Message::Binary(data.clone())));
+                    let mut futures = vec![];
+                    let last = others.len().saturating_sub(1);
+                    for (position, sender) in others.into_iter().enumerate() {
+                        // The last one gets the data itself, all others a copy of it.
+                        let data = match position == last {
+                            true => std::mem::take(&mut data),
+                            false => data.clone(),
+                        };
+                        futures.push(self.send(sender, Message::Binary(data)));
                     }
 
                     drop(server);
+                    let _slot = qos.admit(priority).await;
                     join_all(futures).await;
                 }
             }
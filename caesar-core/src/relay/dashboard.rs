@@ -0,0 +1,257 @@
+//! The status page of the relay.
+//!
+//! Self-hosters without a monitoring stack can open `GET /admin` in a browser. The page
+//! asks for the admin token and then polls `GET /admin/status`, which requires it, for
+//! the open rooms, the pending transfers, the uptime and the version of the relay. Both
+//! routes only exist if the relay has an admin token.
+//!
+//! Room IDs let anyone join a room, so the status only shows their first characters.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use axum_client_ip::SecureClientIp;
+use headers::{authorization::Bearer, Authorization, HeaderMapExt};
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::RwLock;
+use tracing::warn;
+
+use crate::relay::appstate::AppState;
+use crate::relay::reload::{tokens_match, Reloader};
+use crate::relay::{Priority, AUDIT_TARGET};
+
+/// The page that shows the status of the relay.
+const DASHBOARD_PAGE: &str = include_str!("dashboard.html");
+
+/// The page may only run its own script and talk to the relay it came from.
+const DASHBOARD_PAGE_POLICY: &str =
+    "default-src 'none'; script-src 'unsafe-inline'; style-src 'unsafe-inline'; connect-src 'self'";
+
+/// The number of characters of a room ID the status shows.
+const ROOM_ID_PREFIX: usize = 8;
+
+/// What the status page needs to answer its requests.
+#[derive(Debug)]
+pub struct Dashboard {
+    /// The shared state of the server.
+    state: Arc<RwLock<AppState>>,
+    /// The admin token the relay was started with.
+    admin_token: String,
+    /// The reloader of the configuration, which knows the current admin token if it
+    /// was reloaded.
+    reloader: Option<Arc<Reloader>>,
+    /// When the relay was started.
+    started: Instant,
+}
+
+impl Dashboard {
+    /// Creates the status page of a relay that starts now.
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - The shared state of the server.
+    /// * `admin_token` - The admin token the relay was started with.
+    /// * `reloader` - The reloader of the configuration, if it can be reloaded.
+    pub fn new(
+        state: Arc<RwLock<AppState>>,
+        admin_token: String,
+        reloader: Option<Arc<Reloader>>,
+    ) -> Dashboard {
+        Dashboard {
+            state,
+            admin_token,
+            reloader,
+            started: Instant::now(),
+        }
+    }
+
+    /// Returns `true` if the token is the admin token of the relay.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The bearer token of the request.
+    async fn is_admin(&self, token: &str) -> bool {
+        match &self.reloader {
+            Some(reloader) => reloader.is_admin(token).await,
+            None => tokens_match(&self.admin_token, token),
+        }
+    }
+}
+
+/// An open room, as the status page shows it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomStatus {
+    /// The first characters of the room ID.
+    pub id: String,
+    /// The number of clients in the room.
+    pub members: usize,
+    /// The number of clients the room holds.
+    pub size: usize,
+    /// How long the room has been open, in seconds.
+    pub age: u64,
+    /// The priority the sender asked for.
+    pub priority: Priority,
+}
+
+/// The status of the relay.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RelayStatus {
+    /// The version of the relay.
+    pub version: String,
+    /// How long the relay has been running, in seconds.
+    pub uptime: u64,
+    /// The open rooms, oldest first.
+    pub rooms: Vec<RoomStatus>,
+    /// The number of transfers that were announced but not completed yet.
+    pub transfers: usize,
+    /// The number of transfers that were found at the upstream relay.
+    pub forwarded: usize,
+    /// The number of download links that wait for their recipient.
+    pub links: usize,
+}
+
+impl RelayStatus {
+    /// Collects the status of a relay.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The state of the server.
+    /// * `uptime` - How long the relay has been running.
+    pub fn collect(data: &AppState, uptime: Duration) -> RelayStatus {
+        let mut rooms: Vec<(Instant, RoomStatus)> = data
+            .rooms
+            .iter()
+            .map(|(id, room)| {
+                let status = RoomStatus {
+                    id: id.chars().take(ROOM_ID_PREFIX).collect(),
+                    members: room.senders.len(),
+                    size: room.size,
+                    age: room.created.elapsed().as_secs(),
+                    priority: room.priority,
+                };
+                (room.created, status)
+            })
+            .collect();
+        rooms.sort_by_key(|(created, _)| *created);
+
+        RelayStatus {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            uptime: uptime.as_secs(),
+            rooms: rooms.into_iter().map(|(_, status)| status).collect(),
+            transfers: data.transfers.len(),
+            forwarded: data.forwarded.len(),
+            links: data.links.len(),
+        }
+    }
+}
+
+/// Handler for the status page.
+///
+/// The page holds no data, so it is served without the admin token.
+///
+/// # Returns
+///
+/// The page.
+pub async fn dashboard_page() -> impl IntoResponse {
+    (
+        [
+            (header::CACHE_CONTROL, "no-store"),
+            (header::REFERRER_POLICY, "no-referrer"),
+            (header::CONTENT_SECURITY_POLICY, DASHBOARD_PAGE_POLICY),
+        ],
+        Html(DASHBOARD_PAGE),
+    )
+}
+
+/// Handles the `/admin/status` route.
+///
+/// # Arguments
+///
+/// * `dashboard` - The status page of the relay.
+/// * `ip` - The IP address of the client.
+/// * `headers` - The headers of the request, which carry the admin token.
+///
+/// # Returns
+///
+/// `200 OK` with the status of the relay, or `401 Unauthorized` without the admin
+/// token.
+pub async fn status_handler(
+    State(dashboard): State<Arc<Dashboard>>,
+    SecureClientIp(ip): SecureClientIp,
+    headers: HeaderMap,
+) -> Response {
+    let token = headers.typed_get::<Authorization<Bearer>>();
+    let authorized = match &token {
+        Some(Authorization(bearer)) => dashboard.is_admin(bearer.token()).await,
+        None => false,
+    };
+    if !authorized {
+        warn!(target: AUDIT_TARGET, ip = %ip, "Rejected status request without the admin token");
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({
+                "message": "Invalid admin token."
+            })),
+        )
+            .into_response();
+    }
+
+    let data = dashboard.state.read().await;
+    let status = RelayStatus::collect(&data, dashboard.started.elapsed());
+    ([(header::CACHE_CONTROL, "no-store")], Json(status)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::room::Room;
+
+    #[tokio::test]
+    async fn test_collect() {
+        let app_state = AppState::new();
+        let mut state = app_state.write().await;
+
+        let mut older = Room::new(3);
+        older.created -= Duration::from_secs(30);
+        older.priority = Priority::High;
+        state.rooms.insert("0123456789abcdef".into(), older);
+        state.rooms.insert(
+            "fedcba9876543210".into(),
+            Room::new(Room::DEFAULT_ROOM_SIZE),
+        );
+
+        let status = RelayStatus::collect(&state, Duration::from_secs(90));
+        assert_eq!(status.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(status.uptime, 90);
+        assert_eq!(status.transfers, 0);
+        assert_eq!(
+            status.rooms[0],
+            RoomStatus {
+                id: "01234567".into(),
+                members: 0,
+                size: 3,
+                age: 30,
+                priority: Priority::High,
+            }
+        );
+        assert_eq!(status.rooms[1].id, "fedcba98");
+    }
+
+    #[tokio::test]
+    async fn test_is_admin() {
+        let dashboard = Dashboard::new(AppState::new(), "secret".into(), None);
+        assert!(dashboard.is_admin("secret").await);
+        assert!(!dashboard.is_admin("guess").await);
+    }
+}
@@ -0,0 +1,200 @@
+//! Events of single rooms for watchers outside the transfer.
+//!
+//! Orchestration tools can follow a room with `GET /rooms/:id/events` instead of polling
+//! the relay, e.g. to start the next job once a transfer is complete. The relay publishes
+//! an event whenever a client joins or leaves the room, and ends the stream once the
+//! receiver reports the transfer as complete or the room expires.
+
+use std::{collections::HashMap, convert::Infallible};
+
+use axum::response::sse::Event;
+use futures_util::{stream, Stream};
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Something that happened in a room.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum RoomEvent {
+    /// A client created or joined the room.
+    Join {
+        /// The number of clients in the room afterwards.
+        members: usize,
+    },
+    /// A client left the room. It may join again after a reconnect.
+    Leave {
+        /// The number of clients in the room afterwards.
+        members: usize,
+    },
+    /// The receiver reported the transfer as complete. This is the last event.
+    Complete,
+    /// The room waited longer than the TTL for its members and was closed. This is the
+    /// last event.
+    Expired,
+}
+
+impl RoomEvent {
+    /// Returns the name of the event, which watchers can listen for.
+    pub fn name(&self) -> &'static str {
+        match self {
+            RoomEvent::Join { .. } => "join",
+            RoomEvent::Leave { .. } => "leave",
+            RoomEvent::Complete => "complete",
+            RoomEvent::Expired => "expired",
+        }
+    }
+
+    /// Converts the event to a server-sent event with the event as JSON data.
+    pub fn to_sse(&self) -> Event {
+        Event::default()
+            .event(self.name())
+            .data(serde_json::to_string(self).unwrap())
+    }
+}
+
+/// The watchers of every room, where the key is the room's ID.
+#[derive(Debug, Clone, Default)]
+pub struct RoomWatchers {
+    channels: HashMap<String, broadcast::Sender<RoomEvent>>,
+}
+
+impl RoomWatchers {
+    /// The number of events kept for a watcher that falls behind.
+    pub const CAPACITY: usize = 64;
+
+    /// Starts watching a room.
+    ///
+    /// The room does not have to exist yet, so a watcher may subscribe before the
+    /// receiver shows up.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The ID of the room to watch.
+    ///
+    /// # Returns
+    ///
+    /// The receiver of the events of the room.
+    pub fn subscribe(&mut self, room_id: &str) -> broadcast::Receiver<RoomEvent> {
+        // Forget the rooms whose watchers are all gone.
+        self.channels
+            .retain(|_, channel| channel.receiver_count() > 0);
+
+        self.channels
+            .entry(room_id.to_string())
+            .or_insert_with(|| broadcast::channel(Self::CAPACITY).0)
+            .subscribe()
+    }
+
+    /// Sends an event to the watchers of a room, if there are any.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The ID of the room.
+    /// * `event` - What happened in the room.
+    pub fn publish(&mut self, room_id: &str, event: RoomEvent) {
+        if let Some(channel) = self.channels.get(room_id) {
+            // Sending only fails once every watcher is gone.
+            if channel.send(event).is_err() {
+                self.channels.remove(room_id);
+            }
+        }
+    }
+
+    /// Sends the last event to the watchers of a room and ends their streams.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The ID of the room.
+    /// * `event` - What ended the room.
+    pub fn finish(&mut self, room_id: &str, event: RoomEvent) {
+        if let Some(channel) = self.channels.remove(room_id) {
+            let _ = channel.send(event);
+        }
+    }
+
+    /// Returns the number of rooms that are watched.
+    pub fn len(&self) -> usize {
+        self.channels.len()
+    }
+
+    /// Returns `true` if no room is watched.
+    pub fn is_empty(&self) -> bool {
+        self.channels.is_empty()
+    }
+}
+
+/// Turns the receiver of a room's events into a stream of server-sent events.
+///
+/// The stream ends once the room is finished. A watcher that falls behind by more than
+/// `RoomWatchers::CAPACITY` events misses the oldest ones, but still gets the last one.
+///
+/// # Arguments
+///
+/// * `receiver` - The receiver returned by `RoomWatchers::subscribe`.
+pub fn event_stream(
+    receiver: broadcast::Receiver<RoomEvent>,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => return Some((Ok(event.to_sse()), receiver)),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+
+    #[tokio::test]
+    async fn test_room_watchers() {
+        let mut watchers = RoomWatchers::default();
+
+        // Events of rooms nobody watches are dropped
+        watchers.publish("room", RoomEvent::Join { members: 1 });
+        assert!(watchers.is_empty());
+
+        let mut receiver = watchers.subscribe("room");
+        let other = watchers.subscribe("other");
+        watchers.publish("room", RoomEvent::Join { members: 2 });
+        watchers.publish("room", RoomEvent::Leave { members: 1 });
+        watchers.finish("room", RoomEvent::Complete);
+
+        assert_eq!(
+            receiver.recv().await.unwrap(),
+            RoomEvent::Join { members: 2 }
+        );
+        assert_eq!(
+            receiver.recv().await.unwrap(),
+            RoomEvent::Leave { members: 1 }
+        );
+        assert_eq!(receiver.recv().await.unwrap(), RoomEvent::Complete);
+        assert!(matches!(receiver.recv().await, Err(RecvError::Closed)));
+        assert_eq!(watchers.len(), 1);
+
+        // A room whose watchers left is forgotten
+        drop(other);
+        watchers.publish("other", RoomEvent::Join { members: 1 });
+        assert!(watchers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_event_stream() {
+        let mut watchers = RoomWatchers::default();
+        let stream = event_stream(watchers.subscribe("room"));
+
+        watchers.publish("room", RoomEvent::Join { members: 1 });
+        watchers.finish("room", RoomEvent::Expired);
+
+        // The stream ends after the last event
+        assert_eq!(stream.count().await, 2);
+        assert_eq!(
+            serde_json::to_string(&RoomEvent::Leave { members: 1 }).unwrap(),
+            r#"{"type":"leave","members":1}"#
+        );
+    }
+}
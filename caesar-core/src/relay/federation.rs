@@ -0,0 +1,328 @@
+//! Forwarding of rooms to an upstream relay.
+//!
+//! An organization can run an internal relay that knows the transfers of its own
+//! senders and falls back to a public relay for the rest. A transfer the internal relay
+//! was not told about is looked up at the upstream relay, and its room is remembered.
+//! A receiver that joins the room is then connected through to the upstream relay, which
+//! holds the sender, and the internal relay passes their frames back and forth. Once the
+//! receiver reports the transfer as complete, the upstream relay is told as well.
+
+use std::time::{Duration, Instant};
+
+use axum::extract::ws::{self, WebSocket};
+use futures_util::{stream::SplitStream, SinkExt, StreamExt};
+use reqwest::{Client, StatusCode};
+use tokio::sync::RwLock;
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{
+        client::IntoClientRequest,
+        http::HeaderValue,
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Message,
+    },
+};
+use tracing::{debug, warn};
+
+use crate::relay::appstate::AppState;
+use crate::relay::client::Sender;
+use crate::relay::routing::ROUTE_HEADER;
+use crate::relay::transfer::TransferResponse;
+use crate::relay::{RequestPacket, ResponsePacket};
+use crate::sender::util::{replace_protocol, websocket_url};
+
+/// A room of a transfer that lives at the upstream relay.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForwardedRoom {
+    /// The hashed name of the transfer.
+    pub name: String,
+    /// The relay that holds the room.
+    pub upstream: String,
+    /// The routing token of the upstream instance that holds the room, if the receiver
+    /// presented one.
+    pub route: Option<String>,
+    /// When the transfer was looked up.
+    pub created: Instant,
+}
+
+impl ForwardedRoom {
+    /// Creates the record of a room at the upstream relay.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The hashed name of the transfer.
+    /// * `upstream` - The relay that holds the room.
+    /// * `route` - The routing token of the upstream instance, if any.
+    pub fn new(name: String, upstream: String, route: Option<String>) -> ForwardedRoom {
+        ForwardedRoom {
+            name,
+            upstream,
+            route,
+            created: Instant::now(),
+        }
+    }
+
+    /// Returns `true` if the room was looked up longer than the TTL ago.
+    ///
+    /// # Arguments
+    ///
+    /// * `ttl` - How long a room may wait for its members.
+    pub fn is_expired(&self, ttl: Duration) -> bool {
+        self.created.elapsed() > ttl
+    }
+}
+
+/// How long a connection to the upstream relay may take to open.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a request to the upstream relay may take, the connection included.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Creates the client the requests to the upstream relay are sent with.
+///
+/// The client is shared by all handlers, so the connections are reused, and its
+/// timeouts keep a slow upstream relay from holding up the lookups of the receivers.
+pub fn client() -> Client {
+    Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .expect("Failed to create the federation client.")
+}
+
+/// Looks up a transfer at the upstream relay.
+///
+/// # Arguments
+///
+/// * `client` - The client the request is sent with.
+/// * `upstream` - The address of the upstream relay.
+/// * `name` - The hashed name of the transfer.
+/// * `route` - The routing token the receiver presented, if any.
+///
+/// # Returns
+///
+/// The transfer, or `None` if the upstream relay does not know it or cannot be reached.
+pub async fn lookup(
+    client: &Client,
+    upstream: &str,
+    name: &str,
+    route: Option<&str>,
+) -> Option<TransferResponse> {
+    let mut request = client.get(format!("{}/download/{}", replace_protocol(upstream), name));
+    if let Some(route) = route {
+        request = request.header(ROUTE_HEADER, route);
+    }
+    let response = match request.send().await {
+        Ok(response) => response,
+        Err(error) => {
+            warn!("Failed to look up the transfer '{name}' at {upstream}: {error}");
+            return None;
+        }
+    };
+    if response.status() != StatusCode::OK {
+        debug!(
+            "The upstream relay {upstream} answered {}",
+            response.status()
+        );
+        return None;
+    }
+    response.json().await.ok()
+}
+
+/// Tells the upstream relay that a forwarded transfer is complete.
+///
+/// A failure is only logged, the upstream relay drops the transfer once its room
+/// expires.
+///
+/// # Arguments
+///
+/// * `client` - The client the request is sent with.
+/// * `room` - The forwarded room of the transfer.
+pub async fn complete(client: &Client, room: &ForwardedRoom) {
+    let url = format!(
+        "{}/download_success/{}",
+        replace_protocol(&room.upstream),
+        room.name
+    );
+    let mut request = client.post(url);
+    if let Some(route) = &room.route {
+        request = request.header(ROUTE_HEADER, route);
+    }
+    if let Err(error) = request.send().await {
+        warn!(
+            "Failed to complete the transfer '{}' at {}: {error}",
+            room.name, room.upstream
+        );
+    }
+}
+
+/// Returns the forwarded room a message joins, if it is not a room of this relay.
+///
+/// # Arguments
+///
+/// * `state` - The shared state of the server.
+/// * `message` - A message of a client that is in no room yet.
+pub async fn joined_room(state: &RwLock<AppState>, message: &ws::Message) -> Option<ForwardedRoom> {
+    let ws::Message::Text(text) = message else {
+        return None;
+    };
+    let Ok(RequestPacket::Join { id }) = serde_json::from_str(text) else {
+        return None;
+    };
+    let data = state.read().await;
+    if data.rooms.contains_key(&id) {
+        return None;
+    }
+    data.forwarded.get(&id).cloned()
+}
+
+/// Connects a client through to its room at the upstream relay.
+///
+/// The join is sent on, and from then on every frame of either side is passed to the
+/// other until one of them closes the connection.
+///
+/// # Arguments
+///
+/// * `sender` - The sending half of the client's connection.
+/// * `receiver` - The receiving half of the client's connection.
+/// * `room` - The forwarded room the client joins.
+/// * `join` - The message the client joined with.
+pub async fn proxy(
+    sender: Sender,
+    mut receiver: SplitStream<WebSocket>,
+    room: ForwardedRoom,
+    join: ws::Message,
+) {
+    // The routing token takes the client to the upstream instance that holds the room
+    let connected = match websocket_url(&room.upstream).into_client_request() {
+        Ok(mut request) => {
+            let route = room.route.as_deref().map(HeaderValue::from_str);
+            if let Some(Ok(route)) = route {
+                request.headers_mut().insert(ROUTE_HEADER, route);
+            }
+            connect_async(request).await.map(|(socket, _)| socket)
+        }
+        Err(error) => Err(error),
+    };
+    let socket = match connected {
+        Ok(socket) => socket,
+        Err(error) => {
+            warn!(
+                "Failed to connect to the upstream relay {}: {error}",
+                room.upstream
+            );
+            let error = ResponsePacket::Error {
+                message: "The upstream relay cannot be reached.".to_string(),
+            };
+            let _ = sender
+                .lock()
+                .await
+                .send(ws::Message::Text(serde_json::to_string(&error).unwrap()))
+                .await;
+            return;
+        }
+    };
+    debug!("Forwarding a client to {}", room.upstream);
+    let (mut upstream_sender, mut upstream_receiver) = socket.split();
+
+    let downstream = async {
+        let mut message = Some(join);
+        while let Some(next) = message.take() {
+            if upstream_sender.send(to_upstream(next)).await.is_err() {
+                break;
+            }
+            message = receiver.next().await.and_then(Result::ok);
+        }
+        let _ = upstream_sender.close().await;
+    };
+    let upstream = async {
+        while let Some(Ok(message)) = upstream_receiver.next().await {
+            let Some(message) = to_downstream(message) else {
+                continue;
+            };
+            if sender.lock().await.send(message).await.is_err() {
+                break;
+            }
+        }
+        let _ = sender.lock().await.close().await;
+    };
+
+    // Either side closing ends the connection of the other
+    tokio::select! {
+        _ = downstream => {}
+        _ = upstream => {}
+    }
+}
+
+/// Converts a message of a client into one for the upstream relay.
+///
+/// # Arguments
+///
+/// * `message` - The message the client sent.
+fn to_upstream(message: ws::Message) -> Message {
+    match message {
+        ws::Message::Text(text) => Message::Text(text),
+        ws::Message::Binary(data) => Message::Binary(data),
+        ws::Message::Ping(data) => Message::Ping(data),
+        ws::Message::Pong(data) => Message::Pong(data),
+        ws::Message::Close(frame) => Message::Close(frame.map(|frame| CloseFrame {
+            code: CloseCode::from(frame.code),
+            reason: frame.reason,
+        })),
+    }
+}
+
+/// Converts a message of the upstream relay into one for the client.
+///
+/// # Arguments
+///
+/// * `message` - The message the upstream relay sent.
+///
+/// # Returns
+///
+/// The message, or `None` for raw frames, which are never read.
+fn to_downstream(message: Message) -> Option<ws::Message> {
+    Some(match message {
+        Message::Text(text) => ws::Message::Text(text),
+        Message::Binary(data) => ws::Message::Binary(data),
+        Message::Ping(data) => ws::Message::Ping(data),
+        Message::Pong(data) => ws::Message::Pong(data),
+        Message::Close(frame) => ws::Message::Close(frame.map(|frame| ws::CloseFrame {
+            code: frame.code.into(),
+            reason: frame.reason,
+        })),
+        Message::Frame(_) => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forwarded_room_expires() {
+        let mut room = ForwardedRoom::new("name".into(), "ws://relay".into(), None);
+        assert!(!room.is_expired(Duration::from_secs(60)));
+
+        room.created -= Duration::from_secs(120);
+        assert!(room.is_expired(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_messages_round_trip() {
+        let messages = vec![
+            ws::Message::Text("{\"type\":\"leave\"}".into()),
+            ws::Message::Binary(vec![1, 2, 3]),
+            ws::Message::Ping(vec![4]),
+            ws::Message::Pong(vec![5]),
+            ws::Message::Close(Some(ws::CloseFrame {
+                code: ws::close_code::AWAY,
+                reason: "bye".into(),
+            })),
+        ];
+        for message in messages {
+            let forwarded = to_downstream(to_upstream(message.clone())).unwrap();
+            assert_eq!(forwarded, message);
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+/// Tracks failed join attempts of a client address.
+///
+/// Every failed attempt doubles the time a client has to wait before its next
+/// attempt is answered, which makes guessing room identifiers impractical.
+#[derive(Debug, Clone)]
+pub struct JoinAttempts {
+    /// The number of consecutive failed attempts.
+    pub failures: u32,
+    /// The time of the last failed attempt.
+    pub last_failure: Instant,
+}
+
+impl JoinAttempts {
+    /// The delay after the first failed attempt.
+    pub const BASE_DELAY: Duration = Duration::from_millis(250);
+
+    /// The upper bound for the delay between two attempts.
+    pub const MAX_DELAY: Duration = Duration::from_secs(30);
+
+    /// The time after which failed attempts are forgotten.
+    pub const RESET_AFTER: Duration = Duration::from_secs(600);
+
+    /// Creates a new tracker without any failed attempts.
+    ///
+    /// # Returns
+    ///
+    /// A new `JoinAttempts` instance.
+    pub fn new() -> JoinAttempts {
+        JoinAttempts {
+            failures: 0,
+            last_failure: Instant::now(),
+        }
+    }
+
+    /// Records a failed attempt.
+    ///
+    /// # Returns
+    ///
+    /// The delay that should be applied before answering the failed attempt.
+    pub fn record_failure(&mut self) -> Duration {
+        if self.is_expired() {
+            self.failures = 0;
+        }
+
+        self.failures = self.failures.saturating_add(1);
+        self.last_failure = Instant::now();
+
+        self.backoff()
+    }
+
+    /// Returns the current backoff delay based on the number of failed attempts.
+    pub fn backoff(&self) -> Duration {
+        if self.failures == 0 {
+            return Duration::ZERO;
+        }
+
+        let exponent = (self.failures - 1).min(16);
+        Self::BASE_DELAY
+            .saturating_mul(1 << exponent)
+            .min(Self::MAX_DELAY)
+    }
+
+    /// Returns `true` if the backoff delay of the last failed attempt has not passed yet.
+    pub fn is_blocked(&self) -> bool {
+        self.failures > 0 && self.last_failure.elapsed() < self.backoff()
+    }
+
+    /// Returns `true` if the last failed attempt is old enough to be forgotten.
+    pub fn is_expired(&self) -> bool {
+        self.last_failure.elapsed() >= Self::RESET_AFTER
+    }
+}
+
+impl Default for JoinAttempts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The requests a client may make per minute, by kind. Zero means no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimits {
+    /// Rooms created over the websocket.
+    pub create_room: u32,
+    /// Transfers announced or updated with `/upload`.
+    pub upload: u32,
+    /// Transfers looked up with `/download/:name`.
+    pub download: u32,
+}
+
+/// The kinds of requests that are rate limited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RateLimited {
+    /// A room is created.
+    CreateRoom,
+    /// A transfer is announced or updated.
+    Upload,
+    /// A transfer is looked up.
+    Download,
+}
+
+impl RateLimits {
+    /// Returns the requests per minute allowed for a kind of request.
+    ///
+    /// # Arguments
+    ///
+    /// * `kind` - The kind of request.
+    pub fn per_minute(&self, kind: RateLimited) -> u32 {
+        match kind {
+            RateLimited::CreateRoom => self.create_room,
+            RateLimited::Upload => self.upload,
+            RateLimited::Download => self.download,
+        }
+    }
+}
+
+/// A token bucket that holds up to a minute's worth of requests and refills steadily.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    /// The requests that can be made right now.
+    pub tokens: f64,
+    /// The time the bucket was last refilled.
+    pub last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket.
+    ///
+    /// # Arguments
+    ///
+    /// * `per_minute` - The requests allowed per minute.
+    ///
+    /// # Returns
+    ///
+    /// A new `TokenBucket` instance.
+    pub fn new(per_minute: u32) -> TokenBucket {
+        TokenBucket {
+            tokens: per_minute as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes a token for a request if one is left.
+    ///
+    /// # Arguments
+    ///
+    /// * `per_minute` - The requests allowed per minute.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the request may be served.
+    pub fn take(&mut self, per_minute: u32) -> bool {
+        let capacity = per_minute as f64;
+        let refill = self.last_refill.elapsed().as_secs_f64() * capacity / 60.0;
+        self.tokens = (self.tokens + refill).min(capacity);
+        self.last_refill = Instant::now();
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// Returns `true` if the bucket has refilled completely and can be forgotten.
+    pub fn is_idle(&self) -> bool {
+        self.last_refill.elapsed() >= RateLimiter::IDLE_AFTER
+    }
+}
+
+/// Limits the requests of every client address.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    /// The configured limits.
+    pub limits: RateLimits,
+    /// The buckets of the clients, by kind of request and address.
+    pub buckets: HashMap<(RateLimited, IpAddr), TokenBucket>,
+}
+
+impl RateLimiter {
+    /// The time after which an unused bucket is full again.
+    pub const IDLE_AFTER: Duration = Duration::from_secs(60);
+
+    /// Creates a limiter with the given limits.
+    ///
+    /// # Arguments
+    ///
+    /// * `limits` - The requests a client may make per minute.
+    ///
+    /// # Returns
+    ///
+    /// A new `RateLimiter` instance.
+    pub fn new(limits: RateLimits) -> RateLimiter {
+        RateLimiter {
+            limits,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Counts a request of a client against its limit.
+    ///
+    /// Buckets that have been unused long enough to be full again are removed first.
+    ///
+    /// # Arguments
+    ///
+    /// * `kind` - The kind of request.
+    /// * `ip` - The IP address of the client.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the request may be served.
+    pub fn allow(&mut self, kind: RateLimited, ip: IpAddr) -> bool {
+        let per_minute = self.limits.per_minute(kind);
+        if per_minute == 0 {
+            return true;
+        }
+
+        self.buckets.retain(|_, bucket| !bucket.is_idle());
+        self.buckets
+            .entry((kind, ip))
+            .or_insert_with(|| TokenBucket::new(per_minute))
+            .take(per_minute)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles() {
+        let mut attempts = JoinAttempts::new();
+        assert_eq!(attempts.backoff(), Duration::ZERO);
+        assert!(!attempts.is_blocked());
+
+        assert_eq!(attempts.record_failure(), Duration::from_millis(250));
+        assert_eq!(attempts.record_failure(), Duration::from_millis(500));
+        assert_eq!(attempts.record_failure(), Duration::from_millis(1000));
+        assert!(attempts.is_blocked());
+    }
+
+    #[test]
+    fn test_backoff_is_capped() {
+        let mut attempts = JoinAttempts::new();
+        for _ in 0..100 {
+            attempts.record_failure();
+        }
+        assert_eq!(attempts.backoff(), JoinAttempts::MAX_DELAY);
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(RateLimits {
+            create_room: 3,
+            ..Default::default()
+        });
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let other = IpAddr::from([127, 0, 0, 2]);
+
+        for _ in 0..3 {
+            assert!(limiter.allow(RateLimited::CreateRoom, ip));
+        }
+        assert!(!limiter.allow(RateLimited::CreateRoom, ip));
+        // Other clients and kinds without a limit are not affected
+        assert!(limiter.allow(RateLimited::CreateRoom, other));
+        for _ in 0..100 {
+            assert!(limiter.allow(RateLimited::Download, ip));
+        }
+
+        // The bucket refills over time
+        let bucket = limiter.buckets.get_mut(&(RateLimited::CreateRoom, ip)).unwrap();
+        bucket.last_refill -= Duration::from_secs(20);
+        assert!(limiter.allow(RateLimited::CreateRoom, ip));
+        assert!(!limiter.allow(RateLimited::CreateRoom, ip));
+    }
+}
@@ -0,0 +1,243 @@
+//! The relay side of one-time download links.
+//!
+//! Senders leave an encrypted file with `PUT /link/:id`. `GET /link/:id` serves a page
+//! that reads the key from the fragment of the link, fetches the file from
+//! `GET /link/:id/data` and decrypts it in the browser. The file is handed out once and
+//! only lives in memory, so it is also gone when the relay restarts.
+
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use axum_client_ip::SecureClientIp;
+use serde_json::json;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::relay::appstate::AppState;
+use crate::relay::limiter::RateLimited;
+use crate::relay::AUDIT_TARGET;
+
+/// The page that downloads and decrypts a file in the browser.
+const LINK_PAGE: &str = include_str!("link.html");
+
+/// The page may only run its own script and talk to the relay it came from.
+const LINK_PAGE_POLICY: &str =
+    "default-src 'none'; script-src 'unsafe-inline'; style-src 'unsafe-inline'; connect-src 'self'";
+
+/// The most files that wait for their download at the same time.
+pub const MAX_LINKS: usize = 1024;
+
+/// The most bytes all waiting files may have together. Uploads are anonymous, so the
+/// memory of the relay is not left to the rate limit and the TTL alone.
+pub const MAX_SPOOLED_BYTES: usize = 1024 * 1024 * 1024;
+
+/// An encrypted file that waits for its download.
+#[derive(Debug, Clone)]
+pub struct SpooledFile {
+    /// The file as the sender encrypted it.
+    pub data: Bytes,
+    /// The time the file was left on the relay.
+    pub created: Instant,
+}
+
+impl SpooledFile {
+    /// How long a file waits for its download if the relay keeps its rooms forever.
+    pub const DEFAULT_TTL: Duration = Duration::from_secs(3600);
+
+    /// Creates a file that was just left on the relay.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The encrypted file.
+    pub fn new(data: Bytes) -> SpooledFile {
+        SpooledFile {
+            data,
+            created: Instant::now(),
+        }
+    }
+
+    /// Returns whether the file waited longer than the TTL for its download.
+    ///
+    /// # Arguments
+    ///
+    /// * `ttl` - How long a file may wait, the room TTL. A zero TTL, which keeps the
+    ///   rooms, lets the file wait for `DEFAULT_TTL`.
+    pub fn is_expired(&self, ttl: Duration) -> bool {
+        let ttl = if ttl.is_zero() { Self::DEFAULT_TTL } else { ttl };
+        self.created.elapsed() >= ttl
+    }
+}
+
+/// Handler that takes an encrypted file for a download link.
+///
+/// The body is read only up to the size the relay allows, so larger files are refused
+/// before they take up memory.
+///
+/// # Arguments
+///
+/// * `shared_state` - The shared state the file is kept in.
+/// * `ip` - The IP address of the client, whose uploads are rate limited.
+/// * `id` - The ID of the link, chosen by the sender.
+/// * `body` - The encrypted file.
+///
+/// # Returns
+///
+/// `201 Created`, `404 Not Found` if the relay offers no download links, `409 Conflict`
+/// if the ID is taken, `413 Payload Too Large`, `429 Too Many Requests`, or
+/// `503 Service Unavailable` if the relay holds as many files as it may.
+pub async fn upload_link(
+    State(shared_state): State<Arc<RwLock<AppState>>>,
+    SecureClientIp(ip): SecureClientIp,
+    Path(id): Path<String>,
+    body: Body,
+) -> impl IntoResponse {
+    let limit = {
+        let mut data = shared_state.write().await;
+        if !data.rate_limiter.allow(RateLimited::Upload, ip) {
+            warn!(target: AUDIT_TARGET, ip = %ip, "Rejected download link, the rate limit is exceeded");
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                Json(json!({ "message": "too many requests" })),
+            );
+        }
+        if data.is_spool_full(0) {
+            warn!("Rejected download link, too many files wait for their download");
+            return spool_full();
+        }
+        data.link_size
+    };
+    if limit == 0 {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "message": "download links are turned off" })),
+        );
+    }
+
+    // Read the body without holding the lock
+    let Ok(file) = to_bytes(body, usize::try_from(limit).unwrap_or(usize::MAX)).await else {
+        return (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            Json(json!({ "message": "the file is too large" })),
+        );
+    };
+
+    let mut data = shared_state.write().await;
+    if data.links.contains_key(&id) {
+        return (
+            StatusCode::CONFLICT,
+            Json(json!({ "message": "the link exists already" })),
+        );
+    }
+    // Others may have filled the spool while the body was read
+    if data.is_spool_full(file.len()) {
+        warn!("Rejected download link, too many files wait for their download");
+        return spool_full();
+    }
+    info!(
+        target: AUDIT_TARGET,
+        ip = %ip,
+        size = file.len(),
+        "Download link created"
+    );
+    data.links.insert(id, SpooledFile::new(file));
+
+    (
+        StatusCode::CREATED,
+        Json(json!({ "message": "link created" })),
+    )
+}
+
+/// The answer to an upload while the relay holds as many files as it may.
+fn spool_full() -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json(json!({ "message": "too many files wait for their download" })),
+    )
+}
+
+/// Handler for the page behind a download link.
+///
+/// The page is the same for every link and fetches nothing until the recipient asks
+/// for the download, so previews of the link do not use it up.
+///
+/// # Returns
+///
+/// The page.
+pub async fn link_page() -> impl IntoResponse {
+    (
+        [
+            (header::CACHE_CONTROL, "no-store"),
+            (header::REFERRER_POLICY, "no-referrer"),
+            (header::CONTENT_SECURITY_POLICY, LINK_PAGE_POLICY),
+        ],
+        Html(LINK_PAGE),
+    )
+}
+
+/// Handler that hands out the encrypted file of a download link, once.
+///
+/// # Arguments
+///
+/// * `shared_state` - The shared state the file is kept in.
+/// * `ip` - The IP address of the client, whose downloads are rate limited.
+/// * `id` - The ID of the link.
+///
+/// # Returns
+///
+/// The encrypted file, `404 Not Found` if it was downloaded already or has expired, or
+/// `429 Too Many Requests`.
+pub async fn link_data(
+    State(shared_state): State<Arc<RwLock<AppState>>>,
+    SecureClientIp(ip): SecureClientIp,
+    Path(id): Path<String>,
+) -> Response {
+    let mut data = shared_state.write().await;
+    if !data.rate_limiter.allow(RateLimited::Download, ip) {
+        warn!(target: AUDIT_TARGET, ip = %ip, "Rejected link download, the rate limit is exceeded");
+        return StatusCode::TOO_MANY_REQUESTS.into_response();
+    }
+
+    let ttl = data.room_ttl;
+    match data.links.remove(&id) {
+        Some(file) if !file.is_expired(ttl) => {
+            info!(target: AUDIT_TARGET, ip = %ip, "Download link used");
+            (
+                [
+                    (header::CONTENT_TYPE, "application/octet-stream"),
+                    (header::CACHE_CONTROL, "no-store"),
+                ],
+                file.data,
+            )
+                .into_response()
+        }
+        _ => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_expired() {
+        let ttl = Duration::from_secs(60);
+        let mut file = SpooledFile::new(Bytes::from_static(b"sealed"));
+        assert!(!file.is_expired(ttl));
+
+        file.created -= ttl;
+        assert!(file.is_expired(ttl));
+
+        // A zero TTL, which keeps the rooms, still drops the file eventually
+        assert!(!file.is_expired(Duration::ZERO));
+        file.created -= SpooledFile::DEFAULT_TTL;
+        assert!(file.is_expired(Duration::ZERO));
+    }
+}
@@ -1,11 +1,66 @@
+#[cfg(feature = "relay")]
 pub mod appstate;
+#[cfg(feature = "relay")]
+pub mod auth;
+#[cfg(feature = "relay")]
+pub mod blocklist;
+#[cfg(feature = "relay")]
 pub mod client;
+#[cfg(feature = "relay")]
+pub mod dashboard;
+#[cfg(feature = "relay")]
+pub mod events;
+#[cfg(feature = "relay")]
+pub mod federation;
+#[cfg(feature = "relay")]
+pub mod limiter;
+#[cfg(feature = "relay")]
+pub mod link;
+pub mod punch;
+#[cfg(feature = "relay")]
+pub mod qos;
+#[cfg(feature = "relay")]
+pub mod reload;
+#[cfg(feature = "relay")]
 pub mod room;
+pub mod routing;
+#[cfg(feature = "relay")]
 pub mod server;
+#[cfg(feature = "relay")]
+pub mod store;
 pub mod transfer;
 
+use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
 
+/// The tracing target used for security relevant relay events.
+///
+/// Operators can route these events separately, e.g. with `RUST_LOG=caesar::audit=info`.
+pub const AUDIT_TARGET: &str = "caesar::audit";
+
+/// How urgent the transfer of a room is.
+///
+/// The sender passes it as a hint when it creates the room. A relay under load forwards
+/// the frames of rooms with a higher priority first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "camelCase")]
+pub enum Priority {
+    /// Background transfers like backups, which may take longer.
+    Low,
+    /// Transfers without a preference.
+    #[default]
+    Normal,
+    /// Transfers someone is waiting for.
+    High,
+}
+
+impl Priority {
+    /// Returns `true` for the default priority, which is not sent to the relay.
+    pub fn is_normal(&self) -> bool {
+        *self == Priority::Normal
+    }
+}
+
 
 /// Represents a packet sent by a client to the server.
 /// 
@@ -31,6 +86,13 @@ pub enum RequestPacket {
     Create {
         /// The ID of the new room. If it is `None`, a random ID will be generated.
         id: Option<String>,
+        /// The priority of the transfer. Older clients do not send it.
+        #[serde(default)]
+        priority: Priority,
+        /// The number of clients the room holds, the creator included. Older clients do
+        /// not send it and get a room for a single receiver.
+        #[serde(default)]
+        size: Option<usize>,
     },
     /// The client wants to leave the current room.
     Leave,
@@ -55,6 +117,9 @@ pub enum ResponsePacket {
         /// The size of the room. If it is `None`, the size is unknown.
         #[serde(skip_serializing_if = "Option::is_none")]
         size: Option<usize>,
+        /// The position of the client that joined, which the other members address it
+        /// at. It moves up when a client in front of it leaves.
+        index: usize,
     },
     /// The client has created a new room.
     /// 
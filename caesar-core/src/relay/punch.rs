@@ -0,0 +1,120 @@
+//! The rendezvous of TCP hole punching.
+//!
+//! Both peers of a transfer connect to `/punch/:room_id` from the port they punch from
+//! and send their local address. The relay adds the address it sees each peer connect
+//! from, and once both peers are present, it tells each the candidates of the other and
+//! closes the connections. The relay carries nothing else, the peers then connect to
+//! each other directly.
+//!
+//! The public address is only right if the relay sees the peers themselves, so hole
+//! punching fails behind a reverse proxy and the transfer falls back to the relay.
+
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "relay")]
+use std::sync::Arc;
+
+#[cfg(feature = "relay")]
+use axum::extract::ws::{Message, WebSocket};
+#[cfg(feature = "relay")]
+use tokio::sync::{mpsc, RwLock};
+#[cfg(feature = "relay")]
+use tracing::debug;
+
+#[cfg(feature = "relay")]
+use crate::relay::appstate::AppState;
+
+/// The addresses a peer may be reached at.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Candidates {
+    /// The address the relay saw the peer connect from, behind the NAT of the peer.
+    /// Peers leave it empty, the relay fills it in.
+    pub public: Option<SocketAddr>,
+    /// The address of the peer on its own network, for peers behind the same NAT.
+    pub local: Option<SocketAddr>,
+}
+
+/// A peer that waits at the rendezvous for the other peer of its room.
+#[cfg(feature = "relay")]
+#[derive(Debug, Clone)]
+pub struct Waiting {
+    /// The candidates of the waiting peer.
+    candidates: Candidates,
+    /// Hands the candidates of the other peer to the waiting one.
+    reply: mpsc::Sender<Candidates>,
+}
+
+/// Meets the peers of a room and swaps their candidates.
+///
+/// # Arguments
+///
+/// * `socket` - The WebSocket connection of the peer.
+/// * `state` - The shared state of the server.
+/// * `room_id` - The room both peers meet in.
+/// * `address` - The address the peer connected from.
+#[cfg(feature = "relay")]
+pub async fn rendezvous(
+    mut socket: WebSocket,
+    state: Arc<RwLock<AppState>>,
+    room_id: String,
+    address: SocketAddr,
+) {
+    // The first message holds the local address of the peer
+    let local = match socket.recv().await {
+        Some(Ok(Message::Text(text))) => serde_json::from_str::<Candidates>(&text)
+            .ok()
+            .and_then(|candidates| candidates.local),
+        _ => return,
+    };
+    let candidates = Candidates {
+        public: Some(address),
+        local,
+    };
+
+    // Meet the peer that waits in the room, or wait for one
+    let (reply, mut replies) = mpsc::channel(1);
+    let waiting = {
+        let mut data = state.write().await;
+        let waiting = data
+            .punches
+            .remove(&room_id)
+            .filter(|waiting| !waiting.reply.is_closed());
+        if waiting.is_none() {
+            data.punches
+                .insert(room_id.clone(), Waiting { candidates, reply });
+        }
+        waiting
+    };
+
+    let peer = match waiting {
+        Some(waiting) => {
+            let _ = waiting.reply.send(candidates).await;
+            Some(waiting.candidates)
+        }
+        None => {
+            let peer = tokio::select! {
+                peer = replies.recv() => peer,
+                // The peer gave up waiting
+                _ = socket.recv() => None,
+            };
+            // Forget the room if nobody came
+            drop(replies);
+            state
+                .write()
+                .await
+                .punches
+                .retain(|_, waiting| !waiting.reply.is_closed());
+            peer
+        }
+    };
+
+    if let Some(peer) = peer {
+        debug!("Swapped the candidates of room {room_id}");
+        let _ = socket
+            .send(Message::Text(serde_json::to_string(&peer).unwrap()))
+            .await;
+    }
+    let _ = socket.close().await;
+}
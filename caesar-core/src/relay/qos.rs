@@ -0,0 +1,149 @@
+//! Preference of high priority rooms when the relay is under load.
+//!
+//! Every frame the relay forwards holds a slot while it is written to the receiving
+//! socket. Once many frames are in flight, the outgoing connections are congested, and
+//! frames of lower priority rooms wait for a free slot before they are forwarded. The
+//! sockets of those rooms are then read more slowly, so their senders slow down and
+//! leave the bandwidth to the rooms of higher priority.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::sync::Notify;
+
+use crate::relay::Priority;
+
+/// Limits the frames that are forwarded at the same time, by priority.
+#[derive(Debug, Default)]
+pub struct Qos {
+    /// The frames in flight from which on normal priority frames wait. Low priority
+    /// frames wait from half of it on, high priority frames never. Zero turns the
+    /// preference off.
+    capacity: usize,
+    /// The frames that are forwarded right now.
+    in_flight: AtomicUsize,
+    /// Wakes the waiting frames when a slot is freed.
+    released: Notify,
+}
+
+/// A slot of a frame that is being forwarded. The slot is freed when it is dropped.
+#[derive(Debug)]
+pub struct Slot<'a> {
+    /// The limiter the slot was taken from, or `None` if the preference is off.
+    qos: Option<&'a Qos>,
+}
+
+impl Qos {
+    /// Creates a new limiter.
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity` - The frames in flight from which on normal priority frames wait.
+    ///
+    /// # Returns
+    ///
+    /// A new `Qos` instance.
+    pub fn new(capacity: usize) -> Qos {
+        Qos {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the frames in flight from which on frames of a priority wait.
+    ///
+    /// # Arguments
+    ///
+    /// * `priority` - The priority of the room.
+    fn limit(&self, priority: Priority) -> usize {
+        match priority {
+            Priority::Low => (self.capacity / 2).max(1),
+            Priority::Normal => self.capacity,
+            Priority::High => usize::MAX,
+        }
+    }
+
+    /// Waits until a frame of the given priority may be forwarded.
+    ///
+    /// # Arguments
+    ///
+    /// * `priority` - The priority of the room the frame belongs to.
+    ///
+    /// # Returns
+    ///
+    /// The slot of the frame, which has to be held until the frame is written.
+    pub async fn admit(&self, priority: Priority) -> Slot<'_> {
+        if self.capacity == 0 {
+            return Slot { qos: None };
+        }
+
+        let limit = self.limit(priority);
+        loop {
+            // Register for a wakeup before looking, so no release is missed
+            let released = self.released.notified();
+
+            let current = self.in_flight.load(Ordering::Acquire);
+            if current < limit {
+                if self
+                    .in_flight
+                    .compare_exchange(current, current + 1, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok()
+                {
+                    return Slot { qos: Some(self) };
+                }
+                continue;
+            }
+
+            released.await;
+        }
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        if let Some(qos) = self.qos {
+            qos.in_flight.fetch_sub(1, Ordering::AcqRel);
+            qos.released.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn test_admit() {
+        let qos = Qos::new(2);
+        let wait = Duration::from_millis(50);
+
+        let first = qos.admit(Priority::Normal).await;
+        // Low priority frames already wait at half the capacity
+        assert!(timeout(wait, qos.admit(Priority::Low)).await.is_err());
+        let second = qos.admit(Priority::Normal).await;
+        assert!(timeout(wait, qos.admit(Priority::Normal)).await.is_err());
+        // High priority frames are always forwarded
+        let high = qos.admit(Priority::High).await;
+
+        drop(high);
+        drop(second);
+        assert!(timeout(wait, qos.admit(Priority::Low)).await.is_err());
+        // A freed slot wakes the waiting frames
+        let release = async {
+            tokio::time::sleep(wait).await;
+            drop(first);
+        };
+        let (low, ()) = timeout(Duration::from_secs(1), async {
+            tokio::join!(qos.admit(Priority::Low), release)
+        })
+        .await
+        .unwrap();
+        drop(low);
+
+        // Without a capacity nothing waits
+        let off = Qos::new(0);
+        let _slots: Vec<_> =
+            futures_util::future::join_all((0..10).map(|_| off.admit(Priority::Low))).await;
+    }
+}
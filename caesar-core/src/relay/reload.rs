@@ -0,0 +1,337 @@
+//! Reloading the configuration of a running relay.
+//!
+//! A long-lived public relay should not drop the rooms of its clients whenever the
+//! operator tightens a rate limit or blocks an abusive network. The relay reads its
+//! configuration again on `SIGHUP` or a `POST /admin/reload` with the admin token, and
+//! applies the rate limits, the room TTL, the blocklist and the identity provider to the
+//! running server. The other settings, like the listen address or the certificate, are
+//! only read at startup, so changing them requires a restart.
+
+use std::{fmt, sync::Arc};
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_client_ip::SecureClientIp;
+use headers::{authorization::Bearer, Authorization, HeaderMapExt};
+use serde_json::json;
+use tokio::sync::{Mutex, RwLock};
+use tracing::{error, info, warn};
+
+use crate::relay::{
+    appstate::AppState, auth::Authenticator, room::Room, server::RelayConfig, AUDIT_TARGET,
+};
+
+/// Loads the configuration of the relay again, e.g. from its config file.
+#[derive(Clone)]
+pub struct ConfigSource(Arc<dyn Fn() -> Result<RelayConfig, String> + Send + Sync>);
+
+impl ConfigSource {
+    /// Creates a source from the function that loads the configuration.
+    ///
+    /// # Arguments
+    ///
+    /// * `load` - Loads the configuration, or returns why it cannot be loaded.
+    pub fn new<F>(load: F) -> ConfigSource
+    where
+        F: Fn() -> Result<RelayConfig, String> + Send + Sync + 'static,
+    {
+        ConfigSource(Arc::new(load))
+    }
+
+    /// Loads the configuration.
+    pub fn load(&self) -> Result<RelayConfig, String> {
+        (self.0)()
+    }
+}
+
+impl fmt::Debug for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ConfigSource")
+    }
+}
+
+/// Applies a reloaded configuration to the running relay.
+#[derive(Debug)]
+pub struct Reloader {
+    /// The shared state of the server.
+    state: Arc<RwLock<AppState>>,
+    /// The authenticator of the relay, if it requires tokens.
+    authenticator: Option<Arc<Authenticator>>,
+    /// Where the configuration is loaded from.
+    source: ConfigSource,
+    /// The configuration the relay runs with.
+    current: Mutex<RelayConfig>,
+}
+
+impl Reloader {
+    /// Creates a reloader for a running relay.
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - The shared state of the server.
+    /// * `authenticator` - The authenticator of the relay, if it requires tokens.
+    /// * `source` - Where the configuration is loaded from.
+    /// * `current` - The configuration the relay was started with.
+    ///
+    /// # Returns
+    ///
+    /// A new `Reloader` instance.
+    pub fn new(
+        state: Arc<RwLock<AppState>>,
+        authenticator: Option<Arc<Authenticator>>,
+        source: ConfigSource,
+        current: RelayConfig,
+    ) -> Reloader {
+        Reloader {
+            state,
+            authenticator,
+            source,
+            current: Mutex::new(current),
+        }
+    }
+
+    /// Loads the configuration again and applies it without closing any room.
+    ///
+    /// # Returns
+    ///
+    /// The settings that changed but are only read at startup, or why the configuration
+    /// cannot be loaded. In that case the relay keeps its current configuration.
+    pub async fn reload(&self) -> Result<Vec<&'static str>, String> {
+        let config = self.source.load()?;
+        let mut current = self.current.lock().await;
+
+        // Collect the changes that only take effect after a restart
+        let mut restart = vec![];
+        if config.tls != current.tls {
+            restart.push("tls");
+        }
+        if config.auth.is_some() != current.auth.is_some() {
+            restart.push("auth");
+        }
+        if config.instance != current.instance {
+            restart.push("instance");
+        }
+        if config.database != current.database {
+            restart.push("database");
+        }
+        if config.qos_capacity != current.qos_capacity {
+            restart.push("qos_capacity");
+        }
+        if config.admin_token.is_some() != current.admin_token.is_some() {
+            restart.push("admin_token");
+        }
+        for setting in &restart {
+            warn!("Changing {setting} requires a restart of the relay, keeping the old value");
+        }
+
+        {
+            let mut data = self.state.write().await;
+            data.room_ttl = config.room_ttl;
+            data.rate_limiter.limits = config.rate_limits;
+            data.blocklist = config.blocklist.clone();
+            data.link_size = config.link_size;
+            data.max_room_size = Room::max_size(config.max_room_size);
+            data.upstream = config.upstream.clone();
+        }
+        current.room_ttl = config.room_ttl;
+        current.rate_limits = config.rate_limits;
+        current.blocklist = config.blocklist;
+        current.link_size = config.link_size;
+        current.max_room_size = config.max_room_size;
+        current.upstream = config.upstream;
+
+        if let (Some(authenticator), Some(auth)) = (&self.authenticator, config.auth) {
+            authenticator.reconfigure(auth.clone()).await;
+            current.auth = Some(auth);
+        }
+        if current.admin_token.is_some() && config.admin_token.is_some() {
+            current.admin_token = config.admin_token;
+        }
+
+        info!(
+            target: AUDIT_TARGET,
+            room_ttl = current.room_ttl.as_secs(),
+            blocked = current.blocklist.networks.len(),
+            "Reloaded the relay configuration"
+        );
+        Ok(restart)
+    }
+
+    /// Returns `true` if the token is the admin token of the relay.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The bearer token of the request.
+    pub(crate) async fn is_admin(&self, token: &str) -> bool {
+        self.current
+            .lock()
+            .await
+            .admin_token
+            .as_deref()
+            .is_some_and(|admin_token| tokens_match(admin_token, token))
+    }
+}
+
+/// Compares two tokens in a time that does not depend on where they differ.
+///
+/// # Arguments
+///
+/// * `expected` - The token the relay was configured with.
+/// * `given` - The token of the request.
+pub(crate) fn tokens_match(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+/// Reloads the configuration whenever the relay receives `SIGHUP`.
+///
+/// # Arguments
+///
+/// * `reloader` - Applies the reloaded configuration.
+#[cfg(unix)]
+pub async fn reload_on_hangup(reloader: Arc<Reloader>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let Ok(mut hangups) = signal(SignalKind::hangup()) else {
+        error!("Failed to install the SIGHUP handler");
+        return;
+    };
+    while hangups.recv().await.is_some() {
+        info!("Received SIGHUP, reloading the configuration");
+        if let Err(e) = reloader.reload().await {
+            error!("Failed to reload the configuration: {e}");
+        }
+    }
+}
+
+/// Handles the `/admin/reload` route.
+///
+/// # Arguments
+///
+/// * `reloader` - Applies the reloaded configuration.
+/// * `ip` - The IP address of the client.
+/// * `headers` - The headers of the request, which carry the admin token.
+///
+/// # Returns
+///
+/// `200 OK` with the settings that require a restart, `401 Unauthorized` without the
+/// admin token, or `500 Internal Server Error` if the configuration cannot be loaded.
+pub async fn reload_handler(
+    State(reloader): State<Arc<Reloader>>,
+    SecureClientIp(ip): SecureClientIp,
+    headers: HeaderMap,
+) -> Response {
+    let token = headers.typed_get::<Authorization<Bearer>>();
+    let authorized = match &token {
+        Some(Authorization(bearer)) => reloader.is_admin(bearer.token()).await,
+        None => false,
+    };
+    if !authorized {
+        warn!(target: AUDIT_TARGET, ip = %ip, "Rejected reload without the admin token");
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({
+                "message": "Invalid admin token."
+            })),
+        )
+            .into_response();
+    }
+
+    match reloader.reload().await {
+        Ok(restart) => (
+            StatusCode::OK,
+            Json(json!({
+                "message": "Reloaded the configuration.",
+                "restart": restart
+            })),
+        )
+            .into_response(),
+        Err(e) => {
+            error!("Failed to reload the configuration: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "message": format!("Failed to reload the configuration: {e}")
+                })),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use crate::relay::{blocklist::Blocklist, limiter::RateLimits};
+
+    #[tokio::test]
+    async fn test_reload() {
+        let initial = RelayConfig {
+            room_ttl: Duration::from_secs(60),
+            qos_capacity: 16,
+            ..RelayConfig::default()
+        };
+        let reloaded = RelayConfig {
+            room_ttl: Duration::from_secs(600),
+            rate_limits: RateLimits {
+                create_room: 5,
+                upload: 10,
+                download: 10,
+            },
+            blocklist: Blocklist::parse(&["203.0.113.0/24"]).unwrap(),
+            qos_capacity: 32,
+            link_size: 1024,
+            max_room_size: Some(5),
+            ..RelayConfig::default()
+        };
+        let source = ConfigSource::new(move || Ok(reloaded.clone()));
+
+        let state = AppState::new();
+        state
+            .write()
+            .await
+            .rooms
+            .insert("room".into(), Room::new(Room::DEFAULT_ROOM_SIZE));
+        let reloader = Reloader::new(state.clone(), None, source, initial);
+
+        assert_eq!(reloader.reload().await.unwrap(), vec!["qos_capacity"]);
+        let data = state.read().await;
+        assert_eq!(data.room_ttl, Duration::from_secs(600));
+        assert_eq!(data.rate_limiter.limits.create_room, 5);
+        assert!(data.blocklist.is_blocked(&"203.0.113.9".parse().unwrap()));
+        assert_eq!(data.link_size, 1024);
+        assert_eq!(data.max_room_size, 5);
+        // The rooms survive the reload
+        assert!(data.rooms.contains_key("room"));
+    }
+
+    #[tokio::test]
+    async fn test_reload_failure_keeps_config() {
+        let source = ConfigSource::new(|| Err("The config file is invalid.".into()));
+        let state = AppState::new();
+        state.write().await.room_ttl = Duration::from_secs(60);
+        let reloader = Reloader::new(state.clone(), None, source, RelayConfig::default());
+
+        assert!(reloader.reload().await.is_err());
+        assert_eq!(state.read().await.room_ttl, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_tokens_match() {
+        assert!(tokens_match("secret", "secret"));
+        assert!(!tokens_match("secret", "secreT"));
+        assert!(!tokens_match("secret", "secret2"));
+        assert!(!tokens_match("secret", ""));
+    }
+}
@@ -1,7 +1,13 @@
 use axum::extract::ws::{Message, WebSocket};
 use futures_util::stream::SplitSink;
-use std::sync::Arc;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::relay::Priority;
 
 // `Sender` is a type alias for a synchronized WebSocket sender.
 //
@@ -13,6 +19,7 @@ type Sender = Arc<Mutex<SplitSink<WebSocket, Message>>>;
 /// A `Room` contains a list of WebSocket senders and a room size.
 /// The senders are used to send messages to the WebSocket connections,
 /// while the room size represents the maximum number of clients allowed in the room.
+/// The creation time is used to close rooms whose members never show up.
 #[derive(Debug, Clone)]
 pub struct Room {
     /// The list of WebSocket senders.
@@ -23,6 +30,10 @@ pub struct Room {
     ///
     /// This represents the maximum number of clients allowed in the room.
     pub size: usize,
+    /// The time the room was created.
+    pub created: Instant,
+    /// The priority the creator asked for.
+    pub priority: Priority,
 }
 
 impl Room {
@@ -31,6 +42,31 @@ impl Room {
     /// This is used as a fallback value when creating a new room.
     pub const DEFAULT_ROOM_SIZE: usize = 2;
 
+    /// The largest room size, a sender with 64 receivers.
+    ///
+    /// Every frame to all members is copied for each of them, so the limit keeps a
+    /// single room from taking over the relay. Operators may lower it with
+    /// `RelayConfig::max_room_size`.
+    pub const MAX_ROOM_SIZE: usize = 65;
+
+    /// Returns the most clients a room may hold under a configured limit.
+    ///
+    /// The positions of the members are sent as a byte, with `u8::MAX` for every
+    /// member, so a limit of embedders outside `DEFAULT_ROOM_SIZE..=MAX_ROOM_SIZE` is
+    /// clamped into it.
+    ///
+    /// # Arguments
+    ///
+    /// * `configured` - The limit of the relay configuration, if any.
+    pub fn max_size(configured: Option<usize>) -> usize {
+        let size = configured.unwrap_or(Self::MAX_ROOM_SIZE);
+        let clamped = size.clamp(Self::DEFAULT_ROOM_SIZE, Self::MAX_ROOM_SIZE);
+        if clamped != size {
+            warn!("The maximum room size {size} is out of range, using {clamped}");
+        }
+        clamped
+    }
+
     /// Create a new room with the specified size.
     ///
     /// # Arguments
@@ -44,8 +80,21 @@ impl Room {
         Room {
             senders: Vec::new(),
             size,
+            created: Instant::now(),
+            priority: Priority::default(),
         }
     }
+
+    /// Returns whether the room waited longer than the TTL for its members.
+    ///
+    /// Full rooms never expire, so long transfers are not interrupted.
+    ///
+    /// # Arguments
+    ///
+    /// * `ttl` - How long a room may wait for its members.
+    pub fn is_expired(&self, ttl: Duration) -> bool {
+        self.senders.len() < self.size && self.created.elapsed() >= ttl
+    }
 }
 #[cfg(test)]
 mod tests {
@@ -59,4 +108,27 @@ mod tests {
 
         assert!(room.senders.is_empty());
     }
+
+    #[test]
+    fn test_is_expired() {
+        let ttl = Duration::from_secs(60);
+        let mut room = Room::new(2);
+        assert!(!room.is_expired(ttl));
+
+        room.created = Instant::now() - ttl;
+        assert!(room.is_expired(ttl));
+
+        // A full room is never closed
+        room.size = 0;
+        assert!(!room.is_expired(ttl));
+    }
+
+    #[test]
+    fn test_max_size() {
+        assert_eq!(Room::max_size(None), Room::MAX_ROOM_SIZE);
+        assert_eq!(Room::max_size(Some(10)), 10);
+        // Positions beyond a byte or on the broadcast address are never handed out
+        assert_eq!(Room::max_size(Some(1000)), Room::MAX_ROOM_SIZE);
+        assert_eq!(Room::max_size(Some(0)), Room::DEFAULT_ROOM_SIZE);
+    }
 }
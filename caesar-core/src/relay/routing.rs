@@ -0,0 +1,150 @@
+//! Sticky routing of both peers of a transfer to the same relay instance.
+//!
+//! Rooms and transfer records only live in the memory of the instance they were
+//! created on. When several relays run behind a load balancer, the sender asks for the
+//! routing token of the instance it reached and presents it with every later request,
+//! and the receiver takes it from the transfer name. The load balancer routes on the
+//! `X-Caesar-Route` header, e.g. with a map from token to upstream, so both peers end
+//! up on the instance that issued the token.
+
+#[cfg(feature = "relay")]
+use std::sync::Arc;
+
+#[cfg(feature = "relay")]
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "relay")]
+use serde_json::json;
+#[cfg(feature = "relay")]
+use tracing::warn;
+
+/// The header the routing token is presented in.
+pub const ROUTE_HEADER: &str = "x-caesar-route";
+
+/// The character that separates the routing token from the transfer name.
+pub const ROUTE_SEPARATOR: char = '@';
+
+/// The answer to a request for a routing token.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RouteResponse {
+    /// The routing token of the instance.
+    pub route: String,
+}
+
+/// Issues the routing token of this instance.
+///
+/// # Arguments
+///
+/// * `instance` - The ID of this instance.
+///
+/// # Returns
+///
+/// A response containing the routing token.
+#[cfg(feature = "relay")]
+pub async fn issue_route(State(instance): State<Arc<String>>) -> impl IntoResponse {
+    Json(RouteResponse {
+        route: instance.to_string(),
+    })
+}
+
+/// Middleware that rejects requests with the routing token of another instance.
+///
+/// Requests without a token are served, so single peers and older clients keep
+/// working.
+///
+/// # Arguments
+///
+/// * `instance` - The ID of this instance.
+/// * `request` - The incoming request.
+/// * `next` - The rest of the middleware stack.
+///
+/// # Returns
+///
+/// The response of the handler, or `421 Misdirected Request` if the request was meant
+/// for another instance.
+#[cfg(feature = "relay")]
+pub async fn check_route(
+    State(instance): State<Arc<String>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = request
+        .headers()
+        .get(ROUTE_HEADER)
+        .map(|route| route.to_str().unwrap_or_default());
+
+    match route {
+        Some(route) if route != instance.as_str() => {
+            warn!("Got a request for instance '{route}', check the load balancer");
+            (
+                StatusCode::MISDIRECTED_REQUEST,
+                Json(json!({
+                    "message": format!("This relay is '{instance}', not '{route}'.")
+                })),
+            )
+                .into_response()
+        }
+        _ => next.run(request).await,
+    }
+}
+
+/// Appends a routing token to a transfer name.
+///
+/// # Arguments
+///
+/// * `name` - The transfer name.
+/// * `route` - The routing token, if the relay issued one.
+///
+/// # Returns
+///
+/// The name the receiver has to enter.
+pub fn join_route(name: &str, route: Option<&str>) -> String {
+    match route {
+        Some(route) => format!("{name}{ROUTE_SEPARATOR}{route}"),
+        None => name.to_string(),
+    }
+}
+
+/// Splits the routing token off a transfer name.
+///
+/// # Arguments
+///
+/// * `name` - The name the receiver entered.
+///
+/// # Returns
+///
+/// The transfer name and the routing token, if the name has one.
+pub fn split_route(name: &str) -> (&str, Option<&str>) {
+    match name.rsplit_once(ROUTE_SEPARATOR) {
+        Some((name, route)) if !route.is_empty() => (name, Some(route)),
+        _ => (name, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_in_name() {
+        let name = join_route("brave-tiger-cookie", Some("relay-2"));
+        assert_eq!(name, "brave-tiger-cookie@relay-2");
+        assert_eq!(split_route(&name), ("brave-tiger-cookie", Some("relay-2")));
+
+        assert_eq!(join_route("brave-tiger-cookie", None), "brave-tiger-cookie");
+        assert_eq!(
+            split_route("brave-tiger-cookie"),
+            ("brave-tiger-cookie", None)
+        );
+        assert_eq!(
+            split_route("brave-tiger-cookie@"),
+            ("brave-tiger-cookie@", None)
+        );
+    }
+}
@@ -1,26 +1,109 @@
 use axum::{
-    extract::{ws::WebSocket, Json, Path, State, WebSocketUpgrade},
-    http::StatusCode,
-    response::IntoResponse,
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket},
+        ConnectInfo, Extension, Json, Path, State, WebSocketUpgrade,
+    },
+    http::{HeaderMap, StatusCode},
+    middleware,
+    response::{
+        sse::{KeepAlive, Sse},
+        IntoResponse, Response,
+    },
     routing::{get, post, put},
     Router,
 };
 
-use futures_util::StreamExt;
+use axum_client_ip::{SecureClientIp, SecureClientIpSource};
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use futures_util::{future::join_all, SinkExt, StreamExt};
 use serde_json::json;
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Weak},
+    time::Duration,
+};
 use tokio::{
     net::TcpListener,
     signal,
     sync::{Mutex, RwLock},
+    time::sleep,
 };
 use tower_http::trace::{DefaultMakeSpan, TraceLayer};
 use tracing::{debug, error, info, warn};
 
+use crate::net;
+use crate::relay::auth::{self, AuthConfig, Authenticator, Identity};
+use crate::relay::blocklist::{self, Blocklist};
 use crate::relay::client::Client;
-use crate::relay::transfer::TransferResponse;
+use crate::relay::dashboard::{self, Dashboard};
+use crate::relay::events::{self, RoomEvent};
+use crate::relay::federation::{self, ForwardedRoom};
+use crate::relay::limiter::{RateLimited, RateLimiter, RateLimits};
+use crate::relay::link;
+use crate::relay::punch;
+use crate::relay::qos::Qos;
+use crate::relay::reload::{self, ConfigSource, Reloader};
+use crate::relay::room::Room;
+use crate::relay::routing::{self, ROUTE_HEADER};
+use crate::relay::store::TransferStore;
+use crate::relay::transfer::{HistoryEntry, TransferResponse, SCHEMA_VERSION};
+use crate::relay::{ResponsePacket, AUDIT_TARGET};
 use crate::relay::{appstate::AppState, transfer::TransferRequest};
 
+/// The certificate and private key the relay serves HTTPS and WSS with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsConfig {
+    /// The path of the PEM encoded certificate chain.
+    pub cert: PathBuf,
+    /// The path of the PEM encoded private key.
+    pub key: PathBuf,
+}
+
+/// The optional features of the relay.
+#[derive(Debug, Clone, Default)]
+pub struct RelayConfig {
+    /// The certificate to serve HTTPS and WSS with. If `None`, plain HTTP and WS are
+    /// served, e.g. behind a reverse proxy that terminates TLS.
+    pub tls: Option<TlsConfig>,
+    /// The identity provider every request must carry a token of. If `None`, the relay
+    /// is open to everyone.
+    pub auth: Option<AuthConfig>,
+    /// How long a room may wait for its members before it is closed. A zero TTL keeps
+    /// rooms until their members leave.
+    pub room_ttl: Duration,
+    /// The ID of this instance among several relays behind a load balancer. If set, it
+    /// is issued to senders as their routing token.
+    pub instance: Option<String>,
+    /// The SQLite database the transfers are kept in, so they survive a restart. If
+    /// `None`, they only live in memory.
+    pub database: Option<PathBuf>,
+    /// The requests a client address may make per minute. The default has no limits.
+    pub rate_limits: RateLimits,
+    /// The frames in flight from which on the relay prefers rooms of a higher priority.
+    /// Zero forwards all frames alike.
+    pub qos_capacity: usize,
+    /// The client addresses the relay refuses to serve.
+    pub blocklist: Blocklist,
+    /// The token that authorizes `POST /admin/reload`. If `None`, the configuration is
+    /// only reloaded on `SIGHUP`.
+    pub admin_token: Option<String>,
+    /// Where the configuration is loaded from again on `SIGHUP` or `POST /admin/reload`.
+    /// If `None`, the relay keeps the configuration it was started with.
+    pub reload: Option<ConfigSource>,
+    /// The largest file in bytes senders may leave on the relay for a one-time download
+    /// link. The files wait in memory for up to the room TTL. Zero offers no links.
+    pub link_size: u64,
+    /// The most clients a room may hold, the sender included. It is clamped to
+    /// `Room::DEFAULT_ROOM_SIZE..=Room::MAX_ROOM_SIZE`. If `None`, a sender may create
+    /// rooms for up to `Room::MAX_ROOM_SIZE - 1` receivers.
+    pub max_room_size: Option<usize>,
+    /// The relay that transfers unknown to this relay are looked up at, e.g. the public
+    /// relay behind an internal one. Receivers that join their rooms are connected
+    /// through. If `None`, unknown transfers are not found.
+    pub upstream: Option<String>,
+}
+
 /// Start the WebSocket server.
 ///
 /// This function initializes the server and starts listening for incoming connections.
@@ -30,32 +113,46 @@ use crate::relay::{appstate::AppState, transfer::TransferRequest};
 /// # Arguments
 ///
 /// * `port` - The port number to listen on.
-/// * `listen_addr` - The IP address to listen on.
+/// * `listen_addr` - The IP address to listen on. `::` listens on IPv4 as well.
+/// * `config` - The optional features of the relay.
 #[allow(clippy::unused_self)]
-pub async fn start_ws(port: &i32, listen_addr: &String) {
+pub async fn start_ws(port: &i32, listen_addr: &String, config: RelayConfig) {
     // Log the server configuration.
     debug!("Server configured to accept connections on host {listen_addr}...");
     debug!("Server configured to listen connections on port {port}...");
 
-    // Create a new instance of the server state.
-    let server = AppState::new();
+    // Create a new instance of the server state, with the transfers that were announced
+    // before the last restart.
+    let server = match relay_state(&config).await {
+        Ok(server) => server,
+        Err(e) => {
+            let path = config.database.unwrap_or_default();
+            error!("Failed to load the transfers from {}: {e}", path.display());
+            return;
+        }
+    };
 
-    // Set up the routes for the server.
-    let app = Router::new()
-        .route("/ws", get(ws_handler))
-        .route("/upload", put(upload_info))
-        .route("/download/:name", get(download_info))
-        .route("/download_success/:name", post(download_success))
-        .with_state(server)
-        // Set up the tracing layer to log incoming requests.
-        .layer(
-            TraceLayer::new_for_http()
-                .make_span_with(DefaultMakeSpan::default().include_headers(true)),
-        );
+    let tls = config.tls.clone();
+    let (routes, reloader) = build_router(server, config);
+
+    // Reload the configuration on SIGHUP as well.
+    #[cfg(unix)]
+    let hangup = reloader.map(|reloader| tokio::spawn(reload::reload_on_hangup(reloader)));
+    #[cfg(not(unix))]
+    drop(reloader);
+
+    // Set up the tracing layer to log incoming requests.
+    let app = routes.layer(
+        TraceLayer::new_for_http().make_span_with(DefaultMakeSpan::default().include_headers(true)),
+    );
 
-    // Start listening for incoming connections.
-    let addr = format!("{}:{}", listen_addr, port);
-    if let Ok(listener) = TcpListener::bind(&addr).await {
+    // Start listening for incoming connections, on IPv4 as well if the address is `::`.
+    let addr = net::join_host_port(listen_addr, port);
+
+    // Terminate TLS in the relay itself if a certificate is configured.
+    if let Some(tls) = tls {
+        serve_tls(app, &addr, tls).await;
+    } else if let Ok(listener) = net::bind(&addr).and_then(TcpListener::from_std) {
         let local_addr = listener.local_addr().unwrap();
         info!("Listening on: {}", local_addr);
 
@@ -69,7 +166,312 @@ pub async fn start_ws(port: &i32, listen_addr: &String) {
         .unwrap();
     } else {
         // Log if the server failed to start.
-        error!("Failed to listen on: {listen_addr}:{port}");
+        error!("Failed to listen on: {addr}");
+    }
+
+    // Stop reloading once the server is down. The rooms stop expiring once the state is
+    // dropped.
+    #[cfg(unix)]
+    if let Some(hangup) = hangup {
+        hangup.abort();
+    }
+}
+
+/// Creates the state of a relay with the given features.
+///
+/// If the configuration has a database, the transfers that were announced before the
+/// last restart are loaded from it, and new ones are written through to it.
+///
+/// # Arguments
+///
+/// * `config` - The optional features of the relay.
+///
+/// # Returns
+///
+/// The state to build the routes with, or the error if the database cannot be opened
+/// or read.
+pub async fn relay_state(config: &RelayConfig) -> Result<Arc<RwLock<AppState>>, sqlx::Error> {
+    let server = AppState::new();
+    {
+        let mut data = server.write().await;
+        data.rate_limiter = RateLimiter::new(config.rate_limits);
+        data.qos = Arc::new(Qos::new(config.qos_capacity));
+        data.room_ttl = config.room_ttl;
+        data.blocklist = config.blocklist.clone();
+        data.link_size = config.link_size;
+        data.max_room_size = Room::max_size(config.max_room_size);
+        data.upstream = config.upstream.clone();
+    }
+
+    if let Some(path) = &config.database {
+        let store = TransferStore::open(path).await?;
+        let transfers = store.load().await?;
+        info!("Loaded {} transfers from {}", transfers.len(), path.display());
+        let mut data = server.write().await;
+        data.transfers = transfers;
+        data.store = Some(store);
+    }
+
+    Ok(server)
+}
+
+/// Builds the routes of the relay, e.g. to mount them in another axum application.
+///
+/// Nested under a prefix like `/relay`, clients reach the relay at
+/// `https://example.com/relay`. The routes read the address of the client from
+/// `ConnectInfo`, so the application has to be served with
+/// `into_make_service_with_connect_info::<SocketAddr>()`.
+///
+/// The application serves the routes itself, so `tls` of the configuration is not used,
+/// and the database is opened by `relay_state`. Rooms that wait longer than the TTL are
+/// closed as long as the state lives. The configuration is only reloaded through
+/// `POST /admin/reload`, as `SIGHUP` belongs to the application.
+///
+/// # Arguments
+///
+/// * `state` - The state of the relay, created by `relay_state`.
+/// * `config` - The optional features of the relay.
+///
+/// # Returns
+///
+/// The routes of the relay.
+pub fn router(state: Arc<RwLock<AppState>>, config: RelayConfig) -> Router {
+    build_router(state, config).0
+}
+
+/// Builds the routes of the relay and starts closing expired rooms.
+///
+/// # Arguments
+///
+/// * `state` - The state of the relay.
+/// * `config` - The optional features of the relay.
+///
+/// # Returns
+///
+/// The routes, and the reloader of the configuration if it can be reloaded.
+fn build_router(
+    state: Arc<RwLock<AppState>>,
+    config: RelayConfig,
+) -> (Router, Option<Arc<Reloader>>) {
+    let current = config.clone();
+    let RelayConfig {
+        auth,
+        instance,
+        admin_token,
+        reload,
+        ..
+    } = config;
+
+    // Close rooms whose members never show up, e.g. because the sender crashed.
+    tokio::spawn(expire_rooms(Arc::downgrade(&state)));
+
+    // Set up the routes for the server.
+    let mut routes = Router::new()
+        .route("/ws", get(ws_handler))
+        .route("/punch/:room_id", get(punch_handler))
+        .route("/upload", put(upload_info))
+        .route("/download/:name", get(download_info))
+        .route("/download_success/:name", post(download_success))
+        .route("/link/:id", put(link::upload_link));
+
+    // Require a token for every route if an identity provider is configured.
+    // Only then transfers have an owner who can list them.
+    let authenticator = auth.map(|auth| {
+        info!("Accepting tokens issued by {}", auth.issuer);
+        Arc::new(Authenticator::new(auth))
+    });
+    if let Some(authenticator) = authenticator.clone() {
+        routes = routes
+            .route("/transfers", get(list_transfers))
+            .route("/rooms/:id/events", get(room_events))
+            .route_layer(middleware::from_fn_with_state(
+            authenticator,
+            auth::require_token,
+        ));
+    }
+
+    // Recipients of download links open them in a browser, without a token.
+    routes = routes
+        .route("/link/:id", get(link::link_page))
+        .route("/link/:id/data", get(link::link_data));
+
+    // Issue the instance ID as routing token and reject requests meant for another
+    // instance, which shows a misconfigured load balancer right away.
+    if let Some(instance) = instance {
+        info!("Issuing the routing token {instance}");
+        let instance = Arc::new(instance);
+        routes = routes
+            .merge(
+                Router::new()
+                    .route("/route", get(routing::issue_route))
+                    .with_state(instance.clone()),
+            )
+            .route_layer(middleware::from_fn_with_state(
+                instance,
+                routing::check_route,
+            ));
+    }
+
+    // Reload the configuration on request of the admin. The admin route is checked
+    // against the admin token instead of the identity provider.
+    let reloader =
+        reload.map(|source| Arc::new(Reloader::new(state.clone(), authenticator, source, current)));
+    if let (Some(reloader), Some(_)) = (&reloader, &admin_token) {
+        routes = routes.merge(
+            Router::new()
+                .route("/admin/reload", post(reload::reload_handler))
+                .with_state(reloader.clone()),
+        );
+    }
+
+    // Show the status of the relay to its admin.
+    if let Some(admin_token) = admin_token {
+        let dashboard = Dashboard::new(state.clone(), admin_token, reloader.clone());
+        routes = routes.merge(
+            Router::new()
+                .route("/admin", get(dashboard::dashboard_page))
+                .route("/admin/status", get(dashboard::status_handler))
+                .with_state(Arc::new(dashboard)),
+        );
+    }
+
+    let routes = routes
+        .with_state(state.clone())
+        // Reject blocked clients before any handler runs.
+        .layer(middleware::from_fn_with_state(
+            state,
+            blocklist::reject_blocked,
+        ))
+        // Make the client IP available to the handlers.
+        .layer(SecureClientIpSource::ConnectInfo.into_extension());
+
+    (routes, reloader)
+}
+
+/// Closes the rooms that waited longer than the TTL for their members.
+///
+/// The remaining members get an error packet and a close frame with the reason, so they
+/// stop waiting as well. The files of download links nobody used within the TTL are
+/// dropped too, and so are the failed join attempts that are old enough to be
+/// forgotten. Both are dropped whatever the TTL, with a zero TTL the files wait for
+/// `SpooledFile::DEFAULT_TTL`.
+///
+/// The TTL is read from the state on every check, so a reloaded TTL applies to the
+/// rooms that are already open. A zero TTL keeps all rooms.
+///
+/// # Arguments
+///
+/// * `state` - The shared state of the server, which is not kept alive by this task.
+async fn expire_rooms(state: Weak<RwLock<AppState>>) {
+    let reason = "The room expired.";
+
+    // Stop once the relay is gone and nobody holds the state anymore.
+    while let Some(shared) = state.upgrade() {
+        // Check often enough that no room outlives its TTL by much.
+        let ttl = shared.read().await.room_ttl;
+        drop(shared);
+        let interval = (ttl / 10).clamp(Duration::from_secs(1), Duration::from_secs(60));
+        sleep(interval).await;
+
+        let Some(shared) = state.upgrade() else {
+            return;
+        };
+        let expired = {
+            let mut data = shared.write().await;
+            // Failed join attempts are forgotten here rather than on every failure
+            data.remove_expired_joins();
+            let ttl = data.room_ttl;
+            // Links expire even if the rooms are kept
+            let links = data.remove_expired_links(ttl);
+            if links > 0 {
+                info!(target: AUDIT_TARGET, links, "Dropped expired download links");
+            }
+            if ttl.is_zero() {
+                continue;
+            }
+            data.remove_expired_forwards(ttl);
+            data.remove_expired_rooms(ttl)
+        };
+
+        let mut futures = vec![];
+        for (room_id, room) in expired {
+            info!(
+                target: AUDIT_TARGET,
+                room_id = %room_id,
+                members = room.senders.len(),
+                "Closed expired room"
+            );
+
+            for sender in room.senders {
+                futures.push(async move {
+                    let error = ResponsePacket::Error {
+                        message: reason.to_string(),
+                    };
+                    let close = CloseFrame {
+                        code: close_code::AWAY,
+                        reason: reason.into(),
+                    };
+
+                    let mut sender = sender.lock().await;
+                    let _ = sender
+                        .send(Message::Text(serde_json::to_string(&error).unwrap()))
+                        .await;
+                    let _ = sender.send(Message::Close(Some(close))).await;
+                });
+            }
+        }
+        join_all(futures).await;
+    }
+}
+
+/// Serves the relay over HTTPS and WSS.
+///
+/// The server shuts down gracefully on the same signals as the plain server.
+///
+/// # Arguments
+///
+/// * `app` - The router of the relay.
+/// * `addr` - The address to listen on.
+/// * `tls` - The certificate and private key to serve with.
+async fn serve_tls(app: Router, addr: &str, tls: TlsConfig) {
+    // Use ring for the cryptography, unless a provider was installed before.
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    // Load the certificate and the private key.
+    let config = match RustlsConfig::from_pem_file(&tls.cert, &tls.key).await {
+        Ok(config) => config,
+        Err(error) => {
+            error!(
+                "Failed to load the TLS certificate '{}' and key '{}': {error}",
+                tls.cert.display(),
+                tls.key.display()
+            );
+            return;
+        }
+    };
+
+    let Ok(listener) = net::bind(addr) else {
+        // Log if the server failed to start.
+        error!("Failed to listen on: {addr}");
+        return;
+    };
+    info!("Listening with TLS on: {}", listener.local_addr().unwrap());
+
+    // Stop accepting connections once a shutdown signal arrives.
+    let handle = Handle::new();
+    let shutdown = handle.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        shutdown.graceful_shutdown(None);
+    });
+
+    // Serve the app and make the client address available to the handlers.
+    if let Err(error) = axum_server::from_tcp_rustls(listener, config)
+        .handle(handle)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await
+    {
+        error!("Failed to serve with TLS: {error}");
     }
 }
 
@@ -80,9 +482,11 @@ pub async fn start_ws(port: &i32, listen_addr: &String) {
 /// # Arguments
 ///
 /// * `ws` - The WebSocketUpgrade struct containing the upgrade request.
+/// * `ip` - The IP address of the client.
 /// * `shared_state` - The shared state of the server.
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
+    SecureClientIp(ip): SecureClientIp,
     State(shared_state): State<Arc<RwLock<AppState>>>,
 ) -> impl IntoResponse {
     // Log the request on the WebSocket route.
@@ -91,9 +495,25 @@ pub async fn ws_handler(
     debug!("Upgrading Connection");
     // Upgrade the connection to a WebSocket and handle the socket.
     // Move the shared state to the handler to avoid holding the lock during the entire connection.
-    ws.on_upgrade(move |socket| handle_socket(socket, shared_state))
+    ws.on_upgrade(move |socket| handle_socket(socket, shared_state, ip))
 }
 
+/// Handler for the rendezvous of hole punching.
+///
+/// # Arguments
+///
+/// * `ws` - The WebSocketUpgrade struct containing the upgrade request.
+/// * `address` - The address the peer connected from, including its port.
+/// * `room_id` - The room both peers meet in.
+/// * `shared_state` - The shared state of the server.
+pub async fn punch_handler(
+    ws: WebSocketUpgrade,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Path(room_id): Path<String>,
+    State(shared_state): State<Arc<RwLock<AppState>>>,
+) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| punch::rendezvous(socket, shared_state, room_id, address))
+}
 
 /// Handles the WebSocket connection.
 ///
@@ -105,7 +525,8 @@ pub async fn ws_handler(
 ///
 /// * `socket` - The WebSocket connection.
 /// * `rooms` - The shared state of the server.
-async fn handle_socket(socket: WebSocket, rooms: Arc<RwLock<AppState>>) {
+/// * `ip` - The IP address of the client.
+async fn handle_socket(socket: WebSocket, rooms: Arc<RwLock<AppState>>, ip: IpAddr) {
     // Split the WebSocket into a sender and receiver.
     let (sender, mut receiver) = socket.split();
 
@@ -113,12 +534,18 @@ async fn handle_socket(socket: WebSocket, rooms: Arc<RwLock<AppState>>) {
     let sender = Arc::new(Mutex::new(sender));
 
     // Create a new client with the sender.
-    let mut client = Client::new(sender.clone());
+    let mut client = Client::new(sender.clone(), ip);
 
     // Handle the messages received from the client.
     while let Some(message) = receiver.next().await {
         match message {
             Ok(message) => {
+                // A client that joins a room of the upstream relay is connected through
+                if !client.is_in_room() {
+                    if let Some(room) = federation::joined_room(&rooms, &message).await {
+                        return federation::proxy(sender, receiver, room, message).await;
+                    }
+                }
                 // Handle the message received from the client.
                 client.handle_message(&rooms, message).await;
             }
@@ -165,9 +592,15 @@ async fn shutdown_signal() {
 /// based on the payload. If the request is not found, it creates a new transfer request and
 /// adds it to the shared state.
 ///
+/// If the relay requires tokens, only the user who registered a transfer may update it,
+/// and a user may not register more transfers than their quota allows. Clients that
+/// upload too often are answered with `429 Too Many Requests`.
+///
 /// # Arguments
 ///
 /// * `shared_state` - The shared state containing the transfer requests.
+/// * `ip` - The IP address of the client, whose uploads are rate limited.
+/// * `identity` - The authenticated user, if the relay requires tokens.
 /// * `payload` - The JSON payload containing the transfer request information.
 ///
 /// # Returns
@@ -175,9 +608,26 @@ async fn shutdown_signal() {
 /// A tuple of the HTTP status code and the JSON response.
 pub async fn upload_info(
     State(shared_state): State<Arc<RwLock<AppState>>>,
+    SecureClientIp(ip): SecureClientIp,
+    identity: Option<Extension<Identity>>,
     Json(payload): Json<TransferRequest>,
 ) -> impl IntoResponse {
     let mut data = shared_state.write().await;
+    if !data.rate_limiter.allow(RateLimited::Upload, ip) {
+        warn!(target: AUDIT_TARGET, ip = %ip, "Rejected upload, the rate limit is exceeded");
+        return (StatusCode::TOO_MANY_REQUESTS, Json(TransferResponse::default()));
+    }
+    let owner = identity.as_ref().map(|Extension(identity)| identity.subject.clone());
+    // Whether the user already has as many transfers as their quota allows
+    let over_quota = identity.as_ref().is_some_and(|Extension(identity)| {
+        identity.quota > 0
+            && data
+                .transfers
+                .iter()
+                .filter(|request| request.owner == owner)
+                .count()
+                >= identity.quota
+    });
 
     // Find the transfer request in the shared state
     match data
@@ -185,21 +635,42 @@ pub async fn upload_info(
         .iter_mut()
         .find(|request| request.name == payload.name)
     {
+        // Only the user who registered the transfer may update it
+        Some(request) if request.owner.is_some() && request.owner != owner => {
+            warn!(
+                target: AUDIT_TARGET,
+                user = ?owner,
+                name = %request.name,
+                "Rejected update of a transfer of another user"
+            );
+            (StatusCode::FORBIDDEN, Json(TransferResponse::default()))
+        }
         // Update the relay_room_id or local_room_id if the request is found
         Some(request) => {
             debug!("Found Transfer");
             debug!("Request is: {:?}", request);
             if request.relay_room_id.is_empty() {
                 request.relay_room_id = payload.relay_room_id;
-                debug!("Found Transfer and updated");
-                debug!("request is: {:#?}", request);
-                (StatusCode::OK, Json(request.clone()))
             } else {
                 request.local_room_id = payload.local_room_id;
-                debug!("Found Transfer and updated");
-                debug!("request is: {:#?}", request);
-                (StatusCode::OK, Json(request.clone()))
+                request.port = payload.port;
             }
+            // Keep the fields of newer senders, so newer receivers get them
+            request.extra.extend(payload.extra);
+            debug!("Found Transfer and updated");
+            debug!("request is: {:#?}", request);
+            let request = request.clone();
+            data.persist_transfer(&request).await;
+            (StatusCode::OK, Json(request))
+        }
+        // Reject the transfer if the user has used up their quota
+        None if over_quota => {
+            warn!(
+                target: AUDIT_TARGET,
+                user = ?owner,
+                "Rejected transfer, the quota is used up"
+            );
+            (StatusCode::TOO_MANY_REQUESTS, Json(TransferResponse::default()))
         }
         // Create a new transfer request if the request is not found
         None => {
@@ -217,9 +688,25 @@ pub async fn upload_info(
                 ip: payload.ip,
                 local_room_id: local,
                 relay_room_id: relay,
+                port: payload.port,
+                schema_version: SCHEMA_VERSION,
+                extra: payload.extra,
+                owner,
             };
             // Add the transfer request to the shared state
             data.transfers.push(t_request.clone());
+            data.persist_transfer(&t_request).await;
+            // Remember the transfer in the history of its owner
+            if let Some(owner) = &t_request.owner {
+                data.record_transfer(owner, &t_request.name);
+            }
+
+            info!(
+                target: AUDIT_TARGET,
+                user = ?t_request.owner,
+                name = %t_request.name,
+                "Transfer registered"
+            );
 
             debug!("New TransferRequest created");
             debug!("Actual AppState is {:#?}", *data);
@@ -236,38 +723,79 @@ pub async fn upload_info(
 /// # Arguments
 ///
 /// * `shared_state` - The shared state containing the transfer requests.
+/// * `ip` - The IP address of the client, whose lookups are rate limited.
 /// * `name` - The name of the transfer request.
 ///
 /// # Returns
 ///
-/// Returns a response containing the transfer request if found, or a not found
-/// response if the transfer request is not found.
+/// Returns a response containing the transfer request if found, a not found
+/// response if the transfer request is not found, `429 Too Many Requests` if the
+/// client looked up too many transfers, or `503 Service Unavailable` if no further
+/// room of the upstream relay can be remembered.
 pub async fn download_info(
     State(shared_state): State<Arc<RwLock<AppState>>>,
+    SecureClientIp(ip): SecureClientIp,
+    headers: HeaderMap,
     Path(name): Path<String>,
 ) -> impl IntoResponse {
     // Acquire write lock on shared state
-    let data = shared_state.write().await;
+    let mut data = shared_state.write().await;
+    if !data.rate_limiter.allow(RateLimited::Download, ip) {
+        warn!(target: AUDIT_TARGET, ip = %ip, "Rejected download, the rate limit is exceeded");
+        return (StatusCode::TOO_MANY_REQUESTS, Json(TransferResponse::default()));
+    }
 
     // Find transfer request by name
-    match data.transfers.iter().find(|request| request.name == name) {
+    if let Some(request) = data.transfers.iter().find(|request| request.name == name) {
         // If transfer request is found, return Ok response with the transfer request
-        Some(request) => {
-            debug!("Found transfer name.");
-            (StatusCode::OK, Json(request.clone()))
+        debug!("Found transfer name.");
+        return (StatusCode::OK, Json(request.clone()));
+    }
+
+    // The lookup counts against the rate limit above, and none is sent while no
+    // further room of the upstream relay can be remembered
+    let upstream = data.upstream.clone();
+    if upstream.is_some() && data.is_forwarding_full() {
+        warn!("Skipped the upstream lookup of '{name}', too many rooms are forwarded");
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(TransferResponse::default()),
+        );
+    }
+    // Look the transfer up at the upstream relay, without holding up the others
+    let client = data.federation.clone();
+    drop(data);
+    let route = headers
+        .get(ROUTE_HEADER)
+        .and_then(|route| route.to_str().ok());
+    let found = match &upstream {
+        Some(upstream) => federation::lookup(&client, upstream, &name, route).await,
+        None => None,
+    };
+    match (upstream, found) {
+        // Remember the room, so the receiver is connected through when it joins
+        (Some(upstream), Some(request)) => {
+            debug!("Found transfer name at {upstream}.");
+            if !request.relay_room_id.is_empty() {
+                let room = ForwardedRoom::new(name, upstream, route.map(str::to_string));
+                let id = request.relay_room_id.clone();
+                if !shared_state.write().await.forward(id, room) {
+                    warn!("Dropped a room of the upstream relay, too many rooms are forwarded");
+                    return (
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        Json(TransferResponse::default()),
+                    );
+                }
+            }
+            (StatusCode::OK, Json(request))
         }
         // If transfer request is not found, return not found response
-        None => {
+        _ => {
             warn!("couldn't find transfer-name: {}", name);
             (
                 StatusCode::NOT_FOUND,
-                Json(TransferResponse {
-                    // Create a new empty transfer response
-                    name: String::from(""),
-                    ip: String::from(""),
-                    local_room_id: String::from(""),
-                    relay_room_id: String::from(""),
-                }),
+                // Create a new empty transfer response
+                Json(TransferResponse::default()),
             )
         }
     }
@@ -278,6 +806,7 @@ pub async fn download_info(
 /// # Arguments
 ///
 /// * `shared_state` - The shared state containing the transfer requests.
+/// * `identity` - The authenticated user, if the relay requires tokens.
 /// * `name` - The name of the transfer request.
 ///
 /// # Returns
@@ -287,6 +816,7 @@ pub async fn download_info(
 /// response is returned.
 pub async fn download_success(
     State(shared_state): State<Arc<RwLock<AppState>>>,
+    identity: Option<Extension<Identity>>,
     Path(name): Path<String>,
 ) -> impl IntoResponse {
     let mut data = shared_state.write().await;
@@ -298,8 +828,24 @@ pub async fn download_success(
     {
         // If the transfer request is found, remove it from the shared state
         debug!("Found Transfer by name '{name}'");
-        data.transfers.remove(index);
+        let transfer = data.transfers.remove(index);
+        data.forget_transfer(&name).await;
         debug!("Transfer deleted");
+        if let Some(owner) = &transfer.owner {
+            data.complete_transfer(owner, &name);
+        }
+        // Let the watchers of the room know that they can stop waiting.
+        if !transfer.relay_room_id.is_empty() {
+            data.watchers
+                .finish(&transfer.relay_room_id, RoomEvent::Complete);
+        }
+        info!(
+            target: AUDIT_TARGET,
+            user = ?identity.map(|Extension(identity)| identity.subject),
+            owner = ?transfer.owner,
+            name = %name,
+            "Transfer completed"
+        );
         // Return a success response
         (
             StatusCode::OK,
@@ -307,6 +853,18 @@ pub async fn download_success(
                 "message": "transfer deleted"
             })),
         )
+    } else if let Some(room) = data.take_forwarded(&name) {
+        // The transfer belongs to the upstream relay, which is told instead
+        let client = data.federation.clone();
+        drop(data);
+        federation::complete(&client, &room).await;
+        debug!("Forwarded transfer completed at {}", room.upstream);
+        (
+            StatusCode::OK,
+            Json(json!({
+                "message": "transfer deleted"
+            })),
+        )
     } else {
         // If the transfer request is not found, return a not found response
         warn!("couldn't find transfer-name: {}", name);
@@ -318,3 +876,88 @@ pub async fn download_success(
         )
     }
 }
+
+/// Lists the recent transfers of the authenticated user, newest first.
+///
+/// The route only exists if the relay requires tokens.
+///
+/// # Arguments
+///
+/// * `shared_state` - The shared state containing the transfer history.
+/// * `identity` - The authenticated user.
+///
+/// # Returns
+///
+/// Returns a response containing the history entries of the user.
+pub async fn list_transfers(
+    State(shared_state): State<Arc<RwLock<AppState>>>,
+    Extension(identity): Extension<Identity>,
+) -> impl IntoResponse {
+    let data = shared_state.read().await;
+
+    let history: Vec<HistoryEntry> = data
+        .history
+        .get(&identity.subject)
+        .map(|history| history.iter().cloned().collect())
+        .unwrap_or_default();
+
+    (StatusCode::OK, Json(history))
+}
+
+/// Streams the events of a room as server-sent events.
+///
+/// Watchers are told whenever a client joins or leaves the room, and the stream ends
+/// with a `complete` event once the receiver reports the transfer as complete, or with
+/// an `expired` event if the room is closed before. The route only exists if the relay
+/// requires tokens, and only the user who registered the transfer may watch its room.
+///
+/// # Arguments
+///
+/// * `shared_state` - The shared state containing the transfers and the watchers.
+/// * `identity` - The authenticated user.
+/// * `room_id` - The ID of the room to watch.
+///
+/// # Returns
+///
+/// Returns the stream of events, or a not found response if the user registered no
+/// transfer with the room.
+pub async fn room_events(
+    State(shared_state): State<Arc<RwLock<AppState>>>,
+    Extension(identity): Extension<Identity>,
+    Path(room_id): Path<String>,
+) -> Response {
+    let mut data = shared_state.write().await;
+
+    // Rooms of other users are not found, so their IDs cannot be probed
+    let owned = data.transfers.iter().any(|request| {
+        request.relay_room_id == room_id && request.owner.as_ref() == Some(&identity.subject)
+    });
+    if !owned {
+        warn!(
+            target: AUDIT_TARGET,
+            user = %identity.subject,
+            room_id = %room_id,
+            "Rejected watching a room without a transfer of the user"
+        );
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "message": "room not found"
+            })),
+        )
+            .into_response();
+    }
+
+    let receiver = data.watchers.subscribe(&room_id);
+    drop(data);
+
+    info!(
+        target: AUDIT_TARGET,
+        user = %identity.subject,
+        room_id = %room_id,
+        "Watching room"
+    );
+    Sse::new(events::event_stream(receiver))
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
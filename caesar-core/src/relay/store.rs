@@ -0,0 +1,259 @@
+//! Persistence of the transfer records of the relay in SQLite.
+//!
+//! The records in `AppState` are the ones the handlers work with. Every change is
+//! written through to the database, and the records are loaded back when the relay
+//! starts, so transfers that were announced before a deploy or a crash can still be
+//! downloaded afterwards. Rooms are not persisted, their websockets do not survive a
+//! restart anyway and the clients create them again when they reconnect.
+
+use std::path::Path;
+
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool},
+    Error,
+};
+
+use crate::relay::transfer::{TransferResponse, SCHEMA_VERSION};
+
+/// The columns that were added to the table of the transfer records after its first
+/// version, with their definitions.
+const ADDED_COLUMNS: [(&str, &str); 2] = [
+    ("port", "INTEGER NOT NULL DEFAULT 9000"),
+    ("extra", "TEXT NOT NULL DEFAULT '{}'"),
+];
+
+/// A transfer record as it is stored: the name, the IP address, the room IDs, the owner,
+/// the port and the fields of newer schema versions as JSON.
+type Row = (String, String, String, String, Option<String>, u16, String);
+
+/// A handle to the database the transfer records are kept in.
+///
+/// It can be cloned cheaply, all clones share the same connection pool.
+#[derive(Debug, Clone)]
+pub struct TransferStore {
+    /// The connection pool of the database.
+    pool: SqlitePool,
+}
+
+impl TransferStore {
+    /// Opens the database and creates the table of the transfer records if needed.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the database file. It is created if it does not exist.
+    ///
+    /// # Returns
+    ///
+    /// A new `TransferStore` instance, or the error if the database cannot be opened.
+    pub async fn open(path: &Path) -> Result<TransferStore, Error> {
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal);
+        let pool = SqlitePool::connect_with(options).await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS transfers (
+                name TEXT PRIMARY KEY NOT NULL,
+                ip TEXT NOT NULL,
+                local_room_id TEXT NOT NULL,
+                relay_room_id TEXT NOT NULL,
+                owner TEXT,
+                port INTEGER NOT NULL DEFAULT 9000,
+                extra TEXT NOT NULL DEFAULT '{}'
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        // Databases of older relays lack the columns that were added since
+        for (column, definition) in ADDED_COLUMNS {
+            let (exists,): (bool,) = sqlx::query_as(
+                "SELECT COUNT(*) > 0 FROM pragma_table_info('transfers') WHERE name = ?",
+            )
+            .bind(column)
+            .fetch_one(&pool)
+            .await?;
+            if !exists {
+                sqlx::query(&format!(
+                    "ALTER TABLE transfers ADD COLUMN {column} {definition}"
+                ))
+                .execute(&pool)
+                .await?;
+            }
+        }
+
+        Ok(TransferStore { pool })
+    }
+
+    /// Loads all transfer records in the order they were registered.
+    ///
+    /// # Returns
+    ///
+    /// The transfer records, or the error if the database cannot be read.
+    pub async fn load(&self) -> Result<Vec<TransferResponse>, Error> {
+        let rows: Vec<Row> = sqlx::query_as(
+            "SELECT name, ip, local_room_id, relay_room_id, owner, port, extra
+            FROM transfers ORDER BY rowid",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(
+                |(name, ip, local_room_id, relay_room_id, owner, port, extra)| TransferResponse {
+                    name,
+                    ip,
+                    local_room_id,
+                    relay_room_id,
+                    port,
+                    schema_version: SCHEMA_VERSION,
+                    // The relay wrote the fields itself, they only fail to parse if edited
+                    extra: serde_json::from_str(&extra).unwrap_or_default(),
+                    owner,
+                },
+            )
+            .collect())
+    }
+
+    /// Inserts a transfer record, or replaces the one with the same name.
+    ///
+    /// # Arguments
+    ///
+    /// * `transfer` - The transfer record to save.
+    pub async fn save(&self, transfer: &TransferResponse) -> Result<(), Error> {
+        sqlx::query(
+            "INSERT INTO transfers (name, ip, local_room_id, relay_room_id, owner, port, extra)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT (name) DO UPDATE SET
+                ip = excluded.ip,
+                local_room_id = excluded.local_room_id,
+                relay_room_id = excluded.relay_room_id,
+                owner = excluded.owner,
+                port = excluded.port,
+                extra = excluded.extra",
+        )
+        .bind(&transfer.name)
+        .bind(&transfer.ip)
+        .bind(&transfer.local_room_id)
+        .bind(&transfer.relay_room_id)
+        .bind(&transfer.owner)
+        .bind(transfer.port)
+        .bind(serde_json::to_string(&transfer.extra).unwrap_or_default())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Removes the transfer record with the given name.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the transfer.
+    pub async fn remove(&self, name: &str) -> Result<(), Error> {
+        sqlx::query("DELETE FROM transfers WHERE name = ?")
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_store() {
+        let path = std::env::temp_dir().join(format!("caesar-store-{}.db", uuid::Uuid::new_v4()));
+        let transfer = TransferResponse {
+            name: "brave-tiger-cookie".to_string(),
+            ip: "127.0.0.1".to_string(),
+            local_room_id: String::new(),
+            relay_room_id: "relay-room".to_string(),
+            port: 9001,
+            schema_version: SCHEMA_VERSION,
+            extra: serde_json::json!({ "candidates": ["198.51.100.7:9001"] })
+                .as_object()
+                .cloned()
+                .unwrap(),
+            owner: Some("alice".to_string()),
+        };
+
+        let store = TransferStore::open(&path).await.unwrap();
+        store.save(&transfer).await.unwrap();
+        store
+            .save(&TransferResponse {
+                local_room_id: "local-room".to_string(),
+                ..transfer.clone()
+            })
+            .await
+            .unwrap();
+        store
+            .save(&TransferResponse::new(
+                "other".to_string(),
+                "127.0.0.1".to_string(),
+                "local".to_string(),
+                String::new(),
+            ))
+            .await
+            .unwrap();
+        store.remove("other").await.unwrap();
+        store.pool.close().await;
+
+        // The records survive a restart of the relay
+        let store = TransferStore::open(&path).await.unwrap();
+        let transfers = store.load().await.unwrap();
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].local_room_id, "local-room");
+        assert_eq!(transfers[0].owner.as_deref(), Some("alice"));
+        assert_eq!(transfers[0].port, 9001);
+        assert_eq!(transfers[0].extra, transfer.extra);
+
+        store.pool.close().await;
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_store_without_port() {
+        let path = std::env::temp_dir().join(format!("caesar-store-{}.db", uuid::Uuid::new_v4()));
+
+        // A database of a relay before the port of the local server
+        let options = SqliteConnectOptions::new()
+            .filename(&path)
+            .create_if_missing(true);
+        let pool = SqlitePool::connect_with(options).await.unwrap();
+        sqlx::query(
+            "CREATE TABLE transfers (
+                name TEXT PRIMARY KEY NOT NULL,
+                ip TEXT NOT NULL,
+                local_room_id TEXT NOT NULL,
+                relay_room_id TEXT NOT NULL,
+                owner TEXT
+            )",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO transfers VALUES ('old', '127.0.0.1', 'local', '', NULL)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+
+        let store = TransferStore::open(&path).await.unwrap();
+        let transfers = store.load().await.unwrap();
+        assert_eq!(transfers[0].port, crate::sender::LOCAL_PORT);
+        assert!(transfers[0].extra.is_empty());
+
+        store.pool.close().await;
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+    }
+}
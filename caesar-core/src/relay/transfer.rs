@@ -1,4 +1,15 @@
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::sender::LOCAL_PORT;
+
+/// The version of the transfer records this build sends.
+///
+/// Records of clients and relays before versioning have no version and are read as
+/// version 0. Newer versions may add fields, which older builds keep in `extra` and pass
+/// on unchanged, so the API can grow without breaking clients in the field.
+pub const SCHEMA_VERSION: u32 = 1;
 
 /// Request to transfer a connection from one relay to another
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -11,6 +22,16 @@ pub struct TransferRequest {
     pub local_room_id: String,
     /// The relay room ID of the client
     pub relay_room_id: String,
+    /// The port of the client's local server. Clients before this field use
+    /// `LOCAL_PORT`
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// The version of the schema the client sent the request in
+    #[serde(default)]
+    pub schema_version: u32,
+    /// The fields of newer schema versions that this build does not know
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 impl TransferRequest {
@@ -32,12 +53,15 @@ impl TransferRequest {
             ip,
             local_room_id,
             relay_room_id,
+            port: LOCAL_PORT,
+            schema_version: SCHEMA_VERSION,
+            extra: Map::new(),
         }
     }
 }
 
 /// Response containing the details of the transferred connection
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
 pub struct TransferResponse {
     /// The name of the client
     pub name: String,
@@ -47,6 +71,20 @@ pub struct TransferResponse {
     pub local_room_id: String,
     /// The relay room ID of the client
     pub relay_room_id: String,
+    /// The port of the client's local server. Relays before this field do not send it,
+    /// their clients use `LOCAL_PORT`
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// The version of the schema the relay sent the response in
+    #[serde(default)]
+    pub schema_version: u32,
+    /// The fields of newer schema versions that this build does not know, e.g. sent by a
+    /// newer sender through an older relay
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+    /// The authenticated user who registered the transfer, kept for auditing
+    #[serde(skip)]
+    pub owner: Option<String>,
 }
 
 impl TransferResponse {
@@ -68,9 +106,75 @@ impl TransferResponse {
             ip,
             local_room_id,
             relay_room_id,
+            port: LOCAL_PORT,
+            schema_version: SCHEMA_VERSION,
+            extra: Map::new(),
+            owner: None,
         }
     }
 }
+
+/// Returns the port of the local server of clients that do not send one
+fn default_port() -> u16 {
+    LOCAL_PORT
+}
+/// The state of a transfer in the history of its owner.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum TransferStatus {
+    /// The transfer is registered and waits for the receiver.
+    Pending,
+    /// The receiver reported that it has all files.
+    Completed,
+}
+
+/// An entry in the transfer history of an authenticated user.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct HistoryEntry {
+    /// The hashed name of the transfer, as the relay knows it
+    pub name: String,
+    /// The state of the transfer
+    pub status: TransferStatus,
+    /// When the transfer was registered, in seconds since the Unix epoch
+    pub created: u64,
+    /// When the receiver finished, in seconds since the Unix epoch
+    pub completed: Option<u64>,
+}
+
+impl HistoryEntry {
+    /// Creates a new entry for a transfer that was just registered
+    ///
+    /// # Args
+    ///
+    /// * `name` - The hashed name of the transfer
+    ///
+    /// # Returns
+    ///
+    /// A new pending `HistoryEntry` instance
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            status: TransferStatus::Pending,
+            created: unix_time(),
+            completed: None,
+        }
+    }
+
+    /// Marks the transfer as completed
+    pub fn complete(&mut self) {
+        self.status = TransferStatus::Completed;
+        self.completed = Some(unix_time());
+    }
+}
+
+/// Returns the current time in seconds since the Unix epoch
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -82,6 +186,10 @@ mod tests {
             ip: "127.0.0.1".to_string(),
             local_room_id: "This_is_a_test_room_id".to_string(),
             relay_room_id: "This_is_a_test_room_id".to_string(),
+            port: LOCAL_PORT,
+            schema_version: SCHEMA_VERSION,
+            extra: Map::new(),
+            owner: None,
         };
         assert_eq!(
             TransferResponse::new(
@@ -93,4 +201,27 @@ mod tests {
             transfer
         )
     }
+
+    #[test]
+    fn test_default_port() {
+        // Senders and relays before the port field use the default port
+        let request: TransferRequest = serde_json::from_str(
+            r#"{"name":"Test","ip":"127.0.0.1","local_room_id":"local","relay_room_id":""}"#,
+        )
+        .unwrap();
+        assert_eq!(request.port, LOCAL_PORT);
+        assert_eq!(request.schema_version, 0);
+    }
+
+    #[test]
+    fn test_unknown_fields() {
+        // Fields of a newer schema survive a round trip through this build
+        let json = r#"{"name":"Test","ip":"127.0.0.1","local_room_id":"","relay_room_id":"relay","port":9001,"schema_version":2,"candidates":["198.51.100.7:9001"]}"#;
+        let response: TransferResponse = serde_json::from_str(json).unwrap();
+        assert_eq!(response.schema_version, 2);
+        assert_eq!(response.extra["candidates"][0], "198.51.100.7:9001");
+
+        let value: Value = serde_json::to_value(&response).unwrap();
+        assert_eq!(value, serde_json::from_str::<Value>(json).unwrap());
+    }
 }
@@ -0,0 +1,129 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Adapts the size of the chunks to the observed speed of the transfer.
+///
+/// The receiver grants credits after every batch of written chunks, so the time
+/// between two credit packets is the round trip of a batch. Batches that come back
+/// quickly double the chunk size to cut the per-chunk overhead on fast links, while
+/// slow batches halve it so progress updates and credits keep flowing over a
+/// congested relay.
+#[derive(Debug)]
+pub struct ChunkSizer {
+    /// The current chunk size, shared with the file transfer task.
+    size: Arc<AtomicUsize>,
+    /// The time the last credit packet was received.
+    last_ack: Option<Instant>,
+}
+
+impl ChunkSizer {
+    /// The chunk size at the start of a transfer.
+    pub const INITIAL_SIZE: usize = u16::MAX as usize;
+
+    /// The lower bound for the chunk size.
+    pub const MIN_SIZE: usize = 16 * 1024;
+
+    /// The upper bound for the chunk size.
+    pub const MAX_SIZE: usize = 1024 * 1024;
+
+    /// Batches that come back faster than this grow the chunk size.
+    pub const FAST_ROUND_TRIP: Duration = Duration::from_millis(100);
+
+    /// Batches that come back slower than this shrink the chunk size.
+    pub const SLOW_ROUND_TRIP: Duration = Duration::from_millis(500);
+
+    /// Creates a new sizer starting at the initial chunk size.
+    ///
+    /// # Returns
+    ///
+    /// A new `ChunkSizer` instance.
+    pub fn new() -> ChunkSizer {
+        ChunkSizer {
+            size: Arc::new(AtomicUsize::new(Self::INITIAL_SIZE)),
+            last_ack: None,
+        }
+    }
+
+    /// Returns the shared chunk size for the file transfer task.
+    pub fn handle(&self) -> Arc<AtomicUsize> {
+        self.size.clone()
+    }
+
+    /// Returns the current chunk size.
+    pub fn size(&self) -> usize {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    /// Records a credit packet and adapts the chunk size.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The time the credit packet was received.
+    ///
+    /// # Returns
+    ///
+    /// The new chunk size.
+    pub fn on_ack(&mut self, now: Instant) -> usize {
+        let mut size = self.size();
+
+        // The first credits only open the window and say nothing about the link
+        if let Some(last_ack) = self.last_ack {
+            let round_trip = now.saturating_duration_since(last_ack);
+
+            if round_trip < Self::FAST_ROUND_TRIP {
+                size = (size * 2).min(Self::MAX_SIZE);
+            } else if round_trip > Self::SLOW_ROUND_TRIP {
+                size = (size / 2).max(Self::MIN_SIZE);
+            }
+        }
+
+        self.last_ack = Some(now);
+        self.size.store(size, Ordering::Relaxed);
+
+        size
+    }
+}
+
+impl Default for ChunkSizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_sizer() {
+        let mut sizer = ChunkSizer::new();
+        let start = Instant::now();
+
+        assert_eq!(sizer.on_ack(start), ChunkSizer::INITIAL_SIZE);
+
+        // Fast batches grow the chunks up to the limit
+        let mut now = start;
+        for _ in 0..10 {
+            now += Duration::from_millis(10);
+            sizer.on_ack(now);
+        }
+        assert_eq!(sizer.size(), ChunkSizer::MAX_SIZE);
+        assert_eq!(sizer.handle().load(Ordering::Relaxed), ChunkSizer::MAX_SIZE);
+
+        // Batches in the target range keep the size
+        now += Duration::from_millis(300);
+        assert_eq!(sizer.on_ack(now), ChunkSizer::MAX_SIZE);
+
+        // Slow batches shrink the chunks down to the limit
+        for _ in 0..10 {
+            now += Duration::from_secs(1);
+            sizer.on_ack(now);
+        }
+        assert_eq!(sizer.size(), ChunkSizer::MIN_SIZE);
+    }
+}
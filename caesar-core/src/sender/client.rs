@@ -1,40 +1,71 @@
+use crate::cipher::{has_aes_instructions, offered_ciphers};
+use crate::delta::{self, Encoder};
+use crate::error::{CaesarError, Result};
+use crate::event::{ListedFile, TransferEvent};
+use crate::format::NumberFormat;
+use crate::heartbeat;
+use crate::idle::{self, Activity};
 use crate::sender::http_client::send_info;
-use crate::sender::util::{hash_random_name, replace_protocol};
+use crate::sender::chunk_size::ChunkSizer;
+use crate::sender::filter::PathFilter;
+use crate::sender::{ArchiveFormat, SenderOptions, SymlinkPolicy, LOCAL_PORT};
+use crate::pake::{join_code, Role, Secret, Spake2};
+use crate::progress::{FileProgress, Throughput};
+use crate::relay::routing::join_route;
+use crate::sender::util::{
+    base_name, hash_file, hash_random_name, replace_protocol, PathProblem, STDIN_PATH,
+};
 use crate::shared::{
     packets::{
-        list_packet, packet::Value, ChunkPacket, HandshakePacket, HandshakeResponsePacket,
-        ListPacket, Packet, ProgressPacket,
+        list_packet, packet::Value, Compression, CreditPacket, DonePacket, ErrorPacket, Framing,
+        HandshakePacket, HashAlgorithm, HandshakeResponsePacket, KeySchedule, ListPacket, NonceScheme, ProgressPacket, ResumePacket,
+        RetryPacket, SignaturePacket, TextPacket,
     },
-    JsonPacket, JsonPacketResponse, JsonPacketSender, PacketSender, Sender, Socket, Status,
+    decode_packet, negotiation, ChunkRef, Interruption, JsonPacket, JsonPacketResponse,
+    JsonPacketSender, PacketSender, Sender, SessionKey, Status, Transport, INTERLEAVED,
+    MAX_STREAMS, OUTGOING_QUEUE, STREAM_END,
 };
+use crate::summary::{TransferPath, TransferSummary};
+use crate::suspend::{self, SUSPEND_THRESHOLD};
 
-use aes_gcm::{aead::Aead, Aes128Gcm, Key};
-use base64::{engine::general_purpose, Engine as _};
-use futures_util::{future, pin_mut, stream::TryStreamExt, StreamExt};
-use hmac::{Hmac, Mac};
-use p256::{ecdh::EphemeralSecret, PublicKey};
+use futures_util::{future, pin_mut, stream::TryStreamExt, SinkExt, StreamExt};
 use prost::Message;
-use rand::{rngs::OsRng, RngCore};
-use sha2::Sha256;
+use rayon::prelude::*;
 use std::{
+    borrow::Cow,
+    collections::HashMap,
     fs,
-    io::{stdout, Write},
-    path::Path,
-    time::Duration,
+    io::{self, stdout, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU8, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use time::OffsetDateTime;
+use tokio::{
+    sync::{Mutex, Semaphore},
+    task::JoinHandle,
 };
-use tokio::{io::AsyncReadExt, task::JoinHandle, time::sleep};
 use tokio_tungstenite::tungstenite::{protocol::Message as WebSocketMessage, Error};
 use tracing::{debug, error};
+use zip::{
+    result::{ZipError, ZipResult},
+    write::{SimpleFileOptions, StreamWriter},
+    CompressionMethod, DateTime as ZipDateTime, ZipWriter,
+};
 
-const DESTINATION: u8 = 1;
-const NONCE_SIZE: usize = 12;
-const MAX_CHUNK_SIZE: isize = u16::MAX as isize;
-const DELAY: Duration = Duration::from_millis(750);
+const ZSTD_LEVEL: i32 = 3;
+const HASH_ALGORITHM: HashAlgorithm = HashAlgorithm::Blake3;
+#[cfg(feature = "mmap")]
+const MMAP_THRESHOLD: u64 = 64 * 1024 * 1024;
 
 
 /// Struct representing a file to be sent.
 ///
-/// This struct holds the path, name and size of a file.
+/// This struct holds the path, name, size and hash of a file, and the handle it was
+/// opened with when the files were listed.
 #[derive(Clone)]
 struct File {
     /// The path of the file to be sent.
@@ -43,26 +74,244 @@ struct File {
     name: String,
     /// The size of the file to be sent.
     size: u64,
+    /// The hash of the file's content.
+    hash: Vec<u8>,
+    /// The handle the file is hashed and read through.
+    ///
+    /// The file is only opened once, so the content that is sent is the one that was
+    /// measured and hashed, even if the path is replaced meanwhile. The transfers to
+    /// all receivers share the handle and read it at their own positions.
+    handle: Arc<fs::File>,
+    /// Whether the file is a stream like stdin, whose size is unknown until it ends.
+    ///
+    /// A stream is read as it is sent, so it is neither hashed nor sent again.
+    stream: bool,
+    /// Whether the stream is a tar archive of a directory, packed while it is sent.
+    archive: bool,
+    /// When the file was last modified, so the receiver can keep the time.
+    ///
+    /// If `None`, the time is unknown, e.g. for a stream.
+    modified: Option<SystemTime>,
+    /// The target of a link that is sent as a link. It has no content, so nothing of it
+    /// is hashed or sent.
+    ///
+    /// If `None`, the file is sent with its content.
+    link: Option<String>,
+}
+
+impl File {
+    /// Returns the offset at which the receiver has the whole file.
+    ///
+    /// A stream has no known size, so it is only complete once the receiver says so.
+    fn end(&self) -> u64 {
+        if self.stream {
+            STREAM_END
+        } else {
+            self.size
+        }
+    }
 }
 
 /// The context of a sender.
 ///
 /// This struct holds the necessary information for a sender to send files.
-/// It includes the HMAC, the sender, the ephemeral secret, the list of files to
-/// be sent, the shared key, and the task handling the sending of the files.
+/// It includes the secret, the sender, the list of files to be sent, and the state
+/// of the transfer to every receiver in the room.
 struct Context {
-    /// The HMAC used for authentication.
-    hmac: Vec<u8>,
+    /// The secret the handshake is authenticated with.
+    secret: Secret,
     /// The sender used to send packets.
     sender: Sender,
-    /// The ephemeral secret used for key exchange.
-    key: EphemeralSecret,
     /// The list of files to be sent.
     files: Vec<File>,
+    /// The options of the transfer.
+    options: SenderOptions,
+    /// The receivers in the room, in the order the relay keeps them.
+    ///
+    /// The sender is the first member of the room, so the receiver at index `i` is
+    /// reached at position `i + 1`.
+    peers: Vec<Peer>,
+    /// The number of receivers that have every file.
+    completed: usize,
+    /// The number of receivers that entered a wrong code.
+    wrong_codes: usize,
+    /// When the first receiver got the list of files.
+    started: Option<Instant>,
+    /// The bytes of the files the receivers have completed, without the ones they had
+    /// before.
+    sent: u64,
+}
+
+/// The transfer to a single receiver.
+///
+/// Every receiver runs a handshake of its own, so each has its own key and negotiates
+/// its own compression, and its chunks are read and encrypted for it alone.
+struct Peer {
+    /// The key exchange of the handshake.
+    pake: Spake2,
+    /// The handshake packet that was sent to the receiver, which its confirmation covers.
+    offer: HandshakePacket,
     /// The shared key used for encryption.
-    shared_key: Option<Aes128Gcm>,
+    shared_key: Option<SessionKey>,
     /// The task handling the sending of the files.
     task: Option<JoinHandle<()>>,
+    /// The task sending heartbeats, if the receiver accepted them.
+    heartbeat: Option<JoinHandle<()>>,
+    /// The compression negotiated with the receiver.
+    compression: Compression,
+    /// The number of files the receiver takes at the same time.
+    streams: u32,
+    /// The number of bytes the receiver already has for each file.
+    ///
+    /// Files whose offset equals their size are skipped.
+    offsets: Vec<u64>,
+    /// The signatures of the files the receiver has another version of, by their index.
+    ///
+    /// Only the blocks of these files that the receiver lacks are sent.
+    signatures: HashMap<u32, SignaturePacket>,
+    /// The number of chunks the receiver is ready to accept.
+    credits: Arc<Semaphore>,
+    /// Adapts the chunk size to the speed of the transfer.
+    chunk_sizer: ChunkSizer,
+    /// Queues the files the receiver requested again for the transfer task.
+    retries: flume::Sender<u32>,
+    /// The position of the receiver in the room.
+    ///
+    /// It is shared with the transfer task, as the position changes when a receiver
+    /// in front of this one leaves.
+    destination: Arc<AtomicU8>,
+    /// Whether the sender is done with the receiver, because it has every file or was
+    /// turned away.
+    done: bool,
+    /// The moving average of the speed, which the time left is estimated from.
+    throughput: Throughput,
+}
+
+impl Peer {
+    /// Creates the state of a receiver that just joined the room.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret` - The secret the handshake is authenticated with.
+    /// * `destination` - The position of the receiver in the room.
+    ///
+    /// # Returns
+    ///
+    /// A new `Peer` instance that has not started its handshake yet.
+    fn new(secret: &Secret, destination: u8) -> Peer {
+        Peer {
+            pake: Spake2::new(Role::Sender, secret.password()),
+            offer: HandshakePacket::default(),
+            shared_key: None,
+            task: None,
+            heartbeat: None,
+            compression: Compression::None,
+            streams: 1,
+            offsets: vec![],
+            signatures: HashMap::new(),
+            credits: Arc::new(Semaphore::new(0)),
+            chunk_sizer: ChunkSizer::new(),
+            retries: flume::unbounded().0,
+            destination: Arc::new(AtomicU8::new(destination)),
+            done: false,
+            throughput: Throughput::default(),
+        }
+    }
+
+    /// Returns the current position of the receiver in the room.
+    fn destination(&self) -> u8 {
+        self.destination.load(Ordering::Relaxed)
+    }
+}
+
+impl Context {
+    /// Returns the number of receivers the transfer is for.
+    fn receivers(&self) -> usize {
+        self.options.receivers.max(1)
+    }
+
+    /// Returns whether the room is shared by several receivers.
+    fn is_shared(&self) -> bool {
+        self.receivers() > 1
+    }
+}
+
+/// The identity that every session of a transfer shares.
+///
+/// A receiver that rejoins after a session broke down still holds the transfer code of
+/// the first session, so every later session creates the same room and authenticates
+/// its handshake with the same PIN.
+#[derive(Debug, Clone)]
+pub struct Session {
+    /// The ID of the room. If `None`, the relay chooses one for every session.
+    room_id: Option<String>,
+    /// The secret the handshake is authenticated with.
+    secret: Secret,
+}
+
+impl Session {
+    /// Creates the identity of a new transfer with a random PIN.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The ID of the room. If `None`, the relay chooses one.
+    ///
+    /// # Returns
+    ///
+    /// A new `Session` instance.
+    pub fn new(room_id: Option<String>) -> Session {
+        Session {
+            room_id,
+            secret: Secret::random_pin(),
+        }
+    }
+
+    /// Creates the identity of a transfer with a key the receiver already knows.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The ID of the room. If `None`, the relay chooses one.
+    /// * `key` - The key shared with the receiver.
+    ///
+    /// # Returns
+    ///
+    /// A new `Session` instance.
+    pub fn with_key(room_id: Option<String>, key: Vec<u8>) -> Session {
+        Session {
+            room_id,
+            secret: Secret::Key(key),
+        }
+    }
+
+    /// Creates the identity of a transfer as the options ask for.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The ID of the room. If `None`, the relay chooses one.
+    /// * `options` - The options of the transfer.
+    ///
+    /// # Returns
+    ///
+    /// A `Session` with the key of the options, or with their PIN, or with a random
+    /// PIN if there is neither.
+    pub fn for_options(room_id: Option<String>, options: &SenderOptions) -> Session {
+        match (&options.key, &options.pin) {
+            (Some(key), _) => Session::with_key(room_id, key.clone()),
+            (None, Some(pin)) => Session {
+                room_id,
+                secret: Secret::Pin(pin.clone()),
+            },
+            (None, None) => Session::new(room_id),
+        }
+    }
+
+    /// Returns the PIN of the transfer, or `None` if it uses a shared key.
+    pub fn pin(&self) -> Option<&str> {
+        match &self.secret {
+            Secret::Pin(pin) => Some(pin),
+            Secret::Key(_) => None,
+        }
+    }
 }
 
 /// Handles the create room packet.
@@ -92,11 +341,8 @@ fn on_create_room(
     // Debug log the relay URL
     debug!("Creating room on: {relay}");
 
-    // Encode the HMAC key using base64
-    let base64 = general_purpose::STANDARD.encode(&context.hmac);
-
-    // Generate the URL for the room
-    let url = format!("{}-{}", id, base64);
+    // The invite only holds the room, the PIN never reaches the relay
+    let url = id;
 
     // Hash the transfer name
     let hash_name = hash_random_name(transfer_name.clone());
@@ -105,6 +351,26 @@ fn on_create_room(
     let send_url = url.to_string();
     let h_name = hash_name.to_string();
     let server_url = replace_protocol(relay.as_str());
+    let token = context.options.token.clone();
+    let route = context.options.route.clone();
+    let port = context.options.local_port.unwrap_or(LOCAL_PORT);
+    // The receiver needs the PIN for the handshake and the routing token to reach the
+    // same relay instance
+    let transfer_code = match &context.secret {
+        Secret::Pin(pin) => join_code(pin, &transfer_name),
+        Secret::Key(_) => transfer_name,
+    };
+    let transfer_code = join_route(&transfer_code, route.as_deref());
+
+    // A local transfer is found over mDNS, so the relay is never told about it
+    if context.options.local_only {
+        print_transfer_code(context, &url, &transfer_code);
+        notify!(
+            context.options,
+            "Receive it on the same network with: caesar receive --discover {transfer_code}"
+        );
+        return Status::Continue();
+    }
 
     // Spawn a new thread to send the information to the server
     let res = std::thread::spawn(move || {
@@ -113,7 +379,15 @@ fn on_create_room(
             .enable_all()
             .build()
             .unwrap()
-            .block_on(send_info(&server_url, &h_name, send_url.as_str(), is_local))
+            .block_on(send_info(
+                &server_url,
+                &h_name,
+                send_url.as_str(),
+                is_local,
+                port,
+                token,
+                route,
+            ))
     })
     .join()
     .unwrap();
@@ -128,18 +402,11 @@ fn on_create_room(
             if !transfer_response.local_room_id.is_empty()
                 && !transfer_response.relay_room_id.is_empty()
             {
-                println!();
-
-                // Print the QR code for the transfer name
-                if let Err(error) = qr2term::print_qr(&transfer_name) {
-                    error!("Failed to generate QR code: {}", error);
-                }
-                println!();
-
-                println!("Created room: {}", url);
-                println!("Transfername is: {}", transfer_name);
+                print_transfer_code(context, &url, &transfer_code);
             }
         }
+        // Without the relay's consent the receiver cannot find the transfer
+        Err(e @ CaesarError::Refused(_)) => return Status::Err(e),
         Err(e) => {
             // Log the error
             error!("Error sending info: {e}");
@@ -150,18 +417,60 @@ fn on_create_room(
     Status::Continue()
 }
 
+/// Prints the transfer code the receiver enters, along with its QR code.
+///
+/// If the events of the transfer are reported, they are told the code and the files
+/// instead, and no QR code is printed.
+///
+/// # Arguments
+///
+/// * `context` - The context of the sender.
+/// * `room_id` - The ID of the room.
+/// * `transfer_code` - The transfer code.
+fn print_transfer_code(context: &Context, room_id: &str, transfer_code: &str) {
+    if let Some(events) = &context.options.events {
+        events.report(TransferEvent::RoomCreated {
+            room_id: room_id.to_string(),
+            code: transfer_code.to_string(),
+        });
+        events.report(TransferEvent::List {
+            files: context
+                .files
+                .iter()
+                .map(|file| ListedFile {
+                    name: file.name.clone(),
+                    size: file.size,
+                })
+                .collect(),
+        });
+    } else {
+        println!();
+
+        // Print the QR code for the transfer code
+        #[cfg(feature = "qr")]
+        if let Err(error) = qr2term::print_qr(transfer_code) {
+            error!("Failed to generate QR code: {}", error);
+        }
+        println!();
+    }
+
+    notify!(context.options, "Created room: {}", room_id);
+    notify!(context.options, "Transfername is: {}", transfer_code);
+}
+
 
 /// Handle the join room packet.
 ///
 /// This function is responsible for handling the join room packet received from the receiver.
 /// It checks if the size of the room is provided and returns an error if it is not. It then
-/// generates the public key and signs it with the HMAC key. It sends the handshake packet to
-/// the receiver.
+/// adds the receiver to the room and sends it the blinded share of its key exchange in the
+/// handshake packet.
 ///
 /// # Arguments
 ///
 /// * `context` - The sender context.
 /// * `size` - The size of the room.
+/// * `index` - The position of the receiver in the room, if the relay tells it.
 ///
 /// # Returns
 ///
@@ -170,36 +479,63 @@ fn on_create_room(
 /// # Errors
 ///
 /// Returns an error if the join room packet is invalid.
-fn on_join_room(context: &Context, size: Option<usize>) -> Status {
+fn on_join_room(context: &mut Context, size: Option<usize>, index: Option<usize>) -> Status {
     // Check if the size of the room is provided
     if size.is_some() {
         return Status::Err("Invalid join room packet.".into());
     }
 
-    // Generate the public key
-    let public_key = context.key.public_key().to_sec1_bytes().into_vec();
-
-    // Generate the signature by signing the public key with the HMAC key
-    let mut mac = Hmac::<Sha256>::new_from_slice(&context.hmac).unwrap();
-    mac.update(&public_key);
-    let signature = mac.finalize().into_bytes().to_vec();
+    // The relay adds the receiver behind all others, newer relays tell its position
+    let position = context.peers.len() + 1;
+    if index.is_some_and(|index| index != position) {
+        return Status::Err("The relay placed a receiver at an unexpected position.".into());
+    }
+    let Ok(destination) = u8::try_from(position) else {
+        return Status::Err("Too many receivers in the room.".into());
+    };
+    let mut peer = Peer::new(&context.secret, destination);
+
+    // The share is blinded with the secret, so it carries no signature. The receiver
+    // learns that the sender knows the secret when it can decrypt the list.
+    let public_key = peer.pake.message().to_vec();
+    let signature = vec![];
+
+    // Offer compression if it is enabled
+    let compressions = if context.options.compress {
+        vec![Compression::Zstd.into()]
+    } else {
+        vec![]
+    };
 
-    // Create the handshake packet with the public key and signature
+    // Create the handshake packet with the public key, signature and offered compressions,
+    // framings, heartbeats, key schedules, ciphers, nonce schemes, deltas and streams
     let handshake = HandshakePacket {
         public_key,
         signature,
+        compressions,
+        framings: vec![Framing::V3.into()],
+        heartbeat: true,
+        key_schedules: vec![KeySchedule::Hkdf.into()],
+        ciphers: offered_ciphers(has_aes_instructions())
+            .into_iter()
+            .map(Into::into)
+            .collect(),
+        nonce_schemes: vec![NonceScheme::Counter.into()],
+        delta: true,
+        streams: MAX_STREAMS,
     };
 
-    // Send the handshake packet to the receiver
+    // Send the handshake packet to the receiver, and keep it to check its confirmation
+    peer.offer = handshake.clone();
     context
         .sender
-        .send_packet(DESTINATION, Value::Handshake(handshake));
+        .send_packet(destination, Value::Handshake(handshake));
+    context.peers.push(peer);
 
     Status::Continue()
 }
 
 
-
 /// Handles errors by returning a `Status` with the error message.
 ///
 /// # Arguments
@@ -213,60 +549,103 @@ fn on_join_room(context: &Context, size: Option<usize>) -> Status {
 #[inline]
 fn on_error(message: String) -> Status {
     // Return a `Status` with the error message
-    Status::Err(message)
+    Status::Err(message.into())
 }
 
 
 /// Handle the leave room packet.
 ///
-/// This function handles the leave room packet by aborting any ongoing task,
-/// generating a new random key, clearing the shared key, clearing the task,
-/// and printing an error message indicating that the transfer was interrupted
-/// because the receiver disconnected.
+/// This function handles the leave room packet by aborting the transfer to the receiver
+/// that left and removing it from the room. The receivers behind it move up by one
+/// position, like they do on the relay. If the receiver had not received every file,
+/// an error message is printed indicating that the transfer was interrupted.
 ///
 /// # Arguments
 ///
 /// * `context` - The sender context.
-/// * `_` - The index of the sender. Currently unused.
+/// * `index` - The position of the member that left the room.
 ///
 /// # Returns
 ///
 /// A `Status` representing the result of the operation.
-///
-/// # Errors
-///
-/// Returns an error if there are still files being transferred.
 #[allow(clippy::missing_panics_doc)]
 #[inline]
-fn on_leave_room(context: &mut Context, _: usize) -> Status {
-    // Abort any ongoing task
-    if let Some(task) = &context.task {
+fn on_leave_room(context: &mut Context, index: usize) -> Status {
+    // The sender is the first member, so only receivers can leave
+    let Some(position) = index.checked_sub(1).filter(|i| *i < context.peers.len()) else {
+        return Status::Continue();
+    };
+    let peer = context.peers.remove(position);
+
+    // Abort any ongoing task, the position of the receiver goes to the one behind it
+    for task in peer.task.iter().chain(&peer.heartbeat) {
         task.abort();
     }
 
-    // Generate a new random key
-    context.key = EphemeralSecret::random(&mut OsRng);
-
-    // Clear the shared key
-    context.shared_key = None;
-
-    // Clear the task
-    context.task = None;
+    // Move the receivers behind it up
+    for (position, peer) in context.peers.iter().enumerate().skip(position) {
+        peer.destination.store(position as u8 + 1, Ordering::Relaxed);
+    }
 
     // Print an error message
-    println!();
-    error!("Transfer was interrupted because the receiver disconnected.");
+    if !peer.done {
+        notify!(context.options);
+        if context.is_shared() {
+            error!("A receiver disconnected before it had every file.");
+        } else {
+            error!("Transfer was interrupted because the receiver disconnected.");
+        }
+    }
 
     // Return a `Status` to indicate that the operation was successful
     Status::Continue()
 }
 
+/// Counts the bytes a receiver has of all files and the bytes still to come.
+///
+/// # Arguments
+///
+/// * `files` - The files of the transfer.
+/// * `offsets` - The number of bytes the receiver had of each file before.
+/// * `index` - The index of the file the receiver is at.
+/// * `transferred` - The number of bytes the receiver has of that file.
+///
+/// # Returns
+///
+/// The bytes that arrived, and the bytes still to come, which are `None` while a
+/// stream leaves the end open.
+fn count_bytes(
+    files: &[File],
+    offsets: &[u64],
+    index: usize,
+    transferred: u64,
+) -> (u64, Option<u64>) {
+    let mut arrived = 0;
+    let mut remaining = Some(0);
+    for (position, file) in files.iter().enumerate() {
+        let offset = match position == index {
+            true => transferred,
+            false => offsets.get(position).copied().unwrap_or(0),
+        };
+        if file.stream {
+            // A stream is only counted out once the receiver has all of it
+            if offset != STREAM_END {
+                remaining = None;
+            }
+            continue;
+        }
+        arrived += offset.min(file.size);
+        remaining = remaining.map(|bytes| bytes + file.size.saturating_sub(offset));
+    }
+    (arrived, remaining)
+}
 
 /// Handle the progress packet.
 ///
 /// # Arguments
 ///
 /// * `context` - The sender context.
+/// * `peer` - The index of the receiver that sent the packet.
 /// * `progress` - The progress packet.
 ///
 /// # Returns
@@ -278,9 +657,12 @@ fn on_leave_room(context: &mut Context, _: usize) -> Status {
 /// Returns an error if the progress packet is invalid.
 #[allow(clippy::missing_panics_doc)]
 #[inline]
-fn on_progress(context: &Context, progress: ProgressPacket) -> Status {
+fn on_progress(context: &mut Context, peer: usize, progress: ProgressPacket) -> Status {
+    let shared = context.is_shared();
+    let state = &mut context.peers[peer];
+
     // Check if the shared key is established
-    if context.shared_key.is_none() {
+    if state.shared_key.is_none() {
         // Return an error if the progress packet is invalid
         return Status::Err("Invalid progress packet: no shared key established".into());
     }
@@ -291,20 +673,88 @@ fn on_progress(context: &Context, progress: ProgressPacket) -> Status {
         None => return Status::Err("Invalid index in progress packet.".into()),
     };
 
-    // Print the progress of the file transfer
-    print!("\rTransferring '{}': {}%", file.name, progress.progress);
-    // Flush the stdout
-    stdout().flush().unwrap();
+    // The receiver only tells when a stream ended
+    let transferred =
+        (!file.stream).then(|| file.size * u64::from(progress.progress.min(100)) / 100);
+    let (arrived, remaining) = count_bytes(
+        &context.files,
+        &state.offsets,
+        progress.index as usize,
+        transferred.unwrap_or(0),
+    );
+    state.throughput.record(arrived, Instant::now());
+    let left = transferred.map(|transferred| file.size - transferred);
+    if let Some((events, transferred)) = context.options.events.as_ref().zip(transferred) {
+        events.report(TransferEvent::Progress {
+            file: progress.index as usize,
+            receiver: shared.then_some(peer),
+            transferred,
+            size: file.size,
+        });
+    }
+
+    // A report is told the progress of every receiver, whose lines would overwrite
+    // each other
+    let printed = !shared && context.options.progress.is_none() && context.options.events.is_none();
+    if let Some(report) = &context.options.progress {
+        report.report(&FileProgress {
+            peer,
+            index: progress.index,
+            name: file.name.clone(),
+            size: file.size,
+            transferred,
+            done: progress.progress == 100,
+            remaining: state.throughput.remaining_total(left, remaining),
+        });
+    } else if printed {
+        let format = NumberFormat::current();
+        let status = match state.throughput.describe(left, remaining, &format) {
+            Some(speed) => format!(" ({})", speed),
+            None => String::new(),
+        };
+
+        // Print the progress of the file transfer, a stream has no size to show
+        if file.stream {
+            print!(
+                "\rTransferring '{}': {}%{}",
+                file.name, progress.progress, status
+            );
+        } else {
+            print!(
+                "\rTransferring '{}': {}% of {}{}",
+                file.name,
+                progress.progress,
+                format.size(file.size),
+                status
+            );
+        }
+        // Flush the stdout
+        stdout().flush().unwrap();
+    }
 
     // Check if the progress is 100%
     if progress.progress == 100 {
-        // Print a newline
-        println!();
+        if printed {
+            // Print a newline
+            println!();
+        }
+
+        // The receiver has verified the whole file
+        if let Some(offset) = state.offsets.get_mut(progress.index as usize) {
+            if !file.stream {
+                context.sent += file.size.saturating_sub(*offset);
+            }
+            *offset = file.end();
+        }
 
-        // Check if this is the last file being transferred
-        if progress.index as usize == context.files.len() - 1 {
-            // Return an exit status to indicate that the operation was successful
-            return Status::Exit();
+        // Check if any file is still being transferred
+        let remaining = context
+            .files
+            .iter()
+            .enumerate()
+            .any(|(index, file)| state.offsets.get(index).copied().unwrap_or(0) < file.end());
+        if !remaining {
+            return on_peer_complete(context, peer);
         }
     }
 
@@ -312,166 +762,1606 @@ fn on_progress(context: &Context, progress: ProgressPacket) -> Status {
     Status::Continue()
 }
 
+/// Handles a receiver that has every file.
+///
+/// A single receiver leaves the room once it sees the sender leave. In a shared room the
+/// sender stays for the other receivers and tells this one with a done packet instead.
+///
+/// # Arguments
+///
+/// * `context` - The sender context.
+/// * `peer` - The index of the receiver.
+///
+/// # Returns
+///
+/// `Status::Exit` once every receiver is done, `Status::Continue` otherwise.
+fn on_peer_complete(context: &mut Context, peer: usize) -> Status {
+    context.completed += 1;
+    if !context.is_shared() {
+        return Status::Exit();
+    }
+
+    let state = &mut context.peers[peer];
+    state.done = true;
+    context.sender.send_encrypted_packet(
+        &state.shared_key,
+        state.destination(),
+        Value::Done(DonePacket {}),
+    );
+    notify!(
+        context.options,
+        "A receiver has every file ({} of {}).",
+        context.completed,
+        context.receivers()
+    );
+
+    finish_if_done(context)
+}
+
+/// Ends the transfer once the sender is done with every receiver it was started for.
+///
+/// # Arguments
+///
+/// * `context` - The sender context.
+///
+/// # Returns
+///
+/// `Status::Exit` if every receiver has every file or entered a wrong code,
+/// `Status::Continue` otherwise.
+fn finish_if_done(context: &Context) -> Status {
+    if context.completed + context.wrong_codes >= context.receivers() {
+        Status::Exit()
+    } else {
+        Status::Continue()
+    }
+}
+
+/// Sums up how much was sent and how fast, once every receiver is done.
+///
+/// # Arguments
+///
+/// * `context` - The sender context.
+/// * `is_local` - Whether the session ran on the local server.
+///
+/// # Returns
+///
+/// The summary of the transfer. Streams are not counted, as their size is unknown.
+fn summarize(context: &Context, is_local: bool) -> TransferSummary {
+    TransferSummary {
+        files: context.files.len(),
+        // Each receiver only got what it did not have yet
+        bytes: context.sent,
+        elapsed: context
+            .started
+            .map_or(Duration::ZERO, |started| started.elapsed()),
+        path: Some(match is_local {
+            true => TransferPath::Local,
+            false => TransferPath::Relay,
+        }),
+    }
+}
+
+/// Turns a receiver of a shared room away, so the transfer to the others continues.
+///
+/// The receiver is told with an error packet and leaves the room on its own. Its
+/// packets are ignored until then.
+///
+/// # Arguments
+///
+/// * `context` - The sender context.
+/// * `peer` - The index of the receiver.
+/// * `error` - The error that ended the transfer to the receiver.
+fn reject_peer(context: &mut Context, peer: usize, error: &CaesarError) {
+    let state = &mut context.peers[peer];
+    for task in state.task.take().into_iter().chain(state.heartbeat.take()) {
+        task.abort();
+    }
+    state.done = true;
+
+    let packet = Value::Error(ErrorPacket {
+        message: error.to_string(),
+    });
+    if state.shared_key.is_some() {
+        context
+            .sender
+            .send_encrypted_packet(&state.shared_key, state.destination(), packet);
+    } else {
+        context.sender.send_packet(state.destination(), packet);
+    }
+}
+
 
 /// Asynchronously transfers the chunks of files to the receiver.
 ///
+/// If the receiver agreed to it, several files are sent at the same time, so a file
+/// that stalls does not hold up the others. Once every file has been sent, the files
+/// the receiver requests again are resent from the start until the transfer ends.
+///
 /// # Arguments
 ///
 /// * `sender` - The sender object used to send packets.
 /// * `shared_key` - The shared key used for encryption.
+/// * `destination` - The position of the receiver in the room.
 /// * `files` - The list of files to be transferred.
+/// * `offsets` - The number of bytes the receiver already has for each file.
+/// * `signatures` - The signatures of the files the receiver has another version of.
+/// * `streams` - The number of files that are sent at the same time.
+/// * `compression` - The compression applied to each chunk.
+/// * `credits` - The number of chunks the receiver is ready to accept. One credit is
+///   used up for every chunk sent.
+/// * `chunk_size` - The current chunk size, adapted while the transfer runs.
+/// * `retries` - The indices of the files the receiver requested again.
 #[allow(clippy::missing_panics_doc)]
+#[allow(clippy::too_many_arguments)]
 #[inline]
 async fn on_chunk(
     sender: Sender,
-    shared_key: Option<Aes128Gcm>,
+    shared_key: Option<SessionKey>,
+    destination: Arc<AtomicU8>,
     files: Vec<File>,
+    offsets: Vec<u64>,
+    mut signatures: HashMap<u32, SignaturePacket>,
+    streams: u32,
+    compression: Compression,
+    credits: Arc<Semaphore>,
+    chunk_size: Arc<AtomicUsize>,
+    retries: flume::Receiver<u32>,
 ) {
-    // For each file in the list of files
-    for file in files {
-        let mut sequence = 0;
-        let mut chunk_size = MAX_CHUNK_SIZE;
-        let mut size = file.size as isize;
-
-        // Open the file
-        let mut handle = match tokio::fs::File::open(file.path).await {
-            Ok(handle) => handle,
-            Err(error) => {
-                // Print an error message if the file cannot be opened
-                println!("Error: Unable to open file '{}': {}", file.name, error);
-                return;
-            }
-        };
+    // Files sent at the same time share the numbers of their chunks
+    let shared = (streams > 1).then(|| Arc::new(Mutex::new(0)));
+    let numbering = || match &shared {
+        Some(sequence) => Numbering::Shared(sequence.clone()),
+        None => Numbering::File(0),
+    };
 
-        // While there are still chunks to be transferred
-        while size > 0 {
-            // If the remaining size is less than the maximum chunk size
-            if size < chunk_size {
-                // Set the chunk size to the remaining size
-                chunk_size = size;
+    // Send every file the receiver wants, skipping the others. The sends are collected
+    // first, so the task holds no closures across its await points.
+    let sends: Vec<_> = (0..)
+        .zip(&files)
+        .zip(offsets)
+        .filter(|((_, file), offset)| *offset < file.end())
+        .map(|((index, file), offset)| {
+            let signature = signatures.remove(&index).filter(|_| offset == 0);
+            let numbering = numbering();
+            let (sender, shared_key, destination) = (&sender, &shared_key, &*destination);
+            let (credits, chunk_size) = (&*credits, &*chunk_size);
+            async move {
+                if file.stream {
+                    send_stream(
+                        sender,
+                        shared_key,
+                        destination,
+                        index,
+                        file,
+                        numbering,
+                        compression,
+                        credits,
+                        chunk_size,
+                    )
+                    .await
+                } else if let Some(signature) = signature {
+                    send_delta(
+                        sender,
+                        shared_key,
+                        destination,
+                        index,
+                        file,
+                        signature,
+                        numbering,
+                        compression,
+                        credits,
+                        chunk_size,
+                    )
+                    .await
+                } else {
+                    send_file(
+                        sender,
+                        shared_key,
+                        destination,
+                        index,
+                        file,
+                        offset,
+                        numbering,
+                        compression,
+                        credits,
+                        chunk_size,
+                    )
+                    .await
+                }
             }
+        })
+        .collect();
+    let sent = futures_util::stream::iter(sends)
+        .buffer_unordered(streams.max(1) as usize)
+        .all(future::ready)
+        .await;
+    if !sent {
+        return;
+    }
 
-            // Create a vector to hold the chunk
-            let mut chunk = vec![0u8; chunk_size.try_into().unwrap()];
+    // Resend the files that arrived broken
+    while let Ok(index) = retries.recv_async().await {
+        let Some(file) = files.get(index as usize) else {
+            continue;
+        };
 
-            // Read the chunk from the file
-            handle.read_exact(&mut chunk).await.unwrap();
+        let sent = send_file(
+            &sender,
+            &shared_key,
+            &destination,
+            index,
+            file,
+            0,
+            numbering(),
+            compression,
+            &credits,
+            &chunk_size,
+        )
+        .await;
+        if !sent {
+            return;
+        }
+    }
+}
 
-            // Send the encrypted chunk packet to the receiver
-            sender.send_encrypted_packet(
-                &shared_key,
-                DESTINATION,
-                Value::Chunk(ChunkPacket { sequence, chunk }),
-            );
+/// Numbers the chunks of a file as they are sent.
+///
+/// Files sent one after the other number their chunks from zero. Files sent at the same
+/// time share a single count, so the receiver gets the chunks of the whole transfer in
+/// order and tells their files apart by the index in each chunk.
+enum Numbering {
+    /// The number of the next chunk of the file.
+    File(u32),
+    /// The number of the next chunk of the transfer.
+    Shared(Arc<Mutex<u32>>),
+}
 
-            // Increment the sequence and decrement the size
-            sequence += 1;
-            size -= chunk_size;
+impl Numbering {
+    /// Sends the next chunk of a file, waiting while the outgoing queue is full.
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` - The sender object used to send packets.
+    /// * `shared_key` - The shared key used for encryption.
+    /// * `destination` - The position of the receiver in the room.
+    /// * `index` - The index of the file in the list.
+    /// * `chunk` - The content of the chunk, compressed if negotiated.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the session has ended.
+    async fn send(
+        &mut self,
+        sender: &Sender,
+        shared_key: &Option<SessionKey>,
+        destination: &AtomicU8,
+        index: u32,
+        chunk: &[u8],
+    ) -> bool {
+        let destination = destination.load(Ordering::Relaxed);
+        match self {
+            Numbering::File(sequence) => {
+                let chunk = ChunkRef {
+                    sequence: *sequence,
+                    chunk,
+                    index,
+                };
+                *sequence += 1;
+                sender
+                    .send_encrypted_chunk(shared_key, destination, index, chunk)
+                    .await
+            }
+            Numbering::Shared(sequence) => {
+                // The chunk is queued while the number is taken, so the chunks leave in
+                // the order of their numbers
+                let mut sequence = sequence.lock().await;
+                let chunk = ChunkRef {
+                    sequence: *sequence,
+                    chunk,
+                    index,
+                };
+                *sequence += 1;
+                sender
+                    .send_encrypted_chunk(shared_key, destination, INTERLEAVED, chunk)
+                    .await
+            }
         }
-
-        // Wait for a delay before starting the next file transfer
-        sleep(DELAY).await;
     }
 }
 
-/// Finalizes the handshake by sending the list of files to the receiver and
-/// starting the file transfer task.
+/// Sends a single file as chunk packets, starting at the given offset.
 ///
 /// # Arguments
 ///
-/// * `context` - The mutable context holding the sender, files, and shared key.
+/// * `sender` - The sender object used to send packets.
+/// * `shared_key` - The shared key used for encryption.
+/// * `destination` - The position of the receiver in the room.
+/// * `index` - The index of the file in the list.
+/// * `file` - The file to be sent.
+/// * `offset` - The number of bytes the receiver already has.
+/// * `numbering` - Numbers the chunks of the file.
+/// * `compression` - The compression applied to each chunk.
+/// * `credits` - The number of chunks the receiver is ready to accept.
+/// * `chunk_size` - The current chunk size, adapted while the transfer runs.
 ///
 /// # Returns
 ///
-/// A `Status` indicating the success or failure of the handshake finalization.
-fn on_handshake_finalize(context: &mut Context) -> Status {
-    // Create a vector of `Entry`s from the files in the context
-    let mut entries = vec![];
-    for (index, file) in context.files.iter().enumerate() {
-        let entry = list_packet::Entry {
-            // The index of the file in the context
-            index: index.try_into().unwrap(),
-            // The name of the file
-            name: file.name.clone(),
-            // The size of the file
-            size: file.size,
-        };
-        entries.push(entry);
+/// `false` if the transfer cannot continue.
+#[allow(clippy::too_many_arguments)]
+async fn send_file(
+    sender: &Sender,
+    shared_key: &Option<SessionKey>,
+    destination: &AtomicU8,
+    index: u32,
+    file: &File,
+    offset: u64,
+    mut numbering: Numbering,
+    compression: Compression,
+    credits: &Semaphore,
+    chunk_size: &AtomicUsize,
+) -> bool {
+    let mut size = (file.size - offset) as isize;
+
+    // Send large files straight from a memory map if possible
+    #[cfg(feature = "mmap")]
+    if file.size >= MMAP_THRESHOLD {
+        match map_file(&file.handle, file.size) {
+            Ok(map) => {
+                // The receiver may report an offset past the end of the map
+                let data = usize::try_from(offset).ok().and_then(|offset| map.get(offset..));
+                let Some(data) = data else {
+                    eprintln!(
+                        "Error: Unable to read file '{}': the offset is past its end",
+                        file.name
+                    );
+                    return false;
+                };
+                return send_mapped_chunks(
+                    sender,
+                    shared_key,
+                    destination,
+                    index,
+                    file,
+                    data,
+                    numbering,
+                    compression,
+                    credits,
+                    chunk_size,
+                )
+                .await;
+            }
+            Err(error) => {
+                debug!("Falling back to buffered reads for '{}': {}", file.name, error);
+            }
+        }
     }
 
-    // Send the encrypted list packet to the receiver
-    context.sender.send_encrypted_packet(
-        &context.shared_key,
-        DESTINATION,
-        Value::List(ListPacket { entries }),
-    );
+    // Start at the part of the file the receiver does not have yet
+    let mut position = offset;
 
-    // Spawn the file transfer task and store it in the context
-    context.task = Some(tokio::spawn(on_chunk(
-        context.sender.clone(),
-        context.shared_key.clone(),
-        context.files.clone(),
-    )));
+    // While there are still chunks to be transferred
+    while size > 0 {
+        // Wait until the receiver is ready for another chunk
+        if !acquire_credit(credits).await {
+            return false;
+        }
 
-    Status::Continue()
+        // Use the current chunk size, but never more than the remaining size
+        let length = (chunk_size.load(Ordering::Relaxed) as isize).min(size);
+
+        // Read the chunk from the file without blocking the runtime
+        let handle = file.handle.clone();
+        let read = tokio::task::spawn_blocking(move || {
+            let mut chunk = vec![0u8; length.try_into().unwrap()];
+            read_at(&handle, &mut chunk, position).map(|()| chunk)
+        })
+        .await;
+        let chunk = match read {
+            Ok(Ok(chunk)) => chunk,
+            Ok(Err(error)) => {
+                eprintln!("Error: Unable to read file '{}': {}", file.name, error);
+                return false;
+            }
+            Err(_) => return false,
+        };
+
+        // Send the encrypted chunk packet to the receiver
+        let chunk = match compress_chunk(&chunk, compression) {
+            Ok(chunk) => chunk,
+            Err(error) => {
+                eprintln!("Error: Unable to compress file '{}': {}", file.name, error);
+                return false;
+            }
+        };
+        let sent = numbering.send(sender, shared_key, destination, index, &chunk);
+        if !sent.await {
+            return false;
+        }
+
+        // Increment the position and decrement the size
+        position += length as u64;
+        size -= length;
+    }
+
+    true
 }
 
-/// Handles the handshake response packet received from the receiver.
+/// Sends only the blocks of a file the receiver lacks, as chunk packets.
+///
+/// Each chunk holds an encoded `DeltaPacket` that describes about as many bytes of the
+/// file as a chunk of the current size would hold.
 ///
 /// # Arguments
 ///
-/// * `context` - The mutable context holding the sender, files, and shared key.
-/// * `handshake_response` - The handshake response packet received from the receiver.
+/// * `sender` - The sender object used to send packets.
+/// * `shared_key` - The shared key used for encryption.
+/// * `destination` - The position of the receiver in the room.
+/// * `index` - The index of the file in the list.
+/// * `file` - The file to be sent.
+/// * `signature` - The signature of the receiver's version of the file.
+/// * `numbering` - Numbers the chunks of the file.
+/// * `compression` - The compression applied to each chunk.
+/// * `credits` - The number of chunks the receiver is ready to accept.
+/// * `chunk_size` - The current chunk size, adapted while the transfer runs.
 ///
 /// # Returns
 ///
-/// A `Status` indicating the success or failure of the handshake.
-fn on_handshake(context: &mut Context, handshake_response: HandshakeResponsePacket) -> Status {
-    // Check if the handshake has already been performed
-    if context.shared_key.is_some() {
-        return Status::Err("Already performed handshake.".into());
-    }
-
-    // Create a HMAC instance with the HMAC key
-    let mut mac = Hmac::<Sha256>::new_from_slice(&context.hmac).unwrap();
+/// `false` if the transfer cannot continue.
+#[allow(clippy::too_many_arguments)]
+async fn send_delta(
+    sender: &Sender,
+    shared_key: &Option<SessionKey>,
+    destination: &AtomicU8,
+    index: u32,
+    file: &File,
+    signature: SignaturePacket,
+    mut numbering: Numbering,
+    compression: Compression,
+    credits: &Semaphore,
+    chunk_size: &AtomicUsize,
+) -> bool {
+    let reader = PositionReader {
+        handle: file.handle.clone(),
+        position: 0,
+        size: file.size,
+    };
+    let mut encoder = Encoder::new(reader, signature);
+
+    loop {
+        // Compare the file with the blocks without blocking the runtime
+        let length = chunk_size.load(Ordering::Relaxed);
+        let encoded = tokio::task::spawn_blocking(move || {
+            let part = encoder.next_part(length);
+            (encoder, part)
+        })
+        .await;
+        let delta = match encoded {
+            Ok((returned, Ok(Some(delta)))) => {
+                encoder = returned;
+                delta
+            }
+            Ok((_, Ok(None))) => return true,
+            Ok((_, Err(error))) => {
+                eprintln!("Error: Unable to read file '{}': {}", file.name, error);
+                return false;
+            }
+            Err(_) => return false,
+        };
 
-    // Update the HMAC with the public key from the handshake response
-    mac.update(&handshake_response.public_key);
+        // Wait until the receiver is ready for another chunk
+        if !acquire_credit(credits).await {
+            return false;
+        }
 
-    // Verify the signature in the handshake response
-    let verification = mac.verify_slice(&handshake_response.signature);
-    if verification.is_err() {
-        // Return an error if the signature is invalid
-        return Status::Err("Invalid signature from the receiver.".into());
+        // Send the encrypted chunk packet to the receiver
+        let delta = delta.encode_to_vec();
+        let chunk = match compress_chunk(&delta, compression) {
+            Ok(chunk) => chunk,
+            Err(error) => {
+                eprintln!("Error: Unable to compress file '{}': {}", file.name, error);
+                return false;
+            }
+        };
+        let sent = numbering.send(sender, shared_key, destination, index, &chunk);
+        if !sent.await {
+            return false;
+        }
     }
+}
 
-    // Convert the public key from bytes to a public key instance
-    let shared_public_key = PublicKey::from_sec1_bytes(&handshake_response.public_key).unwrap();
+/// Reads a file from start to end through the handle all transfers share.
+///
+/// Every transfer reads at a position of its own, as moving the cursor of the shared
+/// handle would mix up the positions of transfers that run at the same time.
+struct PositionReader {
+    /// The handle the file is read through.
+    handle: Arc<fs::File>,
+    /// The position the next read starts at.
+    position: u64,
+    /// The size of the file, which is not read beyond.
+    size: u64,
+}
 
-    // Derive the shared secret using Diffie-Hellman key exchange
-    let shared_secret = context.key.diffie_hellman(&shared_public_key);
+impl Read for PositionReader {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let length = (self.size - self.position).min(buffer.len() as u64) as usize;
+        read_at(&self.handle, &mut buffer[..length], self.position)?;
+        self.position += length as u64;
+        Ok(length)
+    }
+}
 
-    // Extract the raw secret bytes from the shared secret
-    let shared_secret = shared_secret.raw_secret_bytes();
+/// Sends a stream of unknown size, like stdin, as chunk packets until it ends.
+///
+/// The receiver does not know the size, so the end of the stream is marked with an
+/// empty chunk.
+///
+/// # Arguments
+///
+/// * `sender` - The sender object used to send packets.
+/// * `shared_key` - The shared key used for encryption.
+/// * `destination` - The position of the receiver in the room.
+/// * `index` - The index of the stream in the list.
+/// * `file` - The stream to be sent.
+/// * `numbering` - Numbers the chunks of the stream.
+/// * `compression` - The compression applied to each chunk.
+/// * `credits` - The number of chunks the receiver is ready to accept.
+/// * `chunk_size` - The current chunk size, adapted while the transfer runs.
+///
+/// # Returns
+///
+/// `false` if the transfer cannot continue.
+#[allow(clippy::too_many_arguments)]
+async fn send_stream(
+    sender: &Sender,
+    shared_key: &Option<SessionKey>,
+    destination: &AtomicU8,
+    index: u32,
+    file: &File,
+    mut numbering: Numbering,
+    compression: Compression,
+    credits: &Semaphore,
+    chunk_size: &AtomicUsize,
+) -> bool {
+    loop {
+        // Wait until the receiver is ready for another chunk
+        if !acquire_credit(credits).await {
+            return false;
+        }
 
-    // Take the first 16 bytes of the secret as the shared key
-    let shared_secret = &shared_secret[0..16];
+        // Read a whole chunk unless the stream ends before, without blocking the runtime
+        let length = chunk_size.load(Ordering::Relaxed) as u64;
+        let handle = file.handle.clone();
+        let read = tokio::task::spawn_blocking(move || {
+            let mut chunk = vec![];
+            handle.as_ref().take(length).read_to_end(&mut chunk).map(|_| chunk)
+        })
+        .await;
+        let chunk = match read {
+            Ok(Ok(chunk)) => chunk,
+            Ok(Err(error)) => {
+                eprintln!("Error: Unable to read '{}': {}", file.name, error);
+                return false;
+            }
+            Err(_) => return false,
+        };
+        let end = chunk.is_empty();
 
-    // Convert the shared secret bytes to a `Key<Aes128Gcm>` instance
-    let shared_key: &Key<Aes128Gcm> = shared_secret.into();
+        // Send the encrypted chunk packet to the receiver
+        let chunk = match compress_chunk(&chunk, compression) {
+            Ok(chunk) => chunk,
+            Err(error) => {
+                eprintln!("Error: Unable to compress '{}': {}", file.name, error);
+                return false;
+            }
+        };
+        let sent = numbering.send(sender, shared_key, destination, index, &chunk);
+        if !sent.await {
+            return false;
+        }
+
+        if end {
+            return true;
+        }
+    }
+}
+
+/// Waits for a credit from the receiver and uses it up.
+///
+/// # Arguments
+///
+/// * `credits` - The number of chunks the receiver is ready to accept.
+///
+/// # Returns
+///
+/// `false` if no more credits will be granted.
+async fn acquire_credit(credits: &Semaphore) -> bool {
+    match credits.acquire().await {
+        Ok(permit) => {
+            permit.forget();
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Duplicates the handle of stdin, so it is read like the handles of the other files.
+///
+/// # Returns
+///
+/// The duplicated handle, or an error if stdin is closed.
+fn stdin_handle() -> io::Result<fs::File> {
+    #[cfg(unix)]
+    {
+        use std::os::fd::AsFd;
+        Ok(fs::File::from(io::stdin().as_fd().try_clone_to_owned()?))
+    }
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::io::AsHandle;
+        Ok(fs::File::from(
+            io::stdin().as_handle().try_clone_to_owned()?,
+        ))
+    }
+}
+
+/// Packs a directory into a tar stream while it is read.
+///
+/// A thread writes the archive into a pipe and blocks until the transfer reads it, so
+/// the directory is never packed on disk. If a file of the directory cannot be read,
+/// the thread writes a broken header, which the receiver fails to unpack, instead of
+/// ending the archive early as if it was complete.
+///
+/// # Arguments
+///
+/// * `path` - The path of the directory.
+/// * `symlinks` - What is done with the links inside the directory.
+///
+/// # Returns
+///
+/// The end of the pipe the archive is read from.
+fn tar_stream(path: &str, walk: Walk) -> io::Result<fs::File> {
+    let (reader, writer) = io::pipe()?;
+    let root = Path::new(path).to_path_buf();
+
+    std::thread::spawn(move || {
+        let mut walk = walk;
+        let mut builder = tar::Builder::new(writer);
+        let packed = tar_entry(&mut builder, &root, Path::new("."), true, &mut walk)
+            .and_then(|_| builder.finish());
+        match packed {
+            // The transfer stopped reading, e.g. because the receiver took the other path
+            Err(error) if error.kind() == io::ErrorKind::BrokenPipe => {}
+            Err(error) => {
+                eprintln!("Error: Failed to pack '{}': {}", root.display(), error);
+                let _ = builder.get_mut().write_all(&[0xff; 512]);
+            }
+            Ok(()) => {}
+        }
+    });
+
+    Ok(pipe_handle(reader))
+}
+
+/// Adds a file or a directory with everything inside it to a tar stream.
+///
+/// # Arguments
+///
+/// * `builder` - The archive to add the entry to.
+/// * `path` - The path of the file or directory.
+/// * `name` - The name of the entry in the archive.
+/// * `given` - Whether the user gave the path, rather than it being found in a directory.
+/// * `walk` - How the directory is walked.
+fn tar_entry<W: Write>(
+    builder: &mut tar::Builder<W>,
+    path: &Path,
+    name: &Path,
+    given: bool,
+    walk: &mut Walk,
+) -> io::Result<()> {
+    let Some(metadata) = entry_metadata(path, given, walk.symlinks)? else {
+        return Ok(());
+    };
+
+    if metadata.is_dir() {
+        builder.append_dir(name, path)?;
+        let relative = name.strip_prefix(".").unwrap_or(name);
+        return each_child(path, relative, walk, |child, entry, walk| {
+            tar_entry(builder, child, &name.join(entry), false, walk)
+        });
+    }
+
+    // Links that are kept are packed as links, the receiver must not find files
+    // outside the tree through a followed one
+    builder.follow_symlinks(!metadata.is_symlink());
+    builder.append_path_with_name(path, name)
+}
+
+/// Returns the metadata a file or directory is packed with.
+///
+/// # Arguments
+///
+/// * `path` - The path of the file or directory.
+/// * `given` - Whether the user gave the path. A given link is followed by default,
+///   while links found in directories are packed as links.
+/// * `symlinks` - What is done with links.
+///
+/// # Returns
+///
+/// The metadata of the link or of the file it points to, or `None` if the link is
+/// left out.
+fn entry_metadata(
+    path: &Path,
+    given: bool,
+    symlinks: Option<SymlinkPolicy>,
+) -> io::Result<Option<fs::Metadata>> {
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_symlink() {
+        return Ok(Some(metadata));
+    }
+
+    let policy = symlinks.unwrap_or(match given {
+        true => SymlinkPolicy::Follow,
+        false => SymlinkPolicy::Preserve,
+    });
+    match policy {
+        SymlinkPolicy::Follow => fs::metadata(path).map(Some),
+        SymlinkPolicy::Skip => Ok(None),
+        SymlinkPolicy::Preserve => Ok(Some(metadata)),
+    }
+}
+
+/// How the directories of a transfer are walked when they are packed.
+struct Walk {
+    /// What is done with links.
+    symlinks: Option<SymlinkPolicy>,
+    /// Which entries are packed.
+    filter: PathFilter,
+    /// The directories the current entry is inside of, to find links in a loop.
+    ancestors: Vec<PathBuf>,
+}
+
+impl Walk {
+    /// Creates the walk of a transfer.
+    ///
+    /// # Arguments
+    ///
+    /// * `options` - The options of the transfer.
+    fn new(options: &SenderOptions) -> Walk {
+        Walk {
+            symlinks: options.symlinks,
+            filter: options.filter.clone(),
+            ancestors: vec![],
+        }
+    }
+}
+
+/// Calls a function for every entry of a directory that the filter admits, sorted by
+/// name, so the same directory is always packed the same way.
+///
+/// # Arguments
+///
+/// * `path` - The path of the directory.
+/// * `relative` - The path of the directory inside the packed one, which the entries
+///   are matched against the patterns with.
+/// * `walk` - How the directory is walked. A directory that is reached again through a
+///   link is an error instead of being packed forever.
+/// * `add` - Called with the path and the name of every entry that is packed.
+fn each_child<E: From<io::Error>>(
+    path: &Path,
+    relative: &Path,
+    walk: &mut Walk,
+    mut add: impl FnMut(&Path, &str, &mut Walk) -> std::result::Result<(), E>,
+) -> std::result::Result<(), E> {
+    let real = fs::canonicalize(path)?;
+    if walk.ancestors.contains(&real) {
+        return Err(io::Error::other(format!(
+            "'{}' links to a directory that contains it",
+            path.display()
+        ))
+        .into());
+    }
+
+    let mut entries = fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+
+    walk.ancestors.push(real);
+    for entry in entries {
+        let child = path.join(&entry);
+        if walk.filter.admits(&relative.join(&entry), child.is_dir()) {
+            add(&child, &entry.to_string_lossy(), walk)?;
+        }
+    }
+    walk.ancestors.pop();
+    Ok(())
+}
+
+/// Packs files and directories into a zip stream while it is read.
+///
+/// Like a tar stream, the archive is written into a pipe by a thread. Every path
+/// becomes an entry named after it, directories with everything inside them. Links are
+/// handled as `symlinks` says, and special files inside directories are left out.
+/// If a file cannot be read, the archive ends without its central directory, so it
+/// fails to open instead of silently missing the file.
+///
+/// # Arguments
+///
+/// * `paths` - The paths of the files and directories.
+/// * `walk` - How the directories are walked.
+///
+/// # Returns
+///
+/// The end of the pipe the archive is read from.
+fn zip_stream(paths: &[String], walk: Walk) -> io::Result<fs::File> {
+    let (reader, writer) = io::pipe()?;
+    let paths = paths.to_vec();
+
+    std::thread::spawn(move || {
+        let mut walk = walk;
+        let mut archive = ZipWriter::new_stream(writer);
+        let packed = paths
+            .iter()
+            .try_for_each(|path| {
+                let name = base_name(path);
+                zip_entry(&mut archive, Path::new(path), &name, true, &mut walk)
+            })
+            .and_then(|_| archive.finish().map(|_| ()));
+        match packed {
+            // The transfer stopped reading, e.g. because the receiver took the other path
+            Err(ZipError::Io(error)) if error.kind() == io::ErrorKind::BrokenPipe => {}
+            Err(error) => eprintln!("Error: Failed to pack the files: {}", error),
+            Ok(()) => {}
+        }
+    });
+
+    Ok(pipe_handle(reader))
+}
+
+/// Adds a file or a directory with everything inside it to a zip stream.
+///
+/// # Arguments
+///
+/// * `archive` - The archive to add the entry to.
+/// * `path` - The path of the file or directory.
+/// * `name` - The name of the entry in the archive.
+/// * `given` - Whether the user gave the path, rather than it being found in a directory.
+/// * `walk` - How the directory is walked.
+fn zip_entry<W: Write>(
+    archive: &mut ZipWriter<StreamWriter<W>>,
+    path: &Path,
+    name: &str,
+    given: bool,
+    walk: &mut Walk,
+) -> ZipResult<()> {
+    let Some(metadata) = entry_metadata(path, given, walk.symlinks)? else {
+        return Ok(());
+    };
+    let mut options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Stored)
+        .large_file(metadata.len() >= u32::MAX as u64);
+    if let Some(modified) = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| ZipDateTime::try_from(OffsetDateTime::from(modified)).ok())
+    {
+        options = options.last_modified_time(modified);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        options = options.unix_permissions(metadata.permissions().mode());
+    }
+
+    if metadata.is_symlink() {
+        let target = fs::read_link(path)?;
+        return archive.add_symlink(name, target.to_string_lossy(), options);
+    }
+    if metadata.is_dir() {
+        archive.add_directory(name, options)?;
+        // The entries are matched without the name of the directory the user gave
+        let relative: PathBuf = Path::new(name).components().skip(1).collect();
+        return each_child(path, &relative, walk, |child, entry, walk| {
+            zip_entry(archive, child, &format!("{}/{}", name, entry), false, walk)
+        });
+    }
+    if !metadata.is_file() {
+        return Ok(());
+    }
+
+    archive.start_file(name, options)?;
+    io::copy(&mut fs::File::open(path)?, archive)?;
+    Ok(())
+}
+
+/// Turns the end of a pipe into a file handle, so it is read like the other files.
+///
+/// # Arguments
+///
+/// * `reader` - The end of the pipe that is read from.
+fn pipe_handle(reader: io::PipeReader) -> fs::File {
+    #[cfg(unix)]
+    {
+        fs::File::from(std::os::fd::OwnedFd::from(reader))
+    }
+
+    #[cfg(windows)]
+    {
+        fs::File::from(std::os::windows::io::OwnedHandle::from(reader))
+    }
+}
+
+/// Reads a chunk at the given position of a file.
+///
+/// The cursor of the handle is not used, so the transfers to several receivers can read
+/// the same handle at the same time.
+///
+/// # Arguments
+///
+/// * `handle` - The handle of the file.
+/// * `chunk` - The buffer to fill completely.
+/// * `position` - The position in the file to read from.
+///
+/// # Returns
+///
+/// An error if the chunk could not be read, e.g. because the file became shorter.
+fn read_at(handle: &fs::File, chunk: &mut [u8], position: u64) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileExt;
+
+        handle.read_exact_at(chunk, position)
+    }
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::FileExt;
+
+        let mut read = 0;
+        while read < chunk.len() {
+            match handle.seek_read(&mut chunk[read..], position + read as u64)? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                count => read += count,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Maps a file into memory for reading.
+///
+/// Only the size the file was listed with is mapped, so a file that grew since does
+/// not send more than the receiver expects.
+///
+/// # Arguments
+///
+/// * `handle` - The handle of the file to map.
+/// * `size` - The size the file was listed with.
+///
+/// # Returns
+///
+/// A `Result` containing the read-only memory map of the file, or an error if the file
+/// became shorter than `size`.
+#[cfg(feature = "mmap")]
+fn map_file(handle: &fs::File, size: u64) -> io::Result<memmap2::Mmap> {
+    check_length(handle, size)?;
+    let length = usize::try_from(size).map_err(io::Error::other)?;
+
+    // SAFETY: The map is read-only, but the file is not locked against other processes.
+    // Unlike a read in the buffered path, which fails the transfer, reading a page of
+    // the map that a truncation removed raises SIGBUS and ends the process. The length
+    // is checked here and before every chunk, which leaves only the copy of a single
+    // chunk for another process to truncate the file in. This is why the feature is
+    // off by default.
+    unsafe { memmap2::MmapOptions::new().len(length).map(handle) }
+}
+
+/// Checks that a file still has the size it was listed with.
+///
+/// # Arguments
+///
+/// * `handle` - The handle of the file.
+/// * `size` - The size the file was listed with.
+///
+/// # Returns
+///
+/// An error if the file became shorter, or its size cannot be read.
+#[cfg(feature = "mmap")]
+fn check_length(handle: &fs::File, size: u64) -> io::Result<()> {
+    if handle.metadata()?.len() < size {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "the file became shorter while it was sent",
+        ));
+    }
+    Ok(())
+}
+
+/// Sends the content of a memory mapped file as chunk packets.
+///
+/// # Arguments
+///
+/// * `sender` - The sender object used to send packets.
+/// * `shared_key` - The shared key used for encryption.
+/// * `destination` - The position of the receiver in the room.
+/// * `index` - The index of the file in the list.
+/// * `file` - The file the map was created from.
+/// * `data` - The part of the mapped file that has to be sent.
+/// * `numbering` - Numbers the chunks of the file.
+/// * `compression` - The compression applied to each chunk.
+/// * `credits` - The number of chunks the receiver is ready to accept.
+/// * `chunk_size` - The current chunk size, adapted while the transfer runs.
+///
+/// # Returns
+///
+/// `false` if the transfer cannot continue.
+#[cfg(feature = "mmap")]
+#[allow(clippy::too_many_arguments)]
+async fn send_mapped_chunks(
+    sender: &Sender,
+    shared_key: &Option<SessionKey>,
+    destination: &AtomicU8,
+    index: u32,
+    file: &File,
+    mut data: &[u8],
+    mut numbering: Numbering,
+    compression: Compression,
+    credits: &Semaphore,
+    chunk_size: &AtomicUsize,
+) -> bool {
+    while !data.is_empty() {
+        // Wait until the receiver is ready for another chunk
+        if !acquire_credit(credits).await {
+            return false;
+        }
+
+        // A page the file lost would end the process once it is read
+        if let Err(error) = check_length(&file.handle, file.size) {
+            eprintln!("Error: Unable to read file '{}': {}", file.name, error);
+            return false;
+        }
+
+        let length = chunk_size.load(Ordering::Relaxed).min(data.len());
+        let (chunk, rest) = data.split_at(length);
+
+        // The chunk is encrypted from the map, without a copy in between
+        let chunk = match compress_chunk(chunk, compression) {
+            Ok(chunk) => chunk,
+            Err(error) => {
+                eprintln!("Error: Unable to compress file '{}': {}", file.name, error);
+                return false;
+            }
+        };
+        let sent = numbering.send(sender, shared_key, destination, index, &chunk);
+        if !sent.await {
+            return false;
+        }
+        data = rest;
+    }
+
+    true
+}
+
+/// Compresses a chunk with the negotiated compression.
+///
+/// # Arguments
+///
+/// * `chunk` - The raw content of the chunk.
+/// * `compression` - The compression negotiated with the receiver.
+///
+/// # Returns
+///
+/// The compressed chunk, or the raw chunk itself if no compression was negotiated.
+/// An error of the compressor is returned, so the transfer fails instead of the
+/// process.
+fn compress_chunk(chunk: &[u8], compression: Compression) -> io::Result<Cow<'_, [u8]>> {
+    match compression {
+        Compression::None => Ok(Cow::Borrowed(chunk)),
+        Compression::Zstd => zstd::bulk::compress(chunk, ZSTD_LEVEL).map(Cow::Owned),
+    }
+}
+
+/// Finalizes the handshake by sending the list of files to the receiver.
+///
+/// The file transfer task is started once the receiver answers with a resume packet.
+/// A text snippet is sent right away instead, and the receiver confirms it with a done
+/// packet.
+///
+/// # Arguments
+///
+/// * `context` - The mutable context holding the sender, files, and shared key.
+/// * `peer` - The index of the receiver.
+///
+/// # Returns
+///
+/// A `Status` indicating the success or failure of the handshake finalization.
+fn on_handshake_finalize(context: &mut Context, peer: usize) -> Status {
+    let state = &context.peers[peer];
+    if let Some(text) = &context.options.text {
+        context.sender.send_encrypted_packet(
+            &state.shared_key,
+            state.destination(),
+            Value::Text(TextPacket { text: text.clone() }),
+        );
+        return Status::Continue();
+    }
+
+    // Create a vector of `Entry`s from the files in the context
+    let mut entries = vec![];
+    for (index, file) in context.files.iter().enumerate() {
+        let entry = list_packet::Entry {
+            // The index of the file in the context
+            index: index.try_into().unwrap(),
+            // The name of the file
+            name: file.name.clone(),
+            // The size of the file
+            size: file.size,
+            // The hash of the file
+            hash: file.hash.clone(),
+            // Whether the size is unknown until the file ends
+            stream: file.stream,
+            // Whether the receiver unpacks the file into a directory
+            archive: file.archive,
+            // When the file was last modified
+            modified: file
+                .modified
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .and_then(|since| since.as_nanos().try_into().ok())
+                .unwrap_or(0),
+            // The target of a link the receiver creates instead of a file
+            link: file.link.clone().unwrap_or_default(),
+        };
+        entries.push(entry);
+    }
+
+    // Send the encrypted list packet to the receiver
+    let state = &context.peers[peer];
+    context.sender.send_encrypted_packet(
+        &state.shared_key,
+        state.destination(),
+        Value::List(ListPacket {
+            entries,
+            hash_algorithm: HASH_ALGORITHM.into(),
+        }),
+    );
+    context.started.get_or_insert_with(Instant::now);
+
+    Status::Continue()
+}
+
+/// Handles the resume packet received from the receiver.
+///
+/// The resume packet contains the number of bytes the receiver already has for each
+/// file. The file transfer task is spawned so that each file continues at that offset.
+/// Files reported with their full size are skipped.
+///
+/// # Arguments
+///
+/// * `context` - The mutable context holding the sender, files, and shared key.
+/// * `peer` - The index of the receiver that sent the packet.
+/// * `resume` - The resume packet received from the receiver.
+///
+/// # Returns
+///
+/// A `Status` indicating the success or failure of the operation.
+fn on_resume(context: &mut Context, peer: usize, resume: ResumePacket) -> Status {
+    let state = &mut context.peers[peer];
+
+    // Check if the shared key is established
+    if state.shared_key.is_none() {
+        return Status::Err("Invalid resume packet: no shared key established".into());
+    }
+
+    // Check if the transfer has already been started
+    if state.task.is_some() {
+        return Status::Err("Transfer has already been started.".into());
+    }
+
+    // Collect the offset of every file, defaulting to the start of the file
+    let mut offsets = vec![0u64; context.files.len()];
+    for entry in resume.entries {
+        let Some(file) = context.files.get(entry.index as usize) else {
+            return Status::Err("Invalid index in resume packet.".into());
+        };
+
+        if entry.offset > file.end() {
+            return Status::Err(
+                format!(
+                    "Invalid offset {} for file '{}' in resume packet.",
+                    entry.offset, file.name
+                )
+                .into(),
+            );
+        }
+
+        offsets[entry.index as usize] = entry.offset;
+    }
+
+    // Nothing is left to send if the receiver skipped every file
+    if context
+        .files
+        .iter()
+        .zip(&offsets)
+        .all(|(file, offset)| *offset == file.end())
+    {
+        state.offsets = offsets;
+        return on_peer_complete(context, peer);
+    }
+
+    // Spawn the file transfer task and store it in the context. No chunk is sent until
+    // the receiver grants credits.
+    state.offsets = offsets.clone();
+    state.credits = Arc::new(Semaphore::new(0));
+    state.chunk_sizer = ChunkSizer::new();
+    let (retries, retry_requests) = flume::unbounded();
+    state.retries = retries;
+    state.task = Some(tokio::spawn(on_chunk(
+        context.sender.clone(),
+        state.shared_key.clone(),
+        state.destination.clone(),
+        context.files.clone(),
+        offsets,
+        std::mem::take(&mut state.signatures),
+        state.streams,
+        state.compression,
+        state.credits.clone(),
+        state.chunk_sizer.handle(),
+        retry_requests,
+    )));
+
+    Status::Continue()
+}
+
+/// Handles the signature packet received from the receiver.
+///
+/// The receiver sends a signature for every file it has another version of before it
+/// asks for the files, so only the blocks it lacks are sent.
+///
+/// # Arguments
+///
+/// * `context` - The context holding the files of the transfer.
+/// * `peer` - The index of the receiver that sent the packet.
+/// * `signature` - The signature packet received from the receiver.
+///
+/// # Returns
+///
+/// A `Status` indicating the success or failure of the operation.
+fn on_signature(context: &mut Context, peer: usize, signature: SignaturePacket) -> Status {
+    let state = &mut context.peers[peer];
+
+    // Check if the shared key is established
+    if state.shared_key.is_none() {
+        return Status::Err("Invalid signature packet: no shared key established".into());
+    }
+
+    // The signatures are only used for the files that have not been started yet
+    if state.task.is_some() {
+        return Status::Err("Transfer has already been started.".into());
+    }
+
+    let Some(file) = context.files.get(signature.index as usize) else {
+        return Status::Err("Invalid index in signature packet.".into());
+    };
+
+    // Streams and links are not read in blocks
+    if file.stream || file.link.is_some() {
+        return Status::Err(format!("'{}' cannot be sent as a delta.", file.name).into());
+    }
+
+    if signature.block_size == 0 || signature.block_size > delta::MAX_BLOCK_SIZE {
+        return Status::Err(
+            format!(
+                "Invalid block size {} in signature packet.",
+                signature.block_size
+            )
+            .into(),
+        );
+    }
+
+    state.signatures.insert(signature.index, signature);
+
+    Status::Continue()
+}
+
+/// Handles the credit packet received from the receiver.
+///
+/// The receiver grants credits as it writes chunks to disk. Each credit allows the
+/// transfer task to send one more chunk, so a slow receiver is never flooded while a
+/// fast one is never kept waiting. The time between two credit packets is also used
+/// to adapt the chunk size.
+///
+/// # Arguments
+///
+/// * `context` - The context holding the credits of the transfer.
+/// * `peer` - The index of the receiver that sent the packet.
+/// * `credit` - The credit packet received from the receiver.
+///
+/// # Returns
+///
+/// A `Status` indicating the success or failure of the operation.
+fn on_credit(context: &mut Context, peer: usize, credit: CreditPacket) -> Status {
+    let state = &mut context.peers[peer];
+
+    // Check if the transfer has been started
+    if state.task.is_none() {
+        return Status::Err("Invalid credit packet: transfer has not been started".into());
+    }
+
+    // A receiver cannot grant more credits than the semaphore holds
+    let available = state.credits.available_permits();
+    state
+        .credits
+        .add_permits((credit.chunks as usize).min(Semaphore::MAX_PERMITS - available));
+
+    // Adapt the chunk size to how fast the batch came back
+    let chunk_size = state.chunk_sizer.on_ack(Instant::now());
+    debug!("Chunk size is now {} bytes", chunk_size);
+
+    Status::Continue()
+}
+
+/// Handles the retry packet received from the receiver.
+///
+/// The receiver requests a file again when it arrived broken. The file is queued for
+/// the transfer task, which resends it from the start after all other files, and it
+/// counts as outstanding until the receiver reports it complete.
+///
+/// # Arguments
+///
+/// * `context` - The context holding the files of the transfer.
+/// * `peer` - The index of the receiver that sent the packet.
+/// * `retry` - The retry packet received from the receiver.
+///
+/// # Returns
+///
+/// A `Status` indicating the success or failure of the operation.
+fn on_retry(context: &mut Context, peer: usize, retry: RetryPacket) -> Status {
+    let state = &mut context.peers[peer];
+
+    // Check if the transfer has been started
+    if state.task.is_none() {
+        return Status::Err("Invalid retry packet: transfer has not been started".into());
+    }
+
+    let Some(file) = context.files.get(retry.index as usize) else {
+        return Status::Err("Invalid index in retry packet.".into());
+    };
+
+    // A stream was read as it was sent and cannot be read again
+    if file.stream {
+        return Status::Err(
+            format!(
+                "'{}' was read from a stream and cannot be sent again.",
+                file.name
+            )
+            .into(),
+        );
+    }
+
+    notify!(context.options);
+    notify!(
+        context.options,
+        "The receiver requested '{}' again.",
+        file.name
+    );
+    if let Some(events) = &context.options.events {
+        events.report(TransferEvent::Retry {
+            file: retry.index as usize,
+            reason: "The receiver requested the file again.".into(),
+        });
+    }
+
+    if let Some(offset) = state.offsets.get_mut(retry.index as usize) {
+        *offset = 0;
+    }
+
+    if state.retries.send(retry.index).is_err() {
+        return Status::Err("Transfer task has stopped.".into());
+    }
+
+    Status::Continue()
+}
+
+/// Handles the handshake response packet received from the receiver.
+///
+/// # Arguments
+///
+/// * `context` - The mutable context holding the sender, files, and shared key.
+/// * `peer` - The index of the receiver that sent the packet.
+/// * `handshake_response` - The handshake response packet received from the receiver.
+///
+/// # Returns
+///
+/// A `Status` indicating the success or failure of the handshake.
+fn on_handshake(
+    context: &mut Context,
+    peer: usize,
+    handshake_response: HandshakeResponsePacket,
+) -> Status {
+    // Check if the handshake has already been performed
+    if context.peers[peer].shared_key.is_some() {
+        return Status::Err(CaesarError::Handshake("Already performed handshake.".into()));
+    }
+
+    // Derive the keys from the receiver's share
+    let keys = match context.peers[peer].pake.finish(&handshake_response.public_key) {
+        Ok(keys) => keys,
+        Err(error) => return Status::Err(error),
+    };
+
+    // The receiver proves that it derived the same keys, i.e. that it knows the secret,
+    // and that the offer and its choices were not changed on the way. Every handshake
+    // is a guess at the PIN, so the transfer ends after a wrong one. A shared room
+    // allows one guess for each receiver it was created for.
+    let negotiation = negotiation(&context.peers[peer].offer, &handshake_response);
+    if !keys.verify(Role::Receiver, &negotiation, &handshake_response.signature) {
+        context.wrong_codes += 1;
+        let error = CaesarError::WrongCode(
+            "The receiver does not know the PIN, or the handshake was changed on the way."
+                .into(),
+        );
+        if context.wrong_codes < context.receivers() {
+            notify!(context.options);
+            notify!(context.options, "A receiver entered a wrong code and was turned away.");
+            reject_peer(context, peer, &error);
+            return finish_if_done(context);
+        }
+
+        notify!(context.options);
+        notify!(
+            context.options,
+            "The receiver entered a wrong code. The transfer was ended, send the files again."
+        );
+        return Status::Err(error);
+    }
+
+    // Check that the receiver chose a compression that was offered. Both the offer and
+    // the choice are covered by the confirmation above, so a relay cannot change them
+    let compression = handshake_response.compression();
+    if compression != Compression::None && !context.options.compress {
+        return Status::Err(CaesarError::Handshake(
+            "Receiver chose a compression that was not offered.".into(),
+        ));
+    }
+    let state = &mut context.peers[peer];
+    state.compression = compression;
+
+    // A receiver that does not know streams takes the files one after the other
+    state.streams = handshake_response.streams.clamp(1, MAX_STREAMS);
+
+    // The HKDF key schedule, framing v3 and counter nonces are the only ones offered,
+    // and every receiver that covers the offer with its confirmation supports them, so
+    // anything else is refused
+    if handshake_response.key_schedule() != KeySchedule::Hkdf {
+        return Status::Err(CaesarError::Handshake(
+            "Receiver chose one key for both directions.".into(),
+        ));
+    }
+    if handshake_response.framing() != Framing::V3 {
+        return Status::Err(CaesarError::Handshake(
+            "Receiver chose a framing that was not offered.".into(),
+        ));
+    }
+    if handshake_response.nonce_scheme() != NonceScheme::Counter {
+        return Status::Err(CaesarError::Handshake(
+            "Receiver chose a nonce scheme that was not offered.".into(),
+        ));
+    }
+
+    // The ciphers are covered by the confirmation, and the receiver has to pick one of
+    // them
+    let cipher = handshake_response.cipher();
+    if !state.offer.ciphers().any(|offered| offered == cipher) {
+        return Status::Err(CaesarError::Handshake(
+            "Receiver chose a cipher that was not offered.".into(),
+        ));
+    }
+
+    // Set the shared key of the receiver with the cipher it chose
+    let shared_key = SessionKey::derive(&keys, Role::Sender, cipher);
+    if handshake_response.heartbeat {
+        state.heartbeat = Some(tokio::spawn(heartbeat::beat(
+            context.sender.clone(),
+            shared_key.clone(),
+            state.destination.clone(),
+        )));
+    }
+    state.shared_key = Some(shared_key);
+
+    // Each receiver has keys of its own, and so a code of its own
+    let code = keys.short_code();
+    let shared = context.is_shared();
+    if shared {
+        notify!(
+            context.options,
+            "Verification code of receiver {}: {}. Compare it with the code the receiver shows.",
+            peer + 1,
+            code
+        );
+    } else {
+        notify!(
+            context.options,
+            "Verification code: {}. Compare it with the code the receiver shows.",
+            code
+        );
+    }
+    if let Some(events) = &context.options.events {
+        events.report(TransferEvent::Verification {
+            code,
+            receiver: shared.then_some(peer),
+        });
+    }
+
+    // Finalize the handshake by sending the list of files
+    on_handshake_finalize(context, peer)
+}
+
+/// Handles a binary message from a receiver.
+///
+/// # Arguments
+///
+/// * `context` - The mutable context holding the receivers.
+/// * `data` - The binary message including the leading routing byte.
+///
+/// # Returns
+///
+/// A `Status` indicating the success or failure of handling the message.
+fn on_binary(context: &mut Context, data: Vec<u8>) -> Status {
+    // The routing byte holds the position of the receiver that sent the message
+    let source = data.first().copied().unwrap_or_default();
+    let Some(peer) = usize::from(source)
+        .checked_sub(1)
+        .filter(|peer| *peer < context.peers.len())
+    else {
+        debug!("Ignoring a message from the unknown position {source}");
+        return Status::Continue();
+    };
 
-    // Create a new instance of `Aes128Gcm` with the shared key
-    let shared_key = <Aes128Gcm as aes_gcm::KeyInit>::new(shared_key);
+    // Receivers that were turned away may still send until they have left
+    if context.peers[peer].done && context.is_shared() {
+        return Status::Continue();
+    }
 
-    // Set the shared key in the context
-    context.shared_key = Some(shared_key);
+    // Decrypt and decode the packet
+    let value = match decode_packet(&context.peers[peer].shared_key, source, None, data) {
+        Ok(value) => value,
+        Err(error) => return Status::Err(error),
+    };
 
-    // Finalize the handshake by sending the list of files and starting the file transfer task
-    on_handshake_finalize(context)
+    // Call the corresponding handler based on the packet value
+    match value {
+        // Handle the `HandshakeResponse` packet
+        Value::HandshakeResponse(handshake_response) => {
+            on_handshake(context, peer, handshake_response)
+        }
+        // Handle the `Progress` packet
+        Value::Progress(progress) => on_progress(context, peer, progress),
+        // Handle the `Signature` packet
+        Value::Signature(signature) => on_signature(context, peer, signature),
+        // Handle the `Resume` packet
+        Value::Resume(resume) => on_resume(context, peer, resume),
+        // Handle the `Credit` packet
+        Value::Credit(credit) => on_credit(context, peer, credit),
+        // Handle the `Retry` packet
+        Value::Retry(retry) => on_retry(context, peer, retry),
+        // Handle the `Done` packet, which confirms a text snippet
+        Value::Done(_) if context.options.text.is_some() => on_peer_complete(context, peer),
+        // Handle the `Error` packet
+        Value::Error(error) => on_error(error.message),
+        // Heartbeats only count as activity
+        Value::Heartbeat(_) => Status::Continue(),
+        // Handle unexpected packets
+        _ => Status::Err(format!("Unexpected packet: {:?}", value).into()),
+    }
 }
 
 /// Handles the incoming message from the WebSocket.
 ///
+/// In a shared room, an error in the transfer to one receiver turns only that receiver
+/// away, and the transfer to the others continues.
+///
 /// # Arguments
 ///
 /// * `context` - The mutable context holding the sender and shared key.
@@ -490,7 +2380,7 @@ fn on_message(
     transfer_name: String,
     is_local: bool,
 ) -> Status {
-    match message.clone() {
+    match message {
         // Handle the text WebSocket message
         WebSocketMessage::Text(text) => {
             // Parse the JSON packet from the text message
@@ -508,7 +2398,7 @@ fn on_message(
                     on_create_room(context, id, relay, transfer_name, is_local)
                 }
                 // Handle the `Join` packet
-                JsonPacketResponse::Join { size } => on_join_room(context, size),
+                JsonPacketResponse::Join { size, index } => on_join_room(context, size, index),
                 // Handle the `Leave` packet
                 JsonPacketResponse::Leave { index } => on_leave_room(context, index),
                 // Handle the `Error` packet
@@ -517,35 +2407,25 @@ fn on_message(
         }
         // Handle the binary WebSocket message
         WebSocketMessage::Binary(data) => {
-            // Extract the encrypted data from the binary message
-            let data = data[1..].to_vec();
-
-            // Decrypt the data using the shared key if available
-            let data = if let Some(shared_key) = &context.shared_key {
-                let nonce = &data[..NONCE_SIZE];
-                let ciphertext = &data[NONCE_SIZE..];
-
-                shared_key.decrypt(nonce.into(), ciphertext).unwrap()
-            } else {
-                data
-            };
-
-            // Decode the packet from the decrypted data
-            let packet = Packet::decode(data.as_ref()).unwrap();
-            let value = packet.value.unwrap();
-
-            // Call the corresponding handler based on the packet value
-            return match value {
-                // Handle the `HandshakeResponse` packet
-                Value::HandshakeResponse(handshake_response) => {
-                    on_handshake(context, handshake_response)
+            let source = data.first().copied().unwrap_or_default();
+            return match on_binary(context, data) {
+                Status::Err(error @ CaesarError::WrongCode(_)) => Status::Err(error),
+                Status::Err(error) if context.is_shared() => {
+                    // Only the receiver that caused the error is turned away
+                    error!("Error with receiver {source}: {error}");
+                    if let Some(peer) = usize::from(source)
+                        .checked_sub(1)
+                        .filter(|peer| *peer < context.peers.len())
+                    {
+                        reject_peer(context, peer, &error);
+                    }
+                    Status::Continue()
                 }
-                // Handle the `Progress` packet
-                Value::Progress(progress) => on_progress(context, progress),
-                // Handle unexpected packets
-                _ => Status::Err(format!("Unexpected packet: {:?}", value)),
+                status => status,
             };
         }
+        // Keep-alive probes need no handling
+        WebSocketMessage::Ping(_) | WebSocketMessage::Pong(_) => return Status::Continue(),
         // Handle other message types
         _ => (),
     }
@@ -564,126 +2444,421 @@ fn on_message(
 /// * `relay` - The URL of the relay server.
 /// * `transfer_name` - The name of the transfer.
 /// * `is_local` - Whether the transfer is local or not.
+/// * `options` - The options of the transfer.
+///
+/// # Returns
+///
+/// The summary of the transfer once the receiver has every file, or the error that
+/// ended it.
 #[allow(clippy::needless_doctest_main)]
 pub async fn start(
-    socket: Socket,
+    socket: impl Transport,
     paths: Vec<String>,
     room_id: Option<String>,
     relay: String,
     transfer_name: String,
     is_local: bool,
-) {
+    options: SenderOptions,
+) -> Result<TransferSummary> {
+    let session = Session::for_options(room_id, &options);
+    start_session(socket, paths, &session, relay, transfer_name, is_local, options).await
+}
+
+/// Starts a session of the sender process.
+///
+/// This works like `start`, but creates the room of the given session, so a receiver
+/// that took part in an earlier session of the transfer can rejoin it.
+///
+/// # Arguments
+///
+/// * `socket` - The WebSocket connection.
+/// * `paths` - The paths to the files to be sent.
+/// * `session` - The room and the secret shared by all sessions of the transfer.
+/// * `relay` - The URL of the relay server.
+/// * `transfer_name` - The name of the transfer.
+/// * `is_local` - Whether the transfer is local or not.
+/// * `options` - The options of the transfer.
+///
+/// # Returns
+///
+/// The summary of the transfer once the receiver has every file, or the error that
+/// ended the session.
+pub async fn start_session(
+    socket: impl Transport,
+    paths: Vec<String>,
+    session: &Session,
+    relay: String,
+    transfer_name: String,
+    is_local: bool,
+    options: SenderOptions,
+) -> Result<TransferSummary> {
     // Prepare the files to be sent
     let mut files = vec![];
 
+    // Every path is packed into a single archive, which is sent as one stream
+    let paths = match options.archive {
+        Some(ArchiveFormat::Zip) => {
+            let handle = zip_stream(&paths, Walk::new(&options))
+                .map_err(|error| CaesarError::io("Failed to pack the files", error))?;
+            let name = match paths.as_slice() {
+                [path] => format!("{}.zip", base_name(path)),
+                _ => "archive.zip".to_string(),
+            };
+            files.push(File {
+                name,
+                path: paths.join(", "),
+                size: 0,
+                hash: vec![],
+                handle: Arc::new(handle),
+                stream: true,
+                archive: false,
+                modified: None,
+                link: None,
+            });
+            vec![]
+        }
+        None => paths,
+    };
+
     for path in paths {
+        // Stdin is sent as a stream that ends when the input does
+        if path == STDIN_PATH {
+            let handle = stdin_handle()
+                .map_err(|error| CaesarError::io("Failed to read from stdin", error))?;
+            files.push(File {
+                name: "stdin".to_string(),
+                path,
+                size: 0,
+                hash: vec![],
+                handle: Arc::new(handle),
+                stream: true,
+                archive: false,
+                modified: None,
+                link: None,
+            });
+            continue;
+        }
+
+        // A link is sent as a link if asked to, the receiver creates it again
+        if options.symlinks == Some(SymlinkPolicy::Preserve)
+            && fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.is_symlink())
+        {
+            let target = fs::read_link(&path)
+                .map_err(|error| CaesarError::io(format!("Failed to read link '{}'", path), error))?;
+            // A link has no content, so its handle is a pipe that ends at once
+            let (reader, _) = io::pipe()
+                .map_err(|error| CaesarError::io("Failed to create a pipe", error))?;
+            // The link keeps its own name, not the one of the file it points to
+            let name = Path::new(&path)
+                .file_name()
+                .map_or_else(|| base_name(&path), |name| name.to_string_lossy().into_owned());
+            files.push(File {
+                name,
+                path,
+                size: 0,
+                hash: vec![],
+                handle: Arc::new(pipe_handle(reader)),
+                stream: false,
+                archive: false,
+                modified: None,
+                link: Some(target.to_string_lossy().into_owned()),
+            });
+            continue;
+        }
+
+        // A directory is sent as a tar stream that is packed while it is read
+        if options.tar && Path::new(&path).is_dir() {
+            let name = base_name(&path);
+            let handle = tar_stream(&path, Walk::new(&options))
+                .map_err(|error| CaesarError::io(format!("Failed to pack '{}'", path), error))?;
+            files.push(File {
+                name,
+                path,
+                size: 0,
+                hash: vec![],
+                handle: Arc::new(handle),
+                stream: true,
+                archive: true,
+                modified: None,
+                link: None,
+            });
+            continue;
+        }
+
+        let invalid = |reason: &str, fix: &str| {
+            CaesarError::InvalidPaths(vec![PathProblem {
+                path: path.clone(),
+                reason: reason.to_string(),
+                fix: fix.to_string(),
+            }])
+        };
+
         // Open the file
         let handle = match fs::File::open(&path) {
             Ok(handle) => handle,
             Err(error) => {
                 error!("Error: Failed to open file '{}': {}", path, error);
-                return;
+                return Err(CaesarError::io(format!("Failed to open file '{}'", path), error));
             }
         };
 
-        let metadata = handle.metadata().unwrap();
+        let metadata = handle
+            .metadata()
+            .map_err(|error| CaesarError::io(format!("Failed to inspect '{}'", path), error))?;
 
         // Check if the path points to a file
         if metadata.is_dir() {
             error!("Error: The path '{}' does not point to a file.", path);
-            return;
+            return Err(invalid(
+                "is a directory",
+                "Pass the files inside it or pack it into an archive first.",
+            ));
         }
 
-        let name = Path::new(&path).file_name().unwrap().to_str().unwrap();
+        let Some(name) = Path::new(&path).file_name().and_then(|name| name.to_str()) else {
+            return Err(invalid("has no valid file name", "Rename the file."));
+        };
 
-        let size = metadata.len();
+        // Devices do not report a length, so their size is found by seeking to the end
+        let size = if metadata.is_file() {
+            metadata.len()
+        } else {
+            match (&handle).seek(SeekFrom::End(0)) {
+                Ok(size) => size,
+                Err(error) => {
+                    error!("Error: Failed to determine the size of '{}': {}", path, error);
+                    return Err(CaesarError::io(
+                        format!("Failed to determine the size of '{}'", path),
+                        error,
+                    ));
+                }
+            }
+        };
 
         // Check if the file is empty
         if size == 0 {
             error!("Error: The file '{}' is empty and cannot be sent.", name);
-            return;
+            return Err(invalid("is empty", "Only files with content can be sent."));
         }
 
+        // Keep the handle, so the file is not opened again to be hashed or sent
         files.push(File {
             name: name.to_string(),
             path,
             size,
+            hash: vec![],
+            handle: Arc::new(handle),
+            stream: false,
+            archive: false,
+            modified: metadata.modified().ok(),
+            link: None,
         });
     }
 
-    // Generate the HMAC key
-    let mut hmac = [0u8; 32];
-    OsRng.fill_bytes(&mut hmac);
+    // Hash all files in parallel so the receiver can verify their integrity. A stream
+    // is only read as it is sent, so it has no hash.
+    let handles: Vec<Option<Arc<fs::File>>> = files
+        .iter()
+        .map(|file| (!file.stream && file.link.is_none()).then(|| file.handle.clone()))
+        .collect();
+    let hashing = tokio::task::spawn_blocking(move || {
+        handles
+            .par_iter()
+            .map(|handle| match handle {
+                Some(handle) => hash_file(handle, HASH_ALGORITHM),
+                None => Ok(vec![]),
+            })
+            .collect::<Vec<_>>()
+    });
+
+    // Hashing large files takes a while, the threads are left to finish on a cancel
+    let hashes = tokio::select! {
+        hashes = hashing => hashes.expect("Hashing the files panicked."),
+        _ = options.cancel.cancelled() => {
+            notify!(options, "Transfer was cancelled.");
+            return Err(CaesarError::Cancelled);
+        }
+    };
 
-    // Generate the encryption key
-    let key = EphemeralSecret::random(&mut OsRng);
+    for (file, hash) in files.iter_mut().zip(hashes) {
+        match hash {
+            Ok(hash) => file.hash = hash,
+            Err(error) => {
+                error!("Error: Failed to hash file '{}': {}", file.path, error);
+                return Err(CaesarError::io(
+                    format!("Failed to hash file '{}'", file.path),
+                    error,
+                ));
+            }
+        }
+    }
 
     // Create the flume channels
-    let (sender, receiver) = flume::bounded(1000);
+    let (sender, receiver) = flume::bounded(OUTGOING_QUEUE);
 
     // Split the WebSocket connection
-    let (outgoing, incoming) = socket.split();
+    let (mut outgoing, incoming) = socket.split();
+    let cancel = options.cancel.clone();
+    let idle = options.idle;
+    let reconnect = options.reconnect > 0;
+    let activity = Activity::new();
+    let keep_alive = sender.clone();
 
     // Create the context
     let mut context = Context {
         sender,
-        key,
         files,
 
-        hmac: hmac.to_vec(),
-        shared_key: None,
-        task: None,
+        secret: session.secret.clone(),
+        options,
+        peers: vec![],
+        completed: 0,
+        wrong_codes: 0,
+        started: None,
+        sent: 0,
     };
 
     debug!("Attempting to create room...");
 
-    debug!("With Room-ID: {:?}", room_id);
+    debug!("With Room-ID: {:?}", session.room_id);
     // Send the create room packet
     context.sender.send_json_packet(JsonPacket::Create {
-        id: room_id.clone(),
+        id: session.room_id.clone(),
+        priority: context.options.priority,
+        // A room for a single receiver is what every relay creates by default
+        size: context.is_shared().then(|| context.receivers() + 1),
     });
 
-    // Handle the incoming WebSocket messages
-    let outgoing_handler = receiver.stream().map(Ok).forward(outgoing);
-
-    let incoming_handler = incoming.try_for_each(|message| {
-        match on_message(
-            &mut context,
-            message,
-            relay.clone(),
-            transfer_name.clone(),
-            is_local,
-        ) {
-            Status::Exit() => {
-                // Send the leave room packet
-                context.sender.send_json_packet(JsonPacket::Leave);
-                println!("Transfer has completed.");
-
-                // Return an error
-                return future::err(Error::ConnectionClosed);
+    // The result once the transfer has ended on its own
+    let mut outcome = None;
+
+    let interruption = {
+        // Handle the incoming WebSocket messages
+        // Count the bytes that leave over this path
+        let usage = context.options.usage.clone();
+        let outgoing_handler = receiver
+            .stream()
+            .inspect(|message| usage.record(is_local, message.len() as u64))
+            .map(Ok)
+            .forward(&mut outgoing);
+
+        let incoming_handler = incoming.try_for_each(|message| {
+            // Credits and progress updates show that the transfer is moving
+            if let WebSocketMessage::Binary(_) = message {
+                activity.touch();
             }
-            Status::Err(error) => {
-                error!("Error: {}", error);
 
-                // Return an error
-                return future::err(Error::ConnectionClosed);
+            match on_message(
+                &mut context,
+                message,
+                relay.clone(),
+                transfer_name.clone(),
+                is_local,
+            ) {
+                Status::Exit() => {
+                    // Send the leave room packet
+                    context.sender.send_json_packet(JsonPacket::Leave);
+                    notify!(context.options, "Transfer has completed.");
+                    if let Some(events) = &context.options.events {
+                        events.report(TransferEvent::Complete {
+                            files: context.files.len(),
+                            bytes: context.files.iter().map(|file| file.size).sum(),
+                        });
+                    }
+
+                    // Return an error
+                    outcome = Some(Ok(summarize(&context, is_local)));
+                    return future::err(Error::ConnectionClosed);
+                }
+                Status::Err(error) => {
+                    error!("Error: {}", error);
+
+                    // Return an error
+                    outcome = Some(Err(error));
+                    return future::err(Error::ConnectionClosed);
+                }
+                _ => {}
+            };
+
+            future::ok(())
+        });
+
+        pin_mut!(incoming_handler, outgoing_handler);
+
+        // Wait for the handlers to complete, the transfer to be cancelled, to stall or
+        // for the system to wake up from a suspend
+        tokio::select! {
+            _ = incoming_handler => None,
+            _ = outgoing_handler => None,
+            _ = cancel.cancelled() => Some(Interruption::Cancelled),
+            _ = idle::watch(&activity, idle, &keep_alive) => Some(Interruption::IdleTimeout),
+            gap = suspend::watch(SUSPEND_THRESHOLD), if reconnect => {
+                Some(Interruption::Suspended(gap))
             }
-            _ => {}
-        };
+        }
+    };
 
-        future::ok(())
-    });
+    let Some(interruption) = interruption else {
+        return outcome.unwrap_or_else(|| {
+            Err(CaesarError::Connection(
+                "The connection to the relay was closed.".into(),
+            ))
+        });
+    };
+
+    // Stop sending chunks and heartbeats
+    for peer in &mut context.peers {
+        for task in peer.task.take().into_iter().chain(peer.heartbeat.take()) {
+            task.abort();
+        }
+    }
+
+    // Leave the room so the receiver knows the transfer is over
+    let leave = serde_json::to_string(&JsonPacket::Leave).unwrap();
+    let _ = outgoing.send(WebSocketMessage::Text(leave)).await;
+    let _ = outgoing.close().await;
+
+    match interruption {
+        Interruption::Cancelled => {
+            notify!(context.options, "Transfer was cancelled.");
 
-    pin_mut!(incoming_handler, outgoing_handler);
+            Err(CaesarError::Cancelled)
+        }
+        Interruption::IdleTimeout => {
+            notify!(context.options);
+            notify!(
+                context.options,
+                "Error: The receiver did not respond for {} seconds, closing the transfer. Send the files again to resume.",
+                idle.timeout.as_secs()
+            );
+
+            Err(CaesarError::IdleTimeout(idle.timeout.as_secs()))
+        }
+        Interruption::Suspended(gap) => {
+            notify!(context.options);
+            notify!(
+                context.options,
+                "The system was suspended for {} seconds.",
+                gap.as_secs()
+            );
 
-    // Wait for the incoming or outgoing handlers to complete
-    future::select(incoming_handler, outgoing_handler).await;
+            Err(CaesarError::Suspended(gap.as_secs()))
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use aes_gcm::KeyInit;
+    use crate::cipher::choose_cipher;
+    use crate::progress::ProgressReport;
+    use aes_gcm::{Aes128Gcm, Key, KeyInit};
+    use std::sync::Mutex;
+
+    /// Opens a file that stands in for the files of a transfer.
+    fn handle() -> Arc<fs::File> {
+        Arc::new(fs::File::open(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml")).unwrap())
+    }
 
     // #[test]
     // fn test_on_chunk() {
@@ -711,28 +2886,47 @@ mod tests {
     #[test]
     fn test_on_progress() {
         let (sender, _) = flume::bounded(1000);
-        let context = Context {
-            hmac: vec![],
+        let mut context = Context {
+            secret: Secret::Key(vec![]),
             sender,
-            key: EphemeralSecret::random(&mut OsRng),
-            shared_key: Some(Aes128Gcm::new(Key::<Aes128Gcm>::from_slice(&[0u8; 16]))),
             files: vec![
                 File {
                     name: "file1.txt".to_string(),
                     size: 100,
                     path: "file1.txt".to_string(),
+                    hash: vec![],
+                    handle: handle(),
+                    stream: false,
+                    archive: false,
+                    modified: None,
+                    link: None,
                 },
                 File {
                     name: "file2.txt".to_string(),
                     size: 100,
                     path: "file2.txt".to_string(),
+                    hash: vec![],
+                    handle: handle(),
+                    stream: false,
+                    archive: false,
+                    modified: None,
+                    link: None,
                 },
             ],
-            task: None,
+            options: SenderOptions::default(),
+            peers: vec![Peer {
+                shared_key: Some(Aes128Gcm::new(Key::<Aes128Gcm>::from_slice(&[0u8; 16])).into()),
+                ..Peer::new(&Secret::Key(vec![]), 1)
+            }],
+            completed: 0,
+            wrong_codes: 0,
+            started: None,
+            sent: 0,
         };
         assert_eq!(
             on_progress(
-                &context,
+                &mut context,
+                0,
                 ProgressPacket {
                     index: 0,
                     progress: 50
@@ -745,23 +2939,38 @@ mod tests {
     fn test_on_create_room() {
         let (sender, _) = flume::bounded(1000);
         let context = Context {
-            hmac: vec![],
+            secret: Secret::Key(vec![]),
             sender,
-            key: EphemeralSecret::random(&mut OsRng),
-            shared_key: None,
             files: vec![
                 File {
                     name: "file1.txt".to_string(),
                     size: 100,
                     path: "file1.txt".to_string(),
+                    hash: vec![],
+                    handle: handle(),
+                    stream: false,
+                    archive: false,
+                    modified: None,
+                    link: None,
                 },
                 File {
                     name: "file2.txt".to_string(),
                     size: 100,
                     path: "file2.txt".to_string(),
+                    hash: vec![],
+                    handle: handle(),
+                    stream: false,
+                    archive: false,
+                    modified: None,
+                    link: None,
                 },
             ],
-            task: None,
+            options: SenderOptions::default(),
+            peers: vec![Peer::new(&Secret::Key(vec![]), 1)],
+            completed: 0,
+            wrong_codes: 0,
+            started: None,
+            sent: 0,
         };
         assert_eq!(
             on_create_room(
@@ -800,57 +3009,832 @@ mod tests {
     //     assert_eq!(on_join_room(&context, None), Status::Continue());
     // }
     #[test]
+    fn test_on_resume_invalid_offset() {
+        let (sender, _) = flume::bounded(1000);
+        let mut context = Context {
+            secret: Secret::Key(vec![]),
+            sender,
+            files: vec![File {
+                name: "file1.txt".to_string(),
+                size: 100,
+                path: "file1.txt".to_string(),
+                hash: vec![],
+                handle: handle(),
+                stream: false,
+                archive: false,
+                modified: None,
+                link: None,
+            }],
+            options: SenderOptions::default(),
+            peers: vec![Peer {
+                shared_key: Some(Aes128Gcm::new(Key::<Aes128Gcm>::from_slice(&[0u8; 16])).into()),
+                ..Peer::new(&Secret::Key(vec![]), 1)
+            }],
+            completed: 0,
+            wrong_codes: 0,
+            started: None,
+            sent: 0,
+        };
+        assert_eq!(
+            on_resume(
+                &mut context,
+                0,
+                ResumePacket {
+                    entries: vec![crate::shared::packets::resume_packet::Entry {
+                        index: 0,
+                        offset: 101,
+                    }],
+                }
+            ),
+            Status::Err("Invalid offset 101 for file 'file1.txt' in resume packet.".into())
+        );
+        assert_eq!(
+            on_resume(
+                &mut context,
+                0,
+                ResumePacket {
+                    entries: vec![crate::shared::packets::resume_packet::Entry {
+                        index: 1,
+                        offset: 0,
+                    }],
+                }
+            ),
+            Status::Err("Invalid index in resume packet.".into())
+        );
+    }
+    #[tokio::test]
+    async fn test_on_credit() {
+        let (sender, _) = flume::bounded(1000);
+        let mut context = Context {
+            secret: Secret::Key(vec![]),
+            sender,
+            files: vec![],
+            options: SenderOptions::default(),
+            peers: vec![Peer {
+                shared_key: Some(Aes128Gcm::new(Key::<Aes128Gcm>::from_slice(&[0u8; 16])).into()),
+                ..Peer::new(&Secret::Key(vec![]), 1)
+            }],
+            completed: 0,
+            wrong_codes: 0,
+            started: None,
+            sent: 0,
+        };
+        assert_eq!(
+            on_credit(&mut context, 0, CreditPacket { chunks: 4 }),
+            Status::Err("Invalid credit packet: transfer has not been started".into())
+        );
+
+        context.peers[0].task = Some(tokio::spawn(async {}));
+        assert_eq!(
+            on_credit(&mut context, 0, CreditPacket { chunks: 4 }),
+            Status::Continue()
+        );
+        assert_eq!(context.peers[0].credits.available_permits(), 4);
+
+        // A receiver that grants too many credits cannot crash the sender
+        for _ in 0..2 {
+            assert_eq!(
+                on_credit(&mut context, 0, CreditPacket { chunks: u32::MAX }),
+                Status::Continue()
+            );
+        }
+    }
+    #[tokio::test]
+    async fn test_on_retry() {
+        let (sender, _) = flume::bounded(1000);
+        let (retries, retry_requests) = flume::unbounded();
+        let file = |name: &str| File {
+            name: name.to_string(),
+            size: 100,
+            path: name.to_string(),
+            hash: vec![],
+            handle: handle(),
+            stream: false,
+            archive: false,
+            modified: None,
+            link: None,
+        };
+        let mut context = Context {
+            secret: Secret::Key(vec![]),
+            sender,
+            files: vec![file("file1.txt"), file("file2.txt")],
+            options: SenderOptions::default(),
+            peers: vec![Peer {
+                shared_key: Some(Aes128Gcm::new(Key::<Aes128Gcm>::from_slice(&[0u8; 16])).into()),
+                task: Some(tokio::spawn(async {})),
+                offsets: vec![0, 0],
+                retries,
+                ..Peer::new(&Secret::Key(vec![]), 1)
+            }],
+            completed: 0,
+            wrong_codes: 0,
+            started: None,
+            sent: 0,
+        };
+        let complete = |index| ProgressPacket {
+            index,
+            progress: 100,
+        };
+
+        assert_eq!(on_progress(&mut context, 0, complete(0)), Status::Continue());
+        assert_eq!(
+            on_retry(&mut context, 0, RetryPacket { index: 0 }),
+            Status::Continue()
+        );
+        assert_eq!(retry_requests.try_recv(), Ok(0));
+
+        // The transfer is only done once the file requested again is complete
+        assert_eq!(on_progress(&mut context, 0, complete(1)), Status::Continue());
+        assert_eq!(on_progress(&mut context, 0, complete(0)), Status::Exit());
+
+        assert_eq!(
+            on_retry(&mut context, 0, RetryPacket { index: 2 }),
+            Status::Err("Invalid index in retry packet.".into())
+        );
+    }
+    #[tokio::test]
+    async fn test_send_file_waits_for_room() {
+        let (sender, outgoing) = flume::bounded(1);
+        let shared_key = Some(Aes128Gcm::new(Key::<Aes128Gcm>::from_slice(&[0u8; 16])).into());
+        let file = File {
+            name: "Cargo.toml".to_string(),
+            size: 12,
+            path: "Cargo.toml".to_string(),
+            hash: vec![],
+            handle: handle(),
+            stream: false,
+            archive: false,
+            modified: None,
+            link: None,
+        };
+        let destination = AtomicU8::new(1);
+        let credits = Semaphore::new(10);
+        let chunk_size = AtomicUsize::new(4);
+        let send = || {
+            send_file(
+                &sender,
+                &shared_key,
+                &destination,
+                0,
+                &file,
+                0,
+                Numbering::File(0),
+                Compression::None,
+                &credits,
+                &chunk_size,
+            )
+        };
+
+        // The first chunk fills the queue, and the next one waits until it is taken
+        let sending = send();
+        pin_mut!(sending);
+        let waited = tokio::time::timeout(Duration::from_millis(100), &mut sending).await;
+        assert!(waited.is_err());
+        assert_eq!(outgoing.len(), 1);
+
+        let taken = async {
+            for _ in 0..3 {
+                outgoing.recv_async().await.unwrap();
+            }
+        };
+        assert!(tokio::join!(sending, taken).0);
+
+        // Once the session has ended, the file is not sent any further
+        drop(outgoing);
+        assert!(!send().await);
+    }
+    #[cfg(feature = "mmap")]
+    #[tokio::test]
+    async fn test_send_mapped_file() {
+        let path = "mapped.bin";
+        fs::write(path, b"hello from the map").unwrap();
+        let (sender, outgoing) = flume::unbounded();
+        let key = || Some(Aes128Gcm::new(Key::<Aes128Gcm>::from_slice(&[0u8; 16])).into());
+        let file = File {
+            name: path.to_string(),
+            size: 18,
+            path: path.to_string(),
+            hash: vec![],
+            handle: Arc::new(fs::File::open(path).unwrap()),
+            stream: false,
+            archive: false,
+            modified: None,
+            link: None,
+        };
+        let destination = AtomicU8::new(1);
+        let credits = Semaphore::new(100);
+        let chunk_size = AtomicUsize::new(4);
+
+        // The file is sent in chunks straight from the map, from the offset on
+        let map = map_file(&file.handle, file.size).unwrap();
+        let sent = send_mapped_chunks(
+            &sender,
+            &key(),
+            &destination,
+            0,
+            &file,
+            &map[6..],
+            Numbering::File(0),
+            Compression::None,
+            &credits,
+            &chunk_size,
+        );
+        assert!(sent.await);
+        let mut received = vec![];
+        for mut data in outgoing.drain().map(WebSocketMessage::into_data) {
+            data[0] = 1;
+            let Ok(Value::Chunk(chunk)) = decode_packet(&key(), 1, None, data) else {
+                panic!("the sender did not send a chunk");
+            };
+            received.extend_from_slice(&chunk.chunk);
+        }
+        assert_eq!(received, b"from the map");
+
+        // A file that became shorter is neither mapped nor read from an earlier map,
+        // which would end the process
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_len(4)
+            .unwrap();
+        assert!(map_file(&file.handle, file.size).is_err());
+        let sent = send_mapped_chunks(
+            &sender,
+            &key(),
+            &destination,
+            0,
+            &file,
+            &map[6..],
+            Numbering::File(0),
+            Compression::None,
+            &credits,
+            &chunk_size,
+        );
+        assert!(!sent.await);
+        assert!(outgoing.is_empty());
+
+        drop(map);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_session_for_options() {
+        let options = SenderOptions {
+            pin: Some("482913".into()),
+            ..Default::default()
+        };
+        assert_eq!(Session::for_options(None, &options).pin(), Some("482913"));
+
+        // A key takes the place of the PIN
+        let options = SenderOptions {
+            key: Some(vec![7; 32]),
+            ..options
+        };
+        assert_eq!(Session::for_options(None, &options).pin(), None);
+
+        let pin = Session::for_options(None, &SenderOptions::default());
+        assert_eq!(pin.pin().map(str::len), Some(crate::pake::PIN_DIGITS));
+    }
+
+    #[test]
+    fn test_compress_chunk() {
+        let chunk = b"caesar caesar caesar caesar".to_vec();
+
+        assert_eq!(compress_chunk(&chunk, Compression::None).unwrap(), chunk);
+
+        let compressed = compress_chunk(&chunk, Compression::Zstd).unwrap();
+        assert_eq!(zstd::stream::decode_all(&*compressed).unwrap(), chunk);
+    }
+    #[test]
+    fn test_tar_stream() {
+        fs::create_dir_all("tar_test/nested").unwrap();
+        fs::write("tar_test/nested/hello.txt", b"hello").unwrap();
+
+        let mut stream = tar_stream("tar_test", Walk::new(&SenderOptions::default())).unwrap();
+        let mut packed = vec![];
+        stream.read_to_end(&mut packed).unwrap();
+        fs::remove_dir_all("tar_test").unwrap();
+
+        let mut archive = tar::Archive::new(packed.as_slice());
+        let paths: Vec<String> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+            .collect();
+        assert!(paths.contains(&"nested/hello.txt".to_string()));
+    }
+
+    #[test]
+    fn test_zip_stream() {
+        fs::create_dir_all("zip_test/photos/2024").unwrap();
+        fs::write("zip_test/photos/2024/beach.jpg", b"beach").unwrap();
+        fs::write("zip_test/notes.txt", b"notes").unwrap();
+
+        let paths = vec![
+            "zip_test/photos".to_string(),
+            "zip_test/notes.txt".to_string(),
+        ];
+        let mut stream = zip_stream(&paths, Walk::new(&SenderOptions::default())).unwrap();
+        let mut packed = vec![];
+        stream.read_to_end(&mut packed).unwrap();
+        fs::remove_dir_all("zip_test").unwrap();
+
+        let mut archive = zip::ZipArchive::new(io::Cursor::new(packed)).unwrap();
+        let names: Vec<&str> = archive.file_names().collect();
+        assert!(names.contains(&"photos/2024/beach.jpg"));
+        assert!(names.contains(&"notes.txt"));
+        let mut content = String::new();
+        archive
+            .by_name("photos/2024/beach.jpg")
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "beach");
+    }
+
+    #[test]
+    fn test_zip_stream_filter() {
+        fs::create_dir_all("filter_test/project/src").unwrap();
+        fs::create_dir_all("filter_test/project/target/debug").unwrap();
+        fs::write("filter_test/project/src/main.rs", b"fn main() {}").unwrap();
+        fs::write("filter_test/project/src/build.log", b"log").unwrap();
+        fs::write("filter_test/project/target/debug/caesar", b"binary").unwrap();
+
+        let options = SenderOptions {
+            filter: PathFilter::new(&[], &["target/**".into(), "*.log".into()]).unwrap(),
+            ..Default::default()
+        };
+        let paths = vec!["filter_test/project".to_string()];
+        let mut stream = zip_stream(&paths, Walk::new(&options)).unwrap();
+        let mut packed = vec![];
+        stream.read_to_end(&mut packed).unwrap();
+        fs::remove_dir_all("filter_test").unwrap();
+
+        // The patterns are matched inside the directory that is packed
+        let archive = zip::ZipArchive::new(io::Cursor::new(packed)).unwrap();
+        let names: Vec<&str> = archive.file_names().collect();
+        assert!(names.contains(&"project/src/main.rs"));
+        assert!(!names.contains(&"project/src/build.log"));
+        assert!(!names.contains(&"project/target/debug/caesar"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_policies() {
+        fs::create_dir_all("symlink_test").unwrap();
+        fs::write("symlink_test/hello.txt", b"hello").unwrap();
+        std::os::unix::fs::symlink("hello.txt", "symlink_test/link.txt").unwrap();
+
+        let pack = |symlinks| {
+            let mut packed = vec![];
+            let options = SenderOptions {
+                symlinks,
+                ..Default::default()
+            };
+            tar_stream("symlink_test", Walk::new(&options))
+                .unwrap()
+                .read_to_end(&mut packed)
+                .unwrap();
+            let mut archive = tar::Archive::new(io::Cursor::new(packed));
+            let entries: Vec<(String, tar::EntryType, u64)> = archive
+                .entries()
+                .unwrap()
+                .map(|entry| {
+                    let entry = entry.unwrap();
+                    let path = entry.path().unwrap().display().to_string();
+                    (path, entry.header().entry_type(), entry.size())
+                })
+                .collect();
+            entries
+        };
+        let link = |entries: Vec<(String, tar::EntryType, u64)>| {
+            entries
+                .into_iter()
+                .find(|(path, _, _)| path == "link.txt")
+                .map(|(_, kind, size)| (kind, size))
+        };
+
+        // Links inside a directory are kept by default, or left out or followed
+        assert_eq!(link(pack(None)), Some((tar::EntryType::Symlink, 0)));
+        assert_eq!(
+            link(pack(Some(SymlinkPolicy::Preserve))),
+            Some((tar::EntryType::Symlink, 0))
+        );
+        assert_eq!(link(pack(Some(SymlinkPolicy::Skip))), None);
+        assert_eq!(
+            link(pack(Some(SymlinkPolicy::Follow))),
+            Some((tar::EntryType::Regular, 5))
+        );
+
+        // A link back into the tree is packed as a link, but cannot be followed
+        std::os::unix::fs::symlink(".", "symlink_test/loop").unwrap();
+        assert_eq!(link(pack(None)), Some((tar::EntryType::Symlink, 0)));
+        let mut packed = vec![];
+        let options = SenderOptions {
+            symlinks: Some(SymlinkPolicy::Follow),
+            ..Default::default()
+        };
+        tar_stream("symlink_test", Walk::new(&options))
+            .unwrap()
+            .read_to_end(&mut packed)
+            .unwrap();
+        let mut archive = tar::Archive::new(packed.as_slice());
+        assert!(archive.entries().unwrap().any(|entry| entry.is_err()));
+
+        fs::remove_dir_all("symlink_test").unwrap();
+    }
+
+    #[test]
+    fn test_read_at() {
+        fs::write("read_at_test.txt", b"caesar transfer").unwrap();
+        let handle = fs::File::open("read_at_test.txt").unwrap();
+
+        // Reads at a position leave the cursor alone, so they can run in any order
+        let mut chunk = [0u8; 8];
+        read_at(&handle, &mut chunk, 7).unwrap();
+        assert_eq!(&chunk, b"transfer");
+        let mut chunk = [0u8; 6];
+        read_at(&handle, &mut chunk, 0).unwrap();
+        assert_eq!(&chunk, b"caesar");
+
+        // A file that became shorter fails the read
+        let mut chunk = [0u8; 8];
+        assert!(read_at(&handle, &mut chunk, 10).is_err());
+
+        drop(handle);
+        fs::remove_file("read_at_test.txt").unwrap();
+    }
+    #[test]
     fn test_on_error() {
         assert_eq!(
             on_error("Error message".to_string()),
-            Status::Err("Error message".to_string())
+            Status::Err("Error message".into())
         );
     }
     #[test]
     fn test_on_leave_room() {
         let (sender, _) = flume::bounded(1000);
         let mut context = Context {
-            hmac: vec![],
+            secret: Secret::Key(vec![]),
             sender,
-            key: EphemeralSecret::random(&mut OsRng),
-            shared_key: None,
             files: vec![
                 File {
                     name: "file1.txt".to_string(),
                     size: 100,
                     path: "file1.txt".to_string(),
+                    hash: vec![],
+                    handle: handle(),
+                    stream: false,
+                    archive: false,
+                    modified: None,
+                    link: None,
                 },
                 File {
                     name: "file2.txt".to_string(),
                     size: 100,
                     path: "file2.txt".to_string(),
+                    hash: vec![],
+                    handle: handle(),
+                    stream: false,
+                    archive: false,
+                    modified: None,
+                    link: None,
                 },
             ],
-            task: None,
+            options: SenderOptions::default(),
+            peers: vec![Peer::new(&Secret::Key(vec![]), 1)],
+            completed: 0,
+            wrong_codes: 0,
+            started: None,
+            sent: 0,
         };
         assert_eq!(on_leave_room(&mut context, 5), Status::Continue());
     }
     #[test]
+    fn test_join_room_position() {
+        let (sender, outgoing) = flume::bounded(10);
+        let mut context = Context {
+            secret: Secret::Key(vec![]),
+            sender,
+            files: vec![],
+            options: SenderOptions {
+                receivers: 3,
+                ..Default::default()
+            },
+            peers: vec![],
+            completed: 0,
+            wrong_codes: 0,
+            started: None,
+            sent: 0,
+        };
+
+        // The handshake goes to the position the relay tells
+        assert_eq!(
+            on_join_room(&mut context, None, Some(1)),
+            Status::Continue()
+        );
+        assert_eq!(outgoing.recv().unwrap().into_data()[0], 1);
+
+        // Older relays leave it out, the receiver is behind all others then
+        assert_eq!(on_join_room(&mut context, None, None), Status::Continue());
+        assert_eq!(outgoing.recv().unwrap().into_data()[0], 2);
+        assert_eq!(context.peers.len(), 2);
+
+        // A position the sender does not expect would mix up the receivers
+        assert!(matches!(
+            on_join_room(&mut context, None, Some(4)),
+            Status::Err(_)
+        ));
+        assert_eq!(context.peers.len(), 2);
+    }
+
+    #[test]
+    fn test_handshake_covers_offer() {
+        let (sender, outgoing) = flume::bounded(100);
+        let secret = Secret::Key(b"mailbox key".to_vec());
+        let mut context = Context {
+            secret: secret.clone(),
+            sender,
+            files: vec![],
+            options: SenderOptions {
+                receivers: 5,
+                ..Default::default()
+            },
+            peers: vec![],
+            completed: 0,
+            wrong_codes: 0,
+            started: None,
+            sent: 0,
+        };
+        let join = |context: &mut Context| {
+            assert_eq!(on_join_room(context, None, None), Status::Continue());
+            let data = outgoing.recv().unwrap().into_data();
+            let Ok(Value::Handshake(offer)) = decode_packet(&None, 0, None, data) else {
+                panic!("the sender did not send a handshake");
+            };
+            offer
+        };
+
+        // Answers an offer like a receiver does, with the strongest choices it holds
+        // unless `choose` changes them
+        let answer = |offer: &HandshakePacket, choose: fn(&mut HandshakeResponsePacket)| {
+            let pake = Spake2::new(Role::Receiver, secret.password());
+            let keys = pake.finish(&offer.public_key).unwrap();
+            let strongest = |offered: &[i32], choice: i32| match offered.contains(&choice) {
+                true => choice,
+                false => 0,
+            };
+            let mut answer = HandshakeResponsePacket {
+                public_key: pake.message().to_vec(),
+                framing: strongest(&offer.framings, Framing::V3.into()),
+                key_schedule: strongest(&offer.key_schedules, KeySchedule::Hkdf.into()),
+                nonce_scheme: strongest(&offer.nonce_schemes, NonceScheme::Counter.into()),
+                cipher: choose_cipher(offer.ciphers(), true).into(),
+                streams: 1,
+                ..Default::default()
+            };
+            choose(&mut answer);
+            answer.signature = keys.confirmation(Role::Receiver, &negotiation(offer, &answer));
+            answer
+        };
+
+        // A relay that strips the counter nonces from the offer is noticed, although
+        // the receiver knows the secret
+        let mut offer = join(&mut context);
+        offer.nonce_schemes.clear();
+        assert_eq!(on_handshake(&mut context, 0, answer(&offer, |_| {})), Status::Continue());
+        assert_eq!(context.wrong_codes, 1);
+        assert!(context.peers[0].shared_key.is_none());
+        assert!(context.peers[0].done);
+        let _ = outgoing.recv().unwrap();
+
+        // So is one that strips framing v3, which would leave the position of the chunks
+        // and the ID of the transfer unauthenticated
+        let mut offer = join(&mut context);
+        offer.framings.retain(|&framing| framing != i32::from(Framing::V3));
+        let downgraded = answer(&offer, |_| {});
+        assert_eq!(downgraded.framing(), Framing::V1);
+        assert_eq!(on_handshake(&mut context, 1, downgraded), Status::Continue());
+        assert_eq!(context.wrong_codes, 2);
+        let _ = outgoing.recv().unwrap();
+
+        // And one that changes the choices of the receiver
+        let offer = join(&mut context);
+        let mut downgraded = answer(&offer, |_| {});
+        downgraded.framing = Framing::V2.into();
+        assert_eq!(on_handshake(&mut context, 2, downgraded), Status::Continue());
+        assert_eq!(context.wrong_codes, 3);
+        assert!(context.peers[2].shared_key.is_none());
+        let _ = outgoing.recv().unwrap();
+
+        // The offer as it was sent is accepted
+        let offer = join(&mut context);
+        assert_eq!(on_handshake(&mut context, 3, answer(&offer, |_| {})), Status::Continue());
+        assert_eq!(context.wrong_codes, 3);
+        assert!(context.peers[3].shared_key.is_some());
+
+        // A receiver that chooses one key for both directions is refused, although the
+        // key for each direction was offered
+        let offer = join(&mut context);
+        let shared = answer(&offer, |answer| answer.key_schedule = KeySchedule::Shared.into());
+        assert!(matches!(
+            on_handshake(&mut context, 4, shared),
+            Status::Err(CaesarError::Handshake(_))
+        ));
+        assert!(context.peers[4].shared_key.is_none());
+
+        // A relay that reorders the ciphers to pick the one of the session is noticed
+        let mut offer = join(&mut context);
+        offer.ciphers.reverse();
+        assert_eq!(on_handshake(&mut context, 5, answer(&offer, |_| {})), Status::Continue());
+        assert_eq!(context.wrong_codes, 4);
+        assert!(context.peers[5].shared_key.is_none());
+
+        // So is one that chooses the framing or the nonces of earlier versions
+        let offer = join(&mut context);
+        let legacy = answer(&offer, |answer| answer.framing = Framing::V2.into());
+        assert!(matches!(
+            on_handshake(&mut context, 6, legacy),
+            Status::Err(CaesarError::Handshake(_))
+        ));
+        let offer = join(&mut context);
+        let legacy = answer(&offer, |answer| answer.nonce_scheme = NonceScheme::Random.into());
+        assert!(matches!(
+            on_handshake(&mut context, 7, legacy),
+            Status::Err(CaesarError::Handshake(_))
+        ));
+        assert!(context.peers[7].shared_key.is_none());
+        assert_eq!(context.wrong_codes, 4);
+    }
+
+    #[test]
+    fn test_shared_room() {
+        let (sender, outgoing) = flume::bounded(1000);
+        let secret = Secret::Key(vec![]);
+        let peer = |destination| Peer {
+            shared_key: Some(Aes128Gcm::new(Key::<Aes128Gcm>::from_slice(&[0u8; 16])).into()),
+            offsets: vec![0],
+            ..Peer::new(&secret, destination)
+        };
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let mut context = Context {
+            secret: secret.clone(),
+            sender,
+            files: vec![File {
+                name: "file1.txt".to_string(),
+                size: 100,
+                path: "file1.txt".to_string(),
+                hash: vec![],
+                handle: handle(),
+                stream: false,
+                archive: false,
+                modified: None,
+                link: None,
+            }],
+            options: SenderOptions {
+                receivers: 3,
+                progress: Some(ProgressReport::new({
+                    let reported = reported.clone();
+                    move |progress| reported.lock().unwrap().push(progress.clone())
+                })),
+                ..Default::default()
+            },
+            peers: vec![peer(1), peer(2), peer(3)],
+            completed: 0,
+            wrong_codes: 0,
+            started: None,
+            sent: 0,
+        };
+        let complete = ProgressPacket {
+            index: 0,
+            progress: 100,
+        };
+
+        // The first receiver is told that it is done, the others keep going
+        assert_eq!(on_progress(&mut context, 0, complete.clone()), Status::Continue());
+        assert_eq!(outgoing.try_recv().unwrap().into_data()[0], 1);
+        assert!(context.peers[0].done);
+        // Only the bytes the receiver did not have count towards the summary
+        assert_eq!(context.sent, 100);
+
+        // Unlike the printed lines, the report is told the progress of each receiver
+        assert_eq!(
+            *reported.lock().unwrap(),
+            vec![FileProgress {
+                peer: 0,
+                index: 0,
+                name: "file1.txt".to_string(),
+                size: 100,
+                transferred: Some(100),
+                done: true,
+                remaining: None,
+            }]
+        );
+
+        // The receivers behind one that leaves move up
+        assert_eq!(on_leave_room(&mut context, 1), Status::Continue());
+        assert_eq!(context.peers.len(), 2);
+        assert_eq!(context.peers[0].destination(), 1);
+        assert_eq!(context.peers[1].destination(), 2);
+
+        // A wrong code turns only that receiver away
+        context.peers[0].shared_key = None;
+        assert_eq!(
+            on_handshake(
+                &mut context,
+                0,
+                HandshakeResponsePacket {
+                    public_key: Spake2::new(Role::Receiver, b"guess").message().to_vec(),
+                    signature: vec![0; 32],
+                    ..Default::default()
+                }
+            ),
+            Status::Continue()
+        );
+        assert_eq!(context.wrong_codes, 1);
+        assert!(context.peers[0].done);
+
+        // The transfer ends once every receiver is done
+        assert_eq!(on_progress(&mut context, 1, complete), Status::Exit());
+    }
+    #[test]
+    fn test_text_snippet() {
+        let (sender, outgoing) = flume::bounded(1000);
+        let secret = Secret::Key(vec![]);
+        let key = || Some(Aes128Gcm::new(Key::<Aes128Gcm>::from_slice(&[0u8; 16])).into());
+        let mut context = Context {
+            secret: secret.clone(),
+            sender,
+            files: vec![],
+            options: SenderOptions {
+                text: Some("correct horse battery staple".to_string()),
+                ..Default::default()
+            },
+            peers: vec![Peer {
+                shared_key: key(),
+                ..Peer::new(&secret, 1)
+            }],
+            completed: 0,
+            wrong_codes: 0,
+            started: None,
+            sent: 0,
+        };
+
+        // The text is sent in place of the list
+        assert_eq!(on_handshake_finalize(&mut context, 0), Status::Continue());
+        let mut data = outgoing.try_recv().unwrap().into_data();
+        data[0] = 0;
+        assert_eq!(
+            decode_packet(&key(), 0, None, data).unwrap(),
+            Value::Text(TextPacket {
+                text: "correct horse battery staple".to_string()
+            })
+        );
+
+        // The receiver confirms it with a done packet
+        let (receiver, incoming) = flume::bounded(1000);
+        receiver.send_encrypted_packet(&key(), 0, Value::Done(DonePacket {}));
+        let mut data = incoming.try_recv().unwrap().into_data();
+        data[0] = 1;
+        assert_eq!(on_binary(&mut context, data), Status::Exit());
+    }
+    #[test]
     fn test_on_message() {
         let (sender, _) = flume::bounded(1000);
         let mut context = Context {
-            hmac: vec![],
+            secret: Secret::Key(vec![]),
             sender,
-            key: EphemeralSecret::random(&mut OsRng),
-            shared_key: None,
             files: vec![
                 File {
                     name: "file1.txt".to_string(),
                     size: 100,
                     path: "file1.txt".to_string(),
+                    hash: vec![],
+                    handle: handle(),
+                    stream: false,
+                    archive: false,
+                    modified: None,
+                    link: None,
                 },
                 File {
                     name: "file2.txt".to_string(),
                     size: 100,
                     path: "file2.txt".to_string(),
+                    hash: vec![],
+                    handle: handle(),
+                    stream: false,
+                    archive: false,
+                    modified: None,
+                    link: None,
                 },
             ],
-            task: None,
+            options: SenderOptions::default(),
+            peers: vec![Peer::new(&Secret::Key(vec![]), 1)],
+            completed: 0,
+            wrong_codes: 0,
+            started: None,
+            sent: 0,
         };
         assert_eq!(
             on_message(
@@ -873,7 +3857,7 @@ mod tests {
                 String::from("Test"),
                 true
             ),
-            Status::Err("Error Message: Test".to_string())
+            Status::Err("Error Message: Test".into())
         );
     }
 }
@@ -0,0 +1,117 @@
+//! Choosing which files a sender packs with glob patterns.
+//!
+//! The patterns are matched against the paths the user gave and against every entry
+//! found in the directories that are packed, relative to the directory, like
+//! `target/debug/build.log`. A `*` also matches `/`, so `*.log` leaves out the logs in
+//! every subdirectory.
+
+use std::path::Path;
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+/// The include and exclude patterns of a transfer.
+///
+/// An entry is packed if it matches no exclude pattern and, unless it is a directory,
+/// any of the include patterns. Directories are always walked, so files deep inside
+/// them can be included.
+#[derive(Debug, Clone, Default)]
+pub struct PathFilter {
+    /// The patterns of which a file has to match one, or `None` to pack every file.
+    include: Option<GlobSet>,
+    /// The patterns that leave a file or directory out.
+    exclude: Option<GlobSet>,
+}
+
+impl PathFilter {
+    /// Creates a filter from glob patterns.
+    ///
+    /// # Arguments
+    ///
+    /// * `include` - The patterns of the files to pack. If empty, every file is packed.
+    /// * `exclude` - The patterns of the files and directories to leave out.
+    ///
+    /// # Returns
+    ///
+    /// The filter, or the message to show if a pattern is not valid.
+    pub fn new(include: &[String], exclude: &[String]) -> Result<PathFilter, String> {
+        Ok(PathFilter {
+            include: glob_set(include)?,
+            exclude: glob_set(exclude)?,
+        })
+    }
+
+    /// Returns whether a file or directory is packed.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path as given, or relative to the directory that is packed.
+    /// * `directory` - Whether the path is a directory.
+    pub fn admits(&self, path: &Path, directory: bool) -> bool {
+        // A path given as `./notes.txt` is matched as `notes.txt`
+        let path = path.strip_prefix(".").unwrap_or(path);
+        if self
+            .exclude
+            .as_ref()
+            .is_some_and(|exclude| exclude.is_match(path))
+        {
+            return false;
+        }
+        directory
+            || self
+                .include
+                .as_ref()
+                .is_none_or(|include| include.is_match(path))
+    }
+}
+
+/// Compiles glob patterns into a set.
+///
+/// # Arguments
+///
+/// * `patterns` - The patterns to compile.
+///
+/// # Returns
+///
+/// The set, `None` if there are no patterns, or the message to show if a pattern is
+/// not valid.
+fn glob_set(patterns: &[String]) -> Result<Option<GlobSet>, String> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = Glob::new(pattern)
+            .map_err(|error| format!("The pattern '{}' is not valid: {}", pattern, error))?;
+        builder.add(glob);
+    }
+    builder
+        .build()
+        .map(Some)
+        .map_err(|error| format!("The patterns are not valid: {}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_filter() {
+        let filter = PathFilter::new(&[], &["target/**".into(), "*.log".into()]).unwrap();
+        assert!(filter.admits(Path::new("src/main.rs"), false));
+        assert!(filter.admits(Path::new("target"), true));
+        assert!(!filter.admits(Path::new("target/debug/caesar"), false));
+        assert!(!filter.admits(Path::new("logs/build.log"), false));
+        assert!(!filter.admits(Path::new("./build.log"), false));
+
+        // Directories are walked even if only some files inside them are included
+        let filter = PathFilter::new(&["*.rs".into()], &["tests/**".into()]).unwrap();
+        assert!(filter.admits(Path::new("src"), true));
+        assert!(filter.admits(Path::new("src/main.rs"), false));
+        assert!(!filter.admits(Path::new("README.md"), false));
+        assert!(!filter.admits(Path::new("tests/rooms.rs"), false));
+
+        assert!(PathFilter::default().admits(Path::new("build.log"), false));
+        assert!(PathFilter::new(&["[".into()], &[]).is_err());
+    }
+}
@@ -1,12 +1,18 @@
+use std::time::Duration;
+
 use tracing::{debug, error};
 
 use local_ip_address::{local_ip, local_ipv6};
 use reqwest::blocking::Client;
+use serde_json::Map;
 use tokio::task;
 
-use crate::relay::transfer::{TransferRequest, TransferResponse};
+use crate::error::{CaesarError, Result};
+use crate::relay::routing::{RouteResponse, ROUTE_HEADER};
+use crate::relay::transfer::{TransferRequest, TransferResponse, SCHEMA_VERSION};
 
-type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+/// How long a relay may take to answer before the next one is tried.
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// Asynchronously sends information about the sender to the specified relay.
 ///
@@ -16,6 +22,9 @@ type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>
 /// * `name` - The name of the sender.
 /// * `room_id` - The ID of the room.
 /// * `is_local` - Indicates whether the sender is local.
+/// * `port` - The port of the sender's local server.
+/// * `token` - The access token, if the relay requires authentication.
+/// * `route` - The routing token of the relay instance the room was created on.
 ///
 /// # Returns
 ///
@@ -25,6 +34,9 @@ pub async fn send_info(
     name: &str,
     room_id: &str,
     is_local: bool,
+    port: u16,
+    token: Option<String>,
+    route: Option<String>,
 ) -> Result<TransferResponse> {
     // Build the URL for the request
     let url = relay.to_string();
@@ -37,7 +49,10 @@ pub async fn send_info(
             Err(e) => {
                 // Log the error and return the error
                 error!("Error getting local ip: {e:?}");
-                return Err(Box::new(e));
+                return Err(CaesarError::Connection(format!(
+                    "Failed to determine the local IP address: {}",
+                    e
+                )));
             }
         },
     };
@@ -61,23 +76,111 @@ pub async fn send_info(
         } else {
             String::from("")
         },
+        // Set the port the local server listens on
+        port,
+        schema_version: SCHEMA_VERSION,
+        extra: Map::new(),
     };
 
     // Log the start of the request
     debug!("Trying to send Request.");
     
     // Send the request and parse the response
+    let relay = url.clone();
     let result: Result<TransferResponse> = task::spawn_blocking(move || {
+        let unreachable = |e: reqwest::Error| CaesarError::RelayUnreachable {
+            relay: url.clone(),
+            reason: e.to_string(),
+        };
+
         let client = Client::new();
-        let response = client
+        let mut request = client
             .put(format!("{}/upload", url))
-            .json(&transfer_request)
-            .send()?
-            .json()?;
+            .json(&transfer_request);
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        if let Some(route) = route {
+            request = request.header(ROUTE_HEADER, route);
+        }
+        let response = request.send().map_err(unreachable)?;
+
+        // The relay refuses transfers without a valid token or beyond the quota
+        if let Some(error) = CaesarError::refused(response.status()) {
+            return Err(error);
+        }
+
+        let response = response
+            .json()
+            .map_err(|e| CaesarError::Protocol(format!("Failed to parse JSON response: {}", e)))?;
         Ok(response)
     })
-    .await?;
+    .await
+    .map_err(|e| CaesarError::RelayUnreachable {
+        relay,
+        reason: e.to_string(),
+    })?;
 
     // Return the result
     result
 }
+
+/// Asks the relay for the routing token of the instance that answers.
+///
+/// # Arguments
+///
+/// * `relay` - The URL of the relay.
+/// * `token` - The access token, if the relay requires authentication.
+///
+/// # Returns
+///
+/// The routing token, or `None` if the relay runs as a single instance.
+pub async fn fetch_route(relay: &str, token: Option<&str>) -> Option<String> {
+    let mut request = reqwest::Client::new().get(format!("{}/route", relay));
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+
+    // Relays without an instance ID do not know the route
+    let response = match request.send().await {
+        Ok(response) if response.status().is_success() => response,
+        Ok(response) => {
+            debug!("The relay issues no routing token: {}", response.status());
+            return None;
+        }
+        Err(e) => {
+            debug!("Failed to ask for a routing token: {e}");
+            return None;
+        }
+    };
+
+    response
+        .json::<RouteResponse>()
+        .await
+        .map(|response| response.route)
+        .ok()
+}
+
+/// Checks whether the relay answers at all.
+///
+/// Any answer counts, even a refusal, as only relays that are down are skipped.
+///
+/// # Arguments
+///
+/// * `relay` - The URL of the relay.
+///
+/// # Returns
+///
+/// Whether the relay answered within `PROBE_TIMEOUT`.
+pub async fn is_reachable(relay: &str) -> bool {
+    let request = reqwest::Client::new()
+        .get(format!("{}/route", relay))
+        .timeout(PROBE_TIMEOUT);
+    match request.send().await {
+        Ok(_) => true,
+        Err(e) => {
+            debug!("The relay {relay} is unreachable: {e}");
+            false
+        }
+    }
+}
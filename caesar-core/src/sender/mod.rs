@@ -1,103 +1,605 @@
+/// Prints a message for the user, on stderr if the events of the transfer are reported
+/// on stdout.
+///
+/// # Arguments
+///
+/// * `options` - The `SenderOptions` of the transfer.
+/// * The rest are the arguments of `println!`.
+macro_rules! notify {
+    ($options:expr) => {
+        if $options.events.is_some() {
+            eprintln!()
+        } else {
+            println!()
+        }
+    };
+    ($options:expr, $($arg:tt)*) => {
+        if $options.events.is_some() {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
+pub mod chunk_size;
 pub mod client;
+pub mod filter;
 pub mod http_client;
+pub mod usage;
 pub mod util;
 
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    fs, io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    sync::Arc,
+};
+
+use clap::ValueEnum;
 
 use crate::{
-    relay::{appstate::AppState, server::ws_handler},
-    sender::client as sender,
+    error::{CaesarError, Result},
+    event::{EventReport, TransferEvent},
+    format::NumberFormat,
+    idle::IdleTimeout,
+    net,
+    progress::ProgressReport,
+    relay::{routing::ROUTE_HEADER, Priority},
+    sender::{
+        client::{self as sender, Session},
+        filter::PathFilter,
+        usage::PathUsage,
+    },
+    summary::TransferSummary,
+    suspend,
 };
-use axum::{routing::get, Router};
 use tokio::{net::TcpListener, sync::mpsc, task};
+use tokio_util::sync::CancellationToken;
 use tokio_tungstenite::{
     connect_async,
     tungstenite::{client::IntoClientRequest, http::HeaderValue},
 };
-use tower_http::trace::{DefaultMakeSpan, TraceLayer};
-use tracing::{debug, error, info};
+use tracing::{debug, error, warn};
 use uuid::Uuid;
 
+/// The longest text that is sent as a snippet, in bytes.
+///
+/// The text is sent in a single packet, so longer texts are better sent as a file.
+pub const MAX_TEXT_SIZE: usize = 64 * 1024;
+
+/// The port of the sender's local WebSocket server.
+pub const LOCAL_PORT: u16 = 9000;
+
+/// The number of ports the local server tries, starting at the preferred one, before it
+/// gives up.
+pub const LOCAL_PORT_ATTEMPTS: u16 = 10;
+
+/// Options controlling how the sender transfers files.
+#[derive(Debug, Clone, Default)]
+pub struct SenderOptions {
+    /// Offer zstd compression of the chunks to the receiver.
+    pub compress: bool,
+    /// Allow sending FIFOs, sockets and devices.
+    pub allow_special: bool,
+    /// Cancels the transfer when triggered. The sender leaves the room, which tells the
+    /// receiver to stop.
+    pub cancel: CancellationToken,
+    /// How long the transfer may stall before the session is closed. The receiver keeps
+    /// its partial files, so sending again resumes the transfer.
+    pub idle: IdleTimeout,
+    /// How often the session is re-established after its connection broke down, for
+    /// example because the system was suspended. Zero turns the recovery and the suspend
+    /// detection off.
+    pub reconnect: u32,
+    /// The access token sent to a relay that requires authentication. It is never
+    /// sent to the local server.
+    pub token: Option<String>,
+    /// The routing token presented to the relay, so a load balancer sends every request
+    /// of the transfer to the same relay instance.
+    ///
+    /// If `None`, the sender asks the relay for one and appends it to the transfer name.
+    pub route: Option<String>,
+    /// Further relays that are tried in order when the relay does not answer. The
+    /// transfer is published on the first one that does, so receivers with the same
+    /// list find it there.
+    pub fallback_relays: Vec<String>,
+    /// The key shared with the receiver beforehand, e.g. for a mailbox that cron jobs
+    /// send to every night. It authenticates the handshake in place of the PIN and is
+    /// never sent to the relay. The room ID is then derived from the transfer name, so a
+    /// record that an earlier transfer to the same mailbox left on the relay stays valid.
+    ///
+    /// If `None`, a random PIN is created and shown as part of the transfer code.
+    pub key: Option<Vec<u8>>,
+    /// The PIN shown as part of the transfer code, e.g. because a frontend showed the
+    /// code before the transfer started. It must have `PIN_DIGITS` digits, and is
+    /// ignored if there is a key.
+    ///
+    /// If `None`, a random PIN is created.
+    pub pin: Option<String>,
+    /// How urgent the transfer is. It is passed to the relay as a hint for which rooms
+    /// to serve first when it is under load.
+    pub priority: Priority,
+    /// The number of receivers the files are sent to with the same transfer code, e.g.
+    /// everyone in a class. Zero and one both stand for a single receiver.
+    ///
+    /// Every receiver runs a handshake of its own, and the transfer is complete once
+    /// each of them has every file. Rooms for several receivers need a relay of this
+    /// version.
+    pub receivers: usize,
+    /// A short text sent in place of files, e.g. a password, a URL or a note. The
+    /// receiver shows it instead of saving a file.
+    ///
+    /// If `Some`, the files are ignored. The text may have at most `MAX_TEXT_SIZE` bytes.
+    pub text: Option<String>,
+    /// Announce the transfer on the local network over mDNS, so a receiver in discovery
+    /// mode finds the local server with the PIN alone. Needs the `mdns` feature.
+    pub announce: bool,
+    /// Skip the relay entirely and serve the transfer from the local server alone. The
+    /// transfer is always announced, and the receiver has to find it over mDNS. Needs
+    /// the `mdns` feature.
+    pub local_only: bool,
+    /// Offer receivers behind NAT a direct connection to the local server through hole
+    /// punching, so the relay only carries the signaling.
+    pub punch: bool,
+    /// Accept receivers on the local network over QUIC as well, next to the WebSocket
+    /// connection. Needs the `quic` feature.
+    pub quic: bool,
+    /// The port the local server listens on. If it is busy, the next ports are tried,
+    /// and the one that is bound is told to the relay.
+    ///
+    /// If `None`, `LOCAL_PORT` is tried first.
+    pub local_port: Option<u16>,
+    /// Send directories as tar streams, which are packed while they are sent and unpacked
+    /// by the receiver as they arrive. This saves the overhead of every single file for
+    /// trees of many small files.
+    ///
+    /// Like stdin, a tar stream reaches a single receiver and is not resumed after a
+    /// reconnect.
+    pub tar: bool,
+    /// Pack every path into a single archive of this format while it is sent, so the
+    /// receiver gets one file instead of the files and directories themselves.
+    ///
+    /// Like a tar stream, the archive reaches a single receiver and is not resumed after a
+    /// reconnect.
+    pub archive: Option<ArchiveFormat>,
+    /// What is done with symbolic links, both the ones given as paths and the ones found
+    /// in the directories that are packed.
+    ///
+    /// If `None`, a link given as a path is sent as the file it points to, and links
+    /// inside directories are packed as links.
+    pub symlinks: Option<SymlinkPolicy>,
+    /// Which of the given paths and of the entries of the packed directories are sent,
+    /// e.g. to leave build output and logs out of a project.
+    pub filter: PathFilter,
+    /// Counts the bytes sent over the local server and over the relay. Clones share the
+    /// counters, so the split can be read once the transfer has ended.
+    pub usage: PathUsage,
+    /// Told the progress of every file the receivers confirm, e.g. to draw progress bars.
+    /// Unlike the printed lines, it is also told the progress of several receivers.
+    ///
+    /// If `None`, the progress of a single receiver is printed as a line per file.
+    pub progress: Option<ProgressReport>,
+    /// Told what happens in the transfer, e.g. to print it as JSON. The messages for the
+    /// user then go to stderr.
+    ///
+    /// If `None`, only the messages are printed.
+    pub events: Option<EventReport>,
+}
+
+/// The format of an archive that packs all paths of a transfer into a single file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ArchiveFormat {
+    /// A zip archive, which opens on every system without extra tools. The files are
+    /// stored as they are, so compress the transfer to save bandwidth.
+    Zip,
+}
+
+/// What the sender does with a symbolic link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Send the file or directory the link points to under the name of the link. A link
+    /// to a directory that contains it is an error.
+    Follow,
+    /// Leave the link out.
+    Skip,
+    /// Send the link itself, which the receiver recreates on Unix. Links given as paths
+    /// need a receiver of this version.
+    Preserve,
+}
+
 /// Start the sender process.
 ///
 /// This function starts the sender process which connects to a relay and
 /// initiates file transfers. The sender also starts a local WebSocket server.
 ///
+/// Every path is checked before a room is created. If any path cannot be sent, all
+/// problems are reported together and no room is created.
+///
 /// # Arguments
 ///
 /// * `name` - The name of the sender.
 /// * `relay` - The relay to connect to.
 /// * `files` - The files to transfer.
-pub async fn start_sender(name: String, relay: Arc<String>, files: Arc<Vec<String>>) {
+/// * `options` - The options of the transfer.
+///
+/// # Returns
+///
+/// The summary of the transfer once the receiver has every file, or the error that
+/// ended it.
+pub async fn start_sender(
+    name: String,
+    relay: Arc<String>,
+    files: Arc<Vec<String>>,
+    mut options: SenderOptions,
+) -> Result<TransferSummary> {
     // Log the name of the sender
     debug!("Got name: {:?}", name);
+    // Check all paths or the text before anything is announced to the relay
+    let files = match &options.text {
+        Some(text) if text.len() > MAX_TEXT_SIZE => {
+            let error = CaesarError::TextTooLong(text.len());
+            notify!(options, "Error: {}", error);
+            return Err(error);
+        }
+        Some(_) => Arc::new(vec![]),
+        None => files,
+    };
+    let pack = options.tar || options.archive.is_some();
+    // Links that are left out are not checked, and links that are sent as links need not
+    // point anywhere
+    let is_link = |path: &String| fs::symlink_metadata(path).is_ok_and(|meta| meta.is_symlink());
+    let preserved =
+        |path: &String| options.symlinks == Some(SymlinkPolicy::Preserve) && is_link(path);
+    let (skipped, files): (Vec<String>, Vec<String>) = files
+        .iter()
+        .cloned()
+        .partition(|path| options.symlinks == Some(SymlinkPolicy::Skip) && is_link(path));
+    // The patterns apply to the given paths like to the entries of the directories
+    let (excluded, files): (Vec<String>, Vec<String>) = files.into_iter().partition(|path| {
+        let path = Path::new(path);
+        path != Path::new(util::STDIN_PATH) && !options.filter.admits(path, path.is_dir())
+    });
+    let files = Arc::new(files);
+    let checked: Vec<String> = files
+        .iter()
+        .filter(|path| !preserved(path))
+        .cloned()
+        .collect();
+    let mut problems = util::check_paths(&checked, options.allow_special, pack);
+    if files.is_empty() {
+        problems.extend(skipped.iter().map(|path| util::PathProblem {
+            path: path.to_string(),
+            reason: "is a link and every path was skipped".to_string(),
+            fix: "Pass the file it points to instead, or follow or preserve the links.".to_string(),
+        }));
+        problems.extend(excluded.iter().map(|path| util::PathProblem {
+            path: path.to_string(),
+            reason: "is left out by the patterns and no path is left".to_string(),
+            fix: "Change the include and exclude patterns.".to_string(),
+        }));
+    }
+    for path in &skipped {
+        notify!(options, "Skipping '{}' because it is a link.", path);
+    }
+    if options.archive.is_some() {
+        problems.extend(util::check_archive(&files));
+    }
+    // Stdin and archives are read as they are sent, so they reach a single receiver at
+    // most and cannot be read again after a reconnect
+    let stdin = files.iter().any(|path| path == util::STDIN_PATH);
+    let archives: Vec<&String> = files
+        .iter()
+        .filter(|path| options.tar && Path::new(path).is_dir() && !preserved(path))
+        .collect();
+    if stdin && options.receivers > 1 {
+        problems.push(util::PathProblem {
+            path: util::STDIN_PATH.to_string(),
+            reason: "cannot be sent to several receivers".to_string(),
+            fix: "Save the input to a file and send the file instead.".to_string(),
+        });
+    }
+    if options.receivers > 1 {
+        problems.extend(archives.iter().map(|path| util::PathProblem {
+            path: path.to_string(),
+            reason: "cannot be streamed to several receivers".to_string(),
+            fix: "Pack the directory into an archive and send the archive instead.".to_string(),
+        }));
+    }
+    if options.archive.is_some() && options.receivers > 1 {
+        problems.push(util::PathProblem {
+            path: files.join(", "),
+            reason: "cannot be packed into an archive for several receivers".to_string(),
+            fix: "Pack the files into an archive first and send the archive instead.".to_string(),
+        });
+    }
+    if stdin || !archives.is_empty() || options.archive.is_some() {
+        options.reconnect = 0;
+    }
+    if !problems.is_empty() {
+        let error = CaesarError::InvalidPaths(problems);
+        notify!(options, "Error: {}", error);
+        return Err(error);
+    }
+    // A local transfer can only be found through its announcement
+    #[cfg(not(all(feature = "mdns", feature = "relay")))]
+    if options.local_only {
+        let error = CaesarError::Discovery(
+            "This build cannot announce transfers on the local network.".into(),
+        );
+        notify!(options, "Error: {}", error);
+        return Err(error);
+    }
+    // Publish the transfer on the first relay that answers
+    let relay = match options.local_only {
+        true => relay,
+        false => pick_relay(relay, &options).await,
+    };
+    // Pin the transfer to the relay instance that is reached first
+    if options.route.is_none() && !options.local_only {
+        let http_url = util::replace_protocol(&relay);
+        options.route = http_client::fetch_route(&http_url, options.token.as_deref()).await;
+    }
     // Create a channel for communication between threads
     let (tx, mut rx) = mpsc::channel(1);
-    // Generate a unique room ID
-    let room_id = Uuid::new_v4().to_string();
-    let local_room_id = room_id.clone();
+    // Generate a unique room ID, or the fixed one of a mailbox
+    let room_id = match options.key {
+        Some(_) => util::mailbox_room_id(&name),
+        None => Uuid::new_v4().to_string(),
+    };
+    // Listen on the first free port, so another program on the port does not take the
+    // local path away
+    let preferred = options.local_port.unwrap_or(LOCAL_PORT);
+    let listener = bind_local_ws(preferred, &options).await;
+    let local_port = listener
+        .as_ref()
+        .and_then(|listener| listener.local_addr().ok())
+        .map_or(preferred, |address| address.port());
+    options.local_port = Some(local_port);
+    // Let receivers on the local network find the transfer without the relay
+    #[cfg(all(feature = "mdns", feature = "relay"))]
+    let _announcement = match (listener.is_some() && (options.announce || options.local_only))
+        .then(|| crate::discovery::announce(&name, &room_id, local_port))
+    {
+        // Without the announcement nobody finds a local transfer
+        Some(Err(error)) if options.local_only => {
+            notify!(options, "Error: {}", error);
+            return Err(error);
+        }
+        Some(Err(error)) => {
+            error!("Failed to announce the transfer: {error}");
+            None
+        }
+        announced => announced.and_then(Result::ok),
+    };
+    // Let receivers behind NAT reach the local server directly
+    #[cfg(feature = "relay")]
+    let punch_thread = (options.punch && !options.local_only && listener.is_some()).then(|| {
+        task::spawn(offer_direct(
+            relay.clone(),
+            room_id.clone(),
+            options.clone(),
+        ))
+    });
+    // Both connections use the same room and PIN, so one transfer code reaches either
+    let session = Session::for_options(Some(room_id), &options);
+    let local_session = session.clone();
     let local_files = files.clone();
     let local_relay = relay.clone();
     let local_rand_name = name.clone();
     let local_tx = tx.clone();
-    // Start a local WebSocket server
-    let local_ws_thread = task::spawn(async move {
-        start_local_ws().await;
+    let local_options = options.clone();
+    let local_only = options.local_only;
+    let usage = options.usage.clone();
+    let summary_options = options.clone();
+    let relay_url = match local_only {
+        true => format!("ws://0.0.0.0:{}", local_port),
+        false => relay.to_string(),
+    };
+    // Start a local WebSocket server, unless every port is busy
+    let local = listener.map(|listener| task::spawn(start_local_ws(listener)));
+    // Carry the sessions of receivers that speak QUIC to the local server
+    #[cfg(feature = "quic")]
+    let quic_thread =
+        (options.quic && local.is_some()).then(|| task::spawn(crate::quic::serve(local_port)));
+    // Connect to the relay, unless the transfer stays on the local network
+    let relay_thread = (!local_only).then(|| {
+        task::spawn(async move {
+            connect_to_server(
+                relay.clone(),
+                files.clone(),
+                session,
+                relay.clone(),
+                Arc::new(name.clone()),
+                tx.clone(),
+                false,
+                options,
+            )
+            .await
+        })
     });
-    // Connect to the relay
-    let relay_thread = task::spawn(async move {
-        connect_to_server(
-            relay.clone(),
-            files.clone(),
-            Some(room_id),
-            relay.clone(),
-            Arc::new(name.clone()),
-            tx.clone(),
-            false,
-        )
-        .await
-    });
-    // Connect to the local WebSocket server
-    let local_thread = task::spawn(async move {
-        connect_to_server(
-            Arc::new(String::from("ws://0.0.0.0:9000")),
-            local_files.clone(),
-            Some(local_room_id),
-            local_relay.clone(),
-            Arc::new(local_rand_name.clone()),
-            local_tx.clone(),
-            true,
-        )
-        .await
+    // Connect to the local WebSocket server, but never to another program on its port
+    let local_thread = local.is_some().then(|| {
+        task::spawn(async move {
+            connect_to_server(
+                Arc::new(format!("ws://0.0.0.0:{}", local_port)),
+                local_files.clone(),
+                local_session,
+                local_relay.clone(),
+                Arc::new(local_rand_name.clone()),
+                local_tx.clone(),
+                true,
+                local_options,
+            )
+            .await
+        })
     });
 
-    // Wait for the sender threads to finish
-    rx.recv().await.unwrap();
+    // Wait for the first sender thread to finish its transfer
+    let result = rx.recv().await.unwrap_or_else(|| {
+        Err(CaesarError::RelayUnreachable {
+            relay: relay_url,
+            reason: match local_only {
+                true => "The local server did not accept the connection.".into(),
+                false => "Neither the relay nor the local server accepted the connection.".into(),
+            },
+        })
+    });
     // Abort the local WebSocket server thread
-    local_ws_thread.abort();
+    if let Some(local_ws_thread) = local {
+        local_ws_thread.abort();
+    }
+    // Stop accepting QUIC connections
+    #[cfg(feature = "quic")]
+    if let Some(quic_thread) = quic_thread {
+        quic_thread.abort();
+    }
     // Abort the relay thread
-    relay_thread.abort();
+    if let Some(relay_thread) = relay_thread {
+        relay_thread.abort();
+    }
+    // Stop offering direct connections
+    #[cfg(feature = "relay")]
+    if let Some(punch_thread) = punch_thread {
+        punch_thread.abort();
+    }
     // Abort the local thread
-    local_thread.abort();
+    if let Some(local_thread) = local_thread {
+        local_thread.abort();
+    }
+
+    if let Ok(summary) = &result {
+        let format = NumberFormat::current();
+        // A text has no files to sum up
+        if summary.files > 0 {
+            notify!(summary_options, "Sent {}.", summary.describe(&format));
+        }
+        notify!(summary_options, "{}", usage.summary(&format));
+    }
+    debug!(
+        local = usage.local(),
+        relay = usage.relay(),
+        "Bytes sent over each path"
+    );
+
+    result
+}
+
+/// Picks the relay the transfer is published on.
+///
+/// Without fallback relays, the relay is taken as it is. Otherwise the relays are asked
+/// in order, and the first one that answers is taken, so a single relay outage does not
+/// block the transfer.
+///
+/// # Arguments
+///
+/// * `relay` - The relay tried first.
+/// * `options` - The options of the transfer, with the fallback relays.
+///
+/// # Returns
+///
+/// The first relay that answers, or the last one if none does, whose connection then
+/// fails as usual.
+async fn pick_relay(relay: Arc<String>, options: &SenderOptions) -> Arc<String> {
+    let mut relay = relay;
+    for fallback in &options.fallback_relays {
+        if http_client::is_reachable(&util::replace_protocol(&relay)).await {
+            return relay;
+        }
+        notify!(
+            options,
+            "Warning: The relay '{}' is unreachable, trying '{}'.",
+            relay,
+            fallback
+        );
+        relay = Arc::new(fallback.clone());
+    }
+    relay
+}
+
+/// Binds the local WebSocket server to the first free port.
+///
+/// Another sender or program may already listen on the preferred port, so up to
+/// `LOCAL_PORT_ATTEMPTS` ports from it on are tried. The user is warned whenever the
+/// preferred port is busy, and whenever no port is free, in which case receivers can
+/// only take the relay.
+///
+/// # Arguments
+///
+/// * `port` - The port tried first.
+/// * `options` - The options of the transfer.
+///
+/// # Returns
+///
+/// The listener, or `None` if every port is busy.
+pub async fn bind_local_ws(port: u16, options: &SenderOptions) -> Option<TcpListener> {
+    let last = port.saturating_add(LOCAL_PORT_ATTEMPTS - 1);
+    for candidate in port..=last {
+        match bind_local_port(candidate) {
+            Ok(listener) => {
+                if candidate != port {
+                    warn!(
+                        port,
+                        bound = candidate,
+                        "The port of the local server is busy"
+                    );
+                    notify!(
+                        options,
+                        "Warning: Port {port} is busy, the local server listens on port {candidate} instead."
+                    );
+                }
+                return Some(listener);
+            }
+            Err(error) => debug!("Failed to listen on port {candidate}: {error}"),
+        }
+    }
+
+    warn!(port, last, "Every port of the local server is busy");
+    notify!(options, "Warning: Ports {port} to {last} are busy, receivers can only reach this transfer through the relay.");
+    None
+}
+
+/// Binds the local WebSocket server to a port, dual-stack so receivers reach it over
+/// IPv6 and IPv4, or on IPv4 alone if the host has no IPv6.
+///
+/// # Arguments
+///
+/// * `port` - The port to listen on.
+///
+/// # Returns
+///
+/// The listener, or the error of the bind.
+fn bind_local_port(port: u16) -> io::Result<TcpListener> {
+    let listener = match net::bind_dual_stack(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port))) {
+        Ok(listener) => listener,
+        // The port is taken on IPv4 as well
+        Err(error) if error.kind() == io::ErrorKind::AddrInUse => return Err(error),
+        Err(_) => net::bind_dual_stack(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))?,
+    };
+    TcpListener::from_std(listener)
 }
 
 /// Start a local WebSocket server.
 ///
-/// This function initializes and runs a WebSocket server on the specified host and port.
+/// This function runs a WebSocket server on a listener bound by `bind_local_ws`.
 /// It creates an instance of the `AppState` struct and uses it as the state for the router.
 /// The `ws_handler` function is registered as the handler for the "/ws" route.
 ///
 /// # Arguments
 ///
-/// None
+/// * `listener` - The listener of the local server.
 ///
 /// # Returns
 ///
 /// This function does not return anything.
-pub async fn start_local_ws() {
-    // The host and port the server will listen on.
-    let app_host = "0.0.0.0";
-    let app_port = "9000";
+#[cfg(feature = "relay")]
+pub async fn start_local_ws(listener: TcpListener) {
+    use crate::relay::{appstate::AppState, server::ws_handler};
+    use axum::{routing::get, Router};
+    use axum_client_ip::SecureClientIpSource;
+    use std::net::SocketAddr;
+    use tower_http::trace::{DefaultMakeSpan, TraceLayer};
+    use tracing::info;
 
     // Create an instance of the application state.
     let server = AppState::new();
@@ -108,55 +610,117 @@ pub async fn start_local_ws() {
     let app = Router::new()
         .route("/ws", get(ws_handler))
         .with_state(server)
+        .layer(SecureClientIpSource::ConnectInfo.into_extension())
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(DefaultMakeSpan::default().include_headers(true)),
         );
 
-    // Try to bind the server to the specified host and port.
-    if let Ok(listener) = TcpListener::bind(&format!("{}:{}", app_host, app_port)).await {
-        // Log the address the server is listening on.
-        info!(
-            "Local WebSocket listening on: {}",
-            listener.local_addr().unwrap()
-        );
+    // Log the address the server is listening on.
+    if let Ok(address) = listener.local_addr() {
+        info!("Local WebSocket listening on: {}", address);
+    }
+
+    // Serve the application using the listener.
+    // The `connect_info` parameter is used to include the client's socket address in the tracing spans.
+    if let Err(e) = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    {
+        error!("The local WebSocket server failed: {e}");
+    }
+}
+
+/// Offers direct connections to the local server through hole punching.
+///
+/// The sender waits at the relay's rendezvous for a receiver, punches through the NATs
+/// of both, and bridges every direct connection to the local server. Then it waits for
+/// the next receiver, until it is aborted or the relay has no rendezvous.
+///
+/// # Arguments
+///
+/// * `relay` - The relay whose rendezvous the peers meet at.
+/// * `room_id` - The room of the transfer.
+/// * `options` - The options of the transfer.
+#[cfg(feature = "relay")]
+async fn offer_direct(relay: Arc<String>, room_id: String, options: SenderOptions) {
+    use tokio::{io::copy_bidirectional, net::TcpStream};
 
-        // Serve the application using the listener.
-        // The `connect_info` parameter is used to include the client's socket address in the tracing spans.
-        axum::serve(
-            listener,
-            app.into_make_service_with_connect_info::<SocketAddr>(),
+    let port = options.local_port.unwrap_or(LOCAL_PORT);
+    loop {
+        let punched = crate::punch::punch(
+            &relay,
+            &room_id,
+            options.route.as_deref(),
+            options.token.as_deref(),
+            None,
+            true,
         )
-        .await
-        .unwrap();
-    } else {
-        // Log an error if the server fails to bind.
-        error!("Failed to listen on: {}:{}", app_host, app_port);
+        .await;
+        let mut streams = match punched {
+            Ok(streams) => streams,
+            Err(error) => {
+                debug!("Stopped offering a direct connection: {error}");
+                return;
+            }
+        };
+
+        while let Some(mut stream) = streams.recv().await {
+            debug!("Opened a direct connection to {:?}", stream.peer_addr());
+            // The receiver talks to the local server as if it was on the same network
+            task::spawn(async move {
+                if let Ok(mut local) = TcpStream::connect(("127.0.0.1", port)).await {
+                    let _ = copy_bidirectional(&mut stream, &mut local).await;
+                }
+            });
+        }
     }
 }
 
+/// Stands in for the local WebSocket server in builds without the `relay` feature.
+///
+/// The listener is closed, so the connection to the local server fails and the transfer
+/// always takes the relay.
+///
+/// # Arguments
+///
+/// * `listener` - The listener of the local server.
+#[cfg(not(feature = "relay"))]
+pub async fn start_local_ws(listener: TcpListener) {
+    drop(listener);
+    debug!("Built without the local WebSocket server");
+}
+
 /// Connects to the specified server and starts the file transfer.
 ///
+/// If the connection of a session breaks down, the room is created again up to
+/// `options.reconnect` times, so the receiver can rejoin it and continue the transfer.
+///
 /// # Arguments
 ///
 /// * `relay` - The relay server URL.
 /// * `files` - The files to be transferred.
-/// * `room_id` - The room ID for the transfer. If `None`, a random UUID is generated.
+/// * `session` - The room and the secret shared by all sessions of the transfer.
 /// * `message_server` - The message server URL.
 /// * `transfer_name` - The name of the transfer.
-/// * `tx` - The sender end of a channel to signal the completion of the transfer.
+/// * `tx` - The sender end of a channel to report the result of the transfer.
 /// * `is_local` - Whether the transfer is local or not.
+/// * `options` - The options of the transfer.
+#[allow(clippy::too_many_arguments)]
 async fn connect_to_server(
     relay: Arc<String>,
     files: Arc<Vec<String>>,
-    room_id: Option<String>,
+    session: Session,
     message_server: Arc<String>,
     transfer_name: Arc<String>,
-    tx: mpsc::Sender<()>,
+    tx: mpsc::Sender<Result<TransferSummary>>,
     is_local: bool,
+    options: SenderOptions,
 ) {
-    // Construct the server URL.
-    let url = format!("{}/ws", relay);
+    // Construct the server URL, with TLS if the relay is fronted by HTTPS.
+    let url = util::websocket_url(&relay);
 
     // Construct the message server URL.
     let message_relay = format!("{}", message_server);
@@ -164,49 +728,121 @@ async fn connect_to_server(
     // Construct the transfer name.
     let transfer_name = format!("{}", transfer_name);
 
-    // Create a request to the server.
-    match url.clone().into_client_request() {
-        Ok(mut request) => {
-            // Set the "Origin" header.
-            request
-                .headers_mut()
-                .insert("Origin", HeaderValue::from_str(relay.as_ref()).unwrap());
-
-            // Log the connection attempt.
-            debug!("Attempting to connect to {url}...");
-
-            // Generate a room ID if not provided.
-            let room_id = match room_id {
-                Some(id) => id,
-                None => Uuid::new_v4().to_string(),
-            };
-
-            // Connect to the server and start the file transfer.
-            match connect_async(request).await {
-                Ok((socket, _)) => {
-                    let paths = files.to_vec();
-                    sender::start(
-                        socket,
-                        paths,
-                        Some(room_id),
-                        message_relay.to_string(),
-                        transfer_name.clone(),
-                        is_local,
-                    )
-                    .await;
-
-                    // Signal the completion of the transfer.
-                    tx.send(()).await.unwrap();
+    let mut attempt = 0;
+
+    loop {
+        // Create a request to the server.
+        let mut request = match url.clone().into_client_request() {
+            Ok(request) => request,
+            Err(e) => {
+                // Log the request creation error.
+                error!("Error: failed to create request with reason: {e:?}");
+                return;
+            }
+        };
+
+        // Set the "Origin" header.
+        request
+            .headers_mut()
+            .insert("Origin", HeaderValue::from_str(relay.as_ref()).unwrap());
+
+        // Stay on the relay instance that issued the routing token.
+        if let Some(route) = options.route.as_ref().filter(|_| !is_local) {
+            if let Ok(value) = HeaderValue::from_str(route) {
+                request.headers_mut().insert(ROUTE_HEADER, value);
+            }
+        }
+
+        // Authenticate with the relay, but never hand the token to the local server.
+        if let Some(token) = options.token.as_ref().filter(|_| !is_local) {
+            match HeaderValue::from_str(&format!("Bearer {token}")) {
+                Ok(value) => {
+                    request.headers_mut().insert("Authorization", value);
                 }
                 Err(e) => {
-                    // Log the connection error.
-                    error!("Error: Failed to connect with error: {e}");
+                    error!("Error: invalid access token: {e}");
+                    return;
                 }
             }
         }
-        Err(e) => {
-            // Log the request creation error.
-            error!("Error: failed to create request with reason: {e:?}");
+
+        // Log the connection attempt.
+        debug!("Attempting to connect to {url}...");
+
+        // Connect to the server and start the file transfer.
+        let result = match connect_async(request).await {
+            Ok((socket, _)) => {
+                sender::start_session(
+                    socket,
+                    files.to_vec(),
+                    &session,
+                    message_relay.to_string(),
+                    transfer_name.clone(),
+                    is_local,
+                    options.clone(),
+                )
+                .await
+            }
+            Err(e) if attempt == 0 => {
+                // Log the connection error, the other connection may still succeed.
+                error!("Error: Failed to connect with error: {e}");
+                return;
+            }
+            Err(e) => Err(CaesarError::RelayUnreachable {
+                relay: relay.to_string(),
+                reason: e.to_string(),
+            }),
+        };
+
+        // A session whose connection broke down is re-established in the same room.
+        // Once recovering, the relay may still hold the old room for a while.
+        let broken = |error: &CaesarError| {
+            matches!(error, CaesarError::Connection(_) | CaesarError::Suspended(_))
+                || (attempt > 0 && error.is_recoverable())
+        };
+        match result {
+            Err(error) if attempt < options.reconnect && broken(&error) => {
+                attempt += 1;
+                debug!("Session broke down: {error}");
+                notify!(
+                    options,
+                    "Reconnecting ({}/{})...",
+                    attempt,
+                    options.reconnect
+                );
+                if let Some(events) = &options.events {
+                    events.report(TransferEvent::Reconnect { attempt });
+                }
+                if !suspend::reconnect_delay(attempt, &options.cancel).await {
+                    notify!(options, "Transfer was cancelled.");
+                    let _ = tx.send(Err(CaesarError::Cancelled)).await;
+                    return;
+                }
+            }
+            result => {
+                // Report the result of the transfer.
+                let _ = tx.send(result).await;
+                return;
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bind_local_ws() {
+        // Another program holds the preferred port
+        let busy = TcpListener::bind(("0.0.0.0", 0)).await.unwrap();
+        let port = busy.local_addr().unwrap().port();
+
+        let listener = bind_local_ws(port, &SenderOptions::default())
+            .await
+            .unwrap();
+        let bound = listener.local_addr().unwrap().port();
+        assert_ne!(bound, port);
+        assert!(bound > port && bound < port + LOCAL_PORT_ATTEMPTS);
+    }
+}
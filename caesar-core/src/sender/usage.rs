@@ -0,0 +1,99 @@
+//! How many bytes a sender sent over each path.
+//!
+//! A transfer reaches the receiver either over the sender's local server, which also
+//! carries direct and QUIC connections, or over the relay. Counting the bytes of each
+//! path shows users how much a transfer depended on the relay.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use crate::format::NumberFormat;
+
+/// The number of bytes sent over the local server and over the relay.
+///
+/// Clones share the same counters, so a caller keeps a clone of the options and reads
+/// the usage once the transfer has ended.
+#[derive(Debug, Clone, Default)]
+pub struct PathUsage {
+    /// The bytes sent over the local server.
+    local: Arc<AtomicU64>,
+    /// The bytes sent over the relay.
+    relay: Arc<AtomicU64>,
+}
+
+impl PathUsage {
+    /// Counts bytes sent over a path.
+    ///
+    /// # Arguments
+    ///
+    /// * `is_local` - Whether the bytes were sent over the local server.
+    /// * `bytes` - The number of bytes sent.
+    pub fn record(&self, is_local: bool, bytes: u64) {
+        let counter = if is_local { &self.local } else { &self.relay };
+        counter.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Returns the number of bytes sent over the local server.
+    pub fn local(&self) -> u64 {
+        self.local.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of bytes sent over the relay.
+    pub fn relay(&self) -> u64 {
+        self.relay.load(Ordering::Relaxed)
+    }
+
+    /// Returns the share of the bytes that were sent over the relay, in percent.
+    pub fn relay_percent(&self) -> u64 {
+        let total = self.local() + self.relay();
+        if total == 0 {
+            return 0;
+        }
+        (self.relay() as f64 * 100.0 / total as f64).round() as u64
+    }
+
+    /// Describes the split for the transfer summary.
+    ///
+    /// # Arguments
+    ///
+    /// * `format` - How the sizes are written.
+    ///
+    /// # Returns
+    ///
+    /// A line like `2.4 GiB went out in total, 2.3 GiB over the local network and
+    /// 96.0 MiB (4%) over the relay.`
+    pub fn summary(&self, format: &NumberFormat) -> String {
+        format!(
+            "{} went out in total, {} over the local network and {} ({}%) over the relay.",
+            format.size(self.local() + self.relay()),
+            format.size(self.local()),
+            format.size(self.relay()),
+            self.relay_percent()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_usage() {
+        let usage = PathUsage::default();
+        assert_eq!(usage.relay_percent(), 0);
+
+        // Clones count into the same counters
+        let relay = usage.clone();
+        usage.record(true, 3 * 1024);
+        relay.record(false, 1024);
+        assert_eq!(usage.local(), 3 * 1024);
+        assert_eq!(usage.relay(), 1024);
+        assert_eq!(usage.relay_percent(), 25);
+        assert_eq!(
+            usage.summary(&NumberFormat::default()),
+            "4.0 KiB went out in total, 3.0 KiB over the local network and 1.0 KiB (25%) over the relay."
+        );
+    }
+}
@@ -1,56 +1,138 @@
 use hex;
-use rand::{seq::SliceRandom, thread_rng};
+use rand::{seq::SliceRandom, thread_rng, Rng};
 use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    fmt, fs,
+    io::{self, Seek, SeekFrom},
+    path::Path,
+};
 
-/// Generates a random name composed of an adjective, a noun, and another noun.
+use crate::shared::{packets::HashAlgorithm, FileHasher};
+
+/// The number of words in a generated transfer name.
+pub const DEFAULT_NAME_WORDS: usize = 2;
+
+/// Generates a random transfer name like `brave-tiger-42`.
 ///
 /// # Returns
 ///
-/// A string in the format "{adjective}-{noun1}-{noun2}".
+/// A name of `DEFAULT_NAME_WORDS` words and a number.
 pub fn generate_random_name() -> String {
+    generate_name(DEFAULT_NAME_WORDS)
+}
+
+/// Generates a random transfer name of the given number of words.
+///
+/// The words are short and common, so the name is easy to dictate over the phone. All
+/// words but the last are adjectives, the last one is a noun, and a number between 10
+/// and 99 follows them.
+///
+/// # Parameters
+///
+/// * `words`: The number of words, at least one.
+///
+/// # Returns
+///
+/// A string in the format "{adjective}-...-{noun}-{number}".
+pub fn generate_name(words: usize) -> String {
     let mut rng = thread_rng();
-    let adjective = adjectives().choose(&mut rng).unwrap();
-    let noun1 = nouns1().choose(&mut rng).unwrap();
-    let noun2 = nouns2().choose(&mut rng).unwrap();
+    let mut parts: Vec<String> = (1..words.max(1))
+        .map(|_| ADJECTIVES.choose(&mut rng).unwrap().to_string())
+        .collect();
+    parts.push(NOUNS.choose(&mut rng).unwrap().to_string());
+    parts.push(rng.gen_range(10..100).to_string());
 
-    format!("{adjective}-{noun1}-{noun2}")
+    parts.join("-")
 }
 
-/// Returns a random adjective.
+/// Brings a transfer name into the form it is hashed in.
+///
+/// Names are matched regardless of case and surrounding whitespace, so a name that was
+/// dictated or typed on a phone still finds the transfer.
+///
+/// # Parameters
+///
+/// * `name`: The name as the user entered it.
 ///
 /// # Returns
 ///
-/// A `&'static str` representing an adjective.
-fn adjectives() -> &'static [&'static str] {
-    // Define a list of adjectives.
-    static ADJECTIVES: &[&str] = &["funny", "smart", "creative", "friendly", "great"];
-    ADJECTIVES
+/// The trimmed name in lowercase.
+pub fn normalize_name(name: &str) -> String {
+    name.trim().to_lowercase()
 }
 
-/// Returns a random noun.
+/// The adjectives of generated transfer names.
+static ADJECTIVES: &[&str] = &[
+    "able", "acid", "agile", "amber", "ample", "angry", "arctic", "azure", "bald", "basic",
+    "bold", "brave", "brief", "bright", "brisk", "broad", "busy", "calm", "candid", "cheap",
+    "clean", "clever", "cold", "cool", "cosmic", "crisp", "cute", "daring", "dark", "deep",
+    "dizzy", "dry", "dusty", "eager", "early", "easy", "empty", "epic", "fair", "fancy",
+    "fast", "fierce", "fine", "firm", "flat", "fluffy", "fond", "frank", "free", "fresh",
+    "funny", "fuzzy", "gentle", "giant", "glad", "golden", "good", "grand", "green",
+    "happy", "hardy", "heavy", "hidden", "humble", "icy", "jolly", "juicy", "kind", "large",
+    "lazy", "little", "livid", "loud", "lucky", "lunar", "magic", "merry", "mighty", "mild",
+    "misty", "modern", "narrow", "neat", "nimble", "noble", "odd", "orange",
+    "plain", "polite", "proud", "quick", "quiet", "rapid", "rare", "ready", "red", "rich",
+    "rocky", "round", "royal", "rusty", "safe", "salty", "shiny", "silent", "silver",
+    "simple", "sleepy", "slow", "smart", "smooth", "snowy", "soft", "solid", "spicy",
+    "steady", "stormy", "sunny", "super", "sweet", "swift", "tall", "tame", "tidy", "tiny",
+    "tough", "vast", "warm", "wavy", "wild", "windy", "wise", "witty", "young",
+];
+
+/// The nouns of generated transfer names.
+static NOUNS: &[&str] = &[
+    "anchor", "apple", "arrow", "badger", "banana", "barrel", "beacon", "bear", "beaver",
+    "bell", "bison", "boat", "bottle", "breeze", "bridge", "brook", "bucket", "button",
+    "cabin", "cactus", "camel", "candle", "canyon", "carrot", "castle", "cedar", "cherry",
+    "cloud", "clover", "cobra", "comet", "cookie", "coral", "cotton", "coyote", "crane",
+    "crater", "dolphin", "dragon", "eagle", "falcon", "feather", "fern", "fiddle", "finch",
+    "forest", "fox", "garden", "gecko", "ginger", "glacier", "goat", "harbor", "hawk",
+    "hazel", "heron", "honey", "island", "jaguar", "jelly", "kettle", "kiwi", "koala",
+    "ladder", "lagoon", "lemon", "leopard", "lily", "lizard", "llama", "lotus", "mango",
+    "maple", "meadow", "melon", "meteor", "mirror", "monkey", "moose", "mountain", "nectar",
+    "needle", "ocean", "octopus", "olive", "orbit", "otter", "owl", "panda", "parrot",
+    "peach", "pebble", "pepper", "piano", "pigeon", "pillow", "planet", "plum", "pony",
+    "puffin", "pumpkin", "rabbit", "raccoon", "radish", "raven", "river", "robin", "rocket",
+    "saddle", "salmon", "sparrow", "spider", "squid", "summit", "teapot", "thunder",
+    "tiger", "tomato", "tulip", "turtle", "valley", "violin", "walnut", "walrus", "whale",
+    "willow", "wizard", "wolf", "yacht", "zebra",
+];
+
+/// Generates the name and key of a new mailbox.
+///
+/// Unlike the names of single transfers, the name of a mailbox is used for a long time,
+/// so it is long enough that nobody can guess it.
 ///
 /// # Returns
 ///
-/// A `&'static str` representing a noun.
-fn nouns1() -> &'static [&'static str] {
-    // Define a list of nouns.
-    static NOUNS1: &[&str] = &["dog", "cat", "flower", "tree", "house"];
-    NOUNS1
+/// The hex encoded name and the HMAC key of the mailbox.
+pub fn generate_mailbox() -> (String, Vec<u8>) {
+    let mut rng = thread_rng();
+    let name: [u8; 16] = rng.gen();
+    let key: [u8; 32] = rng.gen();
+
+    (hex::encode(name), key.to_vec())
 }
 
-/// Returns a random noun.
+/// Derives the room ID of a mailbox from its name.
+///
+/// # Parameters
+///
+/// * `name`: The name of the mailbox.
 ///
 /// # Returns
 ///
-/// A `&'static str` representing a noun.
-fn nouns2() -> &'static [&'static str] {
-    // Define a list of nouns.
-    static NOUNS2: &[&str] = &["cookie", "cake", "frosting"];
-    NOUNS2
+/// The room ID every transfer to the mailbox uses.
+pub fn mailbox_room_id(name: &str) -> String {
+    let hashed = Sha256::digest(format!("caesar-mailbox-room:{name}").as_bytes());
+    hex::encode(&hashed[..16])
 }
 
 /// Hashes a given name using SHA256 and returns the hex-encoded result.
 ///
+/// The name is normalized first, so it matches regardless of case.
+///
 /// # Parameters
 ///
 /// * `name`: A `String` representing the name to be hashed.
@@ -59,11 +141,47 @@ fn nouns2() -> &'static [&'static str] {
 ///
 /// A `String` containing the hex-encoded hash of the name.
 pub fn hash_random_name(name: String) -> String {
-    let hashed_name = Sha256::digest(name.as_bytes());
+    let hashed_name = Sha256::digest(normalize_name(&name).as_bytes());
     hex::encode(hashed_name)
 }
 
-/// Replaces occurrences of "ws://" and "wss://" in a given address with "http://" and "https://" respectively.
+/// Computes the hash of a file's content.
+///
+/// The file is read through a handle the caller already opened, so the hash covers the
+/// same file that is sent later even if the path is replaced meanwhile. BLAKE3 hashes of
+/// regular files are computed from a memory map of the file using all available cores.
+///
+/// # Parameters
+///
+/// * `file`: The open file to hash.
+/// * `algorithm`: The hash algorithm to use.
+///
+/// # Returns
+///
+/// A `Result` containing the raw hash bytes, or an error if the file could not be read.
+pub fn hash_file(file: &fs::File, algorithm: HashAlgorithm) -> io::Result<Vec<u8>> {
+    if algorithm == HashAlgorithm::Blake3 && file.metadata()?.is_file() {
+        // SAFETY: The map is read-only and dropped before returning. A file that is
+        // changed meanwhile no longer matches its hash, which the receiver detects.
+        let map = unsafe { memmap2::Mmap::map(file)? };
+        let mut hasher = blake3::Hasher::new();
+        hasher.update_rayon(&map);
+        return Ok(hasher.finalize().as_bytes().to_vec());
+    }
+
+    // Devices are read from the start, whatever position the handle is at
+    let mut reader = file;
+    reader.seek(SeekFrom::Start(0))?;
+    let mut hasher = FileHasher::new(algorithm);
+    io::copy(&mut reader, &mut hasher)?;
+
+    Ok(hasher.finalize_reset())
+}
+
+/// Replaces the "ws://" and "wss://" scheme of a given address with "http://" and "https://" respectively.
+///
+/// Addresses without a scheme, like `127.0.0.1:8080` or `example.com`, get "http://".
+/// Only the scheme at the start of the address is replaced.
 ///
 /// # Parameters
 ///
@@ -73,11 +191,266 @@ pub fn hash_random_name(name: String) -> String {
 ///
 /// A `String` representing the modified address.
 pub fn replace_protocol(address: &str) -> String {
-    let mut result = address.to_string();
-    result = result.replace("ws://", "http://");
-    result = result.replace("wss://", "https://");
+    let address = address.trim_end_matches('/');
 
-    result
+    if let Some(rest) = address.strip_prefix("wss://") {
+        format!("https://{}", rest)
+    } else if let Some(rest) = address.strip_prefix("ws://") {
+        format!("http://{}", rest)
+    } else if address.contains("://") {
+        address.to_string()
+    } else {
+        format!("http://{}", address)
+    }
+}
+
+/// Builds the URL of the WebSocket endpoint of a relay.
+///
+/// Relays fronted by HTTPS are reached through "wss://", so "https://" becomes "wss://"
+/// and "http://" becomes "ws://". Addresses without a scheme get "ws://".
+///
+/// # Parameters
+///
+/// * `address`: A `&str` representing the address of the relay.
+///
+/// # Returns
+///
+/// A `String` with the URL of the "/ws" endpoint.
+pub fn websocket_url(address: &str) -> String {
+    let address = address.trim_end_matches('/');
+
+    let base = if let Some(rest) = address.strip_prefix("https://") {
+        format!("wss://{}", rest)
+    } else if let Some(rest) = address.strip_prefix("http://") {
+        format!("ws://{}", rest)
+    } else if address.contains("://") {
+        address.to_string()
+    } else {
+        format!("ws://{}", address)
+    };
+
+    format!("{}/ws", base)
+}
+
+/// A problem that keeps a path from being sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathProblem {
+    /// The path as given by the user.
+    pub path: String,
+    /// What is wrong with the path.
+    pub reason: String,
+    /// How the user can fix the problem.
+    pub fix: String,
+}
+
+impl fmt::Display for PathProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}' {}. {}", self.path, self.reason, self.fix)
+    }
+}
+
+/// The path that stands for stdin, which is sent as a stream of unknown size.
+pub const STDIN_PATH: &str = "-";
+
+/// Checks that every path can be sent before a room is created.
+///
+/// All paths are checked, so the user sees every problem at once instead of fixing
+/// them one by one. Stdin may be given once as `-`.
+///
+/// # Arguments
+///
+/// * `paths` - The paths to check.
+/// * `allow_special` - Whether FIFOs, sockets and devices may be sent.
+/// * `pack` - Whether directories may be sent, packed into an archive while they are
+///   sent.
+///
+/// # Returns
+///
+/// The problems found, which is empty if every path can be sent.
+pub fn check_paths(paths: &[String], allow_special: bool, pack: bool) -> Vec<PathProblem> {
+    let mut problems = vec![];
+    let mut stdin = false;
+
+    for path in paths {
+        let problem = |reason: &str, fix: &str| PathProblem {
+            path: path.clone(),
+            reason: reason.to_string(),
+            fix: fix.to_string(),
+        };
+
+        // Stdin is read as it is sent, so it can only be read once
+        if path == STDIN_PATH {
+            if stdin {
+                problems.push(problem(
+                    "is given more than once",
+                    "Stdin can only be sent once, remove the other occurrences.",
+                ));
+            }
+            stdin = true;
+            continue;
+        }
+
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(error) => {
+                problems.push(match error.kind() {
+                    io::ErrorKind::NotFound => problem(
+                        "does not exist",
+                        "Check the spelling and the current directory.",
+                    ),
+                    io::ErrorKind::PermissionDenied => problem(
+                        "cannot be accessed",
+                        "Make sure you may enter every directory on the way to it.",
+                    ),
+                    _ => problem(&format!("cannot be read ({})", error), "Check the path."),
+                });
+                continue;
+            }
+        };
+
+        // A directory is packed while it is sent, so only its entries have to be readable
+        if metadata.is_dir() && pack {
+            if let Err(error) = fs::read_dir(path) {
+                problems.push(problem(
+                    &format!("cannot be listed ({})", error),
+                    "Grant read access to the directory.",
+                ));
+            }
+            continue;
+        }
+        if metadata.is_dir() {
+            problems.push(problem(
+                "is a directory",
+                "Pass the files inside it, pack it into an archive first or pass --tar to stream it as one.",
+            ));
+            continue;
+        }
+
+        // Opening a FIFO blocks until someone writes to it, so special files are not opened
+        if let Some(kind) = special_file_kind(&metadata) {
+            if !allow_special {
+                problems.push(problem(
+                    &format!("is a {}", kind),
+                    "Pass --allow-special to send it anyway.",
+                ));
+            }
+            continue;
+        }
+
+        if let Err(error) = fs::File::open(path) {
+            problems.push(match error.kind() {
+                io::ErrorKind::PermissionDenied => problem(
+                    "is not readable",
+                    &format!("Grant read access, e.g. with `chmod u+r {}`.", path),
+                ),
+                _ => problem(&format!("cannot be opened ({})", error), "Check the file."),
+            });
+            continue;
+        }
+
+        if metadata.len() == 0 {
+            problems.push(problem(
+                "is empty",
+                "Remove it from the list, empty files cannot be sent.",
+            ));
+        }
+    }
+
+    problems
+}
+
+/// Checks that the paths can be packed into a single archive.
+///
+/// The archive is packed from the files while it is sent, so stdin and special files
+/// cannot be part of it. Every path becomes an entry named after it, so the names have
+/// to differ.
+///
+/// # Arguments
+///
+/// * `paths` - The paths to check.
+///
+/// # Returns
+///
+/// The problems found, which is empty if every path can be packed.
+pub fn check_archive(paths: &[String]) -> Vec<PathProblem> {
+    let mut problems = vec![];
+    let mut names = HashSet::new();
+
+    for path in paths {
+        let problem = |reason: &str, fix: &str| PathProblem {
+            path: path.clone(),
+            reason: reason.to_string(),
+            fix: fix.to_string(),
+        };
+
+        if path == STDIN_PATH {
+            problems.push(problem(
+                "cannot be packed into an archive",
+                "Save the input to a file and send the file instead.",
+            ));
+            continue;
+        }
+        if let Some(kind) = fs::metadata(path).ok().as_ref().and_then(special_file_kind) {
+            problems.push(problem(
+                &format!("is a {} and cannot be packed into an archive", kind),
+                "Send it without --archive.",
+            ));
+            continue;
+        }
+        if !names.insert(base_name(path)) {
+            problems.push(problem(
+                "has the same name as another path",
+                "Rename one of them or send their parent directory instead.",
+            ));
+        }
+    }
+
+    problems
+}
+
+/// Returns the name a path is sent under, e.g. the name of the current directory for
+/// `.`.
+///
+/// # Arguments
+///
+/// * `path` - The path of a file or directory.
+///
+/// # Returns
+///
+/// The last component of the absolute path, or `archive` if there is none, like for
+/// the root directory.
+pub fn base_name(path: &str) -> String {
+    Path::new(path)
+        .canonicalize()
+        .ok()
+        .and_then(|path| {
+            path.file_name()
+                .map(|name| name.to_string_lossy().to_string())
+        })
+        .unwrap_or_else(|| "archive".to_string())
+}
+
+/// Returns the kind of a special file, or `None` for regular files.
+#[cfg(unix)]
+fn special_file_kind(metadata: &fs::Metadata) -> Option<&'static str> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let file_type = metadata.file_type();
+    if file_type.is_fifo() {
+        Some("FIFO")
+    } else if file_type.is_socket() {
+        Some("socket")
+    } else if file_type.is_block_device() || file_type.is_char_device() {
+        Some("device")
+    } else {
+        None
+    }
+}
+
+/// Returns the kind of a special file, or `None` for regular files.
+#[cfg(not(unix))]
+fn special_file_kind(_: &fs::Metadata) -> Option<&'static str> {
+    None
 }
 
 #[cfg(test)]
@@ -91,6 +464,129 @@ mod tests {
         assert!(name.contains('-'));
         assert!(name.split('-').count() == 3);
         // assert!(name.is_empty());
+
+        let name = generate_name(4);
+        let parts: Vec<&str> = name.split('-').collect();
+        assert_eq!(parts.len(), 5);
+        assert!(ADJECTIVES.contains(&parts[0]));
+        assert!(NOUNS.contains(&parts[3]));
+        assert!((10..100).contains(&parts[4].parse::<u32>().unwrap()));
+        assert_eq!(generate_name(0).split('-').count(), 2);
+
+        // Names match regardless of how they were typed
+        assert_eq!(
+            hash_random_name(" Brave-Tiger-42".to_string()),
+            hash_random_name("brave-tiger-42".to_string())
+        );
+    }
+    #[test]
+    fn test_generate_mailbox() {
+        let (name, key) = generate_mailbox();
+
+        assert_eq!(name.len(), 32);
+        assert_eq!(key.len(), 32);
+        assert_ne!(generate_mailbox().0, name);
+        // Both peers and every transfer to the mailbox agree on the room
+        assert_eq!(mailbox_room_id(&name), mailbox_room_id(&name));
+        assert_ne!(mailbox_room_id(&name), mailbox_room_id("other"));
+    }
+    #[test]
+    fn test_hash_file() {
+        fs::write("hash_test.txt", b"caesar").unwrap();
+        let file = fs::File::open("hash_test.txt").unwrap();
+        let sha256 = hash_file(&file, HashAlgorithm::Sha256).unwrap();
+        let blake3 = hash_file(&file, HashAlgorithm::Blake3).unwrap();
+
+        // The handle keeps hashing the file it was opened for
+        #[cfg(unix)]
+        {
+            fs::remove_file("hash_test.txt").unwrap();
+            fs::write("hash_test.txt", b"other").unwrap();
+            assert_eq!(hash_file(&file, HashAlgorithm::Sha256).unwrap(), sha256);
+        }
+        drop(file);
+        fs::remove_file("hash_test.txt").unwrap();
+
+        assert_eq!(sha256, Sha256::digest(b"caesar").to_vec());
+        assert_eq!(blake3, blake3::hash(b"caesar").as_bytes().to_vec());
+    }
+    #[test]
+    fn test_check_paths() {
+        fs::create_dir_all("check_test").unwrap();
+        fs::write("check_test/file.txt", b"caesar").unwrap();
+        fs::write("check_test/empty.txt", b"").unwrap();
+
+        let paths = vec![
+            "check_test/file.txt".to_string(),
+            "check_test/empty.txt".to_string(),
+            "check_test/missing.txt".to_string(),
+            "check_test".to_string(),
+            STDIN_PATH.to_string(),
+            STDIN_PATH.to_string(),
+        ];
+        let problems = check_paths(&paths, false, false);
+        // A directory is fine as a tar stream
+        let tar_problems = check_paths(&["check_test".to_string()], false, true);
+        fs::remove_dir_all("check_test").unwrap();
+        assert!(tar_problems.is_empty());
+
+        let reasons: Vec<(&str, &str)> = problems
+            .iter()
+            .map(|problem| (problem.path.as_str(), problem.reason.as_str()))
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                ("check_test/empty.txt", "is empty"),
+                ("check_test/missing.txt", "does not exist"),
+                ("check_test", "is a directory"),
+                ("-", "is given more than once"),
+            ]
+        );
+    }
+    #[cfg(unix)]
+    #[test]
+    fn test_check_paths_special() {
+        let paths = vec!["/dev/null".to_string()];
+
+        assert_eq!(check_paths(&paths, false, false)[0].reason, "is a device");
+        assert!(check_paths(&paths, true, false).is_empty());
+        assert_eq!(
+            check_archive(&paths)[0].reason,
+            "is a device and cannot be packed into an archive"
+        );
+    }
+    #[test]
+    fn test_check_archive() {
+        fs::create_dir_all("archive_test/a/nested").unwrap();
+        fs::create_dir_all("archive_test/b").unwrap();
+        fs::write("archive_test/a/notes.txt", b"caesar").unwrap();
+        fs::write("archive_test/b/notes.txt", b"caesar").unwrap();
+
+        let paths = vec![
+            "archive_test/a/notes.txt".to_string(),
+            "archive_test/a/nested".to_string(),
+            "archive_test/b/notes.txt".to_string(),
+            STDIN_PATH.to_string(),
+        ];
+        let problems = check_archive(&paths);
+        assert_eq!(base_name("archive_test/a/nested/.."), "a");
+        fs::remove_dir_all("archive_test").unwrap();
+
+        let reasons: Vec<(&str, &str)> = problems
+            .iter()
+            .map(|problem| (problem.path.as_str(), problem.reason.as_str()))
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                (
+                    "archive_test/b/notes.txt",
+                    "has the same name as another path"
+                ),
+                ("-", "cannot be packed into an archive"),
+            ]
+        );
     }
     #[test]
     fn test_replace_protocol_replaces_ws_with_http() {
@@ -112,4 +608,22 @@ mod tests {
         let result = replace_protocol("https://example.com");
         assert_eq!(result, "https://example.com");
     }
+
+    #[test]
+    fn test_replace_protocol_handles_bare_addresses() {
+        assert_eq!(replace_protocol("127.0.0.1:8080"), "http://127.0.0.1:8080");
+        assert_eq!(replace_protocol("wss://example.com/"), "https://example.com");
+        assert_eq!(
+            replace_protocol("ws://example.com/rooms/ws://x"),
+            "http://example.com/rooms/ws://x"
+        );
+    }
+
+    #[test]
+    fn test_websocket_url() {
+        assert_eq!(websocket_url("wss://example.com"), "wss://example.com/ws");
+        assert_eq!(websocket_url("https://example.com/"), "wss://example.com/ws");
+        assert_eq!(websocket_url("http://127.0.0.1:8000"), "ws://127.0.0.1:8000/ws");
+        assert_eq!(websocket_url("[::1]:8080"), "ws://[::1]:8080/ws");
+    }
 }
@@ -2,18 +2,416 @@ pub mod packets {
     include!(concat!(env!("OUT_DIR"), "/packets.rs"));
 }
 
-use aes_gcm::{
-    aead::{Aead, AeadCore},
-    Aes128Gcm,
+use aes_gcm::{aead::AeadCore, Aes128Gcm};
+use bytes::{BufMut, Bytes, BytesMut};
+use crate::cipher::SessionCipher;
+use crate::error::CaesarError;
+use crate::pake::{Keys, Role};
+use crate::relay::Priority;
+use packets::{
+    packet::Value, Cipher, Framing, HandshakePacket, HandshakeResponsePacket, HashAlgorithm,
+    NonceScheme, Packet,
+};
+use prost::{
+    encoding::{encode_key, encode_varint, encoded_len_varint, key_len, WireType},
+    Message,
 };
-use packets::Packet;
-use prost::Message;
 use rand::rngs::OsRng;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    future::Future,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 use tokio::net::TcpStream;
-use tokio_tungstenite::tungstenite::protocol::Message as WebSocketMessage;
+use futures_util::{Sink, Stream};
+use tokio_tungstenite::tungstenite::{protocol::Message as WebSocketMessage, Error};
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 
+/// The number of messages that may wait to go out over the connection.
+///
+/// Once the queue is full, chunks wait for room in it rather than adding to it, so the
+/// memory a transfer takes stays bounded however fast its files are read.
+pub const OUTGOING_QUEUE: usize = 1000;
+
+/// The offset a receiver reports for a stream it does not want.
+///
+/// A stream like stdin has no size the receiver could report to skip it, so the end of
+/// the largest possible file stands in for it.
+pub const STREAM_END: u64 = u64::MAX;
+
+/// The most files a sender sends and a receiver takes at the same time.
+///
+/// Many small files finish sooner when a stall of one does not hold up the others.
+pub const MAX_STREAMS: u32 = 4;
+
+/// The file index the position of a chunk is authenticated with when several files are
+/// sent at the same time.
+///
+/// The receiver cannot tell then which file the next chunk belongs to. Its sequence
+/// number counts the chunks of the whole transfer instead, and the index of its file is
+/// part of the encrypted chunk.
+pub const INTERLEAVED: u32 = u32::MAX;
+
+/// The size of the nonce prepended to every encrypted packet.
+const NONCE_SIZE: usize = 12;
+
+/// The size of the authentication tag appended to every encrypted packet.
+const TAG_SIZE: usize = 16;
+
+/// How far a packet may fall behind the newest packet of its side before it is
+/// rejected as outdated.
+const REPLAY_WINDOW: u64 = 1024;
+
+/// The key of an established session and the framing negotiated with the other peer.
+///
+/// The relay reads the routing byte in front of every binary message, so it cannot be
+/// encrypted. With `Framing::V3`, the source and the destination of every encrypted
+/// packet and the ID of the transfer are authenticated as associated data instead. A
+/// relay that changes the routing byte or sends a packet back to where it came from
+/// makes decryption fail. A byte after the routing byte marks chunks, and the file index
+/// and the sequence number of a chunk are authenticated as well, so a relay cannot move
+/// a chunk to another place of the transfer, not even to the same sequence number of
+/// another file.
+///
+/// Each side encrypts with a key of its own, so a packet cannot be passed back to the
+/// side that sent it. The nonce of a packet holds the side that sent it and the number
+/// of packets that side sent before, so no nonce is used twice, and a packet the relay
+/// repeats or holds back too long is rejected. Clones share the counters, as the
+/// heartbeats are sent with a clone of the key.
+///
+/// The handshake only accepts these choices, see `derive`. The earlier framings and
+/// random nonces are left to the keys `new` creates for tests, which read the packets
+/// they send themselves.
+#[derive(Clone)]
+pub struct SessionKey {
+    /// The cipher the packets of this side are encrypted with.
+    sealing: SessionCipher,
+    /// The cipher the packets of the other side are decrypted with.
+    opening: SessionCipher,
+    /// How the routing byte is protected.
+    framing: Framing,
+    /// How the nonces of the packets are chosen.
+    nonces: NonceScheme,
+    /// The counter of the next packet of this side.
+    counter: Arc<AtomicU64>,
+    /// The counters of the packets of the other side received so far.
+    window: Arc<Mutex<ReplayWindow>>,
+    /// The ID of the transfer, only authenticated with framing v3.
+    transfer: [u8; 16],
+}
+
+impl SessionKey {
+    /// Creates a session key that uses the same cipher in both directions and random
+    /// nonces, for tests.
+    ///
+    /// # Arguments
+    ///
+    /// * `cipher` - The cipher derived from the shared secret.
+    /// * `framing` - The framing negotiated in the handshake.
+    ///
+    /// # Returns
+    ///
+    /// A new `SessionKey` instance.
+    pub fn new(cipher: Aes128Gcm, framing: Framing) -> SessionKey {
+        SessionKey {
+            sealing: SessionCipher::Aes128Gcm(Box::new(cipher.clone())),
+            opening: SessionCipher::Aes128Gcm(Box::new(cipher)),
+            framing,
+            nonces: NonceScheme::Random,
+            counter: Arc::new(AtomicU64::new(0)),
+            window: Arc::new(Mutex::new(ReplayWindow::default())),
+            transfer: [0; 16],
+        }
+    }
+
+    /// Creates the session key of a side from the keys of the handshake.
+    ///
+    /// Each side encrypts with a key of its own, under framing v3 and with counter
+    /// nonces, the only choices a handshake accepts.
+    ///
+    /// # Arguments
+    ///
+    /// * `keys` - The keys both sides derived in the handshake.
+    /// * `role` - The side the session key is for.
+    /// * `cipher` - The cipher negotiated in the handshake.
+    ///
+    /// # Returns
+    ///
+    /// A new `SessionKey` instance.
+    pub fn derive(keys: &Keys, role: Role, cipher: Cipher) -> SessionKey {
+        SessionKey {
+            sealing: SessionCipher::new(cipher, &keys.traffic(role)),
+            opening: SessionCipher::new(cipher, &keys.traffic(role.peer())),
+            framing: Framing::V3,
+            nonces: NonceScheme::Counter,
+            counter: Arc::new(AtomicU64::new(0)),
+            window: Arc::new(Mutex::new(ReplayWindow::default())),
+            transfer: keys.transfer_id(),
+        }
+    }
+
+    /// Returns the framing negotiated in the handshake.
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    /// Returns the associated data of a packet sent from `source` to `destination`.
+    ///
+    /// The sender is the first member of a room and all others are its receivers, each
+    /// with a key of its own. The data holds the roles of both ends rather than their
+    /// positions, which change as receivers come and go.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The position of the side that sends the packet.
+    /// * `destination` - The position of the side the packet is sent to.
+    /// * `position` - The file index and the sequence number of a chunk, or `None` for
+    ///   any other packet.
+    fn associated_data(
+        &self,
+        source: u8,
+        destination: u8,
+        position: Option<(u32, u32)>,
+    ) -> Vec<u8> {
+        match self.framing {
+            Framing::V1 => vec![],
+            Framing::V2 => vec![Framing::V2 as u8, source.min(1), destination.min(1)],
+            Framing::V3 => {
+                let mut data = vec![
+                    Framing::V3 as u8,
+                    source.min(1),
+                    destination.min(1),
+                    u8::from(position.is_some()),
+                ];
+                data.extend_from_slice(&self.transfer);
+                if let Some((index, sequence)) = position {
+                    data.extend_from_slice(&index.to_be_bytes());
+                    data.extend_from_slice(&sequence.to_be_bytes());
+                }
+                data
+            }
+        }
+    }
+
+    /// Returns the size of the header in front of the ciphertext, the routing byte
+    /// included.
+    fn header_size(&self) -> usize {
+        match self.framing {
+            Framing::V1 | Framing::V2 => 1 + NONCE_SIZE,
+            Framing::V3 => 2 + NONCE_SIZE,
+        }
+    }
+
+    /// Returns the nonce of the next packet sent from `source`.
+    ///
+    /// A counter nonce starts with the role of the side, as both sides count from zero
+    /// and share a key unless the key schedule gives each its own. The counter fills
+    /// the last eight bytes.
+    fn next_nonce(&self, source: u8) -> [u8; NONCE_SIZE] {
+        match self.nonces {
+            NonceScheme::Random => Aes128Gcm::generate_nonce(&mut OsRng).into(),
+            NonceScheme::Counter => {
+                let counter = self.counter.fetch_add(1, Ordering::Relaxed);
+                let mut nonce = [0; NONCE_SIZE];
+                nonce[0] = source.min(1);
+                nonce[NONCE_SIZE - 8..].copy_from_slice(&counter.to_be_bytes());
+                nonce
+            }
+        }
+    }
+}
+
+/// Encodes what the sender offered and the receiver chose in a handshake, which the
+/// confirmation of the receiver authenticates.
+///
+/// The shares are left out, they are part of the transcript of the key exchange. The
+/// confirmation of a receiver is left out as well, as it is computed over the result.
+///
+/// # Arguments
+///
+/// * `offer` - The handshake packet of the sender.
+/// * `answer` - The handshake response packet of the receiver.
+///
+/// # Returns
+///
+/// The bytes both sides compute the confirmation over.
+pub fn negotiation(offer: &HandshakePacket, answer: &HandshakeResponsePacket) -> Vec<u8> {
+    let offer = HandshakePacket {
+        public_key: vec![],
+        signature: vec![],
+        ..offer.clone()
+    };
+    let answer = HandshakeResponsePacket {
+        public_key: vec![],
+        signature: vec![],
+        ..answer.clone()
+    };
+    let mut negotiation = offer.encode_length_delimited_to_vec();
+    negotiation.extend(answer.encode_length_delimited_to_vec());
+    negotiation
+}
+
+/// A chunk packet whose content is borrowed from where it was read.
+///
+/// A `ChunkPacket` owns its content, so a chunk of a memory mapped file would have to be
+/// copied out of the map first. This one is serialized straight into the message that
+/// is sent, exactly like a `ChunkPacket` in a `Packet`.
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkRef<'a> {
+    /// Counts the chunks of the file, or of the transfer if files are interleaved.
+    pub sequence: u32,
+    /// The content of the chunk, compressed if negotiated.
+    pub chunk: &'a [u8],
+    /// The index of the file the chunk belongs to.
+    pub index: u32,
+}
+
+impl ChunkRef<'_> {
+    /// The number of the `chunk` field of `Packet`.
+    const PACKET_TAG: u32 = 5;
+
+    /// Returns the length of the encoded `ChunkPacket`, which leaves out the fields that
+    /// hold their default value, like prost does.
+    fn message_len(&self) -> usize {
+        let varint = |tag, value: u32| match value {
+            0 => 0,
+            value => key_len(tag) + encoded_len_varint(u64::from(value)),
+        };
+        let chunk = match self.chunk.len() {
+            0 => 0,
+            length => key_len(2) + encoded_len_varint(length as u64) + length,
+        };
+        varint(1, self.sequence) + chunk + varint(3, self.index)
+    }
+
+    /// Returns the length of the encoded `Packet` that holds the chunk.
+    fn encoded_len(&self) -> usize {
+        let length = self.message_len();
+        key_len(Self::PACKET_TAG) + encoded_len_varint(length as u64) + length
+    }
+
+    /// Encodes the chunk as a `Packet`.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - The buffer to append the packet to.
+    fn encode(&self, buffer: &mut impl BufMut) {
+        encode_key(Self::PACKET_TAG, WireType::LengthDelimited, buffer);
+        encode_varint(self.message_len() as u64, buffer);
+        if self.sequence != 0 {
+            encode_key(1, WireType::Varint, buffer);
+            encode_varint(u64::from(self.sequence), buffer);
+        }
+        if !self.chunk.is_empty() {
+            encode_key(2, WireType::LengthDelimited, buffer);
+            encode_varint(self.chunk.len() as u64, buffer);
+            buffer.put_slice(self.chunk);
+        }
+        if self.index != 0 {
+            encode_key(3, WireType::Varint, buffer);
+            encode_varint(u64::from(self.index), buffer);
+        }
+    }
+}
+
+/// Returns the counter of a counter nonce sent from `source`.
+///
+/// # Returns
+///
+/// The counter, or `None` if the nonce was sent from the other side or is malformed.
+fn nonce_counter(nonce: &[u8], source: u8) -> Option<u64> {
+    let (prefix, counter) = nonce.split_at(NONCE_SIZE - 8);
+    if prefix[0] != source.min(1) || prefix[1..].iter().any(|&byte| byte != 0) {
+        return None;
+    }
+    Some(u64::from_be_bytes(counter.try_into().ok()?))
+}
+
+/// The counters of the packets received from the other side.
+///
+/// The packets of a side mostly arrive in order, but a heartbeat encrypted beside the
+/// transfer may overtake a packet or two. The window therefore remembers which of the
+/// last `REPLAY_WINDOW` counters were received, at the counter modulo the window size,
+/// rather than only the newest one.
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    /// The highest counter received so far, or `None` before the first packet.
+    newest: Option<u64>,
+    /// A bit for each counter in the window that was received.
+    seen: [u64; (REPLAY_WINDOW / 64) as usize],
+}
+
+impl ReplayWindow {
+    /// Returns whether a packet with the counter may be accepted.
+    ///
+    /// # Arguments
+    ///
+    /// * `counter` - The counter of the packet.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the counter was received before or is too far behind the newest one.
+    fn admits(&self, counter: u64) -> bool {
+        match self.newest {
+            Some(newest) if counter <= newest => {
+                newest - counter < REPLAY_WINDOW && !self.is_seen(counter)
+            }
+            _ => true,
+        }
+    }
+
+    /// Marks a counter as received. Only counters of packets that decrypted are
+    /// inserted, so a forged packet cannot move the window.
+    ///
+    /// # Arguments
+    ///
+    /// * `counter` - The counter of the packet.
+    fn insert(&mut self, counter: u64) {
+        if let Some(newest) = self.newest.filter(|&newest| counter > newest) {
+            // The counters skipped over drop out of the window
+            if counter - newest >= REPLAY_WINDOW {
+                self.seen = Default::default();
+            } else {
+                for skipped in newest + 1..counter {
+                    self.set(skipped, false);
+                }
+            }
+        }
+        self.newest = self.newest.max(Some(counter));
+        self.set(counter, true);
+    }
+
+    /// Returns whether the bit of a counter is set.
+    fn is_seen(&self, counter: u64) -> bool {
+        let bit = counter % REPLAY_WINDOW;
+        self.seen[(bit / 64) as usize] & (1 << (bit % 64)) != 0
+    }
+
+    /// Sets or clears the bit of a counter.
+    fn set(&mut self, counter: u64, seen: bool) {
+        let bit = counter % REPLAY_WINDOW;
+        let word = &mut self.seen[(bit / 64) as usize];
+        if seen {
+            *word |= 1 << (bit % 64);
+        } else {
+            *word &= !(1 << (bit % 64));
+        }
+    }
+}
+
+/// A key without a protected routing byte, for tests that read their own packets.
+impl From<Aes128Gcm> for SessionKey {
+    fn from(cipher: Aes128Gcm) -> Self {
+        SessionKey::new(cipher, Framing::V1)
+    }
+}
+
 /// Represents a packet that is sent over a websocket connection.
 ///
 /// This enum is used to represent different types of packets that can be sent over a websocket connection.
@@ -33,6 +431,13 @@ pub enum JsonPacket {
     Create {
         /// The id of the room to create. It can be `None` to generate a random room id.
         id: Option<String>,
+        /// The priority of the transfer, as a hint for a relay under load.
+        #[serde(default, skip_serializing_if = "Priority::is_normal")]
+        priority: Priority,
+        /// The number of clients the room holds, the creator included. If `None`, the
+        /// relay creates a room for a single receiver.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        size: Option<usize>,
     },
     /// A packet to leave a room.
     ///
@@ -53,6 +458,9 @@ pub enum JsonPacketResponse {
         /// The number of existing users in the room. This field is `None` if the room is empty.
         #[serde(skip_serializing_if = "Option::is_none")]
         size: Option<usize>,
+        /// The position of the user who joined the room. Older relays do not send it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        index: Option<usize>,
     },
     /// A response to a `Create` packet.
     ///
@@ -84,16 +492,26 @@ pub enum JsonPacketResponse {
 ///
 /// - `Continue`: Operation was successful and the client should continue.
 /// - `Exit`: Operation was successful and the client should exit.
-/// - `Err`: Operation encountered an error. The error message is provided in the
-///   variant.
+/// - `Err`: Operation encountered an error. The error is provided in the variant.
 #[derive(Debug, PartialEq)]
 pub enum Status {
     /// Operation was successful and the client should continue.
     Continue(),
     /// Operation was successful and the client should exit.
     Exit(),
-    /// Operation encountered an error. The error message is provided in the variant.
-    Err(String),
+    /// Operation encountered an error. The error is provided in the variant.
+    Err(CaesarError),
+}
+
+/// Why a client stopped before the transfer ended on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interruption {
+    /// The transfer was cancelled through its cancellation token.
+    Cancelled,
+    /// No data arrived for longer than the idle timeout.
+    IdleTimeout,
+    /// The system was suspended for the given time.
+    Suspended(Duration),
 }
 
 /// Represents a sender of JSON packets.
@@ -126,10 +544,30 @@ pub trait PacketSender {
     /// `value` argument is the packet to send.
     fn send_encrypted_packet(
         &self,
-        key: &Option<Aes128Gcm>,
+        key: &Option<SessionKey>,
         destination: u8,
         value: packets::packet::Value,
     );
+
+    /// Sends an encrypted chunk packet to a receiver.
+    ///
+    /// Unlike `send_encrypted_packet`, the index of the file the chunk belongs to is
+    /// known, so the position of the chunk can be authenticated with framing v3.
+    ///
+    /// Chunks are most of what goes out, so while the outgoing queue is full the returned
+    /// future waits for room in it, rather than blocking the thread it runs on. The
+    /// future resolves to `false` if the chunk was not sent, because no key is
+    /// established or the session has ended.
+    ///
+    /// The content is borrowed and encrypted right away, so it is copied only once, into
+    /// the message that is sent.
+    fn send_encrypted_chunk(
+        &self,
+        key: &Option<SessionKey>,
+        destination: u8,
+        index: u32,
+        chunk: ChunkRef<'_>,
+    ) -> impl Future<Output = bool> + Send;
 }
 
 
@@ -151,9 +589,9 @@ impl JsonPacketSender for Sender {
         let serialized_packet = serde_json::to_string(&packet)
             .expect("Failed to serialize JSON packet.");
 
-        // Send the serialized packet as a WebSocketMessage::Text
-        self.send(WebSocketMessage::Text(serialized_packet))
-            .expect("Failed to send JSON packet.");
+        // Send the serialized packet as a WebSocketMessage::Text. The session may have
+        // ended already when the peer left, the packet has nowhere to go then.
+        let _ = self.send(WebSocketMessage::Text(serialized_packet));
     }
 }
 
@@ -171,22 +609,24 @@ impl PacketSender for Sender {
     /// * `destination` - The destination of the packet.
     /// * `value` - The packet to send.
     fn send_packet(&self, destination: u8, value: packets::packet::Value) {
-        // Serialize the packet
+        // Serialize the packet behind the destination, for which room is left up front
         let packet = Packet { value: Some(value) };
-        let mut serialized_packet = packet.encode_to_vec();
-
-        // Insert the destination at the beginning of the packet
-        serialized_packet.insert(0, destination);
+        let mut serialized_packet = BytesMut::with_capacity(1 + packet.encoded_len());
+        serialized_packet.put_u8(destination);
+        packet
+            .encode(&mut serialized_packet)
+            .expect("The buffer holds the whole packet.");
+        let serialized_packet = Vec::from(serialized_packet);
 
-        // Send the serialized packet as a WebSocketMessage::Binary
-        self.send(WebSocketMessage::Binary(serialized_packet))
-            .expect("Failed to send Packet.");
+        // Send the serialized packet as a WebSocketMessage::Binary, unless the session
+        // has ended already
+        let _ = self.send(WebSocketMessage::Binary(serialized_packet));
     }
 
     /// Sends an encrypted packet to a receiver.
     ///
     /// This method encrypts the packet using the provided key and sends it as a
-    /// `WebSocketMessage::Binary` to a receiver.
+    /// `WebSocketMessage::Binary` to a receiver. Without a key, nothing is sent.
     ///
     /// # Arguments
     ///
@@ -195,39 +635,571 @@ impl PacketSender for Sender {
     /// * `value` - The packet to send.
     fn send_encrypted_packet(
         &self,
-        key: &Option<Aes128Gcm>,
+        key: &Option<SessionKey>,
         destination: u8,
         value: packets::packet::Value,
     ) {
-        // Serialize the packet
-        let packet = Packet { value: Some(value) };
+        // Nothing is sent before the keys are established
+        let Some(key) = key else {
+            return;
+        };
+
+        // Send the serialized packet as a WebSocketMessage::Binary, unless the session
+        // has ended already
+        let serialized_packet = encrypt_packet(key, destination, value, None);
+        let _ = self.send(WebSocketMessage::Binary(serialized_packet));
+    }
+
+    /// Sends an encrypted chunk packet to a receiver.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The encryption key to use.
+    /// * `destination` - The destination of the packet.
+    /// * `index` - The index of the file the chunk belongs to.
+    /// * `chunk` - The chunk packet to send.
+    ///
+    /// # Returns
+    ///
+    /// A future that resolves to `true` once the chunk is queued.
+    fn send_encrypted_chunk(
+        &self,
+        key: &Option<SessionKey>,
+        destination: u8,
+        index: u32,
+        chunk: ChunkRef<'_>,
+    ) -> impl Future<Output = bool> + Send {
+        // Encrypt the chunk right away, so the future holds on to nothing but the packet
+        let serialized_packet = key.as_ref().map(|key| {
+            let position = Some((index, chunk.sequence));
+            seal_packet(key, destination, position, chunk.encoded_len(), |buffer| {
+                chunk.encode(buffer)
+            })
+        });
+
+        async move {
+            match serialized_packet {
+                Some(packet) => self
+                    .send_async(WebSocketMessage::Binary(packet))
+                    .await
+                    .is_ok(),
+                None => false,
+            }
+        }
+    }
+}
+
+/// Encrypts a packet and frames it for the relay.
+///
+/// # Arguments
+///
+/// * `key` - The encryption key to use.
+/// * `destination` - The destination of the packet.
+/// * `value` - The packet to encrypt.
+/// * `position` - The file index and the sequence number of a chunk, or `None` for
+///   any other packet.
+///
+/// # Returns
+///
+/// The routing byte, the chunk marker of framing v3, the nonce, the ciphertext and the
+/// tag. The packet is serialized right behind the header and encrypted where it is, so
+/// a chunk is copied only once on its way out.
+fn encrypt_packet(
+    key: &SessionKey,
+    destination: u8,
+    value: Value,
+    position: Option<(u32, u32)>,
+) -> Vec<u8> {
+    let packet = Packet { value: Some(value) };
+    seal_packet(key, destination, position, packet.encoded_len(), |buffer| {
+        packet
+            .encode(buffer)
+            .expect("The buffer holds the whole packet.")
+    })
+}
+
+/// Serializes a packet behind its header, encrypts it in place and frames it for the
+/// relay.
+///
+/// # Arguments
+///
+/// * `key` - The encryption key to use.
+/// * `destination` - The destination of the packet.
+/// * `position` - The file index and the sequence number of a chunk, or `None` for
+///   any other packet.
+/// * `length` - The length of the serialized packet.
+/// * `encode` - Serializes the packet into the buffer it is given.
+///
+/// # Returns
+///
+/// The routing byte, the chunk marker of framing v3, the nonce, the ciphertext and the
+/// tag.
+fn seal_packet(
+    key: &SessionKey,
+    destination: u8,
+    position: Option<(u32, u32)>,
+    length: usize,
+    encode: impl FnOnce(&mut BytesMut),
+) -> Vec<u8> {
+    // The sender only talks to receivers and every receiver only to the sender
+    let source = u8::from(destination == 0);
+    let associated_data = key.associated_data(source, destination, position);
+
+    // Generate a nonce for encryption
+    let nonce = key.next_nonce(source);
+
+    // Start the serialized packet with the destination, the marker and the nonce, and
+    // leave room for the packet and its tag behind them
+    let header_size = key.header_size();
+    let mut serialized_packet = BytesMut::with_capacity(header_size + length + TAG_SIZE);
+    serialized_packet.put_u8(destination);
+    if key.framing == Framing::V3 {
+        serialized_packet.put_u8(u8::from(position.is_some()));
+    }
+    serialized_packet.put_slice(&nonce);
+
+    // Serialize the packet and encrypt it in place using the provided key
+    encode(&mut serialized_packet);
+    let tag = key
+        .sealing
+        .encrypt_in_place_detached(
+            &nonce,
+            &associated_data,
+            &mut serialized_packet[header_size..],
+        )
+        .expect("Failed to encrypt Packet.");
+    serialized_packet.put_slice(&tag);
+
+    // The buffer was never shared, so it becomes the message without a copy
+    Vec::from(serialized_packet)
+}
+
+/// Incrementally hashes the content of a file.
+///
+/// The algorithm is chosen by the sender and announced in the list packet.
+#[derive(Debug, Clone)]
+pub enum FileHasher {
+    /// A SHA-256 hasher.
+    Sha256(Sha256),
+    /// A BLAKE3 hasher.
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl FileHasher {
+    /// Creates a new hasher for the given algorithm.
+    ///
+    /// # Arguments
+    ///
+    /// * `algorithm` - The hash algorithm to use.
+    pub fn new(algorithm: HashAlgorithm) -> FileHasher {
+        match algorithm {
+            HashAlgorithm::Sha256 => FileHasher::Sha256(Sha256::new()),
+            HashAlgorithm::Blake3 => FileHasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    /// Feeds data into the hasher.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The data to hash.
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            FileHasher::Sha256(hasher) => Digest::update(hasher, data),
+            FileHasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    /// Returns the hash of all data fed so far and resets the hasher.
+    pub fn finalize_reset(&mut self) -> Vec<u8> {
+        match self {
+            FileHasher::Sha256(hasher) => hasher.finalize_reset().to_vec(),
+            FileHasher::Blake3(hasher) => {
+                let hash = hasher.finalize().as_bytes().to_vec();
+                hasher.reset();
+                hash
+            }
+        }
+    }
+}
+
+impl io::Write for FileHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Decrypts and decodes a binary message from the other peer.
+///
+/// The message is decrypted in place, and the data of a chunk packet points into it,
+/// so receiving a chunk copies nothing.
+///
+/// # Arguments
+///
+/// * `shared_key` - The shared key, or `None` before the handshake.
+/// * `peer` - The index of the other peer in the room.
+/// * `position` - The file index and the sequence number the next chunk has to have,
+///   or `None` if no chunk is expected.
+/// * `data` - The binary message including the leading routing byte.
+///
+/// # Returns
+///
+/// The value of the packet, or an error if the message cannot be decrypted or decoded.
+pub fn decode_packet(
+    shared_key: &Option<SessionKey>,
+    peer: u8,
+    position: Option<(u32, u32)>,
+    mut data: Vec<u8>,
+) -> Result<Value, CaesarError> {
+    // The routing byte was set by the relay to the source of the message
+    let Some(&source) = data.first() else {
+        return Err("Received an empty message.".into());
+    };
 
-        // Generate a nonce for encryption
-        let nonce = Aes128Gcm::generate_nonce(&mut OsRng);
+    let mut chunk = None;
+    let payload = match shared_key {
+        Some(shared_key) => {
+            if shared_key.framing != Framing::V1 && source != peer {
+                return Err(CaesarError::Crypto(format!(
+                    "Received a packet from the unexpected peer {}.",
+                    source
+                )));
+            }
+            let header_size = shared_key.header_size();
+            if data.len() < header_size + TAG_SIZE {
+                return Err(CaesarError::Crypto("The message is too short.".into()));
+            }
 
-        // Serialize the packet
-        let plaintext = packet.encode_to_vec();
+            // With framing v3, a chunk is authenticated at the position it is expected at
+            chunk = match (shared_key.framing, data[1]) {
+                (Framing::V3, 0) | (Framing::V1 | Framing::V2, _) => None,
+                (Framing::V3, 1) => Some(position.ok_or_else(|| {
+                    CaesarError::Crypto("Received a chunk that was not expected.".into())
+                })?),
+                (Framing::V3, _) => {
+                    return Err(CaesarError::Crypto("Received a malformed packet.".into()))
+                }
+            };
 
-        // Encrypt the packet using the provided key
-        let mut ciphertext = key
-            .as_ref()
-            .unwrap()
-            .encrypt(&nonce, plaintext.as_ref())
-            .expect("Failed to encrypt Packet.");
+            let associated_data = shared_key.associated_data(peer, u8::from(peer == 0), chunk);
+            let (header, ciphertext) = data.split_at_mut(header_size);
+            let (ciphertext, tag) = ciphertext.split_at_mut(ciphertext.len() - TAG_SIZE);
+            let nonce = &header[header_size - NONCE_SIZE..];
 
-        // Create the serialized packet by concatenating the nonce and the ciphertext
-        let mut serialized_packet = nonce.to_vec();
-        serialized_packet.append(&mut ciphertext);
+            // A counter nonce has to come from the other side and must not have been
+            // received before
+            let mut window = shared_key.window.lock().unwrap();
+            let counter = match shared_key.nonces {
+                NonceScheme::Random => None,
+                NonceScheme::Counter => {
+                    let counter = nonce_counter(nonce, peer).ok_or_else(|| {
+                        CaesarError::Crypto("Received a packet with a malformed nonce.".into())
+                    })?;
+                    if !window.admits(counter) {
+                        return Err(CaesarError::Crypto(
+                            "Received a repeated or outdated packet.".into(),
+                        ));
+                    }
+                    Some(counter)
+                }
+            };
 
-        // Insert the destination at the beginning of the packet
-        serialized_packet.insert(0, destination);
+            shared_key
+                .opening
+                .decrypt_in_place_detached(nonce, &associated_data, ciphertext, tag)
+                .map_err(|_| CaesarError::Crypto("Failed to decrypt a packet.".into()))?;
+            if let Some(counter) = counter {
+                window.insert(counter);
+            }
+            header_size..data.len() - TAG_SIZE
+        }
+        None => 1..data.len(),
+    };
 
-        // Send the serialized packet as a WebSocketMessage::Binary
-        self.send(WebSocketMessage::Binary(serialized_packet))
-            .expect("Failed to send encrypted Packet.");
+    let value = Packet::decode(Bytes::from(data).slice(payload))
+        .ok()
+        .and_then(|packet| packet.value)
+        .ok_or_else(|| CaesarError::from("Received a malformed packet."))?;
+
+    // A chunk without the marker would escape the check of its position
+    let framing = shared_key.as_ref().map(SessionKey::framing);
+    if framing == Some(Framing::V3) && matches!(value, Value::Chunk(_)) != chunk.is_some() {
+        return Err(CaesarError::Crypto(
+            "Received a chunk without its position.".into(),
+        ));
     }
+    Ok(value)
 }
 
 pub type Sender = flume::Sender<WebSocketMessage>;
 
 pub type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// A bidirectional stream of WebSocket messages the sender and receiver can run on.
+///
+/// It is implemented for `Socket` and for wrappers around it, such as the chaos
+/// transport used by the integration tests.
+pub trait Transport:
+    Stream<Item = Result<WebSocketMessage, Error>>
+    + Sink<WebSocketMessage, Error = Error>
+    + Send
+    + Unpin
+{
+}
+
+impl<T> Transport for T where
+    T: Stream<Item = Result<WebSocketMessage, Error>>
+        + Sink<WebSocketMessage, Error = Error>
+        + Send
+        + Unpin
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pake::Spake2;
+    use aes_gcm::{Key, KeyInit};
+    use futures_util::FutureExt;
+    use packets::{ChunkPacket, CreditPacket};
+
+    #[test]
+    fn test_decode_packet_checks_route() {
+        let cipher = Aes128Gcm::new(Key::<Aes128Gcm>::from_slice(&[7u8; 16]));
+        let key = Some(SessionKey::new(cipher, Framing::V2));
+        let (sender, receiver) = flume::unbounded();
+        let credit = || Value::Credit(CreditPacket { chunks: 16 });
+
+        // Send from peer 1 to peer 0, the relay replaces the routing byte with the source
+        sender.send_encrypted_packet(&key, 0, credit());
+        let mut data = receiver.recv().unwrap().into_data();
+        data[0] = 1;
+        assert_eq!(
+            decode_packet(&key, 1, None, data.clone()).unwrap(),
+            credit()
+        );
+
+        // A changed routing byte is rejected. Another receiver has a key of its own, so
+        // the packet cannot be passed off as one of its packets either.
+        data[0] = 2;
+        assert!(decode_packet(&key, 1, None, data.clone()).is_err());
+        let other = Aes128Gcm::new(Key::<Aes128Gcm>::from_slice(&[8u8; 16]));
+        let other = Some(SessionKey::new(other, Framing::V2));
+        assert!(decode_packet(&other, 2, None, data.clone()).is_err());
+
+        // A packet sent back to its source is rejected
+        data[0] = 0;
+        assert!(decode_packet(&key, 0, None, data).is_err());
+
+        // Receivers further back in the room are reached the same way
+        sender.send_encrypted_packet(&key, 3, credit());
+        let mut data = receiver.recv().unwrap().into_data();
+        data[0] = 0;
+        assert_eq!(decode_packet(&key, 0, None, data).unwrap(), credit());
+    }
+
+    #[test]
+    fn test_derived_keys_differ_by_direction() {
+        let sender_pake = Spake2::new(Role::Sender, b"482913");
+        let receiver_pake = Spake2::new(Role::Receiver, b"482913");
+        let sender_keys = sender_pake.finish(receiver_pake.message()).unwrap();
+        let receiver_keys = receiver_pake.finish(sender_pake.message()).unwrap();
+        let (sender, receiver) = flume::unbounded();
+        let credit = || Value::Credit(CreditPacket { chunks: 16 });
+
+        for cipher in [
+            Cipher::Aes128Gcm,
+            Cipher::Aes256Gcm,
+            Cipher::Chacha20Poly1305,
+        ] {
+            let sender_key = Some(SessionKey::derive(&sender_keys, Role::Sender, cipher));
+            let receiver_key = Some(SessionKey::derive(&receiver_keys, Role::Receiver, cipher));
+
+            // The receiver reads what the sender encrypted
+            sender.send_encrypted_packet(&sender_key, 1, credit());
+            let mut data = receiver.recv().unwrap().into_data();
+            data[0] = 0;
+            assert_eq!(
+                decode_packet(&receiver_key, 0, None, data.clone()).unwrap(),
+                credit()
+            );
+
+            // A packet passed back to the sender does not decrypt, not even with the
+            // routing byte of the receiver
+            data[0] = 1;
+            assert!(decode_packet(&sender_key, 1, None, data).is_err());
+        }
+    }
+
+    #[test]
+    fn test_counter_nonces_reject_replays() {
+        let sender_pake = Spake2::new(Role::Sender, b"482913");
+        let receiver_pake = Spake2::new(Role::Receiver, b"482913");
+        let sender_keys = sender_pake.finish(receiver_pake.message()).unwrap();
+        let receiver_keys = receiver_pake.finish(sender_pake.message()).unwrap();
+        let derive = |keys, role| Some(SessionKey::derive(keys, role, Cipher::Aes128Gcm));
+        let sender_key = derive(&sender_keys, Role::Sender);
+        let receiver_key = derive(&receiver_keys, Role::Receiver);
+        let (sender, receiver) = flume::unbounded();
+        let credit = |chunks| Value::Credit(CreditPacket { chunks });
+        let send = |key: &Option<SessionKey>, destination, chunks| {
+            sender.send_encrypted_packet(key, destination, credit(chunks));
+            let mut data = receiver.recv().unwrap().into_data();
+            data[0] = u8::from(destination == 0);
+            data
+        };
+
+        // Both sides count from zero, but the nonces differ by the side
+        let first = send(&sender_key, 1, 1);
+        let answer = send(&receiver_key, 0, 1);
+        assert_eq!(first[2..2 + NONCE_SIZE], [0; NONCE_SIZE]);
+        assert_eq!(answer[2], 1);
+        assert_eq!(answer[3..2 + NONCE_SIZE], [0; NONCE_SIZE - 1]);
+
+        // The side in the nonce keeps a packet passed back to the sender from
+        // decrypting
+        let mut reflected = first.clone();
+        reflected[0] = 1;
+        assert!(decode_packet(&sender_key, 1, None, reflected).is_err());
+
+        // A packet that was overtaken is accepted, but only once
+        let second = send(&sender_key, 1, 2);
+        let third = send(&sender_key, 1, 3);
+        assert_eq!(
+            decode_packet(&receiver_key, 0, None, third).unwrap(),
+            credit(3)
+        );
+        assert_eq!(
+            decode_packet(&receiver_key, 0, None, first.clone()).unwrap(),
+            credit(1)
+        );
+        assert!(decode_packet(&receiver_key, 0, None, first).is_err());
+        assert_eq!(
+            decode_packet(&receiver_key, 0, None, second).unwrap(),
+            credit(2)
+        );
+        assert_eq!(
+            decode_packet(&sender_key, 1, None, answer).unwrap(),
+            credit(1)
+        );
+
+        // A forged counter does not move the window
+        let mut forged = send(&sender_key, 1, 4);
+        forged[2 + NONCE_SIZE - 1] = 200;
+        assert!(decode_packet(&receiver_key, 0, None, forged).is_err());
+        let fourth = send(&sender_key, 1, 5);
+        assert_eq!(
+            decode_packet(&receiver_key, 0, None, fourth).unwrap(),
+            credit(5)
+        );
+    }
+
+    #[test]
+    fn test_chunk_position_is_authenticated() {
+        let sender_pake = Spake2::new(Role::Sender, b"482913");
+        let receiver_pake = Spake2::new(Role::Receiver, b"482913");
+        let sender_keys = sender_pake.finish(receiver_pake.message()).unwrap();
+        let receiver_keys = receiver_pake.finish(sender_pake.message()).unwrap();
+        let derive = |keys, role| Some(SessionKey::derive(keys, role, Cipher::Aes256Gcm));
+        let sender_key = derive(&sender_keys, Role::Sender);
+        let receiver_key = derive(&receiver_keys, Role::Receiver);
+        let (sender, receiver) = flume::unbounded();
+        let chunk = |sequence| ChunkPacket {
+            sequence,
+            chunk: Bytes::from_static(b"Hello, world"),
+            index: 0,
+        };
+        let send = |index, sequence| {
+            let borrowed = ChunkRef {
+                sequence,
+                chunk: b"Hello, world",
+                index: 0,
+            };
+            let sent = sender.send_encrypted_chunk(&sender_key, 1, index, borrowed);
+            assert_eq!(sent.now_or_never(), Some(true));
+            let mut data = receiver.recv().unwrap().into_data();
+            data[0] = 0;
+            data
+        };
+
+        // A chunk is only read at the position it was sent for. A packet is accepted
+        // once, so the wrong positions are tried first.
+        let data = send(2, 5);
+        assert_eq!(data[1], 1);
+        assert!(decode_packet(&receiver_key, 0, Some((3, 5)), data.clone()).is_err());
+        assert!(decode_packet(&receiver_key, 0, Some((2, 6)), data.clone()).is_err());
+        assert!(decode_packet(&receiver_key, 0, None, data.clone()).is_err());
+
+        // Without the marker, the chunk is not taken for another packet either
+        let mut unmarked = data.clone();
+        unmarked[1] = 0;
+        assert!(decode_packet(&receiver_key, 0, Some((2, 5)), unmarked).is_err());
+        assert_eq!(
+            decode_packet(&receiver_key, 0, Some((2, 5)), data).unwrap(),
+            Value::Chunk(chunk(5))
+        );
+
+        // Other packets are read wherever the transfer is
+        let credit = Value::Credit(CreditPacket { chunks: 16 });
+        sender.send_encrypted_packet(&receiver_key, 0, credit.clone());
+        let mut data = receiver.recv().unwrap().into_data();
+        data[0] = 1;
+        assert_eq!(data[1], 0);
+        assert_eq!(decode_packet(&sender_key, 1, None, data).unwrap(), credit);
+    }
+
+    #[test]
+    fn test_chunk_ref_encodes_like_chunk_packet() {
+        for (sequence, chunk, index) in [
+            (0, &b""[..], 0),
+            (7, &b"Hello, world"[..], 0),
+            (300, &[0xab; 200][..], INTERLEAVED),
+        ] {
+            let borrowed = ChunkRef {
+                sequence,
+                chunk,
+                index,
+            };
+            let owned = Packet {
+                value: Some(Value::Chunk(ChunkPacket {
+                    sequence,
+                    chunk: Bytes::copy_from_slice(chunk),
+                    index,
+                })),
+            };
+            let mut encoded = vec![];
+            borrowed.encode(&mut encoded);
+            assert_eq!(borrowed.encoded_len(), encoded.len());
+            assert_eq!(encoded, owned.encode_to_vec());
+        }
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::default();
+        assert!(window.admits(5));
+        window.insert(5);
+        assert!(!window.admits(5));
+        assert!(window.admits(3));
+        window.insert(3);
+        assert!(!window.admits(3));
+
+        // Counters too far behind the newest one are outdated
+        window.insert(5 + REPLAY_WINDOW);
+        assert!(!window.admits(5));
+        assert!(window.admits(6));
+        assert!(!window.admits(5 + REPLAY_WINDOW));
+
+        // A jump past the window forgets everything in it
+        window.insert(10 * REPLAY_WINDOW);
+        assert!(window.admits(9 * REPLAY_WINDOW + 1));
+        assert!(!window.admits(9 * REPLAY_WINDOW));
+    }
+}
@@ -0,0 +1,251 @@
+//! Simulated transfers for developing user interfaces.
+//!
+//! A simulation plays the events of a transfer without any network: the handshake, the
+//! list of files, the progress of each file with a jittery speed, and, depending on the
+//! scenario, retries, reconnects and errors. The events follow from the scenario and a
+//! seed alone, so a frontend can be built and tested against the same transfer again
+//! and again.
+
+use std::time::Duration;
+
+use clap::ValueEnum;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{sync::mpsc, task, time::sleep};
+
+use crate::error::CaesarError;
+use crate::event::{ListedFile, TransferEvent};
+
+/// The names the simulated files are given.
+const FILE_NAMES: [&str; 6] = [
+    "holiday.jpg",
+    "report.pdf",
+    "notes.txt",
+    "backup.tar.zst",
+    "talk.mp4",
+    "budget.xlsx",
+];
+
+/// The average speed of a simulated transfer in bytes per second.
+const SPEED: f64 = 12.0 * 1024.0 * 1024.0;
+
+/// How a simulated transfer goes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Scenario {
+    /// Every file arrives at the first attempt.
+    #[default]
+    Success,
+    /// Every file arrives, but one is requested again after a failed check and the
+    /// connection breaks down once and is re-established.
+    Flaky,
+    /// The connection breaks down in the middle of the transfer, which ends with an error.
+    Failure,
+    /// The handshake fails because the receiver entered a wrong code.
+    WrongCode,
+}
+
+/// An event of a simulation along with the time before it happens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    /// The time since the event before.
+    pub delay: Duration,
+    /// The event.
+    pub event: TransferEvent,
+}
+
+/// Writes the script of a simulated transfer.
+///
+/// # Arguments
+///
+/// * `scenario` - How the transfer goes.
+/// * `seed` - The seed of the random generator. The same seed yields the same files,
+///   delays and faults.
+///
+/// # Returns
+///
+/// Every event of the transfer in order, ending with a `Complete` or `Error` event.
+pub fn script(scenario: Scenario, seed: u64) -> Vec<Step> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut steps = vec![Step {
+        delay: Duration::from_millis(rng.gen_range(300..800)),
+        event: TransferEvent::Handshake {
+            name: format!("simulated-{}", seed),
+        },
+    }];
+
+    if scenario == Scenario::WrongCode {
+        steps[0].event = TransferEvent::Error {
+            message: CaesarError::WrongCode("The sender did not accept the handshake.".into())
+                .to_string(),
+        };
+        return steps;
+    }
+    steps.push(Step {
+        delay: Duration::ZERO,
+        event: TransferEvent::Verification {
+            code: format!("{:03} {:03}", rng.gen_range(0..1000), rng.gen_range(0..1000)),
+            receiver: None,
+        },
+    });
+
+    // Sizes spread from a few KiB to a few hundred MiB
+    let count = rng.gen_range(1..=4);
+    let files: Vec<ListedFile> = (0..count)
+        .map(|index| ListedFile {
+            name: FILE_NAMES[(seed as usize).wrapping_add(index) % FILE_NAMES.len()].into(),
+            size: 2f64.powf(rng.gen_range(12.0..28.0)) as u64,
+        })
+        .collect();
+    let total: u64 = files.iter().map(|file| file.size).sum();
+    steps.push(Step {
+        delay: Duration::from_millis(rng.gen_range(50..200)),
+        event: TransferEvent::List {
+            files: files.clone(),
+        },
+    });
+
+    // The faults happen at a random share of the transfer
+    let retried = rng.gen_range(0..files.len());
+    let fault_at = (total as f64 * rng.gen_range(0.3..0.7)) as u64;
+    let mut faulted = false;
+    let mut sent = 0;
+
+    for (index, file) in files.iter().enumerate() {
+        let attempts = if scenario == Scenario::Flaky && index == retried {
+            2
+        } else {
+            1
+        };
+        for attempt in 0..attempts {
+            if attempt > 0 {
+                steps.push(Step {
+                    delay: Duration::from_millis(rng.gen_range(50..150)),
+                    event: TransferEvent::Retry {
+                        file: index,
+                        reason: "The file does not match the checksum of the sender.".into(),
+                    },
+                });
+            }
+
+            let mut transferred = 0;
+            while transferred < file.size {
+                // Each step takes about a quarter second at a speed that jitters by half
+                let delay = Duration::from_millis(rng.gen_range(150..350));
+                let speed = SPEED * rng.gen_range(0.5..1.5);
+                let bytes = ((speed * delay.as_secs_f64()) as u64).max(1);
+                transferred = (transferred + bytes).min(file.size);
+                sent += bytes;
+                steps.push(Step {
+                    delay,
+                    event: TransferEvent::Progress {
+                        file: index,
+                        receiver: None,
+                        transferred,
+                        size: file.size,
+                    },
+                });
+
+                if faulted || sent < fault_at {
+                    continue;
+                }
+                faulted = true;
+                match scenario {
+                    Scenario::Flaky => steps.push(Step {
+                        delay: Duration::from_millis(rng.gen_range(1000..3000)),
+                        event: TransferEvent::Reconnect { attempt: 1 },
+                    }),
+                    Scenario::Failure => {
+                        steps.push(Step {
+                            delay: Duration::from_millis(rng.gen_range(1000..3000)),
+                            event: TransferEvent::Error {
+                                message: CaesarError::Connection(
+                                    "The connection to the relay was closed.".into(),
+                                )
+                                .to_string(),
+                            },
+                        });
+                        return steps;
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    steps.push(Step {
+        delay: Duration::from_millis(rng.gen_range(50..200)),
+        event: TransferEvent::Complete {
+            files: files.len(),
+            bytes: total,
+        },
+    });
+    steps
+}
+
+/// Plays a simulated transfer in real time.
+///
+/// # Arguments
+///
+/// * `scenario` - How the transfer goes.
+/// * `seed` - The seed of the random generator.
+///
+/// # Returns
+///
+/// The receiver of the events, which is closed after the last event.
+pub fn simulate(scenario: Scenario, seed: u64) -> mpsc::Receiver<TransferEvent> {
+    let (tx, rx) = mpsc::channel(16);
+    task::spawn(async move {
+        for step in script(scenario, seed) {
+            sleep(step.delay).await;
+            // Stop once nobody listens anymore
+            if tx.send(step.event).await.is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script() {
+        assert_eq!(script(Scenario::Flaky, 7), script(Scenario::Flaky, 7));
+
+        let events = |scenario| -> Vec<TransferEvent> {
+            script(scenario, 7)
+                .into_iter()
+                .map(|step| step.event)
+                .collect()
+        };
+        let success = events(Scenario::Success);
+        assert!(matches!(success[0], TransferEvent::Handshake { .. }));
+        assert!(matches!(
+            success.last(),
+            Some(TransferEvent::Complete { .. })
+        ));
+        assert!(!success.iter().any(|event| matches!(
+            event,
+            TransferEvent::Retry { .. } | TransferEvent::Reconnect { .. }
+        )));
+
+        let flaky = events(Scenario::Flaky);
+        assert!(flaky
+            .iter()
+            .any(|event| matches!(event, TransferEvent::Retry { .. })));
+        assert!(flaky
+            .iter()
+            .any(|event| matches!(event, TransferEvent::Reconnect { attempt: 1 })));
+        assert_eq!(flaky.last(), success.last());
+
+        assert!(matches!(
+            events(Scenario::Failure).last(),
+            Some(TransferEvent::Error { .. })
+        ));
+        assert!(matches!(
+            events(Scenario::WrongCode)[..],
+            [TransferEvent::Error { .. }]
+        ));
+    }
+}
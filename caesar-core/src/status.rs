@@ -0,0 +1,128 @@
+//! Looks up how far a transfer has come by its transfer code.
+//!
+//! The relay keeps the record of a transfer until the receiver reports that it has
+//! every file, so a known record means the transfer is still pending. Once the record
+//! is gone, only the history of a relay that requires tokens tells whether the
+//! receiver finished or the transfer was given up.
+
+use std::fmt;
+
+use crate::error::{CaesarError, Result};
+use crate::pake::split_code;
+use crate::receiver::http_client::{download_info, transfer_history};
+use crate::relay::routing::split_route;
+use crate::relay::transfer::{HistoryEntry, TransferStatus};
+use crate::sender::util::{hash_random_name, replace_protocol};
+
+/// How far a transfer has come.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferState {
+    /// The relay knows the transfer, the receiver has not finished yet.
+    Pending,
+    /// The receiver reported that it has every file.
+    Completed,
+    /// The transfer is in the history of the user, but the relay forgot it before the
+    /// receiver finished, e.g. because the sender gave up or the room expired.
+    Expired,
+    /// The relay does not know the transfer and keeps no history to tell why. It was
+    /// either completed, given up or never existed.
+    Unknown,
+}
+
+impl fmt::Display for TransferState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferState::Pending => write!(f, "pending, the receiver has not finished yet"),
+            TransferState::Completed => write!(f, "completed"),
+            TransferState::Expired => write!(f, "expired before the receiver finished"),
+            TransferState::Unknown => {
+                write!(f, "not known to the relay, it was completed or has expired")
+            }
+        }
+    }
+}
+
+/// Looks up the state of a transfer on the relay.
+///
+/// # Arguments
+///
+/// * `relay` - The URL of the relay.
+/// * `code` - The transfer code, with or without the PIN and the routing token.
+/// * `token` - The access token of the user. Only then the history of the user is
+///   consulted for transfers the relay no longer knows.
+///
+/// # Returns
+///
+/// The state of the transfer, or the error that kept the relay from answering.
+pub async fn transfer_state(relay: &str, code: &str, token: Option<&str>) -> Result<TransferState> {
+    let (code, route) = split_route(code.trim());
+    let (_, name) = split_code(code);
+    let http_url = replace_protocol(relay);
+
+    let found = match download_info(&http_url, name, token, route).await {
+        Ok(_) => true,
+        Err(CaesarError::TransferNotFound(_)) => false,
+        Err(error) => return Err(error),
+    };
+    let history = match token {
+        Some(token) if !found => match transfer_history(&http_url, token).await {
+            Ok(history) => Some(history),
+            // Relays without authentication keep no history
+            Err(CaesarError::Protocol(_)) => None,
+            Err(error) => return Err(error),
+        },
+        _ => None,
+    };
+
+    Ok(classify(
+        found,
+        history.as_deref(),
+        &hash_random_name(name.to_string()),
+    ))
+}
+
+/// Tells the state of a transfer from what the relay knows about it.
+///
+/// # Arguments
+///
+/// * `found` - Whether the relay still has the record of the transfer.
+/// * `history` - The history of the user, if the relay keeps one.
+/// * `hashed_name` - The name of the transfer as the relay knows it.
+fn classify(found: bool, history: Option<&[HistoryEntry]>, hashed_name: &str) -> TransferState {
+    if found {
+        return TransferState::Pending;
+    }
+
+    match history.and_then(|history| history.iter().find(|entry| entry.name == hashed_name)) {
+        Some(entry) if entry.status == TransferStatus::Completed => TransferState::Completed,
+        Some(_) => TransferState::Expired,
+        None => TransferState::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let mut completed = HistoryEntry::new("done".to_string());
+        completed.complete();
+        let history = vec![completed, HistoryEntry::new("stale".to_string())];
+
+        assert_eq!(classify(true, None, "done"), TransferState::Pending);
+        assert_eq!(
+            classify(false, Some(&history), "done"),
+            TransferState::Completed
+        );
+        assert_eq!(
+            classify(false, Some(&history), "stale"),
+            TransferState::Expired
+        );
+        assert_eq!(
+            classify(false, Some(&history), "other"),
+            TransferState::Unknown
+        );
+        assert_eq!(classify(false, None, "done"), TransferState::Unknown);
+    }
+}
@@ -0,0 +1,125 @@
+//! What a completed transfer amounted to.
+//!
+//! Both clients return a summary once every file has arrived, and print it as the last
+//! line of the transfer. The path the files took tells slow transfers over the relay
+//! apart from slow networks.
+
+use std::{fmt, time::Duration};
+
+use serde::Serialize;
+
+use crate::format::NumberFormat;
+
+/// The way the files reached the receiver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TransferPath {
+    /// The sender's local server, on the same network. The sender also counts direct
+    /// connections here, as they reach the same server.
+    Local,
+    /// A direct connection to the sender's local server, punched through the NATs of
+    /// both peers.
+    Direct,
+    /// The relay, which forwarded every chunk.
+    Relay,
+}
+
+impl fmt::Display for TransferPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferPath::Local => write!(f, "the local network"),
+            TransferPath::Direct => write!(f, "a direct connection"),
+            TransferPath::Relay => write!(f, "the relay"),
+        }
+    }
+}
+
+/// The statistics of a completed transfer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferSummary {
+    /// The number of files.
+    pub files: usize,
+    /// The bytes transferred in the last session. Bytes an earlier session already
+    /// transferred are not counted again.
+    pub bytes: u64,
+    /// How long the files took, from the list of the sender to the last chunk.
+    pub elapsed: Duration,
+    /// The path the files took.
+    ///
+    /// If `None`, it is unknown, e.g. for a session on a connection the caller opened.
+    pub path: Option<TransferPath>,
+}
+
+impl TransferSummary {
+    /// Returns the average throughput in bytes per second.
+    pub fn throughput(&self) -> u64 {
+        // Right after the start, the throughput of the first chunk would be far off
+        let seconds = self.elapsed.as_secs_f64().max(0.001);
+        (self.bytes as f64 / seconds) as u64
+    }
+
+    /// Sets the path the files took.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path the files took.
+    ///
+    /// # Returns
+    ///
+    /// The summary with the path.
+    pub fn over(self, path: TransferPath) -> TransferSummary {
+        TransferSummary {
+            path: Some(path),
+            ..self
+        }
+    }
+
+    /// Describes the transfer for the last line the clients print.
+    ///
+    /// # Arguments
+    ///
+    /// * `format` - How the sizes are written.
+    ///
+    /// # Returns
+    ///
+    /// A phrase like `3 file(s), 2.4 GiB in 1m 05s (37.8 MiB/s) over the relay`.
+    pub fn describe(&self, format: &NumberFormat) -> String {
+        let mut description = format!(
+            "{} file(s), {} in {} ({})",
+            self.files,
+            format.size(self.bytes),
+            format.duration(self.elapsed),
+            format.speed(self.bytes, self.elapsed)
+        );
+        if let Some(path) = self.path {
+            description.push_str(&format!(" over {}", path));
+        }
+        description
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe() {
+        let summary = TransferSummary {
+            files: 3,
+            bytes: 4 * 1024 * 1024,
+            elapsed: Duration::from_secs(2),
+            path: None,
+        };
+        assert_eq!(summary.throughput(), 2 * 1024 * 1024);
+        assert_eq!(
+            summary.describe(&NumberFormat::default()),
+            "3 file(s), 4.0 MiB in 2s (2.0 MiB/s)"
+        );
+        assert_eq!(
+            summary
+                .over(TransferPath::Relay)
+                .describe(&NumberFormat::default()),
+            "3 file(s), 4.0 MiB in 2s (2.0 MiB/s) over the relay"
+        );
+    }
+}
@@ -0,0 +1,170 @@
+//! Detection of system suspend and recovery of the session afterwards.
+//!
+//! When a laptop lid is closed in the middle of a transfer, the websocket usually does
+//! not survive the sleep, but neither peer notices right away. The monotonic clock
+//! stops while the system is suspended on Linux and macOS, while the wall clock keeps
+//! running, so a sudden gap between the two shows that the system was asleep. On
+//! platforms where the monotonic clock keeps running, the gap shows up as a timer tick
+//! that arrives much too late instead.
+//!
+//! Once a suspend is detected, the clients close their session on their own and
+//! re-establish it, so the transfer continues where it stopped.
+
+use std::time::{Duration, Instant, SystemTime};
+
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+/// The gap between the clocks from which on the system is considered to have slept.
+pub const SUSPEND_THRESHOLD: Duration = Duration::from_secs(10);
+
+/// How often the clocks are compared.
+const TICK: Duration = Duration::from_secs(1);
+
+/// The pause before the first attempt to re-establish a session.
+///
+/// Every further attempt waits twice as long, which gives the network time to come
+/// back after a resume and the other peer time to reconnect, without hammering a relay
+/// that is down for longer.
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+/// The longest pause between two attempts to re-establish a session.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Checks two clock readings for a suspend.
+///
+/// # Arguments
+///
+/// * `monotonic` - The time the monotonic clock advanced since the last tick.
+/// * `wall` - The time the wall clock advanced since the last tick.
+/// * `tick` - The time that was expected to pass.
+/// * `threshold` - The gap from which on a suspend is reported.
+///
+/// # Returns
+///
+/// How long the system was suspended, or `None` if the clocks agree.
+fn clock_jump(
+    monotonic: Duration,
+    wall: Duration,
+    tick: Duration,
+    threshold: Duration,
+) -> Option<Duration> {
+    // The wall clock ran on while the monotonic clock stood still
+    let stopped = wall.saturating_sub(monotonic);
+    // The monotonic clock ran on, but the process did not
+    let late = monotonic.saturating_sub(tick);
+
+    let gap = stopped.max(late);
+    (gap >= threshold).then_some(gap)
+}
+
+/// Waits until the system wakes up from a suspend.
+///
+/// A wall clock that is set forward by more than the threshold is reported as a
+/// suspend as well. This only costs an unnecessary reconnect.
+///
+/// # Arguments
+///
+/// * `threshold` - The gap from which on a suspend is reported.
+///
+/// # Returns
+///
+/// How long the system was suspended.
+pub async fn watch(threshold: Duration) -> Duration {
+    let mut monotonic = Instant::now();
+    let mut wall = SystemTime::now();
+
+    loop {
+        sleep(TICK).await;
+
+        let now = Instant::now();
+        let wall_now = SystemTime::now();
+        // A wall clock that was set back counts as no time at all
+        let wall_elapsed = wall_now.duration_since(wall).unwrap_or_default();
+
+        if let Some(gap) = clock_jump(now - monotonic, wall_elapsed, TICK, threshold) {
+            warn!("The system was suspended for {} seconds", gap.as_secs());
+            return gap;
+        }
+
+        monotonic = now;
+        wall = wall_now;
+    }
+}
+
+/// Returns the pause before an attempt to re-establish a session.
+///
+/// # Arguments
+///
+/// * `attempt` - The number of the attempt, starting at one.
+fn backoff(attempt: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+    RECONNECT_DELAY
+        .saturating_mul(factor)
+        .min(MAX_RECONNECT_DELAY)
+}
+
+/// Waits before the next attempt to re-establish a session.
+///
+/// # Arguments
+///
+/// * `attempt` - The number of the attempt, starting at one.
+/// * `cancel` - The cancellation token of the transfer.
+///
+/// # Returns
+///
+/// `false` if the transfer was cancelled while waiting.
+pub async fn reconnect_delay(attempt: u32, cancel: &CancellationToken) -> bool {
+    tokio::select! {
+        _ = sleep(backoff(attempt)) => true,
+        _ = cancel.cancelled() => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_jump() {
+        let second = Duration::from_secs(1);
+        let threshold = Duration::from_secs(10);
+
+        // Both clocks agree
+        assert_eq!(clock_jump(second, second, second, threshold), None);
+        // A small hiccup is no suspend
+        assert_eq!(clock_jump(second * 3, second * 3, second, threshold), None);
+        // The monotonic clock stopped during the suspend
+        assert_eq!(
+            clock_jump(second, second * 61, second, threshold),
+            Some(second * 60)
+        );
+        // The monotonic clock kept running, but the timer fired late
+        assert_eq!(
+            clock_jump(second * 31, second * 31, second, threshold),
+            Some(second * 30)
+        );
+        // The wall clock was set back
+        assert_eq!(clock_jump(second, Duration::ZERO, second, threshold), None);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let second = Duration::from_secs(1);
+
+        assert_eq!(backoff(1), second * 2);
+        assert_eq!(backoff(2), second * 4);
+        assert_eq!(backoff(4), second * 16);
+        // The pause stops growing at some point
+        assert_eq!(backoff(5), MAX_RECONNECT_DELAY);
+        assert_eq!(backoff(u32::MAX), MAX_RECONNECT_DELAY);
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_delay_cancelled() {
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        assert!(!reconnect_delay(1, &cancel).await);
+    }
+}
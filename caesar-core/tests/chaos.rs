@@ -0,0 +1,344 @@
+//! End-to-end transfers through a local relay with faults injected into the sender's
+//! connection. Run with `cargo test -p caesar-core --features chaos`.
+
+use std::{fs, path::PathBuf, time::Duration};
+
+use caesar_core::{
+    chaos::{ChaosProfile, ChaosTransport},
+    error::Result,
+    receiver::{http_client::download_info, start_ws_com, ReceiverOptions},
+    relay::server::RelayConfig,
+    sender::{client, SenderOptions},
+    summary::TransferSummary,
+};
+use tokio::time::{sleep, timeout};
+use tokio_tungstenite::connect_async;
+
+mod common;
+
+/// Creates an empty scratch directory for a test.
+fn scratch_dir(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("caesar-chaos-{}", name));
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).unwrap();
+    path
+}
+
+/// The key both sides of the tests authenticate the handshake with.
+const KEY: &[u8] = b"chaos-test-key";
+
+/// Sends a file through the relay, optionally with faults on the sender's connection.
+///
+/// Returns the results of the sender and the receiver.
+async fn transfer(
+    relay: &str,
+    file: &str,
+    output: &str,
+    transfer_name: &str,
+    profile: Option<ChaosProfile>,
+) -> (Result<TransferSummary>, Result<TransferSummary>) {
+    let (socket, _) = connect_async(format!("{}/ws", relay)).await.unwrap();
+    let paths = vec![file.to_string()];
+    let relay_url = relay.to_string();
+    let name = transfer_name.to_string();
+
+    let sender = tokio::spawn(async move {
+        let options = SenderOptions {
+            key: Some(KEY.to_vec()),
+            ..Default::default()
+        };
+        match profile {
+            Some(profile) => {
+                let socket = ChaosTransport::new(socket, profile);
+                client::start(socket, paths, None, relay_url, name, false, options).await
+            }
+            None => client::start(socket, paths, None, relay_url, name, false, options).await,
+        }
+    });
+
+    // Wait for the sender to announce the room
+    let http_url = relay.replace("ws://", "http://");
+    let mut room_id = String::new();
+    for _ in 0..50 {
+        if let Ok(info) = download_info(&http_url, transfer_name, None, None).await {
+            if !info.relay_room_id.is_empty() {
+                room_id = info.relay_room_id;
+                break;
+            }
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    assert!(!room_id.is_empty(), "The sender did not announce the room.");
+
+    let received = start_ws_com(
+        output.to_string(),
+        relay,
+        &room_id,
+        ReceiverOptions {
+            key: Some(KEY.to_vec()),
+            ..Default::default()
+        },
+    )
+    .await;
+    let sent = timeout(Duration::from_secs(30), sender)
+        .await
+        .unwrap()
+        .unwrap();
+
+    (sent, received)
+}
+
+/// Content that is easy to tell apart at every offset.
+fn content(size: usize) -> Vec<u8> {
+    (0..size).map(|i| (i * 31 % 251) as u8).collect()
+}
+
+#[tokio::test]
+async fn test_transfer_with_latency() {
+    let relay = common::serve_relay(RelayConfig::default());
+    let dir = scratch_dir("latency");
+    let data = content(300_000);
+    fs::write(dir.join("data.bin"), &data).unwrap();
+    fs::create_dir_all(dir.join("out")).unwrap();
+
+    let profile = ChaosProfile {
+        seed: 7,
+        max_latency: Duration::from_millis(20),
+        ..Default::default()
+    };
+    let (sent, received) = transfer(
+        &relay,
+        dir.join("data.bin").to_str().unwrap(),
+        dir.join("out").to_str().unwrap(),
+        "chaos-latency",
+        Some(profile),
+    )
+    .await;
+    assert_eq!(sent.map(|summary| summary.files), Ok(1));
+    assert_eq!(received.map(|summary| summary.files), Ok(1));
+
+    assert_eq!(fs::read(dir.join("out/data.bin")).unwrap(), data);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_resume_after_disconnect() {
+    let relay = common::serve_relay(RelayConfig::default());
+    let dir = scratch_dir("resume");
+    let data = content(1_000_000);
+    fs::write(dir.join("data.bin"), &data).unwrap();
+    fs::create_dir_all(dir.join("out")).unwrap();
+    let file = dir.join("data.bin");
+    let output = dir.join("out");
+
+    // Cut the sender off after the handshake, the list and a few chunks
+    let profile = ChaosProfile {
+        seed: 11,
+        disconnect_after: Some(8),
+        ..Default::default()
+    };
+    let (_, received) = transfer(
+        &relay,
+        file.to_str().unwrap(),
+        output.to_str().unwrap(),
+        "chaos-resume-1",
+        Some(profile),
+    )
+    .await;
+    assert!(received.is_err());
+
+    // The partial download waits in its part file
+    let partial = fs::read(output.join("data.bin.caesar.part")).unwrap();
+    assert!(!partial.is_empty() && partial.len() < data.len());
+    assert_eq!(partial[..], data[..partial.len()]);
+
+    // The second transfer continues where the first one stopped
+    let (sent, received) = transfer(
+        &relay,
+        file.to_str().unwrap(),
+        output.to_str().unwrap(),
+        "chaos-resume-2",
+        None,
+    )
+    .await;
+    assert_eq!(sent.map(|summary| summary.files), Ok(1));
+    assert_eq!(received.map(|summary| summary.files), Ok(1));
+
+    assert_eq!(fs::read(output.join("data.bin")).unwrap(), data);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_recover_after_disconnect() {
+    let relay = common::serve_relay(RelayConfig::default());
+    let dir = scratch_dir("recover");
+    let data = content(1_000_000);
+    fs::write(dir.join("data.bin"), &data).unwrap();
+    fs::create_dir_all(dir.join("out")).unwrap();
+    let file = dir.join("data.bin");
+    let output = dir.join("out");
+
+    // The sender is cut off during its first session and creates the room again, like
+    // after a suspend
+    let paths = vec![file.to_str().unwrap().to_string()];
+    let relay_url = relay.clone();
+    let sender = tokio::spawn(async move {
+        let session =
+            client::Session::with_key(Some("chaos-recover-room".to_string()), KEY.to_vec());
+        let profile = ChaosProfile {
+            seed: 13,
+            disconnect_after: Some(8),
+            ..Default::default()
+        };
+        let (socket, _) = connect_async(format!("{}/ws", relay_url)).await.unwrap();
+        let socket = ChaosTransport::new(socket, profile);
+        let name = "chaos-recover".to_string();
+        let options = SenderOptions::default();
+        let mut result = client::start_session(
+            socket,
+            paths.clone(),
+            &session,
+            relay_url.clone(),
+            name.clone(),
+            false,
+            options.clone(),
+        )
+        .await;
+        assert!(result.is_err());
+
+        // The relay may still hold the old room for a moment
+        for _ in 0..5 {
+            sleep(Duration::from_millis(500)).await;
+            let (socket, _) = connect_async(format!("{}/ws", relay_url)).await.unwrap();
+            result = client::start_session(
+                socket,
+                paths.clone(),
+                &session,
+                relay_url.clone(),
+                name.clone(),
+                false,
+                options.clone(),
+            )
+            .await;
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    });
+
+    // Wait for the sender to announce the room
+    let http_url = relay.replace("ws://", "http://");
+    let mut room_id = String::new();
+    for _ in 0..50 {
+        if let Ok(info) = download_info(&http_url, "chaos-recover", None, None).await {
+            if !info.relay_room_id.is_empty() {
+                room_id = info.relay_room_id;
+                break;
+            }
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    assert!(!room_id.is_empty(), "The sender did not announce the room.");
+
+    // The receiver rejoins the room on its own and continues the file
+    let options = ReceiverOptions {
+        reconnect: 5,
+        key: Some(KEY.to_vec()),
+        ..Default::default()
+    };
+    let received = start_ws_com(
+        output.to_str().unwrap().to_string(),
+        &relay,
+        &room_id,
+        options,
+    )
+    .await;
+    let sent = timeout(Duration::from_secs(60), sender)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(sent.map(|summary| summary.files), Ok(1));
+    assert_eq!(received.map(|summary| summary.files), Ok(1));
+
+    assert_eq!(fs::read(output.join("data.bin")).unwrap(), data);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_transfer_to_several_receivers() {
+    let relay = common::serve_relay(RelayConfig::default());
+    let dir = scratch_dir("receivers");
+    let data = content(500_000);
+    fs::write(dir.join("data.bin"), &data).unwrap();
+
+    // The sender's frames to all receivers share one delayed connection
+    let (socket, _) = connect_async(format!("{}/ws", relay)).await.unwrap();
+    let paths = vec![dir.join("data.bin").to_str().unwrap().to_string()];
+    let relay_url = relay.clone();
+    let sender = tokio::spawn(async move {
+        let profile = ChaosProfile {
+            seed: 17,
+            max_latency: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let options = SenderOptions {
+            key: Some(KEY.to_vec()),
+            receivers: 3,
+            ..Default::default()
+        };
+        let socket = ChaosTransport::new(socket, profile);
+        let name = "chaos-receivers".to_string();
+        client::start(socket, paths, None, relay_url, name, false, options).await
+    });
+
+    // Wait for the sender to announce the room
+    let http_url = relay.replace("ws://", "http://");
+    let mut room_id = String::new();
+    for _ in 0..50 {
+        if let Ok(info) = download_info(&http_url, "chaos-receivers", None, None).await {
+            if !info.relay_room_id.is_empty() {
+                room_id = info.relay_room_id;
+                break;
+            }
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    assert!(!room_id.is_empty(), "The sender did not announce the room.");
+
+    // All receivers join the same room at once
+    let receivers = (0..3).map(|receiver| {
+        let output = dir.join(format!("out-{receiver}"));
+        fs::create_dir_all(&output).unwrap();
+        let options = ReceiverOptions {
+            key: Some(KEY.to_vec()),
+            ..Default::default()
+        };
+        let relay = relay.clone();
+        let room_id = room_id.clone();
+        async move {
+            start_ws_com(
+                output.to_str().unwrap().to_string(),
+                &relay,
+                &room_id,
+                options,
+            )
+            .await
+        }
+    });
+    for received in futures_util::future::join_all(receivers).await {
+        assert_eq!(received.map(|summary| summary.files), Ok(1));
+    }
+
+    let sent = timeout(Duration::from_secs(30), sender)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(sent.map(|summary| summary.files), Ok(1));
+
+    for receiver in 0..3 {
+        let output = dir.join(format!("out-{receiver}/data.bin"));
+        assert_eq!(fs::read(output).unwrap(), data);
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,59 @@
+//! Helpers shared by the integration tests.
+
+use std::{net::SocketAddr, sync::mpsc};
+
+use axum::Router;
+use caesar_core::relay::server::{relay_state, router, RelayConfig};
+use tokio::net::TcpListener;
+
+/// Serves a relay on a free port.
+///
+/// # Arguments
+///
+/// * `config` - The configuration of the relay.
+///
+/// # Returns
+///
+/// The address of the relay.
+#[allow(dead_code)]
+pub fn serve_relay(config: RelayConfig) -> String {
+    format!("ws://{}", serve_router(config, |relay| relay))
+}
+
+/// Serves the relay routes, wrapped in another application, on a free port.
+///
+/// The relay gets its own runtime, because the sender blocks its thread while it
+/// announces the room to the relay. It accepts connections once this returns.
+///
+/// # Arguments
+///
+/// * `config` - The configuration of the relay.
+/// * `wrap` - Builds the served application from the relay routes.
+///
+/// # Returns
+///
+/// The address the application listens on.
+pub fn serve_router<F>(config: RelayConfig, wrap: F) -> SocketAddr
+where
+    F: FnOnce(Router) -> Router + Send + 'static,
+{
+    let (addresses, address) = mpsc::channel();
+    std::thread::spawn(move || {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let state = relay_state(&config).await.unwrap();
+                let app = wrap(router(state, config));
+
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                addresses.send(listener.local_addr().unwrap()).unwrap();
+                axum::serve(
+                    listener,
+                    app.into_make_service_with_connect_info::<SocketAddr>(),
+                )
+                .await
+                .unwrap();
+            })
+    });
+    address.recv().unwrap()
+}
@@ -0,0 +1,48 @@
+//! The relay routes mounted under a prefix of another axum application. Run with
+//! `cargo test -p caesar-core --test embed`.
+
+use axum::{routing::get, Router};
+use caesar_core::{
+    receiver::http_client::download_info,
+    relay::server::RelayConfig,
+    sender::{http_client::send_info, util::hash_random_name},
+};
+use tokio_tungstenite::connect_async;
+
+mod common;
+
+#[tokio::test]
+async fn test_embedded_relay() {
+    let address = common::serve_router(RelayConfig::default(), |relay| {
+        Router::new()
+            .route("/health", get(|| async { "ok" }))
+            .nest("/relay", relay)
+    });
+
+    // Clients reach the relay with the prefix as part of its address
+    let relay = format!("http://{}/relay", address);
+    send_info(
+        &relay,
+        &hash_random_name("brave-tiger-42".to_string()),
+        "room",
+        false,
+        9000,
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    let transfer = download_info(&relay, "brave-tiger-42", None, None)
+        .await
+        .unwrap();
+    assert_eq!(transfer.relay_room_id, "room");
+    assert!(connect_async(format!("ws://{}/relay/ws", address))
+        .await
+        .is_ok());
+
+    // The routes of the application stay where they are
+    let health = reqwest::get(format!("http://{}/health", address))
+        .await
+        .unwrap();
+    assert_eq!(health.text().await.unwrap(), "ok");
+}
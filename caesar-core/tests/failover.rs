@@ -0,0 +1,76 @@
+//! A transfer whose first relay is down, so sender and receiver meet on the fallback
+//! relay. Run with `cargo test -p caesar-core --test failover`.
+
+use std::{sync::Arc, time::Duration};
+
+use caesar_core::{
+    receiver::{execute, resolve, ReceiverOptions},
+    relay::server::RelayConfig,
+    sender::{start_sender, util::generate_mailbox, SenderOptions},
+};
+use tokio::net::TcpListener;
+
+mod common;
+
+/// Finds an address nothing listens on.
+///
+/// # Returns
+///
+/// The address of a relay that is down.
+async fn dead_relay() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    format!("ws://{}", listener.local_addr().unwrap())
+}
+
+#[tokio::test]
+async fn test_fallback_relay() {
+    let dead = dead_relay().await;
+    let relay = common::serve_relay(RelayConfig::default());
+    let dir = std::env::temp_dir().join(format!("caesar-failover-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("out")).unwrap();
+    let path = dir.join("notes.txt");
+    std::fs::write(&path, b"meeting at noon").unwrap();
+
+    // A mailbox spares reading the PIN the sender prints
+    let (name, key) = generate_mailbox();
+    let sender = tokio::spawn(start_sender(
+        name.clone(),
+        Arc::new(dead.clone()),
+        Arc::new(vec![path.to_string_lossy().to_string()]),
+        SenderOptions {
+            key: Some(key.clone()),
+            fallback_relays: vec![relay.clone()],
+            punch: false,
+            ..SenderOptions::default()
+        },
+    ));
+
+    let options = ReceiverOptions {
+        key: Some(key),
+        fallback_relays: vec![relay.clone()],
+        punch: false,
+        ..ReceiverOptions::default()
+    };
+    // The sender may not have published the transfer yet
+    let mut plan = resolve(&dead, &name, &options).await;
+    for _ in 0..50 {
+        if plan.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        plan = resolve(&dead, &name, &options).await;
+    }
+    let plan = plan.unwrap();
+    assert_eq!(plan.relay.as_deref(), Some(relay.as_str()));
+
+    let out = dir.join("out");
+    execute(out.to_string_lossy().to_string(), &plan, options)
+        .await
+        .unwrap();
+    sender.await.unwrap().unwrap();
+    assert_eq!(
+        std::fs::read(out.join("notes.txt")).unwrap(),
+        b"meeting at noon"
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,77 @@
+//! A transfer whose sender is on a public relay and whose receiver asks an internal
+//! relay, which forwards the room to the public one. Run with
+//! `cargo test -p caesar-core --test federation`.
+
+use std::{sync::Arc, time::Duration};
+
+use caesar_core::{
+    error::CaesarError,
+    receiver::{execute, plan::Endpoint, resolve, ReceiverOptions},
+    relay::server::RelayConfig,
+    sender::{start_sender, util::generate_mailbox, SenderOptions},
+};
+
+mod common;
+
+#[tokio::test]
+async fn test_forward_to_upstream() {
+    let public = common::serve_relay(RelayConfig::default());
+    let internal = common::serve_relay(RelayConfig {
+        upstream: Some(public.clone()),
+        ..RelayConfig::default()
+    });
+    let dir = std::env::temp_dir().join(format!("caesar-federation-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("out")).unwrap();
+    let path = dir.join("notes.txt");
+    std::fs::write(&path, b"meeting at noon").unwrap();
+
+    // A mailbox spares reading the PIN the sender prints
+    let (name, key) = generate_mailbox();
+    let sender = tokio::spawn(start_sender(
+        name.clone(),
+        Arc::new(public.clone()),
+        Arc::new(vec![path.to_string_lossy().to_string()]),
+        SenderOptions {
+            key: Some(key.clone()),
+            punch: false,
+            ..SenderOptions::default()
+        },
+    ));
+
+    let options = ReceiverOptions {
+        key: Some(key),
+        punch: false,
+        ..ReceiverOptions::default()
+    };
+    // The sender may not have published the transfer yet
+    let mut plan = resolve(&internal, &name, &options).await;
+    for _ in 0..50 {
+        if plan.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        plan = resolve(&internal, &name, &options).await;
+    }
+    let mut plan = plan.unwrap();
+    assert_eq!(plan.relay.as_deref(), Some(internal.as_str()));
+    // The sender's local server would be reached without any relay
+    plan.endpoints
+        .retain(|endpoint| matches!(endpoint, Endpoint::Relay { .. }));
+
+    let out = dir.join("out");
+    execute(out.to_string_lossy().to_string(), &plan, options.clone())
+        .await
+        .unwrap();
+    sender.await.unwrap().unwrap();
+    assert_eq!(
+        std::fs::read(out.join("notes.txt")).unwrap(),
+        b"meeting at noon"
+    );
+
+    // The completion reached the public relay through the internal one
+    assert!(matches!(
+        resolve(&public, &name, &options).await,
+        Err(CaesarError::TransferNotFound(_))
+    ));
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,78 @@
+//! A download link created by a sender and used once, like the page behind it does. Run
+//! with `cargo test -p caesar-core --test link`.
+
+use aes_gcm::{aead::Aead, Aes128Gcm, Key, KeyInit, Nonce};
+use base64::{engine::general_purpose, Engine as _};
+use caesar_core::{error::CaesarError, link::create_link, relay::server::RelayConfig};
+
+mod common;
+
+/// Serves a relay that takes download links up to the given size.
+///
+/// # Arguments
+///
+/// * `link_size` - The largest file the relay takes for a download link.
+///
+/// # Returns
+///
+/// The address of the relay.
+fn serve_relay(link_size: u64) -> String {
+    common::serve_relay(RelayConfig {
+        link_size,
+        ..RelayConfig::default()
+    })
+}
+
+#[tokio::test]
+async fn test_download_link() {
+    let relay = serve_relay(1024 * 1024);
+    let path = std::env::temp_dir().join("caesar-link-report.txt");
+    std::fs::write(&path, b"quarterly numbers").unwrap();
+
+    let link = create_link(&relay, path.to_str().unwrap(), None)
+        .await
+        .unwrap();
+    let (page, key) = link.split_once('#').unwrap();
+    assert!(page.starts_with("http://127.0.0.1:"));
+
+    // The page is served to everyone and does not use up the link
+    let html = reqwest::get(page).await.unwrap();
+    assert!(html.status().is_success());
+    assert!(html.text().await.unwrap().contains("crypto.subtle"));
+
+    // The file is handed out once and opens with the key of the fragment
+    let sealed = reqwest::get(format!("{page}/data"))
+        .await
+        .unwrap()
+        .bytes()
+        .await
+        .unwrap();
+    let key = general_purpose::URL_SAFE_NO_PAD.decode(key).unwrap();
+    let (nonce, encrypted) = sealed.split_at(12);
+    let plain = Aes128Gcm::new(Key::<Aes128Gcm>::from_slice(&key))
+        .decrypt(Nonce::from_slice(nonce), encrypted)
+        .unwrap();
+    let length = u16::from_be_bytes([plain[0], plain[1]]) as usize;
+    assert_eq!(&plain[2..2 + length], b"caesar-link-report.txt");
+    assert_eq!(&plain[2 + length..], b"quarterly numbers");
+
+    let again = reqwest::get(format!("{page}/data")).await.unwrap();
+    assert_eq!(again.status(), reqwest::StatusCode::NOT_FOUND);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn test_download_link_refused() {
+    let path = std::env::temp_dir().join("caesar-link-large.bin");
+    std::fs::write(&path, vec![7u8; 4096]).unwrap();
+    let path = path.to_str().unwrap();
+
+    // A relay without download links, and one that takes smaller files only
+    let error = create_link(&serve_relay(0), path, None).await.unwrap_err();
+    assert!(matches!(error, CaesarError::Refused(_)));
+    let error = create_link(&serve_relay(1024), path, None)
+        .await
+        .unwrap_err();
+    assert!(error.to_string().contains("larger"));
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,159 @@
+//! Compatibility of the packets with the released versions of the protocol.
+//!
+//! The fixtures under `tests/fixtures/<version>/` are packets as clients of that version
+//! send them. Each `.bin` file was encoded from the `.txtpb` file next to it with the
+//! schema of that version:
+//!
+//! ```sh
+//! protoc --proto_path=proto/history/v1 --encode=Packets.Packet packets.proto \
+//!     < tests/fixtures/v1/list.txtpb > tests/fixtures/v1/list.bin
+//! ```
+
+use std::{fs, path::PathBuf};
+
+use caesar_core::shared::packets::{
+    list_packet::Entry, packet::Value, Cipher, Compression, CreditPacket, Framing, HandshakePacket,
+    HashAlgorithm, KeySchedule, ListPacket, NonceScheme, Packet,
+};
+use prost::Message;
+
+/// The packets of version 1, generated by the build script from `proto/history/v1`.
+mod v1 {
+    include!(concat!(env!("OUT_DIR"), "/history/v1/packets.rs"));
+}
+
+/// Reads a fixture.
+///
+/// # Arguments
+///
+/// * `version` - The protocol version the fixture was encoded with.
+/// * `name` - The name of the fixture without its extension.
+fn fixture(version: &str, name: &str) -> Vec<u8> {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(version)
+        .join(format!("{name}.bin"));
+    fs::read(&path).unwrap_or_else(|error| panic!("cannot read {}: {error}", path.display()))
+}
+
+#[test]
+fn test_decode_v1_fixtures() {
+    for name in [
+        "handshake",
+        "handshake_response",
+        "list",
+        "progress",
+        "chunk",
+    ] {
+        let bytes = fixture("v1", name);
+        let packet = Packet::decode(bytes.as_slice()).unwrap();
+        assert!(packet.value.is_some(), "{name} decoded to an empty packet");
+
+        // Nothing of an old packet is lost, so it is encoded to the same bytes again
+        assert_eq!(packet.encode_to_vec(), bytes, "{name} did not round-trip");
+    }
+
+    // The fields that were added since take the defaults of a version 1 peer
+    let Some(Value::Handshake(handshake)) = Packet::decode(fixture("v1", "handshake").as_slice())
+        .unwrap()
+        .value
+    else {
+        panic!("handshake fixture is not a handshake");
+    };
+    assert_eq!(handshake.public_key.len(), 32);
+    assert_eq!(handshake.compressions().count(), 0);
+    assert_eq!(handshake.framings().count(), 0);
+
+    let Some(Value::HandshakeResponse(response)) =
+        Packet::decode(fixture("v1", "handshake_response").as_slice())
+            .unwrap()
+            .value
+    else {
+        panic!("handshake_response fixture is not a handshake response");
+    };
+    assert_eq!(response.compression(), Compression::None);
+    assert_eq!(response.framing(), Framing::V1);
+
+    let Some(Value::List(list)) = Packet::decode(fixture("v1", "list").as_slice())
+        .unwrap()
+        .value
+    else {
+        panic!("list fixture is not a list");
+    };
+    assert_eq!(list.hash_algorithm(), HashAlgorithm::Sha256);
+    assert_eq!(list.entries.len(), 2);
+    assert_eq!(list.entries[0].name, "backup.tar");
+    assert_eq!(list.entries[0].size, 1048576);
+    assert_eq!(list.entries[1].name, "notes/räume.txt");
+    assert!(list.entries[1].hash.is_empty());
+
+    let Some(Value::Chunk(chunk)) = Packet::decode(fixture("v1", "chunk").as_slice())
+        .unwrap()
+        .value
+    else {
+        panic!("chunk fixture is not a chunk");
+    };
+    assert_eq!(chunk.sequence, 7);
+    assert_eq!(&chunk.chunk[..], b"Hello, world");
+}
+
+#[test]
+fn test_current_packets_decode_as_v1() {
+    // A version 1 peer skips the fields it does not know and reads the rest
+    let handshake = Packet {
+        value: Some(Value::Handshake(HandshakePacket {
+            public_key: vec![1; 32],
+            signature: vec![2; 32],
+            compressions: vec![Compression::Zstd.into()],
+            framings: vec![Framing::V2.into()],
+            heartbeat: true,
+            key_schedules: vec![KeySchedule::Hkdf.into()],
+            ciphers: vec![Cipher::Chacha20Poly1305.into()],
+            nonce_schemes: vec![NonceScheme::Counter.into()],
+            delta: true,
+            streams: 4,
+        })),
+    };
+    let old = v1::Packet::decode(handshake.encode_to_vec().as_slice()).unwrap();
+    let Some(v1::packet::Value::Handshake(old)) = old.value else {
+        panic!("handshake was not decoded as a handshake");
+    };
+    assert_eq!(old.public_key, vec![1; 32]);
+    assert_eq!(old.signature, vec![2; 32]);
+
+    let list = Packet {
+        value: Some(Value::List(ListPacket {
+            entries: vec![Entry {
+                index: 3,
+                size: 4096,
+                name: "report.pdf".to_string(),
+                hash: vec![0xab; 32],
+                stream: false,
+                archive: false,
+                modified: 1_700_000_000_000_000_000,
+                link: String::new(),
+            }],
+            hash_algorithm: HashAlgorithm::Blake3.into(),
+        })),
+    };
+    let old = v1::Packet::decode(list.encode_to_vec().as_slice()).unwrap();
+    assert_eq!(
+        old,
+        v1::Packet {
+            value: Some(v1::packet::Value::List(v1::ListPacket {
+                entries: vec![v1::list_packet::Entry {
+                    index: 3,
+                    size: 4096,
+                    name: "report.pdf".to_string(),
+                }],
+            })),
+        }
+    );
+
+    // Packets that version 1 does not know decode to an empty packet instead of failing
+    let credit = Packet {
+        value: Some(Value::Credit(CreditPacket { chunks: 16 })),
+    };
+    let old = v1::Packet::decode(credit.encode_to_vec().as_slice()).unwrap();
+    assert_eq!(old.value, None);
+}
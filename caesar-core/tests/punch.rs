@@ -0,0 +1,62 @@
+//! Hole punching between two peers on the same host through a local relay. Run with
+//! `cargo test -p caesar-core --test punch`.
+
+use std::time::Duration;
+
+use caesar_core::{
+    punch::{punch, PUNCH_TIMEOUT},
+    relay::server::RelayConfig,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::{sleep, timeout},
+};
+
+mod common;
+
+#[tokio::test]
+async fn test_punch() {
+    let relay = common::serve_relay(RelayConfig::default());
+
+    // The sender waits at the rendezvous before the receiver shows up
+    let sender_relay = relay.clone();
+    let sender = tokio::spawn(async move {
+        let mut streams = punch(&sender_relay, "room", None, None, None, true)
+            .await
+            .unwrap();
+        let mut stream = streams.recv().await.unwrap();
+        let mut greeting = [0; 5];
+        stream.read_exact(&mut greeting).await.unwrap();
+        greeting
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    let mut streams = punch(&relay, "room", None, None, Some(PUNCH_TIMEOUT), false)
+        .await
+        .unwrap();
+    let mut stream = streams.recv().await.unwrap();
+    stream.write_all(b"hello").await.unwrap();
+
+    let greeting = timeout(Duration::from_secs(10), sender)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&greeting, b"hello");
+}
+
+#[tokio::test]
+async fn test_punch_without_peer() {
+    let relay = common::serve_relay(RelayConfig::default());
+
+    // Nobody waits in the room, so the receiver gives up and takes the relay
+    let result = punch(
+        &relay,
+        "empty-room",
+        None,
+        None,
+        Some(Duration::from_millis(300)),
+        false,
+    )
+    .await;
+    assert!(result.is_err());
+}
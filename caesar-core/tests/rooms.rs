@@ -0,0 +1,110 @@
+//! Routing between the members of a relay room for several receivers. Run with
+//! `cargo test -p caesar-core --test rooms`.
+
+use std::time::Duration;
+
+use caesar_core::{
+    relay::{server::RelayConfig, Priority},
+    shared::{JsonPacket, JsonPacketResponse},
+};
+use futures_util::{SinkExt, StreamExt};
+use tokio::{net::TcpStream, time::timeout};
+use tokio_tungstenite::{
+    connect_async, tungstenite::protocol::Message, MaybeTlsStream, WebSocketStream,
+};
+
+mod common;
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Sends a JSON packet to the relay.
+async fn send(socket: &mut Socket, packet: JsonPacket) {
+    let packet = serde_json::to_string(&packet).unwrap();
+    socket.send(Message::Text(packet)).await.unwrap();
+}
+
+/// Returns the next message from the relay.
+async fn next(socket: &mut Socket) -> Message {
+    timeout(Duration::from_secs(5), socket.next())
+        .await
+        .expect("The relay did not answer.")
+        .unwrap()
+        .unwrap()
+}
+
+/// Returns the next JSON packet from the relay.
+async fn next_json(socket: &mut Socket) -> JsonPacketResponse {
+    match next(socket).await {
+        Message::Text(text) => serde_json::from_str(&text).unwrap(),
+        message => panic!("Expected a JSON packet, got {:?}", message),
+    }
+}
+
+#[tokio::test]
+async fn test_room_routing() {
+    let relay = format!("{}/ws", common::serve_relay(RelayConfig::default()));
+
+    // The sender creates a room for three receivers
+    let (mut sender, _) = connect_async(&relay).await.unwrap();
+    send(
+        &mut sender,
+        JsonPacket::Create {
+            id: None,
+            priority: Priority::default(),
+            size: Some(4),
+        },
+    )
+    .await;
+    let JsonPacketResponse::Create { id } = next_json(&mut sender).await else {
+        panic!("The room was not created.");
+    };
+
+    // Every member learns the position of a receiver that joins
+    let mut receivers: Vec<Socket> = vec![];
+    for position in 1..=3 {
+        let (mut receiver, _) = connect_async(&relay).await.unwrap();
+        send(&mut receiver, JsonPacket::Join { id: id.clone() }).await;
+        assert!(matches!(
+            next_json(&mut receiver).await,
+            JsonPacketResponse::Join { size: Some(size), index: Some(index) }
+                if size == position && index == position
+        ));
+        for member in receivers.iter_mut().chain([&mut sender]) {
+            assert!(matches!(
+                next_json(member).await,
+                JsonPacketResponse::Join { size: None, index: Some(index) } if index == position
+            ));
+        }
+        receivers.push(receiver);
+    }
+
+    // A frame reaches the member at its destination, which reads the source instead
+    sender.send(Message::Binary(vec![2, 42])).await.unwrap();
+    assert_eq!(next(&mut receivers[1]).await, Message::Binary(vec![0, 42]));
+    receivers[2]
+        .send(Message::Binary(vec![0, 43]))
+        .await
+        .unwrap();
+    assert_eq!(next(&mut sender).await, Message::Binary(vec![3, 43]));
+
+    // A broadcast reaches every other member
+    sender
+        .send(Message::Binary(vec![u8::MAX, 44]))
+        .await
+        .unwrap();
+    for receiver in &mut receivers {
+        assert_eq!(next(receiver).await, Message::Binary(vec![0, 44]));
+    }
+
+    // The receivers behind one that leaves move up
+    let mut first = receivers.remove(0);
+    send(&mut first, JsonPacket::Leave).await;
+    for member in receivers.iter_mut().chain([&mut sender]) {
+        assert!(matches!(
+            next_json(member).await,
+            JsonPacketResponse::Leave { index: 1 }
+        ));
+    }
+    sender.send(Message::Binary(vec![2, 45])).await.unwrap();
+    assert_eq!(next(&mut receivers[1]).await, Message::Binary(vec![0, 45]));
+}
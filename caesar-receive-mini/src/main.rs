@@ -0,0 +1,144 @@
+//! A minimal receiver for small ARM boards and other embedded devices.
+//!
+//! It is built from the same core as the `caesar` binary, but without the relay
+//! server, the sender's local server and the QR codes, so it only pulls in what a
+//! receiver needs. Build it with the `mini` profile for the smallest binary:
+//!
+//! ```sh
+//! cargo build -p caesar-receive-mini --profile mini --target aarch64-unknown-linux-gnu
+//! ```
+
+use std::time::Duration;
+
+use caesar_core::{
+    error::CaesarError,
+    format::parse_size,
+    idle::IdleTimeout,
+    receiver::{self, ConflictPolicy, ReceiverOptions},
+};
+use clap::Parser;
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+use tracing_subscriber::filter::EnvFilter;
+
+/// Receives files from a caesar sender.
+#[derive(Parser, Debug)]
+#[command(version = env!("CARGO_PKG_VERSION"), about = "Receive files securely")]
+struct Args {
+    /// Transfer code printed by the sender, like 482913-brave-tiger-42
+    #[arg(value_name = "Transfer_Name")]
+    name: String,
+
+    /// Address of the relay server. Accepted formats are: 127.0.0.1:8080, [::1]:8080, example.com, wss://example.com
+    #[arg(short, long, default_value = "wss://caesar-transfer-iu.shuttleapp.rs")]
+    relay: String,
+
+    /// Directory to save the received files in. It is created if it does not exist
+    #[arg(short, long, default_value = ".")]
+    output: String,
+
+    /// What to do when a received file already exists in the output directory
+    #[arg(long, value_enum, default_value = "abort")]
+    on_conflict: ConflictPolicy,
+
+    /// Seconds without data before a stalled transfer is closed, 0 to wait forever
+    #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+    idle_timeout: u64,
+
+    /// Times to reconnect after a suspend or a dropped connection, 0 to give up at once
+    #[arg(long, value_name = "COUNT", default_value_t = 5)]
+    reconnect: u32,
+
+    /// Access token for a relay that requires authentication
+    #[arg(long, value_name = "TOKEN")]
+    token: Option<String>,
+
+    /// Refuse transfers with a file larger than this, like 500M or 2GiB
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_file_size: Option<u64>,
+
+    /// Refuse transfers whose files are larger than this together, like 500M or 2GiB
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_total_size: Option<u64>,
+}
+
+/// Entry point of the receiver.
+///
+/// A single threaded runtime is enough for one transfer and keeps the memory use low.
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    // It configures the logging level based on the `RUST_LOG` environment variable.
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
+
+    let args = Args::parse();
+
+    #[cfg(feature = "detect-accel")]
+    if !caesar_core::cipher::has_aes_instructions() {
+        println!(
+            "Note: This CPU has no AES instructions, transfers use ChaCha20-Poly1305 if the sender supports it."
+        );
+    }
+
+    println!("Receive for {:?}", args.name);
+    let result = receiver::start_receiver(
+        args.output,
+        &args.relay,
+        &args.name,
+        ReceiverOptions {
+            on_conflict: args.on_conflict,
+            cancel: cancel_on_ctrl_c(),
+            idle: IdleTimeout::new(Duration::from_secs(args.idle_timeout)),
+            reconnect: args.reconnect,
+            token: args.token,
+            route: None,
+            fallback_relays: Vec::new(),
+            key: None,
+            save_text: false,
+            preserve_times: false,
+            delta: false,
+            links: false,
+            max_file_size: args.max_file_size,
+            max_total_size: args.max_total_size,
+            stdout: false,
+            discover: false,
+            punch: true,
+            quic: true,
+            select: None,
+            confirm: None,
+            progress: None,
+            events: None,
+        },
+    )
+    .await;
+
+    if let Err(error) = result {
+        debug!("Transfer failed: {error:?}");
+
+        // The receiver prints all other errors itself
+        if let CaesarError::RelayUnreachable { .. }
+        | CaesarError::TransferNotFound(_)
+        | CaesarError::Refused(_) = error
+        {
+            println!("Error: {error}");
+        }
+        std::process::exit(1);
+    }
+}
+
+/// Creates a token that is cancelled when the user presses Ctrl-C.
+///
+/// # Returns
+///
+/// The cancellation token for the receiver options.
+fn cancel_on_ctrl_c() -> CancellationToken {
+    let cancel = CancellationToken::new();
+    let token = cancel.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            token.cancel();
+        }
+    });
+    cancel
+}
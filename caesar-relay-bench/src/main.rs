@@ -0,0 +1,86 @@
+use std::time::{Duration, Instant};
+
+use caesar_core::sender::util::websocket_url;
+use clap::Parser;
+use futures_util::future::join_all;
+use tracing_subscriber::filter::EnvFilter;
+
+use crate::room::{run_room, RoomOptions};
+use crate::stats::{Report, RoomResult};
+
+mod room;
+mod stats;
+
+/// Load test for a caesar relay server.
+///
+/// Simulates many concurrent rooms, each with a sender pushing synthetic chunks to a
+/// receiver, and reports latency percentiles and error rates.
+#[derive(Parser, Debug)]
+#[command(version = env!("CARGO_PKG_VERSION"), about = "Load test a caesar relay server")]
+struct Args {
+    /// Address of the relay server, e.g. ws://127.0.0.1:8000
+    #[arg(short, long, default_value = "ws://127.0.0.1:8000")]
+    relay: String,
+
+    /// Number of concurrent rooms
+    #[arg(long, default_value_t = 100)]
+    rooms: usize,
+
+    /// Number of chunks sent per room
+    #[arg(long, default_value_t = 100)]
+    chunks: u64,
+
+    /// Payload size of a chunk in bytes
+    #[arg(long, default_value_t = 64 * 1024)]
+    chunk_size: usize,
+
+    /// Pause between two chunks of the same room in milliseconds
+    #[arg(long, default_value_t = 0)]
+    interval: u64,
+
+    /// Seconds a room may take before it counts as failed
+    #[arg(long, default_value_t = 60)]
+    timeout: u64,
+}
+
+/// Entry point of the load test.
+///
+/// Starts all rooms at once, waits for them to finish and prints the report.
+#[tokio::main]
+async fn main() {
+    // Initialize the logging subscriber.
+    // It configures the logging level based on the `RUST_LOG` environment variable.
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
+
+    let args = Args::parse();
+    let options = RoomOptions {
+        url: websocket_url(&args.relay),
+        chunks: args.chunks,
+        chunk_size: args.chunk_size,
+        interval: Duration::from_millis(args.interval),
+        timeout: Duration::from_secs(args.timeout),
+    };
+
+    println!(
+        "Simulating {} rooms with {} chunks of {} bytes against {}...",
+        args.rooms, args.chunks, args.chunk_size, options.url
+    );
+
+    // Run all rooms concurrently
+    let epoch = Instant::now();
+    let rooms = (0..args.rooms).map(|_| tokio::spawn(run_room(options.clone(), epoch)));
+    let results = join_all(rooms)
+        .await
+        .into_iter()
+        .map(|result| {
+            result.unwrap_or_else(|e| RoomResult {
+                error: Some(e.to_string()),
+                ..Default::default()
+            })
+        })
+        .collect();
+
+    Report::new(results, epoch.elapsed(), args.chunk_size).print();
+}
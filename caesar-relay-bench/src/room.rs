@@ -0,0 +1,233 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Result};
+use caesar_core::{
+    relay::Priority,
+    shared::{JsonPacket, JsonPacketResponse, Socket},
+};
+use futures_util::{SinkExt, StreamExt};
+use tokio::time::{sleep, timeout};
+use tokio_tungstenite::{connect_async, tungstenite::protocol::Message as WebSocketMessage};
+use tracing::debug;
+
+use crate::stats::RoomResult;
+
+/// The index of the receiver in a room, used as the destination of every chunk.
+const RECEIVER_INDEX: u8 = 1;
+
+/// The number of bytes at the start of a chunk that carry the send timestamp.
+const TIMESTAMP_SIZE: usize = 8;
+
+/// Settings shared by all simulated rooms.
+///
+/// # Fields
+///
+/// - `url`: The WebSocket URL of the relay.
+/// - `chunks`: The number of chunks sent per room.
+/// - `chunk_size`: The payload size of a single chunk in bytes.
+/// - `interval`: The pause between two chunks of the same room.
+/// - `timeout`: The time a room may take before it counts as failed.
+#[derive(Debug, Clone)]
+pub struct RoomOptions {
+    /// The WebSocket URL of the relay.
+    pub url: String,
+
+    /// The number of chunks sent per room.
+    pub chunks: u64,
+
+    /// The payload size of a single chunk in bytes.
+    pub chunk_size: usize,
+
+    /// The pause between two chunks of the same room.
+    pub interval: Duration,
+
+    /// The time a room may take before it counts as failed.
+    pub timeout: Duration,
+}
+
+/// Simulates a single transfer through the relay.
+///
+/// A sender creates a room, a receiver joins it and the sender then pushes synthetic
+/// chunks to the receiver. Each chunk carries the time it was sent relative to `epoch`,
+/// so the receiver can measure how long the relay took to forward it.
+///
+/// # Arguments
+///
+/// * `options` - The settings of the simulation.
+/// * `epoch` - The common reference point for timestamps.
+///
+/// # Returns
+///
+/// The measured latencies, the number of chunks sent and the error that ended the room, if any.
+pub async fn run_room(options: RoomOptions, epoch: Instant) -> RoomResult {
+    let mut result = RoomResult::default();
+
+    let outcome = timeout(options.timeout, simulate(&options, epoch, &mut result)).await;
+    match outcome {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => result.error = Some(e.to_string()),
+        Err(_) => result.error = Some("Timed out.".into()),
+    }
+
+    if let Some(error) = &result.error {
+        debug!("Room failed: {}", error);
+    }
+
+    result
+}
+
+/// Runs the create, join and transfer steps of a room.
+///
+/// # Arguments
+///
+/// * `options` - The settings of the simulation.
+/// * `epoch` - The common reference point for timestamps.
+/// * `result` - The result the measurements are recorded in.
+///
+/// # Errors
+///
+/// Returns an error if a connection fails or the relay answers with an error.
+async fn simulate(options: &RoomOptions, epoch: Instant, result: &mut RoomResult) -> Result<()> {
+    // Create the room as the sender
+    let (mut sender, _) = connect_async(options.url.as_str()).await?;
+    send_json(
+        &mut sender,
+        JsonPacket::Create {
+            id: None,
+            priority: Priority::default(),
+            size: None,
+        },
+    )
+    .await?;
+    let id = match next_json(&mut sender).await? {
+        JsonPacketResponse::Create { id } => id,
+        response => bail!("Unexpected response to create: {:?}", response),
+    };
+
+    // Join the room as the receiver
+    let (mut receiver, _) = connect_async(options.url.as_str()).await?;
+    send_json(&mut receiver, JsonPacket::Join { id }).await?;
+    match next_json(&mut receiver).await? {
+        JsonPacketResponse::Join { size: Some(_), .. } => {}
+        response => bail!("Unexpected response to join: {:?}", response),
+    }
+    match next_json(&mut sender).await? {
+        JsonPacketResponse::Join { size: None, .. } => {}
+        response => bail!("Unexpected join notification: {:?}", response),
+    }
+
+    // Send and receive the chunks at the same time
+    let send = send_chunks(&mut sender, options, epoch, &mut result.sent);
+    let receive = receive_chunks(&mut receiver, options.chunks, epoch, &mut result.latencies);
+    let (sent, received) = tokio::join!(send, receive);
+
+    let _ = sender.close(None).await;
+    let _ = receiver.close(None).await;
+
+    sent.and(received)
+}
+
+/// Sends the synthetic chunks of a room.
+///
+/// # Arguments
+///
+/// * `socket` - The WebSocket connection of the sender.
+/// * `options` - The settings of the simulation.
+/// * `epoch` - The common reference point for timestamps.
+/// * `sent` - The counter of sent chunks.
+///
+/// # Errors
+///
+/// Returns an error if a chunk could not be sent.
+async fn send_chunks(
+    socket: &mut Socket,
+    options: &RoomOptions,
+    epoch: Instant,
+    sent: &mut u64,
+) -> Result<()> {
+    let mut chunk = vec![0u8; 1 + options.chunk_size.max(TIMESTAMP_SIZE)];
+    chunk[0] = RECEIVER_INDEX;
+
+    for _ in 0..options.chunks {
+        // Stamp the chunk with the time it is sent
+        let timestamp = epoch.elapsed().as_micros() as u64;
+        chunk[1..=TIMESTAMP_SIZE].copy_from_slice(&timestamp.to_le_bytes());
+
+        socket.send(WebSocketMessage::Binary(chunk.clone())).await?;
+        *sent += 1;
+
+        if !options.interval.is_zero() {
+            sleep(options.interval).await;
+        }
+    }
+
+    Ok(())
+}
+
+/// Receives the synthetic chunks of a room and records their latency.
+///
+/// # Arguments
+///
+/// * `socket` - The WebSocket connection of the receiver.
+/// * `chunks` - The number of chunks to wait for.
+/// * `epoch` - The common reference point for timestamps.
+/// * `latencies` - The list the latencies are recorded in.
+///
+/// # Errors
+///
+/// Returns an error if the connection fails or closes before all chunks arrived.
+async fn receive_chunks(
+    socket: &mut Socket,
+    chunks: u64,
+    epoch: Instant,
+    latencies: &mut Vec<Duration>,
+) -> Result<()> {
+    while (latencies.len() as u64) < chunks {
+        let Some(message) = socket.next().await else {
+            bail!("Connection closed after {} chunks.", latencies.len());
+        };
+
+        let WebSocketMessage::Binary(data) = message? else {
+            continue;
+        };
+        if data.len() < 1 + TIMESTAMP_SIZE {
+            bail!("Received a chunk of {} bytes.", data.len());
+        }
+
+        let mut timestamp = [0u8; TIMESTAMP_SIZE];
+        timestamp.copy_from_slice(&data[1..=TIMESTAMP_SIZE]);
+        let sent_at = Duration::from_micros(u64::from_le_bytes(timestamp));
+
+        latencies.push(epoch.elapsed().saturating_sub(sent_at));
+    }
+
+    Ok(())
+}
+
+/// Sends a JSON packet to the relay.
+async fn send_json(socket: &mut Socket, packet: JsonPacket) -> Result<()> {
+    let text = serde_json::to_string(&packet)?;
+    socket.send(WebSocketMessage::Text(text)).await?;
+
+    Ok(())
+}
+
+/// Waits for the next JSON packet from the relay.
+///
+/// # Errors
+///
+/// Returns an error if the connection closes or the relay answers with an error packet.
+async fn next_json(socket: &mut Socket) -> Result<JsonPacketResponse> {
+    while let Some(message) = socket.next().await {
+        let WebSocketMessage::Text(text) = message? else {
+            continue;
+        };
+
+        return match serde_json::from_str(&text)? {
+            JsonPacketResponse::Error { message } => Err(anyhow!(message)),
+            response => Ok(response),
+        };
+    }
+
+    bail!("Connection closed by the relay.")
+}
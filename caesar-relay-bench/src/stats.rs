@@ -0,0 +1,192 @@
+use std::time::Duration;
+
+/// The outcome of a single simulated room.
+///
+/// # Fields
+///
+/// - `latencies`: The time each received chunk spent between sender and receiver.
+/// - `sent`: The number of chunks the sender pushed to the relay.
+/// - `error`: The error that ended the room early, if any.
+#[derive(Debug, Default)]
+pub struct RoomResult {
+    /// The time each received chunk spent between sender and receiver.
+    pub latencies: Vec<Duration>,
+
+    /// The number of chunks the sender pushed to the relay.
+    pub sent: u64,
+
+    /// The error that ended the room early, if any.
+    pub error: Option<String>,
+}
+
+/// Aggregated results of a load test run.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// The number of rooms that were simulated.
+    pub rooms: usize,
+
+    /// The number of rooms that failed.
+    pub failed_rooms: usize,
+
+    /// The number of chunks sent.
+    pub sent: u64,
+
+    /// The number of chunks received.
+    pub received: u64,
+
+    /// The latencies of all received chunks, sorted in ascending order.
+    pub latencies: Vec<Duration>,
+
+    /// The wall clock time of the whole run.
+    pub elapsed: Duration,
+
+    /// The payload size of a single chunk in bytes.
+    pub chunk_size: usize,
+}
+
+impl Report {
+    /// Builds a report from the results of all rooms.
+    ///
+    /// # Arguments
+    ///
+    /// * `results` - The results of the simulated rooms.
+    /// * `elapsed` - The wall clock time of the whole run.
+    /// * `chunk_size` - The payload size of a single chunk in bytes.
+    ///
+    /// # Returns
+    ///
+    /// The aggregated report.
+    pub fn new(results: Vec<RoomResult>, elapsed: Duration, chunk_size: usize) -> Report {
+        let mut report = Report {
+            rooms: results.len(),
+            elapsed,
+            chunk_size,
+            ..Default::default()
+        };
+
+        for result in results {
+            if result.error.is_some() {
+                report.failed_rooms += 1;
+            }
+            report.sent += result.sent;
+            report.received += result.latencies.len() as u64;
+            report.latencies.extend(result.latencies);
+        }
+        report.latencies.sort_unstable();
+
+        report
+    }
+
+    /// Returns the latency at the given percentile.
+    ///
+    /// # Arguments
+    ///
+    /// * `percentile` - The percentile between 0 and 100.
+    ///
+    /// # Returns
+    ///
+    /// The latency, or `None` if no chunk was received.
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        if self.latencies.is_empty() {
+            return None;
+        }
+
+        // Nearest-rank method
+        let rank = (percentile / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        let index = rank.clamp(1, self.latencies.len()) - 1;
+
+        Some(self.latencies[index])
+    }
+
+    /// Returns the share of sent chunks that never arrived, between 0 and 1.
+    pub fn loss_rate(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+
+        self.sent.saturating_sub(self.received) as f64 / self.sent as f64
+    }
+
+    /// Returns the share of rooms that failed, between 0 and 1.
+    pub fn error_rate(&self) -> f64 {
+        if self.rooms == 0 {
+            return 0.0;
+        }
+
+        self.failed_rooms as f64 / self.rooms as f64
+    }
+
+    /// Prints the report to stdout.
+    pub fn print(&self) {
+        let seconds = self.elapsed.as_secs_f64().max(f64::EPSILON);
+        let throughput = (self.received * self.chunk_size as u64) as f64 / seconds;
+
+        println!(
+            "Rooms:       {} ({} failed, {:.2}% error rate)",
+            self.rooms,
+            self.failed_rooms,
+            self.error_rate() * 100.0
+        );
+        println!(
+            "Chunks:      {} sent, {} received ({:.2}% lost)",
+            self.sent,
+            self.received,
+            self.loss_rate() * 100.0
+        );
+        println!("Duration:    {:.2}s", seconds);
+        println!(
+            "Throughput:  {:.2} MiB/s, {:.0} chunks/s",
+            throughput / 1024.0 / 1024.0,
+            self.received as f64 / seconds
+        );
+
+        for (label, percentile) in [("p50", 50.0), ("p90", 90.0), ("p99", 99.0), ("max", 100.0)] {
+            match self.percentile(percentile) {
+                Some(latency) => {
+                    println!("Latency {}: {:.2}ms", label, latency.as_secs_f64() * 1000.0)
+                }
+                None => println!("Latency {}: -", label),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let results = vec![
+            RoomResult {
+                latencies: (1..=50).map(Duration::from_millis).collect(),
+                sent: 50,
+                error: None,
+            },
+            RoomResult {
+                latencies: (51..=100).map(Duration::from_millis).collect(),
+                sent: 60,
+                error: Some("Timed out.".into()),
+            },
+        ];
+        let report = Report::new(results, Duration::from_secs(1), 1024);
+
+        assert_eq!(report.rooms, 2);
+        assert_eq!(report.failed_rooms, 1);
+        assert_eq!(report.received, 100);
+        assert_eq!(report.percentile(50.0), Some(Duration::from_millis(50)));
+        assert_eq!(report.percentile(99.0), Some(Duration::from_millis(99)));
+        assert_eq!(report.percentile(100.0), Some(Duration::from_millis(100)));
+        assert!((report.loss_rate() - 10.0 / 110.0).abs() < f64::EPSILON);
+        assert!((report.error_rate() - 0.5).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_empty_report() {
+        let report = Report::new(vec![], Duration::ZERO, 1024);
+
+        assert_eq!(report.percentile(50.0), None);
+        assert_eq!(report.loss_rate(), 0.0);
+        assert_eq!(report.error_rate(), 0.0);
+    }
+}
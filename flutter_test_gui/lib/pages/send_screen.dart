@@ -55,7 +55,7 @@ class SendScreenState extends State<SendScreen> {
     }
   }
 
-  /// Generates a random name for the transfer and navigates to the waiting screen.
+  /// Generates the transfer code and navigates to the waiting screen.
   ///
   /// See also:
   ///   - [generateRandomName]
@@ -63,7 +63,7 @@ class SendScreenState extends State<SendScreen> {
   Future<void> _startTransfer() async {
     final randomName =
         generateRandomName(); // Call Rust function to generate random name
-    print('Generated transfer name: $randomName');
+    print('Generated transfer code: $randomName');
     setState(() {
       transferName = randomName;
     });
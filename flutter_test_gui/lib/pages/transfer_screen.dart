@@ -12,7 +12,7 @@ import 'package:permission_handler/permission_handler.dart';
 ///
 /// This is a [StatefulWidget] that displays a screen for transferring files.
 /// It takes in two parameters:
-///   - [transferName]: The name of the transfer.
+///   - [transferName]: The transfer code, the PIN included.
 ///   - [directory]: The directory containing the files to be transferred.
 class TransferScreen extends StatefulWidget {
   // The name of the transfer.
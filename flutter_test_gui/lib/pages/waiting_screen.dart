@@ -12,7 +12,7 @@ import 'package:flutter_test_gui/consts/consts.dart';
 ///
 /// This is a [StatefulWidget] that displays a screen for the waiting state.
 /// It takes in two parameters:
-///   - [transferName]: The name of the transfer.
+///   - [transferName]: The transfer code, the PIN included.
 ///   - [files]: The list of files being transferred.
 class WaitingScreen extends StatefulWidget {
   // The list of files being transferred.
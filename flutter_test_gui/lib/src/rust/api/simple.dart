@@ -6,9 +6,16 @@
 import '../frb_generated.dart';
 import 'package:flutter_rust_bridge/flutter_rust_bridge_for_generated.dart';
 
+/// Generates the transfer code the receiver types, like `482913-funny-dog-cake`.
+///
+/// The PIN is part of the code, so the sender is started with the code as it is shown.
 String generateRandomName({dynamic hint}) =>
     RustLib.instance.api.generateRandomName(hint: hint);
 
+/// Sends the files under a transfer code from `generate_random_name`.
+///
+/// The handshake is authenticated with the PIN of the code, so the receiver needs the
+/// whole code, not only the name.
 Future<void> startRustSender(
         {required String name,
         required String relay,
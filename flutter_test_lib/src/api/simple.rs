@@ -1,8 +1,9 @@
 use anyhow::{anyhow, Result};
 use std::sync::Arc;
 
-use caesar_core::receiver::start_receiver;
-use caesar_core::sender::start_sender;
+use caesar_core::pake::{join_code, split_code, Secret};
+use caesar_core::receiver::{start_receiver, ReceiverOptions};
+use caesar_core::sender::{start_sender, SenderOptions};
 use rand::{seq::SliceRandom, thread_rng};
 
 #[flutter_rust_bridge::frb(init)]
@@ -11,6 +12,9 @@ pub fn init_app() {
     flutter_rust_bridge::setup_default_user_utils();
 }
 
+/// Generates the transfer code the receiver types, like `482913-funny-dog-cake`.
+///
+/// The PIN is part of the code, so the sender is started with the code as it is shown.
 #[flutter_rust_bridge::frb(sync)]
 pub fn generate_random_name() -> String {
     let mut rng = thread_rng();
@@ -19,7 +23,10 @@ pub fn generate_random_name() -> String {
     let noun1 = nouns1().choose(&mut rng).unwrap();
     let noun2 = nouns2().choose(&mut rng).unwrap();
 
-    format!("{adjective}-{noun1}-{noun2}")
+    let Secret::Pin(pin) = Secret::random_pin() else {
+        unreachable!("random_pin always creates a PIN");
+    };
+    join_code(&pin, &format!("{adjective}-{noun1}-{noun2}"))
 }
 
 fn adjectives() -> &'static [&'static str] {
@@ -37,11 +44,23 @@ fn nouns2() -> &'static [&'static str] {
     NOUNS2
 }
 
+/// Sends the files under a transfer code from `generate_random_name`.
+///
+/// The handshake is authenticated with the PIN of the code, so the receiver needs the
+/// whole code, not only the name.
 // #[flutter_rust_bridge::frb(async)]
 pub async fn start_rust_sender(name: String, relay: String, files: Vec<String>) -> Result<()> {
+    let (pin, name) = split_code(&name);
+    let Some(pin) = pin else {
+        return Err(anyhow!("The transfer code '{name}' has no PIN"));
+    };
+    let options = SenderOptions {
+        pin: Some(pin.to_string()),
+        ..SenderOptions::default()
+    };
     let arc_relay = Arc::new(relay);
     let arc_files = Arc::new(files);
-    let outcome = start_sender(name, arc_relay, arc_files).await;
+    let outcome = start_sender(name.to_string(), arc_relay, arc_files, options).await;
     println!("Start sender result: {:?}", outcome);
     Ok(())
 }
@@ -52,9 +71,14 @@ pub async fn start_rust_receiver(
     transfername: String,
 ) -> Result<String> {
     // #[cfg(target_os = "android")]
-    let outcome = start_receiver(filepath, relay.as_str(), transfername.as_str())
-        .await
-        .map_err(|e| anyhow!("Failed to start Caesar receiver: {}", e))?;
+    let outcome = start_receiver(
+        filepath,
+        relay.as_str(),
+        transfername.as_str(),
+        ReceiverOptions::default(),
+    )
+    .await
+    .map_err(|e| anyhow!("Failed to start Caesar receiver: {}", e))?;
 
     // #[cfg(not(target_os = "android"))]
     // let outcome = start_receiver(relay.as_str(), transfername.as_str())